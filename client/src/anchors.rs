@@ -0,0 +1,352 @@
+use crate::http;
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// The time to wait for a trust anchor to be fetched or looked up.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum size of a trust anchor fetched over HTTPS.
+const MAX_ANCHOR_SIZE: u64 = 1024 * 1024;
+
+/// The DNS record type of text records.
+const TXT: u16 = 16;
+
+/// Root hashes published outside of the database, e.g. by the machine that
+/// made the uploads, that downloads must chain to.
+///
+/// The database records which root hash a file was uploaded under, and the
+/// server proves that the file it serves chains to that root hash. Whoever
+/// can write the database can therefore make the client accept any file.
+/// Checking the root hash against an anchor kept elsewhere closes that gap.
+#[derive(Debug, Default)]
+pub struct Anchors {
+    /// Where the root hashes were loaded from, for error messages.
+    sources: Vec<String>,
+    roots: HashSet<String>,
+}
+
+impl Anchors {
+    /// Loads the root hashes of trust anchors.
+    ///
+    /// # Arguments
+    ///
+    /// * `sources` - The sources of the root hashes: `https://` URLs of
+    ///   files, `dns:` names whose TXT records hold root hashes, or paths of
+    ///   local files. Files hold a root hash per line, the rest of the line
+    ///   and lines starting with `#` being comments.
+    /// * `ca` - A PEM file of the certificate authorities the HTTPS servers
+    ///   are verified with, instead of the Mozilla root certificates.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a source cannot be read, fetched or looked up, or
+    /// holds an invalid line.
+    pub async fn load(sources: &[String], ca: Option<&Path>) -> Result<Self> {
+        let mut anchors = Self::default();
+        for source in sources {
+            let roots = match source.strip_prefix("dns:") {
+                Some(name) => lookup_txt(name)
+                    .await?
+                    .into_iter()
+                    // Other TXT records may share the name, e.g. SPF ones
+                    .filter(|record| is_hash(record.trim()))
+                    .map(|record| record.trim().to_lowercase())
+                    .collect(),
+                None if source.starts_with("https://") => {
+                    parse(&fetch_https(source, ca).await?)?
+                }
+                None if source.starts_with("http://") => {
+                    return Err(anyhow!(
+                        "Trust anchor {} must be fetched over HTTPS",
+                        source
+                    ));
+                }
+                None => {
+                    parse(&std::fs::read_to_string(source).map_err(|e| {
+                        anyhow!("Could not read trust anchor {}: {}", source, e)
+                    })?)?
+                }
+            };
+            log::info!("Loaded {} root hashes from {}", roots.len(), source);
+            anchors.roots.extend(roots);
+            anchors.sources.push(source.clone());
+        }
+        Ok(anchors)
+    }
+
+    /// Checks that a root hash is anchored, i.e. published by a source.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no source published the root hash.
+    pub fn check(&self, root_hash: &str) -> Result<()> {
+        match self.roots.contains(&root_hash.to_lowercase()) {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "Root hash {} is not published by the trust anchors {}",
+                root_hash,
+                self.sources.join(", ")
+            )),
+        }
+    }
+}
+
+/// Returns whether a string is a SHA-256 as a hex string.
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parses a trust anchor file: a root hash per line, followed by anything,
+/// e.g. the date of the upload, lines starting with `#` being comments.
+///
+/// # Errors
+///
+/// Returns an error if a line does not start with a root hash.
+fn parse(content: &str) -> Result<HashSet<String>> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let hash = line.split_whitespace().next().unwrap_or_default();
+            match is_hash(hash) {
+                true => Ok(hash.to_lowercase()),
+                false => {
+                    Err(anyhow!("Invalid trust anchor line {}", number + 1))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Fetches a file over HTTPS, with a plain HTTP/1.1 request.
+///
+/// # Errors
+///
+/// Returns an error if the URL is invalid, the server cannot be reached or
+/// is not trusted, or it does not reply with the file.
+async fn fetch_https(url: &str, ca: Option<&Path>) -> Result<String> {
+    let fetch = async {
+        let response = http::get(url, ca).await?;
+        // A truncated anchor written without a Content-Length could only
+        // lack root hashes
+        response.read_body(MAX_ANCHOR_SIZE).await
+    };
+    let body = tokio::time::timeout(FETCH_TIMEOUT, fetch)
+        .await
+        .map_err(|_| anyhow!("Timed out fetching trust anchor {}", url))?
+        .map_err(|e| anyhow!("Could not fetch trust anchor {}: {}", url, e))?;
+    String::from_utf8(body)
+        .map_err(|_| anyhow!("Trust anchor {} is not text", url))
+}
+
+/// Looks up the TXT records of a name, with the first nameserver of
+/// `/etc/resolv.conf`, over UDP and over TCP if the answer is truncated.
+///
+/// The answer is only as trustworthy as the resolver, DNSSEC not being
+/// checked.
+///
+/// # Errors
+///
+/// Returns an error if no nameserver is configured, or the name cannot be
+/// looked up.
+async fn lookup_txt(name: &str) -> Result<Vec<String>> {
+    let nameserver = std::fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|conf| {
+            conf.lines().find_map(|line| {
+                let address = line.trim().strip_prefix("nameserver")?;
+                address.trim().parse::<IpAddr>().ok()
+            })
+        })
+        .ok_or_else(|| anyhow!("No nameserver is configured"))?;
+    let nameserver = SocketAddr::new(nameserver, 53);
+    let id = fastrand::u16(..);
+    let query = dns_query(id, name)?;
+
+    let lookup = async {
+        let local = match nameserver {
+            SocketAddr::V4(_) => {
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+            }
+            SocketAddr::V6(_) => "[::]:0".parse()?,
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(nameserver).await?;
+        socket.send(&query).await?;
+        let mut answer = vec![0; 4096];
+        let len = socket.recv(&mut answer).await?;
+        answer.truncate(len);
+        match parse_txt(id, &answer)? {
+            Some(records) => Ok(records),
+            None => {
+                // Truncated: ask again over TCP, each message being preceded
+                // by its length
+                let mut stream = TcpStream::connect(nameserver).await?;
+                stream.write_u16(query.len() as u16).await?;
+                stream.write_all(&query).await?;
+                let mut answer = vec![0; stream.read_u16().await? as usize];
+                stream.read_exact(&mut answer).await?;
+                parse_txt(id, &answer)?
+                    .ok_or_else(|| anyhow!("Truncated DNS answer"))
+            }
+        }
+    };
+    tokio::time::timeout(FETCH_TIMEOUT, lookup)
+        .await
+        .map_err(|_| {
+            anyhow!("Timed out looking up the TXT records of {}", name)
+        })?
+        .map_err(|e: anyhow::Error| {
+            anyhow!("Could not look up the TXT records of {}: {}", name, e)
+        })
+}
+
+/// Builds a recursive DNS query of the TXT records of a name.
+fn dns_query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut query = id.to_be_bytes().to_vec();
+    // Recursion desired, 1 question, no answers nor other records
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("Invalid DNS name {}", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TXT.to_be_bytes());
+    // The IN class
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+/// Parses the TXT records of a DNS answer, the strings of a record being
+/// concatenated.
+///
+/// # Returns
+///
+/// Returns `None` if the answer is truncated.
+///
+/// # Errors
+///
+/// Returns an error if the answer is invalid, to another query, or reports
+/// an error, e.g. that the name does not exist.
+fn parse_txt(id: u16, answer: &[u8]) -> Result<Option<Vec<String>>> {
+    let invalid = || anyhow!("Invalid DNS answer");
+    let u16_at = |offset: usize| -> Result<u16> {
+        let bytes = answer.get(offset..offset + 2).ok_or_else(invalid)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    if u16_at(0)? != id {
+        return Err(anyhow!("DNS answer to another query"));
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x0200 != 0 {
+        return Ok(None);
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(anyhow!("The name does not exist")),
+        code => return Err(anyhow!("DNS error {}", code)),
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+
+    // Skips a name: labels up to an empty one, or a pointer to another name
+    let skip_name = |mut offset: usize| -> Result<usize> {
+        loop {
+            let len = *answer.get(offset).ok_or_else(invalid)?;
+            match len {
+                0 => return Ok(offset + 1),
+                len if len & 0xc0 == 0xc0 => return Ok(offset + 2),
+                len => offset += 1 + len as usize,
+            }
+        }
+    };
+    let mut offset = 12;
+    for _ in 0..questions {
+        // The name, then the type and class
+        offset = skip_name(offset)? + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        offset = skip_name(offset)?;
+        let kind = u16_at(offset)?;
+        // The type, class and TTL precede the length of the data
+        let len = u16_at(offset + 8)? as usize;
+        offset += 10;
+        let data = answer.get(offset..offset + len).ok_or_else(invalid)?;
+        offset += len;
+        if kind != TXT {
+            // e.g. the CNAME the name is an alias of
+            continue;
+        }
+        let mut record = vec![];
+        let mut strings = data;
+        while let Some((&len, rest)) = strings.split_first() {
+            let string = rest.get(..len as usize).ok_or_else(invalid)?;
+            record.extend_from_slice(string);
+            strings = &rest[len as usize..];
+        }
+        records.push(String::from_utf8_lossy(&record).into_owned());
+    }
+    Ok(Some(records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let root = "ab".repeat(32);
+        let content = format!(
+            "# Published nightly\n{}  2026-10-14\n\n{}\n",
+            root,
+            "CD".repeat(32)
+        );
+        let roots = parse(&content).unwrap();
+        assert_eq!(roots.len(), 2);
+        let anchors = Anchors {
+            sources: vec!["anchors.txt".to_string()],
+            roots,
+        };
+        assert!(anchors.check(&root).is_ok());
+        assert!(anchors.check(&"cd".repeat(32)).is_ok());
+        assert!(anchors.check(&"ef".repeat(32)).is_err());
+
+        assert!(parse("not a hash\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_txt() {
+        let query = dns_query(7, "anchors.example.com").unwrap();
+        let mut answer = query.clone();
+        // A response, with recursion available and one answer
+        answer[2..4].copy_from_slice(&[0x81, 0x80]);
+        answer[6..8].copy_from_slice(&[0, 1]);
+        // The name as a pointer to the question, then TXT, IN and the TTL
+        answer.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60]);
+        answer.extend_from_slice(&[0, 12, 5]);
+        answer.extend_from_slice(b"hello");
+        answer.push(5);
+        answer.extend_from_slice(b"world");
+        assert_eq!(
+            parse_txt(7, &answer).unwrap(),
+            Some(vec!["helloworld".to_string()])
+        );
+        assert!(parse_txt(8, &answer).is_err());
+
+        // Truncated, then a name that does not exist
+        answer[2] |= 0x02;
+        assert_eq!(parse_txt(7, &answer).unwrap(), None);
+        answer[2..4].copy_from_slice(&[0x81, 0x83]);
+        assert!(parse_txt(7, &answer).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// How long the state of a batch that was never resumed is kept, e.g. of a
+/// batch whose files changed since it was interrupted.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The state of a batch of files being uploaded or downloaded, persisted as
+/// the batch goes so that an interrupted batch resumes where it stopped
+/// rather than from its first file.
+///
+/// The state is a journal under the client store,
+/// `<dir>/<id>.jsonl`, appended one line per step a file goes through: the
+/// batch is identified by the SHA-256 of a key describing it, e.g. the
+/// server, paths, sizes and modification times of the files of an upload,
+/// so that a batch whose files changed starts over. The journal is locked
+/// while the batch runs, and removed once it is done.
+pub struct Batch {
+    path: PathBuf,
+    journal: Mutex<File>,
+    session: String,
+    files: Vec<FileState>,
+}
+
+/// What an earlier run of a batch did with one of its files.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileState {
+    /// The SHA-256 of the file, as a hex string, once hashed or transferred.
+    pub sha256: Option<String>,
+    /// Whether the file was transferred: staged on the server by an upload,
+    /// or written by a download.
+    pub transferred: bool,
+    /// The proof of the file, as hex strings, once downloaded.
+    pub proof: Vec<String>,
+    /// Whether the proof of the file was verified against the root hash.
+    pub verified: bool,
+}
+
+/// A line of the journal of a batch.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+enum Step {
+    /// The first line, with the upload session the files are sent in.
+    Started {
+        session: String,
+    },
+    Hashed {
+        index: usize,
+        sha256: String,
+    },
+    Transferred {
+        index: usize,
+        sha256: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        proof: Vec<String>,
+    },
+    Verified {
+        index: usize,
+    },
+}
+
+impl Batch {
+    /// Opens the state of a batch, resuming the one an earlier run left, and
+    /// removes the states left for more than [`MAX_AGE`].
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of the states of the batches.
+    /// * `key` - The description of the batch, identifying it.
+    /// * `files` - The number of files of the batch.
+    /// * `session` - The upload session of the batch, if it starts over.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state cannot be read or created, or if
+    /// another process is running the same batch.
+    pub fn open(
+        dir: &Path,
+        key: &str,
+        files: usize,
+        session: impl FnOnce() -> String,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        if let Err(e) = purge(dir, SystemTime::now()) {
+            log::warn!("Could not purge the state of old batches: {}", e);
+        }
+        let path =
+            dir.join(format!("{}.jsonl", crate::utils::sha256(key.as_bytes())));
+        let mut journal = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        if journal.try_lock().is_err() {
+            return Err(anyhow::anyhow!(
+                "The same batch is being transferred by another process"
+            ));
+        }
+
+        // A line cut short by an interruption is the last one, and is cut
+        // off so that the next steps are appended after the valid ones
+        let content = fs::read(&path)?;
+        let mut valid = 0;
+        let mut steps = vec![];
+        for line in content.split_inclusive(|&byte| byte == b'\n') {
+            match serde_json::from_slice::<Step>(line) {
+                Ok(step) if line.ends_with(b"\n") => steps.push(step),
+                _ => break,
+            }
+            valid += line.len();
+        }
+        journal.set_len(valid as u64)?;
+        let mut steps = steps.into_iter();
+        let mut state = vec![FileState::default(); files];
+        let session = match steps.next() {
+            Some(Step::Started { session }) => {
+                for step in steps {
+                    apply(&mut state, step);
+                }
+                log::info!("Resuming the batch of {}", path.display());
+                session
+            }
+            _ => {
+                let session = session();
+                journal.set_len(0)?;
+                journal.write_all(&line(&Step::Started {
+                    session: session.clone(),
+                })?)?;
+                session
+            }
+        };
+        Ok(Self {
+            path,
+            journal: Mutex::new(journal),
+            session,
+            files: state,
+        })
+    }
+
+    /// Returns the upload session the files of the batch are sent in, the
+    /// same across runs, so that the server keeps the bytes it received.
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// Returns what earlier runs of the batch did with a file.
+    pub fn file(&self, index: usize) -> &FileState {
+        &self.files[index]
+    }
+
+    /// Records that a file was hashed.
+    pub fn hashed(&self, index: usize, sha256: &str) -> Result<()> {
+        self.append(&Step::Hashed {
+            index,
+            sha256: sha256.to_string(),
+        })
+    }
+
+    /// Records that a file was transferred.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the file in the batch.
+    /// * `sha256` - The SHA-256 of the file, as a hex string.
+    /// * `proof` - The proof of the file, if downloaded.
+    pub fn transferred(
+        &self,
+        index: usize,
+        sha256: &str,
+        proof: &[String],
+    ) -> Result<()> {
+        self.append(&Step::Transferred {
+            index,
+            sha256: sha256.to_string(),
+            proof: proof.to_vec(),
+        })
+    }
+
+    /// Records that the proof of a file was verified.
+    pub fn verified(&self, index: usize) -> Result<()> {
+        self.append(&Step::Verified { index })
+    }
+
+    /// Removes the state of the batch once it is done.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state cannot be removed.
+    pub fn finish(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends a step to the journal, in a single write so that an
+    /// interruption never leaves more than the last line partial.
+    fn append(&self, step: &Step) -> Result<()> {
+        self.journal.lock().unwrap().write_all(&line(step)?)?;
+        Ok(())
+    }
+}
+
+/// Applies a step of the journal to the state of the files.
+fn apply(state: &mut [FileState], step: Step) {
+    match step {
+        Step::Started { .. } => {}
+        Step::Hashed { index, sha256 } => {
+            if let Some(file) = state.get_mut(index) {
+                file.sha256 = Some(sha256);
+            }
+        }
+        Step::Transferred {
+            index,
+            sha256,
+            proof,
+        } => {
+            if let Some(file) = state.get_mut(index) {
+                file.sha256 = Some(sha256);
+                file.transferred = true;
+                file.proof = proof;
+            }
+        }
+        Step::Verified { index } => {
+            if let Some(file) = state.get_mut(index) {
+                file.verified = true;
+            }
+        }
+    }
+}
+
+/// Serializes a step as a line of the journal.
+fn line(step: &Step) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(step)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Removes the states of the batches last written more than [`MAX_AGE`]
+/// before `now`, unless they are running.
+fn purge(dir: &Path, now: SystemTime) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let modified = fs::metadata(&path)?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() <= MAX_AGE {
+            continue;
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        if file.try_lock().is_ok() {
+            log::debug!("Removing the state of {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_batch() {
+        let dir = TempDir::new().unwrap();
+        let session = || "session".to_string();
+        let batch = Batch::open(dir.path(), "key", 3, session).unwrap();
+        assert_eq!(batch.session(), "session");
+        assert_eq!(batch.file(0), &FileState::default());
+        // The same batch cannot run twice at once
+        assert!(Batch::open(dir.path(), "key", 3, session).is_err());
+        batch.hashed(0, "aa").unwrap();
+        batch.transferred(1, "bb", &["cc".to_string()]).unwrap();
+        batch.verified(1).unwrap();
+        drop(batch);
+
+        // A resumed batch keeps its session, and what was done
+        let path = fs::read_dir(dir.path()).unwrap().next().unwrap();
+        let path = path.unwrap().path();
+        let mut journal = OpenOptions::new().append(true).open(&path).unwrap();
+        journal.write_all(b"{\"step\":\"transf").unwrap();
+        let other = || "other".to_string();
+        let batch = Batch::open(dir.path(), "key", 3, other).unwrap();
+        assert_eq!(batch.session(), "session");
+        assert_eq!(batch.file(0).sha256.as_deref(), Some("aa"));
+        assert!(!batch.file(0).transferred);
+        assert!(batch.file(1).transferred && batch.file(1).verified);
+        assert_eq!(batch.file(1).proof, vec!["cc".to_string()]);
+        assert_eq!(batch.file(2), &FileState::default());
+        batch.hashed(2, "dd").unwrap();
+        drop(batch);
+        let batch = Batch::open(dir.path(), "key", 3, other).unwrap();
+        assert_eq!(batch.file(2).sha256.as_deref(), Some("dd"));
+        batch.finish().unwrap();
+        assert!(!path.exists());
+        drop(batch);
+
+        // Another batch starts over
+        let batch = Batch::open(dir.path(), "other", 3, other).unwrap();
+        assert_eq!(batch.session(), "other");
+        drop(batch);
+        purge(dir.path(), SystemTime::now() + MAX_AGE * 2).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
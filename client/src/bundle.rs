@@ -0,0 +1,410 @@
+use crate::db::Upload;
+use crate::manifest;
+use crate::output::{BundleCheckReport, CheckStatus, CheckedFile};
+use crate::utils;
+use anyhow::{anyhow, Result};
+use merkle_tree::{MerkleTree, Proof};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// The version of the format of bundles.
+const BUNDLE_VERSION: u32 = 1;
+
+/// The size of the blocks of a tar archive.
+const BLOCK: usize = 512;
+
+/// The description of a bundle, `bundle.json` in the archive.
+#[derive(Debug, Serialize, Deserialize)]
+struct Bundle {
+    version: u32,
+    root_hash: String,
+    uploaded_at: Option<u64>,
+    /// The files of the upload, in the order of the leaves.
+    files: Vec<BundledFile>,
+}
+
+/// A file of a bundle, whose proof is `proofs/<index>.json`.
+#[derive(Debug, Serialize, Deserialize)]
+struct BundledFile {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_bytes: Option<String>,
+    size: Option<u64>,
+    sha256: String,
+}
+
+/// Exports the verification bundle of an upload: a tar archive holding the
+/// description of the upload, its root hash, its manifest and the proof of
+/// every file, but not the data, so that a copy of the files can later be
+/// checked against the upload without the store or the server.
+///
+/// The archive holds:
+///
+/// * `bundle.json` - The root hash and the files of the upload.
+/// * `root` - The root hash.
+/// * `manifest.sha256` - The manifest, for `sha256sum -c`.
+/// * `proofs/<index>.json` - The proof of every file, as `prove` saves it.
+///
+/// # Arguments
+///
+/// * `file` - The file to write the bundle to.
+/// * `root_hash` - The root hash of the upload.
+/// * `upload` - The recorded upload.
+/// * `tree` - The tree of the upload, rebuilt from its recorded leaves.
+/// * `entries` - The manifest entries of the files of the upload.
+///
+/// # Errors
+///
+/// Returns an error if the bundle cannot be written.
+pub fn export(
+    file: &Path,
+    root_hash: &str,
+    upload: &Upload,
+    tree: &MerkleTree,
+    entries: &[manifest::Entry],
+) -> Result<()> {
+    let root = *tree
+        .root()
+        .ok_or(anyhow!("Root Hash could not be computed"))?;
+    let bundle = Bundle {
+        version: BUNDLE_VERSION,
+        root_hash: root_hash.to_string(),
+        uploaded_at: upload.uploaded_at,
+        files: upload
+            .files
+            .iter()
+            .zip(entries)
+            .map(|(record, entry)| BundledFile {
+                name: record.name.clone(),
+                name_bytes: record.name_bytes.clone(),
+                size: record.size,
+                sha256: entry.sha256.clone(),
+            })
+            .collect(),
+    };
+    let mtime = upload.uploaded_at.unwrap_or_default();
+    let mut archive = vec![];
+    append(
+        &mut archive,
+        "bundle.json",
+        &serde_json::to_vec_pretty(&bundle)?,
+        mtime,
+    )?;
+    append(
+        &mut archive,
+        "root",
+        format!("{}\n", root_hash).as_bytes(),
+        mtime,
+    )?;
+    append(
+        &mut archive,
+        "manifest.sha256",
+        &manifest::format(entries),
+        mtime,
+    )?;
+    for (index, leaf) in tree.leaves().iter().enumerate() {
+        let proof = Proof::new(index, *leaf, root, tree.proof(index)?);
+        append(
+            &mut archive,
+            &format!("proofs/{}.json", index),
+            &serde_json::to_vec_pretty(&proof)?,
+            mtime,
+        )?;
+    }
+    // The end of the archive is marked by two empty blocks
+    archive.resize(archive.len() + 2 * BLOCK, 0);
+    fs::write(file, archive)
+        .map_err(|e| anyhow!("Could not write {}: {}", file.display(), e))
+}
+
+/// Checks a directory against a verification bundle, fully offline: the
+/// bundle is first checked to be consistent, every proof linking its file to
+/// the root hash and the leaves recomputing it, then every file of the
+/// directory is hashed and checked against its proof.
+///
+/// # Arguments
+///
+/// * `file` - The bundle, written by [`export`].
+/// * `dir` - The directory the names of the files are relative to.
+///
+/// # Errors
+///
+/// Returns an error if the bundle cannot be read, or is not consistent.
+pub fn verify(file: &Path, dir: &Path) -> Result<BundleCheckReport> {
+    let archive = fs::read(file)
+        .map_err(|e| anyhow!("Could not read {}: {}", file.display(), e))?;
+    let entries = entries(&archive)?;
+    let entry = |name: &str| {
+        entries
+            .get(name)
+            .copied()
+            .ok_or_else(|| anyhow!("The bundle has no {}", name))
+    };
+    let bundle: Bundle = serde_json::from_slice(entry("bundle.json")?)?;
+    if bundle.version != BUNDLE_VERSION {
+        return Err(anyhow!("Unsupported bundle version {}", bundle.version));
+    }
+    let inconsistent =
+        |reason: String| anyhow!("The bundle is inconsistent: {}", reason);
+    let root = utils::decode_hash(&bundle.root_hash)?;
+    if String::from_utf8_lossy(entry("root")?).trim() != bundle.root_hash {
+        return Err(inconsistent("its root differs".to_string()));
+    }
+
+    let mut proofs = vec![];
+    for (index, file) in bundle.files.iter().enumerate() {
+        let leaf = utils::decode_hash(&file.sha256)?;
+        let proof: Proof =
+            serde_json::from_slice(entry(&format!("proofs/{}.json", index))?)?;
+        if proof.index != index
+            || proof.leaf != leaf
+            || proof.root != root
+            || !proof.verify()
+        {
+            return Err(inconsistent(format!(
+                "the proof of {} does not link it to the root hash",
+                file.name
+            )));
+        }
+        proofs.push(proof);
+    }
+    // The proofs of a subset of the files would verify too: the leaves must
+    // recompute the root for the bundle to hold every file
+    let tree = MerkleTree::from_leaves(
+        proofs.iter().map(|proof| proof.leaf).collect(),
+    )?;
+    if tree.root() != Some(&root) {
+        return Err(inconsistent(
+            "its files do not recompute the root hash".to_string(),
+        ));
+    }
+    let listed = manifest::parse(entry("manifest.sha256")?)?;
+    if listed.len() != bundle.files.len()
+        || listed.iter().zip(&bundle.files).any(|(entry, file)| {
+            entry.sha256 != file.sha256 || entry.name != raw_name(file)
+        })
+    {
+        return Err(inconsistent("its manifest differs".to_string()));
+    }
+
+    let files = bundle
+        .files
+        .iter()
+        .zip(proofs)
+        .map(|(file, proof)| {
+            let path = dir.join(utils::safe_relative_path(
+                &utils::path_from_bytes(&raw_name(file)),
+            )?);
+            let status = match utils::sha256_file(&path) {
+                Ok(leaf)
+                    if MerkleTree::verify_leaf(
+                        proof.index,
+                        &leaf,
+                        &proof.root,
+                        &proof.hashes,
+                    ) =>
+                {
+                    CheckStatus::Ok
+                }
+                Ok(_) => CheckStatus::Failed,
+                Err(_) => CheckStatus::Missing,
+            };
+            Ok(CheckedFile {
+                name: file.name.clone(),
+                path,
+                status,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(BundleCheckReport {
+        bundle: file.to_path_buf(),
+        root_hash: bundle.root_hash,
+        verified: files.iter().all(|file| file.status == CheckStatus::Ok),
+        files,
+    })
+}
+
+/// Returns the bytes of the name of a file of a bundle.
+fn raw_name(file: &BundledFile) -> Vec<u8> {
+    file.name_bytes
+        .as_deref()
+        .and_then(|bytes| hex::decode(bytes).ok())
+        .unwrap_or_else(|| file.name.clone().into_bytes())
+}
+
+/// Appends a regular file to a tar archive, in the ustar format.
+fn append(
+    archive: &mut Vec<u8>,
+    name: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<()> {
+    if name.len() > 100 {
+        return Err(anyhow!("The name {} is too long for the archive", name));
+    }
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], data.len() as u64);
+    octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is computed with its own field made of spaces
+    header[148..156].fill(b' ');
+    let checksum = checksum(&header);
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(BLOCK), 0);
+    Ok(())
+}
+
+/// Returns the regular files of a tar archive, by name.
+fn entries(archive: &[u8]) -> Result<BTreeMap<String, &[u8]>> {
+    let invalid = || anyhow!("The bundle is not a valid tar archive");
+    let mut entries = BTreeMap::new();
+    let mut offset = 0;
+    while let Some(header) = archive.get(offset..offset + BLOCK) {
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        let mut blank = [0; BLOCK];
+        blank.copy_from_slice(header);
+        blank[148..156].fill(b' ');
+        if parse_octal(&header[148..156]) != Some(checksum(&blank)) {
+            return Err(invalid());
+        }
+        let size = parse_octal(&header[124..136]).ok_or_else(invalid)?;
+        let start = offset + BLOCK;
+        let end = usize::try_from(size)
+            .ok()
+            .and_then(|size| start.checked_add(size))
+            .ok_or_else(invalid)?;
+        let data = archive.get(start..end).ok_or_else(invalid)?;
+        if matches!(header[156], b'0' | 0) {
+            let name = match text(&header[345..500]) {
+                prefix if prefix.is_empty() => text(&header[..100]),
+                prefix => format!("{}/{}", prefix, text(&header[..100])),
+            };
+            let name = name.strip_prefix("./").unwrap_or(&name).to_string();
+            entries.insert(name, data);
+        }
+        offset = end.next_multiple_of(BLOCK);
+    }
+    Ok(entries)
+}
+
+/// Writes a number to a field of a tar header, in octal ended by a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let value = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(value.as_bytes());
+}
+
+/// Reads an octal number from a field of a tar header.
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let value = std::str::from_utf8(field).ok()?;
+    match value.trim_matches(|c| c == '\0' || c == ' ') {
+        "" => Some(0),
+        value => u64::from_str_radix(value, 8).ok(),
+    }
+}
+
+/// Returns the checksum of a tar header, the sum of its bytes.
+fn checksum(header: &[u8]) -> u64 {
+    header.iter().map(|&byte| u64::from(byte)).sum()
+}
+
+/// Reads a NUL terminated text field of a tar header.
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|&byte| byte == 0);
+    String::from_utf8_lossy(&field[..end.unwrap_or(field.len())]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::FileRecord;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_bundle() {
+        let dir = TempDir::new().unwrap();
+        let contents = [&b"one"[..], b"two", b"three"];
+        let manifest = ["one", "sub/two", "three"]
+            .iter()
+            .zip(contents)
+            .map(|(name, content)| manifest::Entry {
+                sha256: utils::sha256(content),
+                name: name.as_bytes().to_vec(),
+            })
+            .collect::<Vec<_>>();
+        let upload = Upload {
+            files: manifest
+                .iter()
+                .map(|entry| FileRecord {
+                    name: String::from_utf8(entry.name.clone()).unwrap(),
+                    sha256: Some(entry.sha256.clone()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let leaves = manifest
+            .iter()
+            .map(|entry| utils::decode_hash(&entry.sha256))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let tree = MerkleTree::from_leaves(leaves).unwrap();
+        let root_hash = hex::encode(tree.root().unwrap());
+        let file = dir.path().join("bundle.tar");
+        export(&file, &root_hash, &upload, &tree, &manifest).unwrap();
+        let archive = fs::read(&file).unwrap();
+        let names = entries(&archive).unwrap().into_keys().collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "bundle.json",
+                "manifest.sha256",
+                "proofs/0.json",
+                "proofs/1.json",
+                "proofs/2.json",
+                "root"
+            ]
+        );
+
+        let files = dir.path().join("files");
+        fs::create_dir_all(files.join("sub")).unwrap();
+        fs::write(files.join("one"), "one").unwrap();
+        fs::write(files.join("sub/two"), "changed").unwrap();
+        let report = verify(&file, &files).unwrap();
+        assert_eq!(report.root_hash, root_hash);
+        assert!(!report.verified);
+        let statuses = report
+            .files
+            .iter()
+            .map(|file| file.status)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [CheckStatus::Ok, CheckStatus::Failed, CheckStatus::Missing]
+        );
+        fs::write(files.join("sub/two"), "two").unwrap();
+        fs::write(files.join("three"), "three").unwrap();
+        assert!(verify(&file, &files).unwrap().verified);
+
+        // A bundle missing the proof of a file is not consistent
+        let proof = archive
+            .windows(13)
+            .position(|name| name == b"proofs/2.json")
+            .unwrap();
+        let mut truncated = archive[..proof].to_vec();
+        truncated.resize(truncated.len() + 2 * BLOCK, 0);
+        fs::write(&file, truncated).unwrap();
+        assert!(verify(&file, &files).is_err());
+    }
+}
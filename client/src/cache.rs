@@ -0,0 +1,153 @@
+use anyhow::Result;
+use merkle_tree::Proof;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The proofs of files verified against their root hash, kept under the
+/// client store in the serialization format of [`merkle_tree::Proof`], one
+/// file per proof: `<dir>/<root hash>/<index>.json`.
+///
+/// Proving or downloading a file again takes its proof from the cache
+/// instead of asking the server for it, and verifying a file works offline
+/// once its proof is cached. A cached proof is verified again whenever it
+/// is read, so that a corrupted one is fetched again rather than trusted.
+pub struct ProofCache {
+    dir: PathBuf,
+    /// Whether proofs are read from and written to the cache, `--no-cache`
+    /// only leaving it to invalidate proofs.
+    enabled: bool,
+}
+
+impl ProofCache {
+    /// Creates a cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of the cache, created once a proof is cached.
+    /// * `enabled` - Whether proofs are read from and written to the cache.
+    pub fn new(dir: PathBuf, enabled: bool) -> Self {
+        Self { dir, enabled }
+    }
+
+    /// Returns the cached proof of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload of the file.
+    /// * `index` - The index of the file in the upload.
+    ///
+    /// # Returns
+    ///
+    /// Returns the proof, or `None` if the cache is disabled, holds no proof
+    /// of the file, or holds one that cannot be read or is invalid.
+    pub fn get(&self, root_hash: &str, index: usize) -> Option<Proof> {
+        if !self.enabled {
+            return None;
+        }
+        let path = self.path(root_hash, index);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                log::warn!("Could not read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        match serde_json::from_str::<Proof>(&content) {
+            Ok(proof)
+                if proof.index == index
+                    && hex::encode(proof.root) == root_hash
+                    && proof.verify() =>
+            {
+                log::debug!("Using the cached proof of {}", path.display());
+                Some(proof)
+            }
+            _ => {
+                log::warn!("Ignoring the invalid proof {}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Caches the proof of a file, once verified.
+    ///
+    /// A proof that cannot be written is only logged, the cache saving
+    /// round-trips to the server but never being required.
+    pub fn put(&self, proof: &Proof) {
+        if !self.enabled {
+            return;
+        }
+        let path = self.path(&hex::encode(proof.root), proof.index);
+        if let Err(e) = write(&path, proof) {
+            log::warn!("Could not cache the proof {}: {}", path.display(), e);
+        }
+    }
+
+    /// Removes the cached proofs of the files of an upload, once deleted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the proofs cannot be removed.
+    pub fn invalidate(&self, root_hash: &str) -> Result<()> {
+        match fs::remove_dir_all(self.dir.join(root_hash)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the path of the proof of a file.
+    fn path(&self, root_hash: &str, index: usize) -> PathBuf {
+        self.dir.join(root_hash).join(format!("{}.json", index))
+    }
+}
+
+/// Writes a proof to a temporary file renamed over `path`, so that a proof
+/// read while it is written is never truncated.
+fn write(path: &Path, proof: &Proof) -> Result<()> {
+    let dir = path
+        .parent()
+        .expect("proofs are in the directory of a root");
+    fs::create_dir_all(dir)?;
+    let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&tmp, serde_json::to_string_pretty(proof)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+    use merkle_tree::MerkleTree;
+
+    #[test]
+    fn test_proof_cache() {
+        let dir = TempDir::new().unwrap();
+        let cache = ProofCache::new(dir.path().join("proofs"), true);
+        let tree = MerkleTree::new(&[b"one", b"two", b"six"]).unwrap();
+        let root = *tree.root().unwrap();
+        let root_hash = hex::encode(root);
+        let proof =
+            Proof::new(1, tree.leaves()[1], root, tree.proof(1).unwrap());
+
+        assert_eq!(cache.get(&root_hash, 1), None);
+        cache.put(&proof);
+        assert_eq!(cache.get(&root_hash, 1), Some(proof.clone()));
+        assert_eq!(cache.get(&root_hash, 0), None);
+
+        // A proof that does not verify is not used
+        let path = cache.path(&root_hash, 0);
+        let mut invalid = proof.clone();
+        invalid.index = 0;
+        fs::write(&path, serde_json::to_string(&invalid).unwrap()).unwrap();
+        assert_eq!(cache.get(&root_hash, 0), None);
+
+        // A disabled cache is neither read nor written, but still invalidated
+        let disabled = ProofCache::new(dir.path().join("proofs"), false);
+        assert_eq!(disabled.get(&root_hash, 1), None);
+        disabled.invalidate(&root_hash).unwrap();
+        assert_eq!(cache.get(&root_hash, 1), None);
+        cache.invalidate(&root_hash).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+use crate::anchors::Anchors;
+use crate::cache::ProofCache;
+use crate::db::{FileRecord, Upload};
+use crate::output::{AuditStatus, AuditedFile, CheckReport, CheckedUpload};
+use crate::utils;
+use anyhow::Result;
+use merkle_tree::MerkleTree;
+use std::path::Path;
+
+/// Audits the downloaded files of uploads, e.g. written to the client store
+/// by `download-all`: every file is hashed again and its membership in its
+/// upload recomputed from its proof, recorded in the database or else
+/// cached, without contacting the server.
+///
+/// The uploads none of whose files are under the directory are taken not to
+/// be downloaded there, and skipped.
+///
+/// # Arguments
+///
+/// * `dir` - The directory the names of the files are relative to.
+/// * `uploads` - The root hashes and the recorded uploads to audit.
+/// * `anchors` - The root hashes the files must chain to, if any, the files
+///   of the others being unverifiable.
+/// * `cache` - The cache the proofs not recorded are taken from.
+///
+/// # Errors
+///
+/// Returns an error if the name of a file is not a safe relative path.
+pub fn check(
+    dir: &Path,
+    uploads: &[(&str, &Upload)],
+    anchors: Option<&Anchors>,
+    cache: &ProofCache,
+) -> Result<CheckReport> {
+    let mut checked = vec![];
+    let mut skipped = 0;
+    for (root_hash, upload) in uploads {
+        let paths = upload
+            .files
+            .iter()
+            .map(|record| Ok(dir.join(record.relative_path()?)))
+            .collect::<Result<Vec<_>>>()?;
+        if !paths.iter().any(|path| path.symlink_metadata().is_ok()) {
+            skipped += 1;
+            continue;
+        }
+        let anchored =
+            anchors.map_or(Ok(()), |anchors| anchors.check(root_hash));
+        let files = upload
+            .files
+            .iter()
+            .zip(paths)
+            .enumerate()
+            .map(|(index, (record, path))| {
+                let (status, reason) = match &anchored {
+                    Err(e) if path.is_file() => {
+                        (AuditStatus::Unverifiable, Some(e.to_string()))
+                    }
+                    _ => audit(root_hash, index, record, &path, cache),
+                };
+                AuditedFile {
+                    name: record.name.clone(),
+                    path,
+                    status,
+                    reason,
+                }
+            })
+            .collect();
+        checked.push(CheckedUpload {
+            root_hash: root_hash.to_string(),
+            files,
+        });
+    }
+    let verified = checked.iter().all(|upload| {
+        upload
+            .files
+            .iter()
+            .all(|file| file.status == AuditStatus::Ok)
+    });
+    Ok(CheckReport {
+        dir: dir.to_path_buf(),
+        uploads: checked,
+        skipped,
+        verified,
+    })
+}
+
+/// Audits a downloaded file, returning its status and, unless it is
+/// verified, the reason why.
+fn audit(
+    root_hash: &str,
+    index: usize,
+    record: &FileRecord,
+    path: &Path,
+    cache: &ProofCache,
+) -> (AuditStatus, Option<String>) {
+    if !path.is_file() {
+        return (AuditStatus::Missing, None);
+    }
+    if record.encrypted || record.compressed {
+        return (
+            AuditStatus::Unverifiable,
+            Some("encoded before it was uploaded".to_string()),
+        );
+    }
+    let leaf = match utils::sha256_file(path) {
+        Ok(leaf) => leaf,
+        Err(e) => return (AuditStatus::Missing, Some(e.to_string())),
+    };
+    // The file has changed if it does not have the recorded hash, whatever
+    // its proof
+    if record
+        .sha256
+        .as_ref()
+        .is_some_and(|sha256| *sha256 != hex::encode(leaf))
+    {
+        return (AuditStatus::Modified, None);
+    }
+
+    let proof = match &record.proof {
+        Some(proof) => proof
+            .iter()
+            .map(|hash| utils::decode_hash(hash))
+            .collect::<Result<Vec<_>>>()
+            .ok(),
+        None => cache.get(root_hash, index).map(|proof| proof.hashes),
+    };
+    let Some(proof) = proof else {
+        return (
+            AuditStatus::Unverifiable,
+            Some("no proof is recorded".to_string()),
+        );
+    };
+    let Ok(root) = utils::decode_hash(root_hash) else {
+        return (
+            AuditStatus::Unverifiable,
+            Some("invalid root hash".to_string()),
+        );
+    };
+    match MerkleTree::verify_leaf(index, &leaf, &root, &proof) {
+        true => (AuditStatus::Ok, None),
+        // Without a recorded hash, the file is taken to have changed
+        false if record.sha256.is_none() => (AuditStatus::Modified, None),
+        false => (
+            AuditStatus::Unverifiable,
+            Some("its proof does not link it to the root hash".to_string()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_check() {
+        let dir = TempDir::new().unwrap();
+        let cache = ProofCache::new(dir.path().join("cache"), false);
+        let contents = [&b"one"[..], b"two", b"three", b"four"];
+        let leaves = contents
+            .iter()
+            .map(|content| utils::decode_hash(&utils::sha256(content)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let tree = MerkleTree::from_leaves(leaves).unwrap();
+        let root_hash = hex::encode(tree.root().unwrap());
+        let mut upload = Upload {
+            files: ["one", "sub/two", "three", "four"]
+                .iter()
+                .zip(contents)
+                .enumerate()
+                .map(|(index, (name, content))| FileRecord {
+                    name: name.to_string(),
+                    sha256: Some(utils::sha256(content)),
+                    proof: tree
+                        .proof(index)
+                        .ok()
+                        .map(|proof| proof.iter().map(hex::encode).collect()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        upload.files[2].proof = None;
+        let other = Upload {
+            files: vec![FileRecord {
+                name: "other".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("one"), "one").unwrap();
+        fs::write(dir.path().join("sub/two"), "changed").unwrap();
+        fs::write(dir.path().join("three"), "three").unwrap();
+        let uploads = [(root_hash.as_str(), &upload), ("aa", &other)];
+        let report = check(dir.path(), &uploads, None, &cache).unwrap();
+        assert!(!report.verified);
+        // The upload none of whose files are downloaded is skipped
+        assert_eq!(report.skipped, 1);
+        let statuses = report.uploads[0]
+            .files
+            .iter()
+            .map(|file| file.status)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                AuditStatus::Ok,
+                AuditStatus::Modified,
+                AuditStatus::Unverifiable,
+                AuditStatus::Missing
+            ]
+        );
+    }
+}
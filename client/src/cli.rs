@@ -1,9 +1,174 @@
-use clap::{Parser, Subcommand};
+use crate::completions::Shell;
+use crate::logger::LogFormat;
+use crate::output::OutputFormat;
+use crate::throttle;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(author, about, version)]
 pub struct Args {
+    /// The directory holding the uploads database and the downloaded files
+    /// [default: the platform data directory, e.g.
+    /// ~/.local/share/file-guardian on Linux]
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        env = "FILE_GUARDIAN_STORE_DIR"
+    )]
+    pub store_dir: Option<PathBuf>,
+    /// The configuration file [default: config.toml in the platform config
+    /// directory, e.g. ~/.config/file-guardian on Linux]
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        env = "FILE_GUARDIAN_CONFIG"
+    )]
+    pub config: Option<PathBuf>,
+    /// The server profile from the configuration file to use
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "NAME",
+        env = "FILE_GUARDIAN_PROFILE"
+    )]
+    pub profile: Option<String>,
+    /// The format of the output
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub output: OutputFormat,
+    /// Connect to the server over TLS
+    #[arg(long, global = true)]
+    pub tls: bool,
+    /// A PEM file of the certificate authorities to trust over TLS [default:
+    /// the profile CA file, or the Mozilla root certificates]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub tls_ca: Option<PathBuf>,
+    /// The name the server certificate must be valid for [default: the host
+    /// of the server address]
+    #[arg(long, global = true, value_name = "NAME")]
+    pub tls_server_name: Option<String>,
+    /// A PEM certificate chain to present to servers requiring TLS client
+    /// certificates, with --tls-key [default: the profile certificate]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub tls_cert: Option<PathBuf>,
+    /// The PEM private key of the TLS client certificate [default: the
+    /// profile key]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub tls_key: Option<PathBuf>,
+    /// The token to authenticate to the server with [default: the profile
+    /// token, or the one stored by login]
+    #[arg(
+        long,
+        global = true,
+        value_name = "TOKEN",
+        env = "FILE_GUARDIAN_TOKEN",
+        hide_env_values = true
+    )]
+    pub token: Option<String>,
+    /// The file of the key encrypting uploads and decrypting their
+    /// downloads, 64 hex characters [default: the profile key file, or the
+    /// key stored by login]
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        env = "FILE_GUARDIAN_ENCRYPTION_KEY_FILE"
+    )]
+    pub encryption_key_file: Option<PathBuf>,
+    /// The time to wait for a connection to the server, 0 to wait forever
+    /// [default: the profile timeout, or 10s]
+    #[arg(
+        long,
+        global = true,
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration
+    )]
+    pub connect_timeout: Option<Duration>,
+    /// The time to wait for the server to send or accept data, 0 to wait
+    /// forever [default: the profile timeout, or 2m]
+    #[arg(
+        long,
+        global = true,
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration
+    )]
+    pub timeout: Option<Duration>,
+    /// The number of times a network operation failing with a transient
+    /// error, such as a connection reset, is retried
+    #[arg(long, global = true, value_name = "N", default_value_t = 3)]
+    pub retries: u32,
+    /// The delay before the first retry, doubled before each of the next ones
+    #[arg(
+        long,
+        global = true,
+        value_name = "DURATION",
+        default_value = "500ms",
+        value_parser = humantime::parse_duration
+    )]
+    pub retry_backoff: Duration,
+    /// The maximum delay between two retries
+    #[arg(
+        long,
+        global = true,
+        value_name = "DURATION",
+        default_value = "30s",
+        value_parser = humantime::parse_duration
+    )]
+    pub retry_max_backoff: Duration,
+    /// Wait exactly the backoff delay between retries, rather than a random
+    /// duration of up to it
+    #[arg(long, global = true)]
+    pub no_retry_jitter: bool,
+    /// The bandwidth limit of uploads and downloads, shared by concurrent
+    /// transfers, e.g. 10MB/s or 512KiB/s [default: the profile limit, or
+    /// none]
+    #[arg(
+        long,
+        global = true,
+        value_name = "RATE",
+        value_parser = throttle::parse_rate
+    )]
+    pub limit_rate: Option<u64>,
+    /// Compress the contents of files with zstd in transit, at the given
+    /// level from 1 to 22, if the server supports it, e.g. over slow links;
+    /// unlike --compress, the files are hashed and stored as they are
+    /// [default: the profile level, or none]
+    #[arg(long, global = true, value_name = "LEVEL", num_args = 0..=1, default_missing_value = "3", value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub wire_compression: Option<i32>,
+    /// Only download and verify files of the root hashes published by this
+    /// trust anchor: a file of root hashes, an https:// URL of one, or a
+    /// dns:NAME whose TXT records hold them; may be given several times
+    /// [default: the profile trust anchors]
+    #[arg(long, global = true, value_name = "SOURCE", action = ArgAction::Append)]
+    pub trust_anchor: Vec<String>,
+    /// The public key the server must sign the roots of the uploads with,
+    /// 64 hex characters, as logged by a server run with
+    /// --identity-key-file [default: the profile server key, or any key]
+    #[arg(
+        long,
+        global = true,
+        value_name = "KEY",
+        env = "FILE_GUARDIAN_SERVER_KEY"
+    )]
+    pub server_key: Option<String>,
+    /// Fetch the proofs of files from the server rather than taking them
+    /// from the proof cache of the store, and leave the cache as it is
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+    /// Log what the client does to stderr: -v for the operations, -vv for
+    /// the requests sent to the server, -vvv for everything
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Only log errors, not warnings
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// The format of the log messages
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub log_format: LogFormat,
     #[clap(subcommand)]
     pub subcmd: SubCommand,
 }
@@ -11,36 +176,520 @@ pub struct Args {
 #[derive(Subcommand)]
 pub enum SubCommand {
     /// List all the uploaded files
-    List,
+    List {
+        /// Only list the files matching this glob pattern
+        #[arg(long, value_name = "PATTERN")]
+        filter: Option<String>,
+        /// Only list the uploads whose root hash starts with this prefix
+        #[arg(long, value_name = "PREFIX")]
+        root: Option<String>,
+        /// Order the uploads and their files [default: by root hash]
+        #[arg(long, value_enum)]
+        sort: Option<SortKey>,
+        /// Reverse the order
+        #[arg(long)]
+        reverse: bool,
+        /// Only list the uploads with this tag, and with every tag if given
+        /// several times
+        #[arg(short, long, value_name = "TAG", action = clap::ArgAction::Append)]
+        tag: Vec<String>,
+        /// List the uploads the server holds instead, flagging the ones
+        /// missing from the database, missing from the server, or recorded
+        /// with other files
+        #[arg(long, conflicts_with_all = ["filter", "root", "sort", "reverse", "tag"])]
+        remote: bool,
+        /// The websocket server address, with `--remote` [default: the
+        /// profile address, or 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR", requires = "remote")]
+        server_addr: Option<String>,
+    },
     /// Upload one or more files(s) to the server
     Upload {
+        /// The files, directories or glob patterns (e.g. "logs/**/*.gz") to
+        /// upload, directories are uploaded recursively, and `-` uploads
+        /// stdin
         #[arg(short, long, value_name = "FILE", action = clap::ArgAction::Append)]
-        #[clap(required = true)]
+        #[clap(required_unless_present = "url")]
         files: Vec<PathBuf>,
-        /// The websocket server address
-        #[arg(
-            short,
-            long,
-            value_name = "SERVER_ADDR",
-            default_value = "127.0.0.1:2345"
-        )]
-        server_addr: String,
-    },
-    /// Download a file from the server
+        /// An http:// or https:// URL whose resource is streamed to the
+        /// server, without a local copy, and recorded with the URL; may be
+        /// given several times
+        #[arg(long, value_name = "URL", action = clap::ArgAction::Append, conflicts_with_all = ["files", "delete", "encrypt", "compress"])]
+        url: Vec<String>,
+        /// Glob pattern of files or directories to leave out of the upload
+        #[arg(short, long, value_name = "PATTERN", action = clap::ArgAction::Append)]
+        exclude: Vec<String>,
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// The name stdin is uploaded under
+        #[arg(long, value_name = "NAME", default_value = "stdin")]
+        stdin_name: String,
+        /// Delete the original files once the server has acknowledged the
+        /// upload with a matching root hash, moving them to the trash of the
+        /// store, from which `undelete` restores them
+        #[arg(long)]
+        delete: bool,
+        /// How long the files deleted with --delete are kept in the trash
+        /// before they are purged
+        #[arg(long, value_name = "DURATION", default_value = "30days", value_parser = humantime::parse_duration, requires = "delete")]
+        trash_retention: Duration,
+        /// How long the server keeps the upload before deleting it, e.g.
+        /// "30days" [default: the retention of the server, if any]
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, conflicts_with = "dry_run")]
+        retention: Option<Duration>,
+        /// Once the files are sent, get the proof of each of them from the
+        /// server and verify it against the local hashes, before recording
+        /// the upload
+        #[arg(long)]
+        verify: bool,
+        /// The number of files hashed and sent concurrently, each over its
+        /// own connection
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        jobs: u16,
+        /// Encrypt the files before they are hashed and sent, so that the
+        /// server never sees their content
+        #[arg(long)]
+        encrypt: bool,
+        /// Compress the files with zstd before they are hashed and sent, at
+        /// the given level from 1 to 22
+        #[arg(long, value_name = "LEVEL", num_args = 0..=1, default_missing_value = "3", value_parser = clap::value_parser!(i32).range(1..=22))]
+        compress: Option<i32>,
+        /// Only hash the files and print the root hash, the hash of every
+        /// file and the total size that would be uploaded, without
+        /// contacting the server
+        #[arg(long, conflicts_with = "encrypt")]
+        dry_run: bool,
+        /// A tag to record the upload with, e.g. "backups", to find it with
+        /// `list --tag`
+        #[arg(short, long, value_name = "TAG", action = clap::ArgAction::Append)]
+        tag: Vec<String>,
+        /// Send the files even if the server already holds the same batch,
+        /// without hashing them first to look it up
+        #[arg(long)]
+        no_dedup: bool,
+        /// Queue the upload on the daemon of the store, which uploads it to
+        /// its server over the connections it keeps ready, rather than
+        /// uploading it from this process
+        #[arg(long, conflicts_with_all = ["url", "server_addr", "encrypt", "compress", "dry_run"])]
+        daemon: bool,
+        /// Wait for the daemon to upload the files, rather than only for it
+        /// to queue them, and fail if the upload fails
+        #[arg(long, requires = "daemon")]
+        wait: bool,
+    },
+    /// Watch a directory and upload the files created or modified in it, in
+    /// batches recorded with their own root hash
+    Watch {
+        /// The directory to watch, recursively
+        dir: PathBuf,
+        /// Glob pattern of files or directories to leave out of the uploads
+        #[arg(short, long, value_name = "PATTERN", action = clap::ArgAction::Append)]
+        exclude: Vec<String>,
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// How long to wait without changes before uploading a batch
+        #[arg(long, value_name = "DURATION", default_value = "5s", value_parser = humantime::parse_duration)]
+        debounce: Duration,
+        /// The number of files hashed and sent concurrently, each over its
+        /// own connection
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        jobs: u16,
+        /// A tag to record every batch with
+        #[arg(short, long, value_name = "TAG", action = clap::ArgAction::Append)]
+        tag: Vec<String>,
+    },
+    /// Upload the files of a directory that are new or changed since it was
+    /// last synced to the server, in a batch recorded with its own root hash
+    Sync {
+        /// The directory to sync, recursively
+        dir: PathBuf,
+        /// Glob pattern of files or directories to leave out of the sync
+        #[arg(short, long, value_name = "PATTERN", action = clap::ArgAction::Append)]
+        exclude: Vec<String>,
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// Compare the SHA-256 of every file with the last sync, rather than
+        /// only of the files whose modification time changed
+        #[arg(short, long)]
+        checksum: bool,
+        /// Once the changed files are sent, get the proof of each of them
+        /// from the server and verify it against the local hashes
+        #[arg(long)]
+        verify: bool,
+        /// The number of files hashed and sent concurrently, each over its
+        /// own connection
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        jobs: u16,
+        /// Only print the files that would be uploaded
+        #[arg(long)]
+        dry_run: bool,
+        /// A tag to record the batch with
+        #[arg(short, long, value_name = "TAG", action = clap::ArgAction::Append)]
+        tag: Vec<String>,
+    },
+    /// Download one or more files of an upload from the server
     Download {
+        /// The name of a file to download; may be given several times
+        #[arg(short, long, value_name = "FILE", action = ArgAction::Append, required_unless_present = "files_from")]
+        file: Vec<String>,
+        /// A file listing the names of the files to download, one per line,
+        /// or `-` for stdin
+        #[arg(long, value_name = "LIST")]
+        files_from: Option<PathBuf>,
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// The root hash of the collection of files where the file is located
+        #[arg(short, long)]
+        root_hash: String,
+        /// The file to download to, or the directory to download it to
+        /// under its uploaded path, if it is one, ends with `/` or several
+        /// files are downloaded [default: the current directory]
+        #[arg(short, long, value_name = "PATH")]
+        out: Option<PathBuf>,
+        /// Replace the files that already exist
+        #[arg(long)]
+        overwrite: bool,
+        /// The number of ranges of a large file downloaded concurrently,
+        /// each over its own connection
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        jobs: u16,
+    },
+    /// Find the uploaded files by name across every upload, and download one
+    /// of them
+    Find {
+        /// The name of the files, or a glob pattern, matched against the
+        /// base name of the uploaded files as well as their whole name
+        name: String,
+        /// Only find the files of the uploads the server still holds
+        #[arg(long)]
+        remote: bool,
+        /// Download the file found, asking which one on a terminal if
+        /// several are
+        #[arg(short, long)]
+        download: bool,
+        /// Download the file with this number in the list of files found
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+        pick: Option<u64>,
+        /// The websocket server address [default: the address the file was
+        /// uploaded to, the profile address, or 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// The file to download to, or the directory to download it to
+        /// under its uploaded path, if it is one or ends with `/` [default:
+        /// the current directory]
+        #[arg(short, long, value_name = "PATH")]
+        out: Option<PathBuf>,
+        /// Replace the file if it already exists
+        #[arg(long)]
+        overwrite: bool,
+        /// The number of ranges of a large file downloaded concurrently,
+        /// each over its own connection
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        jobs: u16,
+    },
+    /// Download every file of an upload from the server
+    DownloadAll {
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// The root hash of the collection of files to download
+        #[arg(short, long)]
+        root_hash: String,
+        /// The directory to download the files to [default: the current
+        /// directory]
+        #[arg(short, long, value_name = "DIR")]
+        out: Option<PathBuf>,
+        /// Replace the files that already exist
+        #[arg(long)]
+        overwrite: bool,
+        /// The number of ranges of a large file downloaded concurrently,
+        /// each over its own connection
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        jobs: u16,
+    },
+    /// Download a file from the server and write it to stdout, once its
+    /// proof is verified
+    Cat {
         #[arg(short, long, value_name = "FILE")]
         #[clap(required = true)]
         file: String,
-        /// The websocket server address
-        #[arg(
-            short,
-            long,
-            value_name = "SERVER_ADDR",
-            default_value = "127.0.0.1:2345"
-        )]
-        server_addr: String,
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
         /// The root hash of the collection of files where the file is located
         #[arg(short, long)]
         root_hash: String,
     },
+    /// Get the Merkle proof of an uploaded file from the server, without the
+    /// file, and save it
+    Prove {
+        /// The name of the file to prove
+        #[arg(short, long, value_name = "FILE")]
+        file: String,
+        /// The root hash of the upload the file belongs to
+        #[arg(short, long)]
+        root_hash: String,
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// The file to save the proof to [default: the base name of the file
+        /// followed by .proof.json, in the current directory]
+        #[arg(short, long, value_name = "PATH")]
+        out: Option<PathBuf>,
+    },
+    /// Verify a local file against the root hash of its upload, using the
+    /// proof recorded in the database, without contacting the server
+    Verify {
+        /// The file to verify
+        #[arg(short, long, value_name = "PATH")]
+        file: PathBuf,
+        /// The root hash of the upload the file belongs to
+        #[arg(short, long)]
+        root_hash: String,
+        /// The name the file was uploaded under [default: the name matching
+        /// the end of the path]
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// Print what an upload commits to: its Merkle tree, the leaf hash of
+    /// every file and when it was uploaded, as recorded in the database, and
+    /// what the server holds with `--remote`
+    Info {
+        /// The root hash of the upload
+        #[arg(short, long)]
+        root_hash: String,
+        /// Also ask the server for the upload, and for the proof of every
+        /// file, flagging the files whose leaf is not the one recorded
+        #[arg(long)]
+        remote: bool,
+        /// The websocket server address, with `--remote` [default: the
+        /// address the upload was recorded with, the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR", requires = "remote")]
+        server_addr: Option<String>,
+    },
+    /// Compare two directories by content: hash their files into a Merkle
+    /// tree per directory, and list the files changed, added and removed
+    /// from the first to the second, the subdirectories with the same hash
+    /// being identical without comparing their files
+    Diff {
+        /// The directory compared from
+        dir_a: PathBuf,
+        /// The directory compared to
+        dir_b: PathBuf,
+        /// Glob pattern of files or directories to leave out of the
+        /// comparison
+        #[arg(short, long, value_name = "PATTERN", action = ArgAction::Append)]
+        exclude: Vec<String>,
+    },
+    /// Audit the downloaded files of the recorded uploads: hash every file
+    /// again and check it against the root hash of its upload with its
+    /// proof, without contacting the server, reporting the modified, missing
+    /// and unverifiable files
+    Check {
+        /// The directory the files were downloaded to [default: the current
+        /// directory, where `download-all` writes them]
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+        /// The root hash of an upload to audit [default: every upload with
+        /// files in the directory]; may be given several times
+        #[arg(short, long, action = ArgAction::Append)]
+        root_hash: Vec<String>,
+    },
+    /// Delete an upload from the server, and remove it from the database
+    Delete {
+        /// The root hash of the upload to delete
+        #[arg(short, long)]
+        root_hash: String,
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+    },
+    /// Print the storage used on the server by the uploads of the client, and
+    /// its quota and the space remaining if it has one
+    Usage {
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+    },
+    /// Restore the original files of an upload deleted by `upload --delete`
+    /// from the trash, to the paths they were uploaded from
+    Undelete {
+        /// The root hash of the upload whose files are restored
+        #[arg(short, long, required_unless_present = "list")]
+        root_hash: Option<String>,
+        /// The name of a file to restore [default: every file of the upload];
+        /// may be given several times
+        #[arg(short, long, value_name = "NAME", action = ArgAction::Append)]
+        file: Vec<String>,
+        /// List the files in the trash, and until when they are kept, instead
+        #[arg(long, conflicts_with_all = ["root_hash", "file"])]
+        list: bool,
+    },
+    /// Browse the uploads in an interactive terminal interface, and download,
+    /// verify or delete them
+    Tui {
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+    },
+    /// Export or check the SHA-256 of the files of an upload, in the format
+    /// of sha256sum
+    Manifest {
+        #[clap(subcommand)]
+        subcmd: ManifestCommand,
+    },
+    /// Export the verification bundle of an upload: a tar archive of its
+    /// root hash, its manifest and the proof of every file, but not the
+    /// data, to check a copy of the files with `verify-bundle` offline
+    ExportBundle {
+        /// The root hash of the upload
+        #[arg(short, long)]
+        root_hash: String,
+        /// The file to export to [default: <ROOT_HASH>.bundle.tar]
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Check a directory against a bundle written by `export-bundle`, fully
+    /// offline: check that the proofs of the bundle link its files to its
+    /// root hash, then hash every file of the directory and check it against
+    /// its proof
+    VerifyBundle {
+        /// The bundle to verify against
+        bundle: PathBuf,
+        /// The directory the files are relative to
+        dir: PathBuf,
+    },
+    /// Store the token of a server, read from the terminal or stdin, or the
+    /// encryption key, in the platform keyring
+    Login {
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// Store the key of --encryption-key-file, or of the profile key
+        /// file, as the encryption key instead, so that the file can be
+        /// deleted
+        #[arg(long)]
+        encryption_key: bool,
+    },
+    /// Remove the token of a server, or the encryption key, from the
+    /// platform keyring
+    Logout {
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// Remove the encryption key instead
+        #[arg(long)]
+        encryption_key: bool,
+    },
+    /// Run or control the daemon of the store, which keeps connections to
+    /// its server ready, uploads the files queued with `upload --daemon` and
+    /// verifies the uploads periodically
+    Daemon {
+        #[clap(subcommand)]
+        subcmd: DaemonCommand,
+    },
+    /// Manage the uploads database
+    Db {
+        #[clap(subcommand)]
+        subcmd: DbCommand,
+    },
+    /// Print the completion script of a shell, e.g. to save it to
+    /// /usr/share/bash-completion/completions/client
+    Completions {
+        /// The shell to complete the commands in
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the man page of the client, in roff, e.g. to read it with
+    /// `man -l -` or save it to /usr/local/share/man/man1/client.1
+    Man,
+}
+
+#[derive(Subcommand)]
+pub enum DaemonCommand {
+    /// Run the daemon in the foreground, listening on the `daemon.sock`
+    /// socket of the store until stopped with `daemon stop` or Ctrl-C
+    Run {
+        /// The websocket server address [default: the profile address, or
+        /// 127.0.0.1:2345]
+        #[arg(short, long, value_name = "SERVER_ADDR")]
+        server_addr: Option<String>,
+        /// The number of connections to the server kept ready
+        #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u16).range(1..))]
+        connections: u16,
+        /// How often to verify, with the proofs served by the server, every
+        /// upload recorded for it [default: never]
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        verify_every: Option<Duration>,
+    },
+    /// Print the jobs of the daemon, its ready connections and its last
+    /// verification
+    Status,
+    /// Stop the daemon once the upload it runs, if any, is done, dropping
+    /// the pending ones
+    Stop,
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Export the uploads database to a file
+    Export {
+        /// The file to export to
+        file: PathBuf,
+    },
+    /// Import uploads from a file written by `db export`
+    Import {
+        /// The file to import
+        file: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ManifestCommand {
+    /// Export the SHA-256 of the files of an upload, for `sha256sum -c`
+    Export {
+        /// The root hash of the upload
+        #[arg(short, long)]
+        root_hash: String,
+        /// The file to export to [default: stdout]
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Check local files against a manifest, like `sha256sum -c`
+    Check {
+        /// The manifest, e.g. written by `manifest export` or `sha256sum`
+        file: PathBuf,
+        /// The directory the names of the manifest are relative to
+        /// [default: the current directory]
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+}
+
+/// How to order the uploads listed by `list`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortKey {
+    /// By file name
+    Name,
+    /// By upload date, oldest first
+    Date,
+    /// By size, smallest first
+    Size,
 }
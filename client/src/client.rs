@@ -1,11 +1,650 @@
+use crate::connect;
+use crate::db::RootSignature;
+use crate::http::{self, Url};
+use crate::progress::{FileProgress, Progress, CHUNK_SIZE};
+use crate::retry::RetryPolicy;
+use crate::throttle::Throttle;
+use crate::tls::Tls;
+use crate::utils;
 use anyhow::Result;
-use std::io::prelude::*;
-use std::io::Write;
-use std::net::TcpStream;
+use merkle_tree::Proof;
+use protocol::compression;
+use protocol::{
+    Capabilities, FileRef, Hash, Request, Response, MIN_VERSION, VERSION,
+};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+
+/// The time to wait for a connection to the server, unless configured.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The time to wait for the server to send or accept data, unless
+/// configured.
+pub const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The capabilities of the client, sent in the handshake.
+const CAPABILITIES: Capabilities = Capabilities::AUTH
+    .union(Capabilities::CHUNKING)
+    .union(Capabilities::USAGE)
+    .union(Capabilities::EXPIRY)
+    .union(Capabilities::CONTENTS)
+    .union(Capabilities::STORED)
+    .union(Capabilities::SIGNED)
+    .union(Capabilities::REQUESTS);
+
+/// How long a connection of a [`Pool`] is kept ready before it is replaced,
+/// in case the server or the network dropped it meanwhile.
+const MAX_IDLE: Duration = Duration::from_secs(300);
+
+/// The maximum size of the status line and headers of a response of the
+/// HTTP API.
+const MAX_HTTP_HEAD_SIZE: usize = 64 * 1024;
+
+/// The maximum size of a JSON reply of the HTTP API, e.g. a list of uploads.
+const MAX_HTTP_JSON_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How long to wait for the server before giving up. `None` waits forever.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// The time to wait for a connection to be established.
+    pub connect: Option<Duration>,
+    /// The time to wait for a single read or write to make progress, e.g. for
+    /// the server to acknowledge an upload.
+    pub io: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Creates timeouts, a zero duration meaning waiting forever.
+    pub fn new(connect: Duration, io: Duration) -> Self {
+        let some = |timeout: Duration| (!timeout.is_zero()).then_some(timeout);
+        Self {
+            connect: some(connect),
+            io: some(io),
+        }
+    }
+}
+
+/// The error of a network operation that timed out, wrapped in an
+/// [`io::Error`] of kind [`ErrorKind::TimedOut`].
+#[derive(Debug)]
+pub struct TimeoutError {
+    /// What timed out, e.g. `connecting to the server`.
+    operation: &'static str,
+    timeout: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Timed out after {} {}",
+            humantime::format_duration(self.timeout),
+            self.operation
+        )
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// The kind of an error reported by the server in response to a request.
+pub use protocol::ErrorKind as ServerErrorKind;
+
+/// Returns the exit code of commands failing with an error of a kind, from
+/// `sysexits.h`.
+pub fn exit_code(kind: ServerErrorKind) -> u8 {
+    match kind {
+        // EX_UNAVAILABLE
+        ServerErrorKind::Failed => 69,
+        // EX_NOINPUT
+        ServerErrorKind::NotFound => 66,
+        // EX_NOPERM
+        ServerErrorKind::Unauthorized => 77,
+        // EX_PROTOCOL
+        ServerErrorKind::InvalidRequest => 76,
+        // EX_CANTCREAT
+        ServerErrorKind::QuotaExceeded => 73,
+        // EX_DATAERR
+        ServerErrorKind::TooLarge => 65,
+        // EX_TEMPFAIL
+        ServerErrorKind::ReadOnly => 75,
+    }
+}
+
+/// An error reported by the server, with the reason it gave.
+#[derive(Debug)]
+pub struct ServerError {
+    pub kind: ServerErrorKind,
+    reason: String,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = &self.reason;
+        match self.kind {
+            ServerErrorKind::Failed => write!(f, "Server error: {}", reason),
+            ServerErrorKind::NotFound => {
+                write!(f, "Not found on the server: {}", reason)
+            }
+            ServerErrorKind::Unauthorized => {
+                write!(f, "Server rejected the client: {}", reason)
+            }
+            ServerErrorKind::InvalidRequest => {
+                write!(f, "Server rejected the request: {}", reason)
+            }
+            ServerErrorKind::QuotaExceeded => {
+                write!(f, "Quota exceeded: {}", reason)
+            }
+            ServerErrorKind::TooLarge => {
+                write!(f, "Too large for the server: {}", reason)
+            }
+            ServerErrorKind::ReadOnly => {
+                write!(f, "Server is read-only: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Returns the error reported by the server that caused an error, if any.
+pub fn server_error(error: &anyhow::Error) -> Option<&ServerError> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<ServerError>())
+}
+
+/// Returns whether an error is caused by a network operation timing out.
+pub fn is_timeout(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|error| error.kind() == ErrorKind::TimedOut)
+    })
+}
+
+/// An upload held by the server.
+pub use protocol::StoredUpload;
+
+/// An upload committed by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Committed {
+    /// The root hash computed by the server over the stored files, as a hex
+    /// string.
+    pub root_hash: String,
+    /// Whether the server already held the upload, e.g. when a failed upload
+    /// is retried, an older server never telling so.
+    pub already_stored: bool,
+    /// The signature of the root hash by the server, if it signs them.
+    pub signature: Option<RootSignature>,
+}
+
+/// A server, and how to connect to it.
+#[derive(Clone)]
+pub(crate) struct Server {
+    /// The address of the server, in the format `host:port`.
+    pub addr: String,
+    pub timeouts: Timeouts,
+    /// The TLS settings, if the server is reached over TLS.
+    pub tls: Option<Tls>,
+    /// The token the client authenticates with, if any.
+    pub token: Option<String>,
+    /// When to retry operations failing with a transient error.
+    pub retry: RetryPolicy,
+    /// The bandwidth limit of the transfers, if they are limited.
+    pub throttle: Option<Throttle>,
+    /// The zstd level the contents of files are compressed with in transit,
+    /// if the server supports it, or `None` not to compress them.
+    pub compression: Option<i32>,
+    /// The connections kept ready for the operations, if any.
+    pub pool: Option<Arc<Pool>>,
+    /// The URL of the HTTP API of the server, if it is reached over the API
+    /// rather than over the TCP protocol, `addr` being the URL then.
+    pub http: Option<Url>,
+    /// The public key the server must sign the roots of the uploads with, as
+    /// hex, if it is pinned.
+    pub server_key: Option<String>,
+}
+
+/// Connections to a server established ahead of the operations that use
+/// them, so that an operation does not wait for the connection, the TLS
+/// handshake and the authentication, e.g. in the daemon.
+///
+/// Every connection taken serves a single operation, and is closed once it
+/// ends, so it is replaced by [`Server::keep_warm`].
+pub(crate) struct Pool {
+    /// The number of connections kept ready.
+    size: usize,
+    /// The connections ready to be used, with the time they were made.
+    ready: Mutex<Vec<(Instant, TcpClient)>>,
+    /// Notified whenever a connection is taken.
+    taken: Notify,
+}
+
+impl Pool {
+    /// Creates a pool of `size` connections, made by [`Server::keep_warm`].
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            ready: Mutex::new(vec![]),
+            taken: Notify::new(),
+        }
+    }
+
+    /// Returns the number of connections ready to be used.
+    pub fn ready(&self) -> usize {
+        self.ready.lock().unwrap().len()
+    }
+
+    /// Takes the most recent connection ready to be used, if any.
+    fn take(&self) -> Option<TcpClient> {
+        let mut ready = self.ready.lock().unwrap();
+        ready.retain(|(made, _)| made.elapsed() < MAX_IDLE);
+        let client = ready.pop().map(|(_, client)| client);
+        self.taken.notify_one();
+        client
+    }
+}
+
+/// The connection of operations run one after the other with
+/// [`Server::run_over`], e.g. the downloads of the files of an upload, made
+/// by the first and reused by the next ones, if the server serves several
+/// requests per connection.
+#[derive(Default)]
+pub(crate) struct Connection(Mutex<Option<TcpClient>>);
+
+impl Server {
+    /// Runs an operation over a new connection, retrying it over another
+    /// connection while it fails with a transient error, and logging a
+    /// warning about each retry.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - What the operation does, e.g. `download of a.txt`.
+    /// * `run` - The operation, given a new connection, which must be safe
+    ///   to repeat.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt.
+    pub async fn run<T, F: Future<Output = Result<T>>>(
+        &self,
+        operation: &str,
+        run: impl Fn(TcpClient) -> F,
+    ) -> Result<T> {
+        let run = &run;
+        self.retried(operation, |attempt| async move {
+            run(self.client(attempt).await?).await
+        })
+        .await
+    }
+
+    /// Runs an operation like [`Server::run`], over the connection the
+    /// operation run before it left in `connection`, if any, rather than a
+    /// new one. Once the operation succeeds, its connection is left there
+    /// for the next operation, if the server serves several requests per
+    /// connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - What the operation does, e.g. `download of a.txt`.
+    /// * `connection` - The connection of the operations.
+    /// * `run` - The operation, given a connection, which must be safe to
+    ///   repeat, returning the connection along with its result.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt.
+    pub async fn run_over<T, F: Future<Output = Result<(T, TcpClient)>>>(
+        &self,
+        operation: &str,
+        connection: &Connection,
+        run: impl Fn(TcpClient) -> F,
+    ) -> Result<T> {
+        let run = &run;
+        self.retried(operation, |attempt| async move {
+            // The server may have closed the connection since, e.g. once it
+            // stayed idle, so retries make new ones
+            let kept = match attempt {
+                0 => connection.0.lock().unwrap().take(),
+                _ => None,
+            };
+            let client = match kept {
+                Some(client) => client,
+                None => self.client(attempt).await?,
+            };
+            let (value, client) = run(client).await?;
+            if client.capabilities.contains(Capabilities::REQUESTS) {
+                *connection.0.lock().unwrap() = Some(client);
+            }
+            Ok(value)
+        })
+        .await
+    }
+
+    /// Runs the attempts of an operation, logging each, retrying while they
+    /// fail with a transient error, and logging a warning about each retry.
+    async fn retried<T, F: Future<Output = Result<T>>>(
+        &self,
+        operation: &str,
+        mut attempt: impl FnMut(u32) -> F,
+    ) -> Result<T> {
+        self.retry
+            .run(
+                |retry| {
+                    log::debug!(
+                        "Starting {} (attempt {})",
+                        operation,
+                        retry + 1
+                    );
+                    attempt(retry)
+                },
+                |error, retry| {
+                    log::warn!(
+                        "{} failed ({}), retrying ({} of {})",
+                        operation,
+                        error,
+                        retry,
+                        self.retry.retries
+                    )
+                },
+            )
+            .await
+    }
+
+    /// Makes the connection of an attempt of an operation: one of the pool
+    /// for the first attempt, if any is ready, or a new one. A connection
+    /// kept ready may have been dropped since, so retries make new ones.
+    async fn client(&self, attempt: u32) -> Result<TcpClient> {
+        let ready = match attempt {
+            0 => self.pool.as_ref().and_then(|pool| pool.take()),
+            _ => None,
+        };
+        match ready {
+            Some(client) => Ok(client),
+            None => self.connect().await,
+        }
+    }
+
+    /// Commits the files of an upload, see [`TcpClient::commit`], retrying
+    /// while it fails with a transient error.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server cannot commit the files, or if the
+    /// server key is pinned and the server did not sign the root hash with
+    /// it.
+    pub async fn commit(&self, hashes: &[String]) -> Result<Committed> {
+        let committed = self
+            .run("commit of the upload", |client| client.commit(hashes))
+            .await?;
+        let Some(pinned) = &self.server_key else {
+            return Ok(committed);
+        };
+        match &committed.signature {
+            Some(signature) if signature.key == *pinned => Ok(committed),
+            Some(signature) => Err(anyhow::anyhow!(
+                "The server signed root hash {} with key {}, not the pinned \
+                 server key {}",
+                committed.root_hash,
+                signature.key,
+                pinned
+            )),
+            None => Err(anyhow::anyhow!(
+                "The server did not sign root hash {}, and the server key is \
+                 pinned",
+                committed.root_hash
+            )),
+        }
+    }
+
+    /// Keeps the connections of the pool ready, replacing those taken or
+    /// kept too long, until the returned future is dropped. Connections
+    /// that fail are retried after the retry backoff.
+    pub async fn keep_warm(&self) {
+        let Some(pool) = &self.pool else {
+            return std::future::pending().await;
+        };
+        let mut failures = 0;
+        loop {
+            let missing = {
+                let mut ready = pool.ready.lock().unwrap();
+                ready.retain(|(made, _)| made.elapsed() < MAX_IDLE);
+                pool.size.saturating_sub(ready.len())
+            };
+            if missing == 0 {
+                // Replace the connections before they are kept too long
+                let _ =
+                    tokio::time::timeout(MAX_IDLE, pool.taken.notified()).await;
+                continue;
+            }
+            match self.connect().await {
+                Ok(client) => {
+                    failures = 0;
+                    pool.ready.lock().unwrap().push((Instant::now(), client));
+                }
+                Err(e) => {
+                    log::debug!("Could not connect to {}: {}", self.addr, e);
+                    failures += 1;
+                    tokio::time::sleep(self.retry.delay(failures)).await;
+                }
+            }
+        }
+    }
+
+    /// Makes a new connection to the server.
+    async fn connect(&self) -> Result<TcpClient> {
+        match &self.http {
+            Some(url) => {
+                TcpClient::over_http(
+                    url,
+                    self.timeouts,
+                    self.tls.as_ref(),
+                    self.token.as_deref(),
+                    self.throttle.as_ref(),
+                )
+                .await
+            }
+            None => {
+                TcpClient::new(
+                    &self.addr,
+                    self.timeouts,
+                    self.tls.as_ref(),
+                    self.token.as_deref(),
+                    self.throttle.as_ref(),
+                    self.compression,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// A connection to the server, in cleartext or over TLS.
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// A stream to the server whose reads and writes time out, reported as
+/// [`TimeoutError`]s.
+///
+/// When the bandwidth is limited, data is transferred in chunks of
+/// [`CHUNK_SIZE`] bytes, each taken from the limit, and the timeout applies
+/// to every chunk rather than to the whole transfer.
+struct Stream {
+    inner: Box<dyn Transport>,
+    timeout: Option<Duration>,
+    throttle: Option<Throttle>,
+}
+
+impl Stream {
+    /// Connects to a server, and establishes the TLS session if any.
+    async fn open(
+        address: &str,
+        timeouts: Timeouts,
+        tls: Option<&Tls>,
+        throttle: Option<&Throttle>,
+    ) -> Result<Self> {
+        let connect = dial(address);
+        let stream =
+            timed(timeouts.connect, "connecting to the server", connect)
+                .await?;
+        log::debug!("Connected to {}", address);
+        let inner: Box<dyn Transport> = match tls {
+            Some(tls) => {
+                let stream = timed(
+                    timeouts.io,
+                    "establishing the TLS session",
+                    tls.connect(stream),
+                )
+                .await?;
+                log::debug!("Established the TLS session with {}", address);
+                Box::new(stream)
+            }
+            None => Box::new(stream),
+        };
+        Ok(Self {
+            inner,
+            timeout: timeouts.io,
+            throttle: throttle.cloned(),
+        })
+    }
+
+    /// Reads what the server sent, up to `buf.len()` bytes, first sending
+    /// what is buffered.
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.flush().await?;
+        let len = buf.len().min(CHUNK_SIZE);
+        let read = self.inner.read(&mut buf[..len]);
+        let len = timed(self.timeout, "reading from the server", read).await?;
+        if let Some(throttle) = &self.throttle {
+            throttle.consume(len).await;
+        }
+        Ok(len)
+    }
+
+    /// Fills `buf` from the server, first sending what is buffered, e.g. by
+    /// the TLS session, as a request must be sent before its reply is read.
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.flush().await?;
+        let timeout = self.timeout;
+        match &self.throttle {
+            Some(throttle) => {
+                for chunk in buf.chunks_mut(CHUNK_SIZE) {
+                    let read = self.inner.read_exact(chunk);
+                    timed(timeout, "reading from the server", read).await?;
+                    throttle.consume(chunk.len()).await;
+                }
+            }
+            None => {
+                let read = self.inner.read_exact(buf);
+                timed(timeout, "reading from the server", read).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let timeout = self.timeout;
+        match &self.throttle {
+            Some(throttle) => {
+                for chunk in buf.chunks(CHUNK_SIZE) {
+                    throttle.consume(chunk.len()).await;
+                    let write = self.inner.write_all(chunk);
+                    timed(timeout, "writing to the server", write).await?;
+                }
+                Ok(())
+            }
+            None => {
+                let write = self.inner.write_all(buf);
+                timed(timeout, "writing to the server", write).await
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        let timeout = self.timeout;
+        timed(timeout, "writing to the server", self.inner.flush()).await
+    }
+}
+
+impl protocol::Source for Stream {
+    fn read_exact(
+        &mut self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        Stream::read_exact(self, buf)
+    }
+}
+
+/// Connects to the server, at an address in the format `host:port`, or
+/// `unix:<path>` for a Unix socket.
+async fn dial(address: &str) -> io::Result<Box<dyn Transport>> {
+    match connect::unix_path(address) {
+        #[cfg(unix)]
+        Some(path) => {
+            Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+        }
+        #[cfg(not(unix))]
+        Some(_) => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Unix sockets are not supported",
+        )),
+        None => Ok(Box::new(connect::connect(address).await?)),
+    }
+}
+
+/// Runs a network operation, failing with a [`TimeoutError`] if it does not
+/// complete within `timeout`.
+async fn timed<T>(
+    timeout: Option<Duration>,
+    operation: &'static str,
+    future: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    TimeoutError { operation, timeout },
+                ))
+            }),
+        None => future.await,
+    }
+}
 
 /// A TCP client for uploading and downloading files to/from a server.
+///
+/// The server closes the connection once it has handled a request, so every
+/// request consumes the client.
 pub(crate) struct TcpClient {
-    stream: TcpStream,
+    stream: Stream,
+    /// The capabilities of the server, sent in the handshake.
+    capabilities: Capabilities,
+    /// The zstd level the contents of files are compressed with in transit,
+    /// if both ends asked for it.
+    compression: Option<i32>,
+    /// How the request is sent to the HTTP API of the server, if it is
+    /// reached over the API.
+    http: Option<HttpApi>,
+}
+
+/// How a [`TcpClient`] sends its request to the HTTP API of a server.
+struct HttpApi {
+    /// The host and the port of the server, for the `Host` header.
+    authority: String,
+    /// The path the API is served under, without a trailing `/`.
+    prefix: String,
+    /// The token sent in the `Authorization` header, if any.
+    token: Option<String>,
 }
 
 impl TcpClient {
@@ -14,69 +653,557 @@ impl TcpClient {
     /// # Arguments
     ///
     /// * `address` - The address to connect to, in the format `host:port`.
+    /// * `timeouts` - How long to wait for the server.
+    /// * `tls` - The TLS settings, to connect over TLS.
+    /// * `token` - The token to authenticate with, if the server requires
+    ///   one.
+    /// * `throttle` - The bandwidth limit of the connection, if any.
+    /// * `compression` - The zstd level to compress the contents of files
+    ///   with in transit, if the server supports it.
     ///
     /// # Errors
     ///
-    /// Returns an error if the connection fails.
-    pub fn new(address: &str) -> Result<Self> {
+    /// Returns an error if the connection fails or times out, if the TLS
+    /// handshake fails, if the server speaks no version of the protocol the
+    /// client speaks, or if the server rejects the token.
+    pub async fn new(
+        address: &str,
+        timeouts: Timeouts,
+        tls: Option<&Tls>,
+        token: Option<&str>,
+        throttle: Option<&Throttle>,
+        compression: Option<i32>,
+    ) -> Result<Self> {
+        let mut client = Self {
+            stream: Stream::open(address, timeouts, tls, throttle).await?,
+            capabilities: Capabilities::default(),
+            compression: None,
+            http: None,
+        };
+        client.capabilities = client.hello(compression.is_some()).await?;
+        if client.capabilities.contains(Capabilities::COMPRESSION) {
+            client.compression = compression;
+        }
+        log::debug!(
+            "Negotiated the protocol with {}, which supports {:?}",
+            address,
+            client.capabilities
+        );
+        let token = token.unwrap_or_default();
+        if token.is_empty() && client.capabilities.contains(Capabilities::AUTH)
+        {
+            return Err(ServerError {
+                kind: ServerErrorKind::Unauthorized,
+                reason: "Authentication required, and no token is \
+                         configured: pass --token or run login"
+                    .to_string(),
+            }
+            .into());
+        }
+        client.authenticate(token).await?;
+        log::debug!("Authenticated to {}", address);
+        Ok(client)
+    }
+
+    /// Creates a new `TcpClient` that sends its request to the HTTP API of a
+    /// server, served with `--http`, rather than over the TCP protocol.
+    ///
+    /// The API has no handshake: the token, if any, is sent along with the
+    /// request, and the server is known to report usage, but neither to
+    /// resume uploads nor to serve ranges or files by name.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the API, e.g. `http://localhost:8080`.
+    /// * `timeouts` - How long to wait for the server.
+    /// * `tls` - The TLS settings, for an `https://` URL.
+    /// * `token` - The token to authenticate with, if the server requires
+    ///   one.
+    /// * `throttle` - The bandwidth limit of the connection, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the connection fails or times out, or if the TLS
+    /// handshake fails.
+    pub async fn over_http(
+        url: &Url,
+        timeouts: Timeouts,
+        tls: Option<&Tls>,
+        token: Option<&str>,
+        throttle: Option<&Throttle>,
+    ) -> Result<Self> {
+        let path = url.path.split('?').next().unwrap_or_default();
         Ok(Self {
-            stream: TcpStream::connect(address)?,
+            stream: Stream::open(&url.address, timeouts, tls, throttle).await?,
+            capabilities: Capabilities::USAGE.union(Capabilities::EXPIRY),
+            compression: None,
+            http: Some(HttpApi {
+                authority: url.authority.clone(),
+                prefix: path.trim_end_matches('/').to_string(),
+                token: token
+                    .filter(|token| !token.is_empty())
+                    .map(str::to_string),
+            }),
         })
     }
 
-    /// Sends the specified files to the server.
+    /// Performs the handshake that starts every connection, agreeing with
+    /// the server on the version of the protocol, and asking it to compress
+    /// the contents of files in transit if `compression` is set.
     ///
-    /// # Arguments
+    /// # Returns
     ///
-    /// * `files` - A vector of byte vectors, where each byte vector represents
-    ///   a file.
+    /// Returns the capabilities of the server.
     ///
     /// # Errors
     ///
-    /// Returns an error if the upload fails.
-    pub fn send_files(&mut self, files: Vec<Vec<u8>>) -> Result<()> {
-        // send upload command
-        self.stream.write_all(b"upload\0\0\0\0")?;
+    /// Returns a [`ServerError`] of kind [`ServerErrorKind::InvalidRequest`]
+    /// if the server speaks no version of the protocol the client speaks.
+    async fn hello(&mut self, compression: bool) -> Result<Capabilities> {
+        let capabilities = match compression {
+            true => CAPABILITIES.union(Capabilities::COMPRESSION),
+            false => CAPABILITIES,
+        };
+        self.send(Request::Hello {
+            version: VERSION,
+            capabilities,
+        })
+        .await?;
 
-        // Send the number of files to be uploaded
-        self.stream.write_all(&files.len().to_be_bytes())?;
+        let unsupported = |reason: String| {
+            anyhow::Error::from(ServerError {
+                kind: ServerErrorKind::InvalidRequest,
+                reason,
+            })
+        };
+        match self.read_response().await {
+            Ok(Response::Hello {
+                version,
+                capabilities,
+            }) => match (MIN_VERSION..=VERSION).contains(&version) {
+                true => Ok(capabilities),
+                false => Err(unsupported(format!(
+                    "Unsupported protocol version {}, the client speaks \
+                     versions {} to {}",
+                    version, MIN_VERSION, VERSION
+                ))),
+            },
+            Ok(response) => Err(unexpected(response)),
+            Err(error) => match error.downcast::<ServerError>() {
+                // Servers older than the handshake take it for an invalid
+                // authentication
+                Ok(error) if error.kind != ServerErrorKind::InvalidRequest => {
+                    Err(unsupported(format!(
+                        "The server does not speak protocol version {}, it \
+                         is older than the client ({})",
+                        VERSION, error.reason
+                    )))
+                }
+                Ok(error) => Err(error.into()),
+                Err(error) => Err(error),
+            },
+        }
+    }
 
-        // Send each file
-        for file in files {
-            self.stream.write_all(&file.len().to_be_bytes())?;
-            self.stream.write_all(&file)?;
+    /// Performs the authentication that follows the handshake, sending the
+    /// token, empty if there is none, and reading the response.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ServerError`] of kind [`ServerErrorKind::Unauthorized`]
+    /// if the server rejects the token.
+    async fn authenticate(&mut self, token: &str) -> Result<()> {
+        self.send(Request::Auth {
+            token: token.to_string(),
+        })
+        .await?;
+        self.read_ok().await
+    }
+
+    /// Checks that the server has a capability, failing with a clear error
+    /// rather than with an invalid request.
+    ///
+    /// # Arguments
+    ///
+    /// * `capability` - The capability needed.
+    /// * `feature` - What needs it, e.g. `ranged downloads`.
+    fn require(&self, capability: Capabilities, feature: &str) -> Result<()> {
+        match self.capabilities.contains(capability) {
+            true => Ok(()),
+            false => {
+                Err(anyhow::anyhow!("The server does not support {}", feature))
+            }
         }
+    }
+
+    /// Sends a request to the server.
+    async fn send(&mut self, request: Request) -> Result<()> {
+        let request = request.encode().map_err(wire)?;
+        self.stream.write_all(&request).await?;
         Ok(())
     }
 
+    /// Receives a response sent by the server.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ServerError`] if the server reports that the request
+    /// failed.
+    async fn read_response(&mut self) -> Result<Response> {
+        match Response::read(&mut self.stream).await.map_err(wire)? {
+            Response::Error { kind, message } => Err(ServerError {
+                kind,
+                reason: message,
+            }
+            .into()),
+            response => Ok(response),
+        }
+    }
+
+    /// Receives the response of a request with nothing to send back.
+    async fn read_ok(&mut self) -> Result<()> {
+        match self.read_response().await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sends a single file of a batch to the server, which stages it until
+    /// the batch is committed with [`TcpClient::commit`]. The files of a
+    /// batch can be sent concurrently over several connections.
+    ///
+    /// The server first replies with the number of bytes of the file it
+    /// already received in the session, and only the rest is sent, so that
+    /// an upload interrupted by a network failure can be resumed by calling
+    /// this again over a new connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The upload session, a hex string unique to the upload.
+    /// * `index` - The index of the file in the upload.
+    /// * `file` - The path of the file, streamed from disk.
+    /// * `progress` - The progress bar of the file.
+    ///
+    /// # Returns
+    ///
+    /// Returns the SHA-256 of the file, computed as it is read, and the one
+    /// computed by the server, as a hex string.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, or if the upload fails or
+    /// the server does not acknowledge it.
+    pub async fn put_file(
+        self,
+        session: &str,
+        index: usize,
+        file: &Path,
+        progress: &FileProgress,
+    ) -> Result<(Hash, String)> {
+        let file = File::open(file).await?;
+        let size = file.metadata().await?.len();
+        self.put_stream(session, index, file, size, progress).await
+    }
+
+    /// Sends a single file of a batch read from a stream, e.g. the body of
+    /// an HTTP response, like [`TcpClient::put_file`]. A resumed upload reads
+    /// the stream from its start again, the bytes the server already holds
+    /// being hashed but not sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The upload session, a hex string unique to the upload.
+    /// * `index` - The index of the file in the upload.
+    /// * `file` - The content of the file.
+    /// * `size` - The size of the file, which the stream must have.
+    /// * `progress` - The progress bar of the file.
+    ///
+    /// # Returns
+    ///
+    /// Returns the SHA-256 of the file, computed as it is read, and the one
+    /// computed by the server, as a hex string.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream cannot be read, is shorter than `size`
+    /// or stalls for longer than the timeout, or if the upload fails or the
+    /// server does not acknowledge it.
+    pub async fn put_stream(
+        mut self,
+        session: &str,
+        index: usize,
+        mut file: impl AsyncRead + Unpin,
+        size: u64,
+        progress: &FileProgress,
+    ) -> Result<(Hash, String)> {
+        log::debug!("Sending put of file {} of session {}", index, session);
+        if let Some(api) = self.http.take() {
+            return self.http_put(&api, file, size, progress).await;
+        }
+        self.require(Capabilities::CHUNKING, "chunked uploads")?;
+        self.send(Request::Put {
+            session: session.to_string(),
+            index,
+            size,
+        })
+        .await?;
+
+        // receive the number of bytes the server already has
+        let offset = match self.read_response().await? {
+            Response::Resume { offset } => offset,
+            response => return Err(unexpected(response)),
+        };
+        if offset > size {
+            return Err(anyhow::anyhow!("Invalid offset {}", offset));
+        }
+        progress.set_position(offset);
+
+        // Hash the bytes the server already has, then stream the rest
+        let mut hasher = Sha256::new();
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut read = 0;
+        while read < size {
+            let len = CHUNK_SIZE.min((size - read) as usize);
+            let read_file = file.read(&mut chunk[..len]);
+            let len = timed(self.stream.timeout, "reading the file", read_file)
+                .await?;
+            if len == 0 {
+                // The size was already sent, the upload cannot go on
+                return Err(anyhow::anyhow!(
+                    "File was truncated while being uploaded"
+                ));
+            }
+            hasher.update(&chunk[..len]);
+            let skip = offset.saturating_sub(read).min(len as u64) as usize;
+            if skip < len {
+                self.write_content(&chunk[skip..len]).await?;
+                progress.inc((len - skip) as u64);
+            }
+            read += len as u64;
+        }
+
+        // receive the hash of the staged file as acknowledgment
+        match self.read_response().await? {
+            Response::Staged { hash } => Ok((hasher.finalize().into(), hash)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Asks the server which of the files of a batch it already holds the
+    /// content of, e.g. the files of a snapshot that did not change since the
+    /// last one, so that only the others are sent before the commit.
+    ///
+    /// # Arguments
+    ///
+    /// * `hashes` - The SHA-256 hashes of the files, as hex strings.
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the server holds each file, in order, or nothing if
+    /// the server cannot tell, e.g. an older one or the HTTP API.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails.
+    pub async fn held(mut self, hashes: &[String]) -> Result<Vec<bool>> {
+        if !self.capabilities.contains(Capabilities::CONTENTS) {
+            return Ok(vec![]);
+        }
+        log::debug!("Sending have of {} files", hashes.len());
+        self.send(Request::Have {
+            hashes: hashes.to_vec(),
+        })
+        .await?;
+
+        // receive whether the server holds each file
+        match self.read_response().await? {
+            Response::Held { held } if held.len() == hashes.len() => Ok(held),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Commits the files sent with [`TcpClient::put_file`] as a single
+    /// upload, along with those the server told it holds with
+    /// [`TcpClient::held`].
+    ///
+    /// # Arguments
+    ///
+    /// * `hashes` - The SHA-256 hashes of the files, as hex strings, in the
+    ///   order of the upload.
+    ///
+    /// # Returns
+    ///
+    /// Returns the upload committed by the server.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server cannot commit the files, or sends a
+    /// signature of the root hash which does not verify.
+    pub async fn commit(mut self, hashes: &[String]) -> Result<Committed> {
+        log::debug!("Sending commit of {} files", hashes.len());
+        let committed = match self.http.take() {
+            Some(api) => {
+                let body = serde_json::to_vec(hashes)?;
+                let reply =
+                    self.http_json(&api, "POST", "/uploads", body).await?;
+                let signature = match &reply["signature"] {
+                    serde_json::Value::Null => None,
+                    signature => Some(
+                        serde_json::from_value(signature.clone())
+                            .map_err(|_| invalid_reply(&reply))?,
+                    ),
+                };
+                Committed {
+                    root_hash: json_str(&reply, "root_hash")?,
+                    already_stored: reply["already_stored"] == true,
+                    signature,
+                }
+            }
+            None => self.commit_tcp(hashes).await?,
+        };
+        if let Some(signature) = &committed.signature {
+            if !signature.verifies(&committed.root_hash) {
+                return Err(anyhow::anyhow!(
+                    "The server signed root hash {} with an invalid signature",
+                    committed.root_hash
+                ));
+            }
+        }
+        Ok(committed)
+    }
+
+    /// Commits the files over the TCP protocol, see [`TcpClient::commit`].
+    async fn commit_tcp(&mut self, hashes: &[String]) -> Result<Committed> {
+        self.send(Request::Commit {
+            hashes: hashes.to_vec(),
+        })
+        .await?;
+
+        // receive the root hash of the stored files as acknowledgment
+        let (root_hash, already_stored) = match self.read_response().await? {
+            Response::Committed { root_hash } => (root_hash, false),
+            Response::AlreadyStored { root_hash } => (root_hash, true),
+            response => return Err(unexpected(response)),
+        };
+        // then its signature, from a server signing them
+        let signature = match self.capabilities.contains(Capabilities::SIGNED) {
+            true => match self.read_response().await? {
+                Response::Signed { key, signature } => Some(RootSignature {
+                    key: hex::encode(key),
+                    signature: hex::encode(signature),
+                }),
+                response => return Err(unexpected(response)),
+            },
+            false => None,
+        };
+        Ok(Committed {
+            root_hash,
+            already_stored,
+            signature,
+        })
+    }
+
     /// Gets the file at the specified index from the server.
     ///
     /// # Arguments
     ///
     /// * `root_hash` - The root hash of the Merkle tree that contains the file.
     /// * `index` - The index of the file in the Merkle tree.
+    /// * `name` - The name of the file, shown in the progress bars.
+    /// * `progress` - The progress bars of the download.
+    ///
+    /// # Returns
+    ///
+    /// Returns the file and its Merkle proof, once the proof is verified.
     ///
     /// # Errors
     ///
     /// Returns an error if the download fails.
-    pub fn get_file(
+    pub async fn get_file(
         &mut self,
         root_hash: &str,
         index: usize,
-    ) -> Result<Vec<u8>> {
-        // send download command
-        self.stream.write_all(b"download\0\0")?;
-        // send root hash
-        self.stream.write_all(root_hash.as_bytes())?;
-        // send index
-        self.stream.write_all(&index.to_be_bytes())?;
-
-        // receive file size
-        let mut file_size = [0; std::mem::size_of::<u64>()];
-        self.stream.read_exact(&mut file_size)?;
-        // receive file
-        let mut file = vec![0; u64::from_be_bytes(file_size) as usize];
-        self.stream.read_exact(&mut file)?;
+        name: &str,
+        progress: &Progress,
+    ) -> Result<(Vec<u8>, Vec<Hash>)> {
+        log::debug!("Sending download of file {} of {}", index, root_hash);
+        let file = FileRef::Index(index);
+        let (sent, file, proof) =
+            self.read_file(root_hash, file, name, progress).await?;
+        if sent != index {
+            return Err(anyhow::anyhow!("Server sent file {}", sent));
+        }
+        Ok((file, proof))
+    }
+
+    /// Gets the file with the specified name from the server, as recorded
+    /// with [`TcpClient::put_names`], e.g. to download the files of an upload
+    /// without its records in the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the Merkle tree that contains the file.
+    /// * `name` - The name of the file.
+    /// * `progress` - The progress bars of the download.
+    ///
+    /// # Returns
+    ///
+    /// Returns the index of the file, the file and its Merkle proof, once the
+    /// proof is verified.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server does not know the name, or if the
+    /// download fails.
+    pub async fn get_named_file(
+        &mut self,
+        root_hash: &str,
+        name: &str,
+        progress: &Progress,
+    ) -> Result<(usize, Vec<u8>, Vec<Hash>)> {
+        log::debug!("Sending download of file {} of {}", name, root_hash);
+        let file = FileRef::Name(name.to_string());
+        self.read_file(root_hash, file, name, progress).await
+    }
+
+    /// Downloads a file: sends the request, then receives the index and the
+    /// size of the file, its content and its Merkle proof, and verifies the
+    /// proof.
+    async fn read_file(
+        &mut self,
+        root_hash: &str,
+        file: FileRef,
+        name: &str,
+        progress: &Progress,
+    ) -> Result<(usize, Vec<u8>, Vec<Hash>)> {
+        if let Some(api) = self.http.take() {
+            let FileRef::Index(index) = file else {
+                return Err(over_http("downloads of files by name"));
+            };
+            return self
+                .http_get_file(&api, root_hash, index, name, progress)
+                .await;
+        }
+        self.send(Request::Download {
+            root_hash: root_hash.to_string(),
+            file,
+        })
+        .await?;
+
+        // receive the index and the size of the file, then the file
+        let (index, file_size) = match self.read_response().await? {
+            Response::File { index, size } => (index, size as usize),
+            response => return Err(unexpected(response)),
+        };
+        let mut file = vec![0; file_size];
+        let file_progress = progress.file(name, file_size as u64);
+        let mut read = 0;
+        while read < file_size {
+            let len = self.read_content(&mut file[read..]).await?;
+            file_progress.inc(len as u64);
+            read += len;
+        }
+        file_progress.finish();
 
         // decode root hash from hex string and convert to [u8; 32]
         let root_hash = hex::decode(root_hash)?
@@ -84,22 +1211,1190 @@ impl TcpClient {
             .map_err(|_| anyhow::anyhow!("Invalid hash length"))?;
 
         // receive proof
-        let mut proof = vec![];
-        self.stream.read_to_end(&mut proof)?;
-        let proof = proof
-            .chunks_exact(32)
-            .map(|chunk| {
-                chunk
-                    .try_into()
-                    .map_err(|_| anyhow::anyhow!("Invalid hash length"))
-            })
-            .collect::<Result<Vec<_>>>()?;
+        let (_, proof) = self.read_proof().await?;
 
         // verify proof
         if !merkle_tree::MerkleTree::verify(index, &file, &root_hash, &proof) {
             return Err(anyhow::anyhow!("Invalid proof"));
         }
 
-        Ok(file)
+        Ok((index, file, proof))
+    }
+
+    /// Sends the names of the files of an upload to the server, which keeps
+    /// them along with the files, so that they can be downloaded by name
+    /// with [`TcpClient::get_named_file`].
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    /// * `names` - The names of the files, in the order of the upload.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server cannot record the names, e.g. if it
+    /// does not hold the upload.
+    pub async fn put_names(
+        mut self,
+        root_hash: &str,
+        names: &[String],
+    ) -> Result<()> {
+        log::debug!("Sending names of {} files of {}", names.len(), root_hash);
+        if self.http.is_some() {
+            return Err(over_http("the names of the files"));
+        }
+        self.send(Request::Names {
+            root_hash: root_hash.to_string(),
+            names: names.to_vec(),
+        })
+        .await?;
+        self.read_ok().await
+    }
+
+    /// Asks the server to keep an upload for the given time from now, and to
+    /// delete it then, replacing the retention it had.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    /// * `retention` - How long the upload is kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server does not delete expired uploads, or
+    /// does not hold the upload.
+    pub async fn retain(
+        mut self,
+        root_hash: &str,
+        retention: Duration,
+    ) -> Result<()> {
+        self.require(Capabilities::EXPIRY, "expiring uploads")?;
+        log::debug!("Sending retention of {:?} for {}", retention, root_hash);
+        let secs = retention.as_secs();
+        if let Some(api) = self.http.take() {
+            let path = format!("/uploads/{}/retention", root_hash);
+            let body =
+                serde_json::to_vec(&serde_json::json!({ "seconds": secs }))?;
+            self.http_json(&api, "PUT", &path, body).await?;
+            return Ok(());
+        }
+        self.send(Request::Retain {
+            root_hash: root_hash.to_string(),
+            secs,
+        })
+        .await?;
+        self.read_ok().await
+    }
+
+    /// Gets the Merkle proof of the file at the specified index from the
+    /// server, without the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the Merkle tree that contains the file.
+    /// * `index` - The index of the file in the Merkle tree.
+    ///
+    /// # Returns
+    ///
+    /// Returns the proof, once verified against the root hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the proof is invalid.
+    pub async fn get_proof(
+        mut self,
+        root_hash: &str,
+        index: usize,
+    ) -> Result<Proof> {
+        log::debug!("Sending proof of file {} of {}", index, root_hash);
+        let (leaf, hashes) = match self.http.take() {
+            Some(api) => {
+                let path = format!("/uploads/{}/{}/proof", root_hash, index);
+                let reply = self.http_json(&api, "GET", &path, vec![]).await?;
+                let proof = serde_json::from_value::<Proof>(reply)?;
+                (proof.leaf, proof.hashes)
+            }
+            None => {
+                self.send(Request::Proof {
+                    root_hash: root_hash.to_string(),
+                    index,
+                })
+                .await?;
+
+                // receive the leaf of the file and the proof
+                self.read_proof().await?
+            }
+        };
+
+        let root_hash = hex::decode(root_hash)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid hash length"))?;
+        let proof = Proof::new(index, leaf, root_hash, hashes);
+        if !proof.verify() {
+            return Err(anyhow::anyhow!("Invalid proof"));
+        }
+        Ok(proof)
+    }
+
+    /// Gets a range of the file at the specified index from the server, to
+    /// download a large file in several ranges concurrently.
+    ///
+    /// The range is not verified on its own: the file must be verified
+    /// against its Merkle proof once all its ranges are downloaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the Merkle tree that contains the file.
+    /// * `index` - The index of the file in the Merkle tree.
+    /// * `offset` - The offset of the range in the file.
+    /// * `len` - The length of the range.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the download fails, or if the server does not hold
+    /// the whole range.
+    pub async fn get_range(
+        mut self,
+        root_hash: &str,
+        index: usize,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        log::debug!(
+            "Sending range {}..{} of file {} of {}",
+            offset,
+            offset + len,
+            index,
+            root_hash
+        );
+        self.require(Capabilities::CHUNKING, "ranged downloads")?;
+        self.send(Request::Range {
+            root_hash: root_hash.to_string(),
+            index,
+            offset,
+            len,
+        })
+        .await?;
+
+        let range_len = match self.read_response().await? {
+            Response::Range { len } => len,
+            response => return Err(unexpected(response)),
+        };
+        if range_len != len {
+            return Err(anyhow::anyhow!(
+                "Server sent {} bytes of a range of {}",
+                range_len,
+                len
+            ));
+        }
+        let mut range = vec![0; len as usize];
+        let mut read = 0;
+        while read < range.len() {
+            read += self.read_content(&mut range[read..]).await?;
+        }
+        Ok(range)
+    }
+
+    /// Asks the server whether it holds an upload.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails.
+    pub async fn exists(mut self, root_hash: &str) -> Result<bool> {
+        log::debug!("Sending exists of {}", root_hash);
+        if self.http.is_some() {
+            let uploads = self.list_uploads().await?;
+            return Ok(uploads
+                .iter()
+                .any(|upload| upload.root_hash == root_hash));
+        }
+        self.send(Request::Exists {
+            root_hash: root_hash.to_string(),
+        })
+        .await?;
+
+        // receive whether the server holds the upload
+        match self.read_response().await? {
+            Response::Exists { exists } => Ok(exists),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Deletes an upload from the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the server held the upload.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server could not delete the upload.
+    pub async fn delete(mut self, root_hash: &str) -> Result<bool> {
+        log::debug!("Sending delete of {}", root_hash);
+        if let Some(api) = self.http.take() {
+            let path = format!("/uploads/{}", root_hash);
+            return match self.http_json(&api, "DELETE", &path, vec![]).await {
+                Ok(_) => Ok(true),
+                Err(e) => match server_error(&e) {
+                    Some(e) if e.kind == ServerErrorKind::NotFound => Ok(false),
+                    _ => Err(e),
+                },
+            };
+        }
+        self.send(Request::Delete {
+            root_hash: root_hash.to_string(),
+        })
+        .await?;
+
+        // receive whether the upload existed
+        match self.read_response().await? {
+            Response::Deleted { existed } => Ok(existed),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the uploads the server holds.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails.
+    pub async fn list_uploads(mut self) -> Result<Vec<StoredUpload>> {
+        log::debug!("Sending roots");
+        if let Some(api) = self.http.take() {
+            let reply = self.http_json(&api, "GET", "/uploads", vec![]).await?;
+            let uploads =
+                reply.as_array().ok_or_else(|| invalid_reply(&reply))?;
+            return uploads
+                .iter()
+                .map(|upload| {
+                    Ok(StoredUpload {
+                        root_hash: json_str(upload, "root_hash")?,
+                        files: json_u64(upload, "files")?,
+                        size: json_u64(upload, "size")?,
+                    })
+                })
+                .collect();
+        }
+        self.send(Request::Roots).await?;
+
+        // receive the uploads
+        match self.read_response().await? {
+            Response::Uploads { uploads } => Ok(uploads),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the storage used on the server by the uploads of the client.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of bytes stored, and the quota of the client, if
+    /// it has one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server does not report usage, or the request
+    /// fails.
+    pub async fn usage(mut self) -> Result<(u64, Option<u64>)> {
+        self.require(Capabilities::USAGE, "storage usage")?;
+        log::debug!("Sending usage");
+        if let Some(api) = self.http.take() {
+            let reply = self.http_json(&api, "GET", "/usage", vec![]).await?;
+            let quota = match reply["quota"].is_null() {
+                true => None,
+                false => Some(json_u64(&reply, "quota")?),
+            };
+            return Ok((json_u64(&reply, "used")?, quota));
+        }
+        self.send(Request::Usage).await?;
+
+        // receive the bytes stored and the quota
+        match self.read_response().await? {
+            Response::Usage { used, quota } => Ok((used, quota)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sends bytes of the content of a file, compressed if the connection
+    /// is.
+    async fn write_content(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.compression {
+            Some(level) => {
+                let frame = compression::encode_frame(bytes, level);
+                self.stream.write_all(&frame).await
+            }
+            None => self.stream.write_all(bytes).await,
+        }
+    }
+
+    /// Receives bytes of the content of a file, decompressing them if the
+    /// connection is compressed.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes received, at the start of `buf`.
+    async fn read_content(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.compression {
+            Some(_) => compression::read_frame(&mut self.stream, buf)
+                .await
+                .map_err(wire),
+            None => {
+                let len = buf.len().min(CHUNK_SIZE);
+                self.stream.read_exact(&mut buf[..len]).await?;
+                Ok(len)
+            }
+        }
+    }
+
+    /// Receives the leaf of a file and its Merkle proof sent by the server.
+    async fn read_proof(&mut self) -> Result<(Hash, Vec<Hash>)> {
+        match self.read_response().await? {
+            Response::Proof { leaf, hashes } => Ok((leaf, hashes)),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// The requests sent to the HTTP API of a server, each over the connection
+/// of the client, which the server closes once it has answered.
+impl TcpClient {
+    /// Sends the head of a request, with the token if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `api` - The API of the server.
+    /// * `method` - The method of the request, e.g. `GET`.
+    /// * `path` - The path of the request, under the path of the API.
+    /// * `headers` - The other headers of the request.
+    async fn http_send(
+        &mut self,
+        api: &HttpApi,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+    ) -> Result<()> {
+        let mut head = format!(
+            "{} {}{} HTTP/1.1\r\nHost: {}\r\nUser-Agent: file-guardian\r\n\
+             Connection: close\r\n",
+            method, api.prefix, path, api.authority
+        );
+        if let Some(token) = &api.token {
+            head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        self.stream.write_all(head.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Sends a request and receives its JSON reply.
+    ///
+    /// # Arguments
+    ///
+    /// * `api` - The API of the server.
+    /// * `method` - The method of the request, e.g. `GET`.
+    /// * `path` - The path of the request, under the path of the API.
+    /// * `body` - The JSON body of the request, empty if it has none.
+    ///
+    /// # Returns
+    ///
+    /// Returns the reply, `null` if the response has no body.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ServerError`] if the server answers with an error status.
+    async fn http_json(
+        &mut self,
+        api: &HttpApi,
+        method: &str,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Result<serde_json::Value> {
+        let mut headers = vec![];
+        if !body.is_empty() {
+            headers.push(("Content-Type", "application/json".to_string()));
+            headers.push(("Content-Length", body.len().to_string()));
+        }
+        self.http_send(api, method, path, &headers).await?;
+        self.stream.write_all(&body).await?;
+        self.http_reply(vec![]).await
+    }
+
+    /// Receives the JSON reply of a request, `received` holding what was
+    /// already received of the response.
+    async fn http_reply(
+        &mut self,
+        mut received: Vec<u8>,
+    ) -> Result<serde_json::Value> {
+        let headers = self.http_response(&mut received).await?;
+        let body = self
+            .http_body(&headers, received, MAX_HTTP_JSON_SIZE, None)
+            .await?;
+        match body.is_empty() {
+            true => Ok(serde_json::Value::Null),
+            false => Ok(serde_json::from_slice(&body)?),
+        }
+    }
+
+    /// Receives the head of a response, leaving what was received after it,
+    /// the start of the body, in `received`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the status and the headers, with their names in lowercase.
+    async fn http_head(
+        &mut self,
+        received: &mut Vec<u8>,
+    ) -> Result<(u16, Vec<(String, String)>)> {
+        let mut chunk = [0; 4096];
+        let split = loop {
+            if let Some(split) = http::find(received, b"\r\n\r\n") {
+                break split;
+            }
+            if received.len() > MAX_HTTP_HEAD_SIZE {
+                return Err(anyhow::anyhow!("Invalid HTTP response"));
+            }
+            match self.stream.read(&mut chunk).await? {
+                0 => {
+                    return Err(io::Error::from(ErrorKind::UnexpectedEof).into())
+                }
+                len => received.extend_from_slice(&chunk[..len]),
+            }
+        };
+        let (status, headers) = http::parse_head(&received[..split]);
+        received.drain(..split + 4);
+        let code = status
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok());
+        match code {
+            Some(code) => Ok((code, headers)),
+            None => Err(anyhow::anyhow!("Invalid HTTP status line {}", status)),
+        }
+    }
+
+    /// Receives the head of the final response to a request, after the
+    /// interim ones, leaving the start of the body in `received`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ServerError`] if the server answers with an error status.
+    async fn http_response(
+        &mut self,
+        received: &mut Vec<u8>,
+    ) -> Result<Vec<(String, String)>> {
+        loop {
+            match self.http_head(received).await? {
+                (100..=199, _) => {}
+                (200..=299, headers) => return Ok(headers),
+                (status, headers) => {
+                    let received = std::mem::take(received);
+                    return Err(self
+                        .http_error(status, &headers, received)
+                        .await);
+                }
+            }
+        }
+    }
+
+    /// Receives the body of a response, of `Content-Length` bytes, whose
+    /// start is in `received`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the response gives no length or a length larger
+    /// than `limit`, or if the body cannot be received.
+    async fn http_body(
+        &mut self,
+        headers: &[(String, String)],
+        mut received: Vec<u8>,
+        limit: u64,
+        progress: Option<&FileProgress>,
+    ) -> Result<Vec<u8>> {
+        let length = content_length(headers)?;
+        if length > limit {
+            return Err(anyhow::anyhow!(
+                "The response is larger than {} bytes",
+                limit
+            ));
+        }
+        let start = received.len().min(length as usize);
+        received.resize(length as usize, 0);
+        if let Some(progress) = progress {
+            progress.inc(start as u64);
+        }
+        for chunk in received[start..].chunks_mut(CHUNK_SIZE) {
+            self.stream.read_exact(chunk).await?;
+            if let Some(progress) = progress {
+                progress.inc(chunk.len() as u64);
+            }
+        }
+        Ok(received)
+    }
+
+    /// Returns the [`ServerError`] of a response with an error status, whose
+    /// body tells the reason as `{"error": "..."}`.
+    async fn http_error(
+        &mut self,
+        status: u16,
+        headers: &[(String, String)],
+        received: Vec<u8>,
+    ) -> anyhow::Error {
+        let body = match self
+            .http_body(headers, received, MAX_HTTP_JSON_SIZE, None)
+            .await
+        {
+            Ok(body) => body,
+            Err(e) => return e,
+        };
+        let reason = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|body| body["error"].as_str().map(str::to_string))
+            .unwrap_or_else(|| format!("HTTP status {}", status));
+        ServerError {
+            kind: error_kind(status),
+            reason,
+        }
+        .into()
+    }
+
+    /// Sends a file as the body of `POST /files`, once the server tells it
+    /// accepts its size, hashing it as it is read. The file is sent whole,
+    /// as the API does not resume uploads.
+    async fn http_put(
+        mut self,
+        api: &HttpApi,
+        mut file: impl AsyncRead + Unpin,
+        size: u64,
+        progress: &FileProgress,
+    ) -> Result<(Hash, String)> {
+        let headers = [
+            ("Content-Type", "application/octet-stream".to_string()),
+            ("Content-Length", size.to_string()),
+            ("Expect", "100-continue".to_string()),
+        ];
+        self.http_send(api, "POST", "/files", &headers).await?;
+
+        // The server checks the size of the file and the quota first
+        let mut received = vec![];
+        match self.http_head(&mut received).await? {
+            (100, _) => {}
+            (status, headers) => {
+                return Err(self.http_error(status, &headers, received).await)
+            }
+        }
+        progress.set_position(0);
+        let mut hasher = Sha256::new();
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut read = 0;
+        while read < size {
+            let len = CHUNK_SIZE.min((size - read) as usize);
+            let read_file = file.read(&mut chunk[..len]);
+            let len = timed(self.stream.timeout, "reading the file", read_file)
+                .await?;
+            if len == 0 {
+                return Err(anyhow::anyhow!(
+                    "File was truncated while being uploaded"
+                ));
+            }
+            hasher.update(&chunk[..len]);
+            self.stream.write_all(&chunk[..len]).await?;
+            progress.inc(len as u64);
+            read += len as u64;
+        }
+
+        // receive the hash of the staged file as acknowledgment
+        let reply = self.http_reply(received).await?;
+        Ok((hasher.finalize().into(), json_str(&reply, "hash")?))
+    }
+
+    /// Downloads a file with `GET /uploads/{root}/{index}`, and verifies it
+    /// against the proof sent in the `X-Merkle-Proof` header.
+    async fn http_get_file(
+        &mut self,
+        api: &HttpApi,
+        root_hash: &str,
+        index: usize,
+        name: &str,
+        progress: &Progress,
+    ) -> Result<(usize, Vec<u8>, Vec<Hash>)> {
+        let path = format!("/uploads/{}/{}", root_hash, index);
+        self.http_send(api, "GET", &path, &[]).await?;
+        let mut received = vec![];
+        let headers = self.http_response(&mut received).await?;
+        let file_progress = progress.file(name, content_length(&headers)?);
+        let file = self
+            .http_body(&headers, received, u64::MAX, Some(&file_progress))
+            .await?;
+        file_progress.finish();
+
+        let proof = header(&headers, "x-merkle-proof")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|hash| !hash.is_empty())
+            .map(utils::decode_hash)
+            .collect::<Result<Vec<_>>>()?;
+        let root_hash = utils::decode_hash(root_hash)?;
+        if !merkle_tree::MerkleTree::verify(index, &file, &root_hash, &proof) {
+            return Err(anyhow::anyhow!("Invalid proof"));
+        }
+        Ok((index, file, proof))
+    }
+}
+
+/// Returns the error of a request the HTTP API does not serve.
+fn over_http(feature: &str) -> anyhow::Error {
+    anyhow::anyhow!("The HTTP API of the server does not support {}", feature)
+}
+
+/// Returns the kind of the errors answered with an HTTP status.
+fn error_kind(status: u16) -> ServerErrorKind {
+    match status {
+        401 | 403 => ServerErrorKind::Unauthorized,
+        404 => ServerErrorKind::NotFound,
+        413 => ServerErrorKind::TooLarge,
+        507 => ServerErrorKind::QuotaExceeded,
+        400..=499 => ServerErrorKind::InvalidRequest,
+        _ => ServerErrorKind::Failed,
+    }
+}
+
+/// Returns the value of a header of a response, given its name in
+/// lowercase.
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Returns the length of the body of a response of the HTTP API, which
+/// always gives it.
+fn content_length(headers: &[(String, String)]) -> Result<u64> {
+    header(headers, "content-length")
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!("Invalid Content-Length from the server")
+        })
+}
+
+/// Returns a string field of a JSON reply of the HTTP API.
+fn json_str(reply: &serde_json::Value, field: &str) -> Result<String> {
+    reply[field]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| invalid_reply(reply))
+}
+
+/// Returns an integer field of a JSON reply of the HTTP API.
+fn json_u64(reply: &serde_json::Value, field: &str) -> Result<u64> {
+    reply[field].as_u64().ok_or_else(|| invalid_reply(reply))
+}
+
+/// Returns the error of a JSON reply of the HTTP API other than the one
+/// expected.
+fn invalid_reply(reply: &serde_json::Value) -> anyhow::Error {
+    anyhow::anyhow!("Unexpected reply from the server: {}", reply)
+}
+
+/// Returns the error of a message that cannot be encoded or read, keeping
+/// the errors of the stream as they are, so that timeouts and dropped
+/// connections are still told apart and retried.
+fn wire(error: protocol::Error) -> anyhow::Error {
+    match error {
+        protocol::Error::Io(error) => error.into(),
+        error => error.into(),
+    }
+}
+
+/// Returns the error of a response of the server other than the one
+/// expected.
+fn unexpected(response: Response) -> anyhow::Error {
+    anyhow::anyhow!("Unexpected response from the server: {:?}", response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Limits;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Answers the next request sent to a listener with `response`, and
+    /// returns the head of the request.
+    async fn answer(listener: &TcpListener, response: &str) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = tokio::io::BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            stream.read_line(&mut head).await.unwrap();
+        }
+        stream.write_all(response.as_bytes()).await.unwrap();
+        head
+    }
+
+    /// Answers the handshake and the authentication of the next client of a
+    /// listener, as a server of the given version, and returns the stream
+    /// of the client.
+    async fn accept(listener: &TcpListener, version: u32) -> TcpStream {
+        let (stream, _) = listener.accept().await.unwrap();
+        handshake(stream, version).await
+    }
+
+    /// Answers the handshake and the authentication of a client, as a
+    /// server of the given version.
+    async fn handshake<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        mut stream: S,
+        version: u32,
+    ) -> S {
+        let request = Request::read(&mut stream, &Limits::NONE).await.unwrap();
+        assert!(matches!(request, Request::Hello { .. }));
+        let hello = Response::Hello {
+            version,
+            capabilities: Capabilities::CHUNKING,
+        };
+        stream.write_all(&hello.encode().unwrap()).await.unwrap();
+        if (MIN_VERSION..=VERSION).contains(&version) {
+            let auth = Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            assert!(matches!(auth, Request::Auth { .. }));
+            let ok = Response::Ok.encode().unwrap();
+            stream.write_all(&ok).await.unwrap();
+        }
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let timeouts = Timeouts::new(Duration::ZERO, Duration::ZERO);
+        let connect =
+            || TcpClient::new(&address, timeouts, None, None, None, None);
+
+        // A server speaking a version the client does not is refused
+        let (_, client) =
+            tokio::join!(accept(&listener, VERSION + 1), connect());
+        let error = client.err().unwrap();
+        assert_eq!(
+            server_error(&error).unwrap().kind,
+            ServerErrorKind::InvalidRequest
+        );
+        assert!(error.to_string().contains("Unsupported protocol version"));
+
+        // and a server older than the handshake, taking it for a token, is
+        // told apart from a server rejecting the token
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let error = Response::Error {
+                kind: ServerErrorKind::Unauthorized,
+                message: "Invalid token".to_string(),
+            };
+            stream.write_all(&error.encode().unwrap()).await.unwrap();
+        };
+        let ((), client) = tokio::join!(server, connect());
+        let error = client.err().unwrap().to_string();
+        assert!(error.contains("is older than the client"), "{}", error);
+
+        // The capabilities of the server are kept
+        let (_, client) = tokio::join!(accept(&listener, VERSION), connect());
+        let client = client.unwrap();
+        assert!(client.capabilities.contains(Capabilities::CHUNKING));
+        assert!(client.require(Capabilities::USAGE, "usage").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = std::env::temp_dir().join("file-guardian-test-unix");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let address = format!("unix:{}", path.display());
+        let timeouts = Timeouts::new(Duration::ZERO, Duration::ZERO);
+
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            handshake(stream, VERSION).await
+        };
+        let (_, client) = tokio::join!(
+            server,
+            TcpClient::new(&address, timeouts, None, None, None, None)
+        );
+        assert!(client
+            .unwrap()
+            .capabilities
+            .contains(Capabilities::CHUNKING));
+
+        // Nothing listens on the socket once its listener is dropped
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        let client = TcpClient::new(&address, timeouts, None, None, None, None);
+        assert!(client.await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_upload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let timeouts = Timeouts::new(Duration::ZERO, Duration::ZERO);
+        let file = b"hello world";
+        let put = || async {
+            let client =
+                TcpClient::new(&address, timeouts, None, None, None, None);
+            let progress = Progress::new(None, true).file("file", 11);
+            let session = "ab".repeat(32);
+            let client = client.await.unwrap();
+            let put = client.put_stream(&session, 1, &file[..], 11, &progress);
+            put.await
+        };
+
+        // Only the bytes the server does not hold yet are sent
+        let server = async {
+            let mut stream = accept(&listener, VERSION).await;
+            let put = Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            assert!(matches!(
+                put,
+                Request::Put {
+                    index: 1,
+                    size: 11,
+                    ..
+                }
+            ));
+            let resume = Response::Resume { offset: 6 };
+            stream.write_all(&resume.encode().unwrap()).await.unwrap();
+            let mut rest = [0; 5];
+            stream.read_exact(&mut rest).await.unwrap();
+            let hash = hex::encode(Sha256::digest(file));
+            let staged = Response::Staged { hash };
+            stream.write_all(&staged.encode().unwrap()).await.unwrap();
+            rest
+        };
+        let (rest, sent) = tokio::join!(server, put());
+        assert_eq!(&rest, b"world");
+        // The hash covers the bytes skipped too
+        let (hash, staged) = sent.unwrap();
+        assert_eq!(hex::encode(hash), staged);
+
+        // An offset past the end of the file is refused
+        let server = async {
+            let mut stream = accept(&listener, VERSION).await;
+            Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            let resume = Response::Resume { offset: 12 };
+            stream.write_all(&resume.encode().unwrap()).await.unwrap();
+        };
+        let ((), sent) = tokio::join!(server, put());
+        assert!(sent.unwrap_err().to_string().contains("Invalid offset 12"));
+    }
+
+    #[tokio::test]
+    async fn test_wire_compression() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let timeouts = Timeouts::new(Duration::ZERO, Duration::ZERO);
+        let level = compression::DEFAULT_LEVEL;
+        let file = b"hello world";
+        let connect = || {
+            TcpClient::new(&address, timeouts, None, None, None, Some(level))
+        };
+        // Answers the handshake of a client asking for compression
+        let accept = || async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            match Request::read(&mut stream, &Limits::NONE).await.unwrap() {
+                Request::Hello { capabilities, .. } => {
+                    assert!(capabilities.contains(Capabilities::COMPRESSION))
+                }
+                request => panic!("Unexpected request {:?}", request),
+            }
+            let hello = Response::Hello {
+                version: VERSION,
+                capabilities: Capabilities::CHUNKING
+                    .union(Capabilities::COMPRESSION),
+            };
+            stream.write_all(&hello.encode().unwrap()).await.unwrap();
+            Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            stream
+                .write_all(&Response::Ok.encode().unwrap())
+                .await
+                .unwrap();
+            stream
+        };
+
+        // The rest of a file is sent as frames
+        let server = async {
+            let mut stream = accept().await;
+            Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            let resume = Response::Resume { offset: 6 };
+            stream.write_all(&resume.encode().unwrap()).await.unwrap();
+            let mut rest = [0; 5];
+            let read = compression::read_frame(&mut stream, &mut rest).await;
+            assert_eq!(read.unwrap(), 5);
+            let hash = hex::encode(Sha256::digest(file));
+            let staged = Response::Staged { hash };
+            stream.write_all(&staged.encode().unwrap()).await.unwrap();
+            rest
+        };
+        let put = async {
+            let progress = Progress::new(None, true).file("file", 11);
+            let client = connect().await.unwrap();
+            let session = "ab".repeat(32);
+            client
+                .put_stream(&session, 0, &file[..], 11, &progress)
+                .await
+        };
+        let (rest, sent) = tokio::join!(server, put);
+        assert_eq!(&rest, b"world");
+        sent.unwrap();
+
+        // and ranges are received as frames, whatever their size
+        let range = b"log line\n".repeat(1000);
+        let server = async {
+            let mut stream = accept().await;
+            Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            let len = range.len() as u64;
+            let response = Response::Range { len };
+            stream.write_all(&response.encode().unwrap()).await.unwrap();
+            for chunk in range.chunks(4000) {
+                let frame = compression::encode_frame(chunk, level);
+                stream.write_all(&frame).await.unwrap();
+            }
+        };
+        let get = async {
+            let client = connect().await.unwrap();
+            client
+                .get_range(&"ab".repeat(32), 0, 0, range.len() as u64)
+                .await
+        };
+        let ((), received) = tokio::join!(server, get);
+        assert_eq!(received.unwrap(), range);
+    }
+
+    #[tokio::test]
+    async fn test_signed_commit() {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let timeouts = Timeouts::new(Duration::ZERO, Duration::ZERO);
+        let pair = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let key: Hash = pair.public_key().as_ref().try_into().unwrap();
+        let root_hash = "ab".repeat(32);
+        // Commits the upload as root hash `ab..`, signing `signed`
+        let server = |signed: Hash| {
+            let (listener, pair) = (&listener, &pair);
+            async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                Request::read(&mut stream, &Limits::NONE).await.unwrap();
+                let hello = Response::Hello {
+                    version: VERSION,
+                    capabilities: Capabilities::SIGNED,
+                };
+                stream.write_all(&hello.encode().unwrap()).await.unwrap();
+                Request::read(&mut stream, &Limits::NONE).await.unwrap();
+                let ok = Response::Ok.encode().unwrap();
+                stream.write_all(&ok).await.unwrap();
+                let commit =
+                    Request::read(&mut stream, &Limits::NONE).await.unwrap();
+                assert!(matches!(commit, Request::Commit { .. }));
+                let signature = pair.sign(&protocol::signed_root(&signed));
+                let responses = [
+                    Response::Committed {
+                        root_hash: "ab".repeat(32),
+                    },
+                    Response::Signed {
+                        key,
+                        signature: signature.as_ref().try_into().unwrap(),
+                    },
+                ];
+                for response in responses {
+                    let response = response.encode().unwrap();
+                    stream.write_all(&response).await.unwrap();
+                }
+            }
+        };
+        let commit = || async {
+            let client =
+                TcpClient::new(&address, timeouts, None, None, None, None);
+            client.await.unwrap().commit(&["cd".repeat(32)]).await
+        };
+
+        // The signature of the root hash is kept
+        let ((), committed) = tokio::join!(server([0xab; 32]), commit());
+        let signature = committed.unwrap().signature.unwrap();
+        assert_eq!(signature.key, hex::encode(key));
+        assert!(signature.verifies(&root_hash));
+
+        // and one of another root hash refused
+        let ((), committed) = tokio::join!(server([0xcd; 32]), commit());
+        let error = committed.unwrap_err().to_string();
+        assert!(error.contains("invalid signature"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_reused_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let files = [b"hello".to_vec(), b"world!".to_vec()];
+        let tree = merkle_tree::MerkleTree::new(&files).unwrap();
+        let root_hash = hex::encode(tree.root().unwrap());
+        // Serves both downloads over the one connection accepted
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            let hello = Response::Hello {
+                version: VERSION,
+                capabilities: Capabilities::REQUESTS,
+            };
+            stream.write_all(&hello.encode().unwrap()).await.unwrap();
+            Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            stream
+                .write_all(&Response::Ok.encode().unwrap())
+                .await
+                .unwrap();
+            for index in [1, 0] {
+                let request = Request::read(&mut stream, &Limits::NONE);
+                let Request::Download {
+                    file: FileRef::Index(asked),
+                    ..
+                } = request.await.unwrap()
+                else {
+                    panic!("Unexpected request");
+                };
+                assert_eq!(asked, index);
+                let size = files[index].len() as u64;
+                let file = Response::File { index, size };
+                stream.write_all(&file.encode().unwrap()).await.unwrap();
+                stream.write_all(&files[index]).await.unwrap();
+                let proof = Response::Proof {
+                    leaf: tree.leaves()[index],
+                    hashes: tree.proof(index).unwrap(),
+                };
+                stream.write_all(&proof.encode().unwrap()).await.unwrap();
+            }
+            // until the client closes it
+            let next = Request::read_next(&mut stream, &Limits::NONE).await;
+            assert!(next.unwrap().is_none());
+        };
+
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let timeout = Duration::from_secs(5);
+        let client = Server {
+            addr: server_addr,
+            timeouts: Timeouts::new(timeout, timeout),
+            tls: None,
+            token: None,
+            retry: RetryPolicy::default(),
+            throttle: None,
+            compression: None,
+            pool: None,
+            http: None,
+            server_key: None,
+        };
+        let download = async {
+            let connection = Connection::default();
+            let progress = Progress::new(None, true);
+            let mut downloaded = vec![];
+            for index in [1, 0] {
+                let download =
+                    client.run_over("download", &connection, |mut c| {
+                        let (root_hash, progress) = (&root_hash, &progress);
+                        async move {
+                            let file = c
+                                .get_file(root_hash, index, "file", progress)
+                                .await?;
+                            Ok((file.0, c))
+                        }
+                    });
+                downloaded.push(download.await.unwrap());
+            }
+            downloaded
+        };
+        let ((), downloaded) = tokio::join!(server, download);
+        assert_eq!(downloaded, [files[1].clone(), files[0].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let timeouts = Timeouts::new(Duration::ZERO, Duration::ZERO);
+
+        // The error the server reports is told to the user as it is
+        let server = async {
+            let mut stream = accept(&listener, VERSION).await;
+            Request::read(&mut stream, &Limits::NONE).await.unwrap();
+            let error = Response::Error {
+                kind: ServerErrorKind::NotFound,
+                message: "Upload not found".to_string(),
+            };
+            stream.write_all(&error.encode().unwrap()).await.unwrap();
+        };
+        let client = async {
+            let client =
+                TcpClient::new(&address, timeouts, None, None, None, None);
+            client.await.unwrap().exists(&"0".repeat(64)).await
+        };
+        let ((), exists) = tokio::join!(server, client);
+        let error = exists.unwrap_err();
+        let error = server_error(&error).unwrap();
+        assert_eq!(error.kind, ServerErrorKind::NotFound);
+        assert_eq!(error.reason, "Upload not found");
+        assert_eq!(exit_code(error.kind), 66);
+    }
+
+    #[tokio::test]
+    async fn test_http_api() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/", listener.local_addr().unwrap());
+        let url = Url::parse(&url).unwrap();
+        let timeouts = Timeouts::new(Duration::ZERO, Duration::ZERO);
+        let client = || TcpClient::over_http(&url, timeouts, None, None, None);
+
+        let body = r#"{"used":5,"quota":null}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let (head, usage) = tokio::join!(answer(&listener, &response), async {
+            client().await.unwrap().usage().await.unwrap()
+        });
+        assert!(head.starts_with("GET /api/usage HTTP/1.1\r\n"));
+        assert_eq!(usage, (5, None));
+
+        // Errors are told by the status, with the reason in the body
+        let body = r#"{"error":"Quota of 5 bytes exceeded"}"#;
+        let response = format!(
+            "HTTP/1.1 507 Insufficient Storage\r\nContent-Length: {}\r\n\
+             \r\n{}",
+            body.len(),
+            body
+        );
+        let (_, deleted) = tokio::join!(answer(&listener, &response), async {
+            client().await.unwrap().delete(&"0".repeat(64)).await
+        });
+        let error = deleted.unwrap_err();
+        let error = server_error(&error).unwrap();
+        assert_eq!(error.kind, ServerErrorKind::QuotaExceeded);
+        assert_eq!(error.reason, "Quota of 5 bytes exceeded");
+
+        let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        let (head, deleted) =
+            tokio::join!(answer(&listener, response), async {
+                client().await.unwrap().delete(&"0".repeat(64)).await
+            });
+        assert!(head
+            .starts_with(&format!("DELETE /api/uploads/{}", "0".repeat(64))));
+        assert!(!deleted.unwrap());
     }
 }
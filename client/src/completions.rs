@@ -0,0 +1,427 @@
+use clap::builder::PossibleValue;
+use clap::{Arg, ArgAction, Command, ValueEnum, ValueHint};
+use std::io::{self, Write};
+
+/// The shells completion scripts are generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    /// Bash, e.g. sourced from ~/.bashrc or installed in
+    /// /usr/share/bash-completion/completions
+    Bash,
+    /// Zsh, installed as `_client` in a directory of $fpath
+    Zsh,
+    /// Fish, installed in ~/.config/fish/completions
+    Fish,
+}
+
+/// Writes the completion script of a command for a shell, completing its
+/// subcommands, their options, and the values of the options: the possible
+/// ones, or paths for the options taking files.
+///
+/// # Errors
+///
+/// Returns an error if the script cannot be written.
+pub fn generate(
+    shell: Shell,
+    mut cmd: Command,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    // Propagate the global options to the subcommands
+    cmd.build();
+    let commands = commands(&cmd);
+    match shell {
+        Shell::Bash => bash(cmd.get_name(), &commands, out),
+        Shell::Zsh => zsh(cmd.get_name(), &commands, out),
+        Shell::Fish => fish(cmd.get_name(), &commands, out),
+    }
+}
+
+/// Returns a command and its visible subcommands, recursively, with the
+/// names leading to each of them, the `help` subcommands being completed
+/// without the subcommands they take.
+pub fn commands(cmd: &Command) -> Vec<(Vec<&str>, &Command)> {
+    let mut commands = vec![(vec![cmd.get_name()], cmd)];
+    let mut index = 0;
+    while let Some((path, cmd)) = commands.get(index).cloned() {
+        let subs = subcommands(cmd).filter(|_| cmd.get_name() != "help");
+        for sub in subs {
+            let mut path = path.clone();
+            path.push(sub.get_name());
+            commands.push((path, sub));
+        }
+        index += 1;
+    }
+    commands
+}
+
+/// Returns the visible subcommands of a command.
+pub fn subcommands(cmd: &Command) -> impl Iterator<Item = &Command> {
+    cmd.get_subcommands().filter(|sub| !sub.is_hide_set())
+}
+
+/// Returns the visible options of a command, i.e. its arguments that are not
+/// positional.
+pub fn options(cmd: &Command) -> impl Iterator<Item = &Arg> {
+    cmd.get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+}
+
+/// Returns the names of an option, e.g. `-s` and `--server-addr`.
+fn flags(arg: &Arg) -> Vec<String> {
+    let short = arg.get_short().map(|short| format!("-{}", short));
+    let long = arg.get_long().map(|long| format!("--{}", long));
+    short.into_iter().chain(long).collect()
+}
+
+/// Returns the visible values an option takes, if it only takes some.
+fn possible_values(arg: &Arg) -> Vec<PossibleValue> {
+    arg.get_possible_values()
+        .into_iter()
+        .filter(|value| !value.is_hide_set())
+        .collect()
+}
+
+/// Returns the first line of the help of an argument or a command.
+fn summary(help: Option<&clap::builder::StyledStr>) -> String {
+    help.map(ToString::to_string)
+        .and_then(|help| help.lines().next().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Returns whether an option takes a path.
+fn takes_path(arg: &Arg) -> bool {
+    matches!(
+        arg.get_value_hint(),
+        ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath
+    )
+}
+
+/// Returns whether an option can be given several times.
+fn is_repeated(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append | ArgAction::Count)
+}
+
+/// Returns the name of the shell function or state of a command, e.g.
+/// `client__manifest__export`.
+fn ident(path: &[&str]) -> String {
+    path.join("__").replace('-', "_")
+}
+
+fn bash(
+    name: &str,
+    commands: &[(Vec<&str>, &Command)],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "_{}() {{", ident(&[name]))?;
+    writeln!(out, "    local cur prev cmd i")?;
+    writeln!(out, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"")?;
+    writeln!(out, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"")?;
+    writeln!(out, "    cmd=\"{}\"", ident(&[name]))?;
+    // Find the subcommand being completed among the words before
+    writeln!(out, "    for ((i = 1; i < COMP_CWORD; i++)); do")?;
+    writeln!(out, "        case \"${{cmd}}__${{COMP_WORDS[i]}}\" in")?;
+    for (path, _) in &commands[1..] {
+        let (parent, sub) = path.split_at(path.len() - 1);
+        writeln!(
+            out,
+            "            {}__{}) cmd=\"{}\" ;;",
+            ident(parent),
+            sub[0],
+            ident(path)
+        )?;
+    }
+    writeln!(out, "        esac")?;
+    writeln!(out, "    done")?;
+    writeln!(out)?;
+    writeln!(out, "    case \"${{cmd}}\" in")?;
+    for (path, cmd) in commands {
+        writeln!(out, "        {})", ident(path))?;
+        let words = options(cmd)
+            .flat_map(flags)
+            .chain(subcommands(cmd).map(|sub| sub.get_name().to_string()))
+            .collect::<Vec<_>>();
+        writeln!(out, "            opts=\"{}\"", words.join(" "))?;
+        writeln!(out, "            case \"${{prev}}\" in")?;
+        for arg in options(cmd).filter(|arg| arg.get_action().takes_values()) {
+            let values = possible_values(arg);
+            let reply = if !values.is_empty() {
+                let values = values
+                    .iter()
+                    .map(|value| value.get_name())
+                    .collect::<Vec<_>>();
+                format!(
+                    "COMPREPLY=($(compgen -W \"{}\" -- \"${{cur}}\"))",
+                    values.join(" ")
+                )
+            } else if arg.get_value_hint() == ValueHint::DirPath {
+                "COMPREPLY=($(compgen -d -- \"${cur}\"))".to_string()
+            } else if takes_path(arg) {
+                "COMPREPLY=($(compgen -f -- \"${cur}\"))".to_string()
+            } else {
+                "COMPREPLY=()".to_string()
+            };
+            writeln!(out, "                {})", flags(arg).join("|"))?;
+            writeln!(out, "                    {}", reply)?;
+            writeln!(out, "                    return 0")?;
+            writeln!(out, "                    ;;")?;
+        }
+        writeln!(out, "            esac")?;
+        if cmd.get_positionals().any(takes_path) {
+            writeln!(out, "            if [[ \"${{cur}}\" != -* ]]; then")?;
+            writeln!(
+                out,
+                "                COMPREPLY=($(compgen -f -- \"${{cur}}\"))"
+            )?;
+            writeln!(out, "                return 0")?;
+            writeln!(out, "            fi")?;
+        }
+        writeln!(
+            out,
+            "            COMPREPLY=($(compgen -W \"${{opts}}\" -- \"${{cur}}\"))"
+        )?;
+        writeln!(out, "            ;;")?;
+    }
+    writeln!(out, "    esac")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "complete -F _{} -o bashdefault -o default {}",
+        ident(&[name]),
+        name
+    )
+}
+
+/// Escapes text for a single-quoted zsh word, and for the brackets of an
+/// `_arguments` description.
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh(
+    name: &str,
+    commands: &[(Vec<&str>, &Command)],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "#compdef {}", name)?;
+    writeln!(out)?;
+    writeln!(out, "_{}() {{", ident(&[name]))?;
+    writeln!(out, "    local cmd={} start=1 i", ident(&[name]))?;
+    // Find the subcommand being completed among the words before, and
+    // complete its arguments as if it were the command
+    writeln!(out, "    for ((i = 2; i < CURRENT; i++)); do")?;
+    writeln!(out, "        case \"${{cmd}}__${{words[i]}}\" in")?;
+    for (path, _) in &commands[1..] {
+        let (parent, sub) = path.split_at(path.len() - 1);
+        writeln!(
+            out,
+            "            ({}__{}) cmd={} start=$i ;;",
+            ident(parent),
+            sub[0],
+            ident(path)
+        )?;
+    }
+    writeln!(out, "        esac")?;
+    writeln!(out, "    done")?;
+    writeln!(out, "    words=(\"${{(@)words[start,-1]}}\")")?;
+    writeln!(out, "    (( CURRENT -= start - 1 ))")?;
+    writeln!(out)?;
+    writeln!(out, "    case $cmd in")?;
+    for (path, cmd) in commands {
+        writeln!(out, "        ({})", ident(path))?;
+        writeln!(out, "            _arguments -s -S \\")?;
+        for arg in options(cmd) {
+            let help = zsh_escape(&summary(arg.get_help()));
+            let repeated = if is_repeated(arg) { "*" } else { "" };
+            let value = match arg.get_action().takes_values() {
+                false => String::new(),
+                true => {
+                    let value_name = arg
+                        .get_value_names()
+                        .and_then(|names| names.first())
+                        .map_or_else(
+                            || arg.get_id().to_string().to_uppercase(),
+                            ToString::to_string,
+                        );
+                    let values = possible_values(arg);
+                    let action = if !values.is_empty() {
+                        let values = values
+                            .iter()
+                            .map(|value| value.get_name())
+                            .collect::<Vec<_>>();
+                        format!("({})", values.join(" "))
+                    } else if arg.get_value_hint() == ValueHint::DirPath {
+                        "_files -/".to_string()
+                    } else if takes_path(arg) {
+                        "_files".to_string()
+                    } else {
+                        " ".to_string()
+                    };
+                    format!(":{}:{}", value_name, action)
+                }
+            };
+            let takes_value = arg.get_action().takes_values();
+            for flag in flags(arg) {
+                let separator = match (takes_value, flag.starts_with("--")) {
+                    (false, _) => "",
+                    (true, true) => "=",
+                    (true, false) => "+",
+                };
+                writeln!(
+                    out,
+                    "                '{}{}{}[{}]{}' \\",
+                    repeated, flag, separator, help, value
+                )?;
+            }
+        }
+        for arg in cmd.get_positionals().filter(|arg| !arg.is_hide_set()) {
+            let value_name = arg
+                .get_value_names()
+                .and_then(|names| names.first())
+                .map_or_else(|| arg.get_id().to_string(), ToString::to_string);
+            let action = match takes_path(arg) {
+                true => "_files",
+                false => " ",
+            };
+            writeln!(out, "                ':{}:{}' \\", value_name, action)?;
+        }
+        let subs = subcommands(cmd)
+            .map(|sub| {
+                format!(
+                    "{}\\:\"{}\"",
+                    sub.get_name(),
+                    zsh_escape(&summary(sub.get_about())).replace('"', "\\\"")
+                )
+            })
+            .collect::<Vec<_>>();
+        if !subs.is_empty() {
+            writeln!(
+                out,
+                "                ':command:(({}))' \\",
+                subs.join(" ")
+            )?;
+        }
+        writeln!(out, "                && return 0")?;
+        writeln!(out, "            ;;")?;
+    }
+    writeln!(out, "    esac")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "if [ \"$funcstack[1]\" = \"_{}\" ]; then",
+        ident(&[name])
+    )?;
+    writeln!(out, "    _{} \"$@\"", ident(&[name]))?;
+    writeln!(out, "else")?;
+    writeln!(out, "    compdef _{} {}", ident(&[name]), name)?;
+    writeln!(out, "fi")
+}
+
+/// Escapes text for a single-quoted fish word.
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish(
+    name: &str,
+    commands: &[(Vec<&str>, &Command)],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    for (path, cmd) in commands {
+        // The subcommands leading to the command must be given, and none of
+        // its own yet
+        let mut conditions = path[1..]
+            .iter()
+            .map(|sub| format!("__fish_seen_subcommand_from {}", sub))
+            .collect::<Vec<_>>();
+        let subs = subcommands(cmd).map(Command::get_name).collect::<Vec<_>>();
+        match (path.len(), subs.is_empty()) {
+            (1, _) => conditions.push("__fish_use_subcommand".to_string()),
+            (_, false) => conditions.push(format!(
+                "not __fish_seen_subcommand_from {}",
+                subs.join(" ")
+            )),
+            (_, true) => {}
+        }
+        let condition = conditions.join("; and ");
+        for arg in options(cmd) {
+            let mut line = format!("complete -c {} -n '{}'", name, condition);
+            if let Some(short) = arg.get_short() {
+                line.push_str(&format!(" -s {}", short));
+            }
+            if let Some(long) = arg.get_long() {
+                line.push_str(&format!(" -l {}", long));
+            }
+            line.push_str(&format!(
+                " -d '{}'",
+                fish_escape(&summary(arg.get_help()))
+            ));
+            if arg.get_action().takes_values() {
+                let values = possible_values(arg);
+                if !values.is_empty() {
+                    let values = values
+                        .iter()
+                        .map(|value| value.get_name())
+                        .collect::<Vec<_>>();
+                    line.push_str(&format!(" -x -a '{}'", values.join(" ")));
+                } else if takes_path(arg) {
+                    line.push_str(" -r -F");
+                } else {
+                    line.push_str(" -x");
+                }
+            }
+            writeln!(out, "{}", line)?;
+        }
+        for sub in subcommands(cmd) {
+            writeln!(
+                out,
+                "complete -c {} -n '{}' -f -a {} -d '{}'",
+                name,
+                condition,
+                sub.get_name(),
+                fish_escape(&summary(sub.get_about()))
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Args;
+    use clap::CommandFactory;
+
+    fn script(shell: Shell) -> String {
+        let mut out = vec![];
+        generate(shell, Args::command(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_generate() {
+        let bash = script(Shell::Bash);
+        assert!(bash.contains("client__manifest__export) cmd=\""));
+        assert!(bash.contains("--output)\n"));
+        assert!(bash.contains("compgen -W \"text json\""));
+        assert!(bash.contains("complete -F _client"));
+
+        let zsh = script(Shell::Zsh);
+        assert!(zsh.starts_with("#compdef client\n"));
+        assert!(zsh.contains("'--store-dir=[The directory"));
+        assert!(zsh.contains("'*-f+[The files"));
+
+        let fish = script(Shell::Fish);
+        assert!(fish.contains(
+            "complete -c client -n '__fish_use_subcommand' -f -a upload"
+        ));
+        assert!(fish.contains(
+            "-n '__fish_seen_subcommand_from manifest; and \
+             __fish_seen_subcommand_from export'"
+        ));
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use serde::{de, Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The server address used when neither the command line nor the selected
+/// profile provides one.
+pub const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:2345";
+
+/// The port of the server when its address does not give one.
+const DEFAULT_PORT: u16 = 2345;
+
+/// The client configuration, read from a TOML file.
+///
+/// ```toml
+/// default_profile = "prod"
+///
+/// [profiles.prod]
+/// address = "files.example.com:2345"
+/// store_dir = "/var/lib/file-guardian"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The profile used when `--profile` is not given.
+    pub default_profile: Option<String>,
+    /// The named server profiles.
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// A named set of settings for a server.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// The server address, in the format `host:port`, with IPv6 literals in
+    /// brackets.
+    pub address: Option<String>,
+    /// Whether to connect to the server over TLS.
+    #[serde(default)]
+    pub tls: bool,
+    /// A PEM file of the certificate authorities to trust over TLS.
+    pub tls_ca: Option<PathBuf>,
+    /// The name the server certificate must be valid for.
+    pub tls_server_name: Option<String>,
+    /// The PEM certificate chain presented to the server, if it requires TLS
+    /// client certificates.
+    pub tls_cert: Option<PathBuf>,
+    /// The PEM private key of the TLS client certificate.
+    pub tls_key: Option<PathBuf>,
+    /// The token used to authenticate to the server.
+    pub token: Option<String>,
+    /// The file of the key encrypting and decrypting the files.
+    pub encryption_key_file: Option<PathBuf>,
+    /// The store directory used with this server.
+    pub store_dir: Option<PathBuf>,
+    /// The time to wait for a connection to the server, e.g. `5s`.
+    #[serde(default, deserialize_with = "duration")]
+    pub connect_timeout: Option<Duration>,
+    /// The time to wait for the server to send or accept data, e.g. `1m`.
+    #[serde(default, deserialize_with = "duration")]
+    pub timeout: Option<Duration>,
+    /// The bandwidth limit of the transfers, e.g. `10MB/s`.
+    #[serde(default, deserialize_with = "rate")]
+    pub limit_rate: Option<u64>,
+    /// The zstd level the contents of files are compressed with in transit,
+    /// if they are.
+    pub wire_compression: Option<i32>,
+    /// The sources of the root hashes downloads must chain to, e.g.
+    /// `https://example.com/roots.txt`.
+    #[serde(default)]
+    pub trust_anchors: Vec<String>,
+    /// The public key the server must sign the roots of the uploads with,
+    /// as hex.
+    pub server_key: Option<String>,
+}
+
+/// Deserializes a human readable duration, e.g. `1m 30s`.
+fn duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|duration| {
+            humantime::parse_duration(&duration).map_err(de::Error::custom)
+        })
+        .transpose()
+}
+
+/// Deserializes a bandwidth limit, e.g. `10MB/s`.
+fn rate<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|rate| {
+            crate::throttle::parse_rate(&rate).map_err(de::Error::custom)
+        })
+        .transpose()
+}
+
+impl Config {
+    /// Returns the path of the configuration file in the platform config
+    /// directory (e.g. `~/.config/file-guardian/config.toml` on Linux).
+    pub fn default_path() -> Option<PathBuf> {
+        directories::ProjectDirs::from("", "", "file-guardian")
+            .map(|dirs| dirs.config_dir().join("config.toml"))
+    }
+
+    /// Loads the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the configuration file. If `None`, the file in
+    ///   the platform config directory is used, if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or parsed, or if an
+    /// explicitly given file does not exist.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            anyhow::anyhow!("Could not read config {}: {}", path.display(), e)
+        })?;
+        Self::parse(&content).map_err(|e| {
+            anyhow::anyhow!("Invalid config {}: {}", path.display(), e)
+        })
+    }
+
+    /// Parses the configuration from a TOML string.
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Returns the profile to use.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The profile name given on the command line, if any. The
+    ///   default profile is used otherwise, or an empty profile if there is
+    ///   none.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the profile does not exist.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => {
+                self.profiles.get(name).cloned().ok_or_else(|| {
+                    anyhow::anyhow!("Profile {} not found", name)
+                })
+            }
+            None => Ok(Profile::default()),
+        }
+    }
+}
+
+impl Profile {
+    /// Returns the server address to connect to, preferring the one given on
+    /// the command line, in the format `host:port`, or `unix:<path>`.
+    ///
+    /// Addresses without a port get the default one, and bare IPv6 literals
+    /// get brackets, e.g. `::1` becomes `[::1]:2345`.
+    pub fn server_addr(&self, server_addr: Option<String>) -> String {
+        let addr = server_addr
+            .or_else(|| self.address.clone())
+            .unwrap_or_else(|| DEFAULT_SERVER_ADDR.to_string());
+        // The URL of the HTTP API of the server, or its Unix socket
+        if addr.contains("://") || addr.starts_with("unix:") {
+            return addr;
+        }
+        if addr.parse::<Ipv6Addr>().is_ok() {
+            return format!("[{}]:{}", addr, DEFAULT_PORT);
+        }
+        let port = match addr.strip_prefix('[') {
+            Some(literal) => literal.split_once("]:").map(|(_, port)| port),
+            None => addr.rsplit_once(':').map(|(_, port)| port),
+        };
+        match port {
+            Some(_) => addr,
+            None => format!("{}:{}", addr, DEFAULT_PORT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        default_profile = "local"
+
+        [profiles.local]
+        address = "127.0.0.1:2345"
+
+        [profiles.prod]
+        address = "files.example.com:2345"
+        store_dir = "/var/lib/file-guardian"
+        timeout = "1m 30s"
+        limit_rate = "10MB/s"
+        trust_anchors = ["https://example.com/roots.txt"]
+    "#;
+
+    #[test]
+    fn test_profile() {
+        let config = Config::parse(CONFIG).unwrap();
+
+        let profile = config.profile(Some("prod")).unwrap();
+        assert_eq!(
+            profile.store_dir,
+            Some(PathBuf::from("/var/lib/file-guardian"))
+        );
+        assert_eq!(profile.server_addr(None), "files.example.com:2345");
+        assert_eq!(profile.timeout, Some(Duration::from_secs(90)));
+        assert_eq!(profile.connect_timeout, None);
+        assert_eq!(profile.limit_rate, Some(10_000_000));
+        assert_eq!(profile.trust_anchors, ["https://example.com/roots.txt"]);
+        assert_eq!(
+            profile.server_addr(Some("10.0.0.1:80".to_string())),
+            "10.0.0.1:80"
+        );
+
+        let profile = config.profile(None).unwrap();
+        assert_eq!(profile.server_addr(None), "127.0.0.1:2345");
+        let server_addr = |addr: &str| profile.server_addr(Some(addr.into()));
+        assert_eq!(server_addr("files.example.com"), "files.example.com:2345");
+        assert_eq!(server_addr("[::1]:80"), "[::1]:80");
+        assert_eq!(server_addr("[::1]"), "[::1]:2345");
+        assert_eq!(server_addr("::1"), "[::1]:2345");
+        assert_eq!(server_addr("http://[::1]"), "http://[::1]");
+        assert_eq!(server_addr("unix:server.sock"), "unix:server.sock");
+
+        assert!(config.profile(Some("staging")).is_err());
+    }
+
+    #[test]
+    fn test_empty_config() {
+        let config = Config::parse("").unwrap();
+        let profile = config.profile(None).unwrap();
+        assert_eq!(profile.server_addr(None), DEFAULT_SERVER_ADDR);
+        assert!(Config::parse("unknown = 1").is_err());
+        assert!(Config::parse("[profiles.a]\ntimeout = \"soon\"").is_err());
+    }
+}
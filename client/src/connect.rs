@@ -0,0 +1,155 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+/// The time to wait for a connection attempt before starting the next one
+/// alongside it, as recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to an address, trying every IPv6 and IPv4 address a host name
+/// resolves to ("Happy Eyeballs", RFC 8305).
+///
+/// The addresses are tried in the order of the resolver, alternating between
+/// the two families, starting an attempt as soon as the previous one fails,
+/// or after [`ATTEMPT_DELAY`] while it is pending. The first connection
+/// established wins, and the attempts still pending are dropped. Hosts with
+/// an unreachable address in one family, e.g. an IPv6 address on a network
+/// without IPv6 routes, are then reached over the other one without waiting
+/// for the first attempt to time out.
+///
+/// # Arguments
+///
+/// * `address` - The address to connect to, in the format `host:port`, with
+///   IPv6 literals in brackets, e.g. `[::1]:2345`.
+///
+/// # Errors
+///
+/// Returns an error if the host cannot be resolved, or the error of the last
+/// attempt if no address accepts the connection.
+pub async fn connect(address: &str) -> io::Result<TcpStream> {
+    let addrs = tokio::net::lookup_host(address).await?.collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Could not resolve {}", address),
+        ));
+    }
+    race(interleave(addrs)).await
+}
+
+/// Returns the path of the Unix socket of an address in the format
+/// `unix:<path>`, or None for a TCP address.
+pub fn unix_path(address: &str) -> Option<&Path> {
+    address.strip_prefix("unix:").map(Path::new)
+}
+
+/// Orders addresses alternating between the two families, starting with
+/// the family of the first one, which the resolver prefers.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let ipv6 = addrs[0].is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == ipv6);
+    let mut other = other.into_iter();
+    let mut addrs = vec![];
+    for addr in preferred {
+        addrs.push(addr);
+        addrs.extend(other.next());
+    }
+    addrs.extend(other);
+    addrs
+}
+
+/// Connects to the first of `addrs` accepting the connection, starting the
+/// attempts one after the other.
+async fn race(addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let mut addrs = addrs.into_iter();
+    let mut attempts = JoinSet::new();
+    let mut next = addrs.next();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = next.take() {
+            log::debug!("Connecting to {}", addr);
+            attempts
+                .spawn(async move { (addr, TcpStream::connect(addr).await) });
+        }
+        tokio::select! {
+            attempt = attempts.join_next() => {
+                // Every attempt failed
+                let Some(attempt) = attempt else {
+                    return Err(last_error.expect("an attempt was started"));
+                };
+                match attempt.map_err(io::Error::other)? {
+                    (_, Ok(stream)) => return Ok(stream),
+                    (addr, Err(error)) => {
+                        log::debug!("Could not connect to {}: {}", addr, error);
+                        last_error = Some(error);
+                        next = addrs.next();
+                    }
+                }
+            }
+            _ = tokio::time::sleep(ATTEMPT_DELAY),
+                if !addrs.as_slice().is_empty() =>
+            {
+                next = addrs.next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_interleave() {
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        let addrs = vec![
+            addr("[2001:db8::1]:2345"),
+            addr("[2001:db8::2]:2345"),
+            addr("[2001:db8::3]:2345"),
+            addr("192.0.2.1:2345"),
+        ];
+        assert_eq!(
+            interleave(addrs),
+            vec![
+                addr("[2001:db8::1]:2345"),
+                addr("192.0.2.1:2345"),
+                addr("[2001:db8::2]:2345"),
+                addr("[2001:db8::3]:2345"),
+            ]
+        );
+        let addrs = vec![addr("192.0.2.1:2345"), addr("192.0.2.2:2345")];
+        assert_eq!(interleave(addrs.clone()), addrs);
+    }
+
+    #[test]
+    fn test_unix_path() {
+        assert_eq!(
+            unix_path("unix:/run/server.sock"),
+            Some(Path::new("/run/server.sock"))
+        );
+        assert_eq!(unix_path("127.0.0.1:2345"), None);
+        assert_eq!(unix_path("files.example.com:2345"), None);
+    }
+
+    #[tokio::test]
+    async fn test_race() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // Nothing listens on the port once its listener is dropped
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let stream = race(vec![closed, open]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        let error = race(vec![closed]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::progress::CHUNK_SIZE;
+use aes_gcm::aead::{consts::U12, Aead, KeyInit, OsRng};
+use aes_gcm::{AeadCore, Aes256Gcm, Nonce};
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// The first bytes of an encrypted file, identifying the format.
+const MAGIC: &[u8; 4] = b"FGE1";
+
+/// The size of the random prefix of the nonces of a file, followed by the
+/// index of the chunk and whether it is the last one.
+const NONCE_PREFIX_SIZE: usize = 7;
+
+/// The size of the authentication tag appended to every encrypted chunk.
+const TAG_SIZE: usize = 16;
+
+/// The key files are encrypted with before they are uploaded, so that the
+/// server only ever stores ciphertext.
+///
+/// Files are encrypted with AES-256-GCM in chunks of [`CHUNK_SIZE`] bytes,
+/// each with its own nonce made of a random prefix, the index of the chunk
+/// and a flag marking the last chunk, so that chunks cannot be reordered or
+/// dropped, nor the file truncated, without decryption failing.
+pub struct Key {
+    cipher: Aes256Gcm,
+}
+
+impl Key {
+    /// Reads a key from a file holding 32 bytes as 64 hex characters, e.g.
+    /// generated with `openssl rand -hex 32`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or does not hold a key.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            anyhow!("Could not read key file {}: {}", path.display(), e)
+        })?;
+        Self::from_hex(&content).map_err(|_| {
+            anyhow!(
+                "Invalid key file {}: expected 64 hex characters",
+                path.display()
+            )
+        })
+    }
+
+    /// Parses a key from 64 hex characters, e.g. as stored in the keyring.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the string is not a key.
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let key: [u8; 32] = hex::decode(key.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| {
+                anyhow!("Invalid key: expected 64 hex characters")
+            })?;
+        Ok(Self {
+            cipher: Aes256Gcm::new(&key.into()),
+        })
+    }
+
+    /// Encrypts the content of `reader` into `writer`, one chunk at a time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading or writing fails.
+    pub fn encrypt(
+        &self,
+        mut reader: impl Read,
+        mut writer: impl Write,
+    ) -> Result<()> {
+        let prefix = Aes256Gcm::generate_nonce(&mut OsRng);
+        let prefix = &prefix[..NONCE_PREFIX_SIZE];
+        writer.write_all(MAGIC)?;
+        writer.write_all(prefix)?;
+
+        // A chunk shorter than the others is the last one, and empty if the
+        // size of the file is a multiple of the chunk size
+        let mut chunk = vec![0; CHUNK_SIZE];
+        for index in 0.. {
+            let len = read_chunk(&mut reader, &mut chunk)?;
+            let last = len < CHUNK_SIZE;
+            let encrypted = self
+                .cipher
+                .encrypt(&nonce(prefix, index, last), &chunk[..len])
+                .map_err(|_| anyhow!("Could not encrypt file"))?;
+            writer.write_all(&encrypted)?;
+            if last {
+                break;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Decrypts a file encrypted with [`Key::encrypt`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file is not encrypted, was encrypted with
+    /// another key, or was modified.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let header = MAGIC.len() + NONCE_PREFIX_SIZE;
+        if data.len() < header || !data.starts_with(MAGIC) {
+            return Err(anyhow!("Not an encrypted file"));
+        }
+        let prefix = &data[MAGIC.len()..header];
+
+        let mut file = Vec::with_capacity(data.len());
+        let mut chunks = data[header..].chunks(CHUNK_SIZE + TAG_SIZE);
+        for index in 0.. {
+            let chunk = chunks.next().unwrap_or_default();
+            let last = chunk.len() < CHUNK_SIZE + TAG_SIZE;
+            let decrypted = self
+                .cipher
+                .decrypt(&nonce(prefix, index, last), chunk)
+                .map_err(|_| {
+                    anyhow!("Could not decrypt file, the key may be wrong")
+                })?;
+            file.extend_from_slice(&decrypted);
+            if last {
+                break;
+            }
+        }
+        Ok(file)
+    }
+}
+
+/// Returns the nonce of a chunk.
+fn nonce(prefix: &[u8], index: u32, last: bool) -> Nonce<U12> {
+    let mut nonce = [0; 12];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last.into();
+    nonce.into()
+}
+
+/// Fills `chunk` from `reader`, and returns the number of bytes read, less
+/// than the size of the chunk only at the end of the file.
+fn read_chunk(reader: &mut impl Read, chunk: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < chunk.len() {
+        match reader.read(&mut chunk[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> Key {
+        Key {
+            cipher: Aes256Gcm::new(&[byte; 32].into()),
+        }
+    }
+
+    fn encrypt(key: &Key, data: &[u8]) -> Vec<u8> {
+        let mut encrypted = vec![];
+        key.encrypt(data, &mut encrypted).unwrap();
+        encrypted
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = key(1);
+        for size in [0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 1] {
+            let data = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+            let encrypted = encrypt(&key, &data);
+            assert_ne!(&encrypted[11..], &data[..]);
+            assert_eq!(key.decrypt(&encrypted).unwrap(), data);
+        }
+        // Every encryption uses new nonces
+        assert_ne!(encrypt(&key, b"data"), encrypt(&key, b"data"));
+    }
+
+    #[test]
+    fn test_decrypt_invalid() {
+        let data = vec![7; CHUNK_SIZE + 10];
+        let encrypted = encrypt(&key(1), &data);
+        assert!(key(2).decrypt(&encrypted).is_err());
+        assert!(key(1).decrypt(b"plaintext").is_err());
+
+        // Dropping the last chunk is detected
+        let truncated = &encrypted[..11 + CHUNK_SIZE + TAG_SIZE];
+        assert!(key(1).decrypt(truncated).is_err());
+
+        let mut modified = encrypted.clone();
+        modified[20] ^= 1;
+        assert!(key(1).decrypt(&modified).is_err());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let dir = std::env::temp_dir().join("file-guardian-test-key-file");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key");
+        fs::write(&path, format!("{}\n", "ab".repeat(32))).unwrap();
+        assert!(Key::from_file(&path).is_ok());
+        fs::write(&path, "ab".repeat(16)).unwrap();
+        assert!(Key::from_file(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(Key::from_hex(&"cd".repeat(32)).is_ok());
+        assert!(Key::from_hex("not a key").is_err());
+    }
+}
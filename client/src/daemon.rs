@@ -0,0 +1,700 @@
+use crate::client::Server;
+use crate::db::Db;
+use crate::encoding::Encoding;
+use crate::trash::Trash;
+use crate::utils;
+use crate::UploadOptions;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot, Notify};
+
+/// The name of the socket of the daemon, in the store directory.
+const SOCKET: &str = "daemon.sock";
+
+/// The maximum size of a request, in bytes.
+const MAX_REQUEST: u64 = 16 * 1024 * 1024;
+
+/// The number of finished jobs the daemon remembers, for `daemon status`.
+const FINISHED_JOBS: usize = 100;
+
+/// Returns the path of the socket of the daemon of a store.
+pub fn socket(store_dir: &Path) -> PathBuf {
+    store_dir.join(SOCKET)
+}
+
+/// A request sent to the daemon, as a line of JSON, answered by a
+/// [`Response`] before the daemon closes the connection.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// Queues an upload, answered once it is queued.
+    Upload(UploadRequest),
+    /// Waits for a job, answered once it has succeeded or failed.
+    Wait { job: u64 },
+    /// Returns the state of the daemon.
+    Status,
+    /// Stops the daemon once the job it runs, if any, is done.
+    Stop,
+}
+
+/// An upload queued on the daemon.
+#[derive(Serialize, Deserialize)]
+pub struct UploadRequest {
+    pub files: Vec<QueuedFile>,
+    pub tags: Vec<String>,
+    /// Whether to verify the proof of every file served by the server.
+    pub verify: bool,
+    /// Whether to ask the server whether it already holds the batch first.
+    pub dedup: bool,
+    /// The number of files sent concurrently.
+    pub jobs: usize,
+    /// How long the original files are kept in the trash once the upload is
+    /// verified, if they are deleted.
+    pub trash_retention: Option<Duration>,
+    /// How long the server keeps the upload, if it is to expire.
+    pub retention: Option<Duration>,
+}
+
+/// A file of an upload queued on the daemon.
+#[derive(Serialize, Deserialize)]
+pub struct QueuedFile {
+    /// The absolute path of the file.
+    #[serde(with = "utils::serde_path")]
+    pub path: PathBuf,
+    /// The name the file is uploaded under.
+    pub name: String,
+}
+
+/// The answer of the daemon to a [`Request`].
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Job(Job),
+    Status(Status),
+    Stopping,
+    Error { message: String },
+}
+
+/// An upload queued on the daemon.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    /// The number of the job, unique to the daemon.
+    pub id: u64,
+    /// The number of files of the upload.
+    pub files: usize,
+    /// The time the job was queued, in seconds since the Unix epoch.
+    pub queued_at: u64,
+    #[serde(flatten)]
+    pub state: JobState,
+}
+
+/// Where a job stands.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobState {
+    Pending,
+    Running,
+    Uploaded {
+        root_hash: String,
+        /// Whether the server already held the files.
+        deduplicated: bool,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// The state of the daemon.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    /// The address of the server the daemon uploads to.
+    pub server_addr: String,
+    /// The number of connections to the server ready to be used.
+    pub ready_connections: usize,
+    /// The pending and running jobs, then the last finished ones.
+    pub jobs: Vec<Job>,
+    /// The last verification of the uploads, if any.
+    pub verification: Option<Verification>,
+}
+
+/// A verification of the uploads recorded for the server of the daemon.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Verification {
+    /// The time the verification finished, in seconds since the Unix epoch.
+    pub at: u64,
+    /// The number of uploads verified.
+    pub uploads: usize,
+    /// The uploads that failed to verify, with the reason.
+    pub failures: Vec<String>,
+}
+
+/// How the daemon runs.
+pub struct DaemonOptions<'a> {
+    /// How often the uploads recorded for the server are verified, if they
+    /// are.
+    pub verify_every: Option<Duration>,
+    /// The trash the original files of the uploads are moved to.
+    pub trash: &'a Trash,
+}
+
+/// The SHA-256 of the files hashed by the daemon, reused while their size
+/// and modification time are unchanged, so that files queued again are not
+/// hashed again to look them up on the server.
+#[derive(Default)]
+pub struct Hashes(Mutex<HashMap<PathBuf, Hashed>>);
+
+/// A file hashed by the daemon.
+struct Hashed {
+    size: u64,
+    modified: SystemTime,
+    sha256: [u8; 32],
+}
+
+impl Hashes {
+    /// Returns the SHA-256 of a file, hashing it unless it is unchanged
+    /// since it was last hashed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read.
+    pub fn sha256_file(&self, path: &Path) -> Result<[u8; 32]> {
+        let metadata = std::fs::metadata(path)?;
+        let (size, modified) = (metadata.len(), metadata.modified()?);
+        if let Some(hashed) =
+            self.0.lock().unwrap().get(path).filter(|hashed| {
+                hashed.size == size && hashed.modified == modified
+            })
+        {
+            return Ok(hashed.sha256);
+        }
+        let sha256 = utils::sha256_file(path)?;
+        let hashed = Hashed {
+            size,
+            modified,
+            sha256,
+        };
+        self.0.lock().unwrap().insert(path.to_path_buf(), hashed);
+        Ok(sha256)
+    }
+}
+
+/// The jobs of the daemon, shared with the connections to its socket.
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    jobs: Vec<Job>,
+    /// The connections waiting for a job, by job.
+    waiters: HashMap<u64, Vec<oneshot::Sender<Job>>>,
+    verification: Option<Verification>,
+}
+
+impl State {
+    /// Records a new pending job.
+    fn queue(&mut self, files: usize) -> Job {
+        self.next_id += 1;
+        let job = Job {
+            id: self.next_id,
+            files,
+            queued_at: utils::unix_time(SystemTime::now()).unwrap_or_default(),
+            state: JobState::Pending,
+        };
+        self.jobs.push(job.clone());
+        job
+    }
+
+    /// Updates the state of a job, answering the connections waiting for it
+    /// once it is finished, and forgetting the oldest finished jobs.
+    fn update(&mut self, id: u64, state: JobState) {
+        let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) else {
+            return;
+        };
+        job.state = state;
+        if !matches!(job.state, JobState::Pending | JobState::Running) {
+            let job = job.clone();
+            for waiter in self.waiters.remove(&id).unwrap_or_default() {
+                let _ = waiter.send(job.clone());
+            }
+        }
+        let finished = |job: &Job| {
+            !matches!(job.state, JobState::Pending | JobState::Running)
+        };
+        let mut excess = self
+            .jobs
+            .iter()
+            .filter(|job| finished(job))
+            .count()
+            .saturating_sub(FINISHED_JOBS);
+        self.jobs.retain(|job| {
+            let forget = excess > 0 && finished(job);
+            excess -= forget as usize;
+            !forget
+        });
+    }
+}
+
+/// Runs the daemon until it is stopped with [`Request::Stop`] or Ctrl-C:
+/// it listens on its socket, uploads the queued files one upload after the
+/// other, keeps the connections of the pool of the server ready, and
+/// verifies the uploads recorded for the server periodically.
+///
+/// Queued uploads are only kept in memory, so the pending ones are dropped
+/// when the daemon stops.
+///
+/// # Arguments
+///
+/// * `socket` - The path of the socket, only accessible to the user.
+/// * `server` - The server the files are uploaded to, with the pool of
+///   connections kept ready.
+/// * `options` - How the daemon runs.
+/// * `db` - The database the uploads are recorded in.
+///
+/// # Errors
+///
+/// Returns an error if another daemon listens on the socket, or if the
+/// socket cannot be created.
+#[cfg(unix)]
+pub async fn run(
+    socket: &Path,
+    server: &Server,
+    options: &DaemonOptions<'_>,
+    db: &mut Db,
+) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{UnixListener, UnixStream};
+
+    if UnixStream::connect(socket).await.is_ok() {
+        return Err(anyhow!(
+            "A daemon is already listening on {}",
+            socket.display()
+        ));
+    }
+    // The socket of a daemon that did not stop cleanly
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket).map_err(|e| {
+        anyhow!("Could not listen on {}: {}", socket.display(), e)
+    })?;
+    let _socket = RemoveOnDrop(socket);
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    log::info!(
+        "Listening on {}, uploading to {}",
+        socket.display(),
+        server.addr
+    );
+
+    let state = Arc::new(Mutex::new(State::default()));
+    let stop = Arc::new(Notify::new());
+    let (queue, mut queued) = mpsc::unbounded_channel();
+    let warm = tokio::spawn({
+        let server = server.clone();
+        async move { server.keep_warm().await }
+    });
+    let accept = tokio::spawn({
+        let (state, stop, server) =
+            (state.clone(), stop.clone(), server.clone());
+        async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("Could not accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let connection = Connection {
+                    state: state.clone(),
+                    stop: stop.clone(),
+                    queue: queue.clone(),
+                    server: server.clone(),
+                };
+                tokio::spawn(async move {
+                    if let Err(e) = connection.handle(stream).await {
+                        log::warn!("Could not answer a request: {}", e);
+                    }
+                });
+            }
+        }
+    });
+
+    let hashes = Hashes::default();
+    let mut verification = options
+        .verify_every
+        .filter(|period| !period.is_zero())
+        .map(|period| {
+            tokio::time::interval_at(
+                tokio::time::Instant::now() + period,
+                period,
+            )
+        });
+    loop {
+        tokio::select! {
+            Some((id, request)) = queued.recv() => {
+                state.lock().unwrap().update(id, JobState::Running);
+                let result =
+                    upload(&request, server, options, &hashes, db).await;
+                let job_state = match result {
+                    Ok(state) => state,
+                    Err(e) => {
+                        log::error!("Upload job {} failed: {:#}", id, e);
+                        JobState::Failed { error: format!("{:#}", e) }
+                    }
+                };
+                state.lock().unwrap().update(id, job_state);
+            }
+            _ = tick(&mut verification) => {
+                let verification = verify(server, db).await;
+                state.lock().unwrap().verification = Some(verification);
+            }
+            _ = stop.notified() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    log::info!("Stopping the daemon");
+    accept.abort();
+    warm.abort();
+    Ok(())
+}
+
+/// Runs the daemon, which requires Unix sockets.
+#[cfg(not(unix))]
+pub async fn run(
+    socket: &Path,
+    _server: &Server,
+    _options: &DaemonOptions<'_>,
+    _db: &mut Db,
+) -> Result<()> {
+    Err(anyhow!(
+        "The daemon requires Unix sockets, to listen on {}",
+        socket.display()
+    ))
+}
+
+/// Queues an upload on the daemon.
+///
+/// # Returns
+///
+/// Returns the pending job of the upload.
+///
+/// # Errors
+///
+/// Returns an error if no daemon listens on the socket, or if it rejects
+/// the upload.
+pub async fn queue(socket: &Path, upload: UploadRequest) -> Result<Job> {
+    match request(socket, &Request::Upload(upload)).await? {
+        Response::Job(job) => Ok(job),
+        _ => Err(unexpected()),
+    }
+}
+
+/// Waits for a job of the daemon to succeed or fail.
+///
+/// # Errors
+///
+/// Returns an error if no daemon listens on the socket, if it has no such
+/// job, or if it stops before the job is done.
+pub async fn wait(socket: &Path, job: u64) -> Result<Job> {
+    match request(socket, &Request::Wait { job }).await? {
+        Response::Job(job) => Ok(job),
+        _ => Err(unexpected()),
+    }
+}
+
+/// Returns the state of the daemon.
+///
+/// # Errors
+///
+/// Returns an error if no daemon listens on the socket.
+pub async fn status(socket: &Path) -> Result<Status> {
+    match request(socket, &Request::Status).await? {
+        Response::Status(status) => Ok(status),
+        _ => Err(unexpected()),
+    }
+}
+
+/// Stops the daemon once the job it runs, if any, is done.
+///
+/// # Errors
+///
+/// Returns an error if no daemon listens on the socket.
+pub async fn stop(socket: &Path) -> Result<()> {
+    match request(socket, &Request::Stop).await? {
+        Response::Stopping => Ok(()),
+        _ => Err(unexpected()),
+    }
+}
+
+/// The error of a response that does not answer its request.
+fn unexpected() -> anyhow::Error {
+    anyhow!("Unexpected response from the daemon")
+}
+
+/// Sends a request to the daemon and returns its response.
+///
+/// # Errors
+///
+/// Returns an error if no daemon listens on the socket, or if the daemon
+/// answers with an error.
+#[cfg(unix)]
+async fn request(socket: &Path, request: &Request) -> Result<Response> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let mut stream = UnixStream::connect(socket).await.map_err(|e| {
+        anyhow!(
+            "No daemon listens on {} ({}), start one with `daemon run`",
+            socket.display(),
+            e
+        )
+    })?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    if line.is_empty() {
+        return Err(anyhow!("The daemon stopped before answering"));
+    }
+    match serde_json::from_str(&line)? {
+        Response::Error { message } => Err(anyhow!(message)),
+        response => Ok(response),
+    }
+}
+
+/// Sends a request to the daemon, which requires Unix sockets.
+#[cfg(not(unix))]
+async fn request(socket: &Path, _request: &Request) -> Result<Response> {
+    Err(anyhow!(
+        "The daemon requires Unix sockets, to connect to {}",
+        socket.display()
+    ))
+}
+
+/// A connection to the socket of the daemon.
+struct Connection {
+    state: Arc<Mutex<State>>,
+    stop: Arc<Notify>,
+    queue: mpsc::UnboundedSender<(u64, UploadRequest)>,
+    server: Server,
+}
+
+impl Connection {
+    /// Reads a request and writes its response.
+    #[cfg(unix)]
+    async fn handle(self, stream: tokio::net::UnixStream) -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        tokio::io::BufReader::new(reader.take(MAX_REQUEST))
+            .read_line(&mut line)
+            .await?;
+        // E.g. another daemon checking whether this one listens
+        if line.is_empty() {
+            return Ok(());
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => self.answer(request).await,
+            Err(e) => Err(anyhow!("Invalid request: {}", e)),
+        }
+        .unwrap_or_else(|e| Response::Error {
+            message: e.to_string(),
+        });
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Answers a request.
+    async fn answer(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::Upload(upload) => {
+                if upload.files.is_empty() {
+                    return Err(anyhow!("No files to upload"));
+                }
+                let job = self.state.lock().unwrap().queue(upload.files.len());
+                log::info!(
+                    "Queued upload job {} of {} files",
+                    job.id,
+                    job.files
+                );
+                self.queue
+                    .send((job.id, upload))
+                    .map_err(|_| anyhow!("The daemon is stopping"))?;
+                Ok(Response::Job(job))
+            }
+            Request::Wait { job: id } => {
+                let waiter = {
+                    let mut state = self.state.lock().unwrap();
+                    let job = state
+                        .jobs
+                        .iter()
+                        .find(|job| job.id == id)
+                        .ok_or(anyhow!("The daemon has no job {}", id))?;
+                    match job.state {
+                        JobState::Pending | JobState::Running => {
+                            let (sender, receiver) = oneshot::channel();
+                            state.waiters.entry(id).or_default().push(sender);
+                            receiver
+                        }
+                        _ => return Ok(Response::Job(job.clone())),
+                    }
+                };
+                let job = waiter.await.map_err(|_| {
+                    anyhow!("The daemon stopped before job {} was done", id)
+                })?;
+                Ok(Response::Job(job))
+            }
+            Request::Status => {
+                let state = self.state.lock().unwrap();
+                Ok(Response::Status(Status {
+                    server_addr: self.server.addr.clone(),
+                    ready_connections: self
+                        .server
+                        .pool
+                        .as_ref()
+                        .map_or(0, |pool| pool.ready()),
+                    jobs: state.jobs.clone(),
+                    verification: state.verification.clone(),
+                }))
+            }
+            Request::Stop => {
+                self.stop.notify_one();
+                Ok(Response::Stopping)
+            }
+        }
+    }
+}
+
+/// Uploads the files of a job.
+async fn upload(
+    request: &UploadRequest,
+    server: &Server,
+    options: &DaemonOptions<'_>,
+    hashes: &Hashes,
+    db: &mut Db,
+) -> Result<JobState> {
+    let (paths, names) = request
+        .files
+        .iter()
+        .map(|file| (file.path.clone(), file.name.clone()))
+        .unzip();
+    let upload_options = UploadOptions {
+        stdin_name: "",
+        trash: request
+            .trash_retention
+            .map(|retention| (options.trash, retention)),
+        verify: request.verify,
+        jobs: request.jobs,
+        encoding: Encoding::default(),
+        tags: &request.tags,
+        dry_run: false,
+        dedup: request.dedup,
+        hashes: Some(hashes),
+        retention: request.retention,
+    };
+    let report =
+        crate::upload_files(paths, names, server, &upload_options, true, db)
+            .await?;
+    log::info!("Uploaded the files with root hash {}", report.root_hash);
+    Ok(JobState::Uploaded {
+        root_hash: report.root_hash,
+        deduplicated: report.deduplicated,
+    })
+}
+
+/// Verifies that the server serves, for every file of the uploads recorded
+/// for it, a proof linking the recorded hash of the file to the root hash,
+/// logging the uploads that fail.
+async fn verify(server: &Server, db: &mut Db) -> Verification {
+    let mut failures = vec![];
+    // Other clients may have recorded uploads since the last verification
+    if let Err(e) = db.refresh() {
+        failures.push(format!("Could not read the database: {:#}", e));
+    }
+    let uploads = db
+        .get_uploads()
+        .iter()
+        .filter(|(_, upload)| upload.server_addr.as_ref() == Some(&server.addr))
+        .map(|(root_hash, upload)| (root_hash.clone(), upload.clone()))
+        .collect::<Vec<_>>();
+    log::info!("Verifying the {} uploads of {}", uploads.len(), server.addr);
+    for (root_hash, upload) in &uploads {
+        let verified = match crate::recorded_tree(root_hash, upload) {
+            Ok((tree, names)) => {
+                crate::verify_upload(&tree, &names, server, None).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = verified {
+            log::error!("Root hash {} failed to verify: {:#}", root_hash, e);
+            failures.push(format!("{}: {:#}", root_hash, e));
+        }
+    }
+    Verification {
+        at: utils::unix_time(SystemTime::now()).unwrap_or_default(),
+        uploads: uploads.len(),
+        failures,
+    }
+}
+
+/// Waits for the next tick of an interval, forever without one.
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Removes a file when dropped, e.g. the socket once the daemon stops.
+struct RemoveOnDrop<'a>(&'a Path);
+
+impl Drop for RemoveOnDrop<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let mut state = State::default();
+        let job = state.queue(2);
+        assert_eq!((job.id, job.state), (1, JobState::Pending));
+        let (sender, mut receiver) = oneshot::channel();
+        state.waiters.entry(1).or_default().push(sender);
+        state.update(1, JobState::Running);
+        assert!(receiver.try_recv().is_err());
+        let uploaded = JobState::Uploaded {
+            root_hash: "root".to_string(),
+            deduplicated: false,
+        };
+        state.update(1, uploaded.clone());
+        assert_eq!(receiver.try_recv().unwrap().state, uploaded);
+
+        // Jobs are sent over the socket as JSON
+        let json = serde_json::to_string(&Response::Job(state.jobs[0].clone()));
+        match serde_json::from_str(&json.unwrap()).unwrap() {
+            Response::Job(job) => assert_eq!(job.state, uploaded),
+            _ => panic!("expected a job"),
+        }
+
+        // Only the last finished jobs are remembered
+        for _ in 0..FINISHED_JOBS + 1 {
+            let id = state.queue(1).id;
+            state.update(id, uploaded.clone());
+        }
+        let pending = state.queue(1).id;
+        assert_eq!(state.jobs.len(), FINISHED_JOBS + 1);
+        assert_eq!(state.jobs[0].id, 3);
+        assert_eq!(state.jobs.last().unwrap().id, pending);
+    }
+}
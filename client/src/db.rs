@@ -1,15 +1,135 @@
+use crate::schema;
+use crate::utils;
 use anyhow::Result;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// An upload recorded in the database.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Upload {
+    /// The uploaded files, in the order of the leaves of the Merkle tree.
+    pub files: Vec<FileRecord>,
+    /// When the upload happened, in seconds since the Unix epoch.
+    pub uploaded_at: Option<u64>,
+    /// The address of the server the files were uploaded to.
+    pub server_addr: Option<String>,
+    /// The tags the upload is organized with, sorted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The signature of the root hash by the server, if it signs them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<RootSignature>,
+}
+
+/// The signature of the root hash of an upload by the ed25519 identity key
+/// of the server, evidence of exactly what the server committed to storing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootSignature {
+    /// The public key of the server, as hex.
+    pub key: String,
+    /// The signature of [`protocol::signed_root`], as hex.
+    pub signature: String,
+}
+
+impl RootSignature {
+    /// Returns whether the signature is that of a root hash by its key.
+    pub fn verifies(&self, root_hash: &str) -> bool {
+        let (Ok(key), Ok(signature), Ok(root)) = (
+            hex::decode(&self.key),
+            hex::decode(&self.signature),
+            hex::decode(root_hash),
+        ) else {
+            return false;
+        };
+        let Ok(root) = protocol::Hash::try_from(root) else {
+            return false;
+        };
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(&protocol::signed_root(&root), &signature)
+            .is_ok()
+    }
+}
+
+/// A file recorded in the database.
+///
+/// Only the name is known for files recorded before metadata was kept.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct FileRecord {
+    /// The name of the file, a relative path using `/` as separator, with
+    /// the bytes that are not valid UTF-8 escaped as `\xNN`.
+    pub name: String,
+    /// The bytes of the name as hex, if it is not valid UTF-8, so that the
+    /// file is restored under the name it had on the file system.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_bytes: Option<String>,
+    /// The size of the file in bytes.
+    pub size: Option<u64>,
+    /// The last modification time of the original file, in seconds since the
+    /// Unix epoch.
+    pub modified: Option<u64>,
+    /// The SHA-256 of the file content as a hex string, which is also its
+    /// leaf hash in the Merkle tree.
+    pub sha256: Option<String>,
+    /// Whether the file was encrypted before it was uploaded, in which case
+    /// the size and SHA-256 are those of the encrypted file.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+    /// Whether the file was compressed with zstd before it was uploaded,
+    /// in which case the size and SHA-256 are those of the compressed file,
+    /// encrypted if it also was.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+    /// The Merkle proof of the file as hex strings, to verify it offline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<Vec<String>>,
+    /// The URL the file was fetched from and uploaded, if it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl FileRecord {
+    /// Returns the bytes of the name of the file, as it was on the file
+    /// system it was uploaded from.
+    pub fn raw_name(&self) -> Vec<u8> {
+        self.name_bytes
+            .as_deref()
+            .and_then(|bytes| hex::decode(bytes).ok())
+            .unwrap_or_else(|| self.name.clone().into_bytes())
+    }
+
+    /// Returns the relative path the file is restored under.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name would escape the directory the file is
+    /// restored into.
+    pub fn relative_path(&self) -> Result<PathBuf> {
+        utils::safe_relative_path(&utils::path_from_bytes(&self.raw_name()))
+    }
+}
+
+impl Upload {
+    /// Returns the total size of the files of the upload, if known.
+    pub fn size(&self) -> Option<u64> {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
 
 /// A database that stores the root hash and the files. It persists the
 /// root hash and the files to a JSON file.
+///
+/// Several clients may share the same database: every access takes an
+/// advisory lock on a `.lock` file next to the database, modifications are
+/// applied to the latest content of the file, and the file is replaced
+/// atomically so that a crash never leaves it truncated.
 pub struct Db {
     db_path: PathBuf,
     db: String,
-    uploads: HashMap<String, Vec<String>>,
+    uploads: HashMap<String, Upload>,
 }
 
 impl Db {
@@ -24,29 +144,43 @@ impl Db {
     ///
     /// Returns an error if the database file cannot be created or read.
     pub fn new(db_path: PathBuf, db: &str) -> Result<Self> {
-        let mut uploads = HashMap::new();
-        if !db_path.exists() {
-            // Create the database directory
-            std::fs::create_dir_all(&db_path)?;
-            // Create the JSON file
-            std::fs::File::create(db_path.join(db))?.write_all(b"{}")?;
-        } else {
-            let file = OpenOptions::new().read(true).open(db_path.join(db))?;
-            uploads = serde_json::from_reader(file)?;
-        }
-        Ok(Self {
+        // Create the database directory
+        std::fs::create_dir_all(&db_path)?;
+
+        let mut db = Self {
             db_path,
             db: db.to_string(),
-            uploads,
-        })
+            uploads: HashMap::new(),
+        };
+
+        let _lock = db.lock()?;
+        if db.file().exists() {
+            let version;
+            (db.uploads, version) = db.read()?;
+            if version < schema::VERSION {
+                // Keep a copy of the database as it was before the
+                // migration, in case it needs to be rolled back
+                std::fs::copy(
+                    db.file(),
+                    db.db_path.join(format!("{}.v{}.bak", db.db, version)),
+                )?;
+                db.write()?;
+            }
+        } else {
+            // Create the JSON file
+            db.write()?;
+        }
+        Ok(db)
     }
 
-    /// Persists the root hash and the files to the database.
+    /// Persists the root hash and the files to the database. The tags of an
+    /// upload already recorded with the same root hash are kept, along with
+    /// the new ones, and so is its signature unless a new one is given.
     ///
     /// # Arguments
     ///
     /// * `root_hash` - The root hash of the Merkle tree that contains the files.
-    /// * `files` - A slice of `PathBuf` objects that represent the files.
+    /// * `upload` - The uploaded files and their metadata.
     ///
     /// # Errors
     ///
@@ -54,32 +188,170 @@ impl Db {
     pub fn persist(
         &mut self,
         root_hash: &str,
-        files: &[PathBuf],
+        mut upload: Upload,
     ) -> anyhow::Result<()> {
-        let file = OpenOptions::new()
+        self.modify(|uploads| {
+            if let Some(previous) = uploads.get(root_hash) {
+                upload.tags.extend(previous.tags.iter().cloned());
+                upload.tags.sort();
+                upload.tags.dedup();
+                if upload.signature.is_none() {
+                    upload.signature = previous.signature.clone();
+                }
+            }
+            uploads.insert(root_hash.to_string(), upload);
+        })
+    }
+
+    /// Removes an upload from the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the upload was recorded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database file cannot be written.
+    pub fn remove(&mut self, root_hash: &str) -> Result<bool> {
+        let mut removed = false;
+        self.modify(|uploads| {
+            removed = uploads.remove(root_hash).is_some();
+        })?;
+        Ok(removed)
+    }
+
+    /// Records the Merkle proofs of files of an upload that have none, e.g.
+    /// received from the server for files uploaded before proofs were kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    /// * `proofs` - The index of each file and its proof as hex strings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database file cannot be written.
+    pub fn record_proofs(
+        &mut self,
+        root_hash: &str,
+        proofs: Vec<(usize, Vec<String>)>,
+    ) -> Result<()> {
+        let missing = |uploads: &HashMap<String, Upload>, index: usize| {
+            uploads
+                .get(root_hash)
+                .and_then(|upload| upload.files.get(index))
+                .is_some_and(|file| file.proof.is_none())
+        };
+        if !proofs
+            .iter()
+            .any(|(index, _)| missing(&self.uploads, *index))
+        {
+            return Ok(());
+        }
+        self.modify(|uploads| {
+            for (index, proof) in proofs {
+                if missing(uploads, index) {
+                    uploads.get_mut(root_hash).unwrap().files[index].proof =
+                        Some(proof);
+                }
+            }
+        })
+    }
+
+    /// Applies a modification to the latest content of the database and
+    /// writes it back, while holding the database lock.
+    fn modify(
+        &mut self,
+        f: impl FnOnce(&mut HashMap<String, Upload>),
+    ) -> Result<()> {
+        let _lock = self.lock()?;
+        // Reload the database, other clients may have modified it since it
+        // was opened
+        self.uploads = self.load()?;
+        f(&mut self.uploads);
+        self.write()
+    }
+
+    /// Reloads the database, to see the modifications of other clients, e.g.
+    /// in a long running daemon.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database file cannot be read.
+    pub fn refresh(&mut self) -> Result<()> {
+        let _lock = self.lock()?;
+        self.uploads = self.load()?;
+        Ok(())
+    }
+
+    /// Returns the path to the JSON file.
+    fn file(&self) -> PathBuf {
+        self.db_path.join(&self.db)
+    }
+
+    /// Takes an exclusive advisory lock on the database, released when the
+    /// returned file is dropped.
+    fn lock(&self) -> Result<File> {
+        let lock = OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&self.db_path.join(self.db.clone()))?;
+            .truncate(false)
+            .open(self.db_path.join(format!("{}.lock", self.db)))?;
+        lock.lock()?;
+        Ok(lock)
+    }
 
-        self.uploads.insert(
-            root_hash.to_string(),
-            files
-                .iter()
-                .filter_map(|f| {
-                    f.file_name()
-                        .and_then(|n| n.to_str().map(|s| s.to_string()))
-                })
-                .collect(),
-        );
+    /// Reads the content of the JSON file.
+    fn load(&self) -> Result<HashMap<String, Upload>> {
+        Ok(self.read()?.0)
+    }
+
+    /// Reads the content of the JSON file, migrating it to the current
+    /// schema, along with the schema version it was written with.
+    fn read(&self) -> Result<(HashMap<String, Upload>, u32)> {
+        let file = File::open(self.file())?;
+        let value: serde_json::Value = serde_json::from_reader(file)?;
+        let version = schema::version(&value);
+        let uploads = schema::migrate(value).map_err(|e| {
+            anyhow::anyhow!("Could not read {}: {}", self.file().display(), e)
+        })?;
+        Ok((uploads, version))
+    }
 
-        Ok(serde_json::to_writer_pretty(file, &self.uploads)?)
+    /// Atomically replaces the JSON file with the current content of the
+    /// database, by writing it to a temporary file first and renaming it
+    /// into place.
+    fn write(&self) -> Result<()> {
+        let tmp = self.db_path.join(format!("{}.tmp", self.db));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer_pretty(
+            &mut file,
+            &schema::Schema {
+                version: schema::VERSION,
+                uploads: self.uploads.clone(),
+            },
+        )?;
+        file.flush()?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, self.file())?;
+        sync_dir(&self.db_path)
     }
 
     /// Returns all the uploaded files.
-    pub fn get_uploads(&self) -> &HashMap<String, Vec<String>> {
+    pub fn get_uploads(&self) -> &HashMap<String, Upload> {
         &self.uploads
     }
 
+    /// Returns the upload with the given root hash, or `None` if the root
+    /// hash is unknown.
+    pub fn get_upload(&self, root_hash: &str) -> Option<&Upload> {
+        self.uploads.get(root_hash)
+    }
+
     /// Get index of the file in the list of files.
     ///
     /// # Arguments
@@ -93,38 +365,279 @@ impl Db {
     pub fn get_index(&self, root_hash: &str, file_name: &str) -> Option<usize> {
         self.uploads
             .get(root_hash)
-            .and_then(|files| files.iter().position(|f| f == file_name))
+            .and_then(|u| u.files.iter().position(|f| f.name == file_name))
     }
 
     /// Returns the path to the database.
     pub fn get_db_path(&self) -> &PathBuf {
         &self.db_path
     }
+
+    /// Exports all the uploads to a file, to back up the database or move it
+    /// to another machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file to export to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written.
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let export = Export {
+            format: EXPORT_FORMAT.to_string(),
+            version: EXPORT_VERSION,
+            uploads: self.uploads.clone().into_iter().collect(),
+        };
+        let mut file = File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, &export)?;
+        Ok(file.sync_all()?)
+    }
+
+    /// Imports the uploads of a file written by [`Db::export`]. Uploads that
+    /// are already in the database are kept as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file to import.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of imported uploads.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not an export, or
+    /// if the database cannot be written.
+    pub fn import(&mut self, path: &Path) -> Result<usize> {
+        let export: Export = serde_json::from_reader(File::open(path)?)?;
+        if export.format != EXPORT_FORMAT {
+            return Err(anyhow::anyhow!(
+                "{} is not a database export",
+                path.display()
+            ));
+        }
+        if export.version > EXPORT_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported export version {}",
+                export.version
+            ));
+        }
+
+        let mut imported = 0;
+        self.modify(|uploads| {
+            for (root_hash, upload) in export.uploads {
+                if let Entry::Vacant(entry) = uploads.entry(root_hash) {
+                    entry.insert(upload);
+                    imported += 1;
+                }
+            }
+        })?;
+        Ok(imported)
+    }
+}
+
+/// The value of the `format` field of database exports.
+const EXPORT_FORMAT: &str = "file-guardian-db";
+
+/// The version of the database export format.
+const EXPORT_VERSION: u32 = 1;
+
+/// A database export. Uploads are sorted by root hash so that exporting the
+/// same database twice gives the same file.
+#[derive(Serialize, Deserialize)]
+struct Export {
+    format: String,
+    version: u32,
+    uploads: BTreeMap<String, Upload>,
 }
+
+/// Flushes a directory entry to disk, so that a rename into it is durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    Ok(File::open(dir)?.sync_all()?)
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs::remove_dir_all;
 
+    fn upload(names: &[&str]) -> Upload {
+        Upload {
+            files: names
+                .iter()
+                .map(|name| FileRecord {
+                    name: name.to_string(),
+                    size: Some(3),
+                    ..Default::default()
+                })
+                .collect(),
+            uploaded_at: Some(1_700_000_000),
+            server_addr: Some("127.0.0.1:2345".to_string()),
+            tags: vec![],
+            signature: None,
+        }
+    }
+
     #[test]
     fn test_persist() {
         let db_path = PathBuf::from("test_db1");
         let db_name = "test_db.json";
         let mut db = Db::new(db_path.clone(), db_name).unwrap();
         let root_hash = "root_hash";
-        let files =
-            vec![PathBuf::from("file1.txt"), PathBuf::from("file2.txt")];
-        db.persist(root_hash, &files).unwrap();
+        db.persist(root_hash, upload(&["file1.txt", "file2.txt"]))
+            .unwrap();
 
         let db_file = db_path.join(db_name);
         let file = std::fs::File::open(db_file).unwrap();
-        let uploads: HashMap<String, Vec<String>> =
-            serde_json::from_reader(file).unwrap();
+        let schema: schema::Schema = serde_json::from_reader(file).unwrap();
+        assert_eq!(schema.version, schema::VERSION);
         assert_eq!(
-            uploads.get(root_hash),
-            Some(&vec!["file1.txt".to_string(), "file2.txt".to_string()])
+            schema.uploads.get(root_hash),
+            Some(&upload(&["file1.txt", "file2.txt"]))
+        );
+
+        remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_tags() {
+        let db_path = PathBuf::from("test_db_tags");
+        let mut db = Db::new(db_path.clone(), "test_db.json").unwrap();
+        let tagged = |tags: &[&str]| Upload {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..upload(&["file1.txt"])
+        };
+        db.persist("root_hash", tagged(&["backups", "2024-q3"]))
+            .unwrap();
+        db.persist("root_hash", tagged(&["backups", "photos"]))
+            .unwrap();
+
+        // Uploading the same files again adds to their tags
+        let mut db = Db::new(db_path.clone(), "test_db.json").unwrap();
+        assert_eq!(
+            db.get_upload("root_hash").unwrap().tags,
+            vec!["2024-q3", "backups", "photos"]
         );
 
+        assert!(db.remove("root_hash").unwrap());
+        assert!(!db.remove("root_hash").unwrap());
+        let db = Db::new(db_path.clone(), "test_db.json").unwrap();
+        assert!(db.get_upload("root_hash").is_none());
+
+        remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_signature() {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let db_path = PathBuf::from("test_db_signature");
+        let mut db = Db::new(db_path.clone(), "test_db.json").unwrap();
+        let pair = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let root_hash = "ab".repeat(32);
+        let signature = RootSignature {
+            key: hex::encode(pair.public_key()),
+            signature: hex::encode(
+                pair.sign(&protocol::signed_root(&[0xab; 32])),
+            ),
+        };
+        assert!(signature.verifies(&root_hash));
+        assert!(!signature.verifies(&"cd".repeat(32)));
+        let forged = RootSignature {
+            key: hex::encode([1; 32]),
+            ..signature.clone()
+        };
+        assert!(!forged.verifies(&root_hash));
+
+        let signed = Upload {
+            signature: Some(signature.clone()),
+            ..upload(&["file1.txt"])
+        };
+        db.persist(&root_hash, signed).unwrap();
+        // Recording the upload again, e.g. from an older server, keeps the
+        // signature
+        db.persist(&root_hash, upload(&["file1.txt"])).unwrap();
+        let db = Db::new(db_path.clone(), "test_db.json").unwrap();
+        assert_eq!(
+            db.get_upload(&root_hash).unwrap().signature,
+            Some(signature)
+        );
+
+        remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_concurrent() {
+        let db_path = PathBuf::from("test_db4");
+        let db_name = "test_db.json";
+        let mut db1 = Db::new(db_path.clone(), db_name).unwrap();
+        let mut db2 = Db::new(db_path.clone(), db_name).unwrap();
+        db1.persist("root_hash1", upload(&["file1.txt"])).unwrap();
+        db2.persist("root_hash2", upload(&["file2.txt"])).unwrap();
+
+        // The second persist must not drop the upload of the first one
+        let db = Db::new(db_path.clone(), db_name).unwrap();
+        assert_eq!(db.get_uploads().len(), 2);
+        assert!(!db_path.join("test_db.json.tmp").exists());
+
+        remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_load_names_only() {
+        let db_path = PathBuf::from("test_db5");
+        let db_name = "test_db.json";
+        std::fs::create_dir_all(&db_path).unwrap();
+        std::fs::write(
+            db_path.join(db_name),
+            r#"{"root_hash": ["file1.txt", "file2.txt"]}"#,
+        )
+        .unwrap();
+
+        let db = Db::new(db_path.clone(), db_name).unwrap();
+        let upload = db.get_upload("root_hash").unwrap();
+        assert_eq!(upload.files[1].name, "file2.txt");
+        assert_eq!(upload.files[1].size, None);
+        assert_eq!(upload.size(), None);
+
+        // The database is migrated to the current schema, and the original
+        // is kept
+        assert!(db_path.join("test_db.json.v1.bak").exists());
+        let file = std::fs::File::open(db_path.join(db_name)).unwrap();
+        let schema: schema::Schema = serde_json::from_reader(file).unwrap();
+        assert_eq!(schema.version, schema::VERSION);
+
+        remove_dir_all(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_export_import() {
+        let db_path = PathBuf::from("test_db6");
+        let mut db = Db::new(db_path.clone(), "test_db.json").unwrap();
+        db.persist("root_hash1", upload(&["file1.txt"])).unwrap();
+        db.persist("root_hash2", upload(&["file2.txt"])).unwrap();
+        db.export(&db_path.join("export.json")).unwrap();
+
+        let mut other = Db::new(db_path.clone(), "other.json").unwrap();
+        other.persist("root_hash2", upload(&["other.txt"])).unwrap();
+        assert_eq!(other.import(&db_path.join("export.json")).unwrap(), 1);
+        assert_eq!(other.get_uploads().len(), 2);
+        assert_eq!(other.get_upload("root_hash1"), db.get_upload("root_hash1"));
+        // Existing uploads are not overwritten
+        assert_eq!(
+            other.get_upload("root_hash2").unwrap().files[0].name,
+            "other.txt"
+        );
+
+        assert!(other.import(&db_path.join("test_db.json")).is_err());
+
         remove_dir_all(db_path).unwrap();
     }
 
@@ -134,15 +647,15 @@ mod tests {
         let db = "test_db.json";
         let mut db = Db::new(db_path.clone(), db).unwrap();
         let root_hash = "root_hash";
-        let files =
-            vec![PathBuf::from("file1.txt"), PathBuf::from("file2.txt")];
-        db.persist(root_hash, &files).unwrap();
+        db.persist(root_hash, upload(&["file1.txt", "file2.txt"]))
+            .unwrap();
 
         let uploads = db.get_uploads();
         assert_eq!(
             uploads.get(root_hash),
-            Some(&vec!["file1.txt".to_string(), "file2.txt".to_string()])
+            Some(&upload(&["file1.txt", "file2.txt"]))
         );
+        assert_eq!(uploads[root_hash].size(), Some(6));
 
         remove_dir_all(db_path).unwrap();
     }
@@ -153,9 +666,8 @@ mod tests {
         let db = "test_db.json";
         let mut db = Db::new(db_path.clone(), db).unwrap();
         let root_hash = "root_hash";
-        let files =
-            vec![PathBuf::from("file1.txt"), PathBuf::from("file2.txt")];
-        db.persist(root_hash, &files).unwrap();
+        db.persist(root_hash, upload(&["file1.txt", "file2.txt"]))
+            .unwrap();
 
         let index = db.get_index(root_hash, "file1.txt");
         assert_eq!(index, Some(0));
@@ -168,4 +680,27 @@ mod tests {
 
         remove_dir_all(db_path).unwrap();
     }
+
+    #[test]
+    fn test_record_proofs() {
+        let db_path = PathBuf::from("test_db_proofs");
+        let mut db = Db::new(db_path.clone(), "test_db.json").unwrap();
+        let mut files = upload(&["file1.txt", "file2.txt"]);
+        files.files[1].proof = Some(vec!["bb".to_string()]);
+        db.persist("root_hash", files).unwrap();
+
+        let proof = |i: usize| vec![format!("{:02x}", i)];
+        db.record_proofs("root_hash", vec![(0, proof(0)), (1, proof(1))])
+            .unwrap();
+        db.record_proofs("unknown", vec![(0, proof(0))]).unwrap();
+
+        // Proofs already recorded are kept
+        let files = &Db::new(db_path.clone(), "test_db.json").unwrap().uploads
+            ["root_hash"]
+            .files;
+        assert_eq!(files[0].proof, Some(proof(0)));
+        assert_eq!(files[1].proof, Some(vec!["bb".to_string()]));
+
+        remove_dir_all(db_path).unwrap();
+    }
 }
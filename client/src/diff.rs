@@ -0,0 +1,260 @@
+use crate::output::DiffReport;
+use crate::utils;
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use merkle_tree::MerkleTree;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// A node of the tree of a directory: a file, hashed by its SHA-256, or a
+/// directory, hashed by the root of the Merkle tree over its entries, in
+/// the canonical order of their names, each hashed along with its name and
+/// kind. Two directories with the same hash hold the same files.
+#[derive(Debug)]
+enum Node {
+    File {
+        sha256: [u8; 32],
+    },
+    Dir {
+        hash: [u8; 32],
+        entries: BTreeMap<OsString, Node>,
+    },
+}
+
+impl Node {
+    /// Returns the hash of the node.
+    fn hash(&self) -> &[u8; 32] {
+        match self {
+            Node::File { sha256 } => sha256,
+            Node::Dir { hash, .. } => hash,
+        }
+    }
+
+    /// Returns the names of the files under the node, in canonical order.
+    fn files(&self, name: &str, files: &mut Vec<String>) {
+        match self {
+            Node::File { .. } => files.push(name.to_string()),
+            Node::Dir { entries, .. } => {
+                for (entry, node) in entries {
+                    node.files(&join(name, entry), files);
+                }
+            }
+        }
+    }
+
+    /// Returns the number of files under the node.
+    fn count(&self) -> usize {
+        match self {
+            Node::File { .. } => 1,
+            Node::Dir { entries, .. } => {
+                entries.values().map(Node::count).sum()
+            }
+        }
+    }
+}
+
+/// The entries of a directory being built, before it is hashed.
+#[derive(Default)]
+struct Builder {
+    files: BTreeMap<OsString, [u8; 32]>,
+    dirs: BTreeMap<OsString, Builder>,
+}
+
+impl Builder {
+    /// Adds a file under its path relative to the directory.
+    fn insert(&mut self, components: &[OsString], sha256: [u8; 32]) {
+        match components {
+            [name] => {
+                self.files.insert(name.clone(), sha256);
+            }
+            [dir, rest @ ..] => self
+                .dirs
+                .entry(dir.clone())
+                .or_default()
+                .insert(rest, sha256),
+            [] => {}
+        }
+    }
+
+    /// Hashes the directory, its subdirectories first.
+    fn build(self) -> Result<Node> {
+        let mut entries = BTreeMap::new();
+        for (name, sha256) in self.files {
+            entries.insert(name, Node::File { sha256 });
+        }
+        for (name, dir) in self.dirs {
+            entries.insert(name, dir.build()?);
+        }
+        let leaves = entries
+            .iter()
+            .map(|(name, node)| {
+                let kind: &[u8] = match node {
+                    Node::File { .. } => b"file",
+                    Node::Dir { .. } => b"dir",
+                };
+                let mut hasher = Sha256::new();
+                hasher.update(kind);
+                hasher.update([0]);
+                hasher.update(name.as_encoded_bytes());
+                hasher.update([0]);
+                hasher.update(node.hash());
+                hasher.finalize().into()
+            })
+            .collect::<Vec<[u8; 32]>>();
+        let hash = match leaves.is_empty() {
+            true => Sha256::digest(b"").into(),
+            false => *MerkleTree::from_leaves(leaves)?
+                .root()
+                .ok_or(anyhow!("Root Hash could not be computed"))?,
+        };
+        Ok(Node::Dir { hash, entries })
+    }
+}
+
+/// Compares two directories by content: their files are hashed into a tree
+/// per directory, and the trees compared from their roots, a subdirectory
+/// with the same hash on both sides being identical without comparing its
+/// entries.
+///
+/// # Arguments
+///
+/// * `a` - The directory compared from.
+/// * `b` - The directory compared to.
+/// * `exclude` - Patterns of files and directories to leave out.
+///
+/// # Errors
+///
+/// Returns an error if a path is not a directory, or a file cannot be read.
+pub fn diff(a: &Path, b: &Path, exclude: &[Pattern]) -> Result<DiffReport> {
+    let (tree_a, tree_b) = (tree(a, exclude)?, tree(b, exclude)?);
+    let mut report = DiffReport {
+        a: a.to_path_buf(),
+        b: b.to_path_buf(),
+        root_a: hex::encode(tree_a.hash()),
+        root_b: hex::encode(tree_b.hash()),
+        identical: 0,
+        changed: vec![],
+        added: vec![],
+        removed: vec![],
+    };
+    compare(&tree_a, &tree_b, "", &mut report);
+    Ok(report)
+}
+
+/// Hashes the files of a directory into its tree.
+fn tree(dir: &Path, exclude: &[Pattern]) -> Result<Node> {
+    if !dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    let mut root = Builder::default();
+    for (path, _) in utils::collect_files(&[dir.to_path_buf()], exclude)? {
+        let components = path
+            .strip_prefix(dir)
+            .unwrap_or(&path)
+            .components()
+            .map(|c| c.as_os_str().to_os_string())
+            .collect::<Vec<_>>();
+        let sha256 = utils::sha256_file(&path)
+            .map_err(|e| anyhow!("Could not hash {}: {}", path.display(), e))?;
+        root.insert(&components, sha256);
+    }
+    root.build()
+}
+
+/// Compares two nodes of the same name, recording the differences.
+fn compare(a: &Node, b: &Node, name: &str, report: &mut DiffReport) {
+    if a.hash() == b.hash() {
+        report.identical += a.count();
+        return;
+    }
+    match (a, b) {
+        (Node::File { .. }, Node::File { .. }) => {
+            report.changed.push(name.to_string())
+        }
+        (Node::Dir { entries: a, .. }, Node::Dir { entries: b, .. }) => {
+            let names = a.keys().chain(b.keys()).collect::<BTreeSet<_>>();
+            for entry in names {
+                let name = join(name, entry);
+                match (a.get(entry), b.get(entry)) {
+                    (Some(a), Some(b)) => compare(a, b, &name, report),
+                    (Some(a), None) => a.files(&name, &mut report.removed),
+                    (None, Some(b)) => b.files(&name, &mut report.added),
+                    (None, None) => {}
+                }
+            }
+        }
+        // A file replaced by a directory, or the other way around
+        _ => {
+            a.files(name, &mut report.removed);
+            b.files(name, &mut report.added);
+        }
+    }
+}
+
+/// Returns the `/` separated name of an entry of a directory.
+fn join(dir: &str, entry: &OsString) -> String {
+    let entry = utils::escape_name(entry);
+    match dir.is_empty() {
+        true => entry,
+        false => format!("{}/{}", dir, entry),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_diff() {
+        let dir = TempDir::new().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        for (root, files) in [
+            (
+                &a,
+                &[
+                    ("same/one", "1"),
+                    ("same/two", "2"),
+                    ("x", "x"),
+                    ("y", "y"),
+                    ("old", "o"),
+                    ("kind", "k"),
+                ][..],
+            ),
+            (
+                &b,
+                &[
+                    ("same/one", "1"),
+                    ("same/two", "2"),
+                    ("x", "changed"),
+                    ("y", "y"),
+                    ("new/file", "n"),
+                    ("kind/file", "k"),
+                ][..],
+            ),
+        ] {
+            for (name, content) in files {
+                let path = root.join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+        }
+
+        let report = diff(&a, &b, &[]).unwrap();
+        assert_ne!(report.root_a, report.root_b);
+        assert_eq!(report.identical, 3);
+        assert_eq!(report.changed, ["x"]);
+        assert_eq!(report.added, ["kind/file", "new/file"]);
+        assert_eq!(report.removed, ["kind", "old"]);
+
+        // The same files give the same root, wherever the directory is
+        let report = diff(&a.join("same"), &b.join("same"), &[]).unwrap();
+        assert_eq!(report.root_a, report.root_b);
+        assert_eq!(report.identical, 2);
+        let exclude = [Pattern::new("*/old").unwrap()];
+        assert_eq!(diff(&a, &b, &exclude).unwrap().removed, ["kind"]);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::crypto::Key;
+use crate::db::FileRecord;
+use crate::utils::TempDir;
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::PathBuf;
+
+/// How files are transformed before they are uploaded: compressed, then
+/// encrypted, so that the server stores, and the Merkle tree covers, the
+/// transformed files.
+#[derive(Default)]
+pub struct Encoding<'a> {
+    /// The zstd level to compress the files with, if they are compressed.
+    pub compression: Option<i32>,
+    /// The key to encrypt the files with, if they are encrypted.
+    pub key: Option<&'a Key>,
+}
+
+/// Encoded copies of the files of an upload, in a temporary directory
+/// removed once they are no longer needed.
+///
+/// The files are encoded once before the upload, so that a file whose
+/// transfer is resumed or retried is sent with the same bytes.
+pub struct EncodedFiles {
+    dir: TempDir,
+    /// The paths of the encoded files, in the order of the originals.
+    pub paths: Vec<PathBuf>,
+}
+
+impl Encoding<'_> {
+    /// Returns whether the files are sent as they are.
+    pub fn is_identity(&self) -> bool {
+        self.compression.is_none() && self.key.is_none()
+    }
+
+    /// Encodes files into a new temporary directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file cannot be read, or its encoded copy
+    /// written.
+    pub fn encode(&self, paths: &[PathBuf]) -> Result<EncodedFiles> {
+        let mut files = EncodedFiles {
+            dir: TempDir::new()?,
+            paths: vec![],
+        };
+        for (index, path) in paths.iter().enumerate() {
+            let encoded = files.dir.path().join(index.to_string());
+            self.encode_file(File::open(path)?, File::create(&encoded)?)
+                .map_err(|e| {
+                    anyhow!("Could not encode {}: {}", path.display(), e)
+                })?;
+            files.paths.push(encoded);
+        }
+        Ok(files)
+    }
+
+    /// Streams a file through the compression and the encryption.
+    fn encode_file(&self, file: File, encoded: File) -> Result<()> {
+        let mut reader: Box<dyn Read> = match self.compression {
+            Some(level) => Box::new(zstd::stream::read::Encoder::new(
+                BufReader::new(file),
+                level,
+            )?),
+            None => Box::new(BufReader::new(file)),
+        };
+        let mut writer = BufWriter::new(encoded);
+        match self.key {
+            Some(key) => key.encrypt(reader, &mut writer)?,
+            None => {
+                std::io::copy(&mut reader, &mut writer)?;
+            }
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+}
+
+/// Decodes a downloaded file, once its proof is verified, reversing the
+/// encoding it was uploaded with.
+///
+/// # Errors
+///
+/// Returns an error if the file is encrypted and no key is given, or if it
+/// cannot be decrypted or decompressed.
+pub fn decode(
+    record: &FileRecord,
+    data: Vec<u8>,
+    key: Option<&Key>,
+) -> Result<Vec<u8>> {
+    let data = match record.encrypted {
+        true => key
+            .ok_or_else(|| {
+                anyhow!(
+                    "File {} is encrypted, but no key file is given",
+                    record.name
+                )
+            })?
+            .decrypt(&data)
+            .map_err(|e| anyhow!("File {}: {}", record.name, e))?,
+        false => data,
+    };
+    match record.compressed {
+        true => zstd::decode_all(data.as_slice()).map_err(|e| {
+            anyhow!("File {} could not be decompressed: {}", record.name, e)
+        }),
+        false => Ok(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_encode_decode() {
+        let dir = std::env::temp_dir().join("file-guardian-test-encoding");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.log");
+        let data = "GET /index.html 200\n".repeat(10_000);
+        fs::write(&path, &data).unwrap();
+        let key_path = dir.join("key");
+        fs::write(&key_path, "01".repeat(32)).unwrap();
+        let key = Key::from_file(&key_path).unwrap();
+
+        let encoding = Encoding {
+            compression: Some(3),
+            key: Some(&key),
+        };
+        let files = encoding.encode(&[path]).unwrap();
+        let encoded = fs::read(&files.paths[0]).unwrap();
+        assert!(encoded.len() < data.len() / 10);
+
+        let record = FileRecord {
+            name: "file.log".to_string(),
+            encrypted: true,
+            compressed: true,
+            ..Default::default()
+        };
+        let decoded = decode(&record, encoded.clone(), Some(&key)).unwrap();
+        assert_eq!(decoded, data.as_bytes());
+        assert!(decode(&record, encoded, None).is_err());
+
+        let encoded_dir = files.dir.path().to_path_buf();
+        drop(files);
+        assert!(!encoded_dir.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
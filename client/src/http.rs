@@ -0,0 +1,396 @@
+use crate::connect;
+use crate::tls::{Tls, TlsOptions};
+use anyhow::{anyhow, Result};
+use std::io::{self, Cursor};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// The number of redirects followed before a request fails.
+const MAX_REDIRECTS: usize = 5;
+
+/// The maximum size of the status line and headers of a response.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// An `http://` or `https://` URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    /// Whether the URL is fetched over TLS, i.e. is an `https://` one.
+    pub tls: bool,
+    /// The address of the server, in the format `host:port`.
+    pub address: String,
+    /// The host and the port as given in the URL, for the `Host` header.
+    pub authority: String,
+    /// The path and the query of the resource, starting with `/`.
+    pub path: String,
+}
+
+impl Url {
+    /// Parses a URL, without its fragment.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the URL is neither an `http://` nor an `https://`
+    /// one, or has no host.
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid URL {}", url);
+        let (tls, rest) = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => {
+                (true, rest)
+            }
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => {
+                (false, rest)
+            }
+            _ => return Err(invalid()),
+        };
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() || authority.contains('@') {
+            return Err(invalid());
+        }
+        // The port is optional in URLs, unlike in server addresses
+        let address = match authority.rsplit_once(':') {
+            Some((_, port))
+                if !authority.ends_with(']') && port.parse::<u16>().is_ok() =>
+            {
+                authority.to_string()
+            }
+            _ => format!("{}:{}", authority, if tls { 443 } else { 80 }),
+        };
+        Ok(Self {
+            tls,
+            address,
+            authority: authority.to_string(),
+            path: match path.starts_with('/') {
+                true => path.to_string(),
+                false => format!("/{}", path),
+            },
+        })
+    }
+
+    /// Returns the last segment of the path, e.g. `tool.tar.gz` for
+    /// `https://example.com/releases/tool.tar.gz?raw=1`, or the host if the
+    /// path has none.
+    pub fn file_name(&self) -> &str {
+        let path = self.path.split('?').next().unwrap_or_default();
+        match path.rsplit('/').next() {
+            Some(name) if !name.is_empty() => name,
+            _ => self.address.rsplit_once(':').map_or("", |(host, _)| host),
+        }
+    }
+
+    /// Returns the URL a response redirects to, given its `Location`
+    /// header: an absolute URL, or a path on the same server.
+    fn redirect(&self, location: &str) -> Result<Self> {
+        if location.contains("://") {
+            return Self::parse(location);
+        }
+        let path = match location.starts_with('/') {
+            true => location.to_string(),
+            // Relative to the directory of the path
+            false => {
+                let path = self.path.split('?').next().unwrap_or_default();
+                let dir = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+                format!("{}/{}", dir, location)
+            }
+        };
+        Ok(Self {
+            path,
+            ..self.clone()
+        })
+    }
+}
+
+impl std::fmt::Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{}://{}{}", scheme, self.authority, self.path)
+    }
+}
+
+/// A response to a request, whose body is read as it is received.
+pub struct Response {
+    /// The headers of the response, with their names in lowercase.
+    headers: Vec<(String, String)>,
+    body: Box<dyn AsyncRead + Unpin + Send>,
+}
+
+impl Response {
+    /// Returns the value of a header, its name being case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the size of the body, if the response gives it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the `Content-Length` header is invalid.
+    pub fn content_length(&self) -> Result<Option<u64>> {
+        if self.is_chunked() {
+            return Ok(None);
+        }
+        self.header("Content-Length")
+            .map(|length| {
+                length
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid Content-Length {}", length))
+            })
+            .transpose()
+    }
+
+    /// Returns whether the body is sent with the chunked transfer encoding.
+    fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    }
+
+    /// Returns the body, which ends after `Content-Length` bytes if the
+    /// response gives it, or when the server closes the connection.
+    pub fn into_body(self) -> Box<dyn AsyncRead + Unpin + Send> {
+        self.body
+    }
+
+    /// Reads the whole body, decoding the chunked transfer encoding.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the body is larger than `limit` bytes, or shorter
+    /// than its `Content-Length`.
+    pub async fn read_body(self, limit: u64) -> Result<Vec<u8>> {
+        let (chunked, length) = (self.is_chunked(), self.content_length()?);
+        let mut body = vec![];
+        let read = self.body.take(limit + 1).read_to_end(&mut body).await;
+        // Many servers close the connection without a TLS close_notify,
+        // which is harmless: the length of the body is checked when given
+        match read {
+            Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                return Err(e.into())
+            }
+            _ => {}
+        }
+        if body.len() as u64 > limit {
+            return Err(anyhow!("The response is larger than {} bytes", limit));
+        }
+        match (chunked, length) {
+            (true, _) => dechunk(&body),
+            (_, Some(length)) if (body.len() as u64) < length => {
+                Err(anyhow!("Truncated HTTP response"))
+            }
+            _ => Ok(body),
+        }
+    }
+}
+
+/// Gets a resource with a plain HTTP/1.1 request, following redirects.
+///
+/// Redirects from an `https://` URL to an `http://` one are refused, so that
+/// a resource fetched over TLS is never fetched in cleartext.
+///
+/// # Arguments
+///
+/// * `url` - The URL of the resource.
+/// * `ca` - A PEM file of the certificate authorities HTTPS servers are
+///   verified with, instead of the Mozilla root certificates.
+///
+/// # Errors
+///
+/// Returns an error if the URL is invalid, the server cannot be reached or
+/// is not trusted, or it does not reply with the resource.
+pub async fn get(url: &str, ca: Option<&Path>) -> Result<Response> {
+    let mut url = Url::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        let (status, response) = request(&url, ca).await?;
+        match status.split_whitespace().nth(1).unwrap_or_default() {
+            "200" => return Ok(response),
+            "301" | "302" | "303" | "307" | "308" => {
+                let location =
+                    response.header("Location").ok_or_else(|| {
+                        anyhow!("{} redirects without a Location", url)
+                    })?;
+                let next = url.redirect(location)?;
+                if url.tls && !next.tls {
+                    return Err(anyhow!(
+                        "Refusing the redirect of {} to {}, not over HTTPS",
+                        url,
+                        next
+                    ));
+                }
+                log::debug!("{} redirects to {}", url, next);
+                url = next;
+            }
+            _ => return Err(anyhow!("{}: {}", url, status)),
+        }
+    }
+    Err(anyhow!(
+        "{} redirects more than {} times",
+        url,
+        MAX_REDIRECTS
+    ))
+}
+
+/// Sends a GET request and reads the head of the response.
+///
+/// # Returns
+///
+/// Returns the status line, and the response.
+async fn request(url: &Url, ca: Option<&Path>) -> Result<(String, Response)> {
+    log::debug!("Sending GET {}", url);
+    let stream = connect::connect(&url.address).await?;
+    let mut stream: Box<dyn Transport> = match url.tls {
+        true => {
+            let tls = Tls::new(
+                &url.address,
+                &TlsOptions {
+                    ca,
+                    ..Default::default()
+                },
+            )?;
+            Box::new(tls.connect(stream).await?)
+        }
+        false => Box::new(stream),
+    };
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: \
+                 file-guardian\r\nConnection: close\r\n\r\n",
+                url.path, url.authority
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.flush().await?;
+
+    // Read until the end of the head, the rest being the start of the body
+    let mut received = vec![];
+    let mut chunk = [0; 4096];
+    let split = loop {
+        if let Some(split) = find(&received, b"\r\n\r\n") {
+            break split;
+        }
+        if received.len() > MAX_HEAD_SIZE {
+            return Err(anyhow!("Invalid HTTP response"));
+        }
+        match stream.read(&mut chunk).await? {
+            0 => return Err(anyhow!("Invalid HTTP response")),
+            len => received.extend_from_slice(&chunk[..len]),
+        }
+    };
+    let (status, headers) = parse_head(&received[..split]);
+    let start = Cursor::new(received[split + 4..].to_vec());
+    let mut response = Response {
+        headers,
+        body: Box::new(start.chain(stream)),
+    };
+    if let Some(length) = response.content_length()? {
+        response.body = Box::new(response.body.take(length));
+    }
+    Ok((status, response))
+}
+
+/// A connection to a server, in cleartext or over TLS.
+trait Transport: AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + tokio::io::AsyncWrite + Unpin + Send> Transport for T {}
+
+/// Returns the status line and the headers of the head of a response.
+pub fn parse_head(head: &[u8]) -> (String, Vec<(String, String)>) {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            Some((key.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+    (status, headers)
+}
+
+/// Returns the position of `needle` in `haystack`.
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Decodes a body with the chunked transfer encoding.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+    let truncated = || anyhow!("Truncated HTTP response");
+    let mut decoded = vec![];
+    loop {
+        let line = find(body, b"\r\n").ok_or_else(truncated)?;
+        let size = String::from_utf8_lossy(&body[..line]);
+        // Chunk extensions follow a semicolon
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| anyhow!("Invalid chunk size {}", size))?;
+        body = &body[line + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size).ok_or_else(truncated)?);
+        body = body.get(size + 2..).ok_or_else(truncated)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let url = Url::parse("https://example.com/releases/tool.tar.gz?raw=1")
+            .unwrap();
+        assert!(url.tls);
+        assert_eq!(url.address, "example.com:443");
+        assert_eq!(url.path, "/releases/tool.tar.gz?raw=1");
+        assert_eq!(url.file_name(), "tool.tar.gz");
+        assert_eq!(
+            url.redirect("v2/tool.tar.gz").unwrap().to_string(),
+            "https://example.com/releases/v2/tool.tar.gz"
+        );
+        assert_eq!(
+            url.redirect("/tool.tar.gz").unwrap().to_string(),
+            "https://example.com/tool.tar.gz"
+        );
+
+        let url = Url::parse("http://[::1]:8080#top").unwrap();
+        assert!(!url.tls);
+        assert_eq!(url.address, "[::1]:8080");
+        assert_eq!(url.path, "/");
+        assert_eq!(url.file_name(), "[::1]");
+        assert_eq!(Url::parse("http://[::1]").unwrap().address, "[::1]:80");
+
+        assert!(Url::parse("ftp://example.com/file").is_err());
+        assert!(Url::parse("https:///file").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let response = |head: &str, body: &'static [u8]| Response {
+            headers: parse_head(head.as_bytes()).1,
+            body: Box::new(body),
+        };
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 5";
+        assert_eq!(
+            response(ok, b"hello").read_body(5).await.unwrap(),
+            b"hello"
+        );
+        assert!(response(ok, b"hello").read_body(4).await.is_err());
+        assert!(response(ok, b"hell").read_body(5).await.is_err());
+        let chunked = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked";
+        let body = b"4\r\nhell\r\n1;ext\r\no\r\n0\r\n\r\n";
+        assert_eq!(
+            response(chunked, body).read_body(1024).await.unwrap(),
+            b"hello"
+        );
+        assert_eq!(response(chunked, body).content_length().unwrap(), None);
+    }
+}
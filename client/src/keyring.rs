@@ -0,0 +1,306 @@
+use anyhow::{anyhow, Result};
+use std::io::{self, Write};
+use std::process::{Command, Output, Stdio};
+
+/// The service the secrets of the client are stored under in the keyring.
+const SERVICE: &str = "file-guardian";
+
+/// A secret of the client stored in the platform keyring, instead of in the
+/// configuration file: the Secret Service (e.g. GNOME Keyring or KWallet)
+/// through `secret-tool` on Linux and the BSDs, the login keychain through
+/// `security` on macOS, and the Credential Manager through PowerShell on
+/// Windows.
+///
+/// Secrets are always given to the tools on stdin, never as arguments,
+/// which other users could read from the process list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Secret<'a> {
+    /// The token authenticating to the server at an address.
+    Token(&'a str),
+    /// The key encrypting uploads and decrypting their downloads.
+    EncryptionKey,
+}
+
+impl Secret<'_> {
+    /// Returns the account the secret is stored under.
+    fn account(&self) -> String {
+        match self {
+            Self::Token(server_addr) => format!("token@{}", server_addr),
+            Self::EncryptionKey => "encryption-key".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Secret<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Token(server_addr) => {
+                write!(f, "the token of {}", server_addr)
+            }
+            Self::EncryptionKey => write!(f, "the encryption key"),
+        }
+    }
+}
+
+/// Reads a secret from the keyring.
+///
+/// # Returns
+///
+/// Returns the secret, or `None` if it is not stored.
+///
+/// # Errors
+///
+/// Returns an error if the keyring cannot be read, e.g. if its tool is not
+/// installed or it is locked.
+pub fn get(secret: Secret) -> Result<Option<String>> {
+    let account = secret.account();
+    #[cfg(target_os = "macos")]
+    let output = run(
+        "security",
+        &["find-generic-password", "-s", SERVICE, "-a", &account, "-w"],
+        None,
+    )?;
+    #[cfg(windows)]
+    let output = powershell(GET_CREDENTIAL, &[&account])?;
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let output = run(
+        "secret-tool",
+        &["lookup", "service", SERVICE, "account", &account],
+        None,
+    )?;
+    let value = String::from_utf8_lossy(&output.stdout);
+    let value = value.trim_end_matches(['\r', '\n']);
+    match (output.status.success(), value.is_empty()) {
+        (true, false) => Ok(Some(value.to_string())),
+        // The tools fail without output when the secret is not stored
+        (_, true) if not_found(&output) => Ok(None),
+        _ => Err(failed(secret, &output)),
+    }
+}
+
+/// Stores a secret in the keyring, replacing the one stored before.
+///
+/// # Errors
+///
+/// Returns an error if the secret spans several lines, or if the keyring
+/// cannot be written.
+pub fn set(secret: Secret, value: &str) -> Result<()> {
+    // The tools read the secret as a line of stdin
+    if value.contains(['\r', '\n']) {
+        return Err(anyhow!("{} spans several lines", secret));
+    }
+    let account = secret.account();
+    // The Credential Manager keeps no label
+    #[cfg(not(windows))]
+    let label = format!("file-guardian: {}", secret);
+    // security only takes the secret as an argument, so the command is given
+    // to its interactive mode, which carries on after a failed command
+    #[cfg(target_os = "macos")]
+    let output = run(
+        "security",
+        &["-i"],
+        Some(&format!(
+            "add-generic-password -U -s {} -a {} -l {} -w {}\n",
+            quote(SERVICE),
+            quote(&account),
+            quote(&label),
+            quote(value)
+        )),
+    )
+    .map(|mut output| {
+        if !output.stderr.trim_ascii().is_empty() {
+            output.status = failure();
+        }
+        output
+    })?;
+    #[cfg(windows)]
+    let output = powershell(SET_CREDENTIAL, &[&account, value])?;
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let output = run(
+        "secret-tool",
+        &[
+            "store",
+            &format!("--label={}", label),
+            "service",
+            SERVICE,
+            "account",
+            &account,
+        ],
+        Some(value),
+    )?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(failed(secret, &output)),
+    }
+}
+
+/// Removes a secret from the keyring.
+///
+/// # Returns
+///
+/// Returns whether the secret was stored.
+///
+/// # Errors
+///
+/// Returns an error if the keyring cannot be written.
+pub fn delete(secret: Secret) -> Result<bool> {
+    if get(secret)?.is_none() {
+        return Ok(false);
+    }
+    let account = secret.account();
+    #[cfg(target_os = "macos")]
+    let output = run(
+        "security",
+        &["delete-generic-password", "-s", SERVICE, "-a", &account],
+        None,
+    )?;
+    #[cfg(windows)]
+    let output = powershell(DELETE_CREDENTIAL, &[&account])?;
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let output = run(
+        "secret-tool",
+        &["clear", "service", SERVICE, "account", &account],
+        None,
+    )?;
+    match output.status.success() {
+        true => Ok(true),
+        false => Err(failed(secret, &output)),
+    }
+}
+
+/// Runs the tool of the keyring, with `input` on its stdin.
+fn run(tool: &str, args: &[&str], input: Option<&str>) -> Result<Output> {
+    let mut child = Command::new(tool)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow!(
+                "The keyring requires {}, which is not installed{}",
+                tool,
+                if tool == "secret-tool" {
+                    " (e.g. in the libsecret-tools package)"
+                } else {
+                    ""
+                }
+            ),
+            _ => anyhow!("Could not run {}: {}", tool, e),
+        })?;
+    // Closing stdin once written, there being no input to some commands
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    if let Some(input) = input {
+        stdin.write_all(input.as_bytes())?;
+    }
+    drop(stdin);
+    Ok(child.wait_with_output()?)
+}
+
+/// Returns an argument of a command of the interactive mode of `security`,
+/// quoted.
+#[cfg(any(target_os = "macos", test))]
+fn quote(argument: &str) -> String {
+    format!(
+        "\"{}\"",
+        argument.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// Returns the status of a command that failed.
+#[cfg(target_os = "macos")]
+fn failure() -> std::process::ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    std::process::ExitStatus::from_raw(1 << 8)
+}
+
+/// Loads the Credential Manager of Windows, reading the account of the
+/// secret from the first line of stdin, and the service from `$service`.
+#[cfg(windows)]
+const VAULT: &str = "$ErrorActionPreference = 'Stop'
+[void][Windows.Security.Credentials.PasswordVault, Windows.Security.Credentials, ContentType = WindowsRuntime]
+$vault = New-Object Windows.Security.Credentials.PasswordVault
+$account = [Console]::In.ReadLine()
+function Find-Credential {
+    try { $vault.Retrieve($service, $account) }
+    catch {
+        $e = $_.Exception
+        while ($e.InnerException) { $e = $e.InnerException }
+        # Element not found, as the exit status of security on macOS
+        if ($e.HResult -eq -2147023728) { exit 44 }
+        throw
+    }
+}
+";
+
+/// Prints the secret of the account.
+#[cfg(windows)]
+const GET_CREDENTIAL: &str = "$credential = Find-Credential
+$credential.RetrievePassword()
+[Console]::Out.Write($credential.Password)";
+
+/// Stores the secret read from the second line of stdin, replacing the one
+/// stored before.
+#[cfg(windows)]
+const SET_CREDENTIAL: &str = "$secret = [Console]::In.ReadLine()
+$vault.Add((New-Object Windows.Security.Credentials.PasswordCredential(
+    $service, $account, $secret)))";
+
+/// Removes the secret of the account.
+#[cfg(windows)]
+const DELETE_CREDENTIAL: &str = "$vault.Remove((Find-Credential))";
+
+/// Runs a script of the Credential Manager with PowerShell, the lines of
+/// `input` on its stdin.
+#[cfg(windows)]
+fn powershell(script: &str, input: &[&str]) -> Result<Output> {
+    let script = format!("$service = '{}'\n{}{}", SERVICE, VAULT, script);
+    let mut input = input.join("\n");
+    input.push('\n');
+    run(
+        "powershell",
+        &["-NoProfile", "-NonInteractive", "-Command", &script],
+        Some(&input),
+    )
+}
+
+/// Returns whether the tool failed because the secret is not stored.
+fn not_found(output: &Output) -> bool {
+    match cfg!(any(target_os = "macos", windows)) {
+        // errSecItemNotFound, or the same status from the Credential Manager
+        // scripts
+        true => output.status.code() == Some(44),
+        false => output.stderr.is_empty(),
+    }
+}
+
+/// Returns the error of a tool that failed.
+fn failed(secret: Secret, output: &Output) -> anyhow::Error {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.trim() {
+        "" => anyhow!("Could not access {} in the keyring", secret),
+        stderr => {
+            anyhow!("Could not access {} in the keyring: {}", secret, stderr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account() {
+        assert_eq!(
+            Secret::Token("files.example.com:2345").account(),
+            "token@files.example.com:2345"
+        );
+        assert_eq!(Secret::EncryptionKey.account(), "encryption-key");
+        assert_eq!(
+            Secret::Token("127.0.0.1:2345").to_string(),
+            "the token of 127.0.0.1:2345"
+        );
+        assert_eq!(quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert!(set(Secret::EncryptionKey, "a\nb").is_err());
+    }
+}
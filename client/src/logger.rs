@@ -0,0 +1,127 @@
+use crate::progress;
+use crate::utils::format_time;
+use anyhow::Result;
+use clap::ValueEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::time::SystemTime;
+
+/// The format of the log messages, written to stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// A line per message, e.g. `Warning: download of a.txt failed`
+    #[default]
+    Text,
+    /// A JSON object per line, with the time, level, module and message
+    Json,
+}
+
+/// Logs the messages of the client to stderr, above the progress bars.
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+    /// Whether to also log the messages of the libraries, e.g. of the TLS
+    /// handshake, below warnings.
+    libraries: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && (self.libraries
+                || metadata.level() <= Level::Warn
+                || metadata.target().starts_with(env!("CARGO_CRATE_NAME")))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text => {
+                let level = match record.level() {
+                    Level::Error => "Error",
+                    Level::Warn => "Warning",
+                    Level::Info => "Info",
+                    Level::Debug => "Debug",
+                    Level::Trace => "Trace",
+                };
+                format!("{}: {}", level, record.args())
+            }
+            LogFormat::Json => serde_json::json!({
+                "time": SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|time| format_time(time.as_secs()))
+                    .unwrap_or_default(),
+                "level": record.level().as_str().to_lowercase(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+        };
+        progress::suspend(|| eprintln!("{}", line));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Returns the level of the messages logged: warnings and errors by
+/// default, errors only when quiet, and more with every `-v`.
+fn level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+/// Sets up the logger of the client.
+///
+/// # Arguments
+///
+/// * `verbose` - The number of `-v` flags: 1 logs what the commands do, 2
+///   the requests sent to the server, and 3 everything, including the
+///   messages of the libraries.
+/// * `quiet` - Whether to only log errors.
+/// * `format` - The format of the messages.
+///
+/// # Errors
+///
+/// Returns an error if a logger is already set up.
+pub fn init(verbose: u8, quiet: bool, format: LogFormat) -> Result<()> {
+    let level = level(verbose, quiet);
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        format,
+        libraries: verbose >= 3,
+    }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level(0, false), LevelFilter::Warn);
+        assert_eq!(level(2, false), LevelFilter::Debug);
+        assert_eq!(level(5, false), LevelFilter::Trace);
+        assert_eq!(level(2, true), LevelFilter::Error);
+
+        let logger = Logger {
+            level: LevelFilter::Debug,
+            format: LogFormat::Text,
+            libraries: false,
+        };
+        let metadata = |level, target| {
+            Metadata::builder().level(level).target(target).build()
+        };
+        assert!(logger.enabled(&metadata(Level::Debug, "client::client")));
+        assert!(!logger.enabled(&metadata(Level::Debug, "rustls::client")));
+        assert!(logger.enabled(&metadata(Level::Warn, "rustls::client")));
+        assert!(!logger.enabled(&metadata(Level::Trace, "client")));
+    }
+}
@@ -1,92 +1,2864 @@
-use clap::Parser;
-use cli::{Args, SubCommand};
-use db::Db;
-use merkle_tree::MerkleTree;
-use std::{fs, path::PathBuf};
+use anchors::Anchors;
+use batch::Batch;
+use cache::ProofCache;
+use clap::{CommandFactory, Parser};
+use cli::{Args, DaemonCommand, DbCommand, ManifestCommand, SubCommand};
+use client::{Committed, Connection, Pool, Server, Timeouts};
+use config::Config;
+use crypto::Key;
+use daemon::{DaemonOptions, Hashes, JobState, QueuedFile, UploadRequest};
+use db::{Db, FileRecord, Upload};
+use encoding::Encoding;
+use keyring::Secret;
+use merkle_tree::{MerkleTree, Proof};
+use output::{
+    AuditStatus, BundleReport, CheckStatus, CheckedFile, DaemonStopReport,
+    DbReport, DeleteReport, DownloadReport, DownloadedFile, FindReport,
+    FoundFile, InfoFile, InfoReport, KeyringAction, KeyringReport, ListOptions,
+    ListReport, ManifestCheckReport, ManifestReport, OutputFormat, ProveReport,
+    RemoteInfo, RemoteListReport, ReportedError, TrashReport, UndeleteReport,
+    UploadReport, UsageReport, VerifyReport,
+};
+use progress::Progress;
+use retry::RetryPolicy;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+use throttle::Throttle;
+use tls::{Tls, TlsOptions};
+use tokio::io::AsyncReadExt;
+use tokio::task::JoinSet;
+use trash::Trash;
 
+mod anchors;
+mod batch;
+mod bundle;
+mod cache;
+mod check;
 mod cli;
 mod client;
+mod completions;
+mod config;
+mod connect;
+mod crypto;
+mod daemon;
 mod db;
+mod diff;
+mod encoding;
+mod http;
+mod keyring;
+mod logger;
+mod man;
+mod manifest;
+mod mmap;
+mod output;
+mod progress;
+mod retry;
+mod schema;
+mod sync;
+mod throttle;
+mod tls;
+mod trash;
+mod tui;
 
 #[macro_use]
 mod utils;
+mod watch;
 
-fn main() -> anyhow::Result<()> {
+/// The exit code of commands failing because the server did not respond in
+/// time, as with `timeout(1)`.
+const TIMEOUT_EXIT_CODE: u8 = 124;
+
+/// The size of the ranges large files are downloaded in with `--jobs`.
+const RANGE_SIZE: u64 = 8 * 1024 * 1024;
+
+#[tokio::main]
+async fn main() -> ExitCode {
     let args = Args::parse();
-    let mut db = Db::new(PathBuf::from("downloads"), "uploads.json")?;
+    let output = args.output;
+    logger::init(args.verbose, args.quiet, args.log_format)
+        .expect("no logger is set up yet");
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            output.print_error(&error);
+            if client::is_timeout(&error) {
+                ExitCode::from(TIMEOUT_EXIT_CODE)
+            } else if let Some(error) = client::server_error(&error) {
+                ExitCode::from(client::exit_code(error.kind))
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
 
+async fn run(args: Args) -> anyhow::Result<()> {
+    // Generated from the arguments alone, without a store nor configuration
     match args.subcmd {
-        SubCommand::List => {
-            let uploads = db.get_uploads();
-            utils::print_uploads(uploads);
+        SubCommand::Completions { shell } => {
+            let mut stdout = std::io::stdout().lock();
+            return Ok(completions::generate(
+                shell,
+                Args::command(),
+                &mut stdout,
+            )?);
+        }
+        SubCommand::Man => {
+            return Ok(man::render(Args::command(), &mut std::io::stdout())?)
         }
-        SubCommand::Upload { files, server_addr } => {
-            upload(files, &server_addr, &mut db)?;
+        _ => {}
+    }
+    let profile = Config::load(args.config.as_deref())?
+        .profile(args.profile.as_deref())?;
+    let store_dir = args
+        .store_dir
+        .or_else(|| profile.store_dir.clone())
+        .unwrap_or_else(utils::default_store_dir);
+    let mut db = Db::new(store_dir, "uploads.json")?;
+    let cache =
+        ProofCache::new(db.get_db_path().join("proofs"), !args.no_cache);
+    let trash = Trash::new(db.get_db_path().join("trash"));
+    // Never fail a command because the trash could not be purged
+    if let Err(e) = trash.purge(SystemTime::now()) {
+        log::warn!("Could not purge the trash: {}", e);
+    }
+    let output = args.output;
+    // Keep the JSON output, and stderr, free of progress bars
+    let hide_progress = output == OutputFormat::Json;
+    let server = |server_addr| -> anyhow::Result<Server> {
+        let addr = profile.server_addr(server_addr);
+        let tls_cert = args.tls_cert.as_deref().or(profile.tls_cert.as_deref());
+        let tls_key = args.tls_key.as_deref().or(profile.tls_key.as_deref());
+        let options = TlsOptions {
+            ca: args.tls_ca.as_deref().or(profile.tls_ca.as_deref()),
+            server_name: args
+                .tls_server_name
+                .as_deref()
+                .or(profile.tls_server_name.as_deref()),
+            identity: match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => Some((cert, key)),
+                (None, None) => None,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "A TLS client certificate requires its key, and a key \
+                         its certificate"
+                    ))
+                }
+            },
+        };
+        // An http:// or https:// address is the URL of the HTTP API
+        let http = addr.contains("://").then(|| http::Url::parse(&addr));
+        let http = http.transpose()?;
+        let tls = match &http {
+            Some(url) => url.tls.then(|| Tls::new(&url.address, &options)),
+            None => {
+                (args.tls || profile.tls).then(|| Tls::new(&addr, &options))
+            }
+        }
+        .transpose()?;
+        let token = args
+            .token
+            .clone()
+            .or_else(|| profile.token.clone())
+            .or_else(|| from_keyring(Secret::Token(&addr)));
+        Ok(Server {
+            addr,
+            tls,
+            token,
+            timeouts: Timeouts::new(
+                args.connect_timeout
+                    .or(profile.connect_timeout)
+                    .unwrap_or(client::DEFAULT_CONNECT_TIMEOUT),
+                args.timeout
+                    .or(profile.timeout)
+                    .unwrap_or(client::DEFAULT_IO_TIMEOUT),
+            ),
+            retry: RetryPolicy {
+                retries: args.retries,
+                backoff: args.retry_backoff,
+                max_backoff: args.retry_max_backoff,
+                jitter: !args.no_retry_jitter,
+            },
+            throttle: args.limit_rate.or(profile.limit_rate).map(Throttle::new),
+            compression: args.wire_compression.or(profile.wire_compression),
+            pool: None,
+            http,
+            server_key: args
+                .server_key
+                .as_deref()
+                .or(profile.server_key.as_deref())
+                .map(|key| {
+                    utils::decode_hash(key).map(hex::encode).map_err(|_| {
+                        anyhow::anyhow!("Invalid server key {}", key)
+                    })
+                })
+                .transpose()?,
+        })
+    };
+    // Only read the key for the commands that need it
+    let key = || -> anyhow::Result<Option<Key>> {
+        match args
+            .encryption_key_file
+            .as_deref()
+            .or(profile.encryption_key_file.as_deref())
+        {
+            Some(file) => Key::from_file(file).map(Some),
+            None => from_keyring(Secret::EncryptionKey)
+                .map(|key| Key::from_hex(&key))
+                .transpose(),
+        }
+    };
+    // Only load the trust anchors for the commands checking root hashes
+    let anchors = async || -> anyhow::Result<Option<Anchors>> {
+        let sources = match args.trust_anchor.is_empty() {
+            true => &profile.trust_anchors,
+            false => &args.trust_anchor,
+        };
+        let ca = args.tls_ca.as_deref().or(profile.tls_ca.as_deref());
+        match sources.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(Anchors::load(sources, ca).await?)),
+        }
+    };
+
+    match args.subcmd {
+        SubCommand::List {
+            remote: true,
+            server_addr,
+            ..
+        } => {
+            let server = server(server_addr)?;
+            let stored = server
+                .run("listing", |client| client.list_uploads())
+                .await?;
+            output.print(&RemoteListReport::new(
+                &server.addr,
+                stored,
+                db.get_uploads(),
+            ))?;
+        }
+        SubCommand::List {
+            filter,
+            root,
+            sort,
+            reverse,
+            tag,
+            ..
+        } => {
+            let options = ListOptions {
+                tags: tag,
+                filter: filter
+                    .as_deref()
+                    .map(glob::Pattern::new)
+                    .transpose()?,
+                root,
+                sort,
+                reverse,
+            };
+            output.print(&ListReport::new(db.get_uploads(), &options))?;
+        }
+        SubCommand::Upload {
+            files,
+            url,
+            exclude,
+            stdin_name,
+            server_addr,
+            delete,
+            trash_retention,
+            retention,
+            verify,
+            jobs,
+            encrypt,
+            compress,
+            dry_run,
+            tag,
+            no_dedup,
+            daemon,
+            wait,
+        } => {
+            let tags = utils::tags(tag)?;
+            if daemon {
+                let socket = daemon::socket(db.get_db_path());
+                let request = UploadRequest {
+                    files: daemon_files(&files, &exclude)?,
+                    tags,
+                    verify,
+                    dedup: !no_dedup,
+                    jobs: jobs.into(),
+                    trash_retention: delete.then_some(trash_retention),
+                    retention,
+                };
+                let mut job = daemon::queue(&socket, request).await?;
+                if wait {
+                    job = daemon::wait(&socket, job.id).await?;
+                }
+                if let JobState::Failed { error } = &job.state {
+                    return Err(anyhow::anyhow!("{}", error));
+                }
+                output.print(&job)?;
+                return Ok(());
+            }
+            let key = match encrypt {
+                true => Some(key()?.ok_or(anyhow::anyhow!(
+                    "Encrypting requires a key, given with \
+                     --encryption-key-file, in the profile or stored with \
+                     `login --encryption-key-file`"
+                ))?),
+                false => None,
+            };
+            let options = UploadOptions {
+                stdin_name: &stdin_name,
+                trash: delete.then_some((&trash, trash_retention)),
+                verify,
+                jobs: jobs.into(),
+                encoding: Encoding {
+                    compression: compress,
+                    key: key.as_ref(),
+                },
+                tags: &tags,
+                dry_run,
+                // Every encryption yields new files, which the server never
+                // holds
+                dedup: !no_dedup && !encrypt,
+                hashes: None,
+                retention,
+            };
+            let server = server(server_addr)?;
+            let report = match url.is_empty() {
+                true => {
+                    upload(
+                        files,
+                        &exclude,
+                        &server,
+                        &options,
+                        hide_progress,
+                        &mut db,
+                    )
+                    .await?
+                }
+                false => {
+                    let ca =
+                        args.tls_ca.as_deref().or(profile.tls_ca.as_deref());
+                    upload_urls(
+                        &url,
+                        &server,
+                        &options,
+                        ca,
+                        hide_progress,
+                        &mut db,
+                    )
+                    .await?
+                }
+            };
+            output.print(&report)?;
         }
         SubCommand::Download {
+            root_hash,
+            mut file,
+            files_from,
+            server_addr,
+            out,
+            overwrite,
+            jobs,
+        } => {
+            if let Some(list) = files_from {
+                file.extend(read_names(&list)?);
+            }
+            let (key, anchors) = (key()?, anchors().await?);
+            output.print(
+                &download(
+                    &root_hash,
+                    &file,
+                    &server(server_addr)?,
+                    &DownloadOptions {
+                        out,
+                        overwrite,
+                        key: key.as_ref(),
+                        anchors: anchors.as_ref(),
+                        hide_progress,
+                        jobs: jobs.into(),
+                        cache: &cache,
+                    },
+                    &mut db,
+                )
+                .await?,
+            )?;
+        }
+        SubCommand::Find {
+            name,
+            remote,
+            download: download_found,
+            pick,
+            server_addr,
+            out,
+            overwrite,
+            jobs,
+        } => {
+            let held = match remote {
+                true => {
+                    let server = server(server_addr.clone())?;
+                    let stored = server
+                        .run("listing", |client| client.list_uploads())
+                        .await?;
+                    Some((server.addr, stored))
+                }
+                false => None,
+            };
+            let report = FindReport::new(
+                &glob::Pattern::new(&name)?,
+                db.get_uploads(),
+                held,
+            );
+            if !download_found && pick.is_none() {
+                output.print(&report)?;
+            } else {
+                let file = pick_file(&report, pick, output)?;
+                // Download from the server the file was uploaded to
+                let server_addr =
+                    server_addr.or_else(|| file.server_addr.clone());
+                let (key, anchors) = (key()?, anchors().await?);
+                output.print(
+                    &download(
+                        &file.root_hash,
+                        std::slice::from_ref(&file.name),
+                        &server(server_addr)?,
+                        &DownloadOptions {
+                            out,
+                            overwrite,
+                            key: key.as_ref(),
+                            anchors: anchors.as_ref(),
+                            hide_progress,
+                            jobs: jobs.into(),
+                            cache: &cache,
+                        },
+                        &mut db,
+                    )
+                    .await?,
+                )?;
+            }
+        }
+        SubCommand::DownloadAll {
+            root_hash,
+            server_addr,
+            out,
+            overwrite,
+            jobs,
+        } => {
+            let (key, anchors) = (key()?, anchors().await?);
+            output.print(
+                &download_all(
+                    &root_hash,
+                    &server(server_addr)?,
+                    &DownloadOptions {
+                        out,
+                        overwrite,
+                        key: key.as_ref(),
+                        anchors: anchors.as_ref(),
+                        hide_progress,
+                        jobs: jobs.into(),
+                        cache: &cache,
+                    },
+                    &mut db,
+                )
+                .await?,
+            )?;
+        }
+        SubCommand::Watch {
+            dir,
+            exclude,
+            server_addr,
+            debounce,
+            jobs,
+            tag,
+        } => {
+            let tags = utils::tags(tag)?;
+            let exclude = exclude
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<Result<Vec<_>, _>>()?;
+            let server = server(server_addr)?;
+            let options = UploadOptions {
+                stdin_name: "",
+                trash: None,
+                verify: false,
+                jobs: jobs.into(),
+                encoding: Encoding::default(),
+                tags: &tags,
+                dry_run: false,
+                dedup: false,
+                hashes: None,
+                retention: None,
+            };
+            let ignore = db.get_db_path().clone();
+            watch::watch(&dir, &exclude, &ignore, debounce, async |files| {
+                let (paths, names) = files.into_iter().unzip();
+                let report = upload_files(
+                    paths,
+                    names,
+                    &server,
+                    &options,
+                    hide_progress,
+                    &mut db,
+                )
+                .await?;
+                output.print(&report)
+            })
+            .await?;
+        }
+        SubCommand::Sync {
+            dir,
+            exclude,
+            server_addr,
+            checksum,
+            verify,
+            jobs,
+            dry_run,
+            tag,
+        } => {
+            let tags = utils::tags(tag)?;
+            let exclude = exclude
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<Result<Vec<_>, _>>()?;
+            let options = UploadOptions {
+                stdin_name: "",
+                trash: None,
+                verify,
+                jobs: jobs.into(),
+                encoding: Encoding::default(),
+                tags: &tags,
+                dry_run,
+                dedup: false,
+                hashes: None,
+                retention: None,
+            };
+            output.print(
+                &sync::sync(
+                    &dir,
+                    &exclude,
+                    &server(server_addr)?,
+                    &options,
+                    checksum,
+                    hide_progress,
+                    &mut db,
+                )
+                .await?,
+            )?;
+        }
+        SubCommand::Cat {
             root_hash,
             file,
             server_addr,
         } => {
-            download(&root_hash, &file, &server_addr, &db)?;
+            let (key, anchors) = (key()?, anchors().await?);
+            cat(
+                &root_hash,
+                &file,
+                &server(server_addr)?,
+                &DownloadOptions {
+                    out: None,
+                    overwrite: false,
+                    key: key.as_ref(),
+                    anchors: anchors.as_ref(),
+                    hide_progress,
+                    jobs: 1,
+                    cache: &cache,
+                },
+                &mut db,
+            )
+            .await?;
+        }
+        SubCommand::Prove {
+            file,
+            root_hash,
+            server_addr,
+            out,
+        } => {
+            output.print(
+                &prove(
+                    &root_hash,
+                    &file,
+                    &server(server_addr)?,
+                    out,
+                    &cache,
+                    &mut db,
+                )
+                .await?,
+            )?;
+        }
+        SubCommand::Verify {
+            file,
+            root_hash,
+            name,
+        } => {
+            output.print(&verify(
+                file,
+                &root_hash,
+                name.as_deref(),
+                anchors().await?.as_ref(),
+                &cache,
+                &db,
+            )?)?;
+        }
+        SubCommand::Info {
+            root_hash,
+            remote,
+            server_addr,
+        } => {
+            let mut report = info(&root_hash, &db)?;
+            if remote {
+                // Ask the server the upload was uploaded to
+                let server_addr =
+                    server_addr.or_else(|| report.server_addr.clone());
+                remote_info(&mut report, &server(server_addr)?).await?;
+            }
+            output.print(&report)?;
+        }
+        SubCommand::Diff {
+            dir_a,
+            dir_b,
+            exclude,
+        } => {
+            let exclude = exclude
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<Result<Vec<_>, _>>()?;
+            let report = diff::diff(&dir_a, &dir_b, &exclude)?;
+            output.print(&report)?;
+            if !report.identical() {
+                return Err(ReportedError(format!(
+                    "{} and {} differ",
+                    dir_a.display(),
+                    dir_b.display()
+                ))
+                .into());
+            }
+        }
+        SubCommand::Check { dir, root_hash } => {
+            let mut uploads = match root_hash.is_empty() {
+                true => db
+                    .get_uploads()
+                    .iter()
+                    .map(|(root_hash, upload)| (root_hash.as_str(), upload))
+                    .collect::<Vec<_>>(),
+                false => root_hash
+                    .iter()
+                    .map(|root_hash| {
+                        db.get_upload(root_hash)
+                            .map(|upload| (root_hash.as_str(), upload))
+                            .ok_or(anyhow::anyhow!(
+                                "Root hash {} not found",
+                                root_hash
+                            ))
+                    })
+                    .collect::<Result<_, _>>()?,
+            };
+            uploads.sort_by_key(|(root_hash, _)| *root_hash);
+            let dir = dir.unwrap_or_else(|| PathBuf::from("."));
+            let report = check::check(
+                &dir,
+                &uploads,
+                anchors().await?.as_ref(),
+                &cache,
+            )?;
+            output.print(&report)?;
+            if !report.verified {
+                let files = report
+                    .uploads
+                    .iter()
+                    .map(|upload| upload.files.len())
+                    .sum::<usize>();
+                return Err(ReportedError(format!(
+                    "{} of {} files are not verified",
+                    files - report.count(AuditStatus::Ok),
+                    files
+                ))
+                .into());
+            }
+        }
+        SubCommand::Delete {
+            root_hash,
+            server_addr,
+        } => {
+            output.print(
+                &delete(&root_hash, &server(server_addr)?, &cache, &mut db)
+                    .await?,
+            )?;
+        }
+        SubCommand::Usage { server_addr } => {
+            let server = server(server_addr)?;
+            let (used, quota) =
+                server.run("usage", |client| client.usage()).await?;
+            output.print(&UsageReport::new(&server.addr, used, quota))?;
+        }
+        SubCommand::Undelete {
+            root_hash,
+            file,
+            list,
+        } => match (root_hash, list) {
+            (Some(root_hash), false) => output.print(&UndeleteReport {
+                files: trash.restore(&root_hash, &file)?,
+                root_hash,
+            })?,
+            _ => output.print(&TrashReport {
+                uploads: trash.list()?,
+            })?,
+        },
+        SubCommand::Tui { server_addr } => {
+            let (key, anchors) = (key()?, anchors().await?);
+            tui::run(
+                &server(server_addr)?,
+                key.as_ref(),
+                anchors.as_ref(),
+                &cache,
+                &mut db,
+            )
+            .await?;
+        }
+        SubCommand::Manifest { subcmd } => match subcmd {
+            ManifestCommand::Export { root_hash, out } => {
+                let entries = manifest_entries(&root_hash, &db)?;
+                let content = manifest::format(&entries);
+                match out {
+                    Some(file) => {
+                        fs::write(&file, content)?;
+                        output.print(&ManifestReport {
+                            root_hash,
+                            files: entries.len(),
+                            file,
+                        })?;
+                    }
+                    None => std::io::stdout().write_all(&content)?,
+                }
+            }
+            ManifestCommand::Check { file, dir } => {
+                let report = check_manifest(file, dir)?;
+                output.print(&report)?;
+                if !report.verified {
+                    let failed = report
+                        .files
+                        .iter()
+                        .filter(|file| file.status != CheckStatus::Ok)
+                        .count();
+                    return Err(ReportedError(format!(
+                        "{} of {} files do not match the manifest",
+                        failed,
+                        report.files.len()
+                    ))
+                    .into());
+                }
+            }
+        },
+        SubCommand::ExportBundle { root_hash, out } => {
+            let entries = manifest_entries(&root_hash, &db)?;
+            let upload = db
+                .get_upload(&root_hash)
+                .ok_or(anyhow::anyhow!("Root hash {} not found", root_hash))?;
+            let (tree, _) = recorded_tree(&root_hash, upload)?;
+            let file = out.unwrap_or_else(|| {
+                PathBuf::from(format!("{}.bundle.tar", root_hash))
+            });
+            bundle::export(&file, &root_hash, upload, &tree, &entries)?;
+            output.print(&BundleReport {
+                root_hash,
+                files: entries.len(),
+                file,
+            })?;
+        }
+        SubCommand::VerifyBundle { bundle, dir } => {
+            let report = bundle::verify(&bundle, &dir)?;
+            output.print(&report)?;
+            if !report.verified {
+                let failed = report
+                    .files
+                    .iter()
+                    .filter(|file| file.status != CheckStatus::Ok)
+                    .count();
+                return Err(ReportedError(format!(
+                    "{} of {} files do not match the bundle",
+                    failed,
+                    report.files.len()
+                ))
+                .into());
+            }
+        }
+        SubCommand::Login {
+            server_addr,
+            encryption_key,
+        } => {
+            let addr = profile.server_addr(server_addr);
+            let (secret, value) = match encryption_key {
+                true => {
+                    let file = args
+                        .encryption_key_file
+                        .as_deref()
+                        .or(profile.encryption_key_file.as_deref())
+                        .ok_or(anyhow::anyhow!(
+                            "Storing the encryption key requires its file, \
+                             given with --encryption-key-file or in the \
+                             profile"
+                        ))?;
+                    // Only store valid keys
+                    Key::from_file(file)?;
+                    (Secret::EncryptionKey, fs::read_to_string(file)?)
+                }
+                false => {
+                    (Secret::Token(&addr), read_token(args.token.clone())?)
+                }
+            };
+            let value = value.trim();
+            keyring::set(secret, value)?;
+            output.print(&KeyringReport {
+                secret: secret.to_string(),
+                action: KeyringAction::Stored,
+            })?;
+        }
+        SubCommand::Logout {
+            server_addr,
+            encryption_key,
+        } => {
+            let addr = profile.server_addr(server_addr);
+            let secret = match encryption_key {
+                true => Secret::EncryptionKey,
+                false => Secret::Token(&addr),
+            };
+            output.print(&KeyringReport {
+                secret: secret.to_string(),
+                action: match keyring::delete(secret)? {
+                    true => KeyringAction::Removed,
+                    false => KeyringAction::Missing,
+                },
+            })?;
+        }
+        SubCommand::Db { subcmd } => match subcmd {
+            DbCommand::Export { file } => {
+                db.export(&file)?;
+                output.print(&DbReport {
+                    action: "exported",
+                    uploads: db.get_uploads().len(),
+                    file,
+                })?;
+            }
+            DbCommand::Import { file } => {
+                output.print(&DbReport {
+                    action: "imported",
+                    uploads: db.import(&file)?,
+                    file,
+                })?;
+            }
+        },
+        SubCommand::Daemon { subcmd } => {
+            let socket = daemon::socket(db.get_db_path());
+            match subcmd {
+                DaemonCommand::Run {
+                    server_addr,
+                    connections,
+                    verify_every,
+                } => {
+                    let server = Server {
+                        pool: Some(Arc::new(Pool::new(connections.into()))),
+                        ..server(server_addr)?
+                    };
+                    let options = DaemonOptions {
+                        verify_every,
+                        trash: &trash,
+                    };
+                    daemon::run(&socket, &server, &options, &mut db).await?;
+                }
+                DaemonCommand::Status => {
+                    output.print(&daemon::status(&socket).await?)?
+                }
+                DaemonCommand::Stop => {
+                    daemon::stop(&socket).await?;
+                    output.print(&DaemonStopReport { socket })?;
+                }
+            }
+        }
+        SubCommand::Completions { .. } | SubCommand::Man => {
+            unreachable!("generated before the store is opened")
         }
     }
 
     Ok(())
 }
 
-fn upload(
+/// How the files of an upload are sent, and what happens to them once sent.
+struct UploadOptions<'a> {
+    /// The name stdin is uploaded under, if given as `-`.
+    stdin_name: &'a str,
+    /// The trash the original files are moved to once the upload is
+    /// verified, and how long they are kept there, if they are deleted.
+    trash: Option<(&'a Trash, Duration)>,
+    /// Whether to verify the proof of every file served by the server.
+    verify: bool,
+    /// The number of files sent concurrently.
+    jobs: usize,
+    /// How the files are transformed before they are sent.
+    encoding: Encoding<'a>,
+    /// The tags to record the upload with.
+    tags: &'a [String],
+    /// Whether to only hash the files and report the root hash, without
+    /// contacting the server, recording the upload nor deleting the files.
+    dry_run: bool,
+    /// Whether to hash the files first and ask the server whether it already
+    /// holds the batch, only recording the upload if it does.
+    dedup: bool,
+    /// The hashes of the files already hashed, reused rather than hashing
+    /// them again, e.g. by the daemon.
+    hashes: Option<&'a Hashes>,
+    /// How long the server keeps the upload before deleting it, if not the
+    /// retention of the server.
+    retention: Option<Duration>,
+}
+
+async fn upload(
     files: Vec<PathBuf>,
-    server_addr: &str,
+    exclude: &[String],
+    server: &Server,
+    options: &UploadOptions<'_>,
+    hide_progress: bool,
     db: &mut Db,
-) -> Result<(), anyhow::Error> {
-    // Remove duplicates
-    let files = utils::dedup(files);
+) -> Result<UploadReport, anyhow::Error> {
+    let exclude = exclude
+        .iter()
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Spool stdin to a temporary file, so that it can be hashed and its
+    // transfer resumed like any other file
+    let stdin = Path::new("-");
+    let spool = files
+        .iter()
+        .any(|file| file == stdin)
+        .then(|| -> Result<_, anyhow::Error> {
+            let dir = utils::TempDir::new()?;
+            let path = dir.path().join("stdin");
+            std::io::copy(
+                &mut std::io::stdin().lock(),
+                &mut File::create(&path)?,
+            )?;
+            Ok((dir, path))
+        })
+        .transpose()?;
+
+    // Expand patterns and directories into the files they contain and remove
+    // duplicates
+    let mut collected = vec![];
+    for file in &files {
+        match &spool {
+            Some((_, path)) if file == stdin => {
+                collected.push((path.clone(), options.stdin_name.to_string()))
+            }
+            _ => collected.extend(utils::collect_files(
+                std::slice::from_ref(file),
+                &exclude,
+            )?),
+        }
+    }
+    let (paths, names): (Vec<PathBuf>, Vec<String>) =
+        utils::dedup(collected).into_iter().unzip();
+    if paths.is_empty() {
+        return Err(anyhow::anyhow!("No files to upload"));
+    }
 
-    // Read the files
-    let data = files
+    upload_files(paths, names, server, options, hide_progress, db).await
+}
+
+/// Returns the files to queue on the daemon: the files given on the command
+/// line, with directories and glob patterns expanded, by absolute path, as
+/// the daemon may run in another directory.
+///
+/// # Errors
+///
+/// Returns an error if a pattern is invalid, stdin is given, or there are no
+/// files to upload.
+fn daemon_files(
+    files: &[PathBuf],
+    exclude: &[String],
+) -> Result<Vec<QueuedFile>, anyhow::Error> {
+    if files.iter().any(|file| file == Path::new("-")) {
+        return Err(anyhow::anyhow!("The daemon cannot upload stdin"));
+    }
+    let exclude = exclude
         .iter()
-        .map(utils::read)
-        .collect::<Result<Vec<Vec<u8>>, _>>()?;
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+    let files = utils::dedup(utils::collect_files(files, &exclude)?)
+        .into_iter()
+        .map(|(path, name)| {
+            let path = path.canonicalize()?;
+            Ok(QueuedFile { path, name })
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    if files.is_empty() {
+        return Err(anyhow::anyhow!("No files to upload"));
+    }
+    Ok(files)
+}
 
-    // Compute the root hash
-    let root_hash = MerkleTree::new(&data)?
+/// Uploads files under the given names, and records the upload once the
+/// server has acknowledged it.
+async fn upload_files(
+    paths: Vec<PathBuf>,
+    names: Vec<String>,
+    server: &Server,
+    options: &UploadOptions<'_>,
+    hide_progress: bool,
+    db: &mut Db,
+) -> Result<UploadReport, anyhow::Error> {
+    // Compress and encrypt the files first if asked to, so that the server
+    // only receives, and the tree only covers, the encoded files
+    let encoded = (!options.encoding.is_identity())
+        .then(|| options.encoding.encode(&paths))
+        .transpose()?;
+    let sent = encoded.as_ref().map_or(&paths, |files| &files.paths);
+
+    // Stream, hash and send the files, then compute the root hash from the
+    // hashes of the files, i.e. the leaves of the tree
+    let sizes = sent
+        .iter()
+        .map(|path| Ok(fs::metadata(path)?.len()))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    let data_size = sizes.iter().sum();
+    // Keep the state of the batch as it goes, so that an interrupted upload
+    // resumes where it stopped. Encrypted files are encrypted anew by every
+    // run, so their uploads start over
+    let batch = (!options.dry_run && options.encoding.key.is_none())
+        .then(|| {
+            let key = batch_key(&paths, &names, &sizes, server, options)?;
+            let dir = db.get_db_path().join("batches");
+            Batch::open(&dir, &key, paths.len(), || new_session(server))
+        })
+        .transpose()?
+        .map(Arc::new);
+    // Hash the files locally in a dry run, and to look the batch up on the
+    // server before sending it, the server hashing them as they are received
+    // otherwise
+    let mut leaves = (options.dry_run || options.dedup)
+        .then(|| {
+            sent.iter()
+                .enumerate()
+                .map(|(index, path)| {
+                    let hashed = batch
+                        .as_ref()
+                        .and_then(|batch| batch.file(index).sha256.as_ref());
+                    if let Some(sha256) = hashed {
+                        return utils::decode_hash(sha256);
+                    }
+                    let leaf = match options.hashes {
+                        Some(hashes) => hashes.sha256_file(path),
+                        None => utils::sha256_file(path),
+                    }?;
+                    if let Some(batch) = &batch {
+                        batch.hashed(index, &hex::encode(leaf))?;
+                    }
+                    Ok(leaf)
+                })
+                .collect::<Result<Vec<_>, anyhow::Error>>()
+        })
+        .transpose()?;
+    let mut deduplicated = match &leaves {
+        Some(leaves) if !options.dry_run => {
+            server_holds(leaves, server).await?
+        }
+        _ => false,
+    };
+    // A batch the server holds is committed again for its signature when the
+    // server key is pinned, the server storing nothing again
+    let mut signature = None;
+    if let Some(leaves) = leaves
+        .as_ref()
+        .filter(|_| deduplicated && server.server_key.is_some())
+    {
+        let hashes = leaves.iter().map(hex::encode).collect::<Vec<_>>();
+        signature = server.commit(&hashes).await?.signature;
+    }
+    // Nor are the files whose content the server already holds, e.g. from
+    // the last snapshot, nor those an interrupted run sent
+    let held = match &leaves {
+        Some(leaves) if !options.dry_run && !deduplicated => {
+            held_contents(leaves, server).await
+        }
+        _ => vec![],
+    };
+    let resumed = (0..sizes.len())
+        .filter(|&index| {
+            held.get(index).is_some_and(Option::is_some)
+                || batch
+                    .as_ref()
+                    .is_some_and(|batch| batch.file(index).transferred)
+        })
+        .map(|index| sizes[index])
+        .sum::<u64>();
+    let progress = (!options.dry_run && !deduplicated)
+        .then(|| Progress::new(Some(data_size - resumed), hide_progress));
+    let mut server_root_hash = None;
+    if let Some(progress) = &progress {
+        log::info!(
+            "Uploading {} files, {}, to {}",
+            sent.len(),
+            utils::format_size(data_size),
+            server.addr
+        );
+        let files = sent
+            .iter()
+            .cloned()
+            .zip(names.iter().cloned())
+            .zip(sizes.iter().copied())
+            .collect();
+        let (sent_leaves, committed) = send_files(
+            files,
+            &held,
+            server,
+            options.jobs,
+            progress,
+            batch.clone(),
+        )
+        .await?;
+        if committed.already_stored {
+            log::info!(
+                "The server already held {}, and stored nothing again",
+                committed.root_hash
+            );
+        }
+        deduplicated = committed.already_stored;
+        leaves = Some(sent_leaves);
+        signature = committed.signature;
+        server_root_hash = Some(committed.root_hash);
+    }
+    let tree =
+        MerkleTree::from_leaves(leaves.expect("the files are hashed or sent"))?;
+    let root_hash = tree
         .root()
         .map(hex::encode)
         .ok_or(anyhow::anyhow!("Root Hash could not be computed"))?;
 
-    let mut client = client::TcpClient::new(server_addr)?;
-    client.send_files(data)?;
-    db.persist(&root_hash, &files)?;
+    match server_root_hash {
+        Some(server_root_hash) if server_root_hash != root_hash => {
+            return Err(anyhow::anyhow!(
+                "Server root hash {} does not match local root hash {}",
+                server_root_hash,
+                root_hash
+            ));
+        }
+        _ => {}
+    }
+    // Only trust the server once it proved it stored every file sent, or
+    // already held
+    if options.verify && !options.dry_run {
+        verify_upload(&tree, &names, server, batch.as_deref()).await?;
+    }
+    if let Some(retention) = options.retention.filter(|_| !options.dry_run) {
+        retain(&root_hash, retention, server).await?;
+    }
+    // The names of encrypted files are kept from the server, like their
+    // content
+    if !options.dry_run && options.encoding.key.is_none() {
+        send_names(&root_hash, &names, server).await;
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    // Record the metadata of the files, the leaves of the tree being the
+    // SHA-256 of their content, and their proofs to verify them offline
+    let files = paths
+        .iter()
+        .zip(names)
+        .zip(sizes)
+        .zip(tree.leaves())
+        .enumerate()
+        .map(|(index, (((path, name), size), leaf))| FileRecord {
+            name_bytes: utils::name_bytes(path, &name),
+            name,
+            size: Some(size),
+            modified: fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(utils::unix_time),
+            sha256: Some(hex::encode(leaf)),
+            encrypted: options.encoding.key.is_some(),
+            compressed: options.encoding.compression.is_some(),
+            proof: tree
+                .proof(index)
+                .ok()
+                .map(|proof| proof.iter().map(hex::encode).collect()),
+            source: None,
+        })
+        .collect::<Vec<_>>();
 
-    for file in files {
-        std::fs::remove_file(file)?;
+    if options.dry_run {
+        return Ok(UploadReport {
+            root_hash,
+            server_addr: server.addr.clone(),
+            size: data_size,
+            files,
+            verified: false,
+            proofs_verified: false,
+            deleted: false,
+            dry_run: true,
+            deduplicated: false,
+            signature: None,
+        });
     }
 
-    println!("Succesfully Uploaded files with root hash {}", root_hash);
-    Ok(())
+    if !deduplicated {
+        log::info!("The server stored the files as root hash {}", root_hash);
+    }
+    db.persist(
+        &root_hash,
+        Upload {
+            files: files.clone(),
+            uploaded_at: utils::unix_time(SystemTime::now()),
+            server_addr: Some(server.addr.clone()),
+            tags: options.tags.to_vec(),
+            signature: signature.clone(),
+        },
+    )?;
+    if let Some(batch) = batch {
+        batch.finish()?;
+    }
+
+    // Only remove the originals once the server has confirmed it stored
+    // exactly what we sent, and even then keep them in the trash for a while
+    if let Some((trash, retention)) = options.trash {
+        let trashed = paths
+            .into_iter()
+            .zip(files.iter().map(|file| file.name.clone()))
+            .collect::<Vec<_>>();
+        trash.put(&root_hash, &trashed, retention)?;
+    }
+
+    Ok(UploadReport {
+        root_hash,
+        server_addr: server.addr.clone(),
+        size: data_size,
+        files,
+        verified: true,
+        proofs_verified: options.verify,
+        deleted: options.trash.is_some(),
+        dry_run: false,
+        deduplicated,
+        signature,
+    })
+}
+
+/// Returns the key identifying the batch of an upload: the server, the
+/// compression, and the path, name, size and modification time of every
+/// file, so that the upload starts over if any of them changed.
+fn batch_key(
+    paths: &[PathBuf],
+    names: &[String],
+    sizes: &[u64],
+    server: &Server,
+    options: &UploadOptions<'_>,
+) -> Result<String, anyhow::Error> {
+    let mut key = format!(
+        "upload {} {:?}\n",
+        server.addr, options.encoding.compression
+    );
+    for ((path, name), size) in paths.iter().zip(names).zip(sizes) {
+        let modified = fs::metadata(path)?.modified()?;
+        key.push_str(&format!(
+            "{} {} {} {:?}\n",
+            utils::escape_name(path.canonicalize()?.as_os_str()),
+            name,
+            size,
+            modified
+        ));
+    }
+    Ok(key)
+}
+
+/// Uploads the resources of `http://` and `https://` URLs, streamed from
+/// their web server to the server and hashed as they are received, without
+/// a local copy, and records the upload along with the URL of every file.
+///
+/// The files are named after the last segment of their URL, and sent one
+/// after the other. A transfer failing with a transient error is resumed by
+/// fetching the URL again, the bytes the server already holds being hashed
+/// but not sent, so the web server must give the size of the resource, and
+/// the upload fails if the resource changes in between.
+async fn upload_urls(
+    urls: &[String],
+    server: &Server,
+    options: &UploadOptions<'_>,
+    ca: Option<&Path>,
+    hide_progress: bool,
+    db: &mut Db,
+) -> Result<UploadReport, anyhow::Error> {
+    let urls = urls
+        .iter()
+        .map(|url| http::Url::parse(url))
+        .collect::<Result<Vec<_>, _>>()?;
+    let names = urls
+        .iter()
+        .map(|url| url.file_name().to_string())
+        .collect::<Vec<_>>();
+    if let Some((_, name)) = names
+        .iter()
+        .enumerate()
+        .find(|(index, name)| names[..*index].contains(name))
+    {
+        return Err(anyhow::anyhow!("Several URLs are uploaded as {}", name));
+    }
+
+    let session = new_session(server);
+    let progress = Progress::new(None, hide_progress || options.dry_run);
+    let mut leaves = vec![];
+    let mut sizes = vec![];
+    for (index, (url, name)) in urls.iter().zip(&names).enumerate() {
+        let url = &url.to_string();
+        if options.dry_run {
+            let (leaf, size) = hash_url(url, ca).await?;
+            leaves.push(leaf);
+            sizes.push(size);
+            continue;
+        }
+        log::info!("Uploading {} as {} to {}", url, name, server.addr);
+        let (size, file_progress) = (&OnceLock::new(), &OnceLock::new());
+        let (session, progress) = (&session, &progress);
+        let (leaf, hash) = server
+            .run(&format!("upload of {}", name), move |client| async move {
+                let response = http::get(url, ca).await?;
+                let length = response.content_length()?.ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "{} does not give the size of {}, which must be \
+                         known to upload it",
+                        url,
+                        name
+                    )
+                })?;
+                if *size.get_or_init(|| length) != length {
+                    return Err(anyhow::anyhow!(
+                        "The size of {} changed while it was uploaded",
+                        url
+                    ));
+                }
+                let file_progress =
+                    file_progress.get_or_init(|| progress.file(name, length));
+                client
+                    .put_stream(
+                        session,
+                        index,
+                        response.into_body(),
+                        length,
+                        file_progress,
+                    )
+                    .await
+            })
+            .await?;
+        if hash != hex::encode(leaf) {
+            return Err(anyhow::anyhow!(
+                "Server hash of {} does not match local hash",
+                name
+            ));
+        }
+        leaves.push(leaf);
+        sizes.push(*size.get().expect("the size is known once sent"));
+    }
+    let data_size = sizes.iter().sum();
+    let tree = MerkleTree::from_leaves(leaves)?;
+    let root_hash = tree
+        .root()
+        .map(hex::encode)
+        .ok_or(anyhow::anyhow!("Root Hash could not be computed"))?;
+    let files = urls
+        .iter()
+        .zip(names.iter().cloned())
+        .zip(sizes)
+        .zip(tree.leaves())
+        .enumerate()
+        .map(|(index, (((url, name), size), leaf))| FileRecord {
+            name,
+            size: Some(size),
+            sha256: Some(hex::encode(leaf)),
+            proof: tree
+                .proof(index)
+                .ok()
+                .map(|proof| proof.iter().map(hex::encode).collect()),
+            source: Some(url.to_string()),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let mut report = UploadReport {
+        root_hash: root_hash.clone(),
+        server_addr: server.addr.clone(),
+        size: data_size,
+        files: files.clone(),
+        verified: !options.dry_run,
+        proofs_verified: options.verify && !options.dry_run,
+        deleted: false,
+        dry_run: options.dry_run,
+        deduplicated: false,
+        signature: None,
+    };
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    let hashes = tree.leaves().iter().map(hex::encode).collect::<Vec<_>>();
+    let committed = server.commit(&hashes).await?;
+    if committed.root_hash != root_hash {
+        return Err(anyhow::anyhow!(
+            "Server root hash {} does not match local root hash {}",
+            committed.root_hash,
+            root_hash
+        ));
+    }
+    if options.verify {
+        verify_upload(&tree, &names, server, None).await?;
+    }
+    if let Some(retention) = options.retention {
+        retain(&root_hash, retention, server).await?;
+    }
+    send_names(&root_hash, &names, server).await;
+    progress.finish();
+
+    match committed.already_stored {
+        true => log::info!("The server already held {}", root_hash),
+        false => {
+            log::info!("The server stored the files as root hash {}", root_hash)
+        }
+    }
+    report.deduplicated = committed.already_stored;
+    report.signature = committed.signature.clone();
+    db.persist(
+        &root_hash,
+        Upload {
+            files,
+            uploaded_at: utils::unix_time(SystemTime::now()),
+            server_addr: Some(server.addr.clone()),
+            tags: options.tags.to_vec(),
+            signature: committed.signature,
+        },
+    )?;
+    Ok(report)
+}
+
+/// Streams and hashes the resource of a URL, for a dry run.
+///
+/// # Returns
+///
+/// Returns the SHA-256 of the resource, and its size.
+async fn hash_url(
+    url: &str,
+    ca: Option<&Path>,
+) -> Result<([u8; 32], u64), anyhow::Error> {
+    let mut body = http::get(url, ca).await?.into_body();
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0; progress::CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        match body.read(&mut chunk).await? {
+            0 => return Ok((hasher.finalize().into(), size)),
+            len => {
+                hasher.update(&chunk[..len]);
+                size += len as u64;
+            }
+        }
+    }
+}
+
+/// Where and how downloaded files are written.
+struct DownloadOptions<'a> {
+    /// The file or directory to download to, the default depending on the
+    /// command.
+    out: Option<PathBuf>,
+    /// Whether to replace the files that already exist.
+    overwrite: bool,
+    /// The key to decrypt the files with, if any.
+    key: Option<&'a Key>,
+    /// The root hashes the files must chain to, if any.
+    anchors: Option<&'a Anchors>,
+    /// Whether to hide the progress bars.
+    hide_progress: bool,
+    /// The number of ranges of a large file downloaded concurrently.
+    jobs: usize,
+    /// The cache the proofs of the files are taken from and kept in.
+    cache: &'a ProofCache,
+}
+
+/// Downloads files of an upload, one after the other, once every file is
+/// found and none of them would be replaced unless `options.overwrite` is
+/// set. A name given several times is downloaded once.
+///
+/// The files of an upload that is not recorded in the database are
+/// downloaded by name, with the names the server recorded.
+async fn download(
+    root_hash: &str,
+    filenames: &[String],
+    server: &Server,
+    options: &DownloadOptions<'_>,
+    db: &mut Db,
+) -> Result<DownloadReport, anyhow::Error> {
+    if let Some(anchors) = options.anchors {
+        anchors.check(root_hash)?;
+    }
+    if filenames.is_empty() {
+        return Err(anyhow::anyhow!("No files to download"));
+    }
+    let Some(upload) = db.get_upload(root_hash).cloned() else {
+        let store_dir = db.get_db_path();
+        return download_named(
+            root_hash, filenames, server, options, store_dir,
+        )
+        .await;
+    };
+    // Get the indices of the files
+    let mut indices = vec![];
+    for filename in filenames {
+        let index =
+            db.get_index(root_hash, filename).ok_or(anyhow::anyhow!(
+                "File {} not found in root hash {}",
+                filename,
+                root_hash
+            ))?;
+        if !indices.contains(&index) {
+            indices.push(index);
+        }
+    }
+    warn_server_mismatch(&upload, &server.addr);
+
+    // Download to the current directory by default, under the path the file
+    // was uploaded with, several files always being downloaded to a
+    // directory
+    let out = options.out.clone().unwrap_or_else(|| PathBuf::from("."));
+    let to_dir = indices.len() > 1
+        || out.is_dir()
+        || out.to_string_lossy().ends_with('/');
+    let paths = indices
+        .iter()
+        .map(|&index| match to_dir {
+            true => Ok(out.join(upload.files[index].relative_path()?)),
+            false => Ok(out.clone()),
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    // Check every file before downloading any of them
+    check_outside_store(&paths, db.get_db_path())?;
+    if let Some(path) = paths
+        .iter()
+        .find(|path| path.exists() && !options.overwrite)
+    {
+        return Err(already_exists(path));
+    }
+
+    // Get the files from the server over a single connection, each once its
+    // proof is verified, recording the proof of each file once it is
+    // written, so that the files written before a failure keep theirs
+    let size = indices.iter().map(|&index| upload.files[index].size).sum();
+    let progress = Progress::new(size, options.hide_progress);
+    let connection = Connection::default();
+    let mut files = vec![];
+    for (&index, path) in indices.iter().zip(paths) {
+        let record = &upload.files[index];
+        let (file, proof) = fetch_file(
+            root_hash,
+            index,
+            record,
+            server,
+            &connection,
+            options,
+            &progress,
+        )
+        .await?;
+        write_file(&path, &file, options.overwrite)?;
+        db.record_proofs(root_hash, vec![(index, proof)])?;
+        files.push(downloaded(&record.name, path, &file));
+    }
+    progress.finish();
+
+    Ok(DownloadReport {
+        root_hash: root_hash.to_string(),
+        server_addr: server.addr.clone(),
+        dir: download_dir(&files, out),
+        files,
+    })
+}
+
+/// Downloads files of an upload that is not recorded in the database, e.g.
+/// once the database is lost, by the names the server recorded with the
+/// upload. Without the records of the upload, the files are written as they
+/// were uploaded, compressed if they were.
+async fn download_named(
+    root_hash: &str,
+    filenames: &[String],
+    server: &Server,
+    options: &DownloadOptions<'_>,
+    store_dir: &Path,
+) -> Result<DownloadReport, anyhow::Error> {
+    log::info!(
+        "Root hash {} is not recorded, downloading the files by name",
+        root_hash
+    );
+    let mut names = vec![];
+    for filename in filenames {
+        if !names.contains(filename) {
+            names.push(filename.clone());
+        }
+    }
+    let out = options.out.clone().unwrap_or_else(|| PathBuf::from("."));
+    let to_dir =
+        names.len() > 1 || out.is_dir() || out.to_string_lossy().ends_with('/');
+    let paths = names
+        .iter()
+        .map(|name| match to_dir {
+            true => Ok(out.join(utils::safe_relative_path(Path::new(name))?)),
+            false => Ok(out.clone()),
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    check_outside_store(&paths, store_dir)?;
+    if let Some(path) = paths
+        .iter()
+        .find(|path| path.exists() && !options.overwrite)
+    {
+        return Err(already_exists(path));
+    }
+
+    let progress = Progress::new(None, options.hide_progress);
+    let connection = Connection::default();
+    let mut files = vec![];
+    for (name, path) in names.iter().zip(paths) {
+        log::info!("Downloading {} of {}", name, root_hash);
+        let operation = format!("download of {}", name);
+        let (index, file, proof) = server
+            .run_over(&operation, &connection, |mut client| async {
+                let file =
+                    client.get_named_file(root_hash, name, &progress).await?;
+                Ok((file, client))
+            })
+            .await?;
+        options.cache.put(&Proof::new(
+            index,
+            Sha256::digest(&file).into(),
+            utils::decode_hash(root_hash)?,
+            proof,
+        ));
+        write_file(&path, &file, options.overwrite)?;
+        files.push(downloaded(name, path, &file));
+    }
+    progress.finish();
+
+    Ok(DownloadReport {
+        root_hash: root_hash.to_string(),
+        server_addr: server.addr.clone(),
+        dir: download_dir(&files, out),
+        files,
+    })
+}
+
+/// Returns the directory files were downloaded to: the directory of the file
+/// if there is a single one, or `out`.
+fn download_dir(files: &[DownloadedFile], out: PathBuf) -> PathBuf {
+    match files {
+        [file] => file
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map_or_else(|| PathBuf::from("."), Path::to_path_buf),
+        _ => out,
+    }
 }
 
-fn download(
+/// Reads the names of files listed one per line in a file, or stdin if it
+/// is `-`, ignoring blank lines. Names that are not valid UTF-8 are given
+/// escaped, as they are listed.
+fn read_names(list: &Path) -> Result<Vec<String>, anyhow::Error> {
+    let content = match list == Path::new("-") {
+        true => std::io::read_to_string(std::io::stdin())?,
+        false => fs::read_to_string(list).map_err(|e| {
+            anyhow::anyhow!("Could not read {}: {}", list.display(), e)
+        })?,
+    };
+    Ok(content
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+async fn download_all(
+    root_hash: &str,
+    server: &Server,
+    options: &DownloadOptions<'_>,
+    db: &mut Db,
+) -> Result<DownloadReport, anyhow::Error> {
+    if let Some(anchors) = options.anchors {
+        anchors.check(root_hash)?;
+    }
+    let upload = db
+        .get_upload(root_hash)
+        .cloned()
+        .ok_or(anyhow::anyhow!("Root hash {} not found", root_hash))?;
+    warn_server_mismatch(&upload, &server.addr);
+    // Download to the current directory by default, as `download` does
+    let out = options.out.clone().unwrap_or_else(|| PathBuf::from("."));
+    let paths = upload
+        .files
+        .iter()
+        .map(|record| Ok(out.join(record.relative_path()?)))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    check_outside_store(&paths, db.get_db_path())?;
+
+    // Keep the state of the batch as it goes, so that an interrupted
+    // download resumes where it stopped. A file an earlier run wrote is kept
+    // if it is unchanged since
+    let key = format!(
+        "download {} {}",
+        root_hash,
+        std::path::absolute(&out)?.display()
+    );
+    let dir = db.get_db_path().join("batches");
+    let batch = Batch::open(&dir, &key, paths.len(), String::new)?;
+    let written = paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| {
+            let file = batch.file(index);
+            file.transferred
+                && utils::sha256_file(path).ok().map(hex::encode) == file.sha256
+        })
+        .collect::<Vec<_>>();
+    // Check every file before downloading any of them
+    if let Some((path, _)) =
+        paths.iter().zip(&written).find(|(path, written)| {
+            !*written && path.exists() && !options.overwrite
+        })
+    {
+        return Err(already_exists(path));
+    }
+
+    // Every file is fetched over the same connection, and its proof
+    // verified, then recorded once the file is written
+    let resumed = upload
+        .files
+        .iter()
+        .zip(&written)
+        .filter(|(_, written)| **written)
+        .map(|(record, _)| record.size)
+        .sum::<Option<u64>>();
+    let progress = Progress::new(
+        upload
+            .size()
+            .zip(resumed)
+            .map(|(size, resumed)| size - resumed),
+        options.hide_progress,
+    );
+    let connection = Connection::default();
+    let mut files = vec![];
+    for (index, (record, path)) in upload.files.iter().zip(paths).enumerate() {
+        if written[index] {
+            let file = batch.file(index);
+            files.push(DownloadedFile {
+                name: record.name.clone(),
+                size: fs::metadata(&path)?.len(),
+                path,
+                sha256: file.sha256.clone().unwrap_or_default(),
+                verified: true,
+            });
+            db.record_proofs(root_hash, vec![(index, file.proof.clone())])?;
+            continue;
+        }
+        let (file, proof) = fetch_file(
+            root_hash,
+            index,
+            record,
+            server,
+            &connection,
+            options,
+            &progress,
+        )
+        .await?;
+        write_file(&path, &file, options.overwrite)?;
+        // The proof is verified before the file is written
+        batch.transferred(index, &utils::sha256(&file), &proof)?;
+        db.record_proofs(root_hash, vec![(index, proof)])?;
+        files.push(downloaded(&record.name, path, &file));
+    }
+    progress.finish();
+    batch.finish()?;
+
+    Ok(DownloadReport {
+        root_hash: root_hash.to_string(),
+        server_addr: server.addr.clone(),
+        dir: out,
+        files,
+    })
+}
+
+/// Downloads a file and writes it to stdout, once its proof is verified.
+async fn cat(
     root_hash: &str,
     filename: &str,
-    server_addr: &str,
-    db: &Db,
+    server: &Server,
+    options: &DownloadOptions<'_>,
+    db: &mut Db,
 ) -> Result<(), anyhow::Error> {
-    // Get the index of the file
+    if let Some(anchors) = options.anchors {
+        anchors.check(root_hash)?;
+    }
+    let index = db.get_index(root_hash, filename).ok_or(anyhow::anyhow!(
+        "File {} not found in root hash {}",
+        filename,
+        root_hash
+    ))?;
+    let upload = db
+        .get_upload(root_hash)
+        .cloned()
+        .ok_or(anyhow::anyhow!("Root hash {} not found", root_hash))?;
+    warn_server_mismatch(&upload, &server.addr);
+
+    // The progress bars go to stderr, leaving stdout to the file
+    let progress =
+        Progress::new(upload.files[index].size, options.hide_progress);
+    let (file, proof) = fetch_file(
+        root_hash,
+        index,
+        &upload.files[index],
+        server,
+        &Connection::default(),
+        options,
+        &progress,
+    )
+    .await?;
+    progress.finish();
+
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&file)?;
+    stdout.flush()?;
+    db.record_proofs(root_hash, vec![(index, proof)])?;
+    Ok(())
+}
+
+/// Downloads a file and verifies its proof, then checks it against its
+/// record and decodes it. The file is downloaded over `connection`, reused
+/// by the downloads of the other files of the same command.
+///
+/// A file larger than [`RANGE_SIZE`] whose size is recorded is downloaded
+/// in ranges, `options.jobs` at a time, if it is more than one. The verified
+/// proof of the file is kept in the proof cache.
+///
+/// # Returns
+///
+/// Returns the decoded file, and its proof as hex strings.
+async fn fetch_file(
+    root_hash: &str,
+    index: usize,
+    record: &FileRecord,
+    server: &Server,
+    connection: &Connection,
+    options: &DownloadOptions<'_>,
+    progress: &Progress,
+) -> Result<(Vec<u8>, Vec<String>), anyhow::Error> {
+    log::info!("Downloading {} of {}", record.name, root_hash);
+    let (file, proof) = match record.size {
+        // The HTTP API serves no ranges
+        Some(size)
+            if options.jobs > 1
+                && size > RANGE_SIZE
+                && server.http.is_none() =>
+        {
+            fetch_ranges(
+                root_hash,
+                index,
+                &record.name,
+                size,
+                server,
+                options,
+                progress,
+            )
+            .await?
+        }
+        _ => {
+            let operation = format!("download of {}", record.name);
+            server
+                .run_over(&operation, connection, |mut client| async {
+                    let name = &record.name;
+                    let file = client
+                        .get_file(root_hash, index, name, progress)
+                        .await?;
+                    Ok((file, client))
+                })
+                .await?
+        }
+    };
+    check_file(record, &file)?;
+    let hashes = proof.iter().map(hex::encode).collect();
+    options.cache.put(&Proof::new(
+        index,
+        Sha256::digest(&file).into(),
+        utils::decode_hash(root_hash)?,
+        proof,
+    ));
+    let file = encoding::decode(record, file, options.key)?;
+    Ok((file, hashes))
+}
+
+/// Downloads a file in ranges of [`RANGE_SIZE`] bytes, `options.jobs` at a
+/// time, each over its own connection, then verifies the reassembled file
+/// against its proof, fetched on its own unless it is cached.
+///
+/// A range failing with a transient error is downloaded again on its own,
+/// the other ranges being kept.
+///
+/// # Returns
+///
+/// Returns the file and its Merkle proof, once the proof is verified.
+async fn fetch_ranges(
+    root_hash: &str,
+    index: usize,
+    name: &str,
+    size: u64,
+    server: &Server,
+    options: &DownloadOptions<'_>,
+    progress: &Progress,
+) -> Result<(Vec<u8>, Vec<[u8; 32]>), anyhow::Error> {
+    let proof = match options.cache.get(root_hash, index) {
+        Some(proof) => proof,
+        None => {
+            server
+                .run(&format!("proof of {}", name), |client| {
+                    client.get_proof(root_hash, index)
+                })
+                .await?
+        }
+    };
+
+    let ranges = Arc::new(
+        (0..size)
+            .step_by(RANGE_SIZE as usize)
+            .map(|offset| (offset, RANGE_SIZE.min(size - offset)))
+            .collect::<Vec<_>>(),
+    );
+    let next = Arc::new(AtomicUsize::new(0));
+    let parts = Arc::new(Mutex::new(vec![vec![]; ranges.len()]));
+    let file_progress = Arc::new(progress.file(name, size));
+
+    // Each worker takes the next range until there are none left, like the
+    // workers sending the files of an upload
+    let mut workers = JoinSet::new();
+    for _ in 0..options.jobs.min(ranges.len()) {
+        let (ranges, next, parts) =
+            (ranges.clone(), next.clone(), parts.clone());
+        let (server, file_progress) = (server.clone(), file_progress.clone());
+        let (root_hash, name) = (root_hash.to_string(), name.to_string());
+        workers.spawn(async move {
+            loop {
+                let range = next.fetch_add(1, Ordering::Relaxed);
+                let Some(&(offset, len)) = ranges.get(range) else {
+                    return Ok::<_, anyhow::Error>(());
+                };
+                let part = server
+                    .run(
+                        &format!("download of {} at {}", name, offset),
+                        |client| {
+                            client.get_range(&root_hash, index, offset, len)
+                        },
+                    )
+                    .await?;
+                file_progress.inc(len);
+                parts.lock().unwrap()[range] = part;
+            }
+        });
+    }
+    while let Some(result) = workers.join_next().await {
+        result.expect("download worker panicked")?;
+    }
+    if let Some(file_progress) = Arc::into_inner(file_progress) {
+        file_progress.finish();
+    }
+
+    let file = parts.lock().unwrap().concat();
+    if !MerkleTree::verify(index, &file, &proof.root, &proof.hashes) {
+        return Err(anyhow::anyhow!("Invalid proof"));
+    }
+    Ok((file, proof.hashes))
+}
+
+/// Deletes an upload from the server, then removes it from the database and
+/// its proofs from the proof cache.
+///
+/// An upload the server does not hold, e.g. deleted by another client, is
+/// still removed from the database, and an upload the database does not
+/// record, e.g. made from another machine, still deleted from the server.
+async fn delete(
+    root_hash: &str,
+    server: &Server,
+    cache: &ProofCache,
+    db: &mut Db,
+) -> Result<DeleteReport, anyhow::Error> {
+    utils::decode_hash(root_hash)
+        .map_err(|_| anyhow::anyhow!("Invalid root hash {}", root_hash))?;
+
+    // Deleting again after a lost acknowledgment is harmless, the server
+    // only reporting the upload as missing
+    let remote = server
+        .run(&format!("deletion of {}", root_hash), |client| {
+            client.delete(root_hash)
+        })
+        .await?;
+    match remote {
+        true => log::info!("Deleted {} from the server", root_hash),
+        false => log::info!("The server does not hold {}", root_hash),
+    }
+    let local = db.remove(root_hash)?;
+    cache.invalidate(root_hash)?;
+    if !remote && !local {
+        return Err(anyhow::anyhow!("Root hash {} not found", root_hash));
+    }
+
+    Ok(DeleteReport {
+        root_hash: root_hash.to_string(),
+        server_addr: server.addr.clone(),
+        remote,
+        local,
+    })
+}
+
+/// Gets the proof of an uploaded file from the proof cache, or from the
+/// server, and saves it in the serialization format of
+/// [`merkle_tree::Proof`].
+async fn prove(
+    root_hash: &str,
+    filename: &str,
+    server: &Server,
+    out: Option<PathBuf>,
+    cache: &ProofCache,
+    db: &mut Db,
+) -> Result<ProveReport, anyhow::Error> {
     let index = db.get_index(root_hash, filename).ok_or(anyhow::anyhow!(
         "File {} not found in root hash {}",
         filename,
         root_hash
     ))?;
+    let record = db
+        .get_upload(root_hash)
+        .map(|upload| upload.files[index].clone())
+        .ok_or(anyhow::anyhow!("Root hash {} not found", root_hash))?;
+
+    let cached = cache.get(root_hash, index);
+    let is_cached = cached.is_some();
+    let proof = match cached {
+        Some(proof) => proof,
+        None => {
+            server
+                .run(&format!("proof of {}", filename), |client| {
+                    client.get_proof(root_hash, index)
+                })
+                .await?
+        }
+    };
+    if record
+        .sha256
+        .as_ref()
+        .is_some_and(|sha256| *sha256 != hex::encode(proof.leaf))
+    {
+        return Err(anyhow::anyhow!(
+            "Server leaf of {} does not match the recorded SHA-256",
+            filename
+        ));
+    }
+
+    let path = out.unwrap_or_else(|| {
+        let name = filename.rsplit('/').next().unwrap_or(filename);
+        PathBuf::from(format!("{}.proof.json", name))
+    });
+    fs::write(&path, serde_json::to_string_pretty(&proof)?)?;
+    cache.put(&proof);
+    db.record_proofs(
+        root_hash,
+        vec![(index, proof.hashes.iter().map(hex::encode).collect())],
+    )?;
+
+    Ok(ProveReport {
+        root_hash: root_hash.to_string(),
+        name: filename.to_string(),
+        path,
+        leaf: hex::encode(proof.leaf),
+        verified: true,
+        cached: is_cached,
+    })
+}
+
+/// Returns the manifest entries of the files of an upload.
+///
+/// # Errors
+///
+/// Returns an error if the upload is not recorded, if its files were encoded
+/// before they were uploaded, the recorded SHA-256 being those of the
+/// encoded files, or if the SHA-256 of a file is not recorded.
+fn manifest_entries(
+    root_hash: &str,
+    db: &Db,
+) -> Result<Vec<manifest::Entry>, anyhow::Error> {
+    let upload = db
+        .get_upload(root_hash)
+        .ok_or(anyhow::anyhow!("Root hash {} not found", root_hash))?;
+    upload
+        .files
+        .iter()
+        .map(|record| {
+            if record.encrypted || record.compressed {
+                return Err(anyhow::anyhow!(
+                    "File {} was encoded before it was uploaded, its SHA-256 \
+                     is not that of the file",
+                    record.name
+                ));
+            }
+            Ok(manifest::Entry {
+                sha256: record.sha256.clone().ok_or(anyhow::anyhow!(
+                    "No SHA-256 is recorded for {}",
+                    record.name
+                ))?,
+                name: record.raw_name(),
+            })
+        })
+        .collect()
+}
+
+/// Checks local files against a manifest, the names of the manifest being
+/// relative to `dir`, or to the current directory.
+fn check_manifest(
+    manifest: PathBuf,
+    dir: Option<PathBuf>,
+) -> Result<ManifestCheckReport, anyhow::Error> {
+    let content = fs::read(&manifest).map_err(|e| {
+        anyhow::anyhow!("Could not read manifest {}: {}", manifest.display(), e)
+    })?;
+    let dir = dir.unwrap_or_else(|| PathBuf::from("."));
+    let files = manifest::parse(&content)?
+        .into_iter()
+        .map(|entry| {
+            let path = dir.join(utils::path_from_bytes(&entry.name));
+            let status = match utils::sha256_file(&path) {
+                Ok(sha256) if hex::encode(sha256) == entry.sha256 => {
+                    CheckStatus::Ok
+                }
+                Ok(_) => CheckStatus::Failed,
+                Err(_) => CheckStatus::Missing,
+            };
+            CheckedFile {
+                name: utils::escape_bytes(&entry.name),
+                path,
+                status,
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(ManifestCheckReport {
+        manifest,
+        verified: files.iter().all(|file| file.status == CheckStatus::Ok),
+        files,
+    })
+}
+
+/// Describes what an upload commits to, from the database: its tree,
+/// recomputed from the recorded leaves, and its files.
+fn info(root_hash: &str, db: &Db) -> Result<InfoReport, anyhow::Error> {
+    let upload = db
+        .get_upload(root_hash)
+        .ok_or(anyhow::anyhow!("Root hash {} not found", root_hash))?;
+    let leaves = upload
+        .files
+        .iter()
+        .map(|record| record.sha256.as_deref().map(utils::decode_hash))
+        .collect::<Option<Result<Vec<_>, _>>>()
+        .transpose()?;
+    let tree = leaves.map(MerkleTree::from_leaves).transpose()?;
+    let depth = tree.as_ref().map(MerkleTree::depth);
+    let files = upload
+        .files
+        .iter()
+        .enumerate()
+        .map(|(index, record)| InfoFile {
+            index,
+            name: record.name.clone(),
+            size: record.size,
+            leaf: record.sha256.clone(),
+            proof_recorded: record.proof.is_some(),
+            encrypted: record.encrypted,
+            compressed: record.compressed,
+            server_leaf: None,
+        })
+        .collect();
+    Ok(InfoReport {
+        root_hash: root_hash.to_string(),
+        uploaded_at: upload.uploaded_at,
+        server_addr: upload.server_addr.clone(),
+        tags: upload.tags.clone(),
+        leaves: upload.files.len(),
+        depth,
+        proof_size: depth.map(|depth| depth * 32),
+        size: upload.size(),
+        consistent: tree.map(|tree| {
+            tree.root().map(hex::encode).as_deref() == Some(root_hash)
+        }),
+        files,
+        remote: None,
+    })
+}
+
+/// Adds to the description of an upload what the server holds: the number
+/// and size of its files, and the leaf of every file, from its proof.
+async fn remote_info(
+    report: &mut InfoReport,
+    server: &Server,
+) -> Result<(), anyhow::Error> {
+    let stored = server
+        .run("listing", |client| client.list_uploads())
+        .await?
+        .into_iter()
+        .find(|stored| stored.root_hash == report.root_hash);
+    if stored.is_some() {
+        log::info!("Getting the proofs of the {} files", report.files.len());
+        for file in &mut report.files {
+            let proof = server
+                .run(&format!("proof of {}", file.name), |client| {
+                    client.get_proof(&report.root_hash, file.index)
+                })
+                .await?;
+            file.server_leaf = Some(hex::encode(proof.leaf));
+        }
+    }
+    report.remote = Some(RemoteInfo {
+        server_addr: server.addr.clone(),
+        held: stored.is_some(),
+        files: stored.as_ref().map(|stored| stored.files),
+        size: stored.as_ref().map(|stored| stored.size),
+    });
+    Ok(())
+}
+
+/// Verifies a local file against the root hash of its upload with the proof
+/// recorded in the database, or else cached, without contacting the server.
+fn verify(
+    path: PathBuf,
+    root_hash: &str,
+    name: Option<&str>,
+    anchors: Option<&Anchors>,
+    cache: &ProofCache,
+    db: &Db,
+) -> Result<VerifyReport, anyhow::Error> {
+    if let Some(anchors) = anchors {
+        anchors.check(root_hash)?;
+    }
+    let upload = db
+        .get_upload(root_hash)
+        .ok_or(anyhow::anyhow!("Root hash {} not found", root_hash))?;
+    let index = match name {
+        Some(name) => db.get_index(root_hash, name),
+        // The file whose name matches the most components of the end of
+        // the path, e.g. `sub/file.txt` for `downloads/sub/file.txt`
+        None => upload
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| path.ends_with(&file.name))
+            .max_by_key(|(_, file)| file.name.len())
+            .map(|(index, _)| index),
+    }
+    .ok_or(anyhow::anyhow!(
+        "File {} not found in root hash {}",
+        name.map_or_else(|| path.display().to_string(), str::to_string),
+        root_hash
+    ))?;
+    let record = &upload.files[index];
+    if record.encrypted || record.compressed {
+        return Err(anyhow::anyhow!(
+            "File {} was encoded before it was uploaded, its proof does not \
+             cover the downloaded file",
+            record.name
+        ));
+    }
+    let proof = match &record.proof {
+        Some(proof) => proof
+            .iter()
+            .map(|hash| utils::decode_hash(hash))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => {
+            cache
+                .get(root_hash, index)
+                .ok_or(anyhow::anyhow!(
+                    "No proof of {} is recorded, download it once to record \
+                     its proof",
+                    record.name
+                ))?
+                .hashes
+        }
+    };
+
+    let leaf = utils::sha256_file(&path)?;
+    if !MerkleTree::verify_leaf(
+        index,
+        &leaf,
+        &utils::decode_hash(root_hash)?,
+        &proof,
+    ) {
+        return Err(anyhow::anyhow!(
+            "File {} does not match root hash {}",
+            path.display(),
+            root_hash
+        ));
+    }
+
+    Ok(VerifyReport {
+        root_hash: root_hash.to_string(),
+        name: record.name.clone(),
+        path,
+        sha256: hex::encode(leaf),
+        verified: true,
+    })
+}
+
+/// Streams, hashes and sends the files of an upload over concurrent
+/// connections, then commits them as a single upload.
+///
+/// The files are sent in an upload session, and a file whose transfer fails
+/// is resumed over a new connection from the bytes the server received. The
+/// files of a resumed batch that an earlier run sent are not sent again, nor
+/// those whose content the server holds, unless the server no longer holds
+/// them once the batch is committed.
+///
+/// # Arguments
+///
+/// * `files` - The paths, names and sizes of the files.
+/// * `held` - The SHA-256 of the files whose content the server holds, by
+///   index, as told by [`held_contents`].
+/// * `server` - The server to send the files to.
+/// * `jobs` - The number of files sent concurrently.
+/// * `progress` - The progress bars of the upload.
+/// * `batch` - The state of the batch, recording the files sent.
+///
+/// # Returns
+///
+/// Returns the SHA-256 of the files, and the upload committed by the server.
+async fn send_files(
+    files: Vec<((PathBuf, String), u64)>,
+    held: &[Option<[u8; 32]>],
+    server: &Server,
+    jobs: usize,
+    progress: &Progress,
+    batch: Option<Arc<Batch>>,
+) -> Result<(Vec<[u8; 32]>, Committed), anyhow::Error> {
+    let mut leaves = vec![[0; 32]; files.len()];
+    let mut resumed = vec![];
+    let mut pending = vec![];
+    for (index, leaf) in leaves.iter_mut().enumerate() {
+        let sent = batch
+            .as_ref()
+            .map(|batch| batch.file(index))
+            .filter(|file| file.transferred)
+            .and_then(|file| file.sha256.as_deref());
+        match (sent, held.get(index).copied().flatten()) {
+            (Some(sha256), _) => {
+                *leaf = utils::decode_hash(sha256)?;
+                resumed.push(index);
+            }
+            (None, Some(held)) => {
+                *leaf = held;
+                resumed.push(index);
+            }
+            (None, None) => pending.push(index),
+        }
+    }
+    let sending = Arc::new(Sending {
+        files,
+        leaves: Mutex::new(leaves),
+        session: batch.as_ref().map_or_else(
+            || new_session(server),
+            |batch| batch.session().into(),
+        ),
+        server: server.clone(),
+        progress: progress.clone(),
+        batch,
+    });
+
+    loop {
+        send_pending(&sending, pending, jobs).await?;
+
+        // Committing the same files again returns the same upload
+        let leaves = sending.leaves.lock().unwrap().clone();
+        let hashes = leaves.iter().map(hex::encode).collect::<Vec<_>>();
+        let committed = server.commit(&hashes).await;
+        match committed {
+            // The files staged by an earlier run may have been committed
+            // since, by another upload of the same content, and those the
+            // server held deleted since
+            Err(e)
+                if !resumed.is_empty()
+                    && client::server_error(&e).is_some_and(|error| {
+                        error.kind == client::ServerErrorKind::NotFound
+                    }) =>
+            {
+                log::warn!(
+                    "The server no longer holds the files not sent, sending \
+                     them again"
+                );
+                pending = std::mem::take(&mut resumed);
+            }
+            committed => return Ok((leaves, committed?)),
+        }
+    }
+}
+
+/// Sends the names of the files of an upload to the server, so that they can
+/// be downloaded by name without the database. The upload is complete
+/// without them, so a server that does not record them, e.g. an older one,
+/// is only warned about.
+async fn send_names(root_hash: &str, names: &[String], server: &Server) {
+    if server.http.is_some() {
+        log::debug!("The HTTP API does not record the names of the files");
+        return;
+    }
+    let sent = server
+        .run("names of the files", |client| {
+            client.put_names(root_hash, names)
+        })
+        .await;
+    if let Err(e) = sent {
+        log::warn!("The server did not record the names of the files: {}", e);
+    }
+}
+
+/// Asks the server to delete an upload once its retention is over. Unlike
+/// the names, the retention is part of what was asked for, so the upload
+/// fails if the server does not record it.
+async fn retain(
+    root_hash: &str,
+    retention: Duration,
+    server: &Server,
+) -> Result<(), anyhow::Error> {
+    server
+        .run("retention of the upload", |client| {
+            client.retain(root_hash, retention)
+        })
+        .await
+}
+
+/// The files of an upload being sent, shared by the workers sending them.
+struct Sending {
+    /// The paths, names and sizes of the files.
+    files: Vec<((PathBuf, String), u64)>,
+    /// The SHA-256 of the files, as they are sent.
+    leaves: Mutex<Vec<[u8; 32]>>,
+    session: String,
+    server: Server,
+    progress: Progress,
+    /// The state of the batch, recording the files sent.
+    batch: Option<Arc<Batch>>,
+}
+
+/// Streams, hashes and sends the files of an upload at the given indices,
+/// over `jobs` concurrent connections.
+async fn send_pending(
+    sending: &Arc<Sending>,
+    pending: Vec<usize>,
+    jobs: usize,
+) -> Result<(), anyhow::Error> {
+    let pending = Arc::new(pending);
+    let next = Arc::new(AtomicUsize::new(0));
+
+    // Each worker takes the next file until there are none left, the files
+    // being hashed and sent concurrently on the runtime threads. The other
+    // workers are stopped as soon as one fails
+    let mut workers = JoinSet::new();
+    for _ in 0..jobs.min(pending.len()) {
+        let (sending, pending, next) =
+            (sending.clone(), pending.clone(), next.clone());
+        workers.spawn(async move {
+            loop {
+                let next = next.fetch_add(1, Ordering::Relaxed);
+                let Some(&index) = pending.get(next) else {
+                    return Ok::<_, anyhow::Error>(());
+                };
+                let ((path, name), size) = &sending.files[index];
+                // Sending a file again only appends the bytes the server is
+                // missing
+                let file_progress = sending.progress.file(name, *size);
+                let (leaf, hash) = sending
+                    .server
+                    .run(&format!("upload of {}", name), |client| {
+                        client.put_file(
+                            &sending.session,
+                            index,
+                            path,
+                            &file_progress,
+                        )
+                    })
+                    .await?;
+                file_progress.finish();
+                if hash != hex::encode(leaf) {
+                    return Err(anyhow::anyhow!(
+                        "Server hash of {} does not match local hash",
+                        name
+                    ));
+                }
+                sending.leaves.lock().unwrap()[index] = leaf;
+                if let Some(batch) = &sending.batch {
+                    batch.transferred(index, &hash, &[])?;
+                }
+            }
+        });
+    }
+    while let Some(result) = workers.join_next().await {
+        result.expect("upload worker panicked")?;
+    }
+    Ok(())
+}
+
+/// Returns a new upload session, unique to the upload.
+fn new_session(server: &Server) -> String {
+    utils::sha256(
+        format!(
+            "{}-{}-{:?}",
+            server.addr,
+            std::process::id(),
+            SystemTime::now()
+        )
+        .as_bytes(),
+    )
+}
+
+/// Asks the server whether it already holds the batch of the given leaves,
+/// e.g. an unchanged directory uploaded every night, so that it is not sent
+/// again.
+///
+/// A server failing to answer, e.g. one too old to know the question, is
+/// taken not to hold the batch, which is then sent.
+async fn server_holds(
+    leaves: &[[u8; 32]],
+    server: &Server,
+) -> Result<bool, anyhow::Error> {
+    let root_hash = MerkleTree::from_leaves(leaves.to_vec())?
+        .root()
+        .map(hex::encode)
+        .ok_or(anyhow::anyhow!("Root Hash could not be computed"))?;
+    match server
+        .run("lookup of the upload", |client| client.exists(&root_hash))
+        .await
+    {
+        Ok(held) => {
+            if held {
+                log::info!(
+                    "The server already holds {}, not sending the files",
+                    root_hash
+                );
+            }
+            Ok(held)
+        }
+        Err(e) => {
+            log::warn!("Could not look up {}: {:#}", root_hash, e);
+            Ok(false)
+        }
+    }
+}
+
+/// Asks the server which files of a batch it already holds the content of,
+/// so that only the others are sent.
+///
+/// # Returns
+///
+/// Returns the SHA-256 of the files held, by index, or nothing if the server
+/// cannot tell, in which case every file is sent.
+async fn held_contents(
+    leaves: &[[u8; 32]],
+    server: &Server,
+) -> Vec<Option<[u8; 32]>> {
+    let hashes = leaves.iter().map(hex::encode).collect::<Vec<_>>();
+    let held = match server
+        .run("lookup of the files", |client| client.held(&hashes))
+        .await
+    {
+        Ok(held) => held,
+        Err(e) => {
+            log::warn!("Could not look up the files: {:#}", e);
+            return vec![];
+        }
+    };
+    let count = held.iter().filter(|&&held| held).count();
+    if count > 0 {
+        log::info!(
+            "The server already holds {} of the {} files, not sending them",
+            count,
+            leaves.len()
+        );
+    }
+    leaves
+        .iter()
+        .zip(held)
+        .map(|(leaf, held)| held.then_some(*leaf))
+        .collect()
+}
+
+/// Rebuilds the Merkle tree of a recorded upload from the SHA-256 of its
+/// files, along with their names.
+///
+/// # Errors
+///
+/// Returns an error if a file has no SHA-256 recorded, or if the hashes do
+/// not match the root hash.
+fn recorded_tree(
+    root_hash: &str,
+    upload: &Upload,
+) -> Result<(MerkleTree, Vec<String>), anyhow::Error> {
+    let leaves = upload
+        .files
+        .iter()
+        .map(|record| {
+            let sha256 = record.sha256.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("No SHA-256 is recorded for {}", record.name)
+            })?;
+            utils::decode_hash(sha256)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let tree = MerkleTree::from_leaves(leaves)?;
+    if tree.root().map(hex::encode).as_deref() != Some(root_hash) {
+        return Err(anyhow::anyhow!(
+            "The recorded hashes do not match the root hash"
+        ));
+    }
+    let names = upload.files.iter().map(|record| record.name.clone());
+    Ok((tree, names.collect()))
+}
+
+/// Checks that the server serves, for every file of an upload, a proof
+/// linking the hash of the file sent to the root hash computed locally, i.e.
+/// that it stored what was sent. The files of a batch whose proofs an earlier
+/// run verified are skipped, and the others recorded once verified.
+async fn verify_upload(
+    tree: &MerkleTree,
+    names: &[String],
+    server: &Server,
+    batch: Option<&Batch>,
+) -> Result<(), anyhow::Error> {
+    let root = tree
+        .root()
+        .ok_or(anyhow::anyhow!("Root Hash could not be computed"))?;
+    let root_hash = hex::encode(root);
+    log::info!("Verifying the proofs of the {} files", names.len());
+    for (index, (name, leaf)) in names.iter().zip(tree.leaves()).enumerate() {
+        if batch.is_some_and(|batch| batch.file(index).verified) {
+            continue;
+        }
+        let proof = server
+            .run(&format!("proof of {}", name), |client| {
+                client.get_proof(&root_hash, index)
+            })
+            .await?;
+        if proof.leaf != *leaf || proof.root != *root {
+            return Err(anyhow::anyhow!(
+                "Server proof of {} does not match the file sent",
+                name
+            ));
+        }
+        if let Some(batch) = batch {
+            batch.verified(index)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a secret from the keyring, if it holds it. A keyring that cannot
+/// be read, e.g. without its tool, is taken not to hold it, the client
+/// working without a keyring.
+fn from_keyring(secret: Secret) -> Option<String> {
+    keyring::get(secret).unwrap_or_else(|e| {
+        log::debug!("Could not read {} from the keyring: {:#}", secret, e);
+        None
+    })
+}
+
+/// Returns the token to store with `login`: the one given with `--token`,
+/// or read from the terminal without echoing it, or from stdin.
+fn read_token(token: Option<String>) -> Result<String, anyhow::Error> {
+    let token = match token {
+        Some(token) => token,
+        None if std::io::stdin().is_terminal() => {
+            let term = console::Term::stderr();
+            term.write_str("Token: ")?;
+            term.read_secure_line()?
+        }
+        None => {
+            let mut token = String::new();
+            std::io::stdin().read_line(&mut token)?;
+            token
+        }
+    };
+    match token.trim() {
+        "" => Err(anyhow::anyhow!("No token given")),
+        token => Ok(token.to_string()),
+    }
+}
+
+/// Returns the file found by `find` to download: the one numbered `pick`,
+/// the only one found, or the one the user picks on a terminal.
+///
+/// # Errors
+///
+/// Returns an error if no file is found, if `pick` is not the number of one,
+/// or if several are found and none is picked, once they are printed.
+fn pick_file(
+    report: &FindReport,
+    pick: Option<u64>,
+    output: OutputFormat,
+) -> Result<&FoundFile, anyhow::Error> {
+    let files = &report.files;
+    let index = match (pick, files.len()) {
+        (_, 0) => {
+            return Err(anyhow::anyhow!(
+                "No uploaded file matches {}",
+                report.pattern
+            ))
+        }
+        (Some(pick), len) => match usize::try_from(pick) {
+            Ok(pick) if pick <= len => pick - 1,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Only {} files match {}",
+                    len,
+                    report.pattern
+                ))
+            }
+        },
+        (None, 1) => 0,
+        (None, len)
+            if std::io::stdin().is_terminal()
+                && std::io::stderr().is_terminal() =>
+        {
+            let term = console::Term::stderr();
+            for (number, file) in files.iter().enumerate() {
+                term.write_line(&format!(
+                    "  {}. {}",
+                    number + 1,
+                    file.summary()
+                ))?;
+            }
+            loop {
+                term.write_str(&format!("Download which file? [1-{}] ", len))?;
+                match term.read_line()?.trim().parse::<usize>() {
+                    Ok(number) if (1..=len).contains(&number) => {
+                        break number - 1
+                    }
+                    _ => term.write_line("Enter the number of a file")?,
+                }
+            }
+        }
+        (None, len) => {
+            output.print(report)?;
+            return Err(ReportedError(format!(
+                "{} files match {}, pick one with --pick <N>",
+                len, report.pattern
+            ))
+            .into());
+        }
+    };
+    Ok(&files[index])
+}
+
+/// Describes a downloaded file, whose proof has been verified.
+fn downloaded(name: &str, path: PathBuf, data: &[u8]) -> DownloadedFile {
+    DownloadedFile {
+        name: name.to_string(),
+        path,
+        size: data.len() as u64,
+        sha256: utils::sha256(data),
+        verified: true,
+    }
+}
 
-    // Get the file from the server
-    let mut client = client::TcpClient::new(server_addr)?;
-    let file = client.get_file(root_hash, index)?;
+/// Warns if the files are downloaded from another server than the one they
+/// were uploaded to.
+fn warn_server_mismatch(upload: &Upload, server_addr: &str) {
+    if let Some(uploaded_to) = &upload.server_addr {
+        if uploaded_to != server_addr {
+            log::warn!(
+                "The files were uploaded to {}, not {}",
+                uploaded_to,
+                server_addr
+            );
+        }
+    }
+}
+
+/// Checks a downloaded file against the size and SHA-256 recorded when it
+/// was uploaded.
+fn check_file(record: &FileRecord, data: &[u8]) -> Result<(), anyhow::Error> {
+    if record.size.is_some_and(|size| size != data.len() as u64) {
+        return Err(anyhow::anyhow!(
+            "File {} does not have the recorded size",
+            record.name
+        ));
+    }
+    if record
+        .sha256
+        .as_ref()
+        .is_some_and(|sha256| *sha256 != utils::sha256(data))
+    {
+        return Err(anyhow::anyhow!(
+            "File {} does not have the recorded SHA-256",
+            record.name
+        ));
+    }
+    Ok(())
+}
 
-    // write the file
-    fs::write(db.get_db_path().join(filename), file)?;
+/// Writes a downloaded file, creating its parent directories, and only
+/// replacing an existing file when `overwrite` is set.
+fn write_file(
+    path: &Path,
+    data: &[u8],
+    overwrite: bool,
+) -> Result<(), anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!overwrite)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => already_exists(path),
+            _ => e.into(),
+        })?;
+    file.write_all(data)?;
+    Ok(())
+}
 
-    println!("Succesfully downloaded file {} to downloads", filename);
+/// The error of a download that would replace an existing file.
+/// Checks that no file is downloaded into the store directory, where it
+/// could replace the database, the proofs or the state of the batches.
+fn check_outside_store(
+    paths: &[PathBuf],
+    store_dir: &Path,
+) -> Result<(), anyhow::Error> {
+    for path in paths {
+        if utils::is_within(path, store_dir)? {
+            return Err(anyhow::anyhow!(
+                "Refusing to download {} into the store directory {}, pass \
+                 another directory with --out",
+                path.display(),
+                store_dir.display()
+            ));
+        }
+    }
     Ok(())
 }
+
+fn already_exists(path: &Path) -> anyhow::Error {
+    anyhow::anyhow!(
+        "File {} already exists, pass --overwrite to replace it",
+        path.display()
+    )
+}
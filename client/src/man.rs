@@ -0,0 +1,185 @@
+use crate::completions::{commands, options, subcommands};
+use clap::{Arg, Command};
+use std::io::{self, Write};
+
+/// Writes the man page of a command in roff, the format of `man(1)`: its
+/// synopsis and options, then every subcommand with its own.
+///
+/// # Errors
+///
+/// Returns an error if the page cannot be written.
+pub fn render(mut cmd: Command, out: &mut impl Write) -> io::Result<()> {
+    // Propagate the global options to the subcommands
+    cmd.build();
+    let name = cmd.get_name();
+    let version = cmd.get_version().unwrap_or_default();
+    writeln!(
+        out,
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"",
+        name.to_uppercase(),
+        name,
+        version
+    )?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(
+        out,
+        "{} \\- {}",
+        name,
+        escape(&cmd.get_about().map(ToString::to_string).unwrap_or_default())
+    )?;
+    writeln!(out, ".SH SYNOPSIS")?;
+    writeln!(out, "\\fB{}\\fR [\\fIOPTIONS\\fR] \\fICOMMAND\\fR", name)?;
+    if let Some(about) = cmd.get_long_about().or(cmd.get_about()) {
+        writeln!(out, ".SH DESCRIPTION")?;
+        paragraphs(&about.to_string(), out)?;
+    }
+    writeln!(out, ".SH OPTIONS")?;
+    for arg in options(&cmd) {
+        option(arg, out)?;
+    }
+
+    writeln!(out, ".SH COMMANDS")?;
+    for (path, sub) in commands(&cmd).into_iter().skip(1) {
+        // The help subcommands are those of clap, and the commands with
+        // subcommands are described by theirs
+        if path.contains(&"help") || subcommands(sub).next().is_some() {
+            continue;
+        }
+        writeln!(out, ".SS {}", escape(&path[1..].join(" ")))?;
+        let usage = sub.clone().render_usage().to_string();
+        let usage = usage.trim_start_matches("Usage:").trim();
+        writeln!(out, "\\fB{}\\fR", escape(usage))?;
+        writeln!(out, ".PP")?;
+        if let Some(about) = sub.get_long_about().or(sub.get_about()) {
+            paragraphs(&about.to_string(), out)?;
+        }
+        // The global options are described once, above
+        let own = options(sub).filter(|arg| {
+            !arg.is_global_set()
+                && !["help", "version"].contains(&arg.get_id().as_str())
+        });
+        for arg in own {
+            option(arg, out)?;
+        }
+        for arg in sub.get_positionals() {
+            writeln!(out, ".TP")?;
+            writeln!(out, "\\fI{}\\fR", escape(&value_name(arg)))?;
+            help(arg, out)?;
+        }
+    }
+
+    writeln!(out, ".SH VERSION")?;
+    writeln!(out, "v{}", version)?;
+    if let Some(author) = cmd.get_author() {
+        writeln!(out, ".SH AUTHORS")?;
+        writeln!(out, "{}", escape(author))?;
+    }
+    Ok(())
+}
+
+/// Writes an option with its names, its value and its help.
+fn option(arg: &Arg, out: &mut impl Write) -> io::Result<()> {
+    let mut names = vec![];
+    if let Some(short) = arg.get_short() {
+        names.push(format!("\\fB\\-{}\\fR", short));
+    }
+    if let Some(long) = arg.get_long() {
+        names.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+    }
+    writeln!(out, ".TP")?;
+    match arg.get_action().takes_values() {
+        true => writeln!(
+            out,
+            "{} \\fI{}\\fR",
+            names.join(", "),
+            escape(&value_name(arg))
+        )?,
+        false => writeln!(out, "{}", names.join(", "))?,
+    }
+    help(arg, out)
+}
+
+/// Writes the help of an argument, with its default value, possible values
+/// and environment variable.
+fn help(arg: &Arg, out: &mut impl Write) -> io::Result<()> {
+    let mut help = arg
+        .get_long_help()
+        .or(arg.get_help())
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    let defaults = arg
+        .get_default_values()
+        .iter()
+        .map(|value| value.to_string_lossy())
+        .collect::<Vec<_>>();
+    if !defaults.is_empty() && !arg.is_hide_default_value_set() {
+        help.push_str(&format!(" [default: {}]", defaults.join(", ")));
+    }
+    let values = arg
+        .get_possible_values()
+        .into_iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect::<Vec<_>>();
+    if !values.is_empty() && arg.get_action().takes_values() {
+        help.push_str(&format!(" [possible values: {}]", values.join(", ")));
+    }
+    if let Some(env) = arg.get_env() {
+        help.push_str(&format!(" [env: {}]", env.to_string_lossy()));
+    }
+    paragraphs(&help, out)
+}
+
+/// Returns the name of the value of an argument, e.g. `SERVER_ADDR`.
+fn value_name(arg: &Arg) -> String {
+    arg.get_value_names()
+        .and_then(|names| names.first())
+        .map_or_else(
+            || arg.get_id().to_string().to_uppercase(),
+            ToString::to_string,
+        )
+}
+
+/// Writes text, its paragraphs being separated by blank lines.
+fn paragraphs(text: &str, out: &mut impl Write) -> io::Result<()> {
+    for (index, paragraph) in text.trim().split("\n\n").enumerate() {
+        if index > 0 {
+            writeln!(out, ".IP")?;
+        }
+        writeln!(out, "{}", escape(&paragraph.replace('\n', " ")))?;
+    }
+    Ok(())
+}
+
+/// Escapes text for roff: backslashes and hyphens, and periods and quotes
+/// starting a line, which would be taken as requests.
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    match text.starts_with(['.', '\'']) {
+        true => format!("\\&{}", text),
+        false => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Args;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_render() {
+        let mut out = vec![];
+        render(Args::command(), &mut out).unwrap();
+        let page = String::from_utf8(out).unwrap();
+        assert!(page.starts_with(".TH CLIENT 1 "));
+        assert!(page.contains(".SS manifest export\n"));
+        assert!(page.contains("\\fB\\-\\-store\\-dir\\fR \\fIDIR\\fR\n"));
+        // The global options are only described at the top
+        assert_eq!(page.matches("\\fB\\-\\-store\\-dir\\fR").count(), 1);
+        assert!(!page.contains(".SS help"));
+
+        assert_eq!(escape(".hidden"), "\\&.hidden");
+        assert_eq!(escape("a-b\\c"), "a\\-b\\ec");
+    }
+}
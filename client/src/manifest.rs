@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Result};
+
+/// A line of a checksum manifest: the SHA-256 of a file and its name.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    /// The SHA-256 of the file, as a hex string.
+    pub sha256: String,
+    /// The name of the file, as bytes, since names need not be UTF-8.
+    pub name: Vec<u8>,
+}
+
+/// Formats a manifest in the format of `sha256sum`, so that it can be
+/// checked with `sha256sum -c`.
+///
+/// Like `sha256sum`, names holding a backslash or a line break are escaped,
+/// and their line starts with a backslash. Other bytes are written as they
+/// are, whether they are valid UTF-8 or not.
+pub fn format(entries: &[Entry]) -> Vec<u8> {
+    let mut manifest = vec![];
+    for entry in entries {
+        let mut escaped = Vec::with_capacity(entry.name.len());
+        for &byte in &entry.name {
+            match byte {
+                b'\\' => escaped.extend_from_slice(b"\\\\"),
+                b'\n' => escaped.extend_from_slice(b"\\n"),
+                b'\r' => escaped.extend_from_slice(b"\\r"),
+                byte => escaped.push(byte),
+            }
+        }
+        if escaped != entry.name {
+            manifest.push(b'\\');
+        }
+        manifest.extend_from_slice(entry.sha256.as_bytes());
+        manifest.extend_from_slice(b"  ");
+        manifest.extend_from_slice(&escaped);
+        manifest.push(b'\n');
+    }
+    manifest
+}
+
+/// Parses a manifest in the format of `sha256sum`, in text or binary mode.
+///
+/// # Errors
+///
+/// Returns an error if a line is not a SHA-256 followed by a name.
+pub fn parse(content: &[u8]) -> Result<Vec<Entry>> {
+    content
+        .split(|&byte| byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .enumerate()
+        .filter(|(_, line)| !line.iter().all(u8::is_ascii_whitespace))
+        .map(|(number, line)| {
+            let invalid = || anyhow!("Invalid manifest line {}", number + 1);
+            let (escaped, line) = match line.strip_prefix(b"\\") {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let space = line.iter().position(|&byte| byte == b' ');
+            let (sha256, name) = line.split_at(space.ok_or_else(invalid)?);
+            let name = name[1..]
+                .strip_prefix(b" ")
+                .or_else(|| name[1..].strip_prefix(b"*"))
+                .ok_or_else(invalid)?;
+            let sha256 = std::str::from_utf8(sha256).map_err(|_| invalid())?;
+            if sha256.len() != 64 || hex::decode(sha256).is_err() {
+                return Err(invalid());
+            }
+            Ok(Entry {
+                sha256: sha256.to_lowercase(),
+                name: match escaped {
+                    true => unescape(name).ok_or_else(invalid)?,
+                    false => name.to_vec(),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Reverses the escaping of a name by [`format`].
+fn unescape(name: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(&byte) = bytes.next() {
+        unescaped.push(match byte {
+            b'\\' => match bytes.next()? {
+                b'\\' => b'\\',
+                b'n' => b'\n',
+                b'r' => b'\r',
+                _ => return None,
+            },
+            byte => byte,
+        });
+    }
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_parse() {
+        let entries = vec![
+            Entry {
+                sha256: "ab".repeat(32),
+                name: b"sub/a.txt".to_vec(),
+            },
+            Entry {
+                sha256: "cd".repeat(32),
+                name: b"odd\\name\n.txt".to_vec(),
+            },
+            Entry {
+                sha256: "ef".repeat(32),
+                name: b"caf\xe9.txt".to_vec(),
+            },
+        ];
+        let manifest = format(&entries);
+        assert_eq!(
+            manifest,
+            [
+                format!("{}  sub/a.txt\n", "ab".repeat(32)).as_bytes(),
+                format!("\\{}  odd\\\\name\\n.txt\n", "cd".repeat(32))
+                    .as_bytes(),
+                format!("{}  caf", "ef".repeat(32)).as_bytes(),
+                b"\xe9.txt\n",
+            ]
+            .concat()
+        );
+        assert_eq!(parse(&manifest).unwrap(), entries);
+
+        // Binary mode, as written by `sha256sum -b`
+        let binary = format!("{} *a.bin\r\n\n", "EF".repeat(32));
+        assert_eq!(
+            parse(binary.as_bytes()).unwrap(),
+            vec![Entry {
+                sha256: "ef".repeat(32),
+                name: b"a.bin".to_vec()
+            }]
+        );
+
+        assert!(parse(b"abc  a.txt").is_err());
+        assert!(parse(format!("{}a.txt", "ab".repeat(32)).as_bytes()).is_err());
+        assert!(
+            parse(format!("\\{}  a\\x", "ab".repeat(32)).as_bytes()).is_err()
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+/// A read-only memory map of a whole file, so that a large file can be
+/// hashed without copying it through a buffer. The pages are read by the
+/// kernel as they are touched, and can be evicted again under pressure.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and owned by the `Mmap`, like a `Box<[u8]>`
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps a file in memory.
+    ///
+    /// The file must not be truncated while it is mapped: reading the
+    /// missing pages would raise `SIGBUS`. The client only maps the files it
+    /// is about to read anyway, which a concurrent writer would corrupt too.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file is empty, too large for the address
+    /// space, or cannot be mapped, e.g. a pipe or a file of some network
+    /// filesystems. The file can then still be read.
+    #[cfg(unix)]
+    pub fn map(file: &File) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::other("file too large to map"))?;
+        if len == 0 {
+            // mmap rejects empty mappings
+            return Err(io::Error::other("empty file"));
+        }
+        // SAFETY: a new read-only private mapping of an open file, which
+        // does not alias any memory of the program
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // The file is read once from start to end: read ahead, and drop the
+        // pages read first. Only a hint, so its failure is ignored.
+        // SAFETY: the advice does not change the content of the mapping
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Self { ptr, len })
+    }
+
+    /// Files cannot be mapped on this platform, and are read instead.
+    #[cfg(not(unix))]
+    pub fn map(_file: &File) -> io::Result<Self> {
+        Err(io::Error::other("memory maps are not supported"))
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping holds `len` readable bytes until dropped
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        // SAFETY: the mapping is no longer borrowed, `self` being dropped
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_map() {
+        let path = std::env::temp_dir().join("file-guardian-test-mmap");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"hello world")
+            .unwrap();
+        let mmap = Mmap::map(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(&*mmap, b"hello world");
+
+        // Empty files cannot be mapped, and are read instead
+        File::create(&path).unwrap();
+        assert!(Mmap::map(&File::open(&path).unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1564 @@
+use crate::cli::SortKey;
+#[cfg(doc)]
+use crate::client::ServerErrorKind;
+use crate::client::{is_timeout, server_error, StoredUpload};
+use crate::daemon::{Job, JobState, Status};
+use crate::db::{FileRecord, RootSignature, Upload};
+use crate::trash::{TrashedFile, TrashedUpload};
+use crate::utils::{format_size, format_time};
+use anyhow::Result;
+use clap::ValueEnum;
+use glob::Pattern;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// How the result of a command is printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// A single JSON document on stdout, for scripting
+    Json,
+}
+
+/// The result of a command, printed either for humans or as JSON.
+pub trait Report: Serialize {
+    /// Prints the report for humans.
+    fn print_text(&self);
+}
+
+impl OutputFormat {
+    /// Prints the result of a command.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the report cannot be serialized.
+    pub fn print(&self, report: &impl Report) -> Result<()> {
+        match self {
+            OutputFormat::Text => report.print_text(),
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(report)?)
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the error a command failed with. In JSON mode the error is
+    /// printed on stdout as `{"error": "...", "kind": "..."}`, so that scripts
+    /// always get a JSON document, the kind being `timeout` if the server did
+    /// not respond in time, the name of the [`ServerErrorKind`] of the error
+    /// reported by the server, e.g. `unauthorized` if it rejected the token,
+    /// and `error` otherwise. A [`ReportedError`] is not printed in JSON mode, the
+    /// report printed before it being the document.
+    pub fn print_error(&self, error: &anyhow::Error) {
+        match self {
+            OutputFormat::Text => eprintln!("Error: {:?}", error),
+            OutputFormat::Json
+                if error.downcast_ref::<ReportedError>().is_some() => {}
+            OutputFormat::Json => {
+                let kind = if is_timeout(error) {
+                    "timeout"
+                } else if let Some(error) = server_error(error) {
+                    error.kind.name()
+                } else {
+                    "error"
+                };
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "error": format!("{:#}", error),
+                        "kind": kind,
+                    })
+                )
+            }
+        }
+    }
+}
+
+/// The error of a command whose report, already printed, tells why it
+/// failed, e.g. the files not matching a manifest.
+#[derive(Debug)]
+pub struct ReportedError(pub String);
+
+impl fmt::Display for ReportedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ReportedError {}
+
+/// The uploads recorded in the database.
+#[derive(Serialize)]
+pub struct ListReport {
+    pub uploads: Vec<ListedUpload>,
+}
+
+/// An upload, with only the files matching the list filter.
+#[derive(Serialize, Clone)]
+pub struct ListedUpload {
+    pub root_hash: String,
+    #[serde(flatten)]
+    pub upload: Upload,
+}
+
+/// How to select and order the uploads of a [`ListReport`].
+#[derive(Default)]
+pub struct ListOptions {
+    /// Only list the files matching this pattern, and the uploads containing
+    /// at least one of them.
+    pub filter: Option<Pattern>,
+    /// Only list the uploads whose root hash starts with this prefix.
+    pub root: Option<String>,
+    /// Only list the uploads with every one of these tags.
+    pub tags: Vec<String>,
+    /// The order of the uploads and their files, by root hash if `None`.
+    pub sort: Option<SortKey>,
+    /// Whether to reverse the order.
+    pub reverse: bool,
+}
+
+impl ListReport {
+    /// Selects and orders the uploads to list.
+    pub fn new(
+        uploads: &HashMap<String, Upload>,
+        options: &ListOptions,
+    ) -> Self {
+        let mut uploads = uploads
+            .iter()
+            .filter(|(root_hash, _)| {
+                options
+                    .root
+                    .as_ref()
+                    .is_none_or(|prefix| root_hash.starts_with(prefix.as_str()))
+            })
+            .filter(|(_, upload)| {
+                options.tags.iter().all(|tag| upload.tags.contains(tag))
+            })
+            .filter_map(|(root_hash, upload)| {
+                let mut upload = upload.clone();
+                if let Some(filter) = &options.filter {
+                    upload.files.retain(|f| filter.matches(&f.name));
+                    if upload.files.is_empty() {
+                        return None;
+                    }
+                }
+                Some(ListedUpload {
+                    root_hash: root_hash.clone(),
+                    upload,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        uploads.sort_by(|a, b| a.root_hash.cmp(&b.root_hash));
+        match options.sort {
+            Some(SortKey::Name) => {
+                for listed in &mut uploads {
+                    listed.upload.files.sort_by(|a, b| a.name.cmp(&b.name));
+                }
+                uploads.sort_by(|a, b| {
+                    let first = |l: &ListedUpload| {
+                        l.upload.files.first().map(|f| f.name.clone())
+                    };
+                    first(a).cmp(&first(b))
+                });
+            }
+            Some(SortKey::Date) => {
+                uploads.sort_by_key(|listed| listed.upload.uploaded_at);
+            }
+            Some(SortKey::Size) => {
+                for listed in &mut uploads {
+                    listed.upload.files.sort_by_key(|f| f.size);
+                }
+                uploads.sort_by_key(|listed| listed.upload.size());
+            }
+            None => {}
+        }
+        if options.reverse {
+            for listed in &mut uploads {
+                listed.upload.files.reverse();
+            }
+            uploads.reverse();
+        }
+
+        Self { uploads }
+    }
+}
+
+impl Report for ListReport {
+    fn print_text(&self) {
+        println!("Root hashes and files:");
+        for ListedUpload { root_hash, upload } in &self.uploads {
+            let mut summary = format!("{} files", upload.files.len());
+            if let Some(size) = upload.size() {
+                summary.push_str(&format!(", {}", format_size(size)));
+            }
+            if let Some(uploaded_at) = upload.uploaded_at {
+                summary.push_str(&format!(
+                    ", uploaded {}",
+                    format_time(uploaded_at)
+                ));
+            }
+            if let Some(server_addr) = &upload.server_addr {
+                summary.push_str(&format!(" to {}", server_addr));
+            }
+            if !upload.tags.is_empty() {
+                summary.push_str(&format!(" [{}]", upload.tags.join(", ")));
+            }
+            println!("  {}: {}", root_hash, summary);
+            for file in &upload.files {
+                match file.size {
+                    Some(size) => {
+                        println!("    {} ({})", file.name, format_size(size))
+                    }
+                    None => println!("    {}", file.name),
+                }
+            }
+        }
+    }
+}
+
+/// The uploads a server holds, cross-checked against the database.
+#[derive(Serialize)]
+pub struct RemoteListReport {
+    pub server_addr: String,
+    pub uploads: Vec<RemoteUpload>,
+}
+
+/// An upload held by the server, or recorded as uploaded to it.
+#[derive(Serialize)]
+pub struct RemoteUpload {
+    pub root_hash: String,
+    /// The number of files, as held by the server if it does, as recorded
+    /// otherwise.
+    pub files: u64,
+    /// The total size of the files, if known.
+    pub size: Option<u64>,
+    pub status: RemoteStatus,
+}
+
+/// How an upload held by the server compares to the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteStatus {
+    /// Held by the server as recorded.
+    Recorded,
+    /// Held by the server, but not recorded.
+    Unrecorded,
+    /// Held by the server with other files than recorded.
+    Mismatch,
+    /// Recorded as uploaded to the server, which does not hold it.
+    Missing,
+}
+
+impl RemoteListReport {
+    /// Cross-checks the uploads a server holds against the ones recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `server_addr` - The address of the server.
+    /// * `stored` - The uploads the server holds.
+    /// * `uploads` - The uploads recorded in the database, of which only the
+    ///   ones recorded as uploaded to `server_addr` can be missing.
+    pub fn new(
+        server_addr: &str,
+        stored: Vec<StoredUpload>,
+        uploads: &HashMap<String, Upload>,
+    ) -> Self {
+        let held = stored
+            .iter()
+            .map(|upload| upload.root_hash.as_str())
+            .collect::<std::collections::HashSet<_>>();
+        let mut missing = uploads
+            .iter()
+            .filter(|(root_hash, upload)| {
+                upload.server_addr.as_deref() == Some(server_addr)
+                    && !held.contains(root_hash.as_str())
+            })
+            .map(|(root_hash, upload)| RemoteUpload {
+                root_hash: root_hash.clone(),
+                files: upload.files.len() as u64,
+                size: upload.size(),
+                status: RemoteStatus::Missing,
+            })
+            .collect::<Vec<_>>();
+
+        let mut remote = stored
+            .into_iter()
+            .map(|stored| {
+                let status = match uploads.get(&stored.root_hash) {
+                    None => RemoteStatus::Unrecorded,
+                    Some(upload)
+                        if upload.files.len() as u64 != stored.files
+                            || upload
+                                .size()
+                                .is_some_and(|size| size != stored.size) =>
+                    {
+                        RemoteStatus::Mismatch
+                    }
+                    Some(_) => RemoteStatus::Recorded,
+                };
+                RemoteUpload {
+                    root_hash: stored.root_hash,
+                    files: stored.files,
+                    size: Some(stored.size),
+                    status,
+                }
+            })
+            .collect::<Vec<_>>();
+        remote.append(&mut missing);
+        remote.sort_by(|a, b| a.root_hash.cmp(&b.root_hash));
+
+        Self {
+            server_addr: server_addr.to_string(),
+            uploads: remote,
+        }
+    }
+}
+
+impl Report for RemoteListReport {
+    fn print_text(&self) {
+        println!("Root hashes held by {}:", self.server_addr);
+        for upload in &self.uploads {
+            let mut summary = format!("{} files", upload.files);
+            if let Some(size) = upload.size {
+                summary.push_str(&format!(", {}", format_size(size)));
+            }
+            match upload.status {
+                RemoteStatus::Recorded => {}
+                RemoteStatus::Unrecorded => {
+                    summary.push_str(" (not recorded in the database)")
+                }
+                RemoteStatus::Mismatch => summary
+                    .push_str(" (recorded with other files in the database)"),
+                RemoteStatus::Missing => {
+                    summary.push_str(" (recorded, but missing from the server)")
+                }
+            }
+            println!("  {}: {}", upload.root_hash, summary);
+        }
+    }
+}
+
+/// The uploaded files found by name.
+#[derive(Serialize)]
+pub struct FindReport {
+    /// The name or glob pattern the files were found with.
+    pub pattern: String,
+    /// The server asked which uploads it still holds, if it was.
+    pub server_addr: Option<String>,
+    /// The files found, the latest uploaded first.
+    pub files: Vec<FoundFile>,
+}
+
+/// An uploaded file found by name.
+#[derive(Serialize)]
+pub struct FoundFile {
+    pub root_hash: String,
+    pub name: String,
+    pub size: Option<u64>,
+    pub uploaded_at: Option<u64>,
+    /// The address of the server the file was uploaded to.
+    pub server_addr: Option<String>,
+}
+
+impl FindReport {
+    /// Finds the files of every upload whose base name or whole name matches
+    /// a pattern, so that `report.pdf` finds `docs/report.pdf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The name or glob pattern to find the files with.
+    /// * `uploads` - The uploads recorded in the database.
+    /// * `held` - The address of a server and the uploads it holds, to only
+    ///   find the files of those.
+    pub fn new(
+        pattern: &Pattern,
+        uploads: &HashMap<String, Upload>,
+        held: Option<(String, Vec<StoredUpload>)>,
+    ) -> Self {
+        let roots = held.as_ref().map(|(_, stored)| {
+            stored
+                .iter()
+                .map(|upload| upload.root_hash.as_str())
+                .collect::<std::collections::HashSet<_>>()
+        });
+        let mut files = uploads
+            .iter()
+            .filter(|(root_hash, _)| {
+                roots
+                    .as_ref()
+                    .is_none_or(|roots| roots.contains(root_hash.as_str()))
+            })
+            .flat_map(|(root_hash, upload)| {
+                upload
+                    .files
+                    .iter()
+                    .filter(|file| {
+                        let base = file.name.rsplit('/').next();
+                        pattern.matches(&file.name)
+                            || base.is_some_and(|base| pattern.matches(base))
+                    })
+                    .map(|file| FoundFile {
+                        root_hash: root_hash.clone(),
+                        name: file.name.clone(),
+                        size: file.size,
+                        uploaded_at: upload.uploaded_at,
+                        server_addr: upload.server_addr.clone(),
+                    })
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| {
+            b.uploaded_at
+                .cmp(&a.uploaded_at)
+                .then_with(|| a.root_hash.cmp(&b.root_hash))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Self {
+            pattern: pattern.to_string(),
+            server_addr: held.map(|(server_addr, _)| server_addr),
+            files,
+        }
+    }
+}
+
+impl FoundFile {
+    /// Describes the file and its upload on a single line.
+    pub fn summary(&self) -> String {
+        let mut summary = self.name.clone();
+        if let Some(size) = self.size {
+            summary.push_str(&format!(" ({})", format_size(size)));
+        }
+        summary.push_str(&format!(" in {}", self.root_hash));
+        if let Some(uploaded_at) = self.uploaded_at {
+            summary
+                .push_str(&format!(", uploaded {}", format_time(uploaded_at)));
+        }
+        if let Some(server_addr) = &self.server_addr {
+            summary.push_str(&format!(" to {}", server_addr));
+        }
+        summary
+    }
+}
+
+impl Report for FindReport {
+    fn print_text(&self) {
+        let held = match &self.server_addr {
+            Some(server_addr) => format!(" held by {}", server_addr),
+            None => String::new(),
+        };
+        if self.files.is_empty() {
+            println!("No uploaded file{} matches {}", held, self.pattern);
+            return;
+        }
+        println!(
+            "Found {} files{} matching {}:",
+            self.files.len(),
+            held,
+            self.pattern
+        );
+        for (number, file) in self.files.iter().enumerate() {
+            println!("  {}. {}", number + 1, file.summary());
+        }
+    }
+}
+
+/// The result of an upload.
+#[derive(Serialize)]
+pub struct UploadReport {
+    pub root_hash: String,
+    pub server_addr: String,
+    pub files: Vec<FileRecord>,
+    pub size: u64,
+    /// Whether the server acknowledged the upload with the same root hash.
+    pub verified: bool,
+    /// Whether the proof of every file served by the server was verified
+    /// against the local hashes, with `--verify`.
+    pub proofs_verified: bool,
+    /// Whether the original files were deleted.
+    pub deleted: bool,
+    /// Whether the files were only hashed, and nothing was uploaded.
+    pub dry_run: bool,
+    /// Whether the server already held the files, which were not sent again.
+    pub deduplicated: bool,
+    /// The signature of the root hash by the server, if it signs them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<RootSignature>,
+}
+
+impl Report for UploadReport {
+    fn print_text(&self) {
+        if self.dry_run {
+            println!("Root hash: {}", self.root_hash);
+            for file in &self.files {
+                println!(
+                    "  {} {} ({})",
+                    file.sha256.as_deref().unwrap_or_default(),
+                    file.name,
+                    format_size(file.size.unwrap_or_default())
+                );
+            }
+            println!(
+                "{} files, {} would be uploaded",
+                self.files.len(),
+                format_size(self.size)
+            );
+            return;
+        }
+        match self.deduplicated {
+            true => println!(
+                "The server already holds these files, recorded with root \
+                 hash {}",
+                self.root_hash
+            ),
+            false => println!(
+                "Succesfully Uploaded files with root hash {}",
+                self.root_hash
+            ),
+        }
+        if let Some(signature) = &self.signature {
+            println!("Signed by the server with key {}", signature.key);
+        }
+        if self.deduplicated {
+            return;
+        }
+        if self.deleted {
+            println!(
+                "Moved the original files to the trash, `undelete --root-hash \
+                 {}` restores them",
+                self.root_hash
+            );
+        }
+    }
+}
+
+/// The result of a download.
+#[derive(Serialize)]
+pub struct DownloadReport {
+    pub root_hash: String,
+    pub server_addr: String,
+    /// The directory the files were downloaded to.
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub dir: PathBuf,
+    pub files: Vec<DownloadedFile>,
+}
+
+/// A downloaded file.
+#[derive(Serialize)]
+pub struct DownloadedFile {
+    pub name: String,
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    /// Whether the Merkle proof of the file was verified against the root
+    /// hash.
+    pub verified: bool,
+}
+
+impl Report for DownloadReport {
+    fn print_text(&self) {
+        match self.files.as_slice() {
+            [file] => println!(
+                "Succesfully downloaded file {} to {}",
+                file.name,
+                self.dir.display()
+            ),
+            files => println!(
+                "Succesfully downloaded {} files to {}",
+                files.len(),
+                self.dir.display()
+            ),
+        }
+    }
+}
+
+/// The result of a proof request.
+#[derive(Serialize)]
+pub struct ProveReport {
+    pub root_hash: String,
+    pub name: String,
+    /// The file the proof was saved to.
+    pub path: PathBuf,
+    /// The leaf hash of the file, i.e. its SHA-256.
+    pub leaf: String,
+    /// Whether the proof was verified against the root hash.
+    pub verified: bool,
+    /// Whether the proof was taken from the proof cache rather than fetched
+    /// from the server.
+    pub cached: bool,
+}
+
+impl Report for ProveReport {
+    fn print_text(&self) {
+        println!(
+            "Succesfully saved the proof of {} to {}",
+            self.name,
+            self.path.display()
+        );
+    }
+}
+
+/// The result of an offline verification.
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub root_hash: String,
+    /// The name the file was uploaded under.
+    pub name: String,
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub path: PathBuf,
+    pub sha256: String,
+    pub verified: bool,
+}
+
+impl Report for VerifyReport {
+    fn print_text(&self) {
+        println!(
+            "File {} matches {} of root hash {}",
+            self.path.display(),
+            self.name,
+            self.root_hash
+        );
+    }
+}
+
+/// The result of a sync of a directory.
+#[derive(Serialize)]
+pub struct SyncReport {
+    pub dir: PathBuf,
+    pub server_addr: String,
+    /// The names of the new and modified files, uploaded unless in a dry
+    /// run.
+    pub changed: Vec<String>,
+    /// The number of files matching the last synced snapshot.
+    pub unchanged: usize,
+    /// The names of the files of the snapshot no longer in the directory,
+    /// which are kept on the server.
+    pub removed: Vec<String>,
+    /// The upload of the changed files, if there are any.
+    pub upload: Option<UploadReport>,
+    /// Whether the files were only compared, and nothing was uploaded.
+    pub dry_run: bool,
+}
+
+impl Report for SyncReport {
+    fn print_text(&self) {
+        if self.dry_run {
+            for name in &self.changed {
+                println!("{}", name);
+            }
+        }
+        for name in &self.removed {
+            println!("Removed locally: {}", name);
+        }
+        match (&self.upload, self.dry_run) {
+            (Some(upload), _) => upload.print_text(),
+            (None, true) => println!(
+                "{} files would be uploaded, {} are unchanged",
+                self.changed.len(),
+                self.unchanged
+            ),
+            (None, false) => println!(
+                "{} is up to date with {}, {} files unchanged",
+                self.dir.display(),
+                self.server_addr,
+                self.unchanged
+            ),
+        }
+    }
+}
+
+/// The storage used on a server by the uploads of the client.
+#[derive(Serialize)]
+pub struct UsageReport {
+    pub server_addr: String,
+    /// The bytes stored.
+    pub used: u64,
+    /// The quota of the client, in bytes, if it has one.
+    pub quota: Option<u64>,
+    /// The bytes that can still be stored within the quota.
+    pub remaining: Option<u64>,
+}
+
+impl UsageReport {
+    /// Creates the report of the usage reported by a server.
+    pub fn new(server_addr: &str, used: u64, quota: Option<u64>) -> Self {
+        Self {
+            server_addr: server_addr.to_string(),
+            used,
+            quota,
+            remaining: quota.map(|quota| quota.saturating_sub(used)),
+        }
+    }
+}
+
+impl Report for UsageReport {
+    fn print_text(&self) {
+        match (self.quota, self.remaining) {
+            (Some(quota), Some(remaining)) => println!(
+                "{} of {} used on {}, {} remaining",
+                format_size(self.used),
+                format_size(quota),
+                self.server_addr,
+                format_size(remaining)
+            ),
+            _ => println!(
+                "{} used on {}, without a quota",
+                format_size(self.used),
+                self.server_addr
+            ),
+        }
+    }
+}
+
+/// The result of a deletion.
+#[derive(Serialize)]
+pub struct DeleteReport {
+    pub root_hash: String,
+    pub server_addr: String,
+    /// Whether the server held the upload.
+    pub remote: bool,
+    /// Whether the database recorded the upload.
+    pub local: bool,
+}
+
+impl Report for DeleteReport {
+    fn print_text(&self) {
+        match (self.remote, self.local) {
+            (true, true) => {
+                println!("Succesfully deleted root hash {}", self.root_hash)
+            }
+            (true, false) => println!(
+                "Succesfully deleted root hash {}, which was not recorded \
+                 in the database",
+                self.root_hash
+            ),
+            _ => println!(
+                "Root hash {} was not held by {}, removed it from the \
+                 database",
+                self.root_hash, self.server_addr
+            ),
+        }
+    }
+}
+
+impl Report for Job {
+    fn print_text(&self) {
+        match &self.state {
+            JobState::Pending => println!(
+                "Queued upload job {} of {} files on the daemon",
+                self.id, self.files
+            ),
+            JobState::Running => {
+                println!("Upload job {} is running", self.id)
+            }
+            JobState::Uploaded {
+                root_hash,
+                deduplicated: true,
+            } => println!(
+                "The server already holds these files, recorded with root \
+                 hash {}",
+                root_hash
+            ),
+            JobState::Uploaded { root_hash, .. } => println!(
+                "Succesfully Uploaded files with root hash {}",
+                root_hash
+            ),
+            JobState::Failed { error } => {
+                println!("Upload job {} failed: {}", self.id, error)
+            }
+        }
+    }
+}
+
+impl Report for Status {
+    fn print_text(&self) {
+        println!(
+            "Uploading to {}, {} connections ready",
+            self.server_addr, self.ready_connections
+        );
+        for job in &self.jobs {
+            let state = match &job.state {
+                JobState::Pending => "pending".to_string(),
+                JobState::Running => "running".to_string(),
+                JobState::Uploaded { root_hash, .. } => {
+                    format!("uploaded as {}", root_hash)
+                }
+                JobState::Failed { error } => format!("failed: {}", error),
+            };
+            println!(
+                "  {}. {} files queued at {}, {}",
+                job.id,
+                job.files,
+                format_time(job.queued_at),
+                state
+            );
+        }
+        match &self.verification {
+            Some(verification) => {
+                println!(
+                    "Last verified {} uploads at {}, {} failed",
+                    verification.uploads,
+                    format_time(verification.at),
+                    verification.failures.len()
+                );
+                for failure in &verification.failures {
+                    println!("  {}", failure);
+                }
+            }
+            None => println!("No verification has run yet"),
+        }
+    }
+}
+
+/// The result of `daemon stop`.
+#[derive(Serialize)]
+pub struct DaemonStopReport {
+    /// The socket of the daemon.
+    pub socket: PathBuf,
+}
+
+impl Report for DaemonStopReport {
+    fn print_text(&self) {
+        println!(
+            "The daemon on {} stops once its running upload is done",
+            self.socket.display()
+        );
+    }
+}
+
+/// The files restored from the trash.
+#[derive(Serialize)]
+pub struct UndeleteReport {
+    pub root_hash: String,
+    pub files: Vec<TrashedFile>,
+}
+
+impl Report for UndeleteReport {
+    fn print_text(&self) {
+        for file in &self.files {
+            println!("Restored {}", file.path.display());
+        }
+        println!(
+            "Succesfully restored {} files of root hash {}",
+            self.files.len(),
+            self.root_hash
+        );
+    }
+}
+
+/// The files in the trash.
+#[derive(Serialize)]
+pub struct TrashReport {
+    pub uploads: Vec<TrashedUpload>,
+}
+
+impl Report for TrashReport {
+    fn print_text(&self) {
+        if self.uploads.is_empty() {
+            println!("The trash is empty");
+        }
+        for upload in &self.uploads {
+            println!("Root hash: {}", upload.root_hash);
+            for file in &upload.files {
+                println!(
+                    "  {} ({}, kept until {})",
+                    file.name,
+                    file.path.display(),
+                    format_time(file.expires_at)
+                );
+            }
+        }
+    }
+}
+
+/// The result of a database export or import.
+#[derive(Serialize)]
+pub struct DbReport {
+    /// Either `exported` or `imported`.
+    pub action: &'static str,
+    /// The number of uploads exported or imported.
+    pub uploads: usize,
+    pub file: PathBuf,
+}
+
+impl Report for DbReport {
+    fn print_text(&self) {
+        let direction = if self.action == "exported" {
+            "to"
+        } else {
+            "from"
+        };
+        println!(
+            "Succesfully {} {} uploads {} {}",
+            self.action,
+            self.uploads,
+            direction,
+            self.file.display()
+        );
+    }
+}
+
+/// The result of a manifest export to a file.
+#[derive(Serialize)]
+pub struct ManifestReport {
+    pub root_hash: String,
+    /// The number of files of the manifest.
+    pub files: usize,
+    pub file: PathBuf,
+}
+
+impl Report for ManifestReport {
+    fn print_text(&self) {
+        println!(
+            "Succesfully exported the manifest of {} files of root hash {} \
+             to {}",
+            self.files,
+            self.root_hash,
+            self.file.display()
+        );
+    }
+}
+
+/// The result of a bundle export.
+#[derive(Serialize)]
+pub struct BundleReport {
+    pub root_hash: String,
+    /// The number of files of the bundle.
+    pub files: usize,
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub file: PathBuf,
+}
+
+impl Report for BundleReport {
+    fn print_text(&self) {
+        println!(
+            "Succesfully exported the bundle of {} files of root hash {} to \
+             {}",
+            self.files,
+            self.root_hash,
+            self.file.display()
+        );
+    }
+}
+
+/// What a login or logout did to a secret of the keyring.
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyringAction {
+    Stored,
+    Removed,
+    /// The keyring did not hold the secret to remove.
+    Missing,
+}
+
+/// The result of a login or logout.
+#[derive(Serialize)]
+pub struct KeyringReport {
+    /// The secret, e.g. `the token of 127.0.0.1:2345`.
+    pub secret: String,
+    pub action: KeyringAction,
+}
+
+impl Report for KeyringReport {
+    fn print_text(&self) {
+        match self.action {
+            KeyringAction::Stored => {
+                println!("Stored {} in the keyring", self.secret)
+            }
+            KeyringAction::Removed => {
+                println!("Removed {} from the keyring", self.secret)
+            }
+            KeyringAction::Missing => {
+                println!("The keyring does not hold {}", self.secret)
+            }
+        }
+    }
+}
+
+/// The result of checking local files against a manifest.
+#[derive(Serialize)]
+pub struct ManifestCheckReport {
+    pub manifest: PathBuf,
+    pub files: Vec<CheckedFile>,
+    /// Whether every file matches the manifest.
+    pub verified: bool,
+}
+
+/// A file checked against a manifest.
+#[derive(Serialize)]
+pub struct CheckedFile {
+    /// The name of the file in the manifest.
+    pub name: String,
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub path: PathBuf,
+    pub status: CheckStatus,
+}
+
+/// Whether a file matches its manifest entry.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    /// The file has the SHA-256 of the manifest.
+    Ok,
+    /// The file has another SHA-256.
+    Failed,
+    /// The file does not exist or cannot be read.
+    Missing,
+}
+
+impl Report for ManifestCheckReport {
+    /// Prints a line per file, like `sha256sum -c`.
+    fn print_text(&self) {
+        for file in &self.files {
+            let status = match file.status {
+                CheckStatus::Ok => "OK",
+                CheckStatus::Failed => "FAILED",
+                CheckStatus::Missing => "FAILED open or read",
+            };
+            println!("{}: {}", file.name, status);
+        }
+    }
+}
+
+/// The result of checking local files against a bundle.
+#[derive(Serialize)]
+pub struct BundleCheckReport {
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub bundle: PathBuf,
+    pub root_hash: String,
+    pub files: Vec<CheckedFile>,
+    /// Whether every file is linked to the root hash by its proof.
+    pub verified: bool,
+}
+
+impl Report for BundleCheckReport {
+    /// Prints a line per file, like `sha256sum -c`, then a summary.
+    fn print_text(&self) {
+        for file in &self.files {
+            let status = match file.status {
+                CheckStatus::Ok => "OK",
+                CheckStatus::Failed => "FAILED",
+                CheckStatus::Missing => "FAILED open or read",
+            };
+            println!("{}: {}", file.name, status);
+        }
+        let verified = self
+            .files
+            .iter()
+            .filter(|file| file.status == CheckStatus::Ok)
+            .count();
+        println!(
+            "{} of {} files verified against root hash {}",
+            verified,
+            self.files.len(),
+            self.root_hash
+        );
+    }
+}
+
+/// The differences between two directories, compared by content.
+#[derive(Serialize)]
+pub struct DiffReport {
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub a: PathBuf,
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub b: PathBuf,
+    /// The root hash of the tree of the first directory.
+    pub root_a: String,
+    /// The root hash of the tree of the second directory.
+    pub root_b: String,
+    /// The number of files identical in both directories.
+    pub identical: usize,
+    /// The names of the files whose content changed.
+    pub changed: Vec<String>,
+    /// The names of the files only in the second directory.
+    pub added: Vec<String>,
+    /// The names of the files only in the first directory.
+    pub removed: Vec<String>,
+}
+
+impl DiffReport {
+    /// Returns whether the directories hold the same files.
+    pub fn identical(&self) -> bool {
+        self.root_a == self.root_b
+    }
+}
+
+impl Report for DiffReport {
+    /// Prints a line per different file, like `git diff --name-status`,
+    /// then a summary.
+    fn print_text(&self) {
+        if self.identical() {
+            println!(
+                "The directories are identical, {} files with root hash {}",
+                self.identical, self.root_a
+            );
+            return;
+        }
+        let mut files = self
+            .changed
+            .iter()
+            .map(|name| ('M', name))
+            .chain(self.added.iter().map(|name| ('A', name)))
+            .chain(self.removed.iter().map(|name| ('D', name)))
+            .collect::<Vec<_>>();
+        files.sort_by_key(|(_, name)| *name);
+        for (status, name) in files {
+            println!("{} {}", status, name);
+        }
+        println!(
+            "{} identical, {} changed, {} added, {} removed (root hashes {} \
+             and {})",
+            self.identical,
+            self.changed.len(),
+            self.added.len(),
+            self.removed.len(),
+            self.root_a,
+            self.root_b
+        );
+    }
+}
+
+/// What an upload commits to, as recorded in the database.
+#[derive(Serialize)]
+pub struct InfoReport {
+    pub root_hash: String,
+    pub uploaded_at: Option<u64>,
+    pub server_addr: Option<String>,
+    pub tags: Vec<String>,
+    /// The number of leaves of the tree, i.e. of files.
+    pub leaves: usize,
+    /// The number of levels of the tree above the leaves, if the leaf of
+    /// every file is recorded.
+    pub depth: Option<usize>,
+    /// The size of the proof of every file, in bytes, if the depth is known.
+    pub proof_size: Option<usize>,
+    /// The total size of the files, if known.
+    pub size: Option<u64>,
+    /// Whether the recorded leaves recompute the root hash, if the leaf of
+    /// every file is recorded.
+    pub consistent: Option<bool>,
+    pub files: Vec<InfoFile>,
+    /// What the server holds, with `--remote`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<RemoteInfo>,
+}
+
+/// A file of an upload, i.e. a leaf of its tree.
+#[derive(Serialize)]
+pub struct InfoFile {
+    pub index: usize,
+    pub name: String,
+    pub size: Option<u64>,
+    /// The recorded SHA-256 of the file, i.e. its leaf.
+    pub leaf: Option<String>,
+    /// Whether the proof of the file is recorded, to verify it offline.
+    pub proof_recorded: bool,
+    pub encrypted: bool,
+    pub compressed: bool,
+    /// The leaf the server holds for the file, with `--remote`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_leaf: Option<String>,
+}
+
+/// An upload as held by the server.
+#[derive(Serialize)]
+pub struct RemoteInfo {
+    pub server_addr: String,
+    /// Whether the server holds the upload.
+    pub held: bool,
+    /// The number of files the server holds, if it holds the upload.
+    pub files: Option<u64>,
+    /// The total size of the files as stored, if it holds the upload.
+    pub size: Option<u64>,
+}
+
+impl Report for InfoReport {
+    fn print_text(&self) {
+        println!("Root hash: {}", self.root_hash);
+        let mut uploaded = String::from("Uploaded");
+        if let Some(uploaded_at) = self.uploaded_at {
+            uploaded.push_str(&format!(" {}", format_time(uploaded_at)));
+        }
+        if let Some(server_addr) = &self.server_addr {
+            uploaded.push_str(&format!(" to {}", server_addr));
+        }
+        if !self.tags.is_empty() {
+            uploaded.push_str(&format!(" [{}]", self.tags.join(", ")));
+        }
+        println!("{}", uploaded);
+        match self.size {
+            Some(size) => {
+                println!("{} files, {}", self.leaves, format_size(size))
+            }
+            None => println!("{} files", self.leaves),
+        }
+        match (self.depth, self.proof_size) {
+            (Some(depth), Some(proof_size)) => println!(
+                "Merkle tree: {} leaves, depth {}, proofs of {} hashes ({} B)",
+                self.leaves, depth, depth, proof_size
+            ),
+            _ => println!("Merkle tree: {} leaves", self.leaves),
+        }
+        match self.consistent {
+            Some(true) => {
+                println!("The recorded leaves recompute the root hash")
+            }
+            Some(false) => {
+                println!("The recorded leaves do NOT recompute the root hash")
+            }
+            None => println!("The leaves of some files are not recorded"),
+        }
+        println!("Leaves:");
+        for file in &self.files {
+            let mut line = format!(
+                "  {:>4} {} {}",
+                file.index,
+                file.leaf.as_deref().unwrap_or("(not recorded)"),
+                file.name
+            );
+            if let Some(size) = file.size {
+                line.push_str(&format!(" ({})", format_size(size)));
+            }
+            let mut flags = vec![];
+            if file.compressed {
+                flags.push("compressed");
+            }
+            if file.encrypted {
+                flags.push("encrypted");
+            }
+            if !file.proof_recorded {
+                flags.push("no proof recorded");
+            }
+            if let (Some(server_leaf), Some(leaf)) =
+                (&file.server_leaf, &file.leaf)
+            {
+                if server_leaf != leaf {
+                    flags.push("the server holds another leaf");
+                }
+            }
+            if !flags.is_empty() {
+                line.push_str(&format!(" [{}]", flags.join(", ")));
+            }
+            println!("{}", line);
+        }
+        if let Some(remote) = &self.remote {
+            match (remote.held, remote.files, remote.size) {
+                (true, Some(files), Some(size)) => println!(
+                    "{} holds {} files, {}",
+                    remote.server_addr,
+                    files,
+                    format_size(size)
+                ),
+                _ => {
+                    println!("{} does not hold the upload", remote.server_addr)
+                }
+            }
+        }
+    }
+}
+
+/// The result of auditing the downloaded files of the recorded uploads.
+#[derive(Serialize)]
+pub struct CheckReport {
+    /// The directory the files were looked up in.
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub dir: PathBuf,
+    /// The uploads some of whose files are in the directory.
+    pub uploads: Vec<CheckedUpload>,
+    /// The number of uploads none of whose files are in the directory.
+    pub skipped: usize,
+    /// Whether every file of the uploads checked is verified.
+    pub verified: bool,
+}
+
+/// The files of an upload, audited.
+#[derive(Serialize)]
+pub struct CheckedUpload {
+    pub root_hash: String,
+    pub files: Vec<AuditedFile>,
+}
+
+/// A downloaded file, audited against its recorded hash and proof.
+#[derive(Serialize)]
+pub struct AuditedFile {
+    pub name: String,
+    #[serde(serialize_with = "crate::utils::serialize_escaped")]
+    pub path: PathBuf,
+    pub status: AuditStatus,
+    /// Why the file could not be verified, if it could not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// What auditing a downloaded file found.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditStatus {
+    /// The file has its recorded hash, and its proof links it to the root
+    /// hash of its upload.
+    Ok,
+    /// The file has another hash than the one recorded.
+    Modified,
+    /// The file does not exist or cannot be read.
+    Missing,
+    /// The file has no proof to verify it with, or one that does not link
+    /// it to the root hash.
+    Unverifiable,
+}
+
+impl AuditStatus {
+    /// Returns the status in the text output.
+    fn label(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Modified => "MODIFIED",
+            Self::Missing => "MISSING",
+            Self::Unverifiable => "UNVERIFIABLE",
+        }
+    }
+}
+
+impl CheckReport {
+    /// Returns the number of files checked with a status.
+    pub fn count(&self, status: AuditStatus) -> usize {
+        self.uploads
+            .iter()
+            .flat_map(|upload| &upload.files)
+            .filter(|file| file.status == status)
+            .count()
+    }
+}
+
+impl Report for CheckReport {
+    /// Prints the files that are not verified, then a summary.
+    fn print_text(&self) {
+        for upload in &self.uploads {
+            for file in &upload.files {
+                if file.status == AuditStatus::Ok {
+                    continue;
+                }
+                match &file.reason {
+                    Some(reason) => println!(
+                        "{}: {} ({}, root hash {})",
+                        file.path.display(),
+                        file.status.label(),
+                        reason,
+                        upload.root_hash
+                    ),
+                    None => println!(
+                        "{}: {} (root hash {})",
+                        file.path.display(),
+                        file.status.label(),
+                        upload.root_hash
+                    ),
+                }
+            }
+        }
+        let files = self
+            .uploads
+            .iter()
+            .map(|upload| upload.files.len())
+            .sum::<usize>();
+        println!(
+            "Checked {} files of {} uploads in {}: {} OK, {} modified, {} \
+             missing, {} unverifiable",
+            files,
+            self.uploads.len(),
+            self.dir.display(),
+            self.count(AuditStatus::Ok),
+            self.count(AuditStatus::Modified),
+            self.count(AuditStatus::Missing),
+            self.count(AuditStatus::Unverifiable)
+        );
+        if self.skipped > 0 {
+            println!(
+                "Skipped {} uploads with no files in {}",
+                self.skipped,
+                self.dir.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uploads() -> HashMap<String, Upload> {
+        let upload = |uploaded_at, files: &[(&str, u64)]| Upload {
+            files: files
+                .iter()
+                .map(|(name, size)| FileRecord {
+                    name: name.to_string(),
+                    size: Some(*size),
+                    ..Default::default()
+                })
+                .collect(),
+            uploaded_at: Some(uploaded_at),
+            server_addr: None,
+            tags: vec![],
+            signature: None,
+        };
+        HashMap::from([
+            ("aa".to_string(), upload(3, &[("b.txt", 1), ("a.gz", 9)])),
+            ("ab".to_string(), upload(1, &[("c.gz", 2)])),
+            ("ba".to_string(), upload(2, &[("d.txt", 4)])),
+        ])
+    }
+
+    fn roots(report: &ListReport) -> Vec<&str> {
+        report
+            .uploads
+            .iter()
+            .map(|l| l.root_hash.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_list_filter() {
+        let report = ListReport::new(
+            &uploads(),
+            &ListOptions {
+                filter: Some(Pattern::new("*.gz").unwrap()),
+                root: Some("a".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(roots(&report), vec!["aa", "ab"]);
+        assert_eq!(report.uploads[0].upload.files.len(), 1);
+        assert_eq!(report.uploads[0].upload.files[0].name, "a.gz");
+    }
+
+    #[test]
+    fn test_list_tags() {
+        let mut uploads = uploads();
+        let tag = |tags: &[&str]| {
+            tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>()
+        };
+        uploads.get_mut("aa").unwrap().tags = tag(&["2024-q3", "backups"]);
+        uploads.get_mut("ba").unwrap().tags = tag(&["backups"]);
+
+        let tagged = |tags: &[&str]| {
+            let options = ListOptions {
+                tags: tag(tags),
+                ..Default::default()
+            };
+            let report = ListReport::new(&uploads, &options);
+            roots(&report)
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tagged(&["backups"]), vec!["aa", "ba"]);
+        assert_eq!(tagged(&["backups", "2024-q3"]), vec!["aa"]);
+        assert!(tagged(&["photos"]).is_empty());
+    }
+
+    #[test]
+    fn test_remote_list() {
+        let mut uploads = uploads();
+        for upload in uploads.values_mut() {
+            upload.server_addr = Some("server:2345".to_string());
+        }
+        uploads.get_mut("ba").unwrap().server_addr = None;
+        let stored = |root_hash: &str, files, size| StoredUpload {
+            root_hash: root_hash.to_string(),
+            files,
+            size,
+        };
+        let report = RemoteListReport::new(
+            "server:2345",
+            vec![stored("aa", 2, 10), stored("ab", 1, 3), stored("cc", 1, 1)],
+            &uploads,
+        );
+
+        let statuses = report
+            .uploads
+            .iter()
+            .map(|upload| (upload.root_hash.as_str(), upload.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                ("aa", RemoteStatus::Recorded),
+                ("ab", RemoteStatus::Mismatch),
+                ("cc", RemoteStatus::Unrecorded),
+            ]
+        );
+
+        // Only the uploads recorded for the server can be missing from it
+        let report = RemoteListReport::new("server:2345", vec![], &uploads);
+        assert_eq!(report.uploads.len(), 2);
+        assert!(report
+            .uploads
+            .iter()
+            .all(|upload| upload.status == RemoteStatus::Missing));
+    }
+
+    #[test]
+    fn test_find() {
+        let mut uploads = uploads();
+        uploads.get_mut("ab").unwrap().files[0].name = "logs/b.txt".into();
+        let found = |pattern, held| {
+            let pattern = Pattern::new(pattern).unwrap();
+            FindReport::new(&pattern, &uploads, held)
+                .files
+                .into_iter()
+                .map(|file| (file.root_hash, file.name))
+                .collect::<Vec<_>>()
+        };
+        let found_file = |root_hash: &str, name: &str| {
+            (root_hash.to_string(), name.to_string())
+        };
+        // The latest upload first, and by base name
+        assert_eq!(
+            found("b.txt", None),
+            vec![found_file("aa", "b.txt"), found_file("ab", "logs/b.txt")]
+        );
+        assert_eq!(found("logs/*", None), vec![found_file("ab", "logs/b.txt")]);
+        assert_eq!(
+            found("*.txt", None),
+            vec![
+                found_file("aa", "b.txt"),
+                found_file("ba", "d.txt"),
+                found_file("ab", "logs/b.txt"),
+            ]
+        );
+        assert!(found("e.txt", None).is_empty());
+
+        // Only in the uploads the server holds
+        let held = vec![StoredUpload {
+            root_hash: "ab".to_string(),
+            files: 1,
+            size: 2,
+        }];
+        assert_eq!(
+            found("b.txt", Some(("server:2345".to_string(), held))),
+            vec![found_file("ab", "logs/b.txt")]
+        );
+    }
+
+    #[test]
+    fn test_list_sort() {
+        let sorted = |sort, reverse| {
+            let options = ListOptions {
+                sort: Some(sort),
+                reverse,
+                ..Default::default()
+            };
+            let report = ListReport::new(&uploads(), &options);
+            roots(&report)
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sorted(SortKey::Name, false), vec!["aa", "ab", "ba"]);
+        assert_eq!(sorted(SortKey::Date, false), vec!["ab", "ba", "aa"]);
+        assert_eq!(sorted(SortKey::Size, true), vec!["aa", "ba", "ab"]);
+    }
+}
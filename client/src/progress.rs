@@ -0,0 +1,142 @@
+use indicatif::{
+    MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use std::sync::Mutex;
+
+/// The size of the chunks in which files are sent and received, so that the
+/// progress bars advance smoothly.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The progress bars last shown, which log messages are printed above.
+static SHOWN: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Progress bars for a transfer of one or more files: one bar for the file
+/// being transferred, and one for the total bytes transferred, with the
+/// throughput and ETA.
+#[derive(Clone)]
+pub struct Progress {
+    multi: MultiProgress,
+    total: ProgressBar,
+    /// Whether the total is unknown, and grows as files are started.
+    grow: bool,
+}
+
+/// The progress bar of a single file of a transfer.
+pub struct FileProgress {
+    bar: ProgressBar,
+    total: ProgressBar,
+}
+
+impl Progress {
+    /// Creates the progress bars of a transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `total_bytes` - The number of bytes to transfer, if known. Otherwise
+    ///   the total grows as files are started.
+    /// * `hidden` - Whether to hide the progress bars. They are always hidden
+    ///   when stderr is not a terminal.
+    pub fn new(total_bytes: Option<u64>, hidden: bool) -> Self {
+        let target = if hidden {
+            ProgressDrawTarget::hidden()
+        } else {
+            ProgressDrawTarget::stderr()
+        };
+        let multi = MultiProgress::with_draw_target(target);
+        if !hidden {
+            *SHOWN.lock().unwrap() = Some(multi.clone());
+        }
+        let total = multi.add(ProgressBar::new(total_bytes.unwrap_or(0)));
+        total.set_style(
+            ProgressStyle::with_template(
+                "{prefix:>5} [{bar:40}] {bytes}/{total_bytes} \
+                 ({bytes_per_sec}, ETA {eta})",
+            )
+            .expect("valid template")
+            .progress_chars("=> "),
+        );
+        total.set_prefix("total");
+        Self {
+            multi,
+            total,
+            grow: total_bytes.is_none(),
+        }
+    }
+
+    /// Starts the progress bar of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the file, shown next to the bar.
+    /// * `size` - The size of the file in bytes.
+    pub fn file(&self, name: &str, size: u64) -> FileProgress {
+        if self.grow {
+            self.total.inc_length(size);
+        }
+        let bar = self
+            .multi
+            .insert_before(&self.total, ProgressBar::new(size));
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{prefix:>5} [{bar:40}] {bytes}/{total_bytes} {msg}",
+            )
+            .expect("valid template")
+            .progress_chars("=> "),
+        );
+        bar.set_prefix("file");
+        bar.set_message(name.to_string());
+        FileProgress {
+            bar,
+            total: self.total.clone(),
+        }
+    }
+
+    /// Removes the progress bars once the transfer is done.
+    pub fn finish(&self) {
+        self.total.finish_and_clear();
+    }
+}
+
+/// Runs `f`, e.g. printing a message, with the progress bars shown cleared
+/// so that the message is not drawn over.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    let shown = SHOWN.lock().unwrap().clone();
+    match shown {
+        Some(multi) => multi.suspend(f),
+        None => f(),
+    }
+}
+
+impl FileProgress {
+    /// Records that `bytes` more bytes of the file were transferred.
+    pub fn inc(&self, bytes: u64) {
+        self.bar.inc(bytes);
+        self.total.inc(bytes);
+    }
+
+    /// Moves the progress of the file to `bytes`, e.g. when a transfer is
+    /// resumed from the bytes the server already received, and the total
+    /// along with it.
+    pub fn set_position(&self, bytes: u64) {
+        let total = self.total.position() + bytes;
+        self.total
+            .set_position(total.saturating_sub(self.bar.position()));
+        self.bar.set_position(bytes);
+    }
+
+    /// Removes the progress bar of the file once it is transferred.
+    pub fn finish(self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+impl Drop for FileProgress {
+    /// Removes the progress bar of a file whose transfer failed, and its
+    /// bytes from the total, as the transfer is retried from scratch.
+    fn drop(&mut self) {
+        if !self.bar.is_finished() {
+            self.set_position(0);
+            self.bar.finish_and_clear();
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::Result;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// When and how often a failed network operation is retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The number of times an operation is retried after its first attempt.
+    pub retries: u32,
+    /// The delay before the first retry, doubled before each of the next
+    /// ones.
+    pub backoff: Duration,
+    /// The maximum delay between two attempts.
+    pub max_backoff: Duration,
+    /// Whether to wait a random duration of up to the delay, rather than the
+    /// delay itself, so that clients failing together do not retry together.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Runs an operation, retrying it while it fails with a transient error.
+    ///
+    /// The operation must be safe to repeat: every attempt must leave the
+    /// server in the same state as a single successful one.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - The operation, called with the number of the attempt,
+    ///   starting at 0.
+    /// * `on_retry` - Called with the error of a failed attempt before it is
+    ///   retried, e.g. to warn the user.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt, or of the first one that fails
+    /// with an error that is not transient.
+    pub async fn run<T, F: Future<Output = Result<T>>>(
+        &self,
+        mut operation: impl FnMut(u32) -> F,
+        on_retry: impl Fn(&anyhow::Error, u32),
+    ) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match operation(attempt).await {
+                Err(error)
+                    if attempt < self.retries && is_transient(&error) =>
+                {
+                    attempt += 1;
+                    on_retry(&error, attempt);
+                    tokio::time::sleep(self.delay(attempt)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Returns the delay before the given retry, starting at 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        if self.jitter {
+            delay.mul_f64(fastrand::f64())
+        } else {
+            delay
+        }
+    }
+}
+
+/// Returns whether an error is worth retrying, i.e. whether it is a network
+/// error that may not happen again, such as a connection reset.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<std::io::Error>().is_some_and(|error| {
+            matches!(
+                error.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            jitter: false,
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = policy(5);
+        let delays = (1..=4).map(|retry| policy.delay(retry));
+        assert_eq!(
+            delays.map(|d| d.as_millis()).collect::<Vec<_>>(),
+            vec![1, 2, 4, 4]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let reset = || std::io::Error::from(ErrorKind::ConnectionReset);
+        let retried = Cell::new(0);
+        let result = policy(3)
+            .run(
+                |attempt| async move {
+                    match attempt {
+                        0 | 1 => Err(reset().into()),
+                        _ => Ok(attempt),
+                    }
+                },
+                |_, _| retried.set(retried.get() + 1),
+            )
+            .await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(retried.get(), 2);
+
+        // Gives up after the last retry
+        assert!(policy(1)
+            .run(|_| async { Err::<(), _>(reset().into()) }, |_, _| {})
+            .await
+            .is_err());
+
+        // Does not retry other errors
+        let attempts = Cell::new(0);
+        let result = policy(3)
+            .run(
+                |_| {
+                    attempts.set(attempts.get() + 1);
+                    async { Err::<(), _>(anyhow::anyhow!("Invalid proof")) }
+                },
+                |_, _| {},
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
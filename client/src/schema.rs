@@ -0,0 +1,124 @@
+//! The schema of the uploads database file, and the migrations from the
+//! schemas written by earlier versions of the client.
+//!
+//! Schema versions:
+//!
+//! 1. An unversioned object mapping each root hash to either a list of file
+//!    names, or an upload record with the files metadata.
+//! 2. `{ "version": 2, "uploads": { ... } }`, mapping each root hash to an
+//!    upload record.
+use crate::db::{FileRecord, Upload};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The current schema version, written with every database.
+pub const VERSION: u32 = 2;
+
+/// The content of the database file.
+#[derive(Serialize, Deserialize)]
+pub struct Schema {
+    pub version: u32,
+    pub uploads: HashMap<String, Upload>,
+}
+
+/// Returns the schema version of a database file.
+pub fn version(value: &Value) -> u32 {
+    match value.get("version").and_then(Value::as_u64) {
+        Some(version) => version as u32,
+        // Root hashes are hex strings, so an unversioned database never has
+        // a `version` key
+        None => 1,
+    }
+}
+
+/// Migrates a database file to the current schema and returns its uploads.
+///
+/// # Errors
+///
+/// Returns an error if the file does not match its schema, or was written by
+/// a newer client.
+pub fn migrate(mut value: Value) -> Result<HashMap<String, Upload>> {
+    let version = version(&value);
+    if version > VERSION {
+        return Err(anyhow::anyhow!(
+            "schema version {} is newer than the supported version {}, \
+             please upgrade the client",
+            version,
+            VERSION
+        ));
+    }
+
+    for from in version..VERSION {
+        value = match from {
+            1 => migrate_v1(value)?,
+            _ => unreachable!("no migration from schema version {}", from),
+        };
+    }
+
+    Ok(serde_json::from_value::<Schema>(value)?.uploads)
+}
+
+/// An upload as stored in a version 1 database: either a bare list of file
+/// names, as written before metadata was recorded, or a full record.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum V1Upload {
+    Names(Vec<String>),
+    Upload(Upload),
+}
+
+/// Migrates a version 1 database to version 2.
+fn migrate_v1(value: Value) -> Result<Value> {
+    let uploads: HashMap<String, V1Upload> = serde_json::from_value(value)?;
+    let uploads = uploads
+        .into_iter()
+        .map(|(root_hash, upload)| {
+            let upload = match upload {
+                V1Upload::Names(names) => Upload {
+                    files: names
+                        .into_iter()
+                        .map(|name| FileRecord {
+                            name,
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                },
+                V1Upload::Upload(upload) => upload,
+            };
+            (root_hash, upload)
+        })
+        .collect();
+
+    Ok(serde_json::to_value(Schema {
+        version: 2,
+        uploads,
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_migrate_v1() {
+        let value = json!({
+            "root_hash1": ["file1.txt"],
+            "root_hash2": { "files": [{ "name": "file2.txt", "size": 3 }] },
+        });
+        assert_eq!(version(&value), 1);
+
+        let uploads = migrate(value).unwrap();
+        assert_eq!(uploads["root_hash1"].files[0].name, "file1.txt");
+        assert_eq!(uploads["root_hash2"].files[0].size, Some(3));
+    }
+
+    #[test]
+    fn test_migrate_newer_version() {
+        let value = json!({ "version": VERSION + 1, "uploads": {} });
+        assert!(migrate(value).is_err());
+    }
+}
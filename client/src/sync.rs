@@ -0,0 +1,254 @@
+use crate::client::Server;
+use crate::db::{Db, FileRecord};
+use crate::output::SyncReport;
+use crate::utils::{self, relative_name};
+use crate::UploadOptions;
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The files of a directory, compared with its last synced snapshot.
+#[derive(Debug, Default)]
+struct Changes {
+    /// The new and modified files, as `(path, name)` pairs.
+    changed: Vec<(PathBuf, String)>,
+    /// The names of the files matching the snapshot.
+    unchanged: Vec<String>,
+    /// The names of the files of the snapshot no longer in the directory.
+    removed: Vec<String>,
+}
+
+/// Uploads the files of a directory that are new or changed since it was
+/// last synced to the server, as a new batch recorded with its own root
+/// hash.
+///
+/// The snapshot of the directory is the latest recorded version of every
+/// file uploaded under its name to the server, whichever upload or sync
+/// recorded it. Encoded files are left out of the snapshot, as only the
+/// hash of their encoded content is recorded.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to sync, whose files are named relative to its
+///   parent like `upload` names them.
+/// * `exclude` - Patterns of files and directories to leave out.
+/// * `server` - The server to sync to.
+/// * `options` - How the changed files are uploaded.
+/// * `checksum` - Whether to compare the SHA-256 of every file with the
+///   snapshot, rather than only the files whose size or modification time
+///   differ.
+/// * `hide_progress` - Whether to hide the progress bars.
+/// * `db` - The database recording the uploads.
+///
+/// # Errors
+///
+/// Returns an error if `dir` is not a directory, a file cannot be read, or
+/// the upload fails.
+pub async fn sync(
+    dir: &Path,
+    exclude: &[Pattern],
+    server: &Server,
+    options: &UploadOptions<'_>,
+    checksum: bool,
+    hide_progress: bool,
+    db: &mut Db,
+) -> Result<SyncReport> {
+    if !dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    let prefix = format!(
+        "{}/",
+        relative_name(dir, dir.parent().unwrap_or_else(|| Path::new("")))
+    );
+    // The store directory may be in the synced directory, and changes with
+    // every sync
+    let store = db.get_db_path().canonicalize().ok();
+    let files = utils::collect_files(&[dir.to_path_buf()], exclude)?
+        .into_iter()
+        .filter(|(path, _)| {
+            store.as_ref().is_none_or(|store| {
+                !path
+                    .canonicalize()
+                    .is_ok_and(|path| path.starts_with(store))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let changes =
+        compare(files, &snapshot(db, &server.addr, &prefix), checksum)?;
+    log::info!(
+        "{} files changed, {} unchanged and {} removed since the last sync",
+        changes.changed.len(),
+        changes.unchanged.len(),
+        changes.removed.len()
+    );
+    let mut report = SyncReport {
+        dir: dir.to_path_buf(),
+        server_addr: server.addr.clone(),
+        changed: changes
+            .changed
+            .iter()
+            .map(|(_, name)| name.clone())
+            .collect(),
+        unchanged: changes.unchanged.len(),
+        removed: changes.removed,
+        upload: None,
+        dry_run: options.dry_run,
+    };
+    if !changes.changed.is_empty() && !options.dry_run {
+        let (paths, names) = changes.changed.into_iter().unzip();
+        report.upload = Some(
+            crate::upload_files(
+                paths,
+                names,
+                server,
+                options,
+                hide_progress,
+                db,
+            )
+            .await?,
+        );
+    }
+    Ok(report)
+}
+
+/// Returns the latest recorded version of every file named under `prefix`
+/// uploaded to a server, by name, leaving out encoded files.
+fn snapshot<'a>(
+    db: &'a Db,
+    server_addr: &str,
+    prefix: &str,
+) -> HashMap<&'a str, &'a FileRecord> {
+    let mut uploads = db
+        .get_uploads()
+        .values()
+        .filter(|upload| upload.server_addr.as_deref() == Some(server_addr))
+        .collect::<Vec<_>>();
+    uploads.sort_by_key(|upload| upload.uploaded_at);
+    uploads
+        .into_iter()
+        .flat_map(|upload| &upload.files)
+        .filter(|record| {
+            record.name.starts_with(prefix)
+                && !record.encrypted
+                && !record.compressed
+        })
+        .map(|record| (record.name.as_str(), record))
+        .collect()
+}
+
+/// Compares the files of a directory with its snapshot.
+///
+/// A file is unchanged if its size and SHA-256 are the ones recorded. Like
+/// `rsync`, the SHA-256 is only computed for files whose size matches but
+/// modification time does not, unless `checksum` is set.
+fn compare(
+    files: Vec<(PathBuf, String)>,
+    snapshot: &HashMap<&str, &FileRecord>,
+    checksum: bool,
+) -> Result<Changes> {
+    let names = files
+        .iter()
+        .map(|(_, name)| name.as_str())
+        .collect::<HashSet<_>>();
+    let mut removed = snapshot
+        .keys()
+        .filter(|name| !names.contains(*name))
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    removed.sort();
+
+    let mut changes = Changes {
+        removed,
+        ..Default::default()
+    };
+    for (path, name) in files {
+        let Some(record) = snapshot.get(name.as_str()) else {
+            changes.changed.push((path, name));
+            continue;
+        };
+        let metadata = fs::metadata(&path)?;
+        let unchanged = record.size == Some(metadata.len())
+            && match (checksum, record.modified) {
+                (false, Some(modified))
+                    if metadata.modified().ok().and_then(utils::unix_time)
+                        == Some(modified) =>
+                {
+                    true
+                }
+                _ => {
+                    record.sha256
+                        == Some(hex::encode(utils::sha256_file(&path)?))
+                }
+            };
+        match unchanged {
+            true => changes.unchanged.push(name),
+            false => changes.changed.push((path, name)),
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let dir = std::env::temp_dir().join("file-guardian-test-sync");
+        fs::create_dir_all(&dir).unwrap();
+        let mut files = vec![];
+        for name in ["same.txt", "touched.txt", "edited.txt", "new.txt"] {
+            fs::write(dir.join(name), name).unwrap();
+            files.push((dir.join(name), format!("dir/{}", name)));
+        }
+        let record = |name: &str, content: &str, modified| FileRecord {
+            name: format!("dir/{}", name),
+            size: Some(content.len() as u64),
+            modified,
+            sha256: Some(utils::sha256(content.as_bytes())),
+            ..Default::default()
+        };
+        let modified = |name| {
+            fs::metadata(dir.join(name))
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(utils::unix_time)
+        };
+        let records = [
+            record("same.txt", "same.txt", modified("same.txt")),
+            // Touched since, but with the same content
+            record("touched.txt", "touched.txt", Some(0)),
+            // Edited since, with the same size and modification time
+            record("edited.txt", "EDITED.txt", modified("edited.txt")),
+            record("deleted.txt", "deleted.txt", None),
+        ];
+        let snapshot = records
+            .iter()
+            .map(|record| (record.name.as_str(), record))
+            .collect();
+
+        let changes = compare(files.clone(), &snapshot, false).unwrap();
+        let changed = |changes: &Changes| -> Vec<_> {
+            changes
+                .changed
+                .iter()
+                .map(|(_, name)| name.clone())
+                .collect()
+        };
+        assert_eq!(changed(&changes), vec!["dir/new.txt"]);
+        assert_eq!(
+            changes.unchanged,
+            vec!["dir/same.txt", "dir/touched.txt", "dir/edited.txt"]
+        );
+        assert_eq!(changes.removed, vec!["dir/deleted.txt"]);
+
+        // Comparing the SHA-256 of every file finds the edit
+        let changes = compare(files, &snapshot, true).unwrap();
+        assert_eq!(changed(&changes), vec!["dir/edited.txt", "dir/new.txt"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
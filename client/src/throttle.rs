@@ -0,0 +1,123 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A bandwidth limit shared by every connection of the client, so that
+/// concurrent transfers are limited together.
+///
+/// The limit is a token bucket holding up to one second of transfer: bytes
+/// are taken from the bucket as they are sent or received, and a transfer
+/// taking more than the bucket holds waits until the bucket refills.
+#[derive(Clone)]
+pub struct Throttle {
+    /// The limit, in bytes per second.
+    rate: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    /// The bytes that can be transferred without waiting, negative when
+    /// transfers are ahead of the limit.
+    tokens: f64,
+    /// When the bucket was last refilled.
+    refilled: Instant,
+}
+
+impl Throttle {
+    /// Creates a limit, full so that transfers start at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - The limit, in bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: rate as f64,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes `bytes` from the bucket, waiting until the transfer of the bytes
+    /// no longer exceeds the limit.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let rate = self.rate as f64;
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+            (bucket.tokens < 0.0)
+                .then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parses a bandwidth limit, e.g. `10MB/s`, `512KiB/s` or `1000000`, in
+/// bytes per second. Units are decimal (`KB`, `MB`, `GB`) or binary (`KiB`,
+/// `MiB`, `GiB`), and case insensitive.
+///
+/// # Errors
+///
+/// Returns an error if the limit is not a positive number of bytes per
+/// second.
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid rate {}, expected e.g. 10MB/s", rate);
+    let lowercase = rate.trim().to_lowercase();
+    let lowercase = lowercase.strip_suffix("/s").unwrap_or(&lowercase);
+    let split = lowercase
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(lowercase.len());
+    let (number, unit) = lowercase.split_at(split);
+    let number = number.parse::<f64>().map_err(|_| invalid())?;
+    let multiplier = match unit.trim() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "ki" | "kib" => 1 << 10,
+        "mi" | "mib" => 1 << 20,
+        "gi" | "gib" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    match (number * multiplier as f64) as u64 {
+        0 => Err(invalid()),
+        rate => Ok(rate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("10MB/s"), Ok(10_000_000));
+        assert_eq!(parse_rate("1.5 KiB/s"), Ok(1536));
+        assert_eq!(parse_rate("500k"), Ok(500_000));
+        assert_eq!(parse_rate("1000"), Ok(1000));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("10Mbit/s").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        // The first second of transfer is let through at once
+        let throttle = Throttle::new(100_000);
+        let start = Instant::now();
+        throttle.consume(60_000).await;
+        throttle.clone().consume(40_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Then the transfers of every clone wait for the bucket to refill
+        throttle.clone().consume(30_000).await;
+        throttle.consume(20_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
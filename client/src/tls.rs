@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use rustls::pki_types::{
+    pem::PemObject, CertificateDer, PrivateKeyDer, ServerName,
+};
+use rustls::{ClientConfig, RootCertStore};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// The TLS settings given on the command line or in the profile.
+#[derive(Debug, Default)]
+pub struct TlsOptions<'a> {
+    /// A PEM file of the certificate authorities to trust, instead of the
+    /// Mozilla root certificates, e.g. for a self-signed server.
+    pub ca: Option<&'a Path>,
+    /// The name the certificate must be valid for, instead of the host of
+    /// the server address.
+    pub server_name: Option<&'a str>,
+    /// The PEM files of the certificate chain and private key presented to
+    /// servers requiring client certificates (mutual TLS).
+    pub identity: Option<(&'a Path, &'a Path)>,
+}
+
+/// How to establish TLS sessions with a server.
+#[derive(Clone)]
+pub struct Tls {
+    config: Arc<ClientConfig>,
+    /// The name the server certificate must be valid for.
+    server_name: ServerName<'static>,
+}
+
+impl Tls {
+    /// Creates the TLS settings of the connections to a server.
+    ///
+    /// # Arguments
+    ///
+    /// * `server_addr` - The address of the server, whose host is the name
+    ///   the certificate must be valid for unless another one is given.
+    /// * `options` - The TLS settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a certificate or key file cannot be read or
+    /// parsed, or if the server name is invalid.
+    pub fn new(server_addr: &str, options: &TlsOptions) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match options.ca {
+            Some(ca) => {
+                let certs = CertificateDer::pem_file_iter(ca)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| {
+                        anyhow!("Invalid CA file {}: {}", ca.display(), e)
+                    })?;
+                let (_, ignored) = roots.add_parsable_certificates(certs);
+                if roots.is_empty() || ignored > 0 {
+                    return Err(anyhow!("Invalid CA file {}", ca.display()));
+                }
+            }
+            None => {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned())
+            }
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = match options.identity {
+            Some((cert, key)) => {
+                let certs = CertificateDer::pem_file_iter(cert)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| {
+                        anyhow!("Invalid certificate {}: {}", cert.display(), e)
+                    })?;
+                let key = PrivateKeyDer::from_pem_file(key).map_err(|e| {
+                    anyhow!("Invalid key {}: {}", key.display(), e)
+                })?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let name = options.server_name.unwrap_or_else(|| host(server_addr));
+        let server_name = ServerName::try_from(name.to_string())
+            .map_err(|_| anyhow!("Invalid server name {}", name))?;
+        Ok(Self {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+
+    /// Establishes a TLS session over a connection to the server, verifying
+    /// its certificate.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handshake fails, e.g. if the certificate is
+    /// not trusted or not valid for the server name.
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> io::Result<TlsStream<S>> {
+        TlsConnector::from(self.config.clone())
+            .connect(self.server_name.clone(), stream)
+            .await
+    }
+}
+
+/// Returns the host of an address in the format `host:port`.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("files.example.com:2345"), "files.example.com");
+        assert_eq!(host("127.0.0.1:2345"), "127.0.0.1");
+        assert_eq!(host("[::1]:2345"), "::1");
+        assert_eq!(host("localhost"), "localhost");
+    }
+
+    #[test]
+    fn test_server_name() {
+        let options = |server_name, ca: Option<&'static str>| TlsOptions {
+            server_name,
+            ca: ca.map(Path::new),
+            ..Default::default()
+        };
+        assert!(Tls::new("127.0.0.1:2345", &options(None, None)).is_ok());
+        assert!(
+            Tls::new("localhost:2345", &options(Some("not a name"), None))
+                .is_err()
+        );
+        assert!(
+            Tls::new("localhost:2345", &options(None, Some("none"))).is_err()
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use crate::utils;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// The name of the index of the files trashed from an upload.
+const INDEX: &str = "index.json";
+
+/// The original files deleted by `upload --delete`, moved under the client
+/// store rather than removed, so that a bad server or a proof bug never
+/// loses them: `<dir>/<root hash>/` holds the files, and an index recording
+/// where each one came from.
+///
+/// Files are kept for the retention window given when they are trashed, and
+/// removed by the first purge once it has passed.
+pub struct Trash {
+    dir: PathBuf,
+}
+
+/// A file moved to the trash.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TrashedFile {
+    /// The name the file was uploaded under.
+    pub name: String,
+    /// The path the file was moved from, and is restored to.
+    #[serde(with = "utils::serde_path")]
+    pub path: PathBuf,
+    /// The name of the file in the directory of its upload in the trash.
+    slot: String,
+    /// The time the file was trashed, in seconds since the Unix epoch.
+    pub trashed_at: u64,
+    /// The time from which the file may be purged, in seconds since the Unix
+    /// epoch.
+    pub expires_at: u64,
+}
+
+/// The files of an upload in the trash.
+#[derive(Serialize, Debug)]
+pub struct TrashedUpload {
+    pub root_hash: String,
+    pub files: Vec<TrashedFile>,
+}
+
+impl Trash {
+    /// Creates a trash.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of the trash, created once a file is trashed.
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Moves the original files of an upload to the trash.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    /// * `files` - The paths of the files and the names they were uploaded
+    ///   under.
+    /// * `retention` - How long the files are kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file cannot be moved, the files moved before it
+    /// being recorded in the trash.
+    pub fn put(
+        &self,
+        root_hash: &str,
+        files: &[(PathBuf, String)],
+        retention: Duration,
+    ) -> Result<()> {
+        let dir = self.dir.join(root_hash);
+        fs::create_dir_all(&dir)?;
+        let mut trashed = self.read(root_hash)?;
+        let now = SystemTime::now();
+        let trashed_at = utils::unix_time(now).unwrap_or_default();
+        let expires_at = utils::unix_time(now + retention).unwrap_or(u64::MAX);
+        let mut result = Ok(());
+        for (path, name) in files {
+            // Record absolute paths, so that files are restored to where they
+            // were whatever the current directory
+            let path = match path.canonicalize() {
+                Ok(path) => path,
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            };
+            let slot = next_slot(&trashed);
+            if let Err(e) = move_file(&path, &dir.join(&slot)) {
+                result = Err(anyhow::anyhow!(
+                    "Could not move {} to the trash: {}",
+                    path.display(),
+                    e
+                ));
+                break;
+            }
+            log::debug!("Moved {} to the trash", path.display());
+            trashed.push(TrashedFile {
+                name: name.clone(),
+                path,
+                slot,
+                trashed_at,
+                expires_at,
+            });
+        }
+        self.write(root_hash, &trashed)?;
+        result
+    }
+
+    /// Restores trashed files to the paths they were moved from.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload of the files.
+    /// * `names` - The names of the files to restore, every file of the
+    ///   upload if empty.
+    ///
+    /// # Returns
+    ///
+    /// Returns the restored files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the upload or one of the files is not in the
+    /// trash, or if a file cannot be restored, e.g. because its path exists,
+    /// the files restored before it being removed from the trash.
+    pub fn restore(
+        &self,
+        root_hash: &str,
+        names: &[String],
+    ) -> Result<Vec<TrashedFile>> {
+        let mut trashed = self.read(root_hash)?;
+        if trashed.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Root hash {} has no files in the trash",
+                root_hash
+            ));
+        }
+        if let Some(name) = names
+            .iter()
+            .find(|name| !trashed.iter().any(|file| &file.name == *name))
+        {
+            return Err(anyhow::anyhow!(
+                "File {} of root hash {} is not in the trash",
+                name,
+                root_hash
+            ));
+        }
+
+        let dir = self.dir.join(root_hash);
+        let mut restored = vec![];
+        let mut result = Ok(());
+        let mut kept = vec![];
+        for file in trashed.drain(..) {
+            if result.is_err()
+                || !(names.is_empty() || names.contains(&file.name))
+            {
+                kept.push(file);
+                continue;
+            }
+            match restore_file(&dir.join(&file.slot), &file.path) {
+                Ok(()) => {
+                    log::debug!("Restored {}", file.path.display());
+                    restored.push(file);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    kept.push(file);
+                }
+            }
+        }
+        self.write(root_hash, &kept)?;
+        result.map(|_| restored)
+    }
+
+    /// Returns the uploads with files in the trash, sorted by root hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the trash cannot be read.
+    pub fn list(&self) -> Result<Vec<TrashedUpload>> {
+        let mut uploads = vec![];
+        for root_hash in self.root_hashes()? {
+            let files = self.read(&root_hash)?;
+            if !files.is_empty() {
+                uploads.push(TrashedUpload { root_hash, files });
+            }
+        }
+        uploads.sort_by(|a, b| a.root_hash.cmp(&b.root_hash));
+        Ok(uploads)
+    }
+
+    /// Removes the trashed files whose retention window has passed.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of files removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the trash cannot be read, or a file cannot be
+    /// removed.
+    pub fn purge(&self, now: SystemTime) -> Result<usize> {
+        let now = utils::unix_time(now).unwrap_or_default();
+        let mut purged = 0;
+        for root_hash in self.root_hashes()? {
+            let dir = self.dir.join(&root_hash);
+            let (expired, kept): (Vec<_>, Vec<_>) = self
+                .read(&root_hash)?
+                .into_iter()
+                .partition(|file| file.expires_at <= now);
+            for file in &expired {
+                match fs::remove_file(dir.join(&file.slot)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e.into())
+                    }
+                    _ => log::info!("Purged {} from the trash", file.name),
+                }
+            }
+            if !expired.is_empty() {
+                purged += expired.len();
+                self.write(&root_hash, &kept)?;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Returns the root hashes of the directories of the trash.
+    fn root_hashes(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut root_hashes = vec![];
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                root_hashes.push(entry.file_name().to_string_lossy().into());
+            }
+        }
+        Ok(root_hashes)
+    }
+
+    /// Reads the index of the files trashed from an upload, empty if it has
+    /// none.
+    fn read(&self, root_hash: &str) -> Result<Vec<TrashedFile>> {
+        let path = self.dir.join(root_hash).join(INDEX);
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the index of the files trashed from an upload, removing its
+    /// directory once it has none.
+    fn write(&self, root_hash: &str, files: &[TrashedFile]) -> Result<()> {
+        let dir = self.dir.join(root_hash);
+        if files.is_empty() {
+            return match fs::remove_dir_all(&dir) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let tmp = dir.join(format!("{}.{}.tmp", INDEX, std::process::id()));
+        fs::write(&tmp, serde_json::to_string_pretty(files)?)?;
+        fs::rename(&tmp, dir.join(INDEX))?;
+        Ok(())
+    }
+}
+
+/// Returns a name for the next file trashed from an upload, unused by the
+/// files already trashed from it.
+fn next_slot(trashed: &[TrashedFile]) -> String {
+    (0..)
+        .map(|slot: usize| slot.to_string())
+        .find(|slot| !trashed.iter().any(|file| &file.slot == slot))
+        .expect("there are fewer files than slots")
+}
+
+/// Restores a trashed file, refusing to overwrite the file at its path.
+fn restore_file(from: &Path, to: &Path) -> Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(anyhow::anyhow!(
+            "Could not restore {}, which already exists",
+            to.display()
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    move_file(from, to).map_err(|e| {
+        anyhow::anyhow!("Could not restore {}: {}", to.display(), e)
+    })
+}
+
+/// Moves a file, copying it then removing the original if it cannot be
+/// renamed, e.g. to another file system.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_trash() {
+        let dir = TempDir::new().unwrap();
+        let trash = Trash::new(dir.path().join("trash"));
+        let one = dir.path().join("one.txt");
+        let two = dir.path().join("logs").join("two.txt");
+        fs::create_dir_all(two.parent().unwrap()).unwrap();
+        fs::write(&one, "one").unwrap();
+        fs::write(&two, "two").unwrap();
+        let files = [
+            (one.clone(), "one.txt".to_string()),
+            (two.clone(), "logs/two.txt".to_string()),
+        ];
+        trash.put("root", &files, Duration::from_secs(60)).unwrap();
+        assert!(!one.exists() && !two.exists());
+        let listed = trash.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].files.len(), 2);
+
+        // Restoring never overwrites a file
+        fs::write(&one, "new").unwrap();
+        assert!(trash.restore("root", &["one.txt".to_string()]).is_err());
+        fs::remove_file(&one).unwrap();
+        assert!(trash.restore("root", &["six.txt".to_string()]).is_err());
+        let restored = trash.restore("root", &[]).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(fs::read_to_string(&one).unwrap(), "one");
+        assert_eq!(fs::read_to_string(&two).unwrap(), "two");
+        assert!(trash.list().unwrap().is_empty());
+        assert!(trash.restore("root", &[]).is_err());
+
+        // Files are purged once their retention window has passed
+        trash.put("root", &files, Duration::from_secs(60)).unwrap();
+        assert_eq!(trash.purge(SystemTime::now()).unwrap(), 0);
+        let later = SystemTime::now() + Duration::from_secs(61);
+        assert_eq!(trash.purge(later).unwrap(), 2);
+        assert!(trash.list().unwrap().is_empty());
+        assert!(!dir.path().join("trash").join("root").exists());
+    }
+}
@@ -0,0 +1,396 @@
+use crate::anchors::Anchors;
+use crate::cache::ProofCache;
+use crate::cli::SortKey;
+use crate::client::Server;
+use crate::crypto::Key;
+use crate::db::Db;
+use crate::output::{ListOptions, ListReport, ListedUpload};
+use crate::utils::{format_size, format_time};
+use crate::DownloadOptions;
+use anyhow::{anyhow, Result};
+use console::{style, truncate_str, Key as KeyPress, Term};
+use std::collections::HashMap;
+
+/// The keys of the uploads view.
+const UPLOADS_HELP: &str = "↑/↓ move  enter files  d download  v verify  \
+                            x delete  q quit";
+
+/// The keys of the files view.
+const FILES_HELP: &str = "↑/↓ move  d download  esc back  q quit";
+
+/// What the TUI shows.
+#[derive(PartialEq)]
+enum View {
+    Uploads,
+    /// The files of the selected upload.
+    Files,
+}
+
+/// The state of the TUI.
+struct State {
+    /// The recorded uploads, newest first.
+    uploads: Vec<ListedUpload>,
+    view: View,
+    /// The selected upload.
+    upload: usize,
+    /// The selected file, in the files view.
+    file: usize,
+    /// The first row shown, when the rows do not fit in the terminal.
+    offset: usize,
+    /// The results of the verifications, by root hash.
+    verified: HashMap<String, String>,
+    /// The result of the last action.
+    message: String,
+}
+
+impl State {
+    fn new(db: &Db) -> Self {
+        let options = ListOptions {
+            sort: Some(SortKey::Date),
+            reverse: true,
+            ..Default::default()
+        };
+        Self {
+            uploads: ListReport::new(db.get_uploads(), &options).uploads,
+            view: View::Uploads,
+            upload: 0,
+            file: 0,
+            offset: 0,
+            verified: HashMap::new(),
+            message: String::new(),
+        }
+    }
+
+    /// Returns the number of rows of the view, and the selected one.
+    fn rows(&self) -> (usize, usize) {
+        match self.view {
+            View::Uploads => (self.uploads.len(), self.upload),
+            View::Files => {
+                (self.uploads[self.upload].upload.files.len(), self.file)
+            }
+        }
+    }
+
+    /// Moves the selection by `delta` rows, within the view.
+    fn select(&mut self, delta: isize) {
+        let (rows, selected) = self.rows();
+        let selected = selected
+            .saturating_add_signed(delta)
+            .min(rows.saturating_sub(1));
+        match self.view {
+            View::Uploads => self.upload = selected,
+            View::Files => self.file = selected,
+        }
+    }
+}
+
+/// Runs the TUI, browsing the recorded uploads and their files, and
+/// downloading, verifying or deleting them, until the user quits.
+///
+/// # Arguments
+///
+/// * `server` - The server the actions are run against.
+/// * `key` - The key to decrypt downloaded files with, if any.
+/// * `anchors` - The root hashes downloads and verifications must chain
+///   to, if any.
+/// * `cache` - The cache of the proofs of the files.
+/// * `db` - The database recording the uploads.
+///
+/// # Errors
+///
+/// Returns an error if stdout is not a terminal, or the terminal cannot be
+/// read or written. Actions failing are reported in the TUI.
+pub async fn run(
+    server: &Server,
+    key: Option<&Key>,
+    anchors: Option<&Anchors>,
+    cache: &ProofCache,
+    db: &mut Db,
+) -> Result<()> {
+    let term = Term::stdout();
+    if !term.is_term() {
+        return Err(anyhow!("The TUI requires a terminal"));
+    }
+    term.hide_cursor()?;
+    let result = run_loop(&term, server, key, anchors, cache, db).await;
+    term.clear_screen()?;
+    term.show_cursor()?;
+    result
+}
+
+async fn run_loop(
+    term: &Term,
+    server: &Server,
+    key: Option<&Key>,
+    anchors: Option<&Anchors>,
+    cache: &ProofCache,
+    db: &mut Db,
+) -> Result<()> {
+    let mut state = State::new(db);
+    loop {
+        draw(term, &mut state)?;
+        let pressed = read_key(term).await?;
+        let selected = state.uploads.get(state.upload).cloned();
+        match (&state.view, pressed, selected) {
+            (_, KeyPress::Char('q'), _) => return Ok(()),
+            (View::Uploads, KeyPress::Escape, _) => return Ok(()),
+            (_, KeyPress::ArrowUp | KeyPress::Char('k'), _) => state.select(-1),
+            (_, KeyPress::ArrowDown | KeyPress::Char('j'), _) => {
+                state.select(1)
+            }
+            (_, KeyPress::PageUp, _) => state.select(-10),
+            (_, KeyPress::PageDown, _) => state.select(10),
+            (View::Uploads, KeyPress::Enter | KeyPress::Char('l'), Some(_)) => {
+                state.view = View::Files;
+                state.file = 0;
+                state.offset = 0;
+            }
+            (
+                View::Files,
+                KeyPress::Escape | KeyPress::Backspace | KeyPress::Char('h'),
+                _,
+            ) => {
+                state.view = View::Uploads;
+                state.offset = 0;
+            }
+            (View::Uploads, KeyPress::Char('d'), Some(listed)) => {
+                state.message = format!("Downloading {}...", listed.root_hash);
+                draw(term, &mut state)?;
+                state.message = match crate::download_all(
+                    &listed.root_hash,
+                    server,
+                    &download_options(key, anchors, cache),
+                    db,
+                )
+                .await
+                {
+                    Ok(report) => format!(
+                        "Downloaded {} files to {}",
+                        report.files.len(),
+                        report.dir.display()
+                    ),
+                    Err(e) => format!("Error: {:#}", e),
+                };
+            }
+            (View::Files, KeyPress::Char('d'), Some(listed)) => {
+                let name = &listed.upload.files[state.file].name;
+                state.message = format!("Downloading {}...", name);
+                draw(term, &mut state)?;
+                state.message = match crate::download(
+                    &listed.root_hash,
+                    std::slice::from_ref(name),
+                    server,
+                    &download_options(key, anchors, cache),
+                    db,
+                )
+                .await
+                {
+                    Ok(report) => format!(
+                        "Downloaded {} to {}",
+                        name,
+                        report.files[0].path.display()
+                    ),
+                    Err(e) => format!("Error: {:#}", e),
+                };
+            }
+            (View::Uploads, KeyPress::Char('v'), Some(listed)) => {
+                state.message = format!("Verifying {}...", listed.root_hash);
+                draw(term, &mut state)?;
+                let verified = match verify(&listed, server, anchors).await {
+                    Ok(()) => "verified".to_string(),
+                    Err(e) => format!("failed: {:#}", e),
+                };
+                state.message = format!("{}: {}", listed.root_hash, verified);
+                state.verified.insert(listed.root_hash, verified);
+            }
+            (View::Uploads, KeyPress::Char('x'), Some(listed)) => {
+                state.message = format!(
+                    "Delete {} from the server and the database? (y/n)",
+                    listed.root_hash
+                );
+                draw(term, &mut state)?;
+                if read_key(term).await? != KeyPress::Char('y') {
+                    state.message.clear();
+                    continue;
+                }
+                state.message =
+                    match crate::delete(&listed.root_hash, server, cache, db)
+                        .await
+                    {
+                        Ok(_) => {
+                            state.uploads.remove(state.upload);
+                            state.select(0);
+                            format!("Deleted {}", listed.root_hash)
+                        }
+                        Err(e) => format!("Error: {:#}", e),
+                    };
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Downloads to where the `download` and `download-all` commands do by
+/// default, without replacing existing files nor showing progress bars.
+fn download_options<'a>(
+    key: Option<&'a Key>,
+    anchors: Option<&'a Anchors>,
+    cache: &'a ProofCache,
+) -> DownloadOptions<'a> {
+    DownloadOptions {
+        out: None,
+        overwrite: false,
+        key,
+        anchors,
+        hide_progress: true,
+        jobs: 1,
+        cache,
+    }
+}
+
+/// Checks that the server serves, for every file of an upload, a proof
+/// linking its recorded hash to the root hash, which must be anchored if
+/// there are trust anchors.
+async fn verify(
+    listed: &ListedUpload,
+    server: &Server,
+    anchors: Option<&Anchors>,
+) -> Result<()> {
+    if let Some(anchors) = anchors {
+        anchors.check(&listed.root_hash)?;
+    }
+    let (tree, names) =
+        crate::recorded_tree(&listed.root_hash, &listed.upload)?;
+    crate::verify_upload(&tree, &names, server, None).await
+}
+
+/// Reads a key press without blocking the runtime.
+async fn read_key(term: &Term) -> Result<KeyPress> {
+    let term = term.clone();
+    Ok(tokio::task::spawn_blocking(move || term.read_key()).await??)
+}
+
+/// Draws the view, scrolled so that the selected row is shown.
+fn draw(term: &Term, state: &mut State) -> Result<()> {
+    let (height, width) = term.size();
+    let width = width as usize;
+    // The title and the message and help lines, each followed by a blank
+    let height = (height as usize).saturating_sub(4).max(1);
+    let (rows, selected) = state.rows();
+    state.offset = scroll(selected, state.offset, height);
+
+    let (title, help) = match state.view {
+        View::Uploads => {
+            (format!("{} uploads", state.uploads.len()), UPLOADS_HELP)
+        }
+        View::Files => {
+            let listed = &state.uploads[state.upload];
+            (
+                format!(
+                    "Upload {}, {} files",
+                    listed.root_hash,
+                    listed.upload.files.len()
+                ),
+                FILES_HELP,
+            )
+        }
+    };
+    term.clear_screen()?;
+    term.write_line(
+        &style(truncate_str(&title, width, "…")).bold().to_string(),
+    )?;
+    term.write_line("")?;
+    for row in state.offset..rows.min(state.offset + height) {
+        let line = match state.view {
+            View::Uploads => upload_row(&state.uploads[row], &state.verified),
+            View::Files => file_row(&state.uploads[state.upload], row),
+        };
+        let line = truncate_str(&line, width, "…");
+        match row == selected {
+            true => term.write_line(&style(line).reverse().to_string())?,
+            false => term.write_line(&line)?,
+        }
+    }
+    if rows == 0 {
+        term.write_line("No uploads are recorded")?;
+    }
+    term.write_line("")?;
+    term.write_line(&truncate_str(&state.message, width, "…"))?;
+    term.write_line(&style(truncate_str(help, width, "…")).dim().to_string())?;
+    Ok(())
+}
+
+/// Returns the first row to show for the selected row to be one of the
+/// `height` rows shown, scrolling as little as possible.
+fn scroll(selected: usize, offset: usize, height: usize) -> usize {
+    if selected < offset {
+        selected
+    } else if selected >= offset + height {
+        selected + 1 - height
+    } else {
+        offset
+    }
+}
+
+/// Describes an upload: its root hash, date, number of files, size, tags
+/// and verification.
+fn upload_row(
+    listed: &ListedUpload,
+    verified: &HashMap<String, String>,
+) -> String {
+    let upload = &listed.upload;
+    let mut row = format!(
+        "{}  {:<20}  {:>5} files  {:>10}",
+        listed.root_hash.get(..16).unwrap_or(&listed.root_hash),
+        upload.uploaded_at.map_or("-".to_string(), format_time),
+        upload.files.len(),
+        upload.size().map_or("-".to_string(), format_size),
+    );
+    if !upload.tags.is_empty() {
+        row.push_str(&format!("  [{}]", upload.tags.join(", ")));
+    }
+    if let Some(verified) = verified.get(&listed.root_hash) {
+        row.push_str(&format!("  {}", verified));
+    }
+    row
+}
+
+/// Describes a file: its name, size, SHA-256, encoding and whether its
+/// proof is recorded.
+fn file_row(listed: &ListedUpload, index: usize) -> String {
+    let file = &listed.upload.files[index];
+    let mut row = format!(
+        "{:<40}  {:>10}  {}",
+        file.name,
+        file.size.map_or("-".to_string(), format_size),
+        file.sha256
+            .as_deref()
+            .map_or("-", |sha256| sha256.get(..16).unwrap_or(sha256)),
+    );
+    for (flag, name) in [
+        (file.compressed, "compressed"),
+        (file.encrypted, "encrypted"),
+        (file.proof.is_some(), "proof recorded"),
+    ] {
+        if flag {
+            row.push_str(&format!("  {}", name));
+        }
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scroll() {
+        // The selected row is already shown
+        assert_eq!(scroll(3, 0, 10), 0);
+        assert_eq!(scroll(12, 5, 10), 5);
+        // Moving below or above the rows shown
+        assert_eq!(scroll(10, 0, 10), 1);
+        assert_eq!(scroll(2, 5, 10), 2);
+        assert_eq!(scroll(0, 0, 1), 0);
+    }
+}
@@ -1,29 +1,497 @@
+use crate::mmap::Mmap;
 use anyhow::Result;
+use glob::Pattern;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
-    fs::File,
-    io::Read,
-    path::PathBuf,
+    collections::HashSet,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// The size from which files are memory mapped to be hashed, rather than
+/// copied through a buffer, mapping small files costing more than reading
+/// them.
+pub const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Returns the platform specific directory where the client keeps its state
+/// (e.g. `~/.local/share/file-guardian` on Linux, `%APPDATA%` on Windows),
+/// falling back to `downloads` in the current directory if it cannot be
+/// determined.
+pub fn default_store_dir() -> PathBuf {
+    directories::ProjectDirs::from("", "", "file-guardian")
+        .map(|dirs| dirs.data_dir().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("downloads"))
+}
+
 /// Remove duplicate elements from a vector.
 pub fn dedup<T: Eq + std::hash::Hash + Clone>(vec: Vec<T>) -> Vec<T> {
     let mut set = HashSet::new();
     vec.into_iter().filter(|e| set.insert(e.clone())).collect()
 }
 
-/// Pretty print of a HashMap of root hashes and files.
-pub fn print_uploads(uploads: &HashMap<String, Vec<String>>) {
-    println!("Root hashes and files:");
-    for (root_hash, files) in uploads {
-        println!("  {}: {:?}", root_hash, files);
+/// Normalize the tags given on the command line: trim them, then sort them
+/// and remove duplicates.
+///
+/// # Errors
+///
+/// Returns an error if a tag is empty.
+pub fn tags(tags: Vec<String>) -> Result<Vec<String>> {
+    let mut tags = tags
+        .into_iter()
+        .map(|tag| match tag.trim() {
+            "" => Err(anyhow::anyhow!("Tags cannot be empty")),
+            tag => Ok(tag.to_string()),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    tags.sort();
+    tags.dedup();
+    Ok(tags)
+}
+
+/// Format a size in bytes for humans, e.g. `1.5 KiB`.
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Format a time in seconds since the Unix epoch as an RFC 3339 date.
+pub fn format_time(secs: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs))
+        .to_string()
+}
+
+/// Convert a time to seconds since the Unix epoch.
+pub fn unix_time(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// Compute the SHA-256 of some data, as a hex string.
+pub fn sha256(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Compute the SHA-256 of a file, memory mapped if it is at least
+/// [`MMAP_THRESHOLD`] bytes, streamed from disk otherwise or if it cannot be
+/// mapped.
+pub fn sha256_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    match file.metadata()?.len() >= MMAP_THRESHOLD {
+        true => match Mmap::map(&file) {
+            Ok(mmap) => hasher.update(&*mmap),
+            Err(e) => {
+                log::debug!("Reading {}, not mapped: {}", path.display(), e);
+                std::io::copy(&mut file, &mut hasher)?;
+            }
+        },
+        false => {
+            std::io::copy(&mut file, &mut hasher)?;
+        }
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Decode a SHA-256 from a hex string.
+pub fn decode_hash(hash: &str) -> Result<[u8; 32]> {
+    hex::decode(hash)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid hash {}", hash))
+}
+
+/// A temporary directory, removed with its content when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a new temporary directory, unique to the process.
+    pub fn new() -> Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "file-guardian-{}-{}-{}",
+            std::process::id(),
+            unix_time(SystemTime::now()).unwrap_or_default(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self(dir))
+    }
+
+    /// Returns the path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Expand the given paths into the list of regular files to upload.
+///
+/// Paths containing glob metacharacters (`*`, `?`, `[`) are expanded first,
+/// e.g. `logs/**/*.gz`. Files are kept as is and recorded under their file
+/// name. Directories are walked recursively and every regular file found is
+/// recorded under its path relative to the directory's parent, so that the
+/// directory layout can be restored on download.
+///
+/// # Arguments
+///
+/// * `paths` - The files, directories and glob patterns given on the command
+///   line.
+/// * `exclude` - Patterns of files and directories to leave out.
+///
+/// # Returns
+///
+/// Returns a vector of `(path, name)` pairs, where `name` is the `/`
+/// separated relative path under which the file is recorded.
+///
+/// # Errors
+///
+/// Returns an error if a path does not exist, a pattern is invalid or does
+/// not match anything, or a directory cannot be read.
+pub fn collect_files(
+    paths: &[PathBuf],
+    exclude: &[Pattern],
+) -> Result<Vec<(PathBuf, String)>> {
+    let mut files = vec![];
+    for path in paths {
+        for path in expand_glob(path)? {
+            let base = path.parent().unwrap_or_else(|| Path::new(""));
+            walk(&path, base, exclude, &mut files)?;
+        }
     }
+    Ok(files)
 }
 
-/// Read a file from a path and return its content as a vector of bytes.
-pub fn read(path: &PathBuf) -> Result<Vec<u8>> {
-    let mut file = File::open(path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    Ok(buffer)
+/// Returns the `/` separated path of a file relative to `base`, under which
+/// it is recorded, with the bytes that are not valid UTF-8 escaped by
+/// [`escape_bytes`].
+pub fn relative_name(path: &Path, base: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .components()
+        .map(|c| escape_name(c.as_os_str()))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Returns the bytes of the name a file is recorded under, hex encoded, if
+/// its path is not valid UTF-8: the last components of the path, as many as
+/// the name has, joined by `/`.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file.
+/// * `name` - The name of the file, made by [`relative_name`].
+pub fn name_bytes(path: &Path, name: &str) -> Option<String> {
+    if path.to_str().is_some() {
+        return None;
+    }
+    let components = path.components().collect::<Vec<_>>();
+    let count = name.split('/').count().min(components.len());
+    let bytes = components[components.len() - count..]
+        .iter()
+        .map(|c| os_bytes(c.as_os_str()))
+        .collect::<Vec<_>>()
+        .join(&b'/');
+    Some(hex::encode(bytes))
+}
+
+/// Returns a name, with the bytes that are not valid UTF-8 escaped by
+/// [`escape_bytes`].
+pub fn escape_name(name: &OsStr) -> String {
+    match name.to_str() {
+        Some(name) => name.to_string(),
+        None => escape_bytes(&os_bytes(name)),
+    }
+}
+
+/// Returns bytes as a string, escaping the bytes that are not valid UTF-8
+/// as `\xNN`, so that names that are not UTF-8 stay distinct, unlike with
+/// replacement characters.
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        escaped.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped
+}
+
+/// Returns the bytes of a name, as on the file system. Names are UTF-16 on
+/// Windows, so only their UTF-8 conversion is available there.
+fn os_bytes(name: &OsStr) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        name.as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        name.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// Converts the bytes of a name, as returned by [`name_bytes`], into a path.
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// Serializes paths as strings when they are valid UTF-8, as JSON strings
+/// must be, and as the hex of their bytes otherwise, e.g.
+/// `{"hex": "61ff"}`, so that they are read back as they were.
+pub mod serde_path {
+    use super::{os_bytes, path_from_bytes};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::{Path, PathBuf};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Utf8(String),
+        Bytes { hex: String },
+    }
+
+    pub fn serialize<S: Serializer>(
+        path: &Path,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => Repr::Utf8(path.to_string()),
+            None => Repr::Bytes {
+                hex: hex::encode(os_bytes(path.as_os_str())),
+            },
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PathBuf, D::Error> {
+        match Repr::deserialize(deserializer)? {
+            Repr::Utf8(path) => Ok(PathBuf::from(path)),
+            Repr::Bytes { hex } => hex::decode(&hex)
+                .map(|bytes| path_from_bytes(&bytes))
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Serializes a path as a string, with the bytes that are not valid UTF-8
+/// escaped by [`escape_bytes`], for the reports of the commands.
+pub fn serialize_escaped<S: serde::Serializer>(
+    path: &Path,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&escape_bytes(&os_bytes(path.as_os_str())))
+}
+
+/// Expand a path containing glob metacharacters into the paths it matches.
+/// Other paths are returned as is.
+fn expand_glob(path: &Path) -> Result<Vec<PathBuf>> {
+    let pattern = path.to_string_lossy();
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let paths = glob::glob(&pattern)?.collect::<Result<Vec<_>, _>>()?;
+    if paths.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Pattern {} did not match any files",
+            pattern
+        ));
+    }
+    Ok(paths)
+}
+
+/// Recursively collect the regular files under `path`, naming them relative
+/// to `base` and skipping the ones matching an `exclude` pattern.
+fn walk(
+    path: &Path,
+    base: &Path,
+    exclude: &[Pattern],
+    files: &mut Vec<(PathBuf, String)>,
+) -> Result<()> {
+    if exclude.iter().any(|pattern| pattern.matches_path(path)) {
+        return Ok(());
+    }
+
+    let metadata = std::fs::metadata(path)?;
+    if metadata.is_dir() {
+        // Sort the entries so that the order of the leaves, and therefore the
+        // root hash, does not depend on the file system
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for entry in entries {
+            walk(&entry, base, exclude, files)?;
+        }
+    } else if metadata.is_file() {
+        files.push((path.to_path_buf(), relative_name(path, base)));
+    }
+    Ok(())
+}
+
+/// Check that a recorded file name, as a path, is relative, refusing names
+/// that would escape the directory they are restored into.
+pub fn safe_relative_path(path: &Path) -> Result<PathBuf> {
+    if path
+        .components()
+        .all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        Ok(path.to_path_buf())
+    } else {
+        Err(anyhow::anyhow!("Invalid file name {}", path.display()))
+    }
+}
+
+/// Returns whether a path is a directory or in it, once both are absolute
+/// and the parts of them that exist are resolved, e.g. through symbolic
+/// links, so that a file about to be written can be checked.
+pub fn is_within(path: &Path, dir: &Path) -> Result<bool> {
+    Ok(resolve(path)?.starts_with(resolve(dir)?))
+}
+
+/// Returns a path made absolute, with its longest existing ancestor
+/// canonicalized and the `..` of the rest removed.
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    let mut existing = path.as_path();
+    let mut resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            Err(_) => match existing.parent() {
+                Some(parent) if existing.file_name().is_some() => {
+                    existing = parent
+                }
+                _ => break existing.to_path_buf(),
+            },
+        }
+    };
+    for component in path
+        .strip_prefix(existing)
+        .unwrap_or(Path::new(""))
+        .components()
+    {
+        match component {
+            std::path::Component::ParentDir => {
+                resolved.pop();
+            }
+            std::path::Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn test_collect_files() {
+        let dir = PathBuf::from("test_collect/my-dir");
+        create_dir_all(dir.join("sub")).unwrap();
+        write(dir.join("b.txt"), b"b").unwrap();
+        write(dir.join("sub/a.txt"), b"a").unwrap();
+        write("test_collect/c.txt", b"c").unwrap();
+
+        let files = collect_files(
+            &[dir.clone(), PathBuf::from("test_collect/c.txt")],
+            &[],
+        )
+        .unwrap();
+        let names = files.iter().map(|(_, n)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["my-dir/b.txt", "my-dir/sub/a.txt", "c.txt"]);
+        assert_eq!(files[1].0, dir.join("sub/a.txt"));
+
+        // Glob patterns are expanded, and excluded paths are skipped
+        let files = collect_files(
+            &[PathBuf::from("test_collect/**/*.txt")],
+            &[Pattern::new("**/b.txt").unwrap()],
+        )
+        .unwrap();
+        let names = files.iter().map(|(_, n)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["c.txt", "a.txt"]);
+
+        assert!(
+            collect_files(&[PathBuf::from("test_collect/*.gz")], &[]).is_err()
+        );
+
+        remove_dir_all("test_collect").unwrap();
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(12), "12 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_safe_relative_path() {
+        assert!(safe_relative_path(Path::new("dir/sub/a.txt")).is_ok());
+        assert!(safe_relative_path(Path::new("../a.txt")).is_err());
+        assert!(safe_relative_path(Path::new("/etc/passwd")).is_err());
+
+        let store = Path::new("test_is_within/store");
+        create_dir_all(store).unwrap();
+        let within = |path: &str| is_within(Path::new(path), store).unwrap();
+        assert!(within("test_is_within/store/uploads.json"));
+        assert!(within("test_is_within/store/new/../uploads.json"));
+        assert!(within("test_is_within/./store"));
+        assert!(!within("test_is_within/out/uploads.json"));
+        assert!(!within("test_is_within/store/../store-2/a.txt"));
+        remove_dir_all("test_is_within").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"/data/caf\xe9/a\xff.txt"));
+        let name = relative_name(path, Path::new("/data"));
+        assert_eq!(name, "caf\\xe9/a\\xff.txt");
+        let bytes = hex::decode(name_bytes(path, &name).unwrap()).unwrap();
+        assert_eq!(bytes, b"caf\xe9/a\xff.txt");
+        assert_eq!(
+            path_from_bytes(&bytes),
+            path.strip_prefix("/data").unwrap()
+        );
+        assert_eq!(name_bytes(Path::new("/data/a.txt"), "a.txt"), None);
+
+        let json = serde_json::to_string(&Wrapper(path.to_path_buf())).unwrap();
+        assert_eq!(json, r#"{"hex":"2f646174612f636166e92f61ff2e747874"}"#);
+        assert_eq!(serde_json::from_str::<Wrapper>(&json).unwrap().0, path);
+        let json = serde_json::to_string(&Wrapper("a.txt".into())).unwrap();
+        assert_eq!(json, r#""a.txt""#);
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Wrapper(#[serde(with = "serde_path")] PathBuf);
 }
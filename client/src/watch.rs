@@ -0,0 +1,165 @@
+use crate::utils::relative_name;
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Watches a directory and uploads the files created or modified in it, in
+/// batches.
+///
+/// Changes are collected until none happened for `debounce`, so that a file
+/// being written, or many files being copied, end up in a single batch once
+/// they are complete. A batch that fails to upload is retried with the next
+/// one. This only returns if the directory can no longer be watched.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to watch, recursively.
+/// * `exclude` - Patterns of files and directories to leave out.
+/// * `ignore` - A directory whose changes are ignored, e.g. the store
+///   directory, so that recording a batch does not trigger another one.
+/// * `debounce` - How long to wait for changes to settle.
+/// * `upload` - Uploads a batch of `(path, name)` pairs, named like
+///   [`crate::utils::collect_files`] names the files of a directory.
+///
+/// # Errors
+///
+/// Returns an error if the directory cannot be watched.
+pub async fn watch(
+    dir: &Path,
+    exclude: &[Pattern],
+    ignore: &Path,
+    debounce: Duration,
+    mut upload: impl AsyncFnMut(Vec<(PathBuf, String)>) -> Result<()>,
+) -> Result<()> {
+    let canonical = dir
+        .canonicalize()
+        .map_err(|e| anyhow!("Could not watch {}: {}", dir.display(), e))?;
+    let ignore = ignore.canonicalize().unwrap_or(ignore.to_path_buf());
+
+    // The watcher sends the events from its own thread
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    })?;
+    watcher.watch(&canonical, RecursiveMode::Recursive)?;
+
+    let mut pending = BTreeSet::new();
+    loop {
+        // Only wait for the changes to settle once there are some
+        let event = match pending.is_empty() {
+            true => Ok(receiver.recv().await),
+            false => tokio::time::timeout(debounce, receiver.recv()).await,
+        };
+        match event {
+            Ok(Some(event)) => {
+                let event = event?;
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_)
+                ) {
+                    pending.extend(
+                        event
+                            .paths
+                            .into_iter()
+                            .filter(|path| !path.starts_with(&ignore)),
+                    );
+                }
+            }
+            Err(_) => {
+                let files = batch(&pending, dir, &canonical, exclude);
+                if files.is_empty() {
+                    pending.clear();
+                    continue;
+                }
+                match upload(files).await {
+                    Ok(()) => pending.clear(),
+                    Err(e) => log::error!("{:#}", e),
+                }
+            }
+            Ok(None) => {
+                return Err(anyhow!("Stopped watching {}", dir.display()))
+            }
+        }
+    }
+}
+
+/// Returns the files to upload among the changed paths: the regular files
+/// that still exist and are not excluded, named relative to the parent of
+/// the watched directory.
+///
+/// # Arguments
+///
+/// * `changed` - The changed paths, under `canonical`.
+/// * `dir` - The watched directory, as given on the command line, which the
+///   exclude patterns are matched against.
+/// * `canonical` - The canonical path of `dir`.
+/// * `exclude` - Patterns of files and directories to leave out.
+fn batch(
+    changed: &BTreeSet<PathBuf>,
+    dir: &Path,
+    canonical: &Path,
+    exclude: &[Pattern],
+) -> Vec<(PathBuf, String)> {
+    let base = dir.parent().unwrap_or_else(|| Path::new(""));
+    changed
+        .iter()
+        .filter(|path| path.is_file())
+        .filter_map(|path| Some(dir.join(path.strip_prefix(canonical).ok()?)))
+        .filter(|path| {
+            // A file is excluded if it, or any directory it is in, matches
+            path.ancestors()
+                .take_while(|ancestor| ancestor.starts_with(dir))
+                .all(|ancestor| {
+                    !exclude
+                        .iter()
+                        .any(|pattern| pattern.matches_path(ancestor))
+                })
+        })
+        .map(|path| {
+            let name = relative_name(&path, base);
+            (path, name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_batch() {
+        let dir = std::env::temp_dir().join("file-guardian-test-watch");
+        fs::create_dir_all(dir.join("logs/tmp")).unwrap();
+        for file in ["a.txt", "logs/b.log", "logs/tmp/c.log"] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        let canonical = dir.canonicalize().unwrap();
+        let changed = ["a.txt", "logs/b.log", "logs/tmp/c.log", "deleted"]
+            .iter()
+            .map(|file| canonical.join(file))
+            .chain([canonical.join("logs")])
+            .collect();
+        let exclude = [Pattern::new("**/tmp").unwrap()];
+
+        let names = batch(&changed, &dir, &canonical, &exclude)
+            .into_iter()
+            .map(|(path, name)| {
+                assert!(path.starts_with(&dir));
+                name
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "file-guardian-test-watch/a.txt",
+                "file-guardian-test-watch/logs/b.log"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -8,4 +8,6 @@ pub enum MerkleTreeError {
     InvalidIndex,
     #[error("Invalid proof")]
     InvalidProof,
+    #[error("Invalid encoding: {0}")]
+    InvalidEncoding(&'static str),
 }
@@ -33,6 +33,8 @@
 //! * [Merkle tree - Wikipedia](https://en.wikipedia.org/wiki/Merkle_tree)
 //! * [Mastering Bitcoin: Unlocking Digital Cryptocurrencies](https://www.oreilly.com/library/view/mastering-bitcoin/9781491902639/ch07.html)
 mod error;
+mod proof;
 mod tree;
 
+pub use proof::*;
 pub use tree::*;
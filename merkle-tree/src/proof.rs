@@ -0,0 +1,148 @@
+use crate::tree::{Hash, MerkleTree};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// The version of the serialization format of proofs.
+pub const PROOF_VERSION: u32 = 1;
+
+/// A Merkle proof along with the leaf and the root it links, so that it can
+/// be saved and later verified on its own, e.g. by an auditor already
+/// holding the data block.
+///
+/// Proofs serialize with the hashes as hex strings:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "root": "5891b5b5...",
+///   "index": 0,
+///   "leaf": "b9968f26...",
+///   "hashes": ["6b2f6b1e..."]
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proof {
+    /// The version of the serialization format.
+    pub version: u32,
+    /// The root hash of the Merkle tree.
+    #[serde(with = "hex_hash")]
+    pub root: Hash,
+    /// The index of the data block in the tree.
+    pub index: usize,
+    /// The hash of the data block.
+    #[serde(with = "hex_hash")]
+    pub leaf: Hash,
+    /// The sibling hashes from the leaf up to the root.
+    #[serde(with = "hex_hashes")]
+    pub hashes: Vec<Hash>,
+}
+
+impl Proof {
+    /// Creates the proof of the data block at the given index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the data block.
+    /// * `leaf` - The hash of the data block.
+    /// * `root` - The root hash of the Merkle tree.
+    /// * `hashes` - The Merkle proof of the data block.
+    pub fn new(
+        index: usize,
+        leaf: Hash,
+        root: Hash,
+        hashes: Vec<Hash>,
+    ) -> Self {
+        Self {
+            version: PROOF_VERSION,
+            root,
+            index,
+            leaf,
+            hashes,
+        }
+    }
+
+    /// Verifies that the proof links its leaf to its root.
+    ///
+    /// # Returns
+    ///
+    /// Returns a boolean indicating whether the proof is valid or not.
+    pub fn verify(&self) -> bool {
+        MerkleTree::verify_leaf(
+            self.index,
+            &self.leaf,
+            &self.root,
+            &self.hashes,
+        )
+    }
+}
+
+/// Serializes a hash as a hex string.
+mod hex_hash {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        hash: &Hash,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Hash, D::Error> {
+        decode(&String::deserialize(deserializer)?)
+    }
+
+    pub fn decode<E: de::Error>(hash: &str) -> Result<Hash, E> {
+        hex::decode(hash)
+            .ok()
+            .and_then(|hash| hash.try_into().ok())
+            .ok_or_else(|| E::custom(format!("invalid hash {}", hash)))
+    }
+}
+
+/// Serializes hashes as hex strings.
+mod hex_hashes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        hashes: &[Hash],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(hashes.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Hash>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|hash| hex_hash::decode(hash))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proof() {
+        let data = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
+        let tree = MerkleTree::new(&data).unwrap();
+        let proof = Proof::new(
+            2,
+            tree.leaves()[2],
+            *tree.root().unwrap(),
+            tree.proof(2).unwrap(),
+        );
+        assert!(proof.verify());
+
+        let json = serde_json::to_string(&proof).unwrap();
+        assert!(json.contains(&hex::encode(tree.root().unwrap())));
+        assert_eq!(serde_json::from_str::<Proof>(&json).unwrap(), proof);
+
+        let mut invalid = proof.clone();
+        invalid.index = 1;
+        assert!(!invalid.verify());
+    }
+}
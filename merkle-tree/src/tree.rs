@@ -2,6 +2,19 @@ use crate::error::MerkleTreeError;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+pub(crate) type Hash = [u8; 32];
+
+/// The bytes the binary encoding of a tree starts with.
+const MAGIC: &[u8; 4] = b"MRKL";
+
+/// The version of the binary encoding of trees.
+const ENCODING_VERSION: u8 = 1;
+
+/// The sibling in a proof of a node without one, the last node of a level
+/// with an odd number of nodes, which is hashed alone into its parent.
+/// No data hashes to it.
+pub const NO_SIBLING: Hash = [0; 32];
+
 /// A Binary Merkle Tree.
 ///
 /// The Merkle Tree struct consists a vector of vectors, where each inner
@@ -11,9 +24,6 @@ use sha2::{Digest, Sha256};
 /// non-leaf nodes until the root node is reached. The struct also provides
 /// methods to retrieve the root hash of the tree, generate and verify
 /// Merkle proofs, and compute the hash of the concatenation of two hashes.
-
-type Hash = [u8; 32];
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MerkleTree {
     levels: Vec<Vec<Hash>>,
@@ -44,27 +54,40 @@ impl MerkleTree {
     /// let tree = MerkleTree::new(&data).unwrap();
     /// ```
     pub fn new(data: &[impl AsRef<[u8]>]) -> Result<Self, MerkleTreeError> {
-        if data.is_empty() {
+        Self::from_leaves(data.iter().map(Self::hash).collect())
+    }
+
+    /// Creates a new Merkle Tree from the hashes of the data blocks, e.g. when
+    /// the blocks were hashed separately, in parallel or on another machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaves` - The SHA-256 hashes of the data blocks, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are no leaves.
+    pub fn from_leaves(leaves: Vec<Hash>) -> Result<Self, MerkleTreeError> {
+        if leaves.is_empty() {
             return Err(MerkleTreeError::EmptyData);
         }
         let mut levels: Vec<Vec<Hash>> =
-            Vec::with_capacity((data.len() as f64).log2().ceil() as usize);
-
-        levels.extend(std::iter::successors(
-            Some(data.iter().map(Self::hash).collect::<Vec<Hash>>()),
-            |level| match level.len() {
-                0 | 1 => None,
-                _ => Some(
-                    level
-                        .chunks(2)
-                        .map(|chunk| match chunk.len() {
-                            1 => Self::hash(chunk[0]),
-                            _ => Self::hash_nodes(&chunk[0], &chunk[1]),
-                        })
-                        .collect(),
-                ),
-            },
-        ));
+            Vec::with_capacity((leaves.len() as f64).log2().ceil() as usize);
+
+        levels.extend(std::iter::successors(Some(leaves), |level| match level
+            .len()
+        {
+            0 | 1 => None,
+            _ => Some(
+                level
+                    .chunks(2)
+                    .map(|chunk| match chunk.len() {
+                        1 => Self::hash(chunk[0]),
+                        _ => Self::hash_nodes(&chunk[0], &chunk[1]),
+                    })
+                    .collect(),
+            ),
+        }));
 
         Ok(Self { levels })
     }
@@ -74,8 +97,23 @@ impl MerkleTree {
         self.levels.last().and_then(|level| level.first())
     }
 
+    /// Returns the leaf hashes of the Merkle Tree, i.e. the hashes of the data
+    /// blocks, in order.
+    pub fn leaves(&self) -> &[Hash] {
+        &self.levels[0]
+    }
+
+    /// Returns the depth of the Merkle Tree, i.e. the number of levels above
+    /// the leaves, which is also the number of hashes of every proof.
+    pub fn depth(&self) -> usize {
+        self.levels.len() - 1
+    }
+
     /// Returns the Merkle proof for the data block at the given index.
     ///
+    /// The proof holds a sibling for every level above the leaves, the
+    /// nodes without a sibling having [`NO_SIBLING`] instead.
+    ///
     /// # Arguments
     ///
     /// * `index` - The index of the data block to generate the proof for.
@@ -108,8 +146,11 @@ impl MerkleTree {
             .fold(
                 (index, Vec::with_capacity(self.levels.len())),
                 |(i, mut proof), level| {
-                    let sibling_index = if i % 2 == 0 { i + 1 } else { i - 1 };
-                    proof.push(level[sibling_index]);
+                    let sibling = match i % 2 {
+                        0 => level.get(i + 1).copied().unwrap_or(NO_SIBLING),
+                        _ => level[i - 1],
+                    };
+                    proof.push(sibling);
                     (i / 2, proof)
                 },
             )
@@ -136,18 +177,109 @@ impl MerkleTree {
         root: &Hash,
         proof: &[Hash],
     ) -> bool {
-        let (_, hash) = proof.iter().fold(
-            (index, Self::hash(data)),
-            |(i, hash), sibling| match i % 2 {
-                0 => (i / 2, Self::hash_nodes(&hash, sibling)),
-                1 => (i / 2, Self::hash_nodes(sibling, &hash)),
-                _ => unreachable!(),
-            },
-        );
+        Self::verify_leaf(index, &Self::hash(data), root, proof)
+    }
+
+    /// Verifies the Merkle proof for the leaf at the given index, i.e. for a
+    /// data block already hashed, e.g. while it was streamed.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the leaf to verify the proof for.
+    /// * `leaf` - The hash of the data block.
+    /// * `root` - The root hash of the Merkle tree.
+    /// * `proof` - The Merkle proof for the data block.
+    ///
+    /// # Returns
+    ///
+    /// Returns a boolean indicating whether the proof is valid or not.
+    pub fn verify_leaf(
+        index: usize,
+        leaf: &Hash,
+        root: &Hash,
+        proof: &[Hash],
+    ) -> bool {
+        let mut hash = *leaf;
+        let mut i = index;
+        for sibling in proof {
+            hash = match (i % 2, *sibling == NO_SIBLING) {
+                (0, true) => Self::hash(hash),
+                (0, false) => Self::hash_nodes(&hash, sibling),
+                // Only the last node of a level may have no sibling, and it
+                // is a left node
+                (_, true) => return false,
+                (_, false) => Self::hash_nodes(sibling, &hash),
+            };
+            i /= 2;
+        }
 
         hash == *root
     }
 
+    /// Encodes the tree in a compact binary format, read back with
+    /// [`MerkleTree::from_bytes`]: [`MAGIC`], a version byte, the number of
+    /// leaves as a big-endian `u64`, then the hashes of every level, from the
+    /// leaves to the root.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let hashes = self.levels.iter().map(Vec::len).sum::<usize>();
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 9 + hashes * 32);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(ENCODING_VERSION);
+        bytes.extend_from_slice(&(self.levels[0].len() as u64).to_be_bytes());
+        for hash in self.levels.iter().flatten() {
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    /// Decodes a tree encoded with [`MerkleTree::to_bytes`], without hashing
+    /// its nodes again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bytes are not a tree in the binary format, or
+    /// are of another version of it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleTreeError> {
+        let invalid = MerkleTreeError::InvalidEncoding;
+        let bytes = bytes.strip_prefix(MAGIC).ok_or(invalid("not a tree"))?;
+        let (&version, bytes) = bytes.split_first().ok_or(invalid("empty"))?;
+        if version != ENCODING_VERSION {
+            return Err(invalid("unknown version"));
+        }
+        let (leaves, mut bytes) =
+            bytes.split_first_chunk::<8>().ok_or(invalid("truncated"))?;
+
+        // The lengths of the levels follow from the number of leaves
+        let mut len = usize::try_from(u64::from_be_bytes(*leaves))
+            .map_err(|_| invalid("too many leaves"))?;
+        if len == 0 {
+            return Err(MerkleTreeError::EmptyData);
+        }
+        let mut levels = vec![];
+        loop {
+            let size = len.checked_mul(32).ok_or(invalid("too many leaves"))?;
+            if bytes.len() < size {
+                return Err(invalid("truncated"));
+            }
+            let (level, rest) = bytes.split_at(size);
+            levels.push(
+                level
+                    .chunks_exact(32)
+                    .map(|hash| hash.try_into().unwrap())
+                    .collect(),
+            );
+            bytes = rest;
+            if len == 1 {
+                break;
+            }
+            len = len.div_ceil(2);
+        }
+        if !bytes.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(Self { levels })
+    }
+
     /// Computes the hash of the concatenation of two hashes.
     fn hash_nodes(left: &Hash, right: &Hash) -> Hash {
         let mut combined = [0u8; 64];
@@ -156,7 +288,7 @@ impl MerkleTree {
         Self::hash(combined)
     }
 
-    ///
+    /// Computes the SHA-256 hash of the given data.
     fn hash<T: AsRef<[u8]>>(data: T) -> Hash {
         let mut hasher = Sha256::new();
         hasher.update(data);
@@ -199,6 +331,75 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_verify_odd_number_of_leaves() {
+        let data = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
+        let tree = MerkleTree::new(&data).unwrap();
+        let root = tree.root().unwrap();
+        for (index, leaf) in data.iter().enumerate() {
+            let proof = tree.proof(index).unwrap();
+            assert!(MerkleTree::verify(index, leaf, root, &proof));
+        }
+        assert_eq!(tree.proof(2).unwrap()[0], NO_SIBLING);
+        // Two equal nodes are not mistaken for a node without a sibling
+        let data = vec![vec![1, 2, 3]; 5];
+        let tree = MerkleTree::new(&data).unwrap();
+        let root = tree.root().unwrap();
+        for index in 0..data.len() {
+            let proof = tree.proof(index).unwrap();
+            assert!(MerkleTree::verify(index, &data[0], root, &proof));
+        }
+        let mut proof = tree.proof(1).unwrap();
+        proof[0] = NO_SIBLING;
+        assert!(!MerkleTree::verify(1, &data[0], root, &proof));
+    }
+
+    #[test]
+    fn test_leaves() {
+        let data = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
+        let tree = MerkleTree::new(&data).unwrap();
+        assert_eq!(
+            tree.leaves(),
+            data.iter().map(MerkleTree::hash).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_depth() {
+        let depth = |leaves: usize| {
+            MerkleTree::new(&vec![vec![1, 2, 3]; leaves])
+                .unwrap()
+                .depth()
+        };
+        assert_eq!(depth(1), 0);
+        assert_eq!(depth(2), 1);
+        assert_eq!(depth(5), 3);
+        let tree = MerkleTree::new(&vec![vec![1, 2, 3]; 5]).unwrap();
+        assert_eq!(tree.proof(4).unwrap().len(), tree.depth());
+    }
+
+    #[test]
+    fn test_from_leaves() {
+        let data = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
+        let tree = MerkleTree::new(&data).unwrap();
+        let from_leaves = MerkleTree::from_leaves(tree.leaves().to_vec());
+        assert_eq!(from_leaves.unwrap().root(), tree.root());
+        assert_eq!(
+            MerkleTree::from_leaves(vec![]).err(),
+            Some(MerkleTreeError::EmptyData)
+        );
+    }
+
+    #[test]
+    fn test_verify_leaf() {
+        let data = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
+        let tree = MerkleTree::new(&data).unwrap();
+        let root = tree.root().unwrap();
+        let proof = tree.proof(2).unwrap();
+        assert!(MerkleTree::verify_leaf(2, &tree.leaves()[2], root, &proof));
+        assert!(!MerkleTree::verify_leaf(1, &tree.leaves()[2], root, &proof));
+    }
+
     #[test]
     fn test_invalid_index() {
         let data = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
@@ -223,4 +424,26 @@ mod tests {
         let verified = MerkleTree::verify(1, &[4, 5, 6], root, &proof);
         assert!(verified);
     }
+
+    #[test]
+    fn test_bytes() {
+        for leaves in [1, 2, 5, 8] {
+            let data = (0..leaves).map(|i| vec![i]).collect::<Vec<_>>();
+            let tree = MerkleTree::new(&data).unwrap();
+            let bytes = tree.to_bytes();
+            assert!(bytes.starts_with(MAGIC));
+            let decoded = MerkleTree::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.levels, tree.levels);
+        }
+
+        let bytes = MerkleTree::new(&[[1], [2], [3]]).unwrap().to_bytes();
+        let invalid = |bytes: &[u8]| MerkleTree::from_bytes(bytes).is_err();
+        assert!(invalid(&bytes[..bytes.len() - 1]));
+        assert!(invalid(&[&bytes[..], &[0]].concat()));
+        assert!(invalid(&[&bytes[..4], &[2], &bytes[5..]].concat()));
+        assert!(invalid(b"[[1, 2, 3]]"));
+        // A count of leaves alone does not make the decoder allocate them
+        let huge = [&bytes[..5], &u64::MAX.to_be_bytes()].concat();
+        assert!(invalid(&huge));
+    }
 }
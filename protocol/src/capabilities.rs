@@ -0,0 +1,125 @@
+use std::fmt;
+
+/// The features of the protocol a client or a server supports, exchanged in
+/// the [`Request::Hello`](crate::Request::Hello) handshake as flags, so that
+/// a feature is only used when both ends support it.
+///
+/// The flags a peer does not know are kept, so that a newer peer can tell
+/// what an older one supports.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// File contents compressed in transit, as the frames of
+    /// [`compression`](crate::compression), used when both ends set it.
+    pub const COMPRESSION: Self = Self(1);
+    /// Authentication with a token. A server setting it requires clients to
+    /// authenticate.
+    pub const AUTH: Self = Self(1 << 1);
+    /// Files transferred in chunks: uploads resumed where they stopped, and
+    /// files downloaded in ranges.
+    pub const CHUNKING: Self = Self(1 << 2);
+    /// Storage usage: the server tells clients how many bytes they store,
+    /// and their quota, in reply to [`Request::Usage`](crate::Request::Usage).
+    pub const USAGE: Self = Self(1 << 3);
+    /// Expiring uploads: the server deletes an upload once the retention set
+    /// with [`Request::Retain`](crate::Request::Retain) has passed.
+    pub const EXPIRY: Self = Self(1 << 4);
+    /// Contents negotiated before an upload: the server tells which files
+    /// it already holds the content of, in reply to
+    /// [`Request::Have`](crate::Request::Have), and commits them without
+    /// their being sent.
+    pub const CONTENTS: Self = Self(1 << 5);
+    /// Uploads acknowledged as already stored: the server replies to a
+    /// commit, or a legacy upload, of an upload it already holds with
+    /// [`Response::AlreadyStored`](crate::Response::AlreadyStored) rather
+    /// than [`Response::Committed`](crate::Response::Committed).
+    pub const STORED: Self = Self(1 << 6);
+    /// Signed roots: the server signs the root hash of every upload it
+    /// commits with its ed25519 identity key, following
+    /// [`Response::Committed`](crate::Response::Committed) or
+    /// [`Response::AlreadyStored`](crate::Response::AlreadyStored) with
+    /// [`Response::Signed`](crate::Response::Signed).
+    pub const SIGNED: Self = Self(1 << 7);
+    /// Several requests per connection: the server reads the next request
+    /// of a connection once it answered one, until the client closes it or
+    /// leaves it idle, rather than closing it after a single request.
+    pub const REQUESTS: Self = Self(1 << 8);
+
+    /// Returns the capabilities of the given flags.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the flags of the capabilities.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the capabilities of both `self` and `other`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns the capabilities shared by `self` and `other`.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Returns whether `self` has every capability of `other`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::COMPRESSION, "compression"),
+            (Self::AUTH, "auth"),
+            (Self::CHUNKING, "chunking"),
+            (Self::USAGE, "usage"),
+            (Self::EXPIRY, "expiry"),
+            (Self::CONTENTS, "contents"),
+            (Self::STORED, "stored"),
+            (Self::SIGNED, "signed"),
+            (Self::REQUESTS, "requests"),
+        ];
+        let mut set = f.debug_set();
+        let mut known = 0;
+        for (capability, name) in names {
+            if self.contains(capability) {
+                set.entry(&format_args!("{}", name));
+            }
+            known |= capability.0;
+        }
+        if self.0 & !known != 0 {
+            set.entry(&format_args!("{:#x}", self.0 & !known));
+        }
+        set.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let server = Capabilities::AUTH.union(Capabilities::CHUNKING);
+        let client = Capabilities::from_bits(1 << 40)
+            .union(Capabilities::CHUNKING)
+            .union(Capabilities::COMPRESSION);
+        let shared = server.intersection(client);
+        assert_eq!(shared, Capabilities::CHUNKING);
+        assert!(server.contains(Capabilities::AUTH));
+        assert!(!shared.contains(Capabilities::COMPRESSION));
+        assert!(client.contains(Capabilities::default()));
+        // Unknown flags are kept
+        assert_eq!(client.bits() >> 40, 1);
+        assert_eq!(
+            format!("{:?}", client),
+            "{compression, chunking, 0x10000000000}"
+        );
+    }
+}
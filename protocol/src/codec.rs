@@ -0,0 +1,208 @@
+use crate::error::Error;
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The length of a hash sent as a hex string, e.g. a root hash.
+pub(crate) const HEX_HASH_LEN: usize = 64;
+
+/// A stream messages are read from.
+///
+/// Every [`AsyncRead`] stream is a source; the client implements it for its
+/// own stream, whose reads time out and are throttled.
+pub trait Source: Send {
+    /// Fills `buf` from the stream.
+    fn read_exact(
+        &mut self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+impl<T: AsyncRead + Unpin + Send> Source for T {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        AsyncReadExt::read_exact(self, buf).await?;
+        Ok(())
+    }
+}
+
+/// A message being encoded.
+#[derive(Default)]
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Starts a request with its command, null padded.
+    pub fn command(command: &str) -> Self {
+        let mut writer = Self::default();
+        writer.buf.extend_from_slice(command.as_bytes());
+        writer.buf.resize(crate::COMMAND_LEN, 0);
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    /// Appends bytes as they are, e.g. a hash.
+    pub fn raw(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    /// Appends bytes prefixed with their length.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are more than `max` bytes.
+    pub fn bytes(
+        &mut self,
+        bytes: &[u8],
+        max: usize,
+    ) -> Result<&mut Self, Error> {
+        if bytes.len() > max {
+            return Err(Error::Invalid(format!(
+                "Field of {} bytes too long",
+                bytes.len()
+            )));
+        }
+        Ok(self.u64(bytes.len() as u64).raw(bytes))
+    }
+
+    /// Appends a hash sent as a hex string.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the hash is not a hex string of 64 bytes.
+    pub fn hex_hash(&mut self, hash: &str) -> Result<&mut Self, Error> {
+        if hash.len() != HEX_HASH_LEN
+            || !hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+        {
+            return Err(Error::Invalid(format!("Invalid hash {}", hash)));
+        }
+        Ok(self.raw(hash.as_bytes()))
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+pub(crate) async fn read_u8<S: Source>(source: &mut S) -> Result<u8, Error> {
+    let mut value = [0; 1];
+    source.read_exact(&mut value).await?;
+    Ok(value[0])
+}
+
+pub(crate) async fn read_u32<S: Source>(source: &mut S) -> Result<u32, Error> {
+    let mut value = [0; 4];
+    source.read_exact(&mut value).await?;
+    Ok(u32::from_be_bytes(value))
+}
+
+pub(crate) async fn read_u64<S: Source>(source: &mut S) -> Result<u64, Error> {
+    let mut value = [0; 8];
+    source.read_exact(&mut value).await?;
+    Ok(u64::from_be_bytes(value))
+}
+
+/// Reads a boolean, sent as a byte.
+pub(crate) async fn read_bool<S: Source>(
+    source: &mut S,
+) -> Result<bool, Error> {
+    Ok(read_u8(source).await? == 1)
+}
+
+pub(crate) async fn read_hash<S: Source>(
+    source: &mut S,
+) -> Result<crate::Hash, Error> {
+    let mut hash = [0; 32];
+    source.read_exact(&mut hash).await?;
+    Ok(hash)
+}
+
+pub(crate) async fn read_signature<S: Source>(
+    source: &mut S,
+) -> Result<crate::Signature, Error> {
+    let mut signature = [0; 64];
+    source.read_exact(&mut signature).await?;
+    Ok(signature)
+}
+
+/// Reads bytes prefixed with their length.
+///
+/// # Errors
+///
+/// Returns an error if there are more than `max` bytes, before reading them.
+pub(crate) async fn read_bytes<S: Source>(
+    source: &mut S,
+    max: usize,
+) -> Result<Vec<u8>, Error> {
+    let len = read_u64(source).await?;
+    if len > max as u64 {
+        return Err(Error::Invalid(format!("Field of {} bytes too long", len)));
+    }
+    read_vec(source, len).await
+}
+
+/// Reads as many bytes as given, growing the buffer as they are received
+/// rather than allocating them all upfront, so that a length alone cannot
+/// make the reader allocate more memory than the bytes actually sent.
+pub(crate) async fn read_vec<S: Source>(
+    source: &mut S,
+    len: u64,
+) -> Result<Vec<u8>, Error> {
+    const CHUNK: u64 = 64 * 1024;
+    let mut bytes = Vec::with_capacity(len.min(CHUNK) as usize);
+    while (bytes.len() as u64) < len {
+        let start = bytes.len();
+        let chunk = (len - start as u64).min(CHUNK) as usize;
+        bytes.resize(start + chunk, 0);
+        source.read_exact(&mut bytes[start..]).await?;
+    }
+    Ok(bytes)
+}
+
+/// Reads a UTF-8 string prefixed with its length.
+///
+/// # Errors
+///
+/// Returns an error if there are more than `max` bytes, or if they are not
+/// valid UTF-8.
+pub(crate) async fn read_string<S: Source>(
+    source: &mut S,
+    max: usize,
+) -> Result<String, Error> {
+    String::from_utf8(read_bytes(source, max).await?)
+        .map_err(|_| Error::Invalid("String is not valid UTF-8".to_string()))
+}
+
+/// Reads a hash sent as a hex string.
+///
+/// # Errors
+///
+/// Returns an error if the hash is not a hex string.
+pub(crate) async fn read_hex_hash<S: Source>(
+    source: &mut S,
+) -> Result<String, Error> {
+    let mut hash = [0; HEX_HASH_LEN];
+    source.read_exact(&mut hash).await?;
+    match hash.iter().all(u8::is_ascii_hexdigit) {
+        true => Ok(String::from_utf8_lossy(&hash).to_string()),
+        false => Err(Error::Invalid(format!(
+            "Invalid hash {}",
+            String::from_utf8_lossy(&hash)
+        ))),
+    }
+}
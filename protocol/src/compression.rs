@@ -0,0 +1,214 @@
+use crate::codec::{self, Source};
+use crate::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+
+/// The zstd level file contents are compressed with in transit, unless
+/// configured.
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// The most bytes of a file a frame holds, once decompressed, so that a
+/// frame cannot make the receiver allocate an arbitrary amount of memory.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+/// The bytes of a file [`FrameWriter`] compresses as a frame.
+const FRAME_LEN: usize = 256 * 1024;
+
+/// The flag of the header of a frame whose bytes are sent as they are,
+/// those of incompressible files, e.g. already compressed or encrypted.
+const STORED: u32 = 1 << 31;
+
+/// Encodes bytes of a file as a frame: a `u32` header, with the length of
+/// the frame and whether it is stored rather than compressed, followed by
+/// the bytes compressed with zstd, or as they are if they do not compress.
+///
+/// When [`Capabilities::COMPRESSION`](crate::Capabilities::COMPRESSION) is
+/// negotiated, the content following [`Request::Put`](crate::Request::Put),
+/// [`Response::File`](crate::Response::File) and
+/// [`Response::Range`](crate::Response::Range) is sent as frames, until as
+/// many bytes as their size are decoded.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes, at most [`MAX_FRAME_LEN`].
+/// * `level` - The zstd level to compress them with.
+pub fn encode_frame(bytes: &[u8], level: i32) -> Vec<u8> {
+    debug_assert!(bytes.len() <= MAX_FRAME_LEN);
+    match zstd::bulk::compress(bytes, level) {
+        Ok(compressed) if compressed.len() < bytes.len() => {
+            let header = compressed.len() as u32;
+            [&header.to_be_bytes()[..], &compressed].concat()
+        }
+        _ => {
+            let header = bytes.len() as u32 | STORED;
+            [&header.to_be_bytes()[..], bytes].concat()
+        }
+    }
+}
+
+/// Reads a frame encoded by [`encode_frame`], and decodes it into `buf`.
+///
+/// # Returns
+///
+/// The number of bytes decoded, at the start of `buf`.
+///
+/// # Errors
+///
+/// Returns an error if the stream fails, or if the frame does not decode to
+/// at most `buf.len()` bytes, or to none.
+pub async fn read_frame<S: Source>(
+    source: &mut S,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let max = buf.len().min(MAX_FRAME_LEN);
+    let header = codec::read_u32(source).await?;
+    let len = (header & !STORED) as usize;
+    // A frame is only compressed if that makes it smaller
+    if len == 0 || len > max {
+        return Err(invalid_frame());
+    }
+    if header & STORED != 0 {
+        source.read_exact(&mut buf[..len]).await?;
+        return Ok(len);
+    }
+    let compressed = codec::read_vec(source, len as u64).await?;
+    match zstd::bulk::decompress_to_buffer(&compressed, &mut buf[..max]) {
+        Ok(len) if len > 0 => Ok(len),
+        _ => Err(invalid_frame()),
+    }
+}
+
+fn invalid_frame() -> Error {
+    Error::Invalid("Invalid compressed frame".to_string())
+}
+
+/// Writes what is written to it as frames to another stream, e.g. a file
+/// copied to a client that negotiated compression, see [`encode_frame`].
+///
+/// The bytes are framed once there are enough of them, or once flushed: the
+/// writer must be flushed before anything else is written to the stream.
+pub struct FrameWriter<W> {
+    inner: W,
+    level: i32,
+    /// The bytes written and not framed yet.
+    pending: Vec<u8>,
+    /// The frame being written to the stream.
+    frame: Vec<u8>,
+    /// The bytes of the frame written so far.
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Writes frames to `inner`, compressed at the zstd `level`.
+    pub fn new(inner: W, level: i32) -> Self {
+        Self {
+            inner,
+            level,
+            pending: Vec::with_capacity(FRAME_LEN),
+            frame: vec![],
+            written: 0,
+        }
+    }
+
+    /// Frames the pending bytes, once the frame before is written.
+    fn frame_pending(&mut self) {
+        self.frame = encode_frame(&self.pending, self.level);
+        self.written = 0;
+        self.pending.clear();
+    }
+
+    /// Writes the rest of the frame to the stream.
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.frame.len() {
+            let frame = &self.frame[self.written..];
+            let len = ready!(Pin::new(&mut self.inner).poll_write(cx, frame))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += len;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FrameWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_frame(cx))?;
+        let len = buf.len().min(FRAME_LEN - this.pending.len());
+        this.pending.extend_from_slice(&buf[..len]);
+        if this.pending.len() == FRAME_LEN {
+            this.frame_pending();
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_frame(cx))?;
+        if !this.pending.is_empty() {
+            this.frame_pending();
+            ready!(this.poll_frame(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_frames() {
+        let text =
+            b"The quick brown fox jumps over the lazy dog. ".repeat(20000);
+        let noise: Vec<u8> = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        for (file, compressible) in
+            [(&text[..], true), (&noise[..], false), (b"x", false)]
+        {
+            let mut wire = vec![];
+            let mut writer = FrameWriter::new(&mut wire, DEFAULT_LEVEL);
+            writer.write_all(file).await.unwrap();
+            writer.flush().await.unwrap();
+            assert_eq!(wire.len() < file.len() / 10, compressible);
+
+            let mut source = wire.as_slice();
+            let mut read = vec![0; file.len()];
+            let mut len = 0;
+            while len < file.len() {
+                len += read_frame(&mut source, &mut read[len..]).await.unwrap();
+            }
+            assert_eq!(read, file);
+            assert!(source.is_empty());
+        }
+
+        // A frame decoding to more bytes than expected is rejected
+        let frame = encode_frame(&text[..1000], DEFAULT_LEVEL);
+        let mut buf = [0; 999];
+        let read = read_frame(&mut frame.as_slice(), &mut buf).await;
+        assert!(matches!(read, Err(Error::Invalid(_))));
+        let garbage = [&8u32.to_be_bytes()[..], b"garbage!"].concat();
+        let read = read_frame(&mut garbage.as_slice(), &mut buf).await;
+        assert!(matches!(read, Err(Error::Invalid(_))));
+    }
+}
@@ -0,0 +1,79 @@
+use std::io;
+
+/// The error of a message that cannot be encoded or read.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The stream failed, e.g. it closed in the middle of the message.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The message is invalid, e.g. its command is unknown or a hash is not
+    /// a hex string.
+    #[error("{0}")]
+    Invalid(String),
+    /// The message exceeds the [`Limits`](crate::Limits) of the reader, e.g.
+    /// a file is larger than the server accepts.
+    #[error("{0}")]
+    TooLarge(String),
+}
+
+/// The kind of error a request failed with, sent in the
+/// [`Response::Error`](crate::Response::Error) to the request so that clients
+/// can tell a missing upload from a failure of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The server failed to serve a valid request.
+    Failed,
+    /// The upload, or the file of an upload, does not exist.
+    NotFound,
+    /// The client is not authorized.
+    Unauthorized,
+    /// The request is invalid, e.g. its root hash is not a SHA-256.
+    InvalidRequest,
+    /// The upload would exceed the storage quota of the client.
+    QuotaExceeded,
+    /// The upload exceeds a limit of the server, e.g. on the size of a file.
+    TooLarge,
+    /// The server only serves downloads for now, e.g. during a migration.
+    ReadOnly,
+}
+
+impl ErrorKind {
+    /// Returns the status byte of the kind, zero being a success.
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Failed => 1,
+            Self::NotFound => 2,
+            Self::Unauthorized => 3,
+            Self::InvalidRequest => 4,
+            Self::QuotaExceeded => 5,
+            Self::TooLarge => 6,
+            Self::ReadOnly => 7,
+        }
+    }
+
+    /// Returns the kind of a status byte, unknown kinds being failures.
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            2 => Self::NotFound,
+            3 => Self::Unauthorized,
+            4 => Self::InvalidRequest,
+            5 => Self::QuotaExceeded,
+            6 => Self::TooLarge,
+            7 => Self::ReadOnly,
+            _ => Self::Failed,
+        }
+    }
+
+    /// Returns the name of the kind, e.g. as reported in JSON.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Failed => "server_error",
+            Self::NotFound => "not_found",
+            Self::Unauthorized => "unauthorized",
+            Self::InvalidRequest => "invalid_request",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::TooLarge => "too_large",
+            Self::ReadOnly => "read_only",
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! The wire format of the TCP protocol spoken between the client and the
+//! server of File Guardian.
+//!
+//! Every connection starts with a [`Request::Hello`] handshake, in which the
+//! client and the server agree on the version of the protocol and exchange
+//! their [`Capabilities`], then with a [`Request::Auth`] carrying the token
+//! of the client, and serves its requests afterwards, one after the other,
+//! until the client closes it, or a single one unless the server has
+//! [`Capabilities::REQUESTS`]. A request is a command, a null padded string
+//! of [`COMMAND_LEN`] bytes, followed by its fields, and the server replies
+//! with a [`Response`], or with a [`Response::Error`] telling why the
+//! request failed.
+//! Integers are sent big-endian, strings and lists prefixed with their
+//! length as a `u64`, and hashes as hex strings of 64 bytes.
+//!
+//! The content of files is not part of the messages, so that it is
+//! streamed rather than held in memory: it follows [`Request::Put`] and
+//! [`Response::File`] on the wire, as many bytes as their size, or as the
+//! frames of [`compression`] once both ends negotiated compressing it.
+
+mod capabilities;
+mod codec;
+pub mod compression;
+mod error;
+mod limits;
+mod request;
+mod response;
+
+pub use capabilities::Capabilities;
+pub use codec::Source;
+pub use error::{Error, ErrorKind};
+pub use limits::Limits;
+pub use request::{FileRef, Request};
+pub use response::{Response, StoredUpload};
+
+/// The latest version of the protocol, sent by clients in the handshake.
+pub const VERSION: u32 = 3;
+
+/// The oldest version of the protocol still spoken. Servers reject the
+/// clients speaking an older version, and the other way around.
+pub const MIN_VERSION: u32 = 3;
+
+/// The length of a command, in bytes.
+pub const COMMAND_LEN: usize = 10;
+
+/// The maximum length of a token, in bytes, so that a client cannot make
+/// the server allocate an arbitrary amount of memory.
+pub const MAX_TOKEN_LEN: usize = 4096;
+
+/// The maximum length of a name of a file, in bytes.
+pub const MAX_NAME_LEN: usize = 4096;
+
+/// The maximum number of hashes of a Merkle proof, one per level of the
+/// tree.
+pub const MAX_PROOF_LEN: usize = 64;
+
+/// A SHA-256 hash, e.g. of a file or of the root of a Merkle tree.
+pub type Hash = [u8; 32];
+
+/// An ed25519 signature, e.g. of the root of an upload by the server.
+pub type Signature = [u8; 64];
+
+/// What the server signs the root hash of an upload after, with its ed25519
+/// identity key, so that the signature cannot be passed off as one of
+/// anything else the key signs.
+const ROOT_CONTEXT: &[u8] = b"file-guardian root v1\0";
+
+/// Returns the message the server signs to commit to storing the upload of
+/// a root hash, see [`Capabilities::SIGNED`].
+pub fn signed_root(root: &Hash) -> Vec<u8> {
+    [ROOT_CONTEXT, root].concat()
+}
@@ -0,0 +1,103 @@
+use crate::error::Error;
+
+/// The limits a server puts on uploads, checked as requests are read, before
+/// the server allocates or stores anything for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum size of a file, in bytes.
+    pub max_file_size: u64,
+    /// The maximum number of files of an upload.
+    pub max_files: u64,
+    /// The maximum total size of the files of an upload, in bytes.
+    pub max_upload_size: u64,
+}
+
+impl Limits {
+    /// Limits that accept any upload.
+    pub const NONE: Self = Self {
+        max_file_size: u64::MAX,
+        max_files: u64::MAX,
+        max_upload_size: u64::MAX,
+    };
+
+    /// Checks the size of a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TooLarge`] if the file is larger than the limit.
+    pub fn check_file_size(&self, size: u64) -> Result<(), Error> {
+        match size > self.max_file_size {
+            true => Err(Error::TooLarge(format!(
+                "File of {} bytes exceeds the limit of {} bytes",
+                size, self.max_file_size
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /// Checks the number of files of an upload.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TooLarge`] if the upload has more files than the
+    /// limit.
+    pub fn check_files(&self, files: u64) -> Result<(), Error> {
+        match files > self.max_files {
+            true => Err(Error::TooLarge(format!(
+                "Upload of {} files exceeds the limit of {} files",
+                files, self.max_files
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /// Checks the total size of the files of an upload.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TooLarge`] if the files are larger than the limit.
+    pub fn check_upload_size(&self, size: u64) -> Result<(), Error> {
+        match size > self.max_upload_size {
+            true => Err(Error::TooLarge(format!(
+                "Upload of {} bytes exceeds the limit of {} bytes",
+                size, self.max_upload_size
+            ))),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Default for Limits {
+    /// Files of up to 4 GiB, and uploads of up to 100,000 files and 16 GiB.
+    fn default() -> Self {
+        Self {
+            max_file_size: 4 << 30,
+            max_files: 100_000,
+            max_upload_size: 16 << 30,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_file_size: 10,
+            max_files: 2,
+            max_upload_size: 15,
+        };
+        assert!(limits.check_file_size(10).is_ok());
+        assert!(matches!(
+            limits.check_file_size(11),
+            Err(Error::TooLarge(_))
+        ));
+        assert!(limits.check_files(2).is_ok());
+        assert!(limits.check_files(3).is_err());
+        assert!(limits.check_upload_size(15).is_ok());
+        assert!(limits.check_upload_size(16).is_err());
+        assert!(Limits::NONE.check_upload_size(u64::MAX).is_ok());
+    }
+}
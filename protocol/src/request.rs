@@ -0,0 +1,469 @@
+use crate::codec::{self, Source, Writer};
+use crate::error::Error;
+use crate::{Capabilities, Limits, COMMAND_LEN, MAX_NAME_LEN, MAX_TOKEN_LEN};
+use std::io;
+
+/// The index sent in a download request to download a file by its name,
+/// which follows, rather than by its index.
+const BY_NAME: u64 = u64::MAX;
+
+/// A file of an upload, as asked for in a download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileRef {
+    /// The file at an index of the upload.
+    Index(usize),
+    /// The file with a name, as recorded with [`Request::Names`].
+    Name(String),
+}
+
+/// A request of a client. Root hashes and upload sessions are hex strings
+/// of 64 bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// The handshake that starts every connection, with the latest version
+    /// of the protocol the client speaks and its capabilities. The server
+    /// replies with [`Response::Hello`](crate::Response::Hello).
+    Hello {
+        version: u32,
+        capabilities: Capabilities,
+    },
+    /// The authentication that follows the handshake, with the token of the
+    /// client, empty if it has none.
+    Auth { token: String },
+    /// Uploads a batch of files at once, held in memory. Kept for older
+    /// clients, which used it before [`Request::Put`]. The server replies
+    /// as to [`Request::Commit`].
+    Upload { files: Vec<Vec<u8>> },
+    /// Stages a file of a batch, until the batch is committed. The server
+    /// replies with [`Response::Resume`](crate::Response::Resume), and the
+    /// client then sends the bytes of the file from that offset, then the
+    /// server replies with [`Response::Staged`](crate::Response::Staged).
+    Put {
+        session: String,
+        index: usize,
+        size: u64,
+    },
+    /// Asks which of the files with the given SHA-256 hashes the server
+    /// already holds the content of, e.g. from an earlier snapshot. The
+    /// server replies with [`Response::Held`](crate::Response::Held), and
+    /// the client only stages the others before committing the upload.
+    Have { hashes: Vec<String> },
+    /// Commits the staged files with the given hashes, in order, as an
+    /// upload, the files whose content the server already holds needing not
+    /// be staged. The server replies with
+    /// [`Response::Committed`](crate::Response::Committed), or with
+    /// [`Response::AlreadyStored`](crate::Response::AlreadyStored) if it
+    /// already held the upload, e.g. when a client retries, nothing being
+    /// written again.
+    Commit { hashes: Vec<String> },
+    /// Records the names of the files of an upload, in order.
+    Names {
+        root_hash: String,
+        names: Vec<String>,
+    },
+    /// Downloads a file with its Merkle proof.
+    Download { root_hash: String, file: FileRef },
+    /// Gets the Merkle proof of a file, without the file.
+    Proof { root_hash: String, index: usize },
+    /// Downloads a range of a file, not proven on its own.
+    Range {
+        root_hash: String,
+        index: usize,
+        offset: u64,
+        len: u64,
+    },
+    /// Deletes an upload.
+    Delete { root_hash: String },
+    /// Asks whether the server holds an upload.
+    Exists { root_hash: String },
+    /// Lists the uploads the server holds.
+    Roots,
+    /// Asks how many bytes the client stores, and its quota.
+    Usage,
+    /// Keeps an upload for the given number of seconds from now, after which
+    /// the server deletes it, replacing the retention set before.
+    Retain { root_hash: String, secs: u64 },
+}
+
+impl Request {
+    /// Returns the command of the request.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Hello { .. } => "hello",
+            Self::Auth { .. } => "auth",
+            Self::Upload { .. } => "upload",
+            Self::Put { .. } => "put",
+            Self::Have { .. } => "have",
+            Self::Commit { .. } => "commit",
+            Self::Names { .. } => "names",
+            Self::Download { .. } => "download",
+            Self::Proof { .. } => "proof",
+            Self::Range { .. } => "range",
+            Self::Delete { .. } => "delete",
+            Self::Exists { .. } => "exists",
+            Self::Roots => "roots",
+            Self::Usage => "usage",
+            Self::Retain { .. } => "retain",
+        }
+    }
+
+    /// Encodes the request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a hash or a session is not a hex string of 64
+    /// bytes, or if a token or a name is too long.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = Writer::command(self.command());
+        match self {
+            Self::Hello {
+                version,
+                capabilities,
+            } => {
+                writer.u32(*version).u64(capabilities.bits());
+            }
+            Self::Auth { token } => {
+                writer.bytes(token.as_bytes(), MAX_TOKEN_LEN)?;
+            }
+            Self::Upload { files } => {
+                writer.u64(files.len() as u64);
+                for file in files {
+                    writer.bytes(file, usize::MAX)?;
+                }
+            }
+            Self::Put {
+                session,
+                index,
+                size,
+            } => {
+                writer.hex_hash(session)?.u64(*index as u64).u64(*size);
+            }
+            Self::Have { hashes } | Self::Commit { hashes } => {
+                writer.u64(hashes.len() as u64);
+                for hash in hashes {
+                    writer.hex_hash(hash)?;
+                }
+            }
+            Self::Names { root_hash, names } => {
+                writer.hex_hash(root_hash)?.u64(names.len() as u64);
+                for name in names {
+                    writer.bytes(name.as_bytes(), MAX_NAME_LEN)?;
+                }
+            }
+            Self::Download { root_hash, file } => {
+                writer.hex_hash(root_hash)?;
+                match file {
+                    FileRef::Index(index) => writer.u64(*index as u64),
+                    FileRef::Name(name) => writer
+                        .u64(BY_NAME)
+                        .bytes(name.as_bytes(), MAX_NAME_LEN)?,
+                };
+            }
+            Self::Proof { root_hash, index } => {
+                writer.hex_hash(root_hash)?.u64(*index as u64);
+            }
+            Self::Range {
+                root_hash,
+                index,
+                offset,
+                len,
+            } => {
+                writer
+                    .hex_hash(root_hash)?
+                    .u64(*index as u64)
+                    .u64(*offset)
+                    .u64(*len);
+            }
+            Self::Delete { root_hash } | Self::Exists { root_hash } => {
+                writer.hex_hash(root_hash)?;
+            }
+            Self::Roots | Self::Usage => {}
+            Self::Retain { root_hash, secs } => {
+                writer.hex_hash(root_hash)?.u64(*secs);
+            }
+        }
+        Ok(writer.finish())
+    }
+
+    /// Reads a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The stream the request is read from.
+    /// * `limits` - The limits of the files and uploads of the request,
+    ///   checked before their files are received.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream fails, if the request is invalid, e.g.
+    /// its command is unknown, or if it exceeds the limits, in which case the
+    /// rest of the stream cannot be read.
+    pub async fn read<S: Source>(
+        source: &mut S,
+        limits: &Limits,
+    ) -> Result<Self, Error> {
+        Self::read_next(source, limits).await?.ok_or_else(|| {
+            Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof))
+        })
+    }
+
+    /// Reads the next request of a connection serving several, see
+    /// [`Capabilities::REQUESTS`].
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if the stream ends before the request starts, the
+    /// client having closed the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`Request::read`], a stream ending within the
+    /// request included.
+    pub async fn read_next<S: Source>(
+        source: &mut S,
+        limits: &Limits,
+    ) -> Result<Option<Self>, Error> {
+        let mut command = [0; COMMAND_LEN];
+        match source.read_exact(&mut command[..1]).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None)
+            }
+            read => read?,
+        }
+        source.read_exact(&mut command[1..]).await?;
+        let command = std::str::from_utf8(&command)
+            .map_err(|_| Error::Invalid("Command is not valid UTF-8".into()))?
+            .trim_end_matches(char::from(0));
+        let request = match command {
+            "hello" => Self::Hello {
+                version: codec::read_u32(source).await?,
+                capabilities: Capabilities::from_bits(
+                    codec::read_u64(source).await?,
+                ),
+            },
+            "auth" => Self::Auth {
+                token: codec::read_string(source, MAX_TOKEN_LEN).await?,
+            },
+            "upload" => {
+                let count = codec::read_u64(source).await?;
+                limits.check_files(count)?;
+                let (mut files, mut total) = (vec![], 0u64);
+                for _ in 0..count {
+                    let size = codec::read_u64(source).await?;
+                    limits.check_file_size(size)?;
+                    total = total.saturating_add(size);
+                    limits.check_upload_size(total)?;
+                    files.push(codec::read_vec(source, size).await?);
+                }
+                Self::Upload { files }
+            }
+            "put" => {
+                let session = codec::read_hex_hash(source).await?;
+                let index = read_index(source).await?;
+                limits.check_files((index as u64).saturating_add(1))?;
+                let size = codec::read_u64(source).await?;
+                limits.check_file_size(size)?;
+                Self::Put {
+                    session,
+                    index,
+                    size,
+                }
+            }
+            "have" => Self::Have {
+                hashes: read_hashes(source, limits).await?,
+            },
+            "commit" => Self::Commit {
+                hashes: read_hashes(source, limits).await?,
+            },
+            "names" => {
+                let root_hash = codec::read_hex_hash(source).await?;
+                let count = codec::read_u64(source).await?;
+                limits.check_files(count)?;
+                let mut names = vec![];
+                for _ in 0..count {
+                    names.push(codec::read_string(source, MAX_NAME_LEN).await?);
+                }
+                Self::Names { root_hash, names }
+            }
+            "download" => {
+                let root_hash = codec::read_hex_hash(source).await?;
+                let file = match codec::read_u64(source).await? {
+                    BY_NAME => FileRef::Name(
+                        codec::read_string(source, MAX_NAME_LEN).await?,
+                    ),
+                    index => FileRef::Index(to_index(index)?),
+                };
+                Self::Download { root_hash, file }
+            }
+            "proof" => Self::Proof {
+                root_hash: codec::read_hex_hash(source).await?,
+                index: read_index(source).await?,
+            },
+            "range" => Self::Range {
+                root_hash: codec::read_hex_hash(source).await?,
+                index: read_index(source).await?,
+                offset: codec::read_u64(source).await?,
+                len: codec::read_u64(source).await?,
+            },
+            "delete" => Self::Delete {
+                root_hash: codec::read_hex_hash(source).await?,
+            },
+            "exists" => Self::Exists {
+                root_hash: codec::read_hex_hash(source).await?,
+            },
+            "roots" => Self::Roots,
+            "usage" => Self::Usage,
+            "retain" => Self::Retain {
+                root_hash: codec::read_hex_hash(source).await?,
+                secs: codec::read_u64(source).await?,
+            },
+            command => {
+                return Err(Error::Invalid(format!(
+                    "Unknown command {}",
+                    command
+                )))
+            }
+        };
+        Ok(Some(request))
+    }
+}
+
+/// Reads the hashes of the files of an upload, as many as the limits allow.
+async fn read_hashes<S: Source>(
+    source: &mut S,
+    limits: &Limits,
+) -> Result<Vec<String>, Error> {
+    let count = codec::read_u64(source).await?;
+    limits.check_files(count)?;
+    let mut hashes = vec![];
+    for _ in 0..count {
+        hashes.push(codec::read_hex_hash(source).await?);
+    }
+    Ok(hashes)
+}
+
+/// Reads the index of a file.
+async fn read_index<S: Source>(source: &mut S) -> Result<usize, Error> {
+    to_index(codec::read_u64(source).await?)
+}
+
+fn to_index(index: u64) -> Result<usize, Error> {
+    usize::try_from(index)
+        .map_err(|_| Error::Invalid(format!("Invalid index {}", index)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request() {
+        let hash = "ab".repeat(32);
+        let requests = [
+            Request::Hello {
+                version: crate::VERSION,
+                capabilities: Capabilities::CHUNKING,
+            },
+            Request::Auth {
+                token: "token".to_string(),
+            },
+            Request::Upload {
+                files: vec![b"hello".to_vec(), vec![]],
+            },
+            Request::Put {
+                session: hash.clone(),
+                index: 1,
+                size: 5,
+            },
+            Request::Have {
+                hashes: vec![hash.clone()],
+            },
+            Request::Commit {
+                hashes: vec![hash.clone(), hash.clone()],
+            },
+            Request::Names {
+                root_hash: hash.clone(),
+                names: vec!["a.txt".to_string(), "dir/b.txt".to_string()],
+            },
+            Request::Download {
+                root_hash: hash.clone(),
+                file: FileRef::Index(3),
+            },
+            Request::Download {
+                root_hash: hash.clone(),
+                file: FileRef::Name("a.txt".to_string()),
+            },
+            Request::Proof {
+                root_hash: hash.clone(),
+                index: 2,
+            },
+            Request::Range {
+                root_hash: hash.clone(),
+                index: 0,
+                offset: 10,
+                len: 20,
+            },
+            Request::Delete {
+                root_hash: hash.clone(),
+            },
+            Request::Exists {
+                root_hash: hash.clone(),
+            },
+            Request::Roots,
+            Request::Usage,
+            Request::Retain {
+                root_hash: hash.clone(),
+                secs: 3600,
+            },
+        ];
+        for request in requests {
+            let encoded = request.encode().unwrap();
+            let mut source = encoded.as_slice();
+            let read = Request::read(&mut source, &Limits::NONE).await;
+            assert_eq!(read.unwrap(), request);
+            assert!(source.is_empty());
+        }
+
+        // The requests of a connection follow each other, until it closes
+        let encoded = [Request::Roots, Request::Usage]
+            .map(|request| request.encode().unwrap())
+            .concat();
+        let mut source = encoded.as_slice();
+        for request in [Some(Request::Roots), Some(Request::Usage), None] {
+            let read = Request::read_next(&mut source, &Limits::NONE).await;
+            assert_eq!(read.unwrap(), request);
+        }
+        let mut cut = &encoded[..3];
+        let cut = Request::read_next(&mut cut, &Limits::NONE).await;
+        assert!(matches!(cut, Err(Error::Io(_))));
+
+        let short = Request::Delete {
+            root_hash: "ab".to_string(),
+        };
+        assert!(matches!(short.encode(), Err(Error::Invalid(_))));
+        let mut unknown = &b"unknown\0\0\0"[..];
+        let unknown = Request::read(&mut unknown, &Limits::NONE).await;
+        assert!(matches!(unknown, Err(Error::Invalid(_))));
+        let long = [&b"auth\0\0\0\0\0\0"[..], &u64::MAX.to_be_bytes()].concat();
+        let long = Request::read(&mut long.as_slice(), &Limits::NONE).await;
+        assert!(matches!(long, Err(Error::Invalid(_))));
+
+        // A file larger than the limits is rejected before it is received
+        let limits = Limits {
+            max_file_size: 4,
+            ..Limits::NONE
+        };
+        let upload = [&b"upload\0\0\0\0"[..], &1u64.to_be_bytes()].concat();
+        let huge = [&upload[..], &u64::MAX.to_be_bytes()].concat();
+        let huge = Request::read(&mut huge.as_slice(), &limits).await;
+        assert!(matches!(huge, Err(Error::TooLarge(_))));
+        let put = Request::Put {
+            session: hash.clone(),
+            index: 0,
+            size: 5,
+        };
+        let put = put.encode().unwrap();
+        let put = Request::read(&mut put.as_slice(), &limits).await;
+        assert!(matches!(put, Err(Error::TooLarge(_))));
+    }
+}
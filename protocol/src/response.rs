@@ -0,0 +1,371 @@
+use crate::codec::{self, Source, Writer};
+use crate::error::{Error, ErrorKind};
+use crate::{Capabilities, Hash, Signature, MAX_PROOF_LEN};
+
+/// The maximum length of the message of an error, in bytes.
+const MAX_MESSAGE_LEN: usize = 1 << 16;
+
+/// The first tag of the responses other than [`Response::Ok`] and
+/// [`Response::Error`], whose tags are the codes of the kinds of errors.
+const FIRST_TAG: u8 = 16;
+
+/// An upload held by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredUpload {
+    pub root_hash: String,
+    /// The number of files of the upload.
+    pub files: u64,
+    /// The total size of the files as stored, in bytes.
+    pub size: u64,
+}
+
+/// The response of the server to a request. Each response starts with a tag,
+/// so that a client reading another response than the one it expects, e.g.
+/// an error, tells so rather than misreading it.
+///
+/// [`Response::Ok`] and [`Response::Error`] are encoded as the statuses the
+/// first version of the protocol replied with, so that older clients still
+/// read why the server rejects them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The request was served, and there is nothing to send back, e.g. for
+    /// the authentication handshake.
+    Ok,
+    /// The request failed, e.g. the upload is not found or the index of the
+    /// file is out of range, or the server failed to serve it.
+    Error { kind: ErrorKind, message: String },
+    /// The root hash of the upload of a commit, or of a legacy upload.
+    Committed { root_hash: String },
+    /// The number of bytes of a file already staged, from which the client
+    /// sends the rest.
+    Resume { offset: u64 },
+    /// The hash of a file once staged, as a hex string.
+    Staged { hash: String },
+    /// A downloaded file, whose bytes follow, then its
+    /// [`Response::Proof`]. The index is that of the file asked for by
+    /// name.
+    File { index: usize, size: u64 },
+    /// The leaf of a file, and its Merkle proof.
+    Proof { leaf: Hash, hashes: Vec<Hash> },
+    /// A range of a file, whose bytes follow.
+    Range { len: u64 },
+    /// Whether the server held a deleted upload.
+    Deleted { existed: bool },
+    /// Whether the server holds an upload.
+    Exists { exists: bool },
+    /// The uploads the server holds.
+    Uploads { uploads: Vec<StoredUpload> },
+    /// The reply to the handshake, with the version of the protocol the
+    /// connection speaks, the latest both ends speak, and the capabilities
+    /// of the server.
+    Hello {
+        version: u32,
+        capabilities: Capabilities,
+    },
+    /// The bytes stored by the client, and its quota, `None` if it has none.
+    Usage { used: u64, quota: Option<u64> },
+    /// Whether the server holds the content of each file of a
+    /// [`Request::Have`](crate::Request::Have), in order.
+    Held { held: Vec<bool> },
+    /// The root hash of the upload of a commit, or of a legacy upload, which
+    /// the server already held, sent to the clients with
+    /// [`Capabilities::STORED`] only.
+    AlreadyStored { root_hash: String },
+    /// The signature of the root hash of an upload just committed, see
+    /// [`signed_root`](crate::signed_root), and the public key it verifies
+    /// with, sent to the clients with [`Capabilities::SIGNED`].
+    Signed { key: Hash, signature: Signature },
+}
+
+impl Response {
+    /// Returns the tag of the response.
+    fn tag(&self) -> u8 {
+        match self {
+            Self::Ok => 0,
+            Self::Error { kind, .. } => kind.code(),
+            Self::Committed { .. } => 16,
+            Self::Resume { .. } => 17,
+            Self::Staged { .. } => 18,
+            Self::File { .. } => 19,
+            Self::Proof { .. } => 20,
+            Self::Range { .. } => 21,
+            Self::Deleted { .. } => 22,
+            Self::Exists { .. } => 23,
+            Self::Uploads { .. } => 24,
+            Self::Hello { .. } => 25,
+            Self::Usage { .. } => 26,
+            Self::Held { .. } => 27,
+            Self::AlreadyStored { .. } => 28,
+            Self::Signed { .. } => 29,
+        }
+    }
+
+    /// Encodes the response, the message of an error too long being cut
+    /// short.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a hash is not a hex string of 64 bytes, or if a
+    /// proof has more than [`MAX_PROOF_LEN`] hashes.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = Writer::default();
+        writer.u8(self.tag());
+        match self {
+            Self::Ok => {}
+            Self::Error { message, .. } => {
+                let mut end = message.len().min(MAX_MESSAGE_LEN);
+                while !message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                writer.bytes(&message.as_bytes()[..end], MAX_MESSAGE_LEN)?;
+            }
+            Self::Committed { root_hash }
+            | Self::AlreadyStored { root_hash } => {
+                writer.hex_hash(root_hash)?;
+            }
+            Self::Resume { offset } => {
+                writer.u64(*offset);
+            }
+            Self::Staged { hash } => {
+                writer.hex_hash(hash)?;
+            }
+            Self::File { index, size } => {
+                writer.u64(*index as u64).u64(*size);
+            }
+            Self::Proof { leaf, hashes } => {
+                if hashes.len() > MAX_PROOF_LEN {
+                    return Err(invalid_proof(hashes.len() as u64));
+                }
+                writer.raw(leaf).u64(hashes.len() as u64);
+                for hash in hashes {
+                    writer.raw(hash);
+                }
+            }
+            Self::Range { len } => {
+                writer.u64(*len);
+            }
+            Self::Deleted { existed: value }
+            | Self::Exists { exists: value } => {
+                writer.u8((*value).into());
+            }
+            Self::Uploads { uploads } => {
+                writer.u64(uploads.len() as u64);
+                for upload in uploads {
+                    writer
+                        .hex_hash(&upload.root_hash)?
+                        .u64(upload.files)
+                        .u64(upload.size);
+                }
+            }
+            Self::Hello {
+                version,
+                capabilities,
+            } => {
+                writer.u32(*version).u64(capabilities.bits());
+            }
+            Self::Usage { used, quota } => {
+                writer
+                    .u64(*used)
+                    .u8(quota.is_some().into())
+                    .u64(quota.unwrap_or(0));
+            }
+            Self::Held { held } => {
+                writer.u64(held.len() as u64);
+                for value in held {
+                    writer.u8((*value).into());
+                }
+            }
+            Self::Signed { key, signature } => {
+                writer.raw(key).raw(signature);
+            }
+        }
+        Ok(writer.finish())
+    }
+
+    /// Reads a response.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream fails, or if the response is invalid,
+    /// e.g. its tag is unknown.
+    pub async fn read<S: Source>(source: &mut S) -> Result<Self, Error> {
+        let response = match codec::read_u8(source).await? {
+            0 => Self::Ok,
+            tag if tag < FIRST_TAG => {
+                let message =
+                    codec::read_bytes(source, MAX_MESSAGE_LEN).await?;
+                Self::Error {
+                    kind: ErrorKind::from_code(tag),
+                    message: String::from_utf8_lossy(&message).to_string(),
+                }
+            }
+            16 => Self::Committed {
+                root_hash: codec::read_hex_hash(source).await?,
+            },
+            17 => Self::Resume {
+                offset: codec::read_u64(source).await?,
+            },
+            18 => Self::Staged {
+                hash: codec::read_hex_hash(source).await?,
+            },
+            19 => {
+                let index = codec::read_u64(source).await?;
+                Self::File {
+                    index: usize::try_from(index).map_err(|_| {
+                        Error::Invalid(format!("Invalid index {}", index))
+                    })?,
+                    size: codec::read_u64(source).await?,
+                }
+            }
+            20 => {
+                let leaf = codec::read_hash(source).await?;
+                let len = codec::read_u64(source).await?;
+                if len > MAX_PROOF_LEN as u64 {
+                    return Err(invalid_proof(len));
+                }
+                let mut hashes = vec![];
+                for _ in 0..len {
+                    hashes.push(codec::read_hash(source).await?);
+                }
+                Self::Proof { leaf, hashes }
+            }
+            21 => Self::Range {
+                len: codec::read_u64(source).await?,
+            },
+            22 => Self::Deleted {
+                existed: codec::read_bool(source).await?,
+            },
+            23 => Self::Exists {
+                exists: codec::read_bool(source).await?,
+            },
+            24 => {
+                let mut uploads = vec![];
+                for _ in 0..codec::read_u64(source).await? {
+                    uploads.push(StoredUpload {
+                        root_hash: codec::read_hex_hash(source).await?,
+                        files: codec::read_u64(source).await?,
+                        size: codec::read_u64(source).await?,
+                    });
+                }
+                Self::Uploads { uploads }
+            }
+            25 => Self::Hello {
+                version: codec::read_u32(source).await?,
+                capabilities: Capabilities::from_bits(
+                    codec::read_u64(source).await?,
+                ),
+            },
+            26 => {
+                let used = codec::read_u64(source).await?;
+                let limited = codec::read_bool(source).await?;
+                let quota = codec::read_u64(source).await?;
+                Self::Usage {
+                    used,
+                    quota: limited.then_some(quota),
+                }
+            }
+            27 => {
+                let mut held = vec![];
+                for _ in 0..codec::read_u64(source).await? {
+                    held.push(codec::read_bool(source).await?);
+                }
+                Self::Held { held }
+            }
+            28 => Self::AlreadyStored {
+                root_hash: codec::read_hex_hash(source).await?,
+            },
+            29 => Self::Signed {
+                key: codec::read_hash(source).await?,
+                signature: codec::read_signature(source).await?,
+            },
+            tag => {
+                return Err(Error::Invalid(format!("Unknown response {}", tag)))
+            }
+        };
+        Ok(response)
+    }
+}
+
+fn invalid_proof(len: u64) -> Error {
+    Error::Invalid(format!("Invalid proof length {}", len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_response() {
+        let hash = "cd".repeat(32);
+        let responses = [
+            Response::Ok,
+            Response::Hello {
+                version: crate::VERSION,
+                capabilities: Capabilities::AUTH,
+            },
+            Response::Error {
+                kind: ErrorKind::NotFound,
+                message: "Upload not found".to_string(),
+            },
+            Response::Committed {
+                root_hash: hash.clone(),
+            },
+            Response::Resume { offset: 7 },
+            Response::Staged { hash: hash.clone() },
+            Response::File { index: 2, size: 9 },
+            Response::Proof {
+                leaf: [1; 32],
+                hashes: vec![[2; 32], [3; 32]],
+            },
+            Response::Range { len: 4 },
+            Response::Deleted { existed: true },
+            Response::Exists { exists: false },
+            Response::Uploads {
+                uploads: vec![StoredUpload {
+                    root_hash: hash.clone(),
+                    files: 2,
+                    size: 10,
+                }],
+            },
+            Response::Usage {
+                used: 10,
+                quota: Some(1 << 30),
+            },
+            Response::Usage {
+                used: 0,
+                quota: None,
+            },
+            Response::Held {
+                held: vec![true, false, true],
+            },
+            Response::AlreadyStored {
+                root_hash: hash.clone(),
+            },
+            Response::Signed {
+                key: [4; 32],
+                signature: [5; 64],
+            },
+        ];
+        for response in responses {
+            let encoded = response.encode().unwrap();
+            let mut source = encoded.as_slice();
+            assert_eq!(Response::read(&mut source).await.unwrap(), response);
+            assert!(source.is_empty());
+        }
+
+        let long = Response::Proof {
+            leaf: [0; 32],
+            hashes: vec![[0; 32]; MAX_PROOF_LEN + 1],
+        };
+        assert!(matches!(long.encode(), Err(Error::Invalid(_))));
+        // Errors are encoded as the statuses of the first version
+        let error = Response::Error {
+            kind: ErrorKind::InvalidRequest,
+            message: "x".to_string(),
+        };
+        let status = [&[4][..], &1u64.to_be_bytes(), b"x"].concat();
+        assert_eq!(error.encode().unwrap(), status);
+        let unknown = Response::read(&mut &[0xff][..]).await;
+        assert!(matches!(unknown, Err(Error::Invalid(_))));
+    }
+}
@@ -0,0 +1,600 @@
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::auth::{self, Auth};
+use crate::gc;
+#[cfg(unix)]
+use crate::listener;
+use crate::scrub;
+use crate::server;
+use crate::store::FileStore;
+
+/// The maximum size of a request, in bytes.
+pub const MAX_REQUEST: u64 = 64 * 1024;
+
+/// A command of `server admin`, sent to the running server over its admin
+/// socket.
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// List the uploads, with their number of files and size
+    List {
+        /// Only list the uploads of this user
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// List the files of an upload, with their size and name
+    Show {
+        root_hash: String,
+        /// The user the upload belongs to
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Delete an upload
+    Delete {
+        root_hash: String,
+        /// The user the upload belongs to
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Collect the garbage of the store, which is safe while the server runs
+    Gc {
+        /// Only print what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Scrub the store, exiting with an error if an upload is corrupt
+    Scrub {
+        /// Move the corrupt files into their upload, see --scrub-quarantine
+        #[arg(long)]
+        quarantine: bool,
+    },
+    /// Refuse the uploads and deletions, still serving the downloads
+    ReadOnly,
+    /// Accept the uploads and deletions again
+    Writable,
+    /// Manage the tokens clients authenticate with
+    #[command(subcommand)]
+    Tokens(TokensCommand),
+}
+
+/// A command of `server admin tokens`.
+#[derive(Subcommand)]
+pub enum TokensCommand {
+    /// List the users of the tokens file, with their number of tokens
+    List,
+    /// Add a random token to the tokens file and print it
+    Add {
+        /// The user the token authenticates [default: none]
+        user: Option<String>,
+    },
+    /// Remove every token of a user from the tokens file
+    Revoke {
+        /// The user [default: the tokens without a user]
+        user: Option<String>,
+    },
+    /// Print the token of a user, signed with the HMAC secret
+    Issue { user: String },
+}
+
+/// A request sent to the admin socket, as a line of JSON, answered by a
+/// [`Response`] before the server closes the connection.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// Lists the uploads, of every user unless one is given.
+    List { user: Option<String> },
+    /// Lists the files of an upload.
+    Show {
+        user: Option<String>,
+        root_hash: String,
+    },
+    /// Deletes an upload.
+    Delete {
+        user: Option<String>,
+        root_hash: String,
+    },
+    /// Collects the garbage of the store.
+    Gc { dry_run: bool },
+    /// Scrubs the store.
+    Scrub { quarantine: bool },
+    /// Makes the server read-only, or writable again.
+    ReadOnly { enabled: bool },
+    /// Lists the users of the tokens.
+    Users,
+    /// Adds a token to the tokens file.
+    AddToken { user: Option<String> },
+    /// Removes the tokens of a user from the tokens file.
+    RevokeTokens { user: Option<String> },
+    /// Issues the token of a user with the HMAC secret.
+    IssueToken { user: String },
+}
+
+/// The answer of the server to a [`Request`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Uploads {
+        uploads: Vec<Upload>,
+    },
+    Files {
+        files: Vec<File>,
+    },
+    /// The lines printed for the request, e.g. what the garbage collection
+    /// removed, and whether the command succeeds.
+    Report {
+        lines: Vec<String>,
+        ok: bool,
+    },
+    Users {
+        users: Vec<(Option<String>, usize)>,
+    },
+    Token {
+        token: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// An upload held by the server.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Upload {
+    /// The user whose namespace holds the upload, if any.
+    pub user: Option<String>,
+    pub root_hash: String,
+    pub files: usize,
+    /// The total size of the files, in bytes.
+    pub size: u64,
+    /// When the upload expires, in seconds since the Unix epoch, if it does.
+    pub expiry: Option<u64>,
+}
+
+/// A file of an upload.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct File {
+    pub index: usize,
+    /// The size of the file, in bytes.
+    pub size: u64,
+    /// The name of the file, if the client recorded the names.
+    pub name: Option<String>,
+}
+
+/// What the admin requests are served with, shared with the server.
+#[derive(Clone)]
+pub struct Admin {
+    /// The store of the server.
+    pub store: FileStore,
+    /// How the server authenticates clients, read again from the tokens
+    /// file once it changes.
+    pub auth: Option<Arc<RwLock<Arc<Auth>>>>,
+    /// The tokens file, if the tokens are read from one.
+    pub tokens_file: Option<PathBuf>,
+    /// How long the files being uploaded are kept without being written to.
+    pub gc_grace: Duration,
+    /// Whether the server is read-only.
+    pub read_only: Arc<AtomicBool>,
+}
+
+impl Admin {
+    /// Serves the admin requests on a socket, only accessible to the user
+    /// running the server, until the server is asked to stop, and removes
+    /// the socket then.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if another server listens on the socket, or if the
+    /// socket cannot be created.
+    #[cfg(unix)]
+    pub async fn serve(
+        self,
+        socket: &Path,
+        mut stopping: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let listener = listener::bind_unix(socket, 0o600).await?;
+        log::info!("Serving the admin requests on {}", socket.display());
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("Could not accept an admin request: {}", e);
+                        continue;
+                    }
+                },
+                _ = stopping.changed() => break,
+            };
+            // A scrub taking hours does not hold up the other requests
+            let admin = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.handle(stream).await {
+                    log::warn!("Could not answer an admin request: {}", e);
+                }
+            });
+        }
+        let _ = std::fs::remove_file(socket);
+        Ok(())
+    }
+
+    /// Serves the admin requests, which requires Unix sockets.
+    #[cfg(not(unix))]
+    pub async fn serve(
+        self,
+        socket: &Path,
+        _stopping: watch::Receiver<bool>,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "The admin socket requires Unix sockets, to listen on {}",
+            socket.display()
+        ))
+    }
+
+    /// Reads a request and writes its response.
+    #[cfg(unix)]
+    async fn handle(self, stream: tokio::net::UnixStream) -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        tokio::io::BufReader::new(reader.take(MAX_REQUEST))
+            .read_line(&mut line)
+            .await?;
+        // E.g. another server checking whether this one listens
+        if line.is_empty() {
+            return Ok(());
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => {
+                log::info!("Admin request {:?}", request);
+                self.answer(request).await
+            }
+            Err(e) => Err(anyhow!("Invalid request: {}", e)),
+        };
+        let response = response.unwrap_or_else(|e| Response::Error {
+            message: format!("{:#}", e),
+        });
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Serves a request.
+    async fn answer(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::List { user } => {
+                let stores = match user {
+                    Some(user) => vec![self.store.namespace(&user)?],
+                    None => self.store.with_namespaces().await?,
+                };
+                let mut uploads = vec![];
+                for store in &stores {
+                    for upload in store.list_uploads().await? {
+                        uploads.push(Upload {
+                            user: store.namespace_name().map(str::to_string),
+                            expiry: store.expiry(&upload.root_hash).await?,
+                            root_hash: upload.root_hash,
+                            files: upload.files,
+                            size: upload.size,
+                        });
+                    }
+                }
+                Ok(Response::Uploads { uploads })
+            }
+            Request::Show { user, root_hash } => {
+                let store = self.store_of(user.as_deref())?;
+                let tree = store.get_tree(&root_hash).await?;
+                let names = store.names(&root_hash).await?;
+                let mut files = vec![];
+                for index in 0..tree.leaves().len() {
+                    files.push(File {
+                        index,
+                        size: store.file_size(&root_hash, index).await?,
+                        name: names.as_ref().map(|names| names[index].clone()),
+                    });
+                }
+                Ok(Response::Files { files })
+            }
+            Request::Delete { user, root_hash } => {
+                let store = self.store_of(user.as_deref())?;
+                match store.delete_upload(&root_hash).await? {
+                    true => Ok(report(format!("Deleted {}", root_hash))),
+                    false => Err(anyhow!("Upload {} not found", root_hash)),
+                }
+            }
+            Request::Gc { dry_run } => {
+                let report =
+                    gc::collect(&self.store, self.gc_grace, dry_run).await?;
+                Ok(Response::Report {
+                    lines: report.lines(),
+                    ok: true,
+                })
+            }
+            Request::Scrub { quarantine } => {
+                let report = scrub::scrub(&self.store, quarantine).await?;
+                Ok(Response::Report {
+                    lines: report.lines(),
+                    ok: report.is_clean(),
+                })
+            }
+            Request::ReadOnly { enabled } => {
+                server::set_read_only(&self.read_only, enabled);
+                Ok(report(match enabled {
+                    true => "Read-only, refusing the uploads and deletions",
+                    false => "Writable, accepting uploads again",
+                }))
+            }
+            Request::Users => {
+                let users = self.auth()?.users()?;
+                Ok(Response::Users { users })
+            }
+            Request::AddToken { user } => {
+                let token =
+                    auth::add_token(self.tokens_file()?, user.as_deref())?;
+                self.reload()?;
+                Ok(Response::Token { token })
+            }
+            Request::RevokeTokens { user } => {
+                let revoked =
+                    auth::revoke_tokens(self.tokens_file()?, user.as_deref())?;
+                self.reload()?;
+                Ok(report(format!("Revoked {} tokens", revoked)))
+            }
+            Request::IssueToken { user } => {
+                let token = self.auth()?.issue(&user)?;
+                Ok(Response::Token { token })
+            }
+        }
+    }
+
+    /// Returns the store of a user, or the store itself without one.
+    fn store_of(&self, user: Option<&str>) -> Result<FileStore> {
+        match user {
+            Some(user) => self.store.namespace(user),
+            None => Ok(self.store.clone()),
+        }
+    }
+
+    /// Returns how the clients are authenticated.
+    fn auth(&self) -> Result<Arc<Auth>> {
+        match &self.auth {
+            Some(auth) => Ok(auth.read().unwrap().clone()),
+            None => Err(anyhow!("The clients do not authenticate")),
+        }
+    }
+
+    /// Returns the tokens file.
+    fn tokens_file(&self) -> Result<&Path> {
+        self.tokens_file.as_deref().ok_or_else(|| {
+            anyhow!("The server does not read its tokens from a file")
+        })
+    }
+
+    /// Reads the tokens file again, for the connections accepted from then
+    /// on to be authenticated with the tokens it holds.
+    fn reload(&self) -> Result<()> {
+        match (&self.auth, &self.tokens_file) {
+            (Some(auth), Some(path)) => auth::reload(auth, path),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the response of a request printing a single line.
+fn report(line: impl Into<String>) -> Response {
+    Response::Report {
+        lines: vec![line.into()],
+        ok: true,
+    }
+}
+
+/// Runs an admin command against the server listening on the socket,
+/// printing its outcome.
+///
+/// # Errors
+///
+/// Returns an error if no server listens on the socket, or if the command
+/// fails.
+pub async fn run(socket: &Path, command: AdminCommand) -> Result<()> {
+    let request = match command {
+        AdminCommand::List { user } => Request::List { user },
+        AdminCommand::Show { root_hash, user } => {
+            Request::Show { user, root_hash }
+        }
+        AdminCommand::Delete { root_hash, user } => {
+            Request::Delete { user, root_hash }
+        }
+        AdminCommand::Gc { dry_run } => Request::Gc { dry_run },
+        AdminCommand::Scrub { quarantine } => Request::Scrub { quarantine },
+        AdminCommand::ReadOnly => Request::ReadOnly { enabled: true },
+        AdminCommand::Writable => Request::ReadOnly { enabled: false },
+        AdminCommand::Tokens(TokensCommand::List) => Request::Users,
+        AdminCommand::Tokens(TokensCommand::Add { user }) => {
+            Request::AddToken { user }
+        }
+        AdminCommand::Tokens(TokensCommand::Revoke { user }) => {
+            Request::RevokeTokens { user }
+        }
+        AdminCommand::Tokens(TokensCommand::Issue { user }) => {
+            Request::IssueToken { user }
+        }
+    };
+    match request_to(socket, &request).await? {
+        Response::Uploads { uploads } => {
+            println!(
+                "{:<64}  {:>6}  {:>12}  USER",
+                "ROOT HASH", "FILES", "SIZE"
+            );
+            for upload in &uploads {
+                println!(
+                    "{:<64}  {:>6}  {:>12}  {}",
+                    upload.root_hash,
+                    upload.files,
+                    upload.size,
+                    upload.user.as_deref().unwrap_or("-")
+                );
+            }
+            let size = uploads.iter().map(|upload| upload.size).sum::<u64>();
+            println!("{} uploads, {} bytes", uploads.len(), size);
+        }
+        Response::Files { files } => {
+            println!("{:>6}  {:>12}  NAME", "INDEX", "SIZE");
+            for file in files {
+                println!(
+                    "{:>6}  {:>12}  {}",
+                    file.index,
+                    file.size,
+                    file.name.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Response::Report { lines, ok } => {
+            for line in lines {
+                println!("{}", line);
+            }
+            if !ok {
+                return Err(anyhow!("The command failed"));
+            }
+        }
+        Response::Users { users } => {
+            for (user, tokens) in users {
+                let user = user.as_deref().unwrap_or("(no user)");
+                println!("{}: {} tokens", user, tokens);
+            }
+        }
+        Response::Token { token } => println!("{}", token),
+        Response::Error { message } => return Err(anyhow!(message)),
+    }
+    Ok(())
+}
+
+/// Sends a request to the admin socket and returns its response.
+///
+/// # Errors
+///
+/// Returns an error if no server listens on the socket, or if the server
+/// answers with an error.
+#[cfg(unix)]
+pub async fn request_to(socket: &Path, request: &Request) -> Result<Response> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let mut stream = UnixStream::connect(socket).await.map_err(|e| {
+        anyhow!(
+            "No server listens on {} ({}), start one with --admin-socket",
+            socket.display(),
+            e
+        )
+    })?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    if line.is_empty() {
+        return Err(anyhow!("The server stopped before answering"));
+    }
+    match serde_json::from_str(&line)? {
+        Response::Error { message } => Err(anyhow!(message)),
+        response => Ok(response),
+    }
+}
+
+/// Sends a request to the admin socket, which requires Unix sockets.
+#[cfg(not(unix))]
+pub async fn request_to(socket: &Path, _request: &Request) -> Result<Response> {
+    Err(anyhow!(
+        "The admin socket requires Unix sockets, to connect to {}",
+        socket.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_admin() {
+        let dir = std::env::temp_dir().join("file-guardian-test-admin");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let tokens = dir.join("tokens");
+        fs::write(&tokens, "alice:secret\n").unwrap();
+        let auth = Arc::new(Auth::from_tokens_file(&tokens).unwrap());
+        let read_only = Arc::new(AtomicBool::new(false));
+        let store = FileStore::new(dir.join("store"))
+            .unwrap()
+            .with_read_only(read_only.clone());
+        let alice = store.namespace("alice").unwrap();
+        let root_hash = alice
+            .store_files(vec![b"hello".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+        let auth = Arc::new(RwLock::new(auth));
+        let admin = Admin {
+            store,
+            auth: Some(auth.clone()),
+            tokens_file: Some(tokens),
+            gc_grace: gc::DEFAULT_GRACE,
+            read_only,
+        };
+        let socket = dir.join("admin.sock");
+        let (_stop, stopping) = watch::channel(false);
+        let path = socket.clone();
+        tokio::spawn(async move { admin.serve(&path, stopping).await });
+        while !socket.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let request = |request: Request| {
+            let socket = socket.clone();
+            async move { request_to(&socket, &request).await }
+        };
+
+        let uploads = match request(Request::List { user: None }).await {
+            Ok(Response::Uploads { uploads }) => uploads,
+            response => panic!("Unexpected {:?}", response),
+        };
+        let upload = Upload {
+            user: Some("alice".to_string()),
+            root_hash: root_hash.clone(),
+            files: 1,
+            size: 5,
+            expiry: None,
+        };
+        assert_eq!(uploads, [upload]);
+
+        // The tokens added are accepted by the connections accepted then
+        let user = Some("bob".to_string());
+        let token = match request(Request::AddToken { user }).await {
+            Ok(Response::Token { token }) => token,
+            response => panic!("Unexpected {:?}", response),
+        };
+        let bob = auth.read().unwrap().authenticate(&token).unwrap();
+        assert_eq!(bob.as_deref(), Some("bob"));
+        let user = Some("alice".to_string());
+        assert!(request(Request::RevokeTokens { user }).await.is_ok());
+        assert!(auth.read().unwrap().authenticate("secret").is_err());
+
+        let delete = || Request::Delete {
+            user: Some("alice".to_string()),
+            root_hash: root_hash.clone(),
+        };
+        let read_only = Request::ReadOnly { enabled: true };
+        assert!(request(read_only).await.is_ok());
+        let refused = request(delete()).await.unwrap_err();
+        assert!(refused.to_string().contains("read-only"));
+        let writable = Request::ReadOnly { enabled: false };
+        assert!(request(writable).await.is_ok());
+        assert!(request(delete()).await.is_ok());
+        assert!(request(delete()).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
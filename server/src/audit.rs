@@ -0,0 +1,214 @@
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::logger::{self, Fields};
+
+/// The size the audit log is rotated at by default.
+pub const DEFAULT_MAX_SIZE: u64 = 100 << 20;
+
+/// The number of rotated audit logs kept by default.
+pub const DEFAULT_KEEP: usize = 10;
+
+/// The commands of the TCP protocol audited: those reading, changing or
+/// deleting uploads, and `auth`, recorded for the authentications which
+/// failed.
+const AUDITED: &[&str] = &[
+    "auth", "upload", "put", "commit", "names", "download", "proof", "range",
+    "delete", "retain",
+];
+
+/// An append-only log of the operations served, a JSON object per line,
+/// answering who uploaded, fetched or deleted what, and when.
+///
+/// The log is rotated once it reaches its size: `<file>` is renamed to
+/// `<file>.1`, `<file>.1` to `<file>.2`, and so on, the oldest beyond the
+/// number kept being dropped.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    /// The file appended to, and its size.
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditLog {
+    /// Opens an audit log, appending to the file if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file of the log.
+    /// * `max_size` - The size the log is rotated at.
+    /// * `keep` - The number of rotated logs kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened.
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> Result<Self> {
+        let file = append(path).map_err(|e| {
+            anyhow!("Could not open audit log {}: {}", path.display(), e)
+        })?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    /// Records a connection served, if it served an audited operation: an
+    /// upload, a download, a proof, a deletion, or a failed authentication,
+    /// and any request of the HTTP API but its health checks.
+    ///
+    /// # Arguments
+    ///
+    /// * `fields` - The fields of the span of the connection, e.g. its peer,
+    ///   user, command, root and outcome.
+    /// * `error` - The error the connection failed with, if it did.
+    pub fn record(&self, fields: &Fields, error: Option<&anyhow::Error>) {
+        if !audited(fields) {
+            return;
+        }
+        let time = logger::timestamp(SystemTime::now());
+        let mut entry = Map::new();
+        entry.insert("time".into(), time.into());
+        for (key, value) in fields {
+            entry.insert(key.to_string(), value.clone());
+        }
+        if let Some(error) = error {
+            entry.insert("error".into(), format!("{:#}", error).into());
+        }
+        let line = format!("{}\n", Value::Object(entry));
+        if let Err(e) = self.append(line.as_bytes()) {
+            log::error!("Could not write the audit log: {}", e);
+        }
+    }
+
+    /// Appends a line to the log, rotating it first if it would grow beyond
+    /// its size.
+    fn append(&self, line: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_size {
+            self.rotate()?;
+            *file = (append(&self.path)?, 0);
+        }
+        // A single write, for the lines not to interleave
+        file.0.write_all(line)?;
+        file.1 += line.len() as u64;
+        Ok(())
+    }
+
+    /// Renames the log and those rotated before, dropping the oldest.
+    fn rotate(&self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        for n in (1..self.keep).rev() {
+            match std::fs::rename(rotated(n), rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        match self.keep {
+            0 => std::fs::remove_file(&self.path),
+            _ => std::fs::rename(&self.path, rotated(1)),
+        }
+    }
+}
+
+/// Opens a file to append to, readable by its owner only, as the log names
+/// the users and their addresses.
+fn append(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Returns whether a connection served an audited operation.
+fn audited(fields: &Fields) -> bool {
+    let field = |name| {
+        fields
+            .iter()
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| value.as_str())
+    };
+    match (field("command"), field("path")) {
+        (Some(command), _) => AUDITED.contains(&command),
+        (None, Some(path)) => path != "/health",
+        (None, None) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let dir = std::env::temp_dir().join("file-guardian-test-audit");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let log = AuditLog::open(&path, 1024, 2).unwrap();
+
+        let fields = |command: &str| -> Fields {
+            vec![
+                ("peer", "10.0.0.1:5678".into()),
+                ("user", "alice".into()),
+                ("command", command.into()),
+                ("root", "ab".repeat(32).into()),
+                ("index", 3.into()),
+                ("outcome", "ok".into()),
+            ]
+        };
+        log.record(&fields("download"), None);
+        // Neither the requests only reading metadata, nor the health checks
+        log.record(&fields("exists"), None);
+        let health = vec![("method", "GET".into()), ("path", "/health".into())];
+        log.record(&health, None);
+        let failed = anyhow!("Rejected client: Invalid token");
+        log.record(&fields("auth"), Some(&failed));
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines = text
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["command"], "download");
+        assert_eq!(lines[0]["user"], "alice");
+        assert_eq!(lines[0]["index"], 3);
+        assert!(lines[0]["time"].as_str().unwrap().ends_with('Z'));
+        assert!(lines[0].get("error").is_none());
+        assert_eq!(lines[1]["command"], "auth");
+        assert_eq!(lines[1]["error"], "Rejected client: Invalid token");
+
+        // The log is rotated at its size, keeping the last two rotated
+        for _ in 0..20 {
+            log.record(&fields("put"), None);
+        }
+        let rotated = |n| dir.join(format!("audit.log.{}", n));
+        assert!(rotated(1).exists() && rotated(2).exists());
+        assert!(!rotated(3).exists());
+        for path in [path.clone(), rotated(1), rotated(2)] {
+            let size = std::fs::metadata(&path).unwrap().len();
+            assert!(size > 0 && size <= 1024);
+        }
+        // and appended to once opened again
+        let size = std::fs::metadata(&path).unwrap().len();
+        drop(log);
+        let log = AuditLog::open(&path, 1024 * 1024, 2).unwrap();
+        log.record(&fields("delete"), None);
+        assert!(std::fs::metadata(&path).unwrap().len() > size);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,362 @@
+use crate::store;
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// How the tokens clients authenticate with are validated.
+pub enum Auth {
+    /// Only the tokens listed in a file are valid, each with the user it
+    /// authenticates, if any. Their SHA-256 is kept rather than the tokens,
+    /// so that they are compared in constant time.
+    Tokens(Vec<([u8; 32], Option<String>)>),
+    /// Tokens are `<id>.<signature>`, the signature being the hex encoded
+    /// HMAC-SHA256 of the id with a secret, so that tokens can be issued
+    /// without updating the server. The id is the user the token
+    /// authenticates.
+    Hmac(Vec<u8>),
+}
+
+impl Auth {
+    /// Reads the valid tokens from a file, one per line, as `<user>:<token>`,
+    /// or as `<token>` for a token without a user. Empty lines and lines
+    /// starting with `#` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, has no token, or names
+    /// an invalid user.
+    pub fn from_tokens_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            anyhow!("Could not read tokens {}: {}", path.display(), e)
+        })?;
+        let tokens = content
+            .lines()
+            .filter_map(parse_line)
+            .map(|(user, token)| {
+                if let Some(user) = user.filter(|u| !store::is_namespace(u)) {
+                    return Err(anyhow!(
+                        "Invalid user {} in {}",
+                        user,
+                        path.display()
+                    ));
+                }
+                let hash = Sha256::digest(token.as_bytes()).into();
+                Ok((hash, user.map(str::to_string)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if tokens.is_empty() {
+            return Err(anyhow!("No token in {}", path.display()));
+        }
+        Ok(Self::Tokens(tokens))
+    }
+
+    /// Reads the HMAC secret tokens are signed with from a file, ignoring
+    /// surrounding whitespace.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is too short to be a
+    /// secret.
+    pub fn from_hmac_secret_file(path: &Path) -> Result<Self> {
+        let secret = fs::read_to_string(path).map_err(|e| {
+            anyhow!("Could not read secret {}: {}", path.display(), e)
+        })?;
+        let secret = secret.trim();
+        if secret.len() < 16 {
+            return Err(anyhow!(
+                "The secret in {} must be at least 16 characters long",
+                path.display()
+            ));
+        }
+        Ok(Self::Hmac(secret.as_bytes().to_vec()))
+    }
+
+    /// Returns the users of the tokens, with how many tokens each has, `None`
+    /// being the user of the tokens without one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if tokens are signed with a secret, and so are not
+    /// known to the server.
+    pub fn users(&self) -> Result<Vec<(Option<String>, usize)>> {
+        match self {
+            Self::Tokens(tokens) => {
+                let mut users: Vec<(Option<String>, usize)> = vec![];
+                for (_, user) in tokens {
+                    match users.iter_mut().find(|(u, _)| u == user) {
+                        Some((_, count)) => *count += 1,
+                        None => users.push((user.clone(), 1)),
+                    }
+                }
+                Ok(users)
+            }
+            Self::Hmac(_) => {
+                Err(anyhow!("Tokens signed with an HMAC secret are not listed"))
+            }
+        }
+    }
+
+    /// Issues the token of a client, when tokens are signed with a secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The user the token authenticates, e.g. `backup-host`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if tokens are read from a file, or if the id is not
+    /// a valid user name.
+    pub fn issue(&self, id: &str) -> Result<String> {
+        match self {
+            Self::Tokens(_) => {
+                Err(anyhow!("Tokens can only be issued with an HMAC secret"))
+            }
+            Self::Hmac(secret) => {
+                if !store::is_namespace(id) {
+                    return Err(anyhow!("Invalid token id {}", id));
+                }
+                let signature = Self::mac(secret, id).finalize().into_bytes();
+                Ok(format!("{}.{}", id, hex::encode(signature)))
+            }
+        }
+    }
+
+    /// Authenticates a client with the token it presented.
+    ///
+    /// # Returns
+    ///
+    /// The user the token authenticates, whose uploads are kept apart from
+    /// those of other users, or `None` for a token without a user.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the token is invalid.
+    pub fn authenticate(&self, token: &str) -> Result<Option<String>> {
+        let invalid = || anyhow!("Invalid token");
+        match self {
+            Self::Tokens(tokens) => {
+                let hash: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+                // Compare with every token, so that the time taken does not
+                // tell which one matched
+                tokens
+                    .iter()
+                    .fold(None, |matched, (expected, user)| {
+                        match constant_time_eq(&hash, expected) {
+                            true => Some(user),
+                            false => matched,
+                        }
+                    })
+                    .cloned()
+                    .ok_or_else(invalid)
+            }
+            Self::Hmac(secret) => {
+                let (id, signature) =
+                    token.split_once('.').ok_or_else(invalid)?;
+                let signature =
+                    hex::decode(signature).map_err(|_| invalid())?;
+                Self::mac(secret, id)
+                    .verify(&signature)
+                    .map_err(|_| invalid())?;
+                // Tokens issued before ids named users may not name one
+                match store::is_namespace(id) {
+                    true => Ok(Some(id.to_string())),
+                    false => Err(invalid()),
+                }
+            }
+        }
+    }
+
+    /// Returns the HMAC-SHA256 of a token id.
+    fn mac(secret: &[u8], id: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+            .expect("HMAC accepts keys of any size");
+        mac.update(id.as_bytes());
+        mac
+    }
+}
+
+/// Splits a line of a tokens file into its user, if any, and its token.
+///
+/// # Returns
+///
+/// `None` for the empty lines and the comments.
+fn parse_line(line: &str) -> Option<(Option<&str>, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    Some(match line.split_once(':') {
+        Some((user, token)) => (Some(user), token),
+        None => (None, line),
+    })
+}
+
+/// Reads a tokens file again, replacing the tokens the connections are
+/// authenticated with, for those accepted from then on.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or has no valid token, the
+/// tokens of before being kept then.
+pub fn reload(auth: &RwLock<Arc<Auth>>, path: &Path) -> Result<()> {
+    let tokens = Auth::from_tokens_file(path)?;
+    *auth.write().unwrap() = Arc::new(tokens);
+    Ok(())
+}
+
+/// Adds a new random token to a tokens file, for the clients to
+/// authenticate with once the file is read again.
+///
+/// # Arguments
+///
+/// * `path` - The tokens file.
+/// * `user` - The user the token authenticates, if any.
+///
+/// # Returns
+///
+/// The token, 32 random bytes as a hex string.
+///
+/// # Errors
+///
+/// Returns an error if the user is invalid, or if the file cannot be
+/// written.
+pub fn add_token(path: &Path, user: Option<&str>) -> Result<String> {
+    if let Some(user) = user.filter(|u| !store::is_namespace(u)) {
+        return Err(anyhow!("Invalid user {}", user));
+    }
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| anyhow!("Could not generate a token: {}", e))?;
+    let token = hex::encode(bytes);
+    let content = fs::read_to_string(path).unwrap_or_default();
+    let mut line = match user {
+        Some(user) => format!("{}:{}\n", user, token),
+        None => format!("{}\n", token),
+    };
+    if !content.is_empty() && !content.ends_with('\n') {
+        line.insert(0, '\n');
+    }
+    // Appended, for the file to keep its permissions
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(token)
+}
+
+/// Removes every token of a user from a tokens file, keeping the comments
+/// and the other tokens.
+///
+/// # Arguments
+///
+/// * `path` - The tokens file.
+/// * `user` - The user, `None` for the tokens without one.
+///
+/// # Returns
+///
+/// The number of tokens removed.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or written, or if it would be
+/// left without a token, locking every client out.
+pub fn revoke_tokens(path: &Path, user: Option<&str>) -> Result<usize> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        anyhow!("Could not read tokens {}: {}", path.display(), e)
+    })?;
+    let (revoked, kept): (Vec<_>, Vec<_>) = content
+        .lines()
+        .partition(|line| parse_line(line).is_some_and(|(u, _)| u == user));
+    if !kept.iter().any(|line| parse_line(line).is_some()) {
+        return Err(anyhow!("Revoking every token would lock the clients out"));
+    }
+    // Replaced at once, for the server never to read a partial file
+    let temp = path.with_extension("tmp");
+    let mut kept = kept.join("\n");
+    kept.push('\n');
+    fs::write(&temp, kept)?;
+    fs::set_permissions(&temp, fs::metadata(path)?.permissions())?;
+    fs::rename(&temp, path)?;
+    Ok(revoked.len())
+}
+
+/// Compares two hashes in constant time.
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_file() {
+        let path = std::env::temp_dir().join("file-guardian-test-tokens");
+        fs::write(&path, "# backup host\ns3cr3t-token\n\nalice:other-token\n")
+            .unwrap();
+        let auth = Auth::from_tokens_file(&path).unwrap();
+        assert_eq!(auth.authenticate("s3cr3t-token").unwrap(), None);
+        assert_eq!(
+            auth.authenticate("other-token").unwrap().as_deref(),
+            Some("alice")
+        );
+        assert!(auth.authenticate("alice:other-token").is_err());
+        assert!(auth.authenticate("# backup host").is_err());
+        assert!(auth.authenticate("").is_err());
+        assert!(auth.issue("backup-host").is_err());
+
+        fs::write(&path, "../alice:token\n").unwrap();
+        assert!(Auth::from_tokens_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_manage_tokens() {
+        let path = std::env::temp_dir().join("file-guardian-test-tokens2");
+        fs::write(&path, "# backup host\nalice:other-token").unwrap();
+        let token = add_token(&path, Some("bob")).unwrap();
+        assert_eq!(token.len(), 64);
+        assert_ne!(add_token(&path, Some("bob")).unwrap(), token);
+        assert!(add_token(&path, Some("../bob")).is_err());
+        let auth = Auth::from_tokens_file(&path).unwrap();
+        assert_eq!(auth.authenticate(&token).unwrap().as_deref(), Some("bob"));
+        let users = auth.users().unwrap();
+        let alice = (Some("alice".to_string()), 1);
+        assert_eq!(users, [alice, (Some("bob".to_string()), 2)]);
+
+        assert_eq!(revoke_tokens(&path, Some("bob")).unwrap(), 2);
+        assert_eq!(revoke_tokens(&path, None).unwrap(), 0);
+        assert!(revoke_tokens(&path, Some("alice")).is_err());
+        let auth = Auth::from_tokens_file(&path).unwrap();
+        assert!(auth.authenticate(&token).is_err());
+        assert!(auth.authenticate("other-token").is_ok());
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# backup host\n"));
+        assert!(Auth::Hmac(b"0123456789abcdef".to_vec()).users().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_hmac() {
+        let auth = Auth::Hmac(b"0123456789abcdef".to_vec());
+        let token = auth.issue("backup-host").unwrap();
+        assert!(token.starts_with("backup-host."));
+        assert_eq!(
+            auth.authenticate(&token).unwrap().as_deref(),
+            Some("backup-host")
+        );
+        assert!(auth
+            .authenticate(&token.replace("backup", "other"))
+            .is_err());
+        assert!(auth.authenticate("backup-host").is_err());
+        let other = Auth::Hmac(b"another secret!!".to_vec());
+        assert!(other.authenticate(&token).is_err());
+        assert!(auth.issue("a.b").is_err());
+        assert!(auth.issue("staging").is_err());
+    }
+}
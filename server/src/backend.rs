@@ -0,0 +1,840 @@
+use crate::store::{is_namespace, BLOBS_DIR, STAGING_DIR};
+use anyhow::{anyhow, Result};
+use merkle_tree::MerkleTree;
+use std::{
+    fmt,
+    future::Future,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
+
+/// The file, in the directory of an upload, of its Merkle tree, in the
+/// binary format of [`MerkleTree::to_bytes`].
+pub const TREE_FILE: &str = "tree.bin";
+
+/// The file of the Merkle tree of the uploads stored by earlier versions, as
+/// JSON, replaced by a [`TREE_FILE`] when the upload is first opened.
+const LEGACY_TREE_FILE: &str = "tree.json";
+
+/// The number of blobs written and uploads deleted by the process, naming
+/// the files they are written to before being moved into place, and the
+/// directories of the uploads being deleted.
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// The directories of uploads opened by the process, already laid out in
+/// shards, see [`LocalBackend`].
+static SHARDED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// The future of an operation of a [`StorageBackend`].
+pub type BoxFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// The content of a blob being stored, read as it is stored, e.g. from a
+/// staged file, so that a blob is never held whole in memory.
+pub type BlobReader<'a> = Box<dyn AsyncRead + Send + Unpin + 'a>;
+
+/// Where the content of a blob being read is copied to, e.g. the stream to
+/// the client downloading it.
+pub type BlobWriter<'a> = &'a mut (dyn AsyncWrite + Send + Unpin);
+
+/// Where a [`FileStore`](crate::store::FileStore) keeps its uploads, so that
+/// other storage can be used without changing the handlers of the protocol.
+///
+/// An upload is a set of blobs named within the upload, e.g. its files and
+/// the names of its files, along with its Merkle tree. The tree is stored
+/// once every file of the upload is, and an upload only exists once it has
+/// a tree. The root hashes given to a backend are valid, hex strings of 32
+/// bytes, or [`BLOBS_DIR`](crate::store::BLOBS_DIR), under which the
+/// contents of the files are shared by the uploads.
+pub trait StorageBackend: Send + Sync {
+    /// Stores a blob of an upload, replacing the blob of the same name. The
+    /// blob is never seen partially written.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    /// * `name` - The name of the blob.
+    /// * `blob` - The content of the blob.
+    /// * `size` - The size of the blob, which `blob` must have.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the blob cannot be read or stored, or has another
+    /// size.
+    fn put_blob<'a>(
+        &'a self,
+        root_hash: &'a str,
+        name: &'a str,
+        blob: BlobReader<'a>,
+        size: u64,
+    ) -> BoxFuture<'a, ()>;
+
+    /// Copies a blob of an upload, or a range of it, shorter at the end of
+    /// the blob, to `out`, as it is read.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of bytes copied, or `None` if there is no such
+    /// blob.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the blob cannot be read, or `out` written.
+    fn get_blob<'a>(
+        &'a self,
+        root_hash: &'a str,
+        name: &'a str,
+        range: Option<Range<u64>>,
+        out: BlobWriter<'a>,
+    ) -> BoxFuture<'a, Option<u64>>;
+
+    /// Returns the size of a blob of an upload, in bytes, or `None` if there
+    /// is no such blob.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the blob cannot be read.
+    fn blob_size<'a>(
+        &'a self,
+        root_hash: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Option<u64>>;
+
+    /// Deletes a blob of an upload, which succeeds if there is no such blob.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the blob cannot be deleted.
+    fn delete_blob<'a>(
+        &'a self,
+        root_hash: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, ()>;
+
+    /// Stores the Merkle tree of an upload, once all of its files are, so
+    /// that the upload exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tree cannot be stored.
+    fn put_tree<'a>(
+        &'a self,
+        root_hash: &'a str,
+        tree: &'a MerkleTree,
+    ) -> BoxFuture<'a, ()>;
+
+    /// Returns the Merkle tree of an upload, or `None` if there is no such
+    /// upload.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tree cannot be read, or is invalid.
+    fn get_tree<'a>(
+        &'a self,
+        root_hash: &'a str,
+    ) -> BoxFuture<'a, Option<MerkleTree>>;
+
+    /// Returns the root hashes of the uploads, in any order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the uploads cannot be listed.
+    fn list(&self) -> BoxFuture<'_, Vec<String>>;
+
+    /// Returns the root hashes of the uploads left partial, with blobs but
+    /// no tree, e.g. by a crash while they were stored, in any order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the uploads cannot be listed.
+    fn list_partial(&self) -> BoxFuture<'_, Vec<String>>;
+
+    /// Returns the names of the blobs of an upload, its tree included, in
+    /// any order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the blobs cannot be listed.
+    fn list_blobs<'a>(
+        &'a self,
+        root_hash: &'a str,
+    ) -> BoxFuture<'a, Vec<String>>;
+
+    /// Returns the names of the namespaces holding blobs, in any order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the namespaces cannot be listed.
+    fn namespaces(&self) -> BoxFuture<'_, Vec<String>>;
+
+    /// Deletes an upload, its tree first so that it is never seen with some
+    /// of its files missing, and returns whether it existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the upload cannot be deleted.
+    fn delete<'a>(&'a self, root_hash: &'a str) -> BoxFuture<'a, bool>;
+
+    /// Returns the backend of a namespace, holding its uploads apart from
+    /// those of this backend and of the other namespaces.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the namespace, a valid one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage of the namespace cannot be created.
+    fn namespace(&self, name: &str) -> Result<Arc<dyn StorageBackend>>;
+}
+
+/// Returns the error of an upload that is not as it was stored, e.g. after
+/// its storage was tampered with.
+pub fn corrupt(root_hash: &str, reason: impl fmt::Display) -> anyhow::Error {
+    anyhow!("Upload {} is corrupt: {}", root_hash, reason)
+}
+
+/// Reads the next `len` bytes of a blob being stored, e.g. a part of it.
+///
+/// # Errors
+///
+/// Returns an error if the blob cannot be read, or ends before.
+pub async fn read_part(blob: &mut BlobReader<'_>, len: u64) -> Result<Vec<u8>> {
+    let mut part = vec![0; usize::try_from(len)?];
+    blob.read_exact(&mut part)
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => anyhow!("The blob is truncated"),
+            _ => e.into(),
+        })?;
+    Ok(part)
+}
+
+/// Checks that a blob being stored has been read to its end.
+///
+/// # Errors
+///
+/// Returns an error if the blob cannot be read, or is longer.
+pub async fn check_end(blob: &mut BlobReader<'_>) -> Result<()> {
+    match blob.read(&mut [0]).await? {
+        0 => Ok(()),
+        _ => Err(anyhow!("The blob is longer than its size")),
+    }
+}
+
+/// The default backend, keeping each upload in a directory of the local
+/// disk named by its root hash, with a file per blob and its tree in a
+/// `tree.bin`. The blobs and the tree are written in the staging directory
+/// and flushed to the disk before being moved into place, so that a crash
+/// never leaves a truncated file.
+///
+/// The directories of the uploads, and the contents under
+/// [`BLOBS_DIR`], are spread over two levels of directories named by the
+/// first two bytes of their hash, e.g. `ab/cd/abcd...`, so that no
+/// directory holds more than a few hundred entries. The uploads stored by
+/// earlier versions, directly under the root directory, are moved into
+/// their shard when the backend is first created.
+pub struct LocalBackend {
+    root_dir: PathBuf,
+}
+
+/// Returns the path of an entry named by a hash under a directory, in its
+/// shard, e.g. `<dir>/ab/cd/abcd...`.
+pub fn sharded(dir: &Path, name: &str) -> PathBuf {
+    match (name.get(..2), name.get(2..4)) {
+        (Some(first), Some(second)) => dir.join(first).join(second).join(name),
+        // Not named by a hash
+        _ => dir.join(name),
+    }
+}
+
+/// Returns whether a name is the name of a shard: two lowercase hex digits.
+pub fn is_shard(name: &str) -> bool {
+    name.len() == 2
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Returns the paths of the entries of the shards under a directory, e.g.
+/// of the uploads, with their names.
+fn read_shards(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut found = vec![];
+    for shard in shards(dir)? {
+        for shard in shards(&shard)? {
+            for entry in std::fs::read_dir(&shard)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                found.push((name, entry.path()));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Returns the shards of a directory, which may not exist.
+fn shards(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut shards = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if is_shard(&entry.file_name().to_string_lossy())
+            && entry.file_type()?.is_dir()
+        {
+            shards.push(entry.path());
+        }
+    }
+    Ok(shards)
+}
+
+/// Moves the uploads of a directory laid out by earlier versions, each
+/// directly under it, and the contents under [`BLOBS_DIR`], into their
+/// shards.
+///
+/// # Returns
+///
+/// Returns the number of entries moved.
+///
+/// # Errors
+///
+/// Returns an error if the directory cannot be read, an entry moved, or if
+/// it holds a namespace named like a shard.
+fn shard_layout(root_dir: &Path) -> Result<usize> {
+    let mut moved = 0;
+    let blobs = root_dir.join(BLOBS_DIR);
+    for (dir, is_upload) in [(root_dir, true), (blobs.as_path(), false)] {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let file_type = entry.file_type()?;
+            let hash = name.split('.').next().unwrap_or_default();
+            let flat = match is_upload {
+                true => file_type.is_dir() && is_root_hash(&name),
+                false => file_type.is_file() && is_root_hash(hash),
+            };
+            if is_upload && is_shard(&name) && file_type.is_dir() {
+                // A shard only holds shards of the second level
+                let first =
+                    std::fs::read_dir(entry.path())?.next().transpose()?;
+                if let Some(first) = first {
+                    if !is_shard(&first.file_name().to_string_lossy()) {
+                        return Err(anyhow!(
+                            "{} is a namespace named like a directory of the \
+                             sharded layout, and must be renamed",
+                            entry.path().display()
+                        ));
+                    }
+                }
+            }
+            if !flat {
+                continue;
+            }
+            let to = sharded(dir, &name);
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(entry.path(), to)?;
+            moved += 1;
+        }
+    }
+    Ok(moved)
+}
+
+/// Returns whether a name is a root hash, or the hash of a content: 32 bytes
+/// as hex.
+fn is_root_hash(name: &str) -> bool {
+    name.len() == 64 && hex::decode(name).is_ok()
+}
+
+impl LocalBackend {
+    /// Creates a backend storing the uploads under a directory, created if
+    /// it does not exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_dir` - The directory of the uploads.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory cannot be created, or its uploads
+    /// cannot be moved into their shards.
+    pub fn new(root_dir: impl AsRef<Path>) -> Result<Self> {
+        let root_dir = root_dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&root_dir)?;
+        // Once per directory and process, every store of the connections
+        // creating a backend
+        let mut sharded = SHARDED.lock().unwrap();
+        if !sharded.contains(&root_dir) {
+            let moved = shard_layout(&root_dir)?;
+            if moved > 0 {
+                log::info!(
+                    "Moved {} uploads and contents of {} into shards",
+                    moved,
+                    root_dir.display()
+                );
+            }
+            sharded.push(root_dir.clone());
+        }
+        drop(sharded);
+        Ok(Self { root_dir })
+    }
+
+    /// Returns the directory of an upload, or of the contents.
+    fn dir(&self, root_hash: &str) -> PathBuf {
+        match root_hash {
+            BLOBS_DIR => self.root_dir.join(BLOBS_DIR),
+            root_hash => sharded(&self.root_dir, root_hash),
+        }
+    }
+
+    /// Returns the path of a blob of an upload, or of a content.
+    fn path(&self, root_hash: &str, name: &str) -> PathBuf {
+        match root_hash {
+            BLOBS_DIR => sharded(&self.dir(root_hash), name),
+            root_hash => self.dir(root_hash).join(name),
+        }
+    }
+
+    /// Writes a file of an upload durably, then moves it into place, in a
+    /// single atomic rename.
+    async fn write(
+        &self,
+        root_hash: &str,
+        name: &str,
+        content: BlobReader<'_>,
+        size: u64,
+    ) -> Result<()> {
+        let staging = self.root_dir.join(STAGING_DIR);
+        fs::create_dir_all(&staging).await?;
+        let tmp = staging.join(format!(
+            "storing-{}-{}-{}",
+            root_hash,
+            process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let path = self.path(root_hash, name);
+        let dir = path.parent().unwrap_or(&self.root_dir).to_path_buf();
+        let written = async {
+            write_durably(&tmp, content, size).await?;
+            fs::create_dir_all(&dir).await?;
+            Ok(fs::rename(&tmp, &path).await?)
+        };
+        if let Err(e) = written.await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+        sync_dir(&dir).await
+    }
+
+    /// Replaces the JSON tree of an upload stored by an earlier version with
+    /// its binary tree, much smaller and faster to read.
+    async fn migrate_tree(
+        &self,
+        root_hash: &str,
+        tree: &MerkleTree,
+    ) -> Result<()> {
+        let bytes = tree.to_bytes();
+        let size = bytes.len() as u64;
+        self.write(root_hash, TREE_FILE, Box::new(&bytes[..]), size)
+            .await?;
+        let dir = self.dir(root_hash);
+        fs::remove_file(dir.join(LEGACY_TREE_FILE)).await?;
+        sync_dir(&dir).await
+    }
+
+    /// Returns the root hashes of the uploads, complete or partial, in any
+    /// order.
+    async fn roots(&self, complete: bool) -> Result<Vec<String>> {
+        let mut roots = vec![];
+        let root_dir = self.root_dir.clone();
+        let uploads =
+            tokio::task::spawn_blocking(move || read_shards(&root_dir))
+                .await??;
+        for (root_hash, dir) in uploads {
+            if !is_root_hash(&root_hash) {
+                continue;
+            }
+            let has_tree = fs::try_exists(dir.join(TREE_FILE)).await?
+                || fs::try_exists(dir.join(LEGACY_TREE_FILE)).await?;
+            if has_tree == complete {
+                roots.push(root_hash);
+            }
+        }
+        Ok(roots)
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn put_blob<'a>(
+        &'a self,
+        root_hash: &'a str,
+        name: &'a str,
+        blob: BlobReader<'a>,
+        size: u64,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(self.write(root_hash, name, blob, size))
+    }
+
+    fn get_blob<'a>(
+        &'a self,
+        root_hash: &'a str,
+        name: &'a str,
+        range: Option<Range<u64>>,
+        out: BlobWriter<'a>,
+    ) -> BoxFuture<'a, Option<u64>> {
+        Box::pin(async move {
+            let path = self.path(root_hash, name);
+            let mut file = match File::open(path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let copied = match range {
+                Some(range) => {
+                    file.seek(SeekFrom::Start(range.start)).await?;
+                    let len = range.end.saturating_sub(range.start);
+                    tokio::io::copy(&mut file.take(len), out).await?
+                }
+                None => tokio::io::copy(&mut file, out).await?,
+            };
+            Ok(Some(copied))
+        })
+    }
+
+    fn blob_size<'a>(
+        &'a self,
+        root_hash: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Option<u64>> {
+        Box::pin(async move {
+            let path = self.path(root_hash, name);
+            match fs::metadata(path).await {
+                Ok(metadata) if metadata.is_file() => Ok(Some(metadata.len())),
+                Ok(_) => Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn delete_blob<'a>(
+        &'a self,
+        root_hash: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.path(root_hash, name);
+            match fs::remove_file(&path).await {
+                Ok(()) => sync_dir(path.parent().unwrap_or(&path)).await,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn put_tree<'a>(
+        &'a self,
+        root_hash: &'a str,
+        tree: &'a MerkleTree,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let bytes = tree.to_bytes();
+            let size = bytes.len() as u64;
+            self.write(root_hash, TREE_FILE, Box::new(&bytes[..]), size)
+                .await?;
+            // The directories of the upload and of its shards may be new
+            let dir = self.dir(root_hash);
+            for dir in dir.ancestors().skip(1).take(3) {
+                sync_dir(dir).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_tree<'a>(
+        &'a self,
+        root_hash: &'a str,
+    ) -> BoxFuture<'a, Option<MerkleTree>> {
+        Box::pin(async move {
+            let dir = self.dir(root_hash);
+            let invalid = |e: &dyn fmt::Display| {
+                corrupt(root_hash, format!("invalid tree: {}", e))
+            };
+            match fs::read(dir.join(TREE_FILE)).await {
+                Ok(bytes) => {
+                    let tree = MerkleTree::from_bytes(&bytes)
+                        .map_err(|e| invalid(&e))?;
+                    return Ok(Some(tree));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            let legacy = fs::read_to_string(dir.join(LEGACY_TREE_FILE)).await;
+            let tree_json = match legacy {
+                Ok(tree_json) => tree_json,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let tree: MerkleTree =
+                serde_json::from_str(&tree_json).map_err(|e| invalid(&e))?;
+            // Serving the upload does not depend on its migration, e.g. on a
+            // read-only store
+            if let Err(e) = self.migrate_tree(root_hash, &tree).await {
+                log::warn!(
+                    "Could not migrate the tree of {}: {}",
+                    root_hash,
+                    e
+                );
+            }
+            Ok(Some(tree))
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Vec<String>> {
+        Box::pin(self.roots(true))
+    }
+
+    fn list_partial(&self) -> BoxFuture<'_, Vec<String>> {
+        Box::pin(self.roots(false))
+    }
+
+    fn list_blobs<'a>(
+        &'a self,
+        root_hash: &'a str,
+    ) -> BoxFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let mut names = vec![];
+            if root_hash == BLOBS_DIR {
+                let dir = self.dir(BLOBS_DIR);
+                let blobs =
+                    tokio::task::spawn_blocking(move || read_shards(&dir))
+                        .await??;
+                for (name, path) in blobs {
+                    if fs::metadata(path).await?.is_file() {
+                        names.push(name);
+                    }
+                }
+                return Ok(names);
+            }
+            let mut entries = match fs::read_dir(self.dir(root_hash)).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(names)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_file() {
+                    names.push(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+            Ok(names)
+        })
+    }
+
+    fn namespaces(&self) -> BoxFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let mut names = vec![];
+            let mut entries = fs::read_dir(&self.root_dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if is_namespace(&name) && entry.file_type().await?.is_dir() {
+                    names.push(name);
+                }
+            }
+            Ok(names)
+        })
+    }
+
+    fn delete<'a>(&'a self, root_hash: &'a str) -> BoxFuture<'a, bool> {
+        Box::pin(async move {
+            // The directory of the upload is moved out of the way at once,
+            // rather than its tree removed first, under a name of its own
+            // for a deletion left unfinished by a crash not to be in the way
+            let dir = self.dir(root_hash);
+            if !fs::try_exists(&dir).await? {
+                return Ok(false);
+            }
+            let staging = self.root_dir.join(STAGING_DIR);
+            fs::create_dir_all(&staging).await?;
+            let deleted = staging.join(format!(
+                "deleted-{}-{}-{}",
+                root_hash,
+                process::id(),
+                WRITES.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::rename(&dir, &deleted).await?;
+            fs::remove_dir_all(deleted).await?;
+            Ok(true)
+        })
+    }
+
+    fn namespace(&self, name: &str) -> Result<Arc<dyn StorageBackend>> {
+        Ok(Arc::new(Self::new(self.root_dir.join(name))?))
+    }
+}
+
+/// Writes a file and flushes it to the disk before returning.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be written, or `content` is not of
+/// `size` bytes.
+async fn write_durably(
+    path: &Path,
+    mut content: BlobReader<'_>,
+    size: u64,
+) -> Result<()> {
+    let mut file = File::create(path).await?;
+    let written = tokio::io::copy(&mut content, &mut file).await?;
+    if written != size {
+        return Err(anyhow!("Expected {} bytes, read {}", size, written));
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Flushes the entries of a directory to the disk, so that the files created
+/// or renamed in it survive a crash.
+async fn sync_dir(dir: &Path) -> Result<()> {
+    // Directories cannot be opened as files on every platform
+    #[cfg(unix)]
+    File::open(dir).await?.sync_all().await?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_local_backend() {
+        let dir = std::env::temp_dir().join("file-guardian-test-backend");
+        let _ = fs::remove_dir_all(&dir);
+        let backend = LocalBackend::new(&dir).unwrap();
+        let tree = MerkleTree::new(&[b"hello".to_vec()]).unwrap();
+        let root_hash = hex::encode(tree.root().unwrap());
+
+        // An upload only exists once its tree is stored
+        let blob = Box::new(&b"hello"[..]);
+        backend.put_blob(&root_hash, "0", blob, 5).await.unwrap();
+        // A blob of another size than the one given is not stored
+        let blob = Box::new(&b"hello"[..]);
+        assert!(backend.put_blob(&root_hash, "1", blob, 6).await.is_err());
+        assert!(backend.list().await.unwrap().is_empty());
+        assert!(backend.get_tree(&root_hash).await.unwrap().is_none());
+        backend.put_tree(&root_hash, &tree).await.unwrap();
+        assert_eq!(backend.list().await.unwrap(), [root_hash.as_str()]);
+        let get = |range| async {
+            let mut blob = vec![];
+            let copied = backend.get_blob(&root_hash, "0", range, &mut blob);
+            assert_eq!(copied.await.unwrap(), Some(blob.len() as u64));
+            blob
+        };
+        assert_eq!(get(None).await, b"hello");
+        assert_eq!(get(Some(1..4)).await, b"ell");
+        assert_eq!(get(Some(3..13)).await, b"lo");
+        let size = backend.blob_size(&root_hash, "0").await.unwrap();
+        assert_eq!(size, Some(5));
+        let mut blob = vec![];
+        let missing = backend.get_blob(&root_hash, "1", None, &mut blob);
+        assert!(missing.await.unwrap().is_none());
+        assert!(backend.blob_size(&root_hash, "1").await.unwrap().is_none());
+        assert_eq!(fs::read_dir(dir.join(STAGING_DIR)).unwrap().count(), 0);
+
+        // The JSON trees of earlier versions are migrated when opened
+        let upload = sharded(&dir, &root_hash);
+        fs::remove_file(upload.join(TREE_FILE)).unwrap();
+        let json = serde_json::to_string(&tree).unwrap();
+        fs::write(upload.join(LEGACY_TREE_FILE), json).unwrap();
+        assert_eq!(backend.list().await.unwrap().len(), 1);
+        let migrated = backend.get_tree(&root_hash).await.unwrap().unwrap();
+        assert_eq!(migrated.leaves(), tree.leaves());
+        assert!(upload.join(TREE_FILE).exists());
+        assert!(!upload.join(LEGACY_TREE_FILE).exists());
+
+        // The uploads of a namespace are kept apart
+        let namespace = backend.namespace("alice").unwrap();
+        assert!(namespace.list().await.unwrap().is_empty());
+        assert_eq!(backend.list().await.unwrap().len(), 1);
+
+        // An upload is deleted even if an earlier deletion of it was left
+        // unfinished
+        let unfinished =
+            dir.join(STAGING_DIR).join(format!("deleted-{}", root_hash));
+        fs::create_dir_all(unfinished.join("0")).unwrap();
+        assert!(backend.delete(&root_hash).await.unwrap());
+        assert!(!backend.delete(&root_hash).await.unwrap());
+        assert!(backend.list().await.unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shard_layout() {
+        let dir = std::env::temp_dir().join("file-guardian-test-shards");
+        let _ = fs::remove_dir_all(&dir);
+        let tree = MerkleTree::new(&[b"hello".to_vec()]).unwrap();
+        let root_hash = hex::encode(tree.root().unwrap());
+        let hash = hex::encode(tree.leaves()[0]);
+
+        // The flat layout of earlier versions, in the store and a namespace
+        for store in [dir.clone(), dir.join("alice")] {
+            let upload = store.join(&root_hash);
+            fs::create_dir_all(&upload).unwrap();
+            fs::write(upload.join(TREE_FILE), tree.to_bytes()).unwrap();
+            fs::create_dir_all(store.join(BLOBS_DIR)).unwrap();
+            fs::write(store.join(BLOBS_DIR).join(&hash), b"hello").unwrap();
+            let refs = format!("{}.refs", hash);
+            fs::write(store.join(BLOBS_DIR).join(refs), b"1").unwrap();
+        }
+        let backend = LocalBackend::new(&dir).unwrap();
+        assert!(sharded(&dir, &root_hash).join(TREE_FILE).exists());
+        assert!(!dir.join(&root_hash).exists());
+        assert_eq!(backend.list().await.unwrap(), [root_hash.as_str()]);
+        let mut blobs = backend.list_blobs(BLOBS_DIR).await.unwrap();
+        blobs.sort();
+        assert_eq!(blobs, [hash.clone(), format!("{}.refs", hash)]);
+        let size = backend.blob_size(BLOBS_DIR, &hash).await.unwrap();
+        assert_eq!(size, Some(5));
+        // The shards are not namespaces, but the namespaces are moved too
+        assert_eq!(backend.namespaces().await.unwrap(), ["alice"]);
+        let alice = backend.namespace("alice").unwrap();
+        assert_eq!(alice.list().await.unwrap(), [root_hash.as_str()]);
+        assert!(!dir.join("alice").join(&root_hash).exists());
+
+        // A namespace named like a shard is not mistaken for one
+        let other = dir.join("other");
+        fs::create_dir_all(other.join("ab").join(&root_hash)).unwrap();
+        assert!(LocalBackend::new(&other).is_err());
+        assert!(!is_namespace("ab") && !is_namespace("AB"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,651 @@
+use anyhow::{anyhow, Result};
+use protocol::compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::AsyncWrite;
+
+use crate::identity::RootSignature;
+use crate::logger;
+use crate::store::FileStore;
+
+/// The version of the format of the backups.
+const BACKUP_VERSION: u32 = 1;
+
+/// The first entry of a backup, describing it.
+const DESCRIPTION: &str = "file-guardian-backup.json";
+
+/// The size of the blocks of a tar archive.
+const BLOCK: usize = 512;
+
+/// The largest size a tar header holds in octal, larger sizes being written
+/// in base 256, as GNU tar does.
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// The largest description of an upload read from a backup, so that an
+/// invalid archive cannot make the import allocate an arbitrary amount of
+/// memory.
+const MAX_UPLOAD_LEN: u64 = 1 << 30;
+
+/// The description of a backup, its first entry.
+#[derive(Serialize, Deserialize)]
+struct Description {
+    version: u32,
+    /// When the backup was exported, e.g. `2024-05-01T12:30:00Z`.
+    created: String,
+}
+
+/// An upload of a backup, `<user>uploads/<root hash>.json` in the archive,
+/// where `<user>` is `users/<name>/` for the uploads of a user, and empty
+/// otherwise. The contents of its files follow those of the uploads before
+/// it, as `<user>blobs/<sha256>`, each content of a user being exported
+/// once, before the first upload holding it.
+#[derive(Serialize, Deserialize)]
+struct BackedUpload {
+    root_hash: String,
+    /// The files of the upload, in the order of the leaves.
+    files: Vec<BackedFile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    names: Option<Vec<String>>,
+    /// When the upload expires, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+    /// The signature of the root hash by the server, if it signs them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<RootSignature>,
+}
+
+/// A file of an upload of a backup.
+#[derive(Serialize, Deserialize)]
+struct BackedFile {
+    sha256: String,
+    size: u64,
+}
+
+/// What an export or an import of a backup copied.
+#[derive(Debug, Default)]
+pub struct BackupReport {
+    /// Whether the backup was imported rather than exported.
+    pub imported: bool,
+    /// The number of uploads copied.
+    pub uploads: usize,
+    /// The number of uploads imported that the store already held.
+    pub held: usize,
+    /// The number of contents of files copied, each once per user.
+    pub contents: usize,
+    /// The total size of the contents copied, in bytes.
+    pub size: u64,
+}
+
+impl fmt::Display for BackupReport {
+    /// Formats the summary of the report, on a single line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.imported {
+            true => write!(
+                f,
+                "Imported {} uploads, {} of which were already stored, and \
+                 {} contents of files ({} bytes)",
+                self.uploads, self.held, self.contents, self.size
+            ),
+            false => write!(
+                f,
+                "Exported {} uploads, and {} contents of files ({} bytes)",
+                self.uploads, self.contents, self.size
+            ),
+        }
+    }
+}
+
+/// Exports every upload of a store and of its namespaces to a backup: a tar
+/// archive compressed with zstd, holding the contents of the files of the
+/// uploads, and the leaves, names and expiry of each upload, whatever the
+/// backend and the layout of the store.
+///
+/// Every file is hashed again as it is exported, so that a backup only holds
+/// uploads that were stored intact. The archive is written next to `output`,
+/// and renamed to it once complete.
+///
+/// # Arguments
+///
+/// * `store` - The store to export.
+/// * `output` - The archive to write, readable by its owner only.
+///
+/// # Errors
+///
+/// Returns an error if the store cannot be read, if a file is corrupt, or if
+/// the archive cannot be written.
+pub async fn export(store: &FileStore, output: &Path) -> Result<BackupReport> {
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(&partial).map_err(|e| {
+        anyhow!("Could not create {}: {}", partial.display(), e)
+    })?;
+    let level = compression::DEFAULT_LEVEL;
+    let mut archive =
+        zstd::stream::write::Encoder::new(BufWriter::new(file), level)?;
+
+    match write_backup(store, &mut archive).await {
+        Ok(report) => {
+            // The end of the archive is marked by two empty blocks
+            archive.write_all(&[0; 2 * BLOCK])?;
+            let file =
+                archive.finish()?.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            fs::rename(&partial, output)?;
+            Ok(report)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Writes the entries of a backup of a store, see [`export`].
+async fn write_backup<W: Write + Send + Unpin>(
+    store: &FileStore,
+    archive: &mut W,
+) -> Result<BackupReport> {
+    let now = SystemTime::now();
+    let mtime = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let description = Description {
+        version: BACKUP_VERSION,
+        created: logger::timestamp(now),
+    };
+    let description = serde_json::to_vec_pretty(&description)?;
+    append(archive, DESCRIPTION, &description, mtime)?;
+
+    let mut report = BackupReport::default();
+    for store in store.with_namespaces().await? {
+        let prefix = match store.namespace_name() {
+            Some(name) => format!("users/{}/", name),
+            None => String::new(),
+        };
+        let mut exported = HashSet::new();
+        for upload in store.list_uploads().await? {
+            let root_hash = upload.root_hash;
+            let tree = store.get_tree(&root_hash).await?;
+            let mut files = vec![];
+            for (index, leaf) in tree.leaves().iter().enumerate() {
+                let sha256 = hex::encode(leaf);
+                let size = store.file_size(&root_hash, index).await?;
+                if exported.insert(sha256.clone()) {
+                    let name = format!("{}blobs/{}", prefix, sha256);
+                    write_header(archive, &name, size, mtime)?;
+                    let mut entry = Entry {
+                        archive: &mut *archive,
+                        hasher: Sha256::new(),
+                        len: 0,
+                    };
+                    store
+                        .copy_file(&root_hash, index, None, &mut entry)
+                        .await?;
+                    let (len, hash) = (entry.len, entry.hasher.finalize());
+                    if len != size || hash[..] != leaf[..] {
+                        return Err(anyhow!(
+                            "File {} of {} is corrupt, and must be scrubbed \
+                             before the store is exported",
+                            index,
+                            root_hash
+                        ));
+                    }
+                    pad(archive, size)?;
+                    report.contents += 1;
+                    report.size += size;
+                }
+                files.push(BackedFile { sha256, size });
+            }
+            let backed = BackedUpload {
+                names: store.names(&root_hash).await?,
+                expires: store.expiry(&root_hash).await?,
+                signature: store.signature(&root_hash).await?,
+                root_hash,
+                files,
+            };
+            let name = format!("{}uploads/{}.json", prefix, backed.root_hash);
+            append(archive, &name, &serde_json::to_vec(&backed)?, mtime)?;
+            report.uploads += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Imports the uploads of a backup written by [`export`] into a store, and
+/// into its namespaces for the uploads of users.
+///
+/// Every content is hashed again as it is received, and every upload
+/// committed from its contents, the store building its tree again: an
+/// upload is only imported if the root of that tree is the root of the
+/// upload. The uploads the store already holds are kept, taking the names
+/// and expiry of the backup.
+///
+/// # Arguments
+///
+/// * `store` - The store to import the uploads into.
+/// * `input` - The archive to read.
+///
+/// # Errors
+///
+/// Returns an error if the archive cannot be read or is not a backup, if a
+/// content or an upload does not match its hash, or if the store cannot be
+/// written, the uploads imported before staying imported.
+pub async fn import(store: &FileStore, input: &Path) -> Result<BackupReport> {
+    let file = File::open(input)
+        .map_err(|e| anyhow!("Could not read {}: {}", input.display(), e))?;
+    let mut archive = zstd::stream::read::Decoder::new(file)?;
+    let not_backup = || anyhow!("{} is not a backup", input.display());
+    let description = match read_header(&mut archive)? {
+        Some((name, size)) if name == DESCRIPTION => {
+            let description = read_entry(&mut archive, size)?;
+            skip(&mut archive, padding(size))?;
+            description
+        }
+        _ => return Err(not_backup()),
+    };
+    let description: Description =
+        serde_json::from_slice(&description).map_err(|_| not_backup())?;
+    if description.version != BACKUP_VERSION {
+        return Err(anyhow!(
+            "Unsupported backup version {}",
+            description.version
+        ));
+    }
+    log::info!(
+        "Importing the backup of {} from {}",
+        description.created,
+        input.display()
+    );
+
+    // The contents are staged in a session of their own, until the uploads
+    // holding them are committed
+    let session = hex::encode(Sha256::digest(
+        format!("import:{}:{:?}", std::process::id(), SystemTime::now())
+            .as_bytes(),
+    ));
+    let mut report = BackupReport {
+        imported: true,
+        ..Default::default()
+    };
+    let mut staged = 0;
+    while let Some((name, size)) = read_header(&mut archive)? {
+        let invalid = || anyhow!("Invalid entry {} in the backup", name);
+        let (store, path) = match name.strip_prefix("users/") {
+            Some(path) => {
+                let (user, path) = path.split_once('/').ok_or_else(invalid)?;
+                (store.namespace(user)?, path)
+            }
+            None => (store.clone(), name.as_str()),
+        };
+        if let Some(sha256) = path.strip_prefix("blobs/") {
+            let partial = store.partial_file(&session, staged)?;
+            let mut file = File::create(&partial)?;
+            let copied = io::copy(&mut (&mut archive).take(size), &mut file)?;
+            if copied != size {
+                return Err(truncated());
+            }
+            file.sync_all()?;
+            let hash = store.stage_file(&session, staged)?;
+            staged += 1;
+            if hash != sha256 {
+                return Err(anyhow!(
+                    "The content {} of the backup is corrupt",
+                    name
+                ));
+            }
+            report.contents += 1;
+            report.size += size;
+        } else if let Some(root_hash) = path
+            .strip_prefix("uploads/")
+            .and_then(|path| path.strip_suffix(".json"))
+        {
+            if size > MAX_UPLOAD_LEN {
+                return Err(invalid());
+            }
+            let upload: BackedUpload =
+                serde_json::from_slice(&read_entry(&mut archive, size)?)?;
+            if upload.root_hash != root_hash {
+                return Err(invalid());
+            }
+            let hashes = upload
+                .files
+                .iter()
+                .map(|file| file.sha256.clone())
+                .collect::<Vec<_>>();
+            let committed = store.commit_files(&hashes).await.map_err(|e| {
+                anyhow!("Could not import {}: {}", root_hash, e)
+            })?;
+            if committed.root_hash != root_hash {
+                return Err(anyhow!(
+                    "The files of {} in the backup build the root {}",
+                    root_hash,
+                    committed.root_hash
+                ));
+            }
+            if let Some(names) = &upload.names {
+                store.set_names(root_hash, names).await?;
+            }
+            if let Some(expiry) = upload.expires {
+                store.set_expiry(root_hash, expiry).await?;
+            }
+            if let Some(signature) = &upload.signature {
+                if !signature.verifies(root_hash) {
+                    return Err(anyhow!(
+                        "The signature of {} in the backup is invalid",
+                        root_hash
+                    ));
+                }
+                store.set_signature(root_hash, signature).await?;
+            }
+            report.uploads += 1;
+            report.held += usize::from(committed.already_stored);
+        } else {
+            return Err(invalid());
+        }
+        skip(&mut archive, padding(size))?;
+    }
+    Ok(report)
+}
+
+/// Writes the content of a file to a backup, hashing it, the bytes being
+/// written as they are copied from the store.
+struct Entry<'a, W> {
+    archive: &'a mut W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write + Unpin> AsyncWrite for Entry<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.archive.write_all(buf)?;
+        self.hasher.update(buf);
+        self.len += buf.len() as u64;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Appends a regular file held in memory to a tar archive.
+fn append(
+    archive: &mut impl Write,
+    name: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<()> {
+    write_header(archive, name, data.len() as u64, mtime)?;
+    archive.write_all(data)?;
+    pad(archive, data.len() as u64)
+}
+
+/// Writes the header of a regular file to a tar archive, in the ustar
+/// format, its content to follow.
+fn write_header(
+    archive: &mut impl Write,
+    name: &str,
+    size: u64,
+    mtime: u64,
+) -> Result<()> {
+    // A name longer than its field is split at a `/` into the prefix field
+    let (prefix, name) = match name.len() {
+        0..=100 => ("", name),
+        _ => name
+            .char_indices()
+            .filter(|&(index, c)| c == '/' && index <= 155)
+            .map(|(index, _)| (&name[..index], &name[index + 1..]))
+            .find(|(_, name)| name.len() <= 100)
+            .ok_or_else(|| {
+                anyhow!("The name {} is too long for the archive", name)
+            })?,
+    };
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    match size {
+        0..=MAX_OCTAL_SIZE => octal(&mut header[124..136], size),
+        _ => {
+            header[124] = 0x80;
+            header[128..136].copy_from_slice(&size.to_be_bytes());
+        }
+    }
+    octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // The checksum is computed with its own field made of spaces
+    header[148..156].fill(b' ');
+    let checksum = checksum(&header);
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    archive.write_all(&header)?;
+    Ok(())
+}
+
+/// Pads the content of a file to the end of its last block.
+fn pad(archive: &mut impl Write, size: u64) -> Result<()> {
+    archive.write_all(&[0; BLOCK][..padding(size) as usize])?;
+    Ok(())
+}
+
+/// Returns the number of bytes padding a content of `size` bytes.
+fn padding(size: u64) -> u64 {
+    size.next_multiple_of(BLOCK as u64) - size
+}
+
+/// Reads the header of the next regular file of a tar archive, skipping
+/// the other entries.
+///
+/// # Returns
+///
+/// Returns the name and size of the file, or `None` at the end of the
+/// archive.
+fn read_header(archive: &mut impl Read) -> Result<Option<(String, u64)>> {
+    let invalid = || anyhow!("The backup is not a valid tar archive");
+    loop {
+        let mut header = [0; BLOCK];
+        archive.read_exact(&mut header).map_err(|_| truncated())?;
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+        let mut blank = header;
+        blank[148..156].fill(b' ');
+        if parse_octal(&header[148..156]) != Some(checksum(&blank)) {
+            return Err(invalid());
+        }
+        let size = match header[124] {
+            0x80 => {
+                let mut size = [0; 8];
+                size.copy_from_slice(&header[128..136]);
+                u64::from_be_bytes(size)
+            }
+            _ => parse_octal(&header[124..136]).ok_or_else(invalid)?,
+        };
+        if !matches!(header[156], b'0' | 0) {
+            skip(archive, size + padding(size))?;
+            continue;
+        }
+        let name = match text(&header[345..500]) {
+            prefix if prefix.is_empty() => text(&header[..100]),
+            prefix => format!("{}/{}", prefix, text(&header[..100])),
+        };
+        return Ok(Some((name, size)));
+    }
+}
+
+/// Reads the content of a file of a tar archive held in memory.
+fn read_entry(archive: &mut impl Read, size: u64) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    archive.take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(truncated());
+    }
+    Ok(data)
+}
+
+/// Skips bytes of a tar archive.
+fn skip(archive: &mut impl Read, len: u64) -> Result<()> {
+    match io::copy(&mut archive.take(len), &mut io::sink())? {
+        copied if copied == len => Ok(()),
+        _ => Err(truncated()),
+    }
+}
+
+fn truncated() -> anyhow::Error {
+    anyhow!("The backup is truncated")
+}
+
+/// Writes a number to a field of a tar header, in octal ended by a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let value = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(value.as_bytes());
+}
+
+/// Reads an octal number from a field of a tar header.
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let value = std::str::from_utf8(field).ok()?;
+    match value.trim_matches(|c| c == '\0' || c == ' ') {
+        "" => Some(0),
+        value => u64::from_str_radix(value, 8).ok(),
+    }
+}
+
+/// Returns the checksum of a tar header, the sum of its bytes.
+fn checksum(header: &[u8]) -> u64 {
+    header.iter().map(|&byte| u64::from(byte)).sum()
+}
+
+/// Reads a NUL terminated text field of a tar header.
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|&byte| byte == 0);
+    String::from_utf8_lossy(&field[..end.unwrap_or(field.len())]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_backup() {
+        let dir = std::env::temp_dir().join("file-guardian-test-backup");
+        let _ = fs::remove_dir_all(&dir);
+        let source = FileStore::new(dir.join("source")).unwrap();
+        let alice = source.namespace("alice").unwrap();
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let first = source.store_files(files).await.unwrap().root_hash;
+        let names = vec!["a.txt".to_string(), "b.txt".to_string()];
+        source.set_names(&first, &names).await.unwrap();
+        let files = vec![b"hello".to_vec(), b"again".to_vec()];
+        let second = alice.store_files(files.clone()).await.unwrap().root_hash;
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        alice.retain(&second, week).await.unwrap();
+        let third = alice
+            .store_files(vec![b"again".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+
+        let archive = dir.join("backup.tar.zst");
+        let report = export(&source, &archive).await.unwrap();
+        assert_eq!(report.uploads, 3);
+        // The contents shared by the uploads of a user are exported once
+        assert_eq!(report.contents, 4);
+        assert_eq!(report.size, 20);
+
+        // Importing rebuilds the uploads, their names and expiry
+        let target = FileStore::new(dir.join("target")).unwrap();
+        let report = import(&target, &archive).await.unwrap();
+        assert_eq!((report.uploads, report.held, report.contents), (3, 0, 4));
+        assert_eq!(
+            target.list_uploads().await.unwrap(),
+            source.list_uploads().await.unwrap()
+        );
+        assert_eq!(target.names(&first).await.unwrap(), Some(names));
+        let imported = target.namespace("alice").unwrap();
+        assert_eq!(
+            imported.list_uploads().await.unwrap(),
+            alice.list_uploads().await.unwrap()
+        );
+        assert_eq!(
+            imported.expiry(&second).await.unwrap(),
+            alice.expiry(&second).await.unwrap()
+        );
+        assert!(imported.has_upload(&third).await.unwrap());
+        let mut file = vec![];
+        imported
+            .copy_file(&second, 1, None, &mut file)
+            .await
+            .unwrap();
+        assert_eq!(file, files[1]);
+        // and importing again keeps the uploads
+        let report = import(&target, &archive).await.unwrap();
+        assert_eq!((report.uploads, report.held), (3, 3));
+
+        // A content changed in the backup is rejected
+        let tar = zstd::decode_all(File::open(&archive).unwrap()).unwrap();
+        let at = tar.windows(5).position(|bytes| bytes == b"world").unwrap();
+        let mut tampered = tar.clone();
+        tampered[at] = b'W';
+        let tampered = zstd::encode_all(&tampered[..], 3).unwrap();
+        fs::write(&archive, tampered).unwrap();
+        let empty = FileStore::new(dir.join("empty")).unwrap();
+        let error = import(&empty, &archive).await.unwrap_err();
+        assert!(error.to_string().contains("is corrupt"));
+        // as is an archive that is not a backup
+        let other = zstd::encode_all(&[0; 2 * BLOCK][..], 3).unwrap();
+        fs::write(&archive, other).unwrap();
+        assert!(import(&empty, &archive).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_headers() {
+        // The names of the uploads of users with long names are split, and
+        // the contents of more than 8 GiB sized in base 256
+        let name = format!(
+            "users/{}/uploads/{}.json",
+            "u".repeat(63),
+            "ab".repeat(32)
+        );
+        for size in [5, 9 << 30] {
+            let mut header = vec![];
+            write_header(&mut header, &name, size, 0).unwrap();
+            let read = read_header(&mut header.as_slice()).unwrap();
+            assert_eq!(read, Some((name.clone(), size)));
+        }
+        let long = "x".repeat(101);
+        assert!(write_header(&mut vec![], &long, 0, 0).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use merkle_tree::MerkleTree;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The Merkle trees of the uploads read last, shared by every connection, so
+/// that the tree of an upload is not read and decoded again for each of its
+/// downloads. The trees used least recently are evicted once the cache holds
+/// too many trees, or too many bytes of them.
+pub struct TreeCache {
+    /// The most trees held.
+    max_trees: usize,
+    /// The most bytes of hashes held.
+    max_bytes: u64,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// The trees, by the directory of their upload, so that the uploads of
+    /// different namespaces are kept apart.
+    trees: HashMap<PathBuf, Entry>,
+    /// The bytes of hashes of the trees.
+    bytes: u64,
+    /// The number of uses of the cache, ordering the uses of its trees.
+    uses: u64,
+}
+
+struct Entry {
+    tree: Arc<MerkleTree>,
+    bytes: u64,
+    /// When the tree was last used.
+    used: u64,
+}
+
+impl TreeCache {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_trees` - The most trees held.
+    /// * `max_bytes` - The most bytes of hashes held, a larger tree not
+    ///   being cached at all.
+    pub fn new(max_trees: usize, max_bytes: u64) -> Self {
+        Self {
+            max_trees,
+            max_bytes,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Returns the tree of an upload, if cached.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of the upload.
+    pub fn get(&self, dir: &Path) -> Option<Arc<MerkleTree>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.uses += 1;
+        let uses = inner.uses;
+        let entry = inner.trees.get_mut(dir)?;
+        entry.used = uses;
+        Some(entry.tree.clone())
+    }
+
+    /// Caches the tree of an upload, evicting the trees used least recently
+    /// to make room for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of the upload.
+    /// * `tree` - The tree of the upload.
+    pub fn insert(&self, dir: PathBuf, tree: Arc<MerkleTree>) {
+        // Every level holds at most half of the hashes of the level below
+        let bytes = tree.leaves().len() as u64 * 2 * 32;
+        if bytes > self.max_bytes || self.max_trees == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.trees.remove(&dir) {
+            inner.bytes -= entry.bytes;
+        }
+        while inner.trees.len() >= self.max_trees
+            || inner.bytes + bytes > self.max_bytes
+        {
+            let Some(oldest) = inner
+                .trees
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(dir, _)| dir.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = inner.trees.remove(&oldest) {
+                inner.bytes -= entry.bytes;
+            }
+        }
+        inner.uses += 1;
+        let used = inner.uses;
+        inner.bytes += bytes;
+        inner.trees.insert(dir, Entry { tree, bytes, used });
+    }
+
+    /// Evicts the tree of an upload, e.g. once the upload is deleted.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory of the upload.
+    pub fn remove(&self, dir: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.trees.remove(dir) {
+            inner.bytes -= entry.bytes;
+        }
+    }
+
+    /// Evicts every tree, e.g. once a file shared by uploads is removed.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.trees.clear();
+        inner.bytes = 0;
+    }
+}
+
+/// The bytes held by the uploads of each store, e.g. of each namespace,
+/// shared by every connection. The uploads of a store are only listed once
+/// to count them, the count then being kept up to date as uploads are
+/// stored, rather than listed again for every check of its quota.
+#[derive(Default)]
+pub struct UsageCache {
+    inner: Mutex<HashMap<PathBuf, Usage>>,
+}
+
+#[derive(Default)]
+struct Usage {
+    /// The bytes held, once counted.
+    bytes: Option<u64>,
+    /// The number of changes to the uploads, so that a count taken while
+    /// an upload is stored or deleted is not kept.
+    changes: u64,
+    /// The bytes of the uploads and files being stored, reserved against
+    /// the quota until they are.
+    reserved: u64,
+}
+
+/// Bytes reserved against the quota of a store by [`UsageCache::reserve`],
+/// released once dropped.
+pub struct Reservation {
+    cache: Arc<UsageCache>,
+    dir: PathBuf,
+    bytes: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut inner = self.cache.inner.lock().unwrap();
+        if let Some(usage) = inner.get_mut(&self.dir) {
+            usage.reserved = usage.reserved.saturating_sub(self.bytes);
+        }
+    }
+}
+
+impl UsageCache {
+    /// Returns the bytes held by the uploads of a store, if counted, and
+    /// the number of changes to its uploads, to pass to
+    /// [`UsageCache::insert`] once counted otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The root directory of the store.
+    pub fn get(&self, dir: &Path) -> (Option<u64>, u64) {
+        let inner = self.inner.lock().unwrap();
+        match inner.get(dir) {
+            Some(usage) => (usage.bytes, usage.changes),
+            None => (None, 0),
+        }
+    }
+
+    /// Records the bytes counted for the uploads of a store, unless they
+    /// changed while counted.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The root directory of the store.
+    /// * `bytes` - The bytes held by its uploads.
+    /// * `changes` - The number of changes returned by [`UsageCache::get`]
+    ///   before counting.
+    pub fn insert(&self, dir: &Path, bytes: u64, changes: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let usage = inner.entry(dir.to_path_buf()).or_default();
+        if usage.changes == changes {
+            usage.bytes = Some(bytes);
+        }
+    }
+
+    /// Adds the bytes of an upload just stored to those of its store.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The root directory of the store.
+    /// * `bytes` - The bytes of the upload.
+    pub fn add(&self, dir: &Path, bytes: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let usage = inner.entry(dir.to_path_buf()).or_default();
+        usage.changes += 1;
+        usage.bytes = usage.bytes.map(|used| used.saturating_add(bytes));
+    }
+
+    /// Reserves bytes against the quota of a store, e.g. for an upload
+    /// being stored, until the reservation is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The root directory of the store.
+    /// * `bytes` - The bytes to reserve.
+    ///
+    /// # Returns
+    ///
+    /// Returns the reservation, and the bytes reserved for the store with
+    /// it.
+    pub fn reserve(
+        self: &Arc<Self>,
+        dir: &Path,
+        bytes: u64,
+    ) -> (Reservation, u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let usage = inner.entry(dir.to_path_buf()).or_default();
+        usage.reserved = usage.reserved.saturating_add(bytes);
+        let reservation = Reservation {
+            cache: self.clone(),
+            dir: dir.to_path_buf(),
+            bytes,
+        };
+        (reservation, usage.reserved)
+    }
+
+    /// Forgets the bytes held by the uploads of a store, e.g. once one of
+    /// them is deleted, for them to be counted again.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The root directory of the store.
+    pub fn remove(&self, dir: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        let usage = inner.entry(dir.to_path_buf()).or_default();
+        usage.changes += 1;
+        usage.bytes = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_cache() {
+        let (cache, dir) = (UsageCache::default(), Path::new("a"));
+        assert_eq!(cache.get(dir), (None, 0));
+        cache.insert(dir, 5, 0);
+        cache.add(dir, 3);
+        assert_eq!(cache.get(dir), (Some(8), 1));
+        // A count taken while an upload was stored is not kept
+        cache.remove(dir);
+        let (_, changes) = cache.get(dir);
+        cache.add(dir, 3);
+        cache.insert(dir, 8, changes);
+        assert_eq!(cache.get(dir).0, None);
+
+        // Reservations add up until dropped
+        let cache = Arc::new(cache);
+        let (first, reserved) = cache.reserve(dir, 2);
+        assert_eq!(reserved, 2);
+        assert_eq!(cache.reserve(dir, 3).1, 5);
+        drop(first);
+        assert_eq!(cache.reserve(dir, 1).1, 1);
+    }
+
+    #[test]
+    fn test_tree_cache() {
+        let tree = |leaves: u8| {
+            let data = (0..leaves).map(|i| vec![i]).collect::<Vec<_>>();
+            Arc::new(MerkleTree::new(&data).unwrap())
+        };
+        let dir = |name: &str| PathBuf::from(name);
+
+        // Two trees of a leaf, or one of two leaves, fit
+        let cache = TreeCache::new(3, 128);
+        cache.insert(dir("a"), tree(1));
+        cache.insert(dir("b"), tree(1));
+        assert!(cache.get(&dir("a")).is_some());
+        // b is the least recently used
+        cache.insert(dir("c"), tree(1));
+        assert!(cache.get(&dir("b")).is_none());
+        assert!(cache.get(&dir("a")).is_some());
+        cache.insert(dir("d"), tree(2));
+        assert!(cache.get(&dir("a")).is_none());
+        assert!(cache.get(&dir("c")).is_none());
+        assert_eq!(cache.get(&dir("d")).unwrap().leaves().len(), 2);
+        // A tree larger than the cache is not cached
+        cache.insert(dir("e"), tree(3));
+        assert!(cache.get(&dir("e")).is_none());
+        cache.remove(&dir("d"));
+        assert!(cache.get(&dir("d")).is_none());
+
+        // The count of trees is bounded too
+        let cache = TreeCache::new(1, 1 << 20);
+        cache.insert(dir("a"), tree(1));
+        cache.insert(dir("b"), tree(1));
+        assert!(cache.get(&dir("a")).is_none());
+        assert!(cache.get(&dir("b")).is_some());
+    }
+}
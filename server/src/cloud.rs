@@ -0,0 +1,710 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::{
+    fmt,
+    future::Future,
+    io,
+    ops::Range,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    pki_types::{pem::PemObject, CertificateDer, ServerName},
+    ClientConfig, RootCertStore,
+};
+use tokio_rustls::TlsConnector;
+
+/// The delay before the first retry of a failed request, doubled before each
+/// of the next ones.
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// The maximum delay between two attempts of a request.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long a request may take, from connecting to reading the response,
+/// before it is abandoned, and retried.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The parts of the URL of an endpoint.
+#[derive(Debug, PartialEq)]
+pub struct Endpoint {
+    /// Whether the endpoint is reached over TLS.
+    pub tls: bool,
+    /// The address of the endpoint, in the format `host:port`.
+    pub address: String,
+    /// The host, and the port if given, for the `Host` header.
+    pub authority: String,
+    /// The path the requests are relative to, without a trailing `/`.
+    pub path: String,
+}
+
+impl Endpoint {
+    /// Parses the URL of an endpoint.
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid endpoint {}", url);
+        let (tls, rest) = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => {
+                (true, rest)
+            }
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => {
+                (false, rest)
+            }
+            _ => return Err(invalid()),
+        };
+        let (authority, path) =
+            rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        if authority.is_empty() || authority.contains(['@', '?', '#']) {
+            return Err(invalid());
+        }
+        let address = match authority.rsplit_once(':') {
+            Some((_, port))
+                if !authority.ends_with(']') && port.parse::<u16>().is_ok() =>
+            {
+                authority.to_string()
+            }
+            _ => format!("{}:{}", authority, if tls { 443 } else { 80 }),
+        };
+        Ok(Self {
+            tls,
+            address,
+            authority: authority.to_string(),
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Returns the host of the endpoint, without its port.
+    pub fn host(&self) -> &str {
+        let host = match self.authority.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => &self.authority,
+        };
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
+}
+
+/// A request to a storage service.
+pub struct Request<'a> {
+    pub method: &'a str,
+    /// The key of the object, empty for the requests on the bucket or the
+    /// container.
+    pub key: &'a str,
+    /// The parameters of the query, unencoded.
+    pub query: Vec<(&'a str, String)>,
+    /// The headers of the request, but for those every request has.
+    pub headers: Vec<(&'a str, String)>,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn new(method: &'a str, key: &'a str) -> Self {
+        Self {
+            method,
+            key,
+            query: vec![],
+            headers: vec![],
+            body: &[],
+        }
+    }
+
+    pub fn query(mut self, name: &'a str, value: impl Into<String>) -> Self {
+        self.query.push((name, value.into()));
+        self
+    }
+
+    pub fn header(mut self, name: &'a str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: &'a [u8]) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+/// A response of a storage service.
+pub struct Response {
+    pub status: u16,
+    /// The headers of the response, with their names in lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Returns the value of a header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the body as text, e.g. an XML document.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+/// The error of a request a storage service rejected.
+#[derive(Debug)]
+pub struct ServiceError {
+    /// The name of the service, e.g. `S3`.
+    service: &'static str,
+    status: u16,
+    /// The code of the error, e.g. `NoSuchBucket`.
+    code: String,
+    message: String,
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} error {} {}: {}",
+            self.service, self.status, self.code, self.message
+        )
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+impl ServiceError {
+    /// Returns the error of a response, from its XML body if it has one, as
+    /// both S3 and Azure send them.
+    pub fn from_response(service: &'static str, response: &Response) -> Self {
+        let body = response.text();
+        Self {
+            service,
+            status: response.status,
+            code: tag(&body, "Code").unwrap_or_default(),
+            message: tag(&body, "Message").unwrap_or_default(),
+        }
+    }
+
+    /// Returns whether the request may succeed if retried, the storage
+    /// failing or throttling the requests.
+    fn is_transient(&self) -> bool {
+        self.status >= 500 || self.status == 429 || self.code == "SlowDown"
+    }
+}
+
+/// The connection settings of a storage service, sending each request over
+/// a connection of its own.
+pub struct Transport {
+    /// The name of the service, e.g. `S3`, for the errors.
+    service: &'static str,
+    pub endpoint: Endpoint,
+    /// The TLS settings, if the endpoint is reached over TLS.
+    tls: Option<Arc<ClientConfig>>,
+    /// The number of times a failed request is retried.
+    retries: u32,
+}
+
+impl Transport {
+    /// Creates the transport of the requests to an endpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `service` - The name of the service, e.g. `S3`.
+    /// * `url` - The URL of the endpoint.
+    /// * `ca` - A PEM file of the certificate authorities the endpoint is
+    ///   verified with, instead of the Mozilla root certificates.
+    /// * `retries` - The number of times a failed request is retried.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the URL is not an `http://` or `https://` URL, or
+    /// the CA file cannot be read.
+    pub fn new(
+        service: &'static str,
+        url: &str,
+        ca: Option<&Path>,
+        retries: u32,
+    ) -> Result<Self> {
+        let endpoint = Endpoint::parse(url)
+            .map_err(|_| anyhow!("Invalid {} endpoint {}", service, url))?;
+        let tls = match endpoint.tls {
+            true => Some(tls_config(ca)?),
+            false => None,
+        };
+        Ok(Self {
+            service,
+            endpoint,
+            tls,
+            retries,
+        })
+    }
+
+    /// Sends a request, retrying it while it fails with a transient error:
+    /// every request of the backends is safe to repeat.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request.
+    /// * `head` - Returns the head of the request, built again, and signed
+    ///   again, for every attempt.
+    /// * `expected` - The statuses of the responses that are not errors.
+    pub async fn send(
+        &self,
+        request: &Request<'_>,
+        head: impl Fn() -> String,
+        expected: &[u16],
+    ) -> Result<Response> {
+        let (method, key) = (request.method, request.key);
+        let mut attempt = 0;
+        loop {
+            let response = tokio::time::timeout(
+                REQUEST_TIMEOUT,
+                self.send_once(&head(), request.body, method == "HEAD"),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::from(io::ErrorKind::TimedOut).into())
+            });
+            let error = match response {
+                Ok(response) if expected.contains(&response.status) => {
+                    return Ok(response)
+                }
+                Ok(response) => {
+                    let error =
+                        ServiceError::from_response(self.service, &response);
+                    if !error.is_transient() {
+                        return Err(error.into());
+                    }
+                    anyhow::Error::from(error)
+                }
+                Err(e) => e,
+            };
+            if attempt >= self.retries {
+                return Err(error.context(format!(
+                    "{} {} of {} failed",
+                    self.service, method, key
+                )));
+            }
+            attempt += 1;
+            log::warn!(
+                "Retrying {} {} of {} ({}/{}): {}",
+                self.service,
+                method,
+                key,
+                attempt,
+                self.retries,
+                error
+            );
+            let delay = RETRY_BACKOFF
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(MAX_RETRY_BACKOFF);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Sends a request once, over a connection of its own.
+    async fn send_once(
+        &self,
+        head: &str,
+        body: &[u8],
+        head_only: bool,
+    ) -> Result<Response> {
+        let stream = TcpStream::connect(&self.endpoint.address).await?;
+        match &self.tls {
+            Some(config) => {
+                let name =
+                    ServerName::try_from(self.endpoint.host().to_string())
+                        .map_err(|_| {
+                            anyhow!("Invalid host {}", self.endpoint.host())
+                        })?;
+                let stream = TlsConnector::from(config.clone())
+                    .connect(name, stream)
+                    .await?;
+                exchange(stream, head, body, head_only).await
+            }
+            None => exchange(stream, head, body, head_only).await,
+        }
+    }
+}
+
+/// Copies an object, or a range of it, shorter at the end of the object, to
+/// `out` with a ranged request per part, buffering a single part.
+///
+/// # Arguments
+///
+/// * `range` - The range of the object, or `None` for all of it.
+/// * `part_size` - The size of the ranges asked for.
+/// * `out` - Where the object is copied to.
+/// * `get` - Returns a range of the object, shorter at the end of the
+///   object, or `None` if there is no such object.
+///
+/// # Returns
+///
+/// Returns the number of bytes copied, or `None` if there is no such
+/// object.
+pub async fn copy_parts<F, Fut>(
+    range: Option<Range<u64>>,
+    part_size: u64,
+    out: &mut (dyn AsyncWrite + Send + Unpin),
+    mut get: F,
+) -> Result<Option<u64>>
+where
+    F: FnMut(Range<u64>) -> Fut,
+    Fut: Future<Output = Result<Option<Vec<u8>>>>,
+{
+    let range = range.unwrap_or(0..u64::MAX);
+    let mut copied = 0;
+    loop {
+        let start = range.start.saturating_add(copied);
+        let end = start.saturating_add(part_size).min(range.end);
+        let asked = end.saturating_sub(start);
+        let Some(part) = get(start..end).await? else {
+            if copied == 0 {
+                return Ok(None);
+            }
+            return Err(anyhow!("The object was deleted while read"));
+        };
+        let len = part.len() as u64;
+        if len > asked {
+            return Err(anyhow!("{} bytes returned for a range", len));
+        }
+        out.write_all(&part).await?;
+        copied += len;
+        // A shorter part is the end of the object
+        if len < asked || end == range.end {
+            return Ok(Some(copied));
+        }
+    }
+}
+
+/// Returns the TLS settings an endpoint is verified with.
+pub fn tls_config(ca: Option<&Path>) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            let certs = CertificateDer::pem_file_iter(ca)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| {
+                    anyhow!("Invalid CA file {}: {}", ca.display(), e)
+                })?;
+            let (_, ignored) = roots.add_parsable_certificates(certs);
+            if roots.is_empty() || ignored > 0 {
+                return Err(anyhow!("Invalid CA file {}", ca.display()));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Sends a request over a connection, and reads the whole response, the
+/// server closing the connection after it.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    head: &str,
+    body: &[u8],
+    head_only: bool,
+) -> Result<Response> {
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    let mut received = vec![];
+    match stream.read_to_end(&mut received).await {
+        // Many servers close the connection without a TLS close_notify,
+        // which is harmless: the length of the body is checked
+        Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => {
+            return Err(e.into())
+        }
+        _ => {}
+    }
+    parse_response(&received, head_only)
+}
+
+/// Parses a response, its body ending at the end of the connection
+/// unless it has a length or is chunked.
+fn parse_response(received: &[u8], head_only: bool) -> Result<Response> {
+    let invalid = || anyhow!("Invalid HTTP response");
+    let split = find(received, b"\r\n\r\n").ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&received[..split]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+    let headers = lines
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            Some((key.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+    let mut response = Response {
+        status,
+        headers,
+        body: vec![],
+    };
+    if head_only {
+        return Ok(response);
+    }
+
+    let body = &received[split + 4..];
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+    response.body = match (chunked, response.header("Content-Length")) {
+        (true, _) => dechunk(body)?,
+        (_, Some(length)) => {
+            let length = length.parse::<usize>().map_err(|_| invalid())?;
+            body.get(..length)
+                .ok_or_else(|| anyhow!("Truncated HTTP response"))?
+                .to_vec()
+        }
+        _ => body.to_vec(),
+    };
+    Ok(response)
+}
+
+/// Returns the position of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Decodes a body with the chunked transfer encoding.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+    let truncated = || anyhow!("Truncated HTTP response");
+    let mut decoded = vec![];
+    loop {
+        let line = find(body, b"\r\n").ok_or_else(truncated)?;
+        let size = String::from_utf8_lossy(&body[..line]);
+        // Chunk extensions follow a semicolon
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| anyhow!("Invalid chunk size {}", size))?;
+        body = &body[line + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size).ok_or_else(truncated)?);
+        body = body.get(size + 2..).ok_or_else(truncated)?;
+    }
+}
+
+/// Returns the HMAC-SHA256 of a message.
+pub fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC accepts keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encodes a path or a parameter of a query, every byte but the unreserved
+/// characters being percent-encoded, and the `/` of a path kept.
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// A time in UTC, in the proleptic Gregorian calendar.
+#[derive(Debug, PartialEq)]
+pub struct DateTime {
+    pub year: i64,
+    /// The month, from 1 for January.
+    pub month: i64,
+    pub day: i64,
+    /// The day of the week, from 0 for Sunday.
+    pub weekday: i64,
+    /// The seconds since midnight.
+    pub seconds: u64,
+}
+
+impl DateTime {
+    /// Returns the date and time of a point in time.
+    pub fn from(time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (days, seconds) = ((secs / 86_400) as i64, secs % 86_400);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4) % 7;
+        // The civil date of a number of days since 1970-01-01
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460
+            + day_of_era / 36_524
+            - day_of_era / 146_096)
+            / 365;
+        let day_of_year = day_of_era
+            - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        Self {
+            year: year_of_era + era * 400 + i64::from(month <= 2),
+            month,
+            day,
+            weekday,
+            seconds,
+        }
+    }
+
+    /// Returns the time of the day, as `(hours, minutes, seconds)`.
+    pub fn time(&self) -> (u64, u64, u64) {
+        (
+            self.seconds / 3600,
+            self.seconds / 60 % 60,
+            self.seconds % 60,
+        )
+    }
+}
+
+/// Returns the text of every element of an XML document with the given tag,
+/// e.g. every `<Contents>` of a list of objects.
+pub fn tags<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let mut elements = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        elements.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    elements
+}
+
+/// Returns the unescaped text of the first element of an XML document with
+/// the given tag.
+pub fn tag(xml: &str, name: &str) -> Option<String> {
+    tags(xml, name).first().map(|text| unescape(text))
+}
+
+/// Unescapes the entities of the text of an XML element.
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#34;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// Escapes the text of an XML element.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let xml = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+                   <Contents><Key>a&amp;b</Key></Contents><CommonPrefixes>\
+                   <Prefix>p/</Prefix></CommonPrefixes></ListBucketResult>";
+        assert_eq!(tags(xml, "Contents").len(), 1);
+        assert_eq!(tag(xml, "Key").unwrap(), "a&b");
+        assert_eq!(tag(xml, "IsTruncated").unwrap(), "false");
+        assert_eq!(tag(xml, "Missing"), None);
+
+        let response = parse_response(
+            b"HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\n\r\nabcdef",
+            false,
+        )
+        .unwrap();
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, b"abc");
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n0\r\n\r\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(response.body, b"abc");
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 512\r\n\r\n",
+            true,
+        )
+        .unwrap();
+        assert_eq!(response.header("content-length"), Some("512"));
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n", false).is_err());
+
+        assert_eq!(uri_encode("a b/c~", false), "a%20b/c~");
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+        let endpoint = Endpoint::parse("http://[::1]:9000/s3/").unwrap();
+        assert_eq!(endpoint.address, "[::1]:9000");
+        assert_eq!(endpoint.host(), "::1");
+        assert_eq!(endpoint.path, "/s3");
+        let endpoint = Endpoint::parse("https://s3.amazonaws.com").unwrap();
+        assert_eq!(endpoint.address, "s3.amazonaws.com:443");
+        assert!(Endpoint::parse("s3.amazonaws.com").is_err());
+
+        let time = DateTime::from(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_210_096),
+        );
+        assert_eq!((time.year, time.month, time.day), (2024, 2, 29));
+        // A Thursday, at 12:34:56
+        assert_eq!(time.weekday, 4);
+        assert_eq!(time.time(), (12, 34, 56));
+    }
+
+    #[tokio::test]
+    async fn test_copy_parts() {
+        let object = b"hello world".to_vec();
+        let copy = |range| {
+            let object = object.clone();
+            async move {
+                let mut out = vec![];
+                let copied = copy_parts(range, 4, &mut out, |range| {
+                    let start = (range.start as usize).min(object.len());
+                    let end = (range.end as usize).min(object.len());
+                    let part = object[start..end].to_vec();
+                    async move { Ok(Some(part)) }
+                })
+                .await
+                .unwrap();
+                (copied, out)
+            }
+        };
+        assert_eq!(copy(None).await, (Some(11), object.clone()));
+        assert_eq!(copy(Some(3..9)).await, (Some(6), b"lo wor".to_vec()));
+        assert_eq!(copy(Some(6..40)).await, (Some(5), b"world".to_vec()));
+        assert_eq!(copy(Some(2..2)).await, (Some(0), vec![]));
+
+        // An object deleted between two parts is not taken as complete
+        let mut out = vec![];
+        let mut parts = vec![None, Some(b"hell".to_vec())];
+        let deleted = copy_parts(None, 4, &mut out, |_| {
+            let part = parts.pop().unwrap();
+            async move { Ok(part) }
+        });
+        assert!(deleted.await.is_err());
+        let missing = copy_parts(None, 4, &mut out, |_| async { Ok(None) });
+        assert_eq!(missing.await.unwrap(), None);
+    }
+}
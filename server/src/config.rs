@@ -0,0 +1,289 @@
+use anyhow::Result;
+use log::LevelFilter;
+use serde::{de, Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+
+use crate::logger::LogFormat;
+
+/// The server configuration, read from a TOML file, the command line and
+/// the environment overriding each of its settings.
+///
+/// ```toml
+/// address = "0.0.0.0:2345"
+/// http = "0.0.0.0:8080"
+/// store_dir = "/var/lib/file-guardian"
+/// admin_socket = "/run/file-guardian/admin.sock"
+/// read_only = false
+/// wire_compression_level = 3
+///
+/// [limits]
+/// max_file_size = "4GiB"
+/// quota = "10GiB"
+///
+/// [tls]
+/// cert = "/etc/file-guardian/cert.pem"
+/// key = "/etc/file-guardian/key.pem"
+///
+/// [auth]
+/// tokens_file = "/etc/file-guardian/tokens"
+///
+/// [log]
+/// level = "debug"
+/// format = "json"
+///
+/// [audit]
+/// file = "/var/log/file-guardian/audit.log"
+/// max_size = "100MiB"
+/// keep = 10
+///
+/// [webhooks]
+/// urls = ["https://pipeline.example.com/hooks/landed"]
+/// secret_file = "/etc/file-guardian/webhook-secret"
+///
+/// [[listen]]
+/// address = "[::]:2345"
+///
+/// [[listen]]
+/// address = "127.0.0.1:2346"
+/// plain = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The address to listen on.
+    pub address: Option<String>,
+    /// The address the HTTP API is served on, if it is.
+    pub http: Option<String>,
+    /// The directory of the uploads, and of the files being uploaded when
+    /// the uploads are kept elsewhere.
+    pub store_dir: Option<PathBuf>,
+    /// The Unix socket the admin requests are served on, if they are.
+    pub admin_socket: Option<PathBuf>,
+    /// Whether the server starts read-only, only serving downloads.
+    pub read_only: Option<bool>,
+    /// The zstd level the contents of files are compressed with in transit,
+    /// 0 not to compress them.
+    pub wire_compression_level: Option<i32>,
+    /// The limits of the files, uploads and connections.
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// The certificate connections are accepted over TLS with, if they are.
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// How clients authenticate, if they must.
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// What is logged, and how.
+    #[serde(default)]
+    pub log: LogConfig,
+    /// Where the operations served are audited, if they are.
+    #[serde(default)]
+    pub audit: AuditConfig,
+    /// Where the uploads committed are posted, if anywhere.
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+    /// The addresses listened on besides `address` and `http`.
+    #[serde(default)]
+    pub listen: Vec<ListenConfig>,
+}
+
+/// An address listened on, with what is served there.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenConfig {
+    /// The address, or `unix:<path>` for a Unix socket.
+    pub address: String,
+    /// Whether the HTTP API is served there rather than the TCP protocol.
+    #[serde(default)]
+    pub http: bool,
+    /// The certificate connections are accepted over TLS with there,
+    /// instead of the one of `[tls]`.
+    pub tls: Option<TlsConfig>,
+    /// Whether connections are accepted without TLS there, even with a
+    /// certificate in `[tls]`, e.g. on a loopback address.
+    #[serde(default)]
+    pub plain: bool,
+}
+
+/// The limits of the files, uploads and connections accepted.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// The largest file accepted, e.g. `4GiB`.
+    #[serde(default, deserialize_with = "size")]
+    pub max_file_size: Option<u64>,
+    /// The most files accepted in an upload.
+    pub max_files: Option<u64>,
+    /// The largest total size of the files of an upload, e.g. `16GiB`.
+    #[serde(default, deserialize_with = "size")]
+    pub max_upload_size: Option<u64>,
+    /// The most bytes the uploads of each user may hold, e.g. `10GiB`.
+    #[serde(default, deserialize_with = "size")]
+    pub quota: Option<u64>,
+    /// The most connections from the same IP served at once.
+    pub max_connections_per_ip: Option<u64>,
+    /// The most connections served at once, from every IP.
+    pub max_connections: Option<u64>,
+    /// The most connections per second accepted from the same IP.
+    pub connection_rate: Option<u32>,
+}
+
+/// The PEM files of the TLS certificate of the server.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The certificate chain served.
+    pub cert: Option<PathBuf>,
+    /// The private key of the certificate.
+    pub key: Option<PathBuf>,
+    /// The certificate authorities the certificates of clients must be
+    /// signed by, if clients must present one.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// The files client tokens are validated with.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The file of the tokens, one `<user>:<token>` per line.
+    pub tokens_file: Option<PathBuf>,
+    /// The file of the secret the tokens are signed with.
+    pub hmac_secret_file: Option<PathBuf>,
+}
+
+/// The messages logged by the server.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// The most verbose messages logged, e.g. `debug`.
+    #[serde(default, deserialize_with = "level")]
+    pub level: Option<LevelFilter>,
+    /// The format of the messages.
+    pub format: Option<LogFormat>,
+}
+
+/// The audit log of the operations served.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    /// The file of the log.
+    pub file: Option<PathBuf>,
+    /// The size the log is rotated at, e.g. `100MiB`.
+    #[serde(default, deserialize_with = "size")]
+    pub max_size: Option<u64>,
+    /// The number of rotated logs kept.
+    pub keep: Option<u64>,
+}
+
+/// The webhooks posted the uploads committed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// The URLs posted to.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// The file of the secret the events are signed with, if they are.
+    pub secret_file: Option<PathBuf>,
+}
+
+/// Deserializes a level of the messages logged, e.g. `info`.
+fn level<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<LevelFilter>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|level| {
+            crate::logger::parse_level(&level).map_err(de::Error::custom)
+        })
+        .transpose()
+}
+
+/// Deserializes a size, e.g. `500MB`.
+fn size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|size| crate::parse_size(&size).map_err(de::Error::custom))
+        .transpose()
+}
+
+impl Config {
+    /// Loads the configuration from a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or parsed.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            anyhow::anyhow!("Could not read config {}: {}", path.display(), e)
+        })?;
+        Self::parse(&content).map_err(|e| {
+            anyhow::anyhow!("Invalid config {}: {}", path.display(), e)
+        })
+    }
+
+    /// Parses the configuration from a TOML string.
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            address = "0.0.0.0:2345"
+            store_dir = "/var/lib/file-guardian"
+            read_only = true
+
+            [limits]
+            max_file_size = "4GiB"
+            max_files = 100
+
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+
+            [log]
+            level = "debug"
+            format = "json"
+
+            [[listen]]
+            address = "[::]:2345"
+
+            [[listen]]
+            address = "0.0.0.0:8443"
+            http = true
+            tls = { cert = "api.pem", key = "api-key.pem" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.address.as_deref(), Some("0.0.0.0:2345"));
+        assert_eq!(
+            config.store_dir,
+            Some(PathBuf::from("/var/lib/file-guardian"))
+        );
+        assert_eq!(config.read_only, Some(true));
+        assert_eq!(config.limits.max_file_size, Some(4 << 30));
+        assert_eq!(config.limits.max_files, Some(100));
+        assert_eq!(config.limits.quota, None);
+        assert_eq!(config.tls.key, Some(PathBuf::from("key.pem")));
+        assert_eq!(config.auth.tokens_file, None);
+        assert_eq!(config.log.level, Some(LevelFilter::Debug));
+        assert_eq!(config.log.format, Some(LogFormat::Json));
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.listen[0].address, "[::]:2345");
+        assert!(!config.listen[0].http && config.listen[0].tls.is_none());
+        assert!(config.listen[1].http);
+        let tls = config.listen[1].tls.as_ref().unwrap();
+        assert_eq!(tls.cert, Some(PathBuf::from("api.pem")));
+
+        assert!(Config::parse("").unwrap().address.is_none());
+        assert!(Config::parse("port = 2345").is_err());
+        assert!(Config::parse("[limits]\nquota = \"lots\"").is_err());
+        assert!(Config::parse("[[listen]]\nhttp = true").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// How long the clients are waited for by default during the handshakes.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a connection ready for its request is kept by default. Clients
+/// keep connections ready for up to 5 minutes.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long a read or write of a request may stall by default.
+pub const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the connections wait for their clients, so that a client
+/// connecting and sending nothing, or sending it a byte at a time, does not
+/// hold a connection forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// The TLS handshake, then the protocol handshake and authentication,
+    /// or the head of an HTTP request, must each complete within it.
+    pub handshake: Duration,
+    /// A connection waiting for its request, once authenticated, is closed
+    /// after it.
+    pub idle: Duration,
+    /// A read or write of a request being served fails once it made no
+    /// progress for that long, e.g. when the client stopped reading.
+    pub io: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: DEFAULT_HANDSHAKE_TIMEOUT,
+            idle: DEFAULT_IDLE_TIMEOUT,
+            io: DEFAULT_IO_TIMEOUT,
+        }
+    }
+}
+
+/// Runs a step of a connection, failing with a timeout error if it does
+/// not complete within `timeout`.
+///
+/// # Arguments
+///
+/// * `timeout` - How long the step may take.
+/// * `step` - What the step waits for, e.g. `the handshake`.
+/// * `future` - The step.
+pub async fn within<T>(
+    timeout: Duration,
+    step: &str,
+    future: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => result,
+        Err(_) => Err(timed_out(timeout, step).into()),
+    }
+}
+
+/// Returns the error of a step not completed within `timeout`.
+fn timed_out(timeout: Duration, step: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("Timed out after {:?} waiting for {}", timeout, step),
+    )
+}
+
+/// A stream whose reads and writes fail once they made no progress for a
+/// timeout.
+pub struct Deadline<S> {
+    stream: S,
+    timeout: Duration,
+    /// When the pending read times out, if a read is pending.
+    read: Option<Pin<Box<Sleep>>>,
+    /// When the pending write, flush or shutdown times out, if one is.
+    write: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Deadline<S> {
+    /// Wraps a stream, its reads and writes stalling for up to `timeout`.
+    pub fn new(stream: S, timeout: Duration) -> Self {
+        Self {
+            stream,
+            timeout,
+            read: None,
+            write: None,
+        }
+    }
+}
+
+/// Polls an operation of a stream, failing it once its timer expires, the
+/// timer starting when the operation is first pending and stopping once it
+/// progresses.
+fn poll_timed<T>(
+    timer: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Duration,
+    step: &str,
+    cx: &mut Context<'_>,
+    polled: Poll<io::Result<T>>,
+) -> Poll<io::Result<T>> {
+    if polled.is_ready() {
+        *timer = None;
+        return polled;
+    }
+    let sleep =
+        timer.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    match sleep.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            *timer = None;
+            Poll::Ready(Err(timed_out(timeout, step)))
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deadline<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let polled = Pin::new(&mut this.stream).poll_read(cx, buf);
+        poll_timed(&mut this.read, this.timeout, "the client", cx, polled)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deadline<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let polled = Pin::new(&mut this.stream).poll_write(cx, buf);
+        let step = "the client to read";
+        poll_timed(&mut this.write, this.timeout, step, cx, polled)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let polled = Pin::new(&mut this.stream).poll_flush(cx);
+        let step = "the client to read";
+        poll_timed(&mut this.write, this.timeout, step, cx, polled)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let polled = Pin::new(&mut this.stream).poll_shutdown(cx);
+        let step = "the client to read";
+        poll_timed(&mut this.write, this.timeout, step, cx, polled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let timeout = Duration::from_millis(200);
+        let (client, server) = tokio::io::duplex(4);
+        let mut server = Deadline::new(server, timeout);
+        let mut client = Deadline::new(client, timeout);
+
+        // A client sending a byte now and then keeps the reads going
+        let mut buf = [0; 1];
+        for _ in 0..3 {
+            let send = async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                client.write_all(b"x").await.unwrap();
+            };
+            let (_, read) = tokio::join!(send, server.read_exact(&mut buf));
+            read.unwrap();
+        }
+
+        // but not a client sending nothing
+        let error = server.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        // nor one no longer reading, once the buffer is full
+        let error = server.write_all(&[0; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(error.to_string().contains("the client to read"));
+    }
+
+    #[tokio::test]
+    async fn test_within() {
+        let step = within(Duration::from_millis(10), "the handshake", async {
+            std::future::pending::<()>().await;
+            Ok(())
+        });
+        let error = step.await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Timed out after 10ms waiting for the handshake"
+        );
+        let step =
+            within(Duration::from_secs(30), "the handshake", async { Ok(1) });
+        assert_eq!(step.await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+use std::io;
+
+pub use protocol::ErrorKind;
+
+/// Returns the kind of an error, errors of the filesystem not finding a file
+/// meaning that the client asked for a file the store does not hold.
+pub fn kind_of(error: &anyhow::Error) -> ErrorKind {
+    if let Some(error) = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<RequestError>())
+    {
+        return error.kind;
+    }
+    match error.downcast_ref::<io::Error>().map(io::Error::kind) {
+        Some(io::ErrorKind::NotFound) => ErrorKind::NotFound,
+        _ => ErrorKind::Failed,
+    }
+}
+
+/// The error of a request the server cannot serve, rather than of the
+/// server itself.
+#[derive(Debug)]
+pub struct RequestError {
+    pub kind: ErrorKind,
+    message: String,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Returns an error of the given kind.
+pub fn error(kind: ErrorKind, message: impl Into<String>) -> anyhow::Error {
+    RequestError {
+        kind,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// Returns the error of a message of the protocol that could not be read,
+/// or that exceeds the limits of the server.
+pub fn from_protocol(e: protocol::Error) -> anyhow::Error {
+    match e {
+        protocol::Error::Io(e) => e.into(),
+        protocol::Error::Invalid(reason) => {
+            error(ErrorKind::InvalidRequest, reason)
+        }
+        protocol::Error::TooLarge(reason) => error(ErrorKind::TooLarge, reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        let not_found = error(ErrorKind::NotFound, "Upload 00 not found");
+        assert_eq!(kind_of(&not_found), ErrorKind::NotFound);
+        assert_eq!(
+            kind_of(&not_found.context("Could not serve download")),
+            ErrorKind::NotFound
+        );
+        let io = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(kind_of(&io.into()), ErrorKind::NotFound);
+        let io = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(kind_of(&io.into()), ErrorKind::Failed);
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use std::time::Duration;
+
+use crate::backend::sharded;
+use crate::store::FileStore;
+
+/// How often the expired uploads are deleted by default.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Deletes the expired uploads of a store and of its namespaces.
+///
+/// # Returns
+///
+/// Returns the number of uploads deleted.
+///
+/// # Errors
+///
+/// Returns an error if the namespaces cannot be listed. Those whose uploads
+/// cannot be deleted are skipped, with an error logged.
+pub async fn delete_expired(store: &FileStore) -> Result<usize> {
+    let mut deleted = 0;
+    for store in &store.with_namespaces().await? {
+        match store.delete_expired().await {
+            Ok(root_hashes) => {
+                for root_hash in &root_hashes {
+                    let dir = sharded(store.root_dir(), root_hash);
+                    log::info!("Deleted expired upload {}", dir.display());
+                }
+                deleted += root_hashes.len();
+            }
+            Err(e) => {
+                let dir = store.root_dir().display();
+                log::error!(
+                    "Could not delete the expired uploads of {}: {:#}",
+                    dir,
+                    e
+                );
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+/// Deletes the expired uploads of a store every `interval`, for as long as
+/// the server runs, unless it is read-only.
+///
+/// # Arguments
+///
+/// * `store` - The store.
+/// * `interval` - How often the expired uploads are deleted.
+pub async fn run(store: FileStore, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        // The expired uploads are left until the server is writable again
+        if store.is_read_only() {
+            continue;
+        }
+        if let Err(e) = delete_expired(&store).await {
+            log::error!("Could not delete the expired uploads: {:#}", e);
+        }
+    }
+}
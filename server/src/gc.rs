@@ -0,0 +1,243 @@
+use anyhow::Result;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::store::{FileStore, STAGING_DIR};
+
+/// How long the files being uploaded are kept by default without being
+/// written to, before being collected.
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What a collection of the garbage of a store removed, or would remove on a
+/// dry run.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Whether nothing is removed, the report telling what would be.
+    pub dry_run: bool,
+    /// The paths of what is removed, under the directory of the store, with
+    /// their size in bytes.
+    pub removed: Vec<(PathBuf, u64)>,
+    /// The contents whose count of references is corrected.
+    pub recounted: Vec<PathBuf>,
+}
+
+impl GcReport {
+    /// Reports a file, blob or upload removed.
+    pub fn remove(&mut self, path: PathBuf, size: u64) {
+        self.removed.push((path, size));
+    }
+
+    /// Returns whether there was no garbage.
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.recounted.is_empty()
+    }
+
+    /// Returns the lines the report is printed as: what was removed, then
+    /// the summary.
+    pub fn lines(&self) -> Vec<String> {
+        let removed = self
+            .removed
+            .iter()
+            .map(|(path, size)| format!("{} ({} bytes)", path.display(), size));
+        let recounted = self.recounted.iter().map(|path| {
+            format!("{} (references counted again)", path.display())
+        });
+        removed.chain(recounted).chain([self.to_string()]).collect()
+    }
+}
+
+impl fmt::Display for GcReport {
+    /// Formats the summary of the report, on a single line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.removed.iter().map(|(_, size)| size).sum::<u64>();
+        write!(
+            f,
+            "{} {} files, blobs and uploads ({} bytes), and {} {} counts of \
+             references",
+            if self.dry_run {
+                "Would remove"
+            } else {
+                "Removed"
+            },
+            self.removed.len(),
+            bytes,
+            if self.dry_run {
+                "would correct"
+            } else {
+                "corrected"
+            },
+            self.recounted.len(),
+        )
+    }
+}
+
+/// Collects the garbage of a store and of its namespaces: the files being
+/// uploaded not written to for a while, e.g. those of aborted uploads, the
+/// uploads left partial, and the contents no upload references.
+///
+/// # Arguments
+///
+/// * `store` - The store.
+/// * `grace` - How long a file being uploaded is kept without being written
+///   to, for its upload to be resumed.
+/// * `dry_run` - Whether to only report what would be removed.
+///
+/// # Errors
+///
+/// Returns an error if the namespaces cannot be listed. Those whose garbage
+/// cannot be collected are skipped, with an error logged.
+pub async fn collect(
+    store: &FileStore,
+    grace: Duration,
+    dry_run: bool,
+) -> Result<GcReport> {
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+    let before = SystemTime::now()
+        .checked_sub(grace)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    for store in &store.with_namespaces().await? {
+        let staging = store.root_dir().join(STAGING_DIR);
+        let swept = sweep(&staging, before, &mut report);
+        let collected = match swept {
+            Ok(_) => store.collect_garbage(&mut report).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = collected {
+            let dir = store.root_dir().display();
+            log::error!("Could not collect the garbage of {}: {:#}", dir, e);
+        }
+    }
+    Ok(report)
+}
+
+/// Collects the garbage of a store every `interval`, logging what was
+/// removed, for as long as the server runs, unless it is read-only.
+///
+/// # Arguments
+///
+/// * `store` - The store.
+/// * `interval` - How often the garbage is collected.
+/// * `grace` - How long a file being uploaded is kept without being written
+///   to.
+pub async fn run(store: FileStore, interval: Duration, grace: Duration) {
+    let start = tokio::time::Instant::now() + interval;
+    let mut ticks = tokio::time::interval_at(start, interval);
+    loop {
+        ticks.tick().await;
+        // Nothing is removed while the server is read-only, e.g. while the
+        // store is being migrated
+        if store.is_read_only() {
+            continue;
+        }
+        match collect(&store, grace, false).await {
+            Ok(report) if !report.is_empty() => log::info!("{}", report),
+            Ok(_) => {}
+            Err(e) => log::error!("Could not collect the garbage: {:#}", e),
+        }
+    }
+}
+
+/// Removes the files under a directory not written to since `before`, and
+/// the directories they leave empty.
+///
+/// # Returns
+///
+/// Returns whether everything under the directory is removed.
+fn sweep(
+    dir: &Path,
+    before: SystemTime,
+    report: &mut GcReport,
+) -> io::Result<bool> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    };
+    let mut removed_all = true;
+    for entry in entries {
+        let entry = entry?;
+        let (path, metadata) = (entry.path(), entry.metadata()?);
+        let old = metadata.modified()? < before;
+        if metadata.is_dir() {
+            // A directory is only removed once it is old too, e.g. not
+            // just created for the first file of an upload session
+            if sweep(&path, before, report)? && old {
+                // Unless a file was just created in it
+                if !report.dry_run {
+                    let _ = fs::remove_dir(&path);
+                }
+            } else {
+                removed_all = false;
+            }
+        } else if old {
+            report.remove(path.clone(), metadata.len());
+            if !report.dry_run {
+                remove(fs::remove_file(&path))?;
+            }
+        } else {
+            removed_all = false;
+        }
+    }
+    Ok(removed_all)
+}
+
+/// Ignores a file already removed, e.g. staged by a commit.
+fn remove(removed: io::Result<()>) -> io::Result<()> {
+    match removed {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collect() {
+        let dir = std::env::temp_dir().join("file-guardian-test-gc");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let alice = store.namespace("alice").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        let write = |path: PathBuf, modified| {
+            fs::write(&path, b"abc").unwrap();
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(modified).unwrap();
+            path
+        };
+
+        // The partial files of an aborted upload session, and a file staged
+        // but never committed, the namespaces being collected too
+        let session = "ab".repeat(32);
+        let aborted = write(alice.partial_file(&session, 0).unwrap(), old);
+        let resumed = alice.partial_file(&session, 1).unwrap();
+        let resumed = write(resumed, SystemTime::now());
+        fs::create_dir_all(dir.join(STAGING_DIR)).unwrap();
+        let staged = write(dir.join(STAGING_DIR).join("cd".repeat(32)), old);
+
+        let grace = Duration::from_secs(60 * 60);
+        let report = collect(&store, grace, true).await.unwrap();
+        assert_eq!(report.removed, [(staged.clone(), 3), (aborted.clone(), 3)]);
+        assert!(aborted.exists() && staged.exists());
+        assert_eq!(
+            report.to_string(),
+            "Would remove 2 files, blobs and uploads (6 bytes), and would \
+             correct 0 counts of references"
+        );
+        let report = collect(&store, grace, false).await.unwrap();
+        assert_eq!(report.removed.len(), 2);
+        assert!(!aborted.exists() && !staged.exists());
+        // The files still being written to are kept
+        assert!(resumed.exists());
+        assert!(collect(&store, grace, false).await.unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,781 @@
+use anyhow::{anyhow, Result};
+use merkle_tree::{MerkleTree, Proof};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
+    AsyncWriteExt, BufReader,
+};
+
+use crate::auth::Auth;
+use crate::deadline::{within, Deadline, Timeouts};
+use crate::error::{from_protocol, kind_of, ErrorKind};
+use crate::logger;
+use crate::store::FileStore;
+
+/// The maximum size of the request line and headers of a request.
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+
+/// The maximum size of the body of a commit, a JSON array of hashes.
+const MAX_COMMIT_SIZE: u64 = 1024 * 1024;
+
+/// The maximum size of the body of a retention, a JSON object.
+const MAX_RETENTION_SIZE: u64 = 1024;
+
+/// The number of files received over HTTP since the server started, to give
+/// each its own upload session.
+static SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The request line and headers of an HTTP request.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    /// The path of the request, without the query string.
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Returns the value of a header, whose name is case insensitive.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the length of the body, which must be given as requests are
+    /// not accepted chunked.
+    fn content_length(&self) -> Result<u64> {
+        if self.header("Transfer-Encoding").is_some() {
+            return Err(error(411, "Chunked requests are not supported"));
+        }
+        match self.header("Content-Length") {
+            Some(length) => length
+                .parse()
+                .map_err(|_| error(400, "Invalid Content-Length")),
+            None => Ok(0),
+        }
+    }
+}
+
+/// A response to an HTTP request.
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+/// The body of a response.
+enum Body {
+    Bytes(Vec<u8>),
+    /// A file of an upload, copied from the store as it is sent.
+    File {
+        root_hash: String,
+        index: usize,
+        size: u64,
+    },
+}
+
+impl Response {
+    /// Creates a response with a JSON body.
+    fn json(status: u16, body: &serde_json::Value) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "application/json".to_string())],
+            body: Body::Bytes(body.to_string().into_bytes()),
+        }
+    }
+}
+
+/// An error answered with an HTTP status other than the one of its
+/// [`ErrorKind`].
+#[derive(Debug)]
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Returns an error answered with the given status.
+fn error(status: u16, message: impl Into<String>) -> anyhow::Error {
+    HttpError {
+        status,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// Returns the status answering errors of a kind.
+fn status(kind: ErrorKind) -> u16 {
+    match kind {
+        ErrorKind::Failed => 500,
+        ErrorKind::NotFound => 404,
+        ErrorKind::Unauthorized => 401,
+        ErrorKind::InvalidRequest => 400,
+        ErrorKind::QuotaExceeded => 507,
+        ErrorKind::TooLarge => 413,
+        ErrorKind::ReadOnly => 503,
+    }
+}
+
+/// Handles an HTTP request, the connection being closed once it is answered.
+///
+/// The API mirrors the TCP protocol, for clients such as curl, browsers and
+/// load balancers:
+///
+/// * `POST /files` stages the file sent as the body, and replies with its
+///   SHA-256.
+/// * `POST /uploads` commits the staged files whose hashes are sent as a
+///   JSON array, in order, and replies with the root hash and its
+///   signature, if the server signs them, with `200 OK` rather than
+///   `201 Created` if the upload was already stored.
+/// * `GET /uploads` lists the uploads held by the server.
+/// * `GET /uploads/{root}/{index}` replies with a file, and its leaf hash and
+///   Merkle proof in the `X-Merkle-Leaf` and `X-Merkle-Proof` headers.
+/// * `GET /uploads/{root}/{index}/proof` replies with the proof of a file as
+///   JSON, in the format of [`merkle_tree::Proof`].
+/// * `DELETE /uploads/{root}` deletes an upload.
+/// * `GET /health` replies with the version and uptime of the server once
+///   its store is checked, with `503 Service Unavailable` if it fails, for
+///   load balancers and orchestrators to probe.
+///
+/// When the server requires authentication, requests must carry a token in
+/// an `Authorization: Bearer` header, except for `GET /health`.
+///
+/// # Arguments
+///
+/// * `stream` - The stream, over TCP or TLS, that connects the server to the
+///   client.
+/// * `store` - The store that holds the uploads.
+/// * `auth` - How tokens are validated, `None` accepting any client.
+/// * `started` - When the server started.
+///
+/// # Errors
+///
+/// Returns an error if the request is invalid or could not be served, once
+/// the client has been told so if possible.
+pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: &mut S,
+    store: &FileStore,
+    auth: Option<&Auth>,
+    started: Instant,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let mut stream = BufReader::new(Deadline::new(stream, timeouts.io));
+    let read = read_request(&mut stream);
+    let result = match within(timeouts.handshake, "the request", read).await {
+        Ok(request) => {
+            logger::record("method", request.method.as_str());
+            logger::record("path", request.path.as_str());
+            match (request.method.as_str(), request.path.as_str()) {
+                // Probes carry no token
+                ("GET", "/health") => Ok(health(store, started).await),
+                _ => handle_request(&mut stream, &request, store, auth).await,
+            }
+        }
+        Err(e) => Err(e),
+    };
+    let (response, result) = match result {
+        Ok(response) => (response, Ok(())),
+        Err(e) => {
+            let (status, message) = match e.downcast_ref::<HttpError>() {
+                Some(e) => (e.status, e.message.clone()),
+                None => (status(kind_of(&e)), e.to_string()),
+            };
+            let mut response =
+                Response::json(status, &json!({ "error": message }));
+            if status == 401 {
+                response
+                    .headers
+                    .push(("WWW-Authenticate", "Bearer".to_string()));
+            }
+            (response, Err(e))
+        }
+    };
+    logger::record("status", response.status);
+    write_response(&mut stream, &response, store).await?;
+    stream.shutdown().await?;
+    result
+}
+
+/// Reads the request line and headers of a request.
+///
+/// # Errors
+///
+/// Returns an error if the request is malformed or its head is too large.
+async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Request> {
+    let mut reader = reader.take(MAX_HEAD_SIZE);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version))
+            if version.starts_with("HTTP/1.") =>
+        {
+            (method.to_string(), target)
+        }
+        _ => return Err(error(400, "Invalid request line")),
+    };
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(error(431, "Request head too large or truncated"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| error(400, "Invalid header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(Request {
+        method,
+        path,
+        headers,
+    })
+}
+
+/// Authenticates and routes a request.
+async fn handle_request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &Request,
+    store: &FileStore,
+    auth: Option<&Auth>,
+) -> Result<Response> {
+    let user = match auth {
+        Some(auth) => match request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            None => return Err(error(401, "Authentication required")),
+            Some(token) => auth
+                .authenticate(token.trim())
+                .map_err(|e| error(401, e.to_string()))?,
+        },
+        None => None,
+    };
+    // The uploads of a user are kept apart from those of other users
+    let store = match user {
+        Some(user) => {
+            logger::record("user", user.as_str());
+            &store.namespace(&user)?
+        }
+        None => store,
+    };
+
+    let segments = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["files"]) => put_file(stream, request, store).await,
+        ("POST", ["uploads"]) => commit(stream, request, store).await,
+        ("GET", ["uploads"]) => list_uploads(store).await,
+        ("GET", ["usage"]) => usage(store).await,
+        ("GET", ["uploads", root_hash, index]) => {
+            get_file(store, root_hash, index).await
+        }
+        ("GET", ["uploads", root_hash, index, "proof"]) => {
+            get_proof(store, root_hash, index).await
+        }
+        ("DELETE", ["uploads", root_hash]) => {
+            delete_upload(store, root_hash).await
+        }
+        ("PUT", ["uploads", root_hash, "retention"]) => {
+            retain(stream, request, store, root_hash).await
+        }
+        _ => Err(error(404, format!("No route for {}", request.path))),
+    }
+}
+
+/// Reads the body of a request, after telling the client to send it if it
+/// waits for it.
+async fn body<'a, S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &'a mut S,
+    request: &Request,
+) -> Result<tokio::io::Take<&'a mut S>> {
+    let length = request.content_length()?;
+    if request
+        .header("Expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        stream.flush().await?;
+    }
+    Ok(stream.take(length))
+}
+
+/// Stages the file sent as the body of the request, in a session of its own.
+async fn put_file<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &Request,
+    store: &FileStore,
+) -> Result<Response> {
+    let length = request.content_length()?;
+    store
+        .limits()
+        .check_file_size(length)
+        .map_err(from_protocol)?;
+    // The file is reserved in the quota while received
+    let _reserved = store.reserve_quota(length).await?;
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_nanos();
+    let session = hex::encode(Sha256::digest(
+        format!(
+            "http-{}-{}-{}",
+            std::process::id(),
+            nanos,
+            SESSIONS.fetch_add(1, Ordering::Relaxed)
+        )
+        .as_bytes(),
+    ));
+
+    let path = store.partial_file(&session, 0)?;
+    let mut file = tokio::fs::File::create(&path).await?;
+    let received =
+        tokio::io::copy(&mut body(stream, request).await?, &mut file).await?;
+    file.sync_all().await?;
+    if received < length {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(anyhow!(
+            "Connection closed after {} of {} bytes",
+            received,
+            length
+        ));
+    }
+    let hash = store.stage_file(&session, 0)?;
+    Ok(Response::json(201, &json!({ "hash": hash })))
+}
+
+/// Commits the staged files whose hashes are sent as a JSON array.
+async fn commit<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &Request,
+    store: &FileStore,
+) -> Result<Response> {
+    if request.content_length()? > MAX_COMMIT_SIZE {
+        return Err(error(413, "Too many files"));
+    }
+    let mut content = vec![];
+    body(stream, request)
+        .await?
+        .read_to_end(&mut content)
+        .await?;
+    let hashes: Vec<String> =
+        serde_json::from_slice(&content).map_err(|e| {
+            error(400, format!("Expected an array of hashes: {}", e))
+        })?;
+    // A file not staged is a mistake of the client, not a missing upload
+    let committed =
+        store
+            .commit_files(&hashes)
+            .await
+            .map_err(|e| match kind_of(&e) {
+                ErrorKind::InvalidRequest | ErrorKind::NotFound => {
+                    error(400, e.to_string())
+                }
+                _ => e,
+            })?;
+    let status = match committed.already_stored {
+        true => 200,
+        false => 201,
+    };
+    Ok(Response::json(
+        status,
+        &json!({
+            "root_hash": committed.root_hash,
+            "already_stored": committed.already_stored,
+            "signature": committed.signature,
+        }),
+    ))
+}
+
+/// Keeps an upload for the number of seconds sent as `{"seconds": ...}`,
+/// after which it is deleted.
+async fn retain<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &Request,
+    store: &FileStore,
+    root_hash: &str,
+) -> Result<Response> {
+    if request.content_length()? > MAX_RETENTION_SIZE {
+        return Err(error(413, "The retention is too large"));
+    }
+    let mut content = vec![];
+    body(stream, request)
+        .await?
+        .read_to_end(&mut content)
+        .await?;
+    let secs = serde_json::from_slice::<serde_json::Value>(&content)
+        .ok()
+        .and_then(|retention| retention["seconds"].as_u64())
+        .ok_or_else(|| error(400, "Expected {\"seconds\": ...}"))?;
+    store.retain(root_hash, Duration::from_secs(secs)).await?;
+    Ok(Response {
+        status: 204,
+        headers: vec![],
+        body: Body::Bytes(vec![]),
+    })
+}
+
+/// Tells the client how many bytes its uploads hold, and its quota.
+async fn usage(store: &FileStore) -> Result<Response> {
+    let used = store.usage().await?;
+    Ok(Response::json(
+        200,
+        &json!({ "used": used, "quota": store.quota() }),
+    ))
+}
+
+/// Checks the store, and replies with the version and uptime of the server,
+/// and whether it is read-only.
+async fn health(store: &FileStore, started: Instant) -> Response {
+    let mut health = json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": started.elapsed().as_secs(),
+        "read_only": store.is_read_only(),
+    });
+    match store.check_health().await {
+        Ok(()) => Response::json(200, &health),
+        Err(e) => {
+            log::error!("Health check failed: {:#}", e);
+            health["status"] = "unavailable".into();
+            health["error"] = format!("{:#}", e).into();
+            Response::json(503, &health)
+        }
+    }
+}
+
+/// Lists the uploads held by the server.
+async fn list_uploads(store: &FileStore) -> Result<Response> {
+    let uploads = store
+        .list_uploads()
+        .await?
+        .into_iter()
+        .map(|upload| {
+            json!({
+                "root_hash": upload.root_hash,
+                "files": upload.files,
+                "size": upload.size,
+            })
+        })
+        .collect();
+    Ok(Response::json(200, &serde_json::Value::Array(uploads)))
+}
+
+/// Serves a file, with its leaf hash and Merkle proof in headers.
+async fn get_file(
+    store: &FileStore,
+    root_hash: &str,
+    index: &str,
+) -> Result<Response> {
+    let (tree, index) = get_tree(store, root_hash, index).await?;
+    let proof = tree.proof(index)?;
+    Ok(Response {
+        status: 200,
+        headers: vec![
+            ("Content-Type", "application/octet-stream".to_string()),
+            ("X-Merkle-Leaf", hex::encode(tree.leaves()[index])),
+            (
+                "X-Merkle-Proof",
+                proof.iter().map(hex::encode).collect::<Vec<_>>().join(","),
+            ),
+        ],
+        body: Body::File {
+            root_hash: root_hash.to_string(),
+            index,
+            size: store.file_size(root_hash, index).await?,
+        },
+    })
+}
+
+/// Serves the Merkle proof of a file as JSON.
+async fn get_proof(
+    store: &FileStore,
+    root_hash: &str,
+    index: &str,
+) -> Result<Response> {
+    let (tree, index) = get_tree(store, root_hash, index).await?;
+    let root = *tree
+        .root()
+        .ok_or_else(|| anyhow!("Root Hash could not be computed"))?;
+    let proof =
+        Proof::new(index, tree.leaves()[index], root, tree.proof(index)?);
+    Ok(Response::json(200, &serde_json::to_value(proof)?))
+}
+
+/// Deletes an upload.
+async fn delete_upload(store: &FileStore, root_hash: &str) -> Result<Response> {
+    match store.delete_upload(root_hash).await.map_err(|e| {
+        match kind_of(&e) {
+            ErrorKind::ReadOnly => e,
+            _ => error(400, e.to_string()),
+        }
+    })? {
+        true => Ok(Response {
+            status: 204,
+            headers: vec![],
+            body: Body::Bytes(vec![]),
+        }),
+        false => Err(error(404, format!("Upload {} not found", root_hash))),
+    }
+}
+
+/// Returns the Merkle tree of an upload, and the index of one of its files.
+///
+/// # Errors
+///
+/// Returns an error answered with a 400 status if the root hash or the
+/// index is invalid, and a 404 status if the upload or the file does not
+/// exist.
+async fn get_tree(
+    store: &FileStore,
+    root_hash: &str,
+    index: &str,
+) -> Result<(Arc<MerkleTree>, usize)> {
+    // The root hash names a directory of the store
+    if hex::decode(root_hash).map_or(true, |hash| hash.len() != 32) {
+        return Err(error(400, format!("Invalid root hash {}", root_hash)));
+    }
+    let index = index
+        .parse::<usize>()
+        .map_err(|_| error(400, format!("Invalid index {}", index)))?;
+    let tree = store.get_served_tree(root_hash).await?;
+    if index >= tree.leaves().len() {
+        return Err(error(404, format!("File {} not found", index)));
+    }
+    Ok((tree, index))
+}
+
+/// Sends a response, closing the connection after it.
+///
+/// # Errors
+///
+/// Returns an error if the response cannot be sent, or the file of its body
+/// read, from `store`, to its end.
+async fn write_response<S: AsyncWrite + Unpin + Send>(
+    stream: &mut S,
+    response: &Response,
+    store: &FileStore,
+) -> Result<()> {
+    let len = match &response.body {
+        Body::Bytes(bytes) => bytes.len() as u64,
+        Body::File { size, .. } => *size,
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status),
+        len
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    match &response.body {
+        Body::Bytes(bytes) => stream.write_all(bytes).await?,
+        Body::File {
+            root_hash,
+            index,
+            size,
+        } => {
+            let copied =
+                store.copy_file(root_hash, *index, None, stream).await?;
+            if copied != *size {
+                return Err(anyhow!("File {} changed while sent", index));
+            }
+        }
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Returns the reason phrase of the statuses the server answers with.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends a request to the HTTP API, and returns the response.
+    async fn request(store: &FileStore, request: &str) -> String {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let started = Instant::now();
+        let timeouts = Timeouts::default();
+        let _ =
+            handle_client(&mut server, store, None, started, timeouts).await;
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let mut head = "GET /uploads?x=1 HTTP/1.1\r\nHost: a\r\n\
+                        content-length: 5\r\n\r\nhello"
+            .as_bytes();
+        let request = read_request(&mut head).await.unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/uploads");
+        assert_eq!(request.header("Content-Length"), Some("5"));
+        assert_eq!(request.content_length().unwrap(), 5);
+        assert_eq!(head, b"hello");
+
+        let mut head = "GET /\r\n\r\n".as_bytes();
+        assert!(read_request(&mut head).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_and_download() {
+        let dir = std::env::temp_dir().join("file-guardian-test-http");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+
+        let mut hashes = vec![];
+        for file in ["hello", "world"] {
+            let response = request(
+                &store,
+                &format!(
+                    "POST /files HTTP/1.1\r\nContent-Length: {}\r\n\
+                     Expect: 100-continue\r\n\r\n{}",
+                    file.len(),
+                    file
+                ),
+            )
+            .await;
+            assert!(response.starts_with("HTTP/1.1 100 Continue\r\n\r\n"));
+            assert!(response.contains("HTTP/1.1 201 Created"));
+            let body = response.split("\r\n\r\n").last().unwrap();
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            hashes.push(body["hash"].as_str().unwrap().to_string());
+        }
+
+        let hashes = serde_json::to_string(&hashes).unwrap();
+        let response = request(
+            &store,
+            &format!(
+                "POST /uploads HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                hashes.len(),
+                hashes
+            ),
+        )
+        .await;
+        let body = response.split("\r\n\r\n").last().unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        let root_hash = body["root_hash"].as_str().unwrap();
+
+        // Committing a file that was not staged is a bad request
+        let unstaged = format!("[\"{}\"]", "ab".repeat(32));
+        let response = request(
+            &store,
+            &format!(
+                "POST /uploads HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                unstaged.len(),
+                unstaged
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400"));
+
+        let response = request(
+            &store,
+            &format!("GET /uploads/{}/1 HTTP/1.1\r\n\r\n", root_hash),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nworld"));
+        let tree = store.get_tree(root_hash).await.unwrap();
+        assert!(response.contains(&format!(
+            "X-Merkle-Proof: {}\r\n",
+            hex::encode(tree.leaves()[0])
+        )));
+
+        let response = request(
+            &store,
+            &format!("GET /uploads/{}/0/proof HTTP/1.1\r\n\r\n", root_hash),
+        )
+        .await;
+        let body = response.split("\r\n\r\n").last().unwrap();
+        let proof: Proof = serde_json::from_str(body).unwrap();
+        assert!(proof.verify());
+
+        for (path, status) in [
+            (format!("/uploads/{}/2", root_hash), "404"),
+            (format!("/uploads/{}/x", root_hash), "400"),
+            ("/uploads/../0".to_string(), "400"),
+            ("/other".to_string(), "404"),
+        ] {
+            let response =
+                request(&store, &format!("GET {} HTTP/1.1\r\n\r\n", path))
+                    .await;
+            assert!(response.starts_with(&format!("HTTP/1.1 {}", status)));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let dir = std::env::temp_dir().join("file-guardian-test-http-health");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+
+        let response = request(&store, "GET /health HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").last().unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert!(std::fs::read_dir(dir.join(crate::store::STAGING_DIR))
+            .unwrap()
+            .next()
+            .is_none());
+
+        // The files being uploaded can no longer be written
+        std::fs::remove_dir_all(dir.join(crate::store::STAGING_DIR)).unwrap();
+        std::fs::write(dir.join(crate::store::STAGING_DIR), b"").unwrap();
+        let response = request(&store, "GET /health HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(response.contains("\"status\":\"unavailable\""));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
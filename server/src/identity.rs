@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use protocol::{Hash, Response, Signature};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The ed25519 identity key of a server, which signs the root hash of every
+/// upload it commits, for the clients to hold evidence of exactly what the
+/// server committed to storing.
+pub struct Identity {
+    pair: Ed25519KeyPair,
+}
+
+impl Identity {
+    /// Creates the identity of a 32-byte seed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the seed is rejected.
+    pub fn from_seed(seed: &[u8; 32]) -> Result<Self> {
+        let pair = Ed25519KeyPair::from_seed_unchecked(seed)
+            .map_err(|e| anyhow!("Invalid identity key: {}", e))?;
+        Ok(Self { pair })
+    }
+
+    /// Reads the identity from a file holding its seed as hex, generating
+    /// one and writing it to the file, readable by its owner only, if the
+    /// file does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or written, or does not
+    /// hold a seed.
+    pub fn load(path: &Path) -> Result<Self> {
+        let read = |e: io::Error| {
+            anyhow!("Could not read identity key {}: {}", path.display(), e)
+        };
+        let seed = match std::fs::read_to_string(path) {
+            Ok(seed) => hex::decode(seed.trim())
+                .ok()
+                .and_then(|seed| <[u8; 32]>::try_from(seed).ok())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Identity key {} is not a hex encoded 32-byte seed",
+                        path.display()
+                    )
+                })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let seed = generate(path).map_err(|e| {
+                    anyhow!(
+                        "Could not write identity key {}: {}",
+                        path.display(),
+                        e
+                    )
+                })?;
+                log::info!("Generated identity key {}", path.display());
+                seed
+            }
+            Err(e) => return Err(read(e)),
+        };
+        Self::from_seed(&seed)
+    }
+
+    /// Returns the public key the signatures verify with.
+    pub fn public_key(&self) -> Hash {
+        let mut key = [0; 32];
+        key.copy_from_slice(self.pair.public_key().as_ref());
+        key
+    }
+
+    /// Signs the root hash of an upload, see [`protocol::signed_root`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the root hash is not a hex encoded SHA-256.
+    pub fn sign(&self, root_hash: &str) -> Result<RootSignature> {
+        let root = hex::decode(root_hash)
+            .ok()
+            .and_then(|root| Hash::try_from(root).ok())
+            .ok_or_else(|| anyhow!("Invalid root hash {}", root_hash))?;
+        let signature = self.pair.sign(&protocol::signed_root(&root));
+        Ok(RootSignature {
+            key: hex::encode(self.public_key()),
+            signature: hex::encode(signature.as_ref()),
+        })
+    }
+}
+
+/// Writes a random seed to a new file, readable by its owner only.
+fn generate(path: &Path) -> io::Result<[u8; 32]> {
+    let mut seed = [0; 32];
+    getrandom::getrandom(&mut seed)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(format!("{}\n", hex::encode(seed)).as_bytes())?;
+    file.sync_all()?;
+    Ok(seed)
+}
+
+/// The signature of the root hash of an upload by a server, kept with the
+/// upload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootSignature {
+    /// The public key of the server, as hex.
+    pub key: String,
+    /// The signature, as hex.
+    pub signature: String,
+}
+
+impl RootSignature {
+    /// Returns the response carrying the signature to a client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key or the signature is not valid hex of its
+    /// length, e.g. as the file holding it was altered.
+    pub fn response(&self) -> Result<Response> {
+        let invalid = || anyhow!("Invalid signature {:?}", self);
+        let key = hex::decode(&self.key)
+            .ok()
+            .and_then(|key| Hash::try_from(key).ok())
+            .ok_or_else(invalid)?;
+        let signature = hex::decode(&self.signature)
+            .ok()
+            .and_then(|signature| Signature::try_from(signature).ok())
+            .ok_or_else(invalid)?;
+        Ok(Response::Signed { key, signature })
+    }
+
+    /// Returns whether the signature is that of a root hash by its key.
+    pub fn verifies(&self, root_hash: &str) -> bool {
+        let (Ok(key), Ok(signature), Ok(root)) = (
+            hex::decode(&self.key),
+            hex::decode(&self.signature),
+            hex::decode(root_hash),
+        ) else {
+            return false;
+        };
+        let Ok(root) = Hash::try_from(root) else {
+            return false;
+        };
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(&protocol::signed_root(&root), &signature)
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let dir = std::env::temp_dir().join("file-guardian-test-identity");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("identity.key");
+
+        // Generated once, then read
+        let identity = Identity::load(&path).unwrap();
+        let key = identity.public_key();
+        assert_eq!(Identity::load(&path).unwrap().public_key(), key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let root_hash = "ab".repeat(32);
+        let signed = identity.sign(&root_hash).unwrap();
+        assert_eq!(signed.key, hex::encode(key));
+        let Response::Signed { signature, .. } = signed.response().unwrap()
+        else {
+            panic!("Unexpected response");
+        };
+        let message = protocol::signed_root(&[0xab; 32]);
+        let public = UnparsedPublicKey::new(&ED25519, key);
+        assert!(public.verify(&message, &signature).is_ok());
+        let other = protocol::signed_root(&[0xcd; 32]);
+        assert!(public.verify(&other, &signature).is_err());
+        assert!(signed.verifies(&root_hash));
+        assert!(!signed.verifies(&"cd".repeat(32)));
+        assert!(identity.sign("root").is_err());
+
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(Identity::load(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
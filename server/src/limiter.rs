@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The number of addresses tracked beyond which idle ones are forgotten.
+const MAX_IDLE_CLIENTS: usize = 1024;
+
+/// Limits the connections accepted from each source IP, so that a single
+/// client cannot monopolize the server or exhaust its file descriptors.
+pub struct ConnectionLimiter {
+    /// The most connections from an IP served at once, if limited.
+    max_concurrent: Option<usize>,
+    /// The most connections per second accepted from an IP, if limited, as
+    /// many being accepted in a burst.
+    rate: Option<u32>,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+}
+
+/// The connections of a source IP.
+struct Client {
+    /// The connections being served.
+    active: usize,
+    /// The connections that can be accepted right away, refilled at the
+    /// rate of the limiter.
+    tokens: f64,
+    /// When the tokens were last refilled.
+    refilled: Instant,
+}
+
+/// A connection accepted by a [`ConnectionLimiter`], counted as served
+/// until it is dropped.
+pub struct Permit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    /// Creates a limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_concurrent` - The most connections from an IP served at once.
+    /// * `rate` - The most connections per second accepted from an IP.
+    pub fn new(max_concurrent: Option<usize>, rate: Option<u32>) -> Self {
+        Self {
+            max_concurrent,
+            rate,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accepts a connection from an IP, unless it exceeds the limits.
+    ///
+    /// # Returns
+    ///
+    /// The permit of the connection, to hold while it is served.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the IP has too many connections being served, or
+    /// connected too often.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Permit> {
+        let burst = self.rate.map_or(0.0, f64::from);
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_IDLE_CLIENTS {
+            clients.retain(|_, client| {
+                client.active > 0 || self.refill(client, now) < burst
+            });
+        }
+        let client = clients.entry(ip).or_insert(Client {
+            active: 0,
+            tokens: burst,
+            refilled: now,
+        });
+
+        if self.max_concurrent.is_some_and(|max| client.active >= max) {
+            return Err(anyhow!("Too many connections from {}", ip));
+        }
+        if self.rate.is_some() {
+            if self.refill(client, now) < 1.0 {
+                return Err(anyhow!(
+                    "Too many connections per second from {}",
+                    ip
+                ));
+            }
+            client.tokens -= 1.0;
+        }
+        client.active += 1;
+        Ok(Permit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    /// Refills the tokens of a client for the time elapsed, returning them.
+    fn refill(&self, client: &mut Client, now: Instant) -> f64 {
+        if let Some(rate) = self.rate.map(f64::from) {
+            let elapsed = now.duration_since(client.refilled).as_secs_f64();
+            client.tokens = (client.tokens + elapsed * rate).min(rate);
+            client.refilled = now;
+        }
+        client.tokens
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&self.ip) {
+            client.active -= 1;
+            // Without a rate, nothing is left to remember of an idle client
+            if client.active == 0 && self.limiter.rate.is_none() {
+                clients.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter() {
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let limiter = Arc::new(ConnectionLimiter::new(Some(2), None));
+        let first = limiter.admit(a).unwrap();
+        let _second = limiter.admit(a).unwrap();
+        assert!(limiter.admit(a).is_err());
+        // Other addresses are limited on their own
+        let _other = limiter.admit(b).unwrap();
+        drop(first);
+        let _third = limiter.admit(a).unwrap();
+
+        let limiter = Arc::new(ConnectionLimiter::new(None, Some(3)));
+        for _ in 0..3 {
+            limiter.admit(a).unwrap();
+        }
+        assert!(limiter.admit(a).is_err());
+        assert!(limiter.admit(b).is_ok());
+        std::thread::sleep(std::time::Duration::from_millis(400));
+        assert!(limiter.admit(a).is_ok());
+    }
+}
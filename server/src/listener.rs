@@ -0,0 +1,383 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+/// The prefix of the addresses of Unix sockets, e.g.
+/// `unix:/run/file-guardian/server.sock`.
+const UNIX_PREFIX: &str = "unix:";
+
+/// The connections a TCP listener queues before they are accepted.
+const BACKLOG: i32 = 1024;
+
+/// A connection accepted by a [`Listener`], over TCP or a Unix socket.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Where a connection comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    /// A client connected over TCP, from its address.
+    Tcp(SocketAddr),
+    /// A client connected over a Unix socket, with its user ID if the
+    /// system tells it.
+    Unix(Option<u32>),
+}
+
+impl Peer {
+    /// Returns the IP of the peer, which the connections are limited by,
+    /// or None for a Unix socket, whose permissions control who connects.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(Some(uid)) => write!(f, "unix (uid {})", uid),
+            Self::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+/// The prefix of the addresses of sockets inherited from the process
+/// starting the server, e.g. `fd:3` for the first socket systemd passes.
+const FD_PREFIX: &str = "fd:";
+
+/// A listener on a TCP address, or on a Unix socket, which is removed once
+/// the listener is dropped unless it was inherited.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Listens on an address.
+    ///
+    /// A listener on an IPv6 address only accepts IPv6 connections, so that
+    /// another one can listen on the same port of an IPv4 address, e.g.
+    /// `[::]:2345` and `0.0.0.0:2345` for both families.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address, in the format `host:port`, `unix:<path>`
+    ///   for a Unix socket, or `fd:<fd>` for a listening socket inherited,
+    ///   e.g. from systemd, which must not be used elsewhere.
+    /// * `mode` - The permissions of a Unix socket, which the clients need
+    ///   write access to in order to connect.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address cannot be listened on, e.g. if it is
+    /// in use, or for a Unix socket on a system without them.
+    pub async fn bind(address: &str, mode: u32) -> Result<Self> {
+        if let Some(fd) = address.strip_prefix(FD_PREFIX) {
+            let fd = fd
+                .parse()
+                .map_err(|_| anyhow!("Invalid file descriptor {}", fd))?;
+            return Self::inherit(fd).map_err(|e| {
+                anyhow!("Could not listen on {}: {}", address, e)
+            });
+        }
+        match unix_path(address) {
+            #[cfg(unix)]
+            Some(path) => {
+                let listener = bind_unix(path, mode).await?;
+                Ok(Self::Unix(listener, Some(path.to_path_buf())))
+            }
+            #[cfg(not(unix))]
+            Some(path) => {
+                let _ = mode;
+                Err(anyhow!(
+                    "Unix sockets are not supported, to listen on {}",
+                    path.display()
+                ))
+            }
+            None => Ok(Self::Tcp(bind_tcp(address).await.map_err(|e| {
+                anyhow!("Could not listen on {}: {}", address, e)
+            })?)),
+        }
+    }
+
+    /// Listens on a socket inherited from the process starting the server,
+    /// already bound and listening.
+    #[cfg(unix)]
+    fn inherit(fd: i32) -> io::Result<Self> {
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        if fd < 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a socket",
+            ));
+        }
+        // Safety: the descriptor is only taken once, see `bind`
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        if socket.r#type()? != socket2::Type::STREAM {
+            // Leave the descriptor to whoever owns it
+            std::mem::forget(socket);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a stream socket",
+            ));
+        }
+        socket.set_nonblocking(true)?;
+        socket.set_cloexec(true)?;
+        match socket.local_addr()?.is_unix() {
+            true => {
+                let listener = std::os::unix::net::UnixListener::from(
+                    OwnedFd::from(socket),
+                );
+                Ok(Self::Unix(
+                    tokio::net::UnixListener::from_std(listener)?,
+                    None,
+                ))
+            }
+            false => Ok(Self::Tcp(TcpListener::from_std(socket.into())?)),
+        }
+    }
+
+    /// Listens on a socket inherited, which requires Unix.
+    #[cfg(not(unix))]
+    fn inherit(_fd: i32) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Accepts a connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no connection can be accepted, e.g. when out of
+    /// file descriptors.
+    pub async fn accept(&self) -> io::Result<(Box<dyn Stream>, Peer)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Box::new(socket), Peer::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let (socket, _) = listener.accept().await?;
+                let uid = socket.peer_cred().ok().map(|cred| cred.uid());
+                Ok((Box::new(socket), Peer::Unix(uid)))
+            }
+        }
+    }
+
+    /// Returns the address listened on, for the logs.
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Self::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let addr = listener.local_addr()?;
+                match addr.as_pathname() {
+                    Some(path) => {
+                        Ok(format!("{}{}", UNIX_PREFIX, path.display()))
+                    }
+                    None => Ok(format!("{}{:?}", UNIX_PREFIX, addr)),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Self::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Returns the path of the Unix socket of an address in the format
+/// `unix:<path>`, or None for a TCP address.
+pub fn unix_path(address: &str) -> Option<&Path> {
+    address.strip_prefix(UNIX_PREFIX).map(Path::new)
+}
+
+/// Listens on a TCP address, only accepting IPv6 connections on an IPv6
+/// address.
+async fn bind_tcp(address: &str) -> io::Result<TcpListener> {
+    let addr =
+        match address.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            // A host name, resolved to the first of its addresses
+            Err(_) => tokio::net::lookup_host(address)
+                .await?
+                .next()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Could not resolve {}", address),
+                    )
+                })?,
+        };
+    let domain = socket2::Domain::for_address(addr);
+    let socket = socket2::Socket::new(domain, socket2::Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // As tokio does, for a restarted server not to wait for the connections
+    // of the previous one to time out
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Listens on a Unix socket, replacing the socket of a server that did not
+/// stop cleanly.
+///
+/// # Arguments
+///
+/// * `path` - The path of the socket.
+/// * `mode` - The permissions of the socket.
+///
+/// # Errors
+///
+/// Returns an error if a server is already listening on the socket, or if
+/// it cannot be created.
+#[cfg(unix)]
+pub async fn bind_unix(
+    path: &Path,
+    mode: u32,
+) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{UnixListener, UnixStream};
+
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(anyhow!(
+            "A server is already listening on {}",
+            path.display()
+        ));
+    }
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path).map_err(|e| {
+        anyhow!("Could not listen on {}: {}", path.display(), e)
+    })?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_unix_path() {
+        assert_eq!(
+            unix_path("unix:/run/server.sock"),
+            Some(Path::new("/run/server.sock"))
+        );
+        assert_eq!(
+            unix_path("unix:server.sock"),
+            Some(Path::new("server.sock"))
+        );
+        assert_eq!(unix_path("127.0.0.1:2345"), None);
+        assert_eq!(unix_path("[::1]:2345"), None);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let ipv4 = Listener::bind("127.0.0.1:0", 0).await.unwrap();
+        let port = ipv4
+            .local_addr()
+            .unwrap()
+            .rsplit_once(':')
+            .unwrap()
+            .1
+            .to_string();
+        // Without a route to IPv6, or IPv6 at all, there is nothing to test
+        let Ok(ipv6) = Listener::bind(&format!("[::1]:{}", port), 0).await
+        else {
+            return;
+        };
+        assert_eq!(ipv6.local_addr().unwrap(), format!("[::1]:{}", port));
+
+        let connect = |address: String| async move {
+            tokio::net::TcpStream::connect(address).await.unwrap();
+        };
+        let (_, (_, peer)) =
+            tokio::join!(connect(format!("127.0.0.1:{}", port)), async {
+                ipv4.accept().await.unwrap()
+            });
+        assert!(peer.ip().unwrap().is_ipv4());
+        let (_, (_, peer)) =
+            tokio::join!(connect(format!("[::1]:{}", port)), async {
+                ipv6.accept().await.unwrap()
+            });
+        assert!(peer.ip().unwrap().is_ipv6());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_inherit() {
+        use std::os::fd::IntoRawFd;
+
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let fd = socket.into_raw_fd();
+        let listener = Listener::bind(&format!("fd:{}", fd), 0).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr.to_string());
+        let (_, (_, peer)) = tokio::join!(
+            async { tokio::net::TcpStream::connect(addr).await.unwrap() },
+            async { listener.accept().await.unwrap() }
+        );
+        assert!(matches!(peer, Peer::Tcp(_)));
+
+        assert!(Listener::bind("fd:0", 0).await.is_err());
+        assert!(Listener::bind("fd:stdin", 0).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_listener() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("file-guardian-test-listener");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.sock");
+        let address = format!("unix:{}", path.display());
+
+        let listener = Listener::bind(&address, 0o660).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), address);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let client = tokio::spawn({
+            let path = path.clone();
+            async move {
+                let mut stream =
+                    tokio::net::UnixStream::connect(path).await.unwrap();
+                stream.write_all(b"ping").await.unwrap();
+            }
+        });
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(matches!(peer, Peer::Unix(Some(_))));
+        assert_eq!(peer.ip(), None);
+        client.await.unwrap();
+
+        drop(listener);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
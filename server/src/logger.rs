@@ -0,0 +1,241 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::future::Future;
+use std::time::SystemTime;
+
+use crate::cloud::DateTime;
+
+/// The format of the log messages, written to stderr.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// A line per message, with the fields of its span, e.g.
+    /// `Info: Served connection peer=10.0.0.1:5678 command=put`
+    #[default]
+    Text,
+    /// A JSON object per line, with the time, level, target and message of
+    /// the message, and the fields of its span
+    Json,
+}
+
+/// The fields of a span, in the order they were recorded.
+pub type Fields = Vec<(&'static str, Value)>;
+
+tokio::task_local! {
+    /// The fields of the span of the task, e.g. of the connection it serves,
+    /// logged with every message of the task.
+    static SPAN: RefCell<Fields>;
+}
+
+/// Runs a future in a span, every message it logs carrying the fields of the
+/// span, and those recorded by the future with [`record`].
+///
+/// # Arguments
+///
+/// * `fields` - The fields the span starts with, e.g. the peer of a
+///   connection.
+/// * `future` - The future, e.g. serving the connection.
+pub async fn in_span<F: Future>(fields: Fields, future: F) -> F::Output {
+    SPAN.scope(RefCell::new(fields), future).await
+}
+
+/// Records a field of the span of the task, e.g. the command of the request
+/// it serves, replacing the value recorded before. Outside of a span, the
+/// field is dropped.
+pub fn record(key: &'static str, value: impl Into<Value>) {
+    let value = value.into();
+    let _ = SPAN.try_with(|span| {
+        let mut span = span.borrow_mut();
+        match span.iter_mut().find(|(name, _)| *name == key) {
+            Some(field) => field.1 = value,
+            None => span.push((key, value)),
+        }
+    });
+}
+
+/// Drops fields of the span of the task, e.g. those of a request served
+/// before the next request of its connection.
+pub fn forget(keys: &[&str]) {
+    let _ = SPAN.try_with(|span| {
+        span.borrow_mut().retain(|(name, _)| !keys.contains(name));
+    });
+}
+
+/// Returns the fields of the span of the task, if any.
+pub fn fields() -> Fields {
+    SPAN.try_with(|span| span.borrow().clone())
+        .unwrap_or_default()
+}
+
+/// Logs the messages of the server to stderr.
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // The messages of the libraries, e.g. of the TLS handshakes, are
+        // only logged along with everything else
+        metadata.level() <= self.level
+            && (self.level == LevelFilter::Trace
+                || metadata.level() <= Level::Warn
+                || metadata.target().starts_with(env!("CARGO_CRATE_NAME")))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", self.format(record, &fields()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl Logger {
+    /// Formats a message with the fields of its span.
+    fn format(&self, record: &Record, fields: &Fields) -> String {
+        match self.format {
+            LogFormat::Text => {
+                let level = match record.level() {
+                    Level::Error => "Error",
+                    Level::Warn => "Warning",
+                    Level::Info => "Info",
+                    Level::Debug => "Debug",
+                    Level::Trace => "Trace",
+                };
+                let mut line = format!("{}: {}", level, record.args());
+                for (key, value) in fields {
+                    let value = match value {
+                        // Quoted if it would not read as a single value
+                        Value::String(text)
+                            if !text.is_empty()
+                                && !text.contains([' ', '"', '=']) =>
+                        {
+                            text.clone()
+                        }
+                        value => value.to_string(),
+                    };
+                    line.push_str(&format!(" {}={}", key, value));
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut line = Map::new();
+                line.insert("time".into(), timestamp(SystemTime::now()).into());
+                line.insert(
+                    "level".into(),
+                    record.level().as_str().to_lowercase().into(),
+                );
+                line.insert("target".into(), record.target().into());
+                line.insert("message".into(), record.args().to_string().into());
+                for (key, value) in fields {
+                    line.insert(key.to_string(), value.clone());
+                }
+                Value::Object(line).to_string()
+            }
+        }
+    }
+}
+
+/// Formats a time as RFC 3339, in UTC, e.g. `2024-05-01T12:30:00Z`.
+pub fn timestamp(time: SystemTime) -> String {
+    let time = DateTime::from(time);
+    let (hours, minutes, seconds) = time.time();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        time.year, time.month, time.day, hours, minutes, seconds
+    )
+}
+
+/// Parses a level of the messages logged, e.g. `info`.
+///
+/// # Errors
+///
+/// Returns an error if the level is not one of `off`, `error`, `warn`,
+/// `info`, `debug` or `trace`.
+pub fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level.parse().map_err(|_| {
+        format!(
+            "Invalid log level {}, expected one of off, error, warn, info, \
+             debug or trace",
+            level
+        )
+    })
+}
+
+/// Sets up the logger of the server.
+///
+/// # Arguments
+///
+/// * `level` - The most verbose level of the messages logged.
+/// * `format` - The format of the messages.
+///
+/// # Errors
+///
+/// Returns an error if a logger is already set up.
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<()> {
+    log::set_boxed_logger(Box::new(Logger { level, format }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_format() {
+        let logger = Logger {
+            level: LevelFilter::Info,
+            format: LogFormat::Text,
+        };
+        assert!(fields().is_empty());
+        let span = in_span(vec![("peer", "10.0.0.1:5678".into())], async {
+            record("command", "put");
+            record("bytes", 5);
+            record("outcome", "Upload not found");
+            record("bytes", 8);
+            fields()
+        })
+        .await;
+        let args = format_args!("Served connection");
+        let record = Record::builder()
+            .args(args)
+            .level(Level::Info)
+            .target("server::server")
+            .build();
+        assert_eq!(
+            logger.format(&record, &span),
+            "Info: Served connection peer=10.0.0.1:5678 command=put bytes=8 \
+             outcome=\"Upload not found\""
+        );
+
+        let logger = Logger {
+            format: LogFormat::Json,
+            ..logger
+        };
+        let line: Value =
+            serde_json::from_str(&logger.format(&record, &span)).unwrap();
+        assert_eq!(line["level"], "info");
+        assert_eq!(line["message"], "Served connection");
+        assert_eq!(line["bytes"], 8);
+        assert_eq!(line["outcome"], "Upload not found");
+
+        let metadata = |level, target| {
+            Metadata::builder().level(level).target(target).build()
+        };
+        assert!(logger.enabled(&metadata(Level::Info, "server::gc")));
+        assert!(!logger.enabled(&metadata(Level::Debug, "server::gc")));
+        assert!(!logger.enabled(&metadata(Level::Info, "rustls::server")));
+        assert!(logger.enabled(&metadata(Level::Warn, "rustls::server")));
+        assert_eq!(parse_level("debug"), Ok(LevelFilter::Debug));
+        assert!(parse_level("loud").is_err());
+    }
+}
@@ -1,16 +1,1064 @@
+use admin::AdminCommand;
 use anyhow::Result;
+use audit::AuditLog;
+use auth::Auth;
+use cache::TreeCache;
+use clap::{Parser, Subcommand};
+use config::{Config, ListenConfig, TlsConfig};
+use deadline::Timeouts;
+use identity::Identity;
+use limiter::ConnectionLimiter;
+use log::LevelFilter;
+use logger::LogFormat;
+use protocol::Limits;
+use replica::{Peer, Replicator};
+use s3::{Credentials, S3Backend, S3Config};
+use server::Bind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use systemd::ListenFd;
+use webhook::Webhooks;
 
+mod admin;
+mod audit;
+mod auth;
+#[cfg(feature = "azure")]
+mod azure;
+mod backend;
+mod backup;
+mod cache;
+mod cloud;
+mod config;
+mod deadline;
+mod error;
+mod expiry;
+mod gc;
+mod http;
+mod identity;
+mod limiter;
+mod listener;
+mod logger;
+mod replica;
+mod s3;
+mod scrub;
 mod server;
 mod store;
+mod systemd;
+mod tls;
+mod wal;
+mod webhook;
+
+/// The commands run instead of serving.
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the running server over its admin socket, see --admin-socket
+    #[command(subcommand)]
+    Admin(AdminCommand),
+    /// Export every upload of the store, with the contents of its files, the
+    /// names and expiry, to a backup archive, and exit. Not to be run while a
+    /// server writes to the same store, unless it is read-only
+    Export {
+        /// The archive to write, a tar archive compressed with zstd
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// Import the uploads of a backup archive written by `export` into the
+    /// store, building the tree of every upload again and checking its root,
+    /// and exit
+    Import {
+        /// The archive to read
+        #[arg(value_name = "FILE")]
+        input: PathBuf,
+    },
+}
+
+/// A server storing files and serving them along with their Merkle proofs
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// The address to listen on, or unix:<PATH> for a Unix socket [default:
+    /// the address of the configuration file, or 127.0.0.1:2345]
+    #[arg(env = "FILE_GUARDIAN_SERVER_ADDR")]
+    addr: Option<String>,
+    /// The TOML configuration file, whose settings the options and their
+    /// environment variables override
+    #[arg(long, value_name = "FILE", env = "FILE_GUARDIAN_SERVER_CONFIG")]
+    config: Option<PathBuf>,
+    /// The directory of the uploads, and of the files being uploaded when
+    /// the uploads are kept in S3 or Azure [default: server_store]
+    #[arg(long, value_name = "DIR", env = "FILE_GUARDIAN_SERVER_STORE_DIR")]
+    store_dir: Option<PathBuf>,
+    /// Also serve the TCP protocol on this address, e.g. [::]:2345 besides
+    /// 0.0.0.0:2345, or unix:<PATH>; may be given several times
+    #[arg(
+        long,
+        value_name = "ADDR",
+        env = "FILE_GUARDIAN_SERVER_LISTEN",
+        value_delimiter = ','
+    )]
+    listen: Vec<String>,
+    /// Also serve the HTTP API on this address, or unix:<PATH>; may be given
+    /// several times
+    #[arg(
+        long,
+        value_name = "ADDR",
+        env = "FILE_GUARDIAN_SERVER_HTTP",
+        value_delimiter = ','
+    )]
+    http: Vec<String>,
+    /// The addresses of the configuration listened on besides the others,
+    /// with their own TLS settings
+    #[arg(skip)]
+    listeners: Vec<ListenConfig>,
+    /// The permissions of the Unix sockets listened on, in octal, which the
+    /// clients need write access to
+    #[arg(long, value_name = "MODE", env = "FILE_GUARDIAN_SERVER_UNIX_SOCKET_MODE", default_value = "660", value_parser = parse_mode)]
+    unix_socket_mode: u32,
+    /// Serve the admin requests of `server admin` on this Unix socket, or
+    /// send them to it
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "FILE_GUARDIAN_SERVER_ADMIN_SOCKET",
+        global = true
+    )]
+    admin_socket: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// The PEM certificate chain to serve, to accept only TLS connections
+    #[arg(long, value_name = "FILE", env = "FILE_GUARDIAN_SERVER_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM private key of the TLS certificate
+    #[arg(long, value_name = "FILE", env = "FILE_GUARDIAN_SERVER_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// A PEM file of certificate authorities, to only accept TLS clients
+    /// presenting a certificate signed by one of them
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "FILE_GUARDIAN_SERVER_TLS_CLIENT_CA"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    /// A file of the tokens clients must authenticate with, one per line, as
+    /// `<user>:<token>` to keep the uploads of each user apart
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "FILE_GUARDIAN_SERVER_TOKENS_FILE",
+        conflicts_with = "hmac_secret_file"
+    )]
+    tokens_file: Option<PathBuf>,
+    /// A file holding the secret client tokens must be signed with, see
+    /// --issue-token
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "FILE_GUARDIAN_SERVER_HMAC_SECRET_FILE"
+    )]
+    hmac_secret_file: Option<PathBuf>,
+    /// The most verbose messages logged: off, error, warn, info, debug, or
+    /// trace to also log the messages of the libraries [default: info]
+    #[arg(long, value_name = "LEVEL", env = "FILE_GUARDIAN_SERVER_LOG_LEVEL", value_parser = logger::parse_level)]
+    log_level: Option<LevelFilter>,
+    /// The format of the messages logged to stderr [default: text]
+    #[arg(long, value_enum, env = "FILE_GUARDIAN_SERVER_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Record the uploads, downloads, proofs, deletions and failed
+    /// authentications served to this file, a JSON object per line with the
+    /// time, peer, user, command, root and outcome of each
+    #[arg(long, value_name = "FILE", env = "FILE_GUARDIAN_SERVER_AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+    /// The size the audit log is rotated at, renaming it <FILE>.1 [default:
+    /// 100MiB]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    audit_log_max_size: Option<u64>,
+    /// The number of rotated audit logs kept, the oldest being deleted
+    /// [default: 10]
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u64).range(1..))]
+    audit_log_keep: Option<u64>,
+    /// Print the token of a user, signed with the HMAC secret, and exit
+    #[arg(long, value_name = "USER")]
+    issue_token: Option<String>,
+    /// Collect the garbage of the store, print what was removed, and exit:
+    /// the files being uploaded not written to for --gc-grace, the uploads
+    /// left partial, and the contents no upload references. Not to be run
+    /// while a server stores uploads in the same store, see --gc-interval
+    #[arg(long)]
+    gc: bool,
+    /// Only print what --gc would remove
+    #[arg(long, requires = "gc")]
+    dry_run: bool,
+    /// Also collect the garbage of the store while serving, this often, e.g.
+    /// 1h [default: never]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    gc_interval: Option<Duration>,
+    /// Scrub the store, print the corrupt uploads, and exit, with an error if
+    /// there are any: hash every file of every upload again, and build the
+    /// tree of each upload again from the hashes
+    #[arg(long, conflicts_with = "gc")]
+    scrub: bool,
+    /// Also scrub the store while serving, this often, e.g. 7d [default:
+    /// never]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    scrub_interval: Option<Duration>,
+    /// Move the corrupt files found by scrubbing into their upload, as
+    /// <index>.corrupt, for the server to refuse the upload until a client
+    /// uploads it again, rather than only report them
+    #[arg(long)]
+    scrub_quarantine: bool,
+    /// Also send the uploads committed to this server, as host:port, which
+    /// verifies their root before keeping them; repeat for several peers.
+    /// The peers are to form a chain, not a cycle
+    #[arg(
+        long,
+        value_name = "ADDR",
+        env = "FILE_GUARDIAN_SERVER_REPLICATE_TO",
+        value_delimiter = ','
+    )]
+    replicate_to: Vec<String>,
+    /// A file holding the token the uploads without a user are sent to the
+    /// peers with. The uploads of the users are sent with their tokens,
+    /// signed with the HMAC secret, which the peers must share
+    #[arg(long, value_name = "FILE", requires = "replicate_to")]
+    replica_token_file: Option<PathBuf>,
+    /// Connect to the peers over TLS
+    #[arg(long, requires = "replicate_to")]
+    replica_tls: bool,
+    /// A PEM file of the certificate authorities the peers are verified
+    /// with, rather than the web ones
+    #[arg(long, value_name = "FILE", requires = "replica_tls")]
+    replica_ca: Option<PathBuf>,
+    /// How often the peers are sent the uploads they are missing, on top of
+    /// on startup
+    #[arg(long, value_name = "DURATION", default_value = "1h", value_parser = parse_duration)]
+    replica_sync_interval: Duration,
+    /// Post a JSON event to this URL whenever an upload is committed, with
+    /// its root hash, number of files, size and user; repeat for several
+    /// URLs
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "FILE_GUARDIAN_SERVER_WEBHOOKS",
+        value_delimiter = ','
+    )]
+    webhook: Vec<String>,
+    /// A file holding the secret the events are signed with, in their
+    /// X-File-Guardian-Signature header
+    #[arg(long, value_name = "FILE")]
+    webhook_secret_file: Option<PathBuf>,
+    /// The number of times an event is posted again while the URL cannot be
+    /// reached or fails
+    #[arg(long, value_name = "N", default_value_t = webhook::DEFAULT_RETRIES)]
+    webhook_retries: u32,
+    /// A PEM file of the certificate authorities the HTTPS URLs are verified
+    /// with, rather than the web ones
+    #[arg(long, value_name = "FILE")]
+    webhook_ca: Option<PathBuf>,
+    /// Sign the root hash of every upload committed with the ed25519 key of
+    /// this file, its 32-byte seed as hex, generated if the file does not
+    /// exist. The signature is sent to the clients and kept with the upload
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "FILE_GUARDIAN_SERVER_IDENTITY_KEY_FILE"
+    )]
+    identity_key_file: Option<PathBuf>,
+    /// Delete the uploads once kept for this long, e.g. 30d, unless their
+    /// client gives them another retention [default: never]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    retention: Option<Duration>,
+    /// How often the uploads are looked at, to delete those which have
+    /// expired
+    #[arg(long, value_name = "DURATION", default_value = "1m", value_parser = parse_duration)]
+    expiry_interval: Duration,
+    /// How long the files being uploaded are kept without being written to,
+    /// for their upload to be resumed, before being collected
+    #[arg(long, value_name = "DURATION", default_value = "1d", value_parser = parse_duration)]
+    gc_grace: Duration,
+    /// How long the connections being served are waited for once the server
+    /// is asked to stop with SIGINT or SIGTERM, before they are closed
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+    shutdown_timeout: Duration,
+    /// How long the TLS handshake, then the protocol handshake and the
+    /// authentication, or the head of an HTTP request, may each take
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+    handshake_timeout: Duration,
+    /// How long a connection waits for its request once authenticated,
+    /// e.g. one a client keeps ready, before it is closed
+    #[arg(long, value_name = "DURATION", default_value = "10m", value_parser = parse_duration)]
+    idle_timeout: Duration,
+    /// How long a read or write of a request may make no progress, e.g.
+    /// with a client that stopped reading, before the connection is closed
+    #[arg(long, value_name = "DURATION", default_value = "1m", value_parser = parse_duration)]
+    io_timeout: Duration,
+    /// Start read-only, refusing the uploads and deletions while serving the
+    /// downloads, e.g. during a migration. SIGUSR1 makes the server
+    /// read-only while it runs, and SIGUSR2 writable again
+    #[arg(long, env = "FILE_GUARDIAN_SERVER_READ_ONLY")]
+    read_only: bool,
+    /// The largest file accepted, e.g. 4GiB or 500MB [default: 4GiB]
+    #[arg(long, value_name = "SIZE", env = "FILE_GUARDIAN_SERVER_MAX_FILE_SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+    /// The most files accepted in an upload [default: 100000]
+    #[arg(long, value_name = "COUNT", env = "FILE_GUARDIAN_SERVER_MAX_FILES")]
+    max_files: Option<u64>,
+    /// The largest total size of the files of an upload accepted [default:
+    /// 16GiB]
+    #[arg(long, value_name = "SIZE", env = "FILE_GUARDIAN_SERVER_MAX_UPLOAD_SIZE", value_parser = parse_size)]
+    max_upload_size: Option<u64>,
+    /// The most bytes the uploads of each user may hold, e.g. 10GiB
+    /// [default: unlimited]
+    #[arg(long, value_name = "SIZE", env = "FILE_GUARDIAN_SERVER_QUOTA", value_parser = parse_size)]
+    quota: Option<u64>,
+    /// The most connections from the same IP served at once [default:
+    /// unlimited]
+    #[arg(long, value_name = "COUNT", env = "FILE_GUARDIAN_SERVER_MAX_CONNECTIONS_PER_IP", value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections_per_ip: Option<u64>,
+    /// The most connections served at once, from every IP, the others
+    /// waiting to be accepted [default: unlimited]
+    #[arg(long, value_name = "COUNT", env = "FILE_GUARDIAN_SERVER_MAX_CONNECTIONS", value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: Option<u64>,
+    /// The most connections per second accepted from the same IP, as many
+    /// being accepted at once [default: unlimited]
+    #[arg(long, value_name = "PER_SECOND", env = "FILE_GUARDIAN_SERVER_CONNECTION_RATE", value_parser = clap::value_parser!(u32).range(1..))]
+    connection_rate: Option<u32>,
+    /// The zstd level the contents of files are compressed with in transit,
+    /// from 1 to 22, for the clients asking for it, 0 not to compress them
+    /// [default: 3]
+    #[arg(long, value_name = "LEVEL", env = "FILE_GUARDIAN_SERVER_WIRE_COMPRESSION_LEVEL", value_parser = clap::value_parser!(i32).range(0..=22))]
+    wire_compression_level: Option<i32>,
+    /// The most trees of uploads kept in memory, 0 to read them from the
+    /// disk for every download
+    #[arg(long, value_name = "COUNT", default_value_t = server::DEFAULT_CACHED_TREES)]
+    tree_cache_trees: usize,
+    /// The most bytes of trees of uploads kept in memory
+    #[arg(long, value_name = "SIZE", default_value = "64MiB", value_parser = parse_size)]
+    tree_cache_size: u64,
+    /// Keep the uploads in this bucket of an S3 compatible storage, signing
+    /// the requests with the credentials of AWS_ACCESS_KEY_ID,
+    /// AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN, rather than on the local
+    /// disk
+    #[arg(long, value_name = "BUCKET")]
+    s3_bucket: Option<String>,
+    /// The prefix of the keys of the uploads in the bucket
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "",
+        requires = "s3_bucket"
+    )]
+    s3_prefix: String,
+    /// The region of the bucket
+    #[arg(
+        long,
+        value_name = "REGION",
+        default_value = "us-east-1",
+        requires = "s3_bucket"
+    )]
+    s3_region: String,
+    /// The URL of the S3 API, e.g. http://localhost:9000 for a compatible storage
+    /// [default: https://s3.<REGION>.amazonaws.com]
+    #[arg(long, value_name = "URL", requires = "s3_bucket")]
+    s3_endpoint: Option<String>,
+    /// The size of the parts of the files stored with multipart uploads, those
+    /// larger than a part, at least 5MiB
+    #[arg(long, value_name = "SIZE", default_value = "16MiB", value_parser = parse_size, requires = "s3_bucket")]
+    s3_part_size: u64,
+    /// The number of times a failed request to S3 is retried
+    #[arg(long, value_name = "N", default_value_t = 3, requires = "s3_bucket")]
+    s3_retries: u32,
+    /// A PEM file of the certificate authorities the S3 endpoint is verified
+    /// with, instead of the Mozilla root certificates
+    #[arg(long, value_name = "FILE", requires = "s3_bucket")]
+    s3_ca: Option<PathBuf>,
+    /// Keep the uploads in a container of this Azure Storage account,
+    /// authorizing the requests with the key of AZURE_STORAGE_KEY or the
+    /// shared access signature of AZURE_STORAGE_SAS_TOKEN, rather than on the
+    /// local disk
+    #[cfg(feature = "azure")]
+    #[arg(
+        long,
+        value_name = "ACCOUNT",
+        requires = "azure_container",
+        conflicts_with = "s3_bucket"
+    )]
+    azure_account: Option<String>,
+    /// The container of the uploads in the account
+    #[cfg(feature = "azure")]
+    #[arg(long, value_name = "CONTAINER", requires = "azure_account")]
+    azure_container: Option<String>,
+    /// The prefix of the names of the blobs of the uploads in the container
+    #[cfg(feature = "azure")]
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "",
+        requires = "azure_account"
+    )]
+    azure_prefix: String,
+    /// The URL of the Blob service, e.g.
+    /// http://127.0.0.1:10000/devstoreaccount1 for the emulator [default:
+    /// https://<ACCOUNT>.blob.core.windows.net]
+    #[cfg(feature = "azure")]
+    #[arg(long, value_name = "URL", requires = "azure_account")]
+    azure_endpoint: Option<String>,
+    /// The size of the blocks of the files stored block by block, those larger
+    /// than a block
+    #[cfg(feature = "azure")]
+    #[arg(long, value_name = "SIZE", default_value = "16MiB", value_parser = parse_size, requires = "azure_account")]
+    azure_block_size: u64,
+    /// The number of times a failed request to Azure is retried
+    #[cfg(feature = "azure")]
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        requires = "azure_account"
+    )]
+    azure_retries: u32,
+    /// A PEM file of the certificate authorities the Azure endpoint is
+    /// verified with, instead of the Mozilla root certificates
+    #[cfg(feature = "azure")]
+    #[arg(long, value_name = "FILE", requires = "azure_account")]
+    azure_ca: Option<PathBuf>,
+}
+
+/// The address listened on when neither the command line nor the
+/// configuration gives one.
+const DEFAULT_ADDR: &str = "127.0.0.1:2345";
+
+impl Args {
+    /// Takes the settings not given on the command line nor in the
+    /// environment from the configuration.
+    fn merge(&mut self, config: Config) {
+        let Config {
+            address,
+            http,
+            store_dir,
+            admin_socket,
+            read_only,
+            wire_compression_level,
+            limits,
+            tls,
+            auth,
+            log,
+            audit,
+            webhooks,
+            listen,
+        } = config;
+        self.addr = self.addr.take().or(address);
+        if self.http.is_empty() {
+            self.http.extend(http);
+        }
+        self.listeners = listen;
+        self.store_dir = self.store_dir.take().or(store_dir);
+        self.admin_socket = self.admin_socket.take().or(admin_socket);
+        self.read_only |= read_only.unwrap_or(false);
+        self.wire_compression_level =
+            self.wire_compression_level.or(wire_compression_level);
+        self.max_file_size = self.max_file_size.or(limits.max_file_size);
+        self.max_files = self.max_files.or(limits.max_files);
+        self.max_upload_size = self.max_upload_size.or(limits.max_upload_size);
+        self.quota = self.quota.or(limits.quota);
+        self.max_connections_per_ip = self
+            .max_connections_per_ip
+            .or(limits.max_connections_per_ip);
+        self.max_connections = self.max_connections.or(limits.max_connections);
+        self.connection_rate = self.connection_rate.or(limits.connection_rate);
+        self.tls_cert = self.tls_cert.take().or(tls.cert);
+        self.tls_key = self.tls_key.take().or(tls.key);
+        self.tls_client_ca = self.tls_client_ca.take().or(tls.client_ca);
+        self.log_level = self.log_level.or(log.level);
+        self.log_format = self.log_format.or(log.format);
+        self.audit_log = self.audit_log.take().or(audit.file);
+        self.audit_log_max_size = self.audit_log_max_size.or(audit.max_size);
+        self.audit_log_keep = self.audit_log_keep.or(audit.keep);
+        if self.webhook.is_empty() {
+            self.webhook = webhooks.urls;
+        }
+        self.webhook_secret_file =
+            self.webhook_secret_file.take().or(webhooks.secret_file);
+        // A user given tokens on the command line does not mean to also
+        // accept those signed with the secret of the configuration
+        if self.tokens_file.is_none() && self.hmac_secret_file.is_none() {
+            self.tokens_file = auth.tokens_file;
+            self.hmac_secret_file = auth.hmac_secret_file;
+        }
+    }
+
+    /// Checks the settings which depend on each other, wherever they were
+    /// given.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the settings are inconsistent, e.g. a TLS
+    /// certificate without its key.
+    fn check(&self) -> Result<()> {
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(anyhow::anyhow!(
+                "A TLS certificate and its key must be given together"
+            ));
+        }
+        if self.tls_client_ca.is_some() && self.tls_cert.is_none() {
+            return Err(anyhow::anyhow!(
+                "Client certificates are only accepted over TLS, which \
+                 requires a certificate"
+            ));
+        }
+        for listener in &self.listeners {
+            match &listener.tls {
+                Some(_) if listener.plain => {
+                    return Err(anyhow::anyhow!(
+                        "{} is either plain or over TLS, not both",
+                        listener.address
+                    ));
+                }
+                Some(TlsConfig { cert, key, .. })
+                    if cert.is_none() || key.is_none() =>
+                {
+                    return Err(anyhow::anyhow!(
+                        "The TLS certificate of {} and its key must be given \
+                         together",
+                        listener.address
+                    ));
+                }
+                _ => (),
+            }
+        }
+        if self.tokens_file.is_some() && self.hmac_secret_file.is_some() {
+            return Err(anyhow::anyhow!(
+                "Tokens are validated with either a tokens file or an HMAC \
+                 secret, not both"
+            ));
+        }
+        if self.max_connections_per_ip == Some(0)
+            || self.max_connections == Some(0)
+            || self.connection_rate == Some(0)
+        {
+            return Err(anyhow::anyhow!(
+                "The limits of the connections must be at least 1"
+            ));
+        }
+        if self.audit_log_max_size == Some(0) || self.audit_log_keep == Some(0)
+        {
+            return Err(anyhow::anyhow!(
+                "The audit log is rotated at 1 byte at least, keeping 1 \
+                 rotated log at least"
+            ));
+        }
+        if let Some(level) = self.wire_compression_level {
+            if !(0..=22).contains(&level) {
+                return Err(anyhow::anyhow!(
+                    "Invalid wire compression level {}, from 0 to 22",
+                    level
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the addresses to listen on: the address, or the default one when
+/// no other is given, then those of `--listen`, `--http`, the configuration
+/// and the sockets passed by systemd.
+///
+/// # Errors
+///
+/// Returns an error if the TLS settings of an address of the configuration
+/// cannot be read.
+fn listeners(args: &Args, activated: &[ListenFd]) -> Result<Vec<Bind>> {
+    let mut binds = vec![];
+    let others = !args.listen.is_empty()
+        || !args.listeners.is_empty()
+        || !activated.is_empty();
+    match &args.addr {
+        Some(address) => binds.push(Bind::tcp(address)),
+        None if !others => binds.push(Bind::tcp(DEFAULT_ADDR)),
+        None => (),
+    }
+    binds.extend(args.listen.iter().map(|address| Bind::tcp(address)));
+    binds.extend(args.http.iter().map(|address| Bind::http(address)));
+    for listener in &args.listeners {
+        let tls = match &listener.tls {
+            Some(TlsConfig {
+                cert: Some(cert),
+                key: Some(key),
+                client_ca,
+            }) => Some(Some(tls::acceptor(cert, key, client_ca.as_deref())?)),
+            _ if listener.plain => Some(None),
+            _ => None,
+        };
+        binds.push(Bind {
+            address: listener.address.clone(),
+            http: listener.http,
+            tls,
+        });
+    }
+    for socket in activated {
+        binds.push(match socket.name.as_str() {
+            systemd::HTTP_NAME => Bind::http(&socket.address()),
+            _ => Bind::tcp(&socket.address()),
+        });
+    }
+    Ok(binds)
+}
+
+/// Parses a size, e.g. `500MB`, `4GiB` or `1000000`, in bytes. Units are
+/// decimal (`KB`, `MB`, `GB`) or binary (`KiB`, `MiB`, `GiB`), and case
+/// insensitive.
+///
+/// # Errors
+///
+/// Returns an error if the size is not a positive number of bytes.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid size {}, expected e.g. 500MB", size);
+    let lowercase = size.trim().to_lowercase();
+    let split = lowercase
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(lowercase.len());
+    let (number, unit) = lowercase.split_at(split);
+    let number = number.parse::<f64>().map_err(|_| invalid())?;
+    let multiplier = match unit.trim() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "ki" | "kib" => 1 << 10,
+        "mi" | "mib" => 1 << 20,
+        "gi" | "gib" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    match (number * multiplier as f64) as u64 {
+        0 => Err(invalid()),
+        size => Ok(size),
+    }
+}
+
+/// Parses a duration, e.g. `90s`, `15m`, `1h` or `7d`, a number without a
+/// unit being seconds.
+///
+/// # Errors
+///
+/// Returns an error if the duration is not a positive whole number of
+/// seconds, minutes, hours or days.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration {}, expected e.g. 1h", duration);
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    match number.checked_mul(multiplier) {
+        Some(0) | None => Err(invalid()),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+    }
+}
+
+/// Parses permissions in octal, e.g. `660` or `0o660`.
+///
+/// # Errors
+///
+/// Returns an error if the permissions are not in octal, or not only
+/// permissions.
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    let invalid = || format!("Invalid mode {}, expected e.g. 660", mode);
+    let digits = mode.trim().trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(invalid()),
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Read the addr from the command line
-    let addr = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:2345".to_string());
+    let mut args = Args::parse();
+    if let Some(path) = &args.config {
+        let config = Config::load(path)?;
+        args.merge(config);
+    }
+    let command = args.command.take();
+    if let Some(Command::Admin(command)) = command {
+        let socket = args.admin_socket.ok_or(anyhow::anyhow!(
+            "The admin socket is given with --admin-socket or in the \
+             configuration"
+        ))?;
+        return admin::run(&socket, command).await;
+    }
+    args.check()?;
+    logger::init(
+        args.log_level.unwrap_or(LevelFilter::Info),
+        args.log_format.unwrap_or_default(),
+    )?;
+    let auth = match (&args.tokens_file, &args.hmac_secret_file) {
+        (Some(tokens), _) => Some(Auth::from_tokens_file(tokens)?),
+        (_, Some(secret)) => Some(Auth::from_hmac_secret_file(secret)?),
+        _ => None,
+    }
+    .map(Arc::new);
+    if let Some(id) = &args.issue_token {
+        let auth = auth.ok_or(anyhow::anyhow!(
+            "Issuing a token requires an HMAC secret, given with \
+             --hmac-secret-file or in the configuration"
+        ))?;
+        println!("{}", auth.issue(id)?);
+        return Ok(());
+    }
 
-    let tcp_server = server::Server::new(&addr);
+    let defaults = Limits::default();
+    let activated = systemd::listen_fds()?;
+    let mut tcp_server = server::Server::new(listeners(&args, &activated)?)
+        .with_limits(Limits {
+            max_file_size: args.max_file_size.unwrap_or(defaults.max_file_size),
+            max_files: args.max_files.unwrap_or(defaults.max_files),
+            max_upload_size: args
+                .max_upload_size
+                .unwrap_or(defaults.max_upload_size),
+        })
+        .with_tree_cache(TreeCache::new(
+            args.tree_cache_trees,
+            args.tree_cache_size,
+        ))
+        .with_gc_grace(args.gc_grace)
+        .with_expiry_interval(args.expiry_interval)
+        .with_shutdown_timeout(args.shutdown_timeout)
+        .with_timeouts(Timeouts {
+            handshake: args.handshake_timeout,
+            idle: args.idle_timeout,
+            io: args.io_timeout,
+        });
+    if let Some(path) = &args.audit_log {
+        tcp_server = tcp_server.with_audit_log(AuditLog::open(
+            path,
+            args.audit_log_max_size.unwrap_or(audit::DEFAULT_MAX_SIZE),
+            args.audit_log_keep
+                .map_or(audit::DEFAULT_KEEP, |n| n as usize),
+        )?);
+    }
+    if let Some(max) = args.max_connections {
+        tcp_server = tcp_server.with_max_connections(max as usize);
+    }
+    if let Some(level) = args.wire_compression_level {
+        tcp_server =
+            tcp_server.with_wire_compression(Some(level).filter(|&l| l > 0));
+    }
+    if args.read_only {
+        tcp_server = tcp_server.with_read_only();
+    }
+    if let Some(retention) = args.retention {
+        tcp_server = tcp_server.with_retention(retention);
+    }
+    if let Some(interval) = args.gc_interval {
+        tcp_server = tcp_server.with_gc(interval);
+    }
+    if let Some(interval) = args.scrub_interval {
+        tcp_server = tcp_server.with_scrub(interval);
+    }
+    if args.scrub_quarantine {
+        tcp_server = tcp_server.with_scrub_quarantine();
+    }
+    if args.max_connections_per_ip.is_some() || args.connection_rate.is_some() {
+        tcp_server =
+            tcp_server.with_connection_limiter(ConnectionLimiter::new(
+                args.max_connections_per_ip.map(|max| max as usize),
+                args.connection_rate,
+            ));
+    }
+    if let Some(quota) = args.quota {
+        tcp_server = tcp_server.with_quota(quota);
+    }
+    if !args.replicate_to.is_empty() {
+        let mut peers = vec![];
+        for address in &args.replicate_to {
+            let mut peer = Peer::new(address);
+            if args.replica_tls {
+                peer = peer.with_tls(args.replica_ca.as_deref())?;
+            }
+            peers.push(peer);
+        }
+        let token = match &args.replica_token_file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .map_err(|e| {
+                        anyhow::anyhow!(
+                            "Could not read token {}: {}",
+                            path.display(),
+                            e
+                        )
+                    })?
+                    .trim()
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let replicator = Replicator::new(peers, token, auth.clone())
+            .with_sync_interval(args.replica_sync_interval);
+        tcp_server = tcp_server.with_replicator(replicator);
+    }
+    if !args.webhook.is_empty() {
+        let ca = args.webhook_ca.as_deref();
+        let mut webhooks =
+            Webhooks::new(&args.webhook, ca, args.webhook_retries)?;
+        if let Some(path) = &args.webhook_secret_file {
+            let secret = std::fs::read_to_string(path).map_err(|e| {
+                anyhow::anyhow!(
+                    "Could not read webhook secret {}: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+            webhooks = webhooks.with_secret(secret.trim().as_bytes());
+        }
+        tcp_server = tcp_server.with_webhooks(webhooks);
+    }
+    if let Some(path) = &args.identity_key_file {
+        tcp_server = tcp_server.with_identity(Identity::load(path)?);
+    }
+    if let Some(bucket) = args.s3_bucket {
+        let config = S3Config {
+            endpoint: args.s3_endpoint.unwrap_or_else(|| {
+                format!("https://s3.{}.amazonaws.com", args.s3_region)
+            }),
+            region: args.s3_region,
+            bucket,
+            prefix: args.s3_prefix,
+            part_size: args.s3_part_size,
+            retries: args.s3_retries,
+            ca: args.s3_ca,
+        };
+        tcp_server = tcp_server
+            .with_backend(S3Backend::new(config, Credentials::from_env()?)?);
+    }
+    #[cfg(feature = "azure")]
+    if let (Some(account), Some(container)) =
+        (args.azure_account, args.azure_container)
+    {
+        let config = azure::AzureConfig {
+            endpoint: args.azure_endpoint.unwrap_or_else(|| {
+                format!("https://{}.blob.core.windows.net", account)
+            }),
+            account,
+            container,
+            prefix: args.azure_prefix,
+            block_size: args.azure_block_size,
+            retries: args.azure_retries,
+            ca: args.azure_ca,
+        };
+        let credentials = azure::AzureCredentials::from_env()?;
+        tcp_server = tcp_server
+            .with_backend(azure::AzureBackend::new(config, credentials)?);
+    }
+    tcp_server = tcp_server.with_unix_socket_mode(args.unix_socket_mode);
+    if let Some(auth) = auth {
+        tcp_server = tcp_server.with_auth(auth);
+    }
+    if let Some(path) = &args.tokens_file {
+        tcp_server = tcp_server.with_tokens_file(path);
+    }
+    if let Some(path) = &args.admin_socket {
+        tcp_server = tcp_server.with_admin_socket(path);
+    }
+    if let Some(dir) = &args.store_dir {
+        tcp_server = tcp_server.with_store_dir(dir);
+    }
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        tcp_server = tcp_server.with_tls(tls::acceptor(
+            cert,
+            key,
+            args.tls_client_ca.as_deref(),
+        )?);
+    }
+    match command {
+        Some(Command::Export { output }) => {
+            println!("{}", tcp_server.export(&output).await?);
+            return Ok(());
+        }
+        Some(Command::Import { input }) => {
+            println!("{}", tcp_server.import(&input).await?);
+            return Ok(());
+        }
+        _ => {}
+    }
+    if args.gc {
+        let report = tcp_server.collect_garbage(args.dry_run).await?;
+        for line in report.lines() {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    if args.scrub {
+        let report = tcp_server.scrub().await?;
+        for line in report.lines() {
+            println!("{}", line);
+        }
+        if !report.is_clean() {
+            anyhow::bail!("{} uploads are corrupt", report.corrupt.len());
+        }
+        return Ok(());
+    }
     tcp_server.run().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4GiB"), Ok(4 << 30));
+        assert_eq!(parse_size("500 MB"), Ok(500_000_000));
+        assert_eq!(parse_size("1.5k"), Ok(1500));
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("big").is_err());
+        assert_eq!(
+            parse_size("16GiB").unwrap(),
+            Limits::default().max_upload_size
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let config = Config::parse(
+            r#"
+            address = "0.0.0.0:2345"
+            store_dir = "/var/lib/file-guardian"
+
+            [limits]
+            max_file_size = "1GiB"
+            quota = "10GiB"
+
+            [auth]
+            hmac_secret_file = "secret"
+            "#,
+        )
+        .unwrap();
+        let mut args = Args::parse_from([
+            "server",
+            "127.0.0.1:2346",
+            "--quota",
+            "1GiB",
+            "--tokens-file",
+            "tokens",
+        ]);
+        args.merge(config);
+        assert!(args.check().is_ok());
+        // The command line overrides the configuration, which overrides the
+        // defaults
+        assert_eq!(args.addr.as_deref(), Some("127.0.0.1:2346"));
+        assert_eq!(args.quota, Some(1 << 30));
+        assert_eq!(args.max_file_size, Some(1 << 30));
+        assert_eq!(args.max_upload_size, None);
+        assert_eq!(
+            args.store_dir,
+            Some(PathBuf::from("/var/lib/file-guardian"))
+        );
+        assert_eq!(args.tokens_file, Some(PathBuf::from("tokens")));
+        assert_eq!(args.hmac_secret_file, None);
+
+        let mut args = Args::parse_from(["server", "--tls-cert", "cert.pem"]);
+        assert!(args.check().is_err());
+        args.merge(Config::parse("[tls]\nkey = \"key.pem\"").unwrap());
+        assert!(args.check().is_ok());
+    }
+
+    #[test]
+    fn test_listeners() {
+        let addresses = |args: &Args| {
+            let binds = listeners(args, &[]).unwrap();
+            binds
+                .into_iter()
+                .map(|bind| (bind.address, bind.http, bind.tls.is_some()))
+                .collect::<Vec<_>>()
+        };
+        let args = Args::parse_from(["server", "--http", "0.0.0.0:8080"]);
+        assert_eq!(
+            addresses(&args),
+            [
+                (DEFAULT_ADDR.to_string(), false, false),
+                ("0.0.0.0:8080".to_string(), true, false)
+            ]
+        );
+
+        // The default address is only listened on without another one
+        let config = Config::parse(
+            r#"
+            [[listen]]
+            address = "[::]:2345"
+
+            [[listen]]
+            address = "127.0.0.1:2346"
+            plain = true
+            "#,
+        )
+        .unwrap();
+        let mut args = Args::parse_from(["server", "--listen", "0.0.0.0:2345"]);
+        args.merge(config);
+        assert!(args.check().is_ok());
+        assert_eq!(
+            addresses(&args),
+            [
+                ("0.0.0.0:2345".to_string(), false, false),
+                ("[::]:2345".to_string(), false, false),
+                ("127.0.0.1:2346".to_string(), false, true)
+            ]
+        );
+
+        let config = "[[listen]]\naddress = \"[::]:2345\"\nplain = true\n\
+                      tls = { cert = \"cert.pem\", key = \"key.pem\" }";
+        let mut args = Args::parse_from(["server"]);
+        args.merge(Config::parse(config).unwrap());
+        assert!(args.check().is_err());
+
+        // The sockets systemd passes replace the default address
+        let activated = [
+            ListenFd {
+                fd: 3,
+                name: "server.socket".to_string(),
+            },
+            ListenFd {
+                fd: 4,
+                name: systemd::HTTP_NAME.to_string(),
+            },
+        ];
+        let binds = listeners(&Args::parse_from(["server"]), &activated);
+        let binds = binds
+            .unwrap()
+            .into_iter()
+            .map(|bind| (bind.address, bind.http))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            binds,
+            [("fd:3".to_string(), false), ("fd:4".to_string(), true)]
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("1d"), Ok(gc::DEFAULT_GRACE));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("1w").is_err());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert_eq!(parse_mode("0777"), Ok(0o777));
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("680").is_err());
+        assert!(parse_mode("").is_err());
+    }
+}
@@ -0,0 +1,633 @@
+use anyhow::{anyhow, Result};
+use protocol::compression::{self, FrameWriter};
+use protocol::{Capabilities, Request, Response, MIN_VERSION, VERSION};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig};
+use tokio_rustls::TlsConnector;
+
+use crate::auth::Auth;
+use crate::store::FileStore;
+
+/// How often the uploads the peers are missing are sent by default.
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How many times an upload is sent to a peer before it is left to the next
+/// sync.
+const ATTEMPTS: u32 = 3;
+
+/// How long to wait before sending an upload to a peer again, doubled on
+/// every attempt.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A connection to a peer, over TCP or TLS.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// A server the uploads are replicated to.
+pub struct Peer {
+    /// The address of its TCP protocol.
+    address: String,
+    /// The TLS settings it is connected to with, if over TLS.
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl Peer {
+    /// Creates a peer connected to over TCP.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address of the peer, as `host:port`.
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            tls: None,
+        }
+    }
+
+    /// Connects to the peer over TLS, verifying its certificate with the
+    /// given certificate authorities rather than the web ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the CA file cannot be read.
+    pub fn with_tls(mut self, ca: Option<&Path>) -> Result<Self> {
+        self.tls = Some(crate::cloud::tls_config(ca)?);
+        Ok(self)
+    }
+
+    /// Opens a connection to the peer, for a single request, performing the
+    /// handshake and authenticating with `token`.
+    ///
+    /// # Returns
+    ///
+    /// The connection, and the capabilities of the peer, the contents of
+    /// files being compressed in transit over it if the peer offers it.
+    async fn connect(
+        &self,
+        token: &str,
+    ) -> Result<(Box<dyn Stream>, Capabilities)> {
+        let socket = TcpStream::connect(&self.address).await?;
+        let mut stream: Box<dyn Stream> = match &self.tls {
+            Some(config) => {
+                let host = match self.address.rsplit_once(':') {
+                    Some((host, _)) => host.trim_matches(['[', ']']),
+                    None => &self.address,
+                };
+                let name = ServerName::try_from(host.to_string())
+                    .map_err(|_| anyhow!("Invalid host {}", host))?;
+                let connector = TlsConnector::from(config.clone());
+                Box::new(connector.connect(name, socket).await?)
+            }
+            None => Box::new(socket),
+        };
+        let hello = Request::Hello {
+            version: VERSION,
+            capabilities: Capabilities::CHUNKING
+                .union(Capabilities::COMPRESSION)
+                .union(Capabilities::CONTENTS),
+        };
+        let capabilities = match exchange(&mut stream, hello).await? {
+            Response::Hello {
+                version,
+                capabilities,
+            } if (MIN_VERSION..=VERSION).contains(&version) => capabilities,
+            response => return Err(unexpected(response)),
+        };
+        let auth = Request::Auth {
+            token: token.to_string(),
+        };
+        match exchange(&mut stream, auth).await? {
+            Response::Ok => Ok((stream, capabilities)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sends a request to the peer over a connection of its own.
+    async fn request(&self, token: &str, request: Request) -> Result<Response> {
+        let (mut stream, _) = self.connect(token).await?;
+        exchange(&mut stream, request).await
+    }
+
+    /// Sends an upload to the peer, unless it holds it already, along with
+    /// the names of its files and its expiry. The peer stores the files in
+    /// order and builds the tree of the upload from them, the replication
+    /// only succeeding if the root it commits is the root of the upload.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store holding the upload.
+    /// * `root_hash` - The root hash of the upload.
+    /// * `token` - The token of the user of the store on the peer.
+    async fn replicate(
+        &self,
+        store: &FileStore,
+        root_hash: &str,
+        token: &str,
+    ) -> Result<()> {
+        let exists = Request::Exists {
+            root_hash: root_hash.to_string(),
+        };
+        let exists = match self.request(token, exists).await? {
+            Response::Exists { exists } => exists,
+            response => return Err(unexpected(response)),
+        };
+        if !exists {
+            self.send_files(store, root_hash, token).await?;
+        }
+
+        if let Some(names) = store.names(root_hash).await? {
+            let names = Request::Names {
+                root_hash: root_hash.to_string(),
+                names,
+            };
+            match self.request(token, names).await? {
+                Response::Ok => {}
+                response => return Err(unexpected(response)),
+            }
+        }
+        if let Some(expiry) = store.expiry(root_hash).await? {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            let retain = Request::Retain {
+                root_hash: root_hash.to_string(),
+                secs: expiry.saturating_sub(now),
+            };
+            match self.request(token, retain).await? {
+                Response::Ok => {}
+                response => return Err(unexpected(response)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Stages the files of an upload on the peer, but those it already holds
+    /// the content of, and commits them.
+    async fn send_files(
+        &self,
+        store: &FileStore,
+        root_hash: &str,
+        token: &str,
+    ) -> Result<()> {
+        // The same session for every attempt, for the peer to resume the
+        // files it received before
+        let session =
+            Sha256::digest(format!("replica:{}", root_hash).as_bytes());
+        let session = hex::encode(session);
+        let tree = store.get_tree(root_hash).await?;
+        let hashes = tree.leaves().iter().map(hex::encode).collect::<Vec<_>>();
+        let held = self.held(token, &hashes).await?;
+        for (index, leaf) in tree.leaves().iter().enumerate() {
+            if held.get(index) == Some(&true) {
+                continue;
+            }
+            let size = store.file_size(root_hash, index).await?;
+            let (mut stream, capabilities) = self.connect(token).await?;
+            let compressed = capabilities.contains(Capabilities::COMPRESSION);
+            let put = Request::Put {
+                session: session.clone(),
+                index,
+                size,
+            };
+            let offset = match exchange(&mut stream, put).await? {
+                Response::Resume { offset } if offset <= size => offset,
+                response => return Err(unexpected(response)),
+            };
+            let range = Some(offset..size);
+            if offset < size && compressed {
+                let level = compression::DEFAULT_LEVEL;
+                let mut frames = FrameWriter::new(&mut stream, level);
+                store
+                    .copy_file(root_hash, index, range, &mut frames)
+                    .await?;
+                frames.flush().await?;
+            } else if offset < size {
+                store
+                    .copy_file(root_hash, index, range, &mut stream)
+                    .await?;
+                stream.flush().await?;
+            }
+            let hash = match read_response(&mut stream).await? {
+                Response::Staged { hash } => hash,
+                response => return Err(unexpected(response)),
+            };
+            if hash != hex::encode(leaf) {
+                return Err(anyhow!(
+                    "The peer staged file {} with another hash, {}",
+                    index,
+                    hash
+                ));
+            }
+        }
+
+        match self.request(token, Request::Commit { hashes }).await? {
+            Response::Committed { root_hash: root }
+                if root.as_str() == root_hash =>
+            {
+                Ok(())
+            }
+            Response::Committed { root_hash: root } => Err(anyhow!(
+                "The peer committed the files as {} rather than {}",
+                root,
+                root_hash
+            )),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Returns which files of an upload the peer already holds the content
+    /// of, none if the peer is too old to tell.
+    async fn held(&self, token: &str, hashes: &[String]) -> Result<Vec<bool>> {
+        let (mut stream, capabilities) = self.connect(token).await?;
+        if !capabilities.contains(Capabilities::CONTENTS) {
+            return Ok(vec![]);
+        }
+        let have = Request::Have {
+            hashes: hashes.to_vec(),
+        };
+        match exchange(&mut stream, have).await? {
+            Response::Held { held } if held.len() == hashes.len() => Ok(held),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Returns the root hashes of the uploads the peer holds for a user.
+    async fn roots(&self, token: &str) -> Result<HashSet<String>> {
+        match self.request(token, Request::Roots).await? {
+            Response::Uploads { uploads } => {
+                Ok(uploads.into_iter().map(|upload| upload.root_hash).collect())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// Sends a request over a connection, and reads the response.
+async fn exchange(
+    stream: &mut Box<dyn Stream>,
+    request: Request,
+) -> Result<Response> {
+    stream.write_all(&request.encode()?).await?;
+    stream.flush().await?;
+    read_response(stream).await
+}
+
+/// Reads a response, a [`Response::Error`] failing with its message.
+async fn read_response(stream: &mut Box<dyn Stream>) -> Result<Response> {
+    match Response::read(stream).await? {
+        Response::Error { kind, message } => {
+            Err(anyhow!("The peer failed ({}): {}", kind.name(), message))
+        }
+        response => Ok(response),
+    }
+}
+
+/// Returns the error of a response the peer should not have sent.
+fn unexpected(response: Response) -> anyhow::Error {
+    anyhow!("Unexpected response from the peer: {:?}", response)
+}
+
+/// An upload to replicate: the namespace of its store, if any, and its root
+/// hash.
+type Job = (Option<String>, String);
+
+/// Replicates the uploads of a store to peer servers, so that losing the
+/// disk of the server does not lose the uploads whose originals the clients
+/// deleted.
+///
+/// Every upload committed is sent to each peer in the background, along
+/// with the names of its files and its expiry, recorded later. The uploads
+/// a peer missed, e.g. while it was down, are sent by a sync on startup and
+/// every sync interval.
+pub struct Replicator {
+    /// The peers the uploads are sent to.
+    peers: Vec<Peer>,
+    /// The token the uploads of the store are sent with, not those of its
+    /// namespaces, empty if the peers require none.
+    token: String,
+    /// How the tokens of the users are issued, with the HMAC secret which
+    /// the peers share, for the uploads of each user to be kept apart on
+    /// the peers too.
+    auth: Option<Arc<Auth>>,
+    /// How often the uploads the peers are missing are sent.
+    sync_interval: Duration,
+    queue: mpsc::UnboundedSender<Job>,
+    /// The end of the queue, until the replicator runs.
+    jobs: Mutex<Option<mpsc::UnboundedReceiver<Job>>>,
+}
+
+impl Replicator {
+    /// Creates a replicator to the given peers, syncing them every hour.
+    ///
+    /// # Arguments
+    ///
+    /// * `peers` - The peers.
+    /// * `token` - The token the uploads without a user are sent with, if
+    ///   the peers require one.
+    /// * `auth` - How the server authenticates clients, the tokens of the
+    ///   users being issued with it when it has an HMAC secret.
+    pub fn new(
+        peers: Vec<Peer>,
+        token: Option<String>,
+        auth: Option<Arc<Auth>>,
+    ) -> Self {
+        let (queue, jobs) = mpsc::unbounded_channel();
+        Self {
+            peers,
+            token: token.unwrap_or_default(),
+            auth,
+            sync_interval: DEFAULT_SYNC_INTERVAL,
+            queue,
+            jobs: Mutex::new(Some(jobs)),
+        }
+    }
+
+    /// Sends the uploads the peers are missing every `interval` rather than
+    /// every hour.
+    pub fn with_sync_interval(mut self, interval: Duration) -> Self {
+        self.sync_interval = interval;
+        self
+    }
+
+    /// Queues an upload to be sent to the peers, e.g. once committed.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace of the store holding the upload.
+    /// * `root_hash` - The root hash of the upload.
+    pub fn push(&self, namespace: Option<&str>, root_hash: &str) {
+        let job = (namespace.map(str::to_string), root_hash.to_string());
+        // Closed once the server stops, the sync sending it on restart
+        let _ = self.queue.send(job);
+    }
+
+    /// Returns the token the uploads of a namespace are sent with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server has no HMAC secret to issue the token
+    /// of the user of the namespace with.
+    fn token(&self, namespace: Option<&str>) -> Result<String> {
+        match (namespace, self.auth.as_deref()) {
+            (None, _) => Ok(self.token.clone()),
+            (Some(user), Some(auth @ Auth::Hmac(_))) => auth.issue(user),
+            (Some(user), _) => Err(anyhow!(
+                "The uploads of {} are only replicated with an HMAC secret \
+                 shared by the peers",
+                user
+            )),
+        }
+    }
+
+    /// Sends an upload to a peer, trying again a few times.
+    async fn send(
+        &self,
+        peer: &Peer,
+        store: &FileStore,
+        namespace: Option<&str>,
+        root_hash: &str,
+    ) -> Result<()> {
+        let token = self.token(namespace)?;
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1.. {
+            match peer.replicate(store, root_hash, &token).await {
+                Ok(()) => break,
+                Err(e) if attempt < ATTEMPTS => {
+                    log::warn!(
+                        "Could not replicate {} to {}, trying again: {:#}",
+                        root_hash,
+                        peer.address,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        log::info!("Replicated {} to {}", root_hash, peer.address);
+        Ok(())
+    }
+
+    /// Sends each peer the uploads of a store and of its namespaces it does
+    /// not hold.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of uploads sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the namespaces cannot be listed. The uploads
+    /// that cannot be sent are skipped, with an error logged.
+    pub async fn sync(&self, store: &FileStore) -> Result<usize> {
+        let mut namespaces = vec![None];
+        namespaces.extend(store.namespaces().await?.into_iter().map(Some));
+        let mut sent = 0;
+        for namespace in namespaces.iter().map(Option::as_deref) {
+            let store = match namespace {
+                Some(name) => store.namespace(name)?,
+                None => store.clone(),
+            };
+            let token = match self.token(namespace) {
+                Ok(token) => token,
+                Err(e) => {
+                    log::error!(
+                        "Could not sync {}: {:#}",
+                        store.root_dir().display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            for peer in &self.peers {
+                let synced = async {
+                    let held = peer.roots(&token).await?;
+                    let mut sent = 0;
+                    for upload in store.list_uploads().await? {
+                        if held.contains(&upload.root_hash) {
+                            continue;
+                        }
+                        let root_hash = &upload.root_hash;
+                        match self
+                            .send(peer, &store, namespace, root_hash)
+                            .await
+                        {
+                            Ok(()) => sent += 1,
+                            Err(e) => log::error!(
+                                "Could not replicate {} to {}: {:#}",
+                                root_hash,
+                                peer.address,
+                                e
+                            ),
+                        }
+                    }
+                    anyhow::Ok(sent)
+                };
+                match synced.await {
+                    Ok(synced) => sent += synced,
+                    Err(e) => log::error!(
+                        "Could not sync {} with {}: {:#}",
+                        store.root_dir().display(),
+                        peer.address,
+                        e
+                    ),
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Sends the uploads queued to the peers as they are committed, and the
+    /// uploads the peers are missing on startup and every sync interval,
+    /// for as long as the server runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store, whose namespaces are replicated too.
+    pub async fn run(self: Arc<Self>, store: FileStore) {
+        let Some(mut jobs) = self.jobs.lock().unwrap().take() else {
+            return;
+        };
+        let mut syncs = tokio::time::interval(self.sync_interval);
+        loop {
+            tokio::select! {
+                _ = syncs.tick() => match self.sync(&store).await {
+                    Ok(0) => {}
+                    Ok(sent) => log::info!("Synced {} uploads with the peers", sent),
+                    Err(e) => log::error!("Could not sync the peers: {:#}", e),
+                },
+                Some((namespace, root_hash)) = jobs.recv() => {
+                    let namespace = namespace.as_deref();
+                    let store = match namespace {
+                        Some(name) => match store.namespace(name) {
+                            Ok(store) => store,
+                            Err(e) => {
+                                log::error!("Could not replicate {}: {:#}", root_hash, e);
+                                continue;
+                            }
+                        },
+                        None => store.clone(),
+                    };
+                    for peer in &self.peers {
+                        if let Err(e) = self.send(peer, &store, namespace, &root_hash).await {
+                            log::error!(
+                                "Could not replicate {} to {}, left to the next sync: {:#}",
+                                root_hash,
+                                peer.address,
+                                e
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deadline::Timeouts;
+    use crate::server::Server;
+    use std::fs;
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
+
+    /// Serves a store on a local port, as a peer.
+    ///
+    /// # Returns
+    ///
+    /// The address of the peer.
+    async fn serve(store: FileStore) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (_stop, stopping) = watch::channel(false);
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let store = store.clone();
+                let stopping = stopping.clone();
+                tokio::spawn(async move {
+                    let _ = Server::handle_client(
+                        &mut socket,
+                        &store,
+                        None,
+                        Timeouts::default(),
+                        Some(compression::DEFAULT_LEVEL),
+                        None,
+                        stopping,
+                    )
+                    .await;
+                });
+            }
+        });
+        address
+    }
+
+    async fn read(store: &FileStore, root_hash: &str, index: usize) -> Vec<u8> {
+        let mut file = vec![];
+        store
+            .copy_file(root_hash, index, None, &mut file)
+            .await
+            .unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn test_replicate() {
+        let dir = std::env::temp_dir().join("file-guardian-test-replica");
+        let _ = fs::remove_dir_all(&dir);
+        let replica = FileStore::new(dir.join("replica")).unwrap();
+        let peer = Peer::new(&serve(replica.clone()).await);
+        let replicator = Arc::new(Replicator::new(vec![peer], None, None));
+        let primary = FileStore::new(dir.join("primary"))
+            .unwrap()
+            .with_replicator(replicator.clone());
+
+        // Stored before the replicator runs, the uploads are sent by the
+        // sync on startup
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let named = primary.store_files(files).await.unwrap().root_hash;
+        let names = vec!["a.txt".to_string(), "b.txt".to_string()];
+        primary.set_names(&named, &names).await.unwrap();
+        let other = primary
+            .store_files(vec![vec![7; 100]])
+            .await
+            .unwrap()
+            .root_hash;
+        assert_eq!(replicator.sync(&primary).await.unwrap(), 2);
+        assert!(replica.has_upload(&named).await.unwrap());
+        assert_eq!(replica.names(&named).await.unwrap(), Some(names));
+        assert_eq!(read(&replica, &named, 1).await, b"world");
+        assert_eq!(read(&replica, &other, 0).await, vec![7; 100]);
+        assert_eq!(replicator.sync(&primary).await.unwrap(), 0);
+
+        // Once it runs, the uploads are sent as they are committed
+        tokio::spawn(replicator.clone().run(primary.clone()));
+        let root_hash = primary
+            .store_files(vec![b"new".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+        let sent = async {
+            while !replica.has_upload(&root_hash).await.unwrap() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), sent)
+            .await
+            .unwrap();
+        assert_eq!(read(&replica, &root_hash, 0).await, b"new");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
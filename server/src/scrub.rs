@@ -0,0 +1,119 @@
+use anyhow::Result;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::store::FileStore;
+
+/// What a scrub of a store found: the uploads whose files or tree are no
+/// longer as they were stored, e.g. after bit rot on the disk.
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    /// Whether the corrupt files are quarantined, for the server to refuse
+    /// their uploads until clients upload them again.
+    pub quarantine: bool,
+    /// The number of uploads checked.
+    pub uploads: usize,
+    /// The number of bytes hashed again.
+    pub bytes: u64,
+    /// The directories of the corrupt uploads, under the directory of the
+    /// store, with why they are.
+    pub corrupt: Vec<(PathBuf, String)>,
+}
+
+impl ScrubReport {
+    /// Reports a corrupt upload.
+    pub fn flag(&mut self, path: PathBuf, reason: String) {
+        self.corrupt.push((path, reason));
+    }
+
+    /// Returns whether no upload is corrupt.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+
+    /// Returns the lines the report is printed as: the corrupt uploads, then
+    /// the summary.
+    pub fn lines(&self) -> Vec<String> {
+        self.corrupt
+            .iter()
+            .map(|(path, reason)| format!("{}: {}", path.display(), reason))
+            .chain([self.to_string()])
+            .collect()
+    }
+}
+
+impl fmt::Display for ScrubReport {
+    /// Formats the summary of the report, on a single line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checked {} uploads ({} bytes), {} corrupt{}",
+            self.uploads,
+            self.bytes,
+            self.corrupt.len(),
+            if self.quarantine && !self.is_clean() {
+                " and quarantined"
+            } else {
+                ""
+            },
+        )
+    }
+}
+
+/// Scrubs a store and its namespaces: hashes every file of every upload
+/// again, and builds the tree of each upload again from them, to find the
+/// uploads no longer as they were stored before a client's proof fails.
+///
+/// # Arguments
+///
+/// * `store` - The store.
+/// * `quarantine` - Whether to move the corrupt files aside, see
+///   [`FileStore::scrub`].
+///
+/// # Errors
+///
+/// Returns an error if the namespaces cannot be listed. Those which cannot
+/// be scrubbed are skipped, with an error logged.
+pub async fn scrub(store: &FileStore, quarantine: bool) -> Result<ScrubReport> {
+    let mut report = ScrubReport {
+        quarantine,
+        ..Default::default()
+    };
+    for store in &store.with_namespaces().await? {
+        if let Err(e) = store.scrub(&mut report).await {
+            let dir = store.root_dir().display();
+            log::error!("Could not scrub {}: {:#}", dir, e);
+        }
+    }
+    Ok(report)
+}
+
+/// Scrubs a store every `interval`, logging the corrupt uploads, for as long
+/// as the server runs.
+///
+/// # Arguments
+///
+/// * `store` - The store.
+/// * `interval` - How often the store is scrubbed.
+/// * `quarantine` - Whether to move the corrupt files aside.
+pub async fn run(store: FileStore, interval: Duration, quarantine: bool) {
+    let start = tokio::time::Instant::now() + interval;
+    let mut ticks = tokio::time::interval_at(start, interval);
+    loop {
+        ticks.tick().await;
+        match scrub(&store, quarantine).await {
+            Ok(report) => {
+                for (path, reason) in &report.corrupt {
+                    log::error!(
+                        "Upload {} is corrupt: {}",
+                        path.display(),
+                        reason
+                    );
+                }
+                log::info!("{}", report);
+            }
+            Err(e) => log::error!("Could not scrub the store: {:#}", e),
+        }
+    }
+}
@@ -1,14 +1,187 @@
-use anyhow::Result;
-use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use anyhow::{anyhow, Result};
+use merkle_tree::MerkleTree;
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 
-use crate::store::{self, FileStore};
+use protocol::compression::{self, FrameWriter};
+use protocol::{
+    Capabilities, FileRef, Limits, Request, Response, StoredUpload,
+    MIN_VERSION, VERSION,
+};
+
+use crate::admin::Admin;
+use crate::audit::AuditLog;
+use crate::auth::{self, Auth};
+use crate::backend::{LocalBackend, StorageBackend};
+use crate::backup::{self, BackupReport};
+use crate::cache::{TreeCache, UsageCache};
+use crate::deadline::{within, Deadline, Timeouts};
+use crate::error::{error, from_protocol, kind_of, ErrorKind};
+use crate::expiry;
+use crate::gc::{self, GcReport};
+use crate::http;
+use crate::identity::Identity;
+use crate::limiter::ConnectionLimiter;
+use crate::listener::Listener;
+use crate::logger;
+use crate::replica::Replicator;
+use crate::scrub::{self, ScrubReport};
+use crate::store::{self, Committed, FileStore};
+use crate::systemd;
+use crate::wal::{self, Wal};
+use crate::webhook::Webhooks;
+
+/// How long to wait before accepting connections again after failing to.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The permissions of the Unix sockets listened on by default: the clients
+/// connecting must be the owner of the server, or in its group.
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// The directory of the store by default, relative to the working directory.
+const DEFAULT_STORE_DIR: &str = "server_store";
+
+/// How long the connections being served are waited for by default, once
+/// the server is asked to stop, before they are closed.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most trees of uploads cached by default.
+pub const DEFAULT_CACHED_TREES: usize = 1024;
+
+/// The most bytes of trees of uploads cached by default.
+pub const DEFAULT_TREE_CACHE_SIZE: u64 = 64 << 20;
+
+/// What a connection is served, with the settings of the protocol.
+#[derive(Clone, Copy)]
+enum Protocol {
+    /// The HTTP API, given when the server started for the health checks.
+    Http { started: Instant },
+    /// The TCP protocol, given the zstd level file contents are compressed
+    /// with in transit, if offered.
+    Tcp { compression: Option<i32> },
+}
+
+/// An address the server listens on, with what it serves there.
+#[derive(Clone)]
+pub struct Bind {
+    /// The address, in the format `host:port`, or `unix:<path>` for a Unix
+    /// socket.
+    pub address: String,
+    /// Whether the HTTP API is served there rather than the TCP protocol.
+    pub http: bool,
+    /// The TLS acceptor of the connections, if they are accepted over TLS,
+    /// or None for those of [`Server::with_tls`].
+    pub tls: Option<Option<TlsAcceptor>>,
+}
+
+impl Bind {
+    /// Serves the TCP protocol on an address, with the TLS settings of the
+    /// server.
+    pub fn tcp(address: &str) -> Bind {
+        Bind {
+            address: address.to_string(),
+            http: false,
+            tls: None,
+        }
+    }
+
+    /// Serves the HTTP API on an address, see [`http::handle_client`], with
+    /// the TLS settings of the server.
+    pub fn http(address: &str) -> Bind {
+        Bind {
+            http: true,
+            ..Bind::tcp(address)
+        }
+    }
+}
 
 /// A server that listens for incoming connections and handles file uploads and
 /// downloads.
 pub struct Server {
-    address: String,
+    /// The addresses listened on.
+    listeners: Vec<Bind>,
+    /// The directory of the uploads, and of the files being uploaded.
+    store_dir: PathBuf,
+    /// The TLS acceptor, if connections are only accepted over TLS.
+    tls: Option<TlsAcceptor>,
+    /// How client tokens are validated, if clients must authenticate,
+    /// replaced when the admin requests change the tokens file.
+    auth: Option<Arc<RwLock<Arc<Auth>>>>,
+    /// The file the tokens are read from, if they are.
+    tokens_file: Option<PathBuf>,
+    /// The Unix socket the admin requests are served on, if they are.
+    admin_socket: Option<PathBuf>,
+    /// The permissions of the Unix sockets listened on, see
+    /// [`Server::with_unix_socket_mode`].
+    unix_socket_mode: u32,
+    /// The limits of the files and uploads accepted from clients.
+    limits: Limits,
+    /// The storage quota of each user, if limited.
+    quota: Option<u64>,
+    /// The limits of the connections from each source IP, if limited.
+    limiter: Option<Arc<ConnectionLimiter>>,
+    /// The connections served at once, if limited, whatever their source.
+    connections: Option<Arc<Semaphore>>,
+    /// How long the connections wait for their clients.
+    timeouts: Timeouts,
+    /// The zstd level file contents are compressed with in transit, for the
+    /// clients asking for it, if offered.
+    compression: Option<i32>,
+    /// Where the operations served are recorded, if they are.
+    audit: Option<Arc<AuditLog>>,
+    /// The trees of the uploads read last, shared by every connection.
+    cache: Arc<TreeCache>,
+    /// The bytes held by the uploads of each user, shared by every
+    /// connection.
+    usage: Arc<UsageCache>,
+    /// Where the uploads are kept, if not on the local disk.
+    backend: Option<Arc<dyn StorageBackend>>,
+    /// How often the garbage of the store is collected, if it is.
+    gc_interval: Option<Duration>,
+    /// How long the files being uploaded are kept without being written to.
+    gc_grace: Duration,
+    /// How long the uploads are kept unless given another retention, if
+    /// they expire.
+    retention: Option<Duration>,
+    /// How often the expired uploads are deleted.
+    expiry_interval: Duration,
+    /// How often the store is scrubbed in the background, if it is.
+    scrub_interval: Option<Duration>,
+    /// Whether scrubbing quarantines the corrupt files.
+    scrub_quarantine: bool,
+    /// What the uploads committed are replicated to peer servers with, if
+    /// they are.
+    replicator: Option<Arc<Replicator>>,
+    /// What the uploads committed are posted to, if anything.
+    webhooks: Option<Arc<Webhooks>>,
+    /// The key the root hashes of the uploads committed are signed with, if
+    /// they are.
+    identity: Option<Arc<Identity>>,
+    /// Where the commits and deletions are logged before they begin, once
+    /// opened by the server or an import.
+    wal: OnceLock<Arc<Wal>>,
+    /// Whether the uploads and deletions are refused for now, shared by
+    /// every connection and toggled with SIGUSR1 and SIGUSR2.
+    read_only: Arc<AtomicBool>,
+    /// How long the connections being served are waited for once the server
+    /// is asked to stop.
+    shutdown_timeout: Duration,
+    /// The number of connections accepted, over both protocols, numbering
+    /// them in the logs.
+    accepted: AtomicU64,
+    /// When the server was created, for health checks to report its uptime.
+    started: Instant,
 }
 
 impl Server {
@@ -16,118 +189,2387 @@ impl Server {
     ///
     /// # Arguments
     ///
-    /// * `address` - The address that the server listens on.
-    pub fn new(address: &str) -> Server {
+    /// * `listeners` - The addresses that the server listens on, serving the
+    ///   TCP protocol or the HTTP API with the same store and
+    ///   authentication, see [`Bind`].
+    pub fn new(listeners: Vec<Bind>) -> Server {
         Server {
-            address: address.to_string(),
+            listeners,
+            store_dir: PathBuf::from(DEFAULT_STORE_DIR),
+            tls: None,
+            auth: None,
+            tokens_file: None,
+            admin_socket: None,
+            unix_socket_mode: DEFAULT_UNIX_SOCKET_MODE,
+            limits: Limits::default(),
+            quota: None,
+            limiter: None,
+            connections: None,
+            timeouts: Timeouts::default(),
+            compression: Some(compression::DEFAULT_LEVEL),
+            audit: None,
+            cache: Arc::new(TreeCache::new(
+                DEFAULT_CACHED_TREES,
+                DEFAULT_TREE_CACHE_SIZE,
+            )),
+            usage: Arc::default(),
+            backend: None,
+            gc_interval: None,
+            gc_grace: gc::DEFAULT_GRACE,
+            retention: None,
+            expiry_interval: expiry::DEFAULT_INTERVAL,
+            scrub_interval: None,
+            scrub_quarantine: false,
+            replicator: None,
+            webhooks: None,
+            identity: None,
+            wal: OnceLock::new(),
+            read_only: Arc::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            accepted: AtomicU64::new(0),
+            started: Instant::now(),
         }
     }
 
-    /// Handles a file upload request from a client.
+    /// Keeps the uploads in the given directory rather than under
+    /// `server_store`, or only the files being uploaded if the uploads are
+    /// kept elsewhere.
     ///
     /// # Arguments
     ///
-    /// * `stream` - The TCP stream that connects the server to the client.
+    /// * `dir` - The directory of the store.
+    pub fn with_store_dir(mut self, dir: impl Into<PathBuf>) -> Server {
+        self.store_dir = dir.into();
+        self
+    }
+
+    /// Only serves clients authenticating with a valid token.
     ///
-    /// # Returns
+    /// # Arguments
+    ///
+    /// * `auth` - How the tokens are validated.
+    pub fn with_auth(mut self, auth: Arc<Auth>) -> Server {
+        self.auth = Some(Arc::new(RwLock::new(auth)));
+        self
+    }
+
+    /// Lets the admin requests add tokens to and revoke them from the file
+    /// the tokens are read from, which is read again after each change.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The tokens file.
+    pub fn with_tokens_file(mut self, path: &Path) -> Server {
+        self.tokens_file = Some(path.to_path_buf());
+        self
+    }
+
+    /// Serves the admin requests of `server admin` on a Unix socket, only
+    /// accessible to the user running the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the socket.
+    pub fn with_admin_socket(mut self, path: &Path) -> Server {
+        self.admin_socket = Some(path.to_path_buf());
+        self
+    }
+
+    /// Only accepts files and uploads within the given limits, rather than
+    /// the default ones, rejecting the others before receiving their files.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The limits of the files and uploads.
+    pub fn with_limits(mut self, limits: Limits) -> Server {
+        self.limits = limits;
+        self
+    }
+
+    /// Only accepts the uploads of a user while their uploads hold at most
+    /// the given number of bytes, the clients without a user sharing a quota.
+    ///
+    /// # Arguments
+    ///
+    /// * `quota` - The quota of each user, in bytes.
+    pub fn with_quota(mut self, quota: u64) -> Server {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Limits the connections accepted from each source IP, closing the
+    /// others as soon as they are accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `limiter` - The limits of the connections.
+    pub fn with_connection_limiter(
+        mut self,
+        limiter: ConnectionLimiter,
+    ) -> Server {
+        self.limiter = Some(Arc::new(limiter));
+        self
+    }
+
+    /// Serves at most `max` connections at once, on all the addresses, the
+    /// connections beyond waiting to be accepted until one closes.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - The most connections served at once.
+    pub fn with_max_connections(mut self, max: usize) -> Server {
+        self.connections = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Closes the connections whose clients take too long, see
+    /// [`Timeouts`].
+    ///
+    /// # Arguments
+    ///
+    /// * `timeouts` - How long the connections wait for their clients.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Server {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Compresses the contents of files in transit at another zstd level
+    /// than [`compression::DEFAULT_LEVEL`], for the clients asking for it,
+    /// or not at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The zstd level, `None` not to offer compression.
+    pub fn with_wire_compression(mut self, level: Option<i32>) -> Server {
+        self.compression = level;
+        self
+    }
+
+    /// Records the uploads, downloads, proofs, deletions and failed
+    /// authentications served in an audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `log` - The audit log.
+    pub fn with_audit_log(mut self, log: AuditLog) -> Server {
+        self.audit = Some(Arc::new(log));
+        self
+    }
+
+    /// Caches the trees of the uploads read last in the given cache, rather
+    /// than in one of the default size.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache` - The cache of the trees.
+    pub fn with_tree_cache(mut self, cache: TreeCache) -> Server {
+        self.cache = Arc::new(cache);
+        self
+    }
+
+    /// Keeps the uploads in the given backend rather than on the local disk,
+    /// under `server_store`, the files being uploaded still being received
+    /// there.
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - Where the uploads are kept.
+    pub fn with_backend(
+        mut self,
+        backend: impl StorageBackend + 'static,
+    ) -> Server {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Only accepts connections over TLS, the existing protocol running
+    /// inside the TLS session, on the addresses without TLS settings of
+    /// their own.
+    ///
+    /// # Arguments
+    ///
+    /// * `acceptor` - The TLS acceptor, holding the server certificate.
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Server {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Sets the permissions of the Unix sockets listened on, when an address
+    /// is in the format `unix:<path>`, which control who can connect: the
+    /// clients need write access to the socket.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - The permissions, e.g. `0o660` for the owner of the server
+    ///   and its group.
+    pub fn with_unix_socket_mode(mut self, mode: u32) -> Server {
+        self.unix_socket_mode = mode;
+        self
+    }
+
+    /// Collects the garbage of the store in the background, see
+    /// [`gc::collect`].
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - How often the garbage is collected.
+    pub fn with_gc(mut self, interval: Duration) -> Server {
+        self.gc_interval = Some(interval);
+        self
+    }
+
+    /// Keeps the files being uploaded for the given time without being
+    /// written to, rather than for a day, before collecting them.
+    ///
+    /// # Arguments
+    ///
+    /// * `grace` - How long the files are kept.
+    pub fn with_gc_grace(mut self, grace: Duration) -> Server {
+        self.gc_grace = grace;
+        self
+    }
+
+    /// Deletes the uploads once kept for the given time, unless their client
+    /// gives them another retention.
+    ///
+    /// # Arguments
+    ///
+    /// * `retention` - How long the uploads are kept.
+    pub fn with_retention(mut self, retention: Duration) -> Server {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Looks for the uploads which have expired, to delete them, every
+    /// `interval` rather than every minute.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - How often the expired uploads are deleted.
+    pub fn with_expiry_interval(mut self, interval: Duration) -> Server {
+        self.expiry_interval = interval;
+        self
+    }
+
+    /// Scrubs the store in the background, see [`scrub::scrub`].
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - How often the store is scrubbed.
+    pub fn with_scrub(mut self, interval: Duration) -> Server {
+        self.scrub_interval = Some(interval);
+        self
+    }
+
+    /// Quarantines the corrupt files found when scrubbing, rather than only
+    /// reporting them, see [`FileStore::scrub`].
+    pub fn with_scrub_quarantine(mut self) -> Server {
+        self.scrub_quarantine = true;
+        self
+    }
+
+    /// Replicates the uploads committed to peer servers, and sends them the
+    /// uploads they are missing on startup and every sync interval.
+    ///
+    /// # Arguments
     ///
-    /// Returns a vector of vectors of bytes that represent the uploaded files.
+    /// * `replicator` - The peers and how the uploads are sent to them.
+    pub fn with_replicator(mut self, replicator: Replicator) -> Server {
+        self.replicator = Some(Arc::new(replicator));
+        self
+    }
+
+    /// Posts an event to webhooks for every upload committed, see
+    /// [`Webhooks`].
+    ///
+    /// # Arguments
+    ///
+    /// * `webhooks` - The URLs and how the events are posted to them.
+    pub fn with_webhooks(mut self, webhooks: Webhooks) -> Server {
+        self.webhooks = Some(Arc::new(webhooks));
+        self
+    }
+
+    /// Signs the root hash of every upload committed, for the clients to
+    /// hold evidence of what the server committed to storing, see
+    /// [`Identity`].
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The ed25519 identity key of the server.
+    pub fn with_identity(mut self, identity: Identity) -> Server {
+        log::info!(
+            "Signing the roots of the uploads with the identity key {}",
+            hex::encode(identity.public_key())
+        );
+        self.identity = Some(Arc::new(identity));
+        self
+    }
+
+    /// Starts read-only, refusing the uploads and deletions while still
+    /// serving the downloads, until SIGUSR2 makes the server writable.
+    pub fn with_read_only(self) -> Server {
+        self.read_only.store(true, Ordering::Relaxed);
+        self
+    }
+
+    /// Waits for the connections being served for up to `timeout` rather
+    /// than 30 seconds once asked to stop, before closing them.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long the connections are waited for.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Server {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Collects the garbage of the store once, e.g. from an admin command.
+    /// Uploads being stored by another process, such as a server running on
+    /// the same store, may be taken for garbage.
+    ///
+    /// # Arguments
+    ///
+    /// * `dry_run` - Whether to only report what would be removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be opened or read.
+    pub async fn collect_garbage(&self, dry_run: bool) -> Result<GcReport> {
+        let store = self.store(&self.backend()?)?;
+        gc::collect(&store, self.gc_grace, dry_run).await
+    }
+
+    /// Scrubs the store once, e.g. from an admin command.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be opened or read.
+    pub async fn scrub(&self) -> Result<ScrubReport> {
+        let store = self.store(&self.backend()?)?;
+        scrub::scrub(&store, self.scrub_quarantine).await
+    }
+
+    /// Exports the uploads of the store to a backup archive, see
+    /// [`backup::export`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be opened or read, or the archive
+    /// cannot be written.
+    pub async fn export(&self, output: &Path) -> Result<BackupReport> {
+        let store = self.store(&self.backend()?)?;
+        backup::export(&store, output).await
+    }
+
+    /// Imports the uploads of a backup archive into the store, see
+    /// [`backup::import`]. The uploads are imported as they were, whatever
+    /// the limits, quotas and retention of the server.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be opened or written, or the
+    /// archive cannot be read or is not a valid backup.
+    pub async fn import(&self, input: &Path) -> Result<BackupReport> {
+        let backend = self.backend()?;
+        self.open_wal(&backend).await?;
+        let mut store = store::FileStore::new(self.store_dir.clone())?
+            .with_backend(backend)
+            .with_limits(Limits::NONE);
+        if let Some(wal) = self.wal.get() {
+            store = store.with_wal(wal.clone());
+        }
+        backup::import(&store, input).await
+    }
+
+    /// Returns where the uploads are kept.
+    fn backend(&self) -> Result<Arc<dyn StorageBackend>> {
+        Ok(match &self.backend {
+            Some(backend) => backend.clone(),
+            None => Arc::new(LocalBackend::new(&self.store_dir)?),
+        })
+    }
+
+    /// Opens the write-ahead log of the store, the commits and deletions
+    /// being logged from then on, and repairs the operations a crash
+    /// interrupted, unless the server is read-only. A log held by another
+    /// process, e.g. a server running while uploads are imported, is left
+    /// to it, the operations not being logged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the log cannot be opened, or the operations
+    /// repaired.
+    async fn open_wal(&self, backend: &Arc<dyn StorageBackend>) -> Result<()> {
+        std::fs::create_dir_all(&self.store_dir)?;
+        let path = self.store_dir.join(wal::WAL_FILE);
+        let Some(wal) = Wal::open(&path)? else {
+            log::warn!(
+                "The log {} is held by another process, the commits and \
+                 deletions are not logged",
+                path.display()
+            );
+            return Ok(());
+        };
+        let interrupted = wal.unfinished().len();
+        let _ = self.wal.set(Arc::new(wal));
+        if interrupted == 0 {
+            return Ok(());
+        }
+        if self.read_only.load(Ordering::Relaxed) {
+            log::warn!(
+                "Not repairing the {} operations a crash interrupted while \
+                 read-only",
+                interrupted
+            );
+            return Ok(());
+        }
+        let repaired = self.store(backend)?.recover().await?;
+        log::info!("Repaired {} operations a crash interrupted", repaired);
+        Ok(())
+    }
+
+    /// Returns the store of the uploads, before the namespace of a client is
+    /// known.
+    fn store(&self, backend: &Arc<dyn StorageBackend>) -> Result<FileStore> {
+        let mut store = store::FileStore::new(self.store_dir.clone())?
+            .with_backend(backend.clone())
+            .with_limits(self.limits)
+            .with_cache(self.cache.clone())
+            .with_usage_cache(self.usage.clone())
+            .with_read_only(self.read_only.clone());
+        if let Some(quota) = self.quota {
+            store = store.with_quota(quota);
+        }
+        if let Some(retention) = self.retention {
+            store = store.with_retention(retention);
+        }
+        if let Some(replicator) = &self.replicator {
+            store = store.with_replicator(replicator.clone());
+        }
+        if let Some(webhooks) = &self.webhooks {
+            store = store.with_webhooks(webhooks.clone());
+        }
+        if let Some(identity) = &self.identity {
+            store = store.with_identity(identity.clone());
+        }
+        if let Some(wal) = self.wal.get() {
+            store = store.with_wal(wal.clone());
+        }
+        Ok(store)
+    }
+
+    /// Handles the upload of a single file of a batch, which is staged until
+    /// the batch is committed.
+    ///
+    /// The server replies to the request with a [`Response::Resume`] with the
+    /// number of bytes of the file already received in the session, and the
+    /// client only sends the rest, so that an upload interrupted by a network
+    /// failure resumes where it stopped. Once the file is received, the
+    /// server replies with a [`Response::Staged`] with the hash of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream, over TCP or TLS, that connects the server to
+    ///   the client.
+    /// * `store` - The file store where the file is staged.
+    /// * `session` - The upload session of the batch.
+    /// * `index` - The index of the file in the batch.
+    /// * `file_size` - The size of the file.
+    /// * `compression` - The zstd level of the connection, if the file is
+    ///   sent compressed.
     ///
     /// # Errors
     ///
     /// Returns an error if the file upload fails.
-    async fn handle_upload(stream: &mut TcpStream) -> Result<Vec<Vec<u8>>> {
-        // Read the number of files from the client
-        let number_of_files: usize = stream.read_u64().await? as usize;
+    async fn handle_put<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        store: &FileStore,
+        session: &str,
+        index: usize,
+        file_size: u64,
+        compression: Option<i32>,
+    ) -> Result<()> {
+        // Send the number of bytes already received, restarting from scratch
+        // if the file got smaller, unless the file cannot fit in the quota,
+        // in which it is reserved while received
+        let file = async {
+            let reserved = store.reserve_quota(file_size).await?;
+            let path = store.partial_file(session, index)?;
+            let file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            let mut offset = file.metadata().await?.len();
+            if offset > file_size {
+                file.set_len(0).await?;
+                offset = 0;
+            }
+            Ok((file, offset, reserved))
+        };
+        let (mut file, offset, _reserved) =
+            Self::report(stream, file.await).await?;
+        Self::respond(stream, Response::Resume { offset }).await?;
+
+        // Append the rest of the file, keeping what was received if the
+        // connection drops
+        let len = file_size - offset;
+        let received = match compression {
+            Some(_) => Self::receive_frames(stream, &mut file, len).await,
+            None => {
+                tokio::io::copy(&mut (&mut *stream).take(len), &mut file).await
+            }
+        };
+        let synced = file.sync_all().await;
+        let received = received?;
+        if received < file_size - offset {
+            return Err(anyhow!(
+                "Connection closed after {} of {} bytes",
+                offset + received,
+                file_size
+            ));
+        }
+
+        // acknowledge the file with its hash, under which it is staged, or
+        // tell the client why it could not be
+        let staged = synced
+            .map_err(anyhow::Error::from)
+            .and_then(|()| store.stage_file(session, index));
+        let hash = Self::report(stream, staged).await?;
+        Self::respond(stream, Response::Staged { hash }).await
+    }
+
+    /// Receives the frames of a file sent compressed, appending what they
+    /// decode to `file` as they are received.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes received, fewer than `len` if the connection
+    /// closed before.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream or the file fails, or if a frame is
+    /// invalid, after which the rest of the stream cannot be read.
+    async fn receive_frames<S: AsyncRead + Unpin + Send>(
+        stream: &mut S,
+        file: &mut tokio::fs::File,
+        len: u64,
+    ) -> io::Result<u64> {
+        let mut buf =
+            vec![0; len.min(compression::MAX_FRAME_LEN as u64) as usize];
+        let mut received = 0;
+        while received < len {
+            let max = buf.len().min((len - received) as usize);
+            match compression::read_frame(stream, &mut buf[..max]).await {
+                Ok(read) => {
+                    file.write_all(&buf[..read]).await?;
+                    received += read as u64;
+                }
+                Err(protocol::Error::Io(e))
+                    if e.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    break
+                }
+                Err(protocol::Error::Io(e)) => return Err(e),
+                Err(e) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+                }
+            }
+        }
+        Ok(received)
+    }
 
-        // Read each file from the client and store it in a vector
-        let mut res = vec![];
-        for _ in 0..number_of_files {
-            let file_size = stream.read_u64().await? as usize;
-            let mut file = vec![0; file_size];
-            stream.read_exact(&mut file).await?;
-            res.push(file);
+    /// Copies a file, or a range of it, from the store to the client,
+    /// compressed if the connection is.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes of the file copied.
+    async fn send_file<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        store: &FileStore,
+        root_hash: &str,
+        index: usize,
+        range: Option<Range<u64>>,
+        compression: Option<i32>,
+    ) -> Result<u64> {
+        match compression {
+            Some(level) => {
+                let mut frames = FrameWriter::new(&mut *stream, level);
+                let copied = store
+                    .copy_file(root_hash, index, range, &mut frames)
+                    .await?;
+                frames.flush().await?;
+                Ok(copied)
+            }
+            None => store.copy_file(root_hash, index, range, stream).await,
         }
-        Ok(res)
     }
 
-    /// Handles a file download request from a client.
+    /// Handles a file download request from a client: the server replies
+    /// with a [`Response::File`] with the index and the size of the file,
+    /// followed by the file and a [`Response::Proof`] with its leaf and
+    /// Merkle proof.
+    ///
+    /// The file may be asked for by its name, as recorded with a
+    /// [`Request::Names`], rather than by its index.
     ///
     /// # Arguments
     ///
-    /// * `stream` - The TCP stream that connects the server to the client.
+    /// * `stream` - The stream, over TCP or TLS, that connects the server to
+    ///   the client.
     /// * `store` - The file store that contains the files.
+    /// * `root_hash` - The root hash of the upload.
+    /// * `file` - The file asked for.
+    /// * `compression` - The zstd level of the connection, if the file is
+    ///   sent compressed.
     ///
     /// # Errors
     ///
-    /// Returns an error if the file download fails.
-    async fn handle_download(
-        stream: &mut TcpStream,
+    /// Returns an error if the file download fails, once the client has been
+    /// told so if possible.
+    async fn handle_download<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
         store: &FileStore,
+        root_hash: &str,
+        file: FileRef,
+        compression: Option<i32>,
     ) -> Result<()> {
-        // Read the root hash from the client
-        let mut root_hash = [0; 64];
-        stream.read_exact(&mut root_hash).await?;
-
-        // Convert the root hash to a hex string
-        let root_hash = std::str::from_utf8(&root_hash)?;
-
-        // Read the index from the client
-        let index = stream.read_u64().await? as usize;
-        // get file from store
-        let file = store.get_file(&root_hash, index)?;
-
-        // Generate proof for file and export it as a vector of bytes
-        let proof = store
-            .get_tree(&root_hash)?
-            .proof(index)?
-            .into_iter()
-            .flatten()
-            .collect::<Vec<u8>>();
-
-        // send file size
-        stream.write_all(&(file.len().to_be_bytes())).await?;
-        // send file
-        stream.write_all(&file).await?;
-        // send proof
-        stream.write_all(&proof).await?;
+        let file = async {
+            let index = match file {
+                FileRef::Index(index) => index,
+                FileRef::Name(name) => {
+                    store.get_index(root_hash, &name).await?
+                }
+            };
+            // Generate proof for file
+            let tree = Self::get_tree(store, root_hash, index).await?;
+            let proof = tree.proof(index)?;
+            let size = store.file_size(root_hash, index).await?;
+            Ok((index, size, tree.leaves()[index], proof))
+        };
+        let (index, size, leaf, hashes) =
+            Self::report(stream, file.await).await?;
 
-        Ok(())
+        // send the index and the size of the file, the file, copied from the
+        // store as it is sent, then its proof
+        Self::respond(stream, Response::File { index, size }).await?;
+        let copied =
+            Self::send_file(stream, store, root_hash, index, None, compression)
+                .await?;
+        if copied != size {
+            return Err(anyhow!("File {} changed while sent", index));
+        }
+        Self::respond(stream, Response::Proof { leaf, hashes }).await
     }
 
-    async fn handle_client(
-        stream: &mut TcpStream,
+    /// Handles a proof request, for clients that already hold a file and only
+    /// need its Merkle proof: the server replies with a [`Response::Proof`]
+    /// with the leaf hash of the file and its proof.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream, over TCP or TLS, that connects the server to
+    ///   the client.
+    /// * `store` - The store that holds the uploads.
+    /// * `root_hash` - The root hash of the upload.
+    /// * `index` - The index of the file in the upload.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the upload or the file does not exist, once the
+    /// client has been told so.
+    async fn handle_proof<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        store: &FileStore,
+        root_hash: &str,
+        index: usize,
+    ) -> Result<()> {
+        let proof = async {
+            let tree = Self::get_tree(store, root_hash, index).await?;
+            let proof = tree.proof(index)?;
+            Ok((tree.leaves()[index], proof))
+        };
+        let (leaf, hashes) = Self::report(stream, proof.await).await?;
+        Self::respond(stream, Response::Proof { leaf, hashes }).await
+    }
+
+    /// Handles the download of a range of a file: the server replies with a
+    /// [`Response::Range`] with the length of the range, shorter at the end
+    /// of the file, followed by its bytes.
+    ///
+    /// The range is not proven on its own: the client verifies the whole file
+    /// once all its ranges are downloaded, against the proof of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream, over TCP or TLS, that connects the server to
+    ///   the client.
+    /// * `store` - The store that holds the uploads.
+    /// * `root_hash` - The root hash of the upload.
+    /// * `index` - The index of the file in the upload.
+    /// * `offset` - The offset of the range in the file.
+    /// * `len` - The length of the range.
+    /// * `compression` - The zstd level of the connection, if the range is
+    ///   sent compressed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, once the client has been
+    /// told so.
+    async fn handle_range<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
         store: &FileStore,
+        root_hash: &str,
+        index: usize,
+        offset: u64,
+        len: u64,
+        compression: Option<i32>,
+    ) -> Result<()> {
+        // The range is shorter at the end of the file
+        let size = async {
+            Self::get_tree(store, root_hash, index).await?;
+            store.file_size(root_hash, index).await
+        };
+        let size = Self::report(stream, size.await).await?;
+        let len = len.min(size.saturating_sub(offset));
+        Self::respond(stream, Response::Range { len }).await?;
+        let range = Some(offset..offset + len);
+        let copied = Self::send_file(
+            stream,
+            store,
+            root_hash,
+            index,
+            range,
+            compression,
+        )
+        .await?;
+        if copied != len {
+            return Err(anyhow!("File {} changed while sent", index));
+        }
+        Ok(())
+    }
+
+    /// Handles the handshake that starts every connection: the client sends
+    /// a [`Request::Hello`] with the latest version of the protocol it speaks
+    /// and its capabilities, and the server replies with a
+    /// [`Response::Hello`] with the version the connection speaks, the latest
+    /// both speak, and the capabilities of the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream, over TCP or TLS, that connects the server to
+    ///   the client.
+    /// * `auth` - How tokens are validated, `None` accepting any client.
+    /// * `limits` - The limits of the uploads, checked even before the
+    ///   handshake.
+    /// * `compression` - The zstd level file contents are compressed with in
+    ///   transit, if offered.
+    /// * `signs` - Whether the root hashes of the uploads are signed.
+    ///
+    /// # Returns
+    ///
+    /// The capabilities both the client and the server have, compression
+    /// only if it is offered.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client speaks no version the server speaks,
+    /// once it has been told so.
+    async fn handle_hello<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        auth: Option<&Auth>,
+        limits: &Limits,
+        compression: Option<i32>,
+        signs: bool,
+    ) -> Result<Capabilities> {
+        let invalid = |reason: String| error(ErrorKind::InvalidRequest, reason);
+        let (version, client) = match Request::read(stream, limits).await {
+            Ok(Request::Hello {
+                version,
+                capabilities,
+            }) => (version, capabilities),
+            // Clients older than the handshake start with their token
+            Ok(_) => {
+                let old = invalid(format!(
+                    "Expected the protocol handshake, the client speaking a \
+                     version older than {}",
+                    MIN_VERSION
+                ));
+                return Self::reject(stream, old)
+                    .await
+                    .map(|()| Capabilities::default());
+            }
+            Err(protocol::Error::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                let invalid = from_protocol(e);
+                return Self::reject(stream, invalid)
+                    .await
+                    .map(|()| Capabilities::default());
+            }
+        };
+        if version < MIN_VERSION {
+            let unsupported = invalid(format!(
+                "Unsupported protocol version {}, the server speaks versions \
+                 {} to {}",
+                version, MIN_VERSION, VERSION
+            ));
+            return Self::reject(stream, unsupported)
+                .await
+                .map(|()| Capabilities::default());
+        }
+
+        let mut capabilities = Capabilities::CHUNKING
+            .union(Capabilities::USAGE)
+            .union(Capabilities::EXPIRY)
+            .union(Capabilities::CONTENTS)
+            .union(Capabilities::STORED)
+            .union(Capabilities::REQUESTS);
+        if auth.is_some() {
+            capabilities = capabilities.union(Capabilities::AUTH);
+        }
+        if compression.is_some() {
+            capabilities = capabilities.union(Capabilities::COMPRESSION);
+        }
+        if signs {
+            capabilities = capabilities.union(Capabilities::SIGNED);
+        }
+        let version = version.min(VERSION);
+        Self::respond(
+            stream,
+            Response::Hello {
+                version,
+                capabilities,
+            },
+        )
+        .await?;
+        Ok(capabilities.intersection(client))
+    }
+
+    /// Handles the authentication that follows the handshake: the client
+    /// sends a [`Request::Auth`] with its token, possibly empty, and the
+    /// server replies with a [`Response::Ok`], or with the error rejecting
+    /// the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream, over TCP or TLS, that connects the server to
+    ///   the client.
+    /// * `auth` - How tokens are validated, `None` accepting any client.
+    /// * `limits` - The limits of the uploads.
+    ///
+    /// # Returns
+    ///
+    /// The user the client authenticated as, if its token names one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client is not authorized, once it has been
+    /// told so.
+    async fn handle_auth<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        auth: Option<&Auth>,
+        limits: &Limits,
+    ) -> Result<Option<String>> {
+        let unauthorized =
+            |reason: &str| error(ErrorKind::Unauthorized, reason);
+        let token = match Request::read(stream, limits).await {
+            Ok(Request::Auth { token }) => Ok(token),
+            Ok(_) => Err(unauthorized("Expected authentication")),
+            Err(protocol::Error::Io(e)) => return Err(e.into()),
+            Err(e) => Err(unauthorized(&e.to_string())),
+        };
+
+        let user = token.and_then(|token| match auth {
+            Some(_) if token.is_empty() => {
+                Err(unauthorized("Authentication required"))
+            }
+            Some(auth) => auth
+                .authenticate(&token)
+                .map_err(|e| unauthorized(&e.to_string())),
+            None => Ok(None),
+        });
+        match user {
+            Ok(user) => {
+                Self::respond(stream, Response::Ok).await.map(|()| user)
+            }
+            Err(e) => {
+                // The failed authentications are audited as such
+                logger::record("command", "auth");
+                Self::reject(stream, e).await.map(|()| None)
+            }
+        }
+    }
+
+    /// Tells the client why the handshake failed, closes the connection, and
+    /// returns the reason as an error.
+    async fn reject<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        rejection: anyhow::Error,
     ) -> Result<()> {
-        let mut command = [0; 10];
-        stream.read(&mut command).await?;
-        let command =
-            std::str::from_utf8(&command)?.trim_end_matches(char::from(0));
+        Self::respond(stream, Self::error_response(&rejection)).await?;
+        stream.shutdown().await?;
+        Err(anyhow!("Rejected client: {}", rejection))
+    }
 
-        match command {
-            "upload" => {
-                let files = Self::handle_upload(stream).await?;
-                store.store_files(files)?;
+    /// Tells the client why a request failed, if it did, then returns its
+    /// result, for the caller to send the response on success.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the request, once the client has been told so, or
+    /// an error if the response cannot be sent.
+    async fn report<S: AsyncRead + AsyncWrite + Unpin + Send, T>(
+        stream: &mut S,
+        result: Result<T>,
+    ) -> Result<T> {
+        match result {
+            Ok(value) => Ok(value),
+            Err(error) => {
+                Self::respond(stream, Self::error_response(&error)).await?;
+                Err(error)
             }
-            "download" => {
-                Self::handle_download(stream, store).await?;
+        }
+    }
+
+    /// Returns the response telling the client a request failed with an
+    /// error.
+    fn error_response(error: &anyhow::Error) -> Response {
+        Response::Error {
+            kind: kind_of(error),
+            message: error.to_string(),
+        }
+    }
+
+    /// Acknowledges an upload with its root hash, telling the clients which
+    /// negotiated it whether the upload was already stored, then sending
+    /// them the signature of the root hash.
+    async fn acknowledge<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        committed: Committed,
+        capabilities: Capabilities,
+    ) -> Result<()> {
+        let root_hash = committed.root_hash;
+        logger::record("root", root_hash.as_str());
+        if committed.already_stored {
+            logger::record("already_stored", true);
+        }
+        let response = match committed.already_stored {
+            true if capabilities.contains(Capabilities::STORED) => {
+                Response::AlreadyStored { root_hash }
+            }
+            _ => Response::Committed { root_hash },
+        };
+        Self::respond(stream, response).await?;
+        match committed.signature {
+            Some(signature) if capabilities.contains(Capabilities::SIGNED) => {
+                Self::respond(stream, signature.response()?).await
             }
-            _ => println!("Unknown command"),
+            _ => Ok(()),
         }
+    }
 
+    /// Sends a response to the client.
+    async fn respond<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        response: Response,
+    ) -> Result<()> {
+        stream.write_all(&response.encode()?).await?;
+        stream.flush().await?;
         Ok(())
     }
 
+    /// Returns the Merkle tree of an upload, checking that it has a file at
+    /// the index.
+    async fn get_tree(
+        store: &FileStore,
+        root_hash: &str,
+        index: usize,
+    ) -> Result<Arc<MerkleTree>> {
+        let tree = store.get_served_tree(root_hash).await?;
+        if index >= tree.leaves().len() {
+            return Err(error(
+                ErrorKind::NotFound,
+                format!("File {} of {} not found", index, root_hash),
+            ));
+        }
+        Ok(tree)
+    }
+
+    /// Reads the next request of a client, once authenticated.
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if the client closed the connection instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request cannot be read, once the client has
+    /// been told so if it is invalid.
+    async fn read_request<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        limits: &Limits,
+    ) -> Result<Option<Request>> {
+        match Request::read_next(stream, limits).await {
+            Ok(request) => Ok(request),
+            Err(protocol::Error::Io(e)) => Err(e.into()),
+            Err(e) => Self::report(stream, Err(from_protocol(e))).await,
+        }
+    }
+
+    /// Serves the request of a client, once authenticated, the contents of
+    /// files being compressed at the zstd level `compression` if the
+    /// connection is, and the replies being those of the `capabilities`
+    /// negotiated.
+    async fn handle_request<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: &mut S,
+        store: &FileStore,
+        request: Request,
+        compression: Option<i32>,
+        capabilities: Capabilities,
+    ) -> Result<()> {
+        match request {
+            Request::Hello { .. } | Request::Auth { .. } => {
+                let twice =
+                    error(ErrorKind::InvalidRequest, "Already authenticated");
+                Self::report(stream, Err(twice)).await
+            }
+            Request::Upload { files } => {
+                let committed =
+                    Self::report(stream, store.store_files(files).await)
+                        .await?;
+                // acknowledge the upload with the root hash of the stored
+                // files
+                Self::acknowledge(stream, committed, capabilities).await
+            }
+            Request::Put {
+                session,
+                index,
+                size,
+            } => {
+                Self::handle_put(
+                    stream,
+                    store,
+                    &session,
+                    index,
+                    size,
+                    compression,
+                )
+                .await
+            }
+            Request::Have { hashes } => {
+                let held =
+                    Self::report(stream, store.held_contents(&hashes).await)
+                        .await?;
+                Self::respond(stream, Response::Held { held }).await
+            }
+            Request::Commit { hashes } => {
+                // acknowledge the batch with the root hash of the stored files
+                let committed =
+                    Self::report(stream, store.commit_files(&hashes).await)
+                        .await?;
+                Self::acknowledge(stream, committed, capabilities).await
+            }
+            Request::Names { root_hash, names } => {
+                Self::report(stream, store.set_names(&root_hash, &names).await)
+                    .await?;
+                Self::respond(stream, Response::Ok).await
+            }
+            Request::Download { root_hash, file } => {
+                Self::handle_download(
+                    stream,
+                    store,
+                    &root_hash,
+                    file,
+                    compression,
+                )
+                .await
+            }
+            Request::Proof { root_hash, index } => {
+                Self::handle_proof(stream, store, &root_hash, index).await
+            }
+            Request::Range {
+                root_hash,
+                index,
+                offset,
+                len,
+            } => {
+                Self::handle_range(
+                    stream,
+                    store,
+                    &root_hash,
+                    index,
+                    offset,
+                    len,
+                    compression,
+                )
+                .await
+            }
+            Request::Delete { root_hash } => {
+                // Deletions are only accepted from authenticated clients when
+                // the server requires authentication, like every other
+                // request
+                let existed =
+                    Self::report(stream, store.delete_upload(&root_hash).await)
+                        .await?;
+                Self::respond(stream, Response::Deleted { existed }).await
+            }
+            Request::Exists { root_hash } => {
+                let exists =
+                    Self::report(stream, store.has_upload(&root_hash).await)
+                        .await?;
+                Self::respond(stream, Response::Exists { exists }).await
+            }
+            Request::Roots => {
+                let uploads =
+                    Self::report(stream, store.list_uploads().await).await?;
+                let uploads = uploads
+                    .into_iter()
+                    .map(|upload| StoredUpload {
+                        root_hash: upload.root_hash,
+                        files: upload.files as u64,
+                        size: upload.size,
+                    })
+                    .collect();
+                Self::respond(stream, Response::Uploads { uploads }).await
+            }
+            Request::Usage => {
+                let used = Self::report(stream, store.usage().await).await?;
+                let quota = store.quota();
+                Self::respond(stream, Response::Usage { used, quota }).await
+            }
+            Request::Retain { root_hash, secs } => {
+                let retention = Duration::from_secs(secs);
+                let retained = store.retain(&root_hash, retention).await;
+                Self::report(stream, retained).await?;
+                Self::respond(stream, Response::Ok).await
+            }
+        }
+    }
+
+    pub(crate) async fn handle_client<
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    >(
+        stream: &mut S,
+        store: &FileStore,
+        auth: Option<&Auth>,
+        timeouts: Timeouts,
+        compression: Option<i32>,
+        audit: Option<&AuditLog>,
+        mut stopping: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let limits = store.limits();
+        let signs = store.signs_roots();
+        let handshake = async {
+            let capabilities =
+                Self::handle_hello(stream, auth, limits, compression, signs)
+                    .await?;
+            let user = Self::handle_auth(stream, auth, limits).await?;
+            Ok((capabilities, user))
+        };
+        let (capabilities, user) =
+            within(timeouts.handshake, "the handshake", handshake).await?;
+        let compression = compression
+            .filter(|_| capabilities.contains(Capabilities::COMPRESSION));
+        if let Some(level) = compression {
+            logger::record("compression", level);
+        }
+        let store = match user {
+            Some(user) => {
+                logger::record("user", user.as_str());
+                &store.namespace(&user)?
+            }
+            None => store,
+        };
+
+        // Serve the requests of the connection until the client closes it. A
+        // connection kept ready by a client, still waiting for its request,
+        // is closed as soon as the server stops rather than waited for
+        let mut served = 0u64;
+        let result = loop {
+            let read = Self::read_request(stream, store.limits());
+            let request = tokio::select! {
+                request = within(timeouts.idle, "a request", read) => request,
+                _ = stopped(&mut stopping) => return Ok(()),
+            };
+            // The last request of the connection is recorded with it, and
+            // those before it on their own, once the next one is read
+            let request = match request {
+                Ok(Some(request)) => request,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            if served > 0 {
+                Self::served(audit);
+            }
+            served += 1;
+            logger::record("requests", served);
+            logger::record("command", request.command());
+            if let Some(root_hash) = root_hash(&request) {
+                logger::record("root", root_hash);
+            }
+            match &request {
+                Request::Put { index, .. }
+                | Request::Proof { index, .. }
+                | Request::Range { index, .. }
+                | Request::Download {
+                    file: FileRef::Index(index),
+                    ..
+                } => logger::record("index", *index),
+                Request::Download {
+                    file: FileRef::Name(name),
+                    ..
+                } => logger::record("file", name.as_str()),
+                _ => (),
+            }
+            let mut stream = Deadline::new(&mut *stream, timeouts.io);
+            let handled = Self::handle_request(
+                &mut stream,
+                store,
+                request,
+                compression,
+                capabilities,
+            )
+            .await;
+            if let Err(e) = handled {
+                break Err(e);
+            }
+        };
+
+        // Close the connection cleanly, which TLS clients expect before
+        // reading the reason of a failure
+        let shutdown = Deadline::new(stream, timeouts.io).shutdown().await;
+        result?;
+        Ok(shutdown?)
+    }
+
+    /// Records a request served on a connection, in the log and the audit
+    /// log, before the next request of the connection replaces its fields
+    /// in the span.
+    fn served(audit: Option<&AuditLog>) {
+        logger::record("outcome", "ok");
+        log::info!("Served request");
+        if let Some(audit) = audit {
+            audit.record(&logger::fields(), None);
+        }
+        logger::forget(&[
+            "command",
+            "root",
+            "index",
+            "file",
+            "already_stored",
+            "outcome",
+        ]);
+    }
+
+    /// Serves clients until asked to stop with SIGINT, e.g. Ctrl-C, or
+    /// SIGTERM. The server then stops accepting connections, and waits for
+    /// those being served to close, for up to the shutdown timeout, so that
+    /// the uploads and downloads under way complete. Asking again stops it
+    /// at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the addresses cannot be listened on, or the
+    /// signals cannot be handled.
     pub async fn run(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.address).await?;
+        let mut listeners = vec![];
+        for bind in &self.listeners {
+            let listener =
+                Listener::bind(&bind.address, self.unix_socket_mode).await?;
+            match bind.http {
+                true => log::info!(
+                    "Serving the HTTP API on {}",
+                    listener.local_addr()?
+                ),
+                false => log::info!("Listening on {}", listener.local_addr()?),
+            }
+            let tls = bind.tls.clone().unwrap_or_else(|| self.tls.clone());
+            listeners.push((listener, bind.http, tls));
+        }
+        let mut signals = Signals::new()?;
+        let (stop, stopping) = watch::channel(false);
+        tokio::spawn(async move {
+            signals.recv().await;
+            systemd::notify("STOPPING=1");
+            log::info!("Stopping once the connections being served close");
+            let _ = stop.send(true);
+            signals.recv().await;
+            log::warn!("Stopping at once");
+            std::process::exit(1);
+        });
+        #[cfg(unix)]
+        tokio::spawn(toggle_read_only(self.read_only.clone())?);
+        #[cfg(unix)]
+        tokio::spawn(reload_on_hangup(
+            self.auth.clone(),
+            self.tokens_file.clone(),
+        )?);
+        if self.read_only.load(Ordering::Relaxed) {
+            log::info!("Read-only, refusing the uploads and deletions");
+        }
+        // The backend is shared by every connection, which only start once
+        // the operations a crash interrupted are repaired
+        let backend = self.backend()?;
+        self.open_wal(&backend).await?;
+        if let Some(interval) = self.gc_interval {
+            let store = self.store(&backend)?;
+            tokio::spawn(gc::run(store, interval, self.gc_grace));
+        }
+        if let Some(interval) = self.scrub_interval {
+            let store = self.store(&backend)?;
+            let quarantine = self.scrub_quarantine;
+            tokio::spawn(scrub::run(store, interval, quarantine));
+        }
+        if let Some(replicator) = &self.replicator {
+            let store = self.store(&backend)?;
+            tokio::spawn(replicator.clone().run(store));
+        }
+        if let Some(webhooks) = &self.webhooks {
+            tokio::spawn(webhooks.clone().run());
+        }
+        let store = self.store(&backend)?;
+        tokio::spawn(expiry::run(store, self.expiry_interval));
+        let mut served: Vec<Pin<Box<dyn Future<Output = Result<()>> + '_>>> =
+            vec![];
+        for (listener, http, tls) in listeners {
+            let stopping = stopping.clone();
+            let serve = self.serve(listener, &backend, http, tls, stopping);
+            served.push(Box::pin(serve));
+        }
+        served.push(Box::pin(async {
+            match &self.admin_socket {
+                Some(socket) => {
+                    let admin = Admin {
+                        store: self.store(&backend)?,
+                        auth: self.auth.clone(),
+                        tokens_file: self.tokens_file.clone(),
+                        gc_grace: self.gc_grace,
+                        read_only: self.read_only.clone(),
+                    };
+                    admin.serve(socket, stopping.clone()).await
+                }
+                None => Ok(()),
+            }
+        }));
+        systemd::notify("READY=1");
+        try_join_all(served).await
+    }
+
+    /// Accepts connections, serving each in its own task, until the server
+    /// is asked to stop, and then waits for them to close.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - The listener to accept connections from.
+    /// * `backend` - Where the uploads are kept.
+    /// * `http` - Whether the connections speak HTTP rather than the TCP
+    ///   protocol.
+    /// * `tls` - The TLS acceptor of the connections, if they are accepted
+    ///   over TLS.
+    /// * `stopping` - Changed once the server is asked to stop.
+    async fn serve(
+        &self,
+        listener: Listener,
+        backend: &Arc<dyn StorageBackend>,
+        http: bool,
+        tls: Option<TlsAcceptor>,
+        mut stopping: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let mut connections = JoinSet::new();
         loop {
-            let (mut socket, _) = listener.accept().await?;
-            let store = store::FileStore::new(PathBuf::from("server_store"))?;
-            tokio::spawn(async move {
-                Self::handle_client(&mut socket, &store)
+            // The connections beyond the limit wait in the backlog of the
+            // listener, for one being served to close
+            let slot = match &self.connections {
+                Some(slots) => {
+                    if slots.available_permits() == 0 {
+                        log::warn!(
+                            "Serving as many connections as allowed, \
+                             waiting for one to close"
+                        );
+                    }
+                    tokio::select! {
+                        slot = slots.clone().acquire_owned() => Some(slot?),
+                        _ = stopping.changed() => break,
+                    }
+                }
+                None => None,
+            };
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = stopping.changed() => break,
+            };
+            // Forget the connections closed since
+            while connections.try_join_next().is_some() {}
+            let (socket, peer) = match accepted {
+                Ok(accepted) => accepted,
+                // e.g. out of file descriptors, which connections closing
+                // will free: wait for them rather than stop serving
+                Err(error) => {
+                    log::error!("Could not accept a connection: {}", error);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            // Close the connections over the limits before reading anything
+            let permit = match (&self.limiter, peer.ip()) {
+                (Some(limiter), Some(ip)) => match limiter.admit(ip) {
+                    Ok(permit) => Some(permit),
+                    Err(error) => {
+                        log::warn!(
+                            "Rejected connection from {}: {}",
+                            peer,
+                            error
+                        );
+                        continue;
+                    }
+                },
+                _ => None,
+            };
+            let store = self.store(backend)?;
+            let tls = tls.clone();
+            let auth = self.auth.as_ref().map(|a| a.read().unwrap().clone());
+            let stopping = stopping.clone();
+            let timeouts = self.timeouts;
+            let audit = self.audit.clone();
+            let protocol = match http {
+                true => Protocol::Http {
+                    started: self.started,
+                },
+                false => Protocol::Tcp {
+                    compression: self.compression,
+                },
+            };
+            let span = vec![
+                (
+                    "connection",
+                    self.accepted.fetch_add(1, Ordering::Relaxed).into(),
+                ),
+                ("peer", peer.to_string().into()),
+                ("protocol", if http { "http" } else { "tcp" }.into()),
+            ];
+            connections.spawn(logger::in_span(span, async move {
+                log::debug!("Accepted connection");
+                let started = Instant::now();
+                let auth = auth.as_deref();
+                let result = match tls {
+                    Some(acceptor) => {
+                        let accept =
+                            async { Ok(acceptor.accept(socket).await?) };
+                        match within(
+                            timeouts.handshake,
+                            "the TLS handshake",
+                            accept,
+                        )
+                        .await
+                        {
+                            Ok(stream) => {
+                                Self::serve_connection(
+                                    stream,
+                                    &store,
+                                    auth,
+                                    protocol,
+                                    timeouts,
+                                    audit.as_deref(),
+                                    stopping,
+                                )
+                                .await
+                            }
+                            Err(error) => Err(error),
+                        }
+                    }
+                    None => {
+                        Self::serve_connection(
+                            socket,
+                            &store,
+                            auth,
+                            protocol,
+                            timeouts,
+                            audit.as_deref(),
+                            stopping,
+                        )
+                        .await
+                    }
+                };
+                let duration = started.elapsed().as_millis() as u64;
+                logger::record("duration_ms", duration);
+                match &result {
+                    Ok(()) => {
+                        logger::record("outcome", "ok");
+                        log::info!("Served connection");
+                    }
+                    Err(error) => {
+                        logger::record("outcome", "error");
+                        log::warn!("Connection failed: {:#}", error);
+                    }
+                }
+                if let Some(audit) = audit {
+                    audit.record(&logger::fields(), result.err().as_ref());
+                }
+                drop(permit);
+                drop(slot);
+            }));
+        }
+        drop(listener);
+        self.drain(connections).await;
+        Ok(())
+    }
+
+    /// Serves a connection with the HTTP API or the TCP protocol, recording
+    /// the bytes read from and written to it in its span.
+    async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        stream: S,
+        store: &FileStore,
+        auth: Option<&Auth>,
+        protocol: Protocol,
+        timeouts: Timeouts,
+        audit: Option<&AuditLog>,
+        stopping: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let mut stream = Counted::new(stream);
+        let result = match protocol {
+            Protocol::Http { started } => {
+                let stream = &mut stream;
+                http::handle_client(stream, store, auth, started, timeouts)
                     .await
-                    .unwrap_or_else(|error| eprintln!("{:?}", error));
+            }
+            Protocol::Tcp { compression } => {
+                let stream = &mut stream;
+                Self::handle_client(
+                    stream,
+                    store,
+                    auth,
+                    timeouts,
+                    compression,
+                    audit,
+                    stopping,
+                )
+                .await
+            }
+        };
+        logger::record("bytes_read", stream.read);
+        logger::record("bytes_written", stream.written);
+        result
+    }
+
+    /// Waits for the connections being served to close, for up to the
+    /// shutdown timeout, and closes those still open then.
+    async fn drain(&self, mut connections: JoinSet<()>) {
+        while connections.try_join_next().is_some() {}
+        if connections.is_empty() {
+            return;
+        }
+        log::info!("Waiting for {} connections to close", connections.len());
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            log::warn!("Closing {} connections still open", connections.len());
+            // Their uploads are left to resume, or to collect
+            connections.shutdown().await;
+        }
+    }
+}
+
+/// Awaits futures concurrently, failing as soon as one of them fails.
+async fn try_join_all(
+    mut futures: Vec<Pin<Box<dyn Future<Output = Result<()>> + '_>>>,
+) -> Result<()> {
+    std::future::poll_fn(|cx| {
+        let mut i = 0;
+        while i < futures.len() {
+            match futures[i].as_mut().poll(cx) {
+                Poll::Ready(Ok(())) => drop(futures.swap_remove(i)),
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => i += 1,
+            }
+        }
+        match futures.is_empty() {
+            true => Poll::Ready(Ok(())),
+            false => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Returns the root hash of the upload a request is about, if it is about
+/// one already stored.
+fn root_hash(request: &Request) -> Option<&str> {
+    match request {
+        Request::Names { root_hash, .. }
+        | Request::Download { root_hash, .. }
+        | Request::Proof { root_hash, .. }
+        | Request::Range { root_hash, .. }
+        | Request::Delete { root_hash }
+        | Request::Exists { root_hash }
+        | Request::Retain { root_hash, .. } => Some(root_hash),
+        _ => None,
+    }
+}
+
+/// A stream counting the bytes read from it and written to it.
+struct Counted<S> {
+    stream: S,
+    read: u64,
+    written: u64,
+}
+
+impl<S> Counted<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream,
+            read: 0,
+            written: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.stream).poll_read(cx, buf);
+        self.read += (buf.filled().len() - before) as u64;
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = polled {
+            self.written += written as u64;
+        }
+        polled
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Waits for the server to be asked to stop, forever if it cannot be.
+async fn stopped(stopping: &mut watch::Receiver<bool>) {
+    if stopping.changed().await.is_err() {
+        std::future::pending().await
+    }
+}
+
+/// Makes the server read-only on SIGUSR1 and writable again on SIGUSR2, e.g.
+/// for the time of a migration.
+///
+/// # Returns
+///
+/// The task toggling the flag, for as long as the server runs.
+///
+/// # Errors
+///
+/// Returns an error if the signals cannot be handled.
+#[cfg(unix)]
+fn toggle_read_only(
+    read_only: Arc<AtomicBool>,
+) -> Result<impl std::future::Future<Output = ()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut enter = signal(SignalKind::user_defined1())?;
+    let mut leave = signal(SignalKind::user_defined2())?;
+    Ok(async move {
+        loop {
+            let enabled = tokio::select! {
+                _ = enter.recv() => true,
+                _ = leave.recv() => false,
+            };
+            set_read_only(&read_only, enabled);
+        }
+    })
+}
+
+/// Reads the tokens file again on SIGHUP, e.g. from `systemctl reload`, for
+/// the connections accepted from then on to be authenticated with the tokens
+/// it holds, telling systemd while it does.
+///
+/// # Errors
+///
+/// Returns an error if the signal cannot be handled.
+#[cfg(unix)]
+fn reload_on_hangup(
+    auth: Option<Arc<RwLock<Arc<Auth>>>>,
+    tokens_file: Option<PathBuf>,
+) -> Result<impl std::future::Future<Output = ()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(async move {
+        loop {
+            hangup.recv().await;
+            let (Some(auth), Some(path)) = (&auth, &tokens_file) else {
+                log::info!("Nothing to reload without a tokens file");
+                continue;
+            };
+            systemd::notify("RELOADING=1");
+            match auth::reload(auth, path) {
+                Ok(()) => {
+                    log::info!("Reloaded the tokens of {}", path.display())
+                }
+                Err(e) => log::error!("Could not reload the tokens: {:#}", e),
+            }
+            systemd::notify("READY=1");
+        }
+    })
+}
+
+/// Makes the server read-only, or writable again, logging the change.
+///
+/// # Arguments
+///
+/// * `read_only` - The flag the stores of the server share.
+/// * `enabled` - Whether the server is to be read-only.
+pub fn set_read_only(read_only: &AtomicBool, enabled: bool) {
+    if read_only.swap(enabled, Ordering::Relaxed) != enabled {
+        match enabled {
+            true => log::info!("Read-only, refusing the uploads and deletions"),
+            false => log::info!("Writable, accepting uploads again"),
+        }
+    }
+}
+
+/// The signals asking the server to stop.
+struct Signals {
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    /// Handles the signals, rather than letting them kill the server.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the signals cannot be handled.
+    fn new() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(Self {
+                interrupt: signal(SignalKind::interrupt())?,
+                terminate: signal(SignalKind::terminate())?,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(Self {})
+    }
+
+    /// Waits for the next signal asking the server to stop.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.interrupt.recv() => {}
+            _ = self.terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    /// Sends a request to the server.
+    async fn send(client: &mut DuplexStream, request: Request) {
+        client.write_all(&request.encode().unwrap()).await.unwrap();
+    }
+
+    /// Connects a client to a server over an in-memory stream, and sends
+    /// the handshake of the given version and capabilities.
+    ///
+    /// # Returns
+    ///
+    /// Returns the end of the client, and the task serving the connection.
+    async fn connect(
+        store: &FileStore,
+        version: u32,
+        capabilities: Capabilities,
+    ) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let store = store.clone();
+        let served = tokio::spawn(async move {
+            let (_, stopping) = watch::channel(false);
+            Server::handle_client(
+                &mut server,
+                &store,
+                None,
+                Timeouts::default(),
+                None,
+                None,
+                stopping,
+            )
+            .await
+        });
+        send(
+            &mut client,
+            Request::Hello {
+                version,
+                capabilities,
+            },
+        )
+        .await;
+        (client, served)
+    }
+
+    /// Connects a client to a server, and authenticates it.
+    async fn authenticate(
+        store: &FileStore,
+    ) -> (DuplexStream, JoinHandle<Result<()>>) {
+        authenticate_with(store, Capabilities::CHUNKING).await
+    }
+
+    /// Connects a client with the given capabilities to a server, and
+    /// authenticates it.
+    async fn authenticate_with(
+        store: &FileStore,
+        capabilities: Capabilities,
+    ) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (mut client, served) = connect(store, VERSION, capabilities).await;
+        let hello = Response::read(&mut client).await.unwrap();
+        assert!(matches!(
+            hello,
+            Response::Hello {
+                version: VERSION,
+                ..
+            }
+        ));
+        let token = String::new();
+        send(&mut client, Request::Auth { token }).await;
+        assert_eq!(Response::read(&mut client).await.unwrap(), Response::Ok);
+        (client, served)
+    }
+
+    /// Starts sending a file of an upload session, and returns the offset
+    /// the server resumes it from.
+    async fn put(client: &mut DuplexStream, index: usize, size: u64) -> u64 {
+        let session = "ab".repeat(32);
+        send(
+            client,
+            Request::Put {
+                session,
+                index,
+                size,
+            },
+        )
+        .await;
+        match Response::read(client).await.unwrap() {
+            Response::Resume { offset } => offset,
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let dir = std::env::temp_dir().join("file-guardian-test-server1");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+
+        // A client older than the server is told why it is rejected
+        let (mut client, served) =
+            connect(&store, MIN_VERSION - 1, Capabilities::CHUNKING).await;
+        match Response::read(&mut client).await.unwrap() {
+            Response::Error { kind, message } => {
+                assert_eq!(kind, ErrorKind::InvalidRequest);
+                assert!(message.contains("Unsupported protocol version"));
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        assert!(served.await.unwrap().is_err());
+
+        // A newer client speaks the version of the server
+        let (mut client, served) =
+            connect(&store, VERSION + 1, Capabilities::CHUNKING).await;
+        let hello = Response::read(&mut client).await.unwrap();
+        assert!(matches!(
+            hello,
+            Response::Hello {
+                version: VERSION,
+                ..
+            }
+        ));
+        drop(client);
+        assert!(served.await.unwrap().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_upload() {
+        let dir = std::env::temp_dir().join("file-guardian-test-server2");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let file = b"hello world";
+
+        // The bytes received before the connection drops are kept
+        let (mut client, served) = authenticate(&store).await;
+        assert_eq!(put(&mut client, 0, 11).await, 0);
+        client.write_all(&file[..4]).await.unwrap();
+        drop(client);
+        assert!(served.await.unwrap().is_err());
+
+        // and the upload resumes from them
+        let (mut client, served) = authenticate(&store).await;
+        assert_eq!(put(&mut client, 0, 11).await, 4);
+        client.write_all(&file[4..]).await.unwrap();
+        let hash = hex::encode(Sha256::digest(file));
+        let staged = Response::read(&mut client).await.unwrap();
+        assert_eq!(staged, Response::Staged { hash: hash.clone() });
+        drop(client);
+        served.await.unwrap().unwrap();
+
+        // A file that got smaller than the bytes received is sent again
+        let (mut client, served) = authenticate(&store).await;
+        assert_eq!(put(&mut client, 1, 11).await, 0);
+        client.write_all(&file[..8]).await.unwrap();
+        drop(client);
+        assert!(served.await.unwrap().is_err());
+        let (mut client, served) = authenticate(&store).await;
+        assert_eq!(put(&mut client, 1, 5).await, 0);
+        client.write_all(&file[..5]).await.unwrap();
+        let smaller = hex::encode(Sha256::digest(&file[..5]));
+        let staged = Response::read(&mut client).await.unwrap();
+        assert_eq!(
+            staged,
+            Response::Staged {
+                hash: smaller.clone()
+            }
+        );
+        drop(client);
+        served.await.unwrap().unwrap();
+
+        // The staged files are committed and downloaded, with their proof
+        let (mut client, served) = authenticate(&store).await;
+        let hashes = vec![hash, smaller];
+        let commit = Request::Commit { hashes };
+        send(&mut client, commit.clone()).await;
+        let root_hash = match Response::read(&mut client).await.unwrap() {
+            Response::Committed { root_hash } => root_hash,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        drop(client);
+        served.await.unwrap().unwrap();
+
+        // Committing them again, e.g. on a retry, stores nothing again, which
+        // only the clients with the capability are told
+        let stored = Capabilities::CHUNKING.union(Capabilities::STORED);
+        let (mut client, served) = authenticate_with(&store, stored).await;
+        send(&mut client, commit.clone()).await;
+        assert_eq!(
+            Response::read(&mut client).await.unwrap(),
+            Response::AlreadyStored {
+                root_hash: root_hash.clone()
+            }
+        );
+        drop(client);
+        served.await.unwrap().unwrap();
+        // and its signature to those asking for it, once the server signs
+        let identity = Arc::new(Identity::from_seed(&[7; 32]).unwrap());
+        let signed = store.clone().with_identity(identity.clone());
+        let capabilities = Capabilities::CHUNKING.union(Capabilities::SIGNED);
+        let (mut client, served) =
+            authenticate_with(&signed, capabilities).await;
+        send(&mut client, commit.clone()).await;
+        assert_eq!(
+            Response::read(&mut client).await.unwrap(),
+            Response::Committed {
+                root_hash: root_hash.clone()
+            }
+        );
+        let signature = identity.sign(&root_hash).unwrap();
+        assert_eq!(
+            Response::read(&mut client).await.unwrap(),
+            signature.response().unwrap()
+        );
+        drop(client);
+        served.await.unwrap().unwrap();
+        let (mut client, served) = authenticate(&store).await;
+        send(&mut client, commit).await;
+        assert_eq!(
+            Response::read(&mut client).await.unwrap(),
+            Response::Committed {
+                root_hash: root_hash.clone()
+            }
+        );
+        drop(client);
+        served.await.unwrap().unwrap();
+        let (mut client, served) = authenticate(&store).await;
+        let file = FileRef::Index(1);
+        send(&mut client, Request::Download { root_hash, file }).await;
+        let response = Response::read(&mut client).await.unwrap();
+        assert_eq!(response, Response::File { index: 1, size: 5 });
+        let mut downloaded = [0; 5];
+        client.read_exact(&mut downloaded).await.unwrap();
+        assert_eq!(&downloaded, b"hello");
+        let proof = Response::read(&mut client).await.unwrap();
+        assert!(matches!(proof, Response::Proof { .. }));
+        drop(client);
+        served.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wire_compression() {
+        let dir = std::env::temp_dir().join("file-guardian-test-server6");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let file = b"2024-01-01 INFO request served\n".repeat(10_000);
+        let level = compression::DEFAULT_LEVEL;
+
+        // Connects a client asking for compression, to a server offering it
+        let connect = || async {
+            let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+            let store = store.clone();
+            let served = tokio::spawn(async move {
+                let (_, stopping) = watch::channel(false);
+                let timeouts = Timeouts::default();
+                Server::handle_client(
+                    &mut server,
+                    &store,
+                    None,
+                    timeouts,
+                    Some(level),
+                    None,
+                    stopping,
+                )
+                .await
             });
+            let capabilities =
+                Capabilities::CHUNKING.union(Capabilities::COMPRESSION);
+            let version = VERSION;
+            send(
+                &mut client,
+                Request::Hello {
+                    version,
+                    capabilities,
+                },
+            )
+            .await;
+            match Response::read(&mut client).await.unwrap() {
+                Response::Hello { capabilities, .. } => {
+                    assert!(capabilities.contains(Capabilities::COMPRESSION))
+                }
+                response => panic!("Unexpected response {:?}", response),
+            }
+            let token = String::new();
+            send(&mut client, Request::Auth { token }).await;
+            assert_eq!(
+                Response::read(&mut client).await.unwrap(),
+                Response::Ok
+            );
+            (client, served)
+        };
+
+        // The file is received as frames
+        let (mut client, served) = connect().await;
+        assert_eq!(put(&mut client, 0, file.len() as u64).await, 0);
+        let mut sent = 0;
+        for chunk in file.chunks(64 * 1024) {
+            let frame = compression::encode_frame(chunk, level);
+            sent += frame.len();
+            client.write_all(&frame).await.unwrap();
+        }
+        assert!(sent < file.len() / 10);
+        let hash = hex::encode(Sha256::digest(&file));
+        let staged = Response::read(&mut client).await.unwrap();
+        assert_eq!(staged, Response::Staged { hash: hash.clone() });
+        drop(client);
+        served.await.unwrap().unwrap();
+        let (mut client, served) = connect().await;
+        send(&mut client, Request::Commit { hashes: vec![hash] }).await;
+        let root_hash = match Response::read(&mut client).await.unwrap() {
+            Response::Committed { root_hash } => root_hash,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        drop(client);
+        served.await.unwrap().unwrap();
+
+        // and sent as frames, whole or in ranges
+        let (mut client, served) = connect().await;
+        let download = Request::Download {
+            root_hash: root_hash.clone(),
+            file: FileRef::Index(0),
+        };
+        send(&mut client, download).await;
+        let response = Response::read(&mut client).await.unwrap();
+        let size = file.len() as u64;
+        assert_eq!(response, Response::File { index: 0, size });
+        let mut downloaded = vec![0; file.len()];
+        let mut len = 0;
+        while len < file.len() {
+            let frame =
+                compression::read_frame(&mut client, &mut downloaded[len..]);
+            len += frame.await.unwrap();
+        }
+        assert_eq!(downloaded, file);
+        let proof = Response::read(&mut client).await.unwrap();
+        assert!(matches!(proof, Response::Proof { .. }));
+        drop(client);
+        served.await.unwrap().unwrap();
+        let (mut client, served) = connect().await;
+        let range = Request::Range {
+            root_hash,
+            index: 0,
+            offset: 10,
+            len: 100,
+        };
+        send(&mut client, range).await;
+        let response = Response::read(&mut client).await.unwrap();
+        assert_eq!(response, Response::Range { len: 100 });
+        let mut range = [0; 100];
+        let frame = compression::read_frame(&mut client, &mut range);
+        assert_eq!(frame.await.unwrap(), 100);
+        assert_eq!(range, file[10..110]);
+        drop(client);
+        served.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let dir = std::env::temp_dir().join("file-guardian-test-server3");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap().with_quota(4);
+
+        // The client is told why a request failed
+        let (mut client, served) = authenticate(&store).await;
+        let root_hash = "ab".repeat(32);
+        let file = FileRef::Index(0);
+        send(&mut client, Request::Download { root_hash, file }).await;
+        match Response::read(&mut client).await.unwrap() {
+            Response::Error { kind, .. } => {
+                assert_eq!(kind, ErrorKind::NotFound)
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        assert!(served.await.unwrap().is_err());
+
+        // even before a file is sent
+        let (mut client, served) = authenticate(&store).await;
+        let (session, index, size) = ("ab".repeat(32), 0, 5);
+        send(
+            &mut client,
+            Request::Put {
+                session,
+                index,
+                size,
+            },
+        )
+        .await;
+        match Response::read(&mut client).await.unwrap() {
+            Response::Error { kind, .. } => {
+                assert_eq!(kind, ErrorKind::QuotaExceeded)
+            }
+            response => panic!("Unexpected response {:?}", response),
         }
+        assert!(served.await.unwrap().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_download() {
+        let dir = std::env::temp_dir().join("file-guardian-test-server7");
+        let _ = std::fs::remove_dir_all(&dir);
+        let day = Duration::from_secs(24 * 60 * 60);
+        let store = FileStore::new(&dir).unwrap().with_retention(day);
+        let root_hash = store
+            .store_files(vec![b"hello".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+        store.retain(&root_hash, Duration::ZERO).await.unwrap();
+
+        // An expired upload is not served, though not deleted yet
+        for request in [
+            Request::Download {
+                root_hash: root_hash.clone(),
+                file: FileRef::Index(0),
+            },
+            Request::Range {
+                root_hash: root_hash.clone(),
+                index: 0,
+                offset: 0,
+                len: 5,
+            },
+        ] {
+            let (mut client, served) = authenticate(&store).await;
+            send(&mut client, request).await;
+            match Response::read(&mut client).await.unwrap() {
+                Response::Error { kind, .. } => {
+                    assert_eq!(kind, ErrorKind::NotFound)
+                }
+                response => panic!("Unexpected response {:?}", response),
+            }
+            assert!(served.await.unwrap().is_err());
+        }
+        store.get_tree(&root_hash).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop() {
+        let dir = std::env::temp_dir().join("file-guardian-test-server4");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let (stop, stopping) = watch::channel(false);
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(async move {
+            Server::handle_client(
+                &mut server,
+                &store,
+                None,
+                Timeouts::default(),
+                None,
+                None,
+                stopping,
+            )
+            .await
+        });
+        let capabilities = Capabilities::CHUNKING;
+        send(
+            &mut client,
+            Request::Hello {
+                version: VERSION,
+                capabilities,
+            },
+        )
+        .await;
+        Response::read(&mut client).await.unwrap();
+        let token = String::new();
+        send(&mut client, Request::Auth { token }).await;
+        assert_eq!(Response::read(&mut client).await.unwrap(), Response::Ok);
+
+        // A connection kept ready, without a request, is closed once the
+        // server is asked to stop
+        stop.send(true).unwrap();
+        assert!(served.await.unwrap().is_ok());
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        let dir = std::env::temp_dir().join("file-guardian-test-server5");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let timeouts = Timeouts {
+            handshake: Duration::from_millis(100),
+            idle: Duration::from_millis(200),
+            io: Duration::from_millis(100),
+        };
+        let serve = |server| {
+            let store = store.clone();
+            let (_, stopping) = watch::channel(false);
+            tokio::spawn(async move {
+                let mut server = server;
+                Server::handle_client(
+                    &mut server,
+                    &store,
+                    None,
+                    timeouts,
+                    None,
+                    None,
+                    stopping,
+                )
+                .await
+            })
+        };
+
+        // A client connecting and sending nothing is not waited for
+        let (_client, server) = tokio::io::duplex(64 * 1024);
+        let error = serve(server).await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("waiting for the handshake"));
+
+        // nor is a client authenticated without a request, for long
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let served = serve(server);
+        let capabilities = Capabilities::CHUNKING;
+        let version = VERSION;
+        send(
+            &mut client,
+            Request::Hello {
+                version,
+                capabilities,
+            },
+        )
+        .await;
+        Response::read(&mut client).await.unwrap();
+        send(
+            &mut client,
+            Request::Auth {
+                token: String::new(),
+            },
+        )
+        .await;
+        assert_eq!(Response::read(&mut client).await.unwrap(), Response::Ok);
+        let error = served.await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("waiting for a request"));
+
+        // A connection serves several requests, each waited for as long
+        let capabilities = Capabilities::CHUNKING.union(Capabilities::USAGE);
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let served = serve(server);
+        send(
+            &mut client,
+            Request::Hello {
+                version,
+                capabilities,
+            },
+        )
+        .await;
+        let hello = Response::read(&mut client).await.unwrap();
+        let Response::Hello { capabilities, .. } = hello else {
+            panic!("Unexpected response {:?}", hello);
+        };
+        assert!(capabilities.contains(Capabilities::REQUESTS));
+        let token = String::new();
+        send(&mut client, Request::Auth { token }).await;
+        assert_eq!(Response::read(&mut client).await.unwrap(), Response::Ok);
+        send(&mut client, Request::Roots).await;
+        assert_eq!(
+            Response::read(&mut client).await.unwrap(),
+            Response::Uploads { uploads: vec![] }
+        );
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        send(&mut client, Request::Usage).await;
+        assert_eq!(
+            Response::read(&mut client).await.unwrap(),
+            Response::Usage {
+                used: 0,
+                quota: None
+            }
+        );
+        assert!(!served.is_finished());
+        let error = served.await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("waiting for a request"));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
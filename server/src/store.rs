@@ -1,88 +1,2521 @@
+use crate::backend::{
+    corrupt, is_shard, sharded, BlobReader, BlobWriter, LocalBackend,
+    StorageBackend,
+};
+use crate::cache::{Reservation, TreeCache, UsageCache};
+use crate::error::{error, from_protocol, ErrorKind};
+use crate::gc::GcReport;
+use crate::identity::{Identity, RootSignature};
+use crate::replica::Replicator;
+use crate::scrub::ScrubReport;
+use crate::wal::{Op, OpKind, Wal};
+use crate::webhook::{Event, Webhooks};
 use anyhow::{anyhow, Result};
 use merkle_tree::MerkleTree;
+use protocol::Limits;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::{BTreeSet, HashMap},
     fs::{self, File},
-    io::Write,
+    io,
+    ops::Range,
     path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
 };
+use tokio::io::AsyncWrite;
+
+/// The directory, under the root directory, of the files uploaded one by one
+/// and waiting to be committed as a batch.
+pub const STAGING_DIR: &str = "staging";
+
+/// The directory, next to the uploads, of the contents of their files, each
+/// named by its SHA-256 and stored once however many uploads hold it, along
+/// with the number of uploads referencing it.
+pub const BLOBS_DIR: &str = "blobs";
+
+/// Held while the references to a content are counted, so that a content is
+/// never deleted as another upload takes a reference to it.
+static REFS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// The directories of the uploads being stored by the process, and the
+/// contents they reference, not yet counted from the trees of the uploads by
+/// the garbage collector.
+static STORING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// The number of health checks made by the process, naming the files they
+/// write so that concurrent checks do not remove each other's.
+static CHECKS: AtomicU64 = AtomicU64::new(0);
+
+/// The blob of an upload holding the names of its files.
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// The blob of an upload holding when it expires, in seconds since the Unix
+/// epoch, if it does.
+const EXPIRY_FILE: &str = "expires";
+
+/// The blob of an upload holding the signature of its root hash by the
+/// server, if the server signs them.
+const SIGNATURE_FILE: &str = "signature.json";
+
+/// The suffix of the corrupt files moved into their upload by a scrub,
+/// after the index of the file.
+const QUARANTINE_SUFFIX: &str = ".corrupt";
+
+/// The version of the format of the manifests of uploads.
+const MANIFEST_VERSION: u32 = 1;
+
+/// The longest name of a namespace, shorter than a root hash so that a
+/// namespace is never mistaken for an upload.
+const MAX_NAMESPACE_LEN: usize = 63;
+
+/// Returns whether a name, e.g. of a user, can name a namespace of the
+/// store: letters, digits, `-` and `_`, other than the staging and blobs
+/// directories, and than two hex digits, which name the shards of the
+/// uploads.
+pub fn is_namespace(name: &str) -> bool {
+    (1..=MAX_NAMESPACE_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        && name != STAGING_DIR
+        && name != BLOBS_DIR
+        && !is_shard(&name.to_lowercase())
+}
+
+/// The names of the files of an upload, sent by the client once the upload
+/// is committed, so that the files can be told apart, and downloaded by
+/// name, without the records of the client.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// The names of the files, in the order of the leaves.
+    files: Vec<String>,
+}
+
+/// Registers an upload being stored, and the contents it references, in
+/// [`STORING`] until dropped, once its tree is stored or it failed to be.
+struct Storing(Vec<PathBuf>);
+
+impl Storing {
+    /// Registers the directory of an upload being stored.
+    fn new(dir: PathBuf) -> Self {
+        STORING.lock().unwrap().push(dir.clone());
+        Self(vec![dir])
+    }
+
+    /// Registers a content the upload references.
+    fn add(&mut self, path: PathBuf) {
+        STORING.lock().unwrap().push(path.clone());
+        self.0.push(path);
+    }
+}
+
+impl Drop for Storing {
+    fn drop(&mut self) {
+        let mut storing = STORING.lock().unwrap();
+        for path in &self.0 {
+            if let Some(index) = storing.iter().position(|p| p == path) {
+                storing.swap_remove(index);
+            }
+        }
+    }
+}
+
+/// An upload held by a file store.
+#[derive(Debug, PartialEq)]
+pub struct StoredUpload {
+    /// The root hash of the upload, as a hex string.
+    pub root_hash: String,
+    /// The number of files of the upload.
+    pub files: usize,
+    /// The total size of the files, in bytes.
+    pub size: u64,
+}
+
+/// An upload committed to a file store.
+#[derive(Debug, PartialEq)]
+pub struct Committed {
+    /// The root hash of the upload, as a hex string.
+    pub root_hash: String,
+    /// Whether the store already held the upload, e.g. as the client
+    /// retried, in which case nothing was written again.
+    pub already_stored: bool,
+    /// The signature of the root hash by the server, if it signs them.
+    pub signature: Option<RootSignature>,
+}
 
 /// A struct that represents a file store.
 #[derive(Clone)]
 pub struct FileStore {
+    /// The directory of the files being uploaded, and of the uploads unless
+    /// they are kept by another backend.
     root_dir: PathBuf,
+    /// Where the uploads are kept.
+    backend: Arc<dyn StorageBackend>,
+    /// The limits of the files and uploads the store accepts.
+    limits: Limits,
+    /// The most bytes the uploads of the store may hold, if limited.
+    quota: Option<u64>,
+    /// The trees of the uploads read last, if cached.
+    cache: Option<Arc<TreeCache>>,
+    /// The bytes held by the uploads of the store and of its namespaces.
+    usage_cache: Arc<UsageCache>,
+    /// How long the uploads are kept unless given another retention, if
+    /// they expire.
+    retention: Option<Duration>,
+    /// The name of the namespace of the store, if it is one.
+    namespace: Option<String>,
+    /// Where the uploads are replicated to, if they are.
+    replicator: Option<Arc<Replicator>>,
+    /// What is told of the uploads stored, if anything is.
+    webhooks: Option<Arc<Webhooks>>,
+    /// The key the root hashes of the uploads are signed with, if they are.
+    identity: Option<Arc<Identity>>,
+    /// Where the commits and deletions are logged before they begin, if
+    /// they are.
+    wal: Option<Arc<Wal>>,
+    /// Whether the uploads and deletions are refused, shared with the server
+    /// which toggles it.
+    read_only: Arc<AtomicBool>,
 }
 
 impl FileStore {
-    /// Creates a new instance of `FileStore` with the given root directory.
+    /// Creates a new instance of `FileStore` with the given root directory,
+    /// keeping the uploads on the local disk, under the root directory.
     ///
     /// # Arguments
     ///
     /// * `root_dir` - The root directory for the file store.
     pub fn new(root_dir: impl AsRef<Path>) -> Result<Self> {
         // Create the root directory if it doesn't exist
-        if !root_dir.as_ref().exists() {
-            fs::create_dir_all(&root_dir)?;
-        }
+        let backend = LocalBackend::new(&root_dir)?;
 
         Ok(Self {
             root_dir: root_dir.as_ref().to_path_buf(),
+            backend: Arc::new(backend),
+            limits: Limits::default(),
+            quota: None,
+            cache: None,
+            usage_cache: Arc::default(),
+            retention: None,
+            namespace: None,
+            replicator: None,
+            webhooks: None,
+            identity: None,
+            wal: None,
+            read_only: Arc::default(),
+        })
+    }
+
+    /// Keeps the uploads in the given backend rather than on the local disk,
+    /// the files being uploaded still being received under the root
+    /// directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - Where the uploads are kept.
+    pub fn with_backend(mut self, backend: Arc<dyn StorageBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Only accepts files and uploads within the given limits.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The limits of the files and uploads.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Returns the limits of the files and uploads the store accepts.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Only accepts uploads while its uploads hold at most the given number
+    /// of bytes, the quota of each of its namespaces too.
+    ///
+    /// # Arguments
+    ///
+    /// * `quota` - The quota, in bytes.
+    pub fn with_quota(mut self, quota: u64) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Keeps the trees of the uploads read last in a cache, which may be
+    /// shared with other stores, e.g. those of the namespaces.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache` - The cache of the trees.
+    pub fn with_cache(mut self, cache: Arc<TreeCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Keeps the count of the bytes held by the uploads in the given cache,
+    /// which may be shared with other stores, e.g. those of the other
+    /// connections.
+    ///
+    /// # Arguments
+    ///
+    /// * `usage_cache` - The cache of the counts.
+    pub fn with_usage_cache(mut self, usage_cache: Arc<UsageCache>) -> Self {
+        self.usage_cache = usage_cache;
+        self
+    }
+
+    /// Deletes the uploads once they have been kept for the given time, unless
+    /// given another retention with [`FileStore::retain`]. An upload stored
+    /// again is kept for that time from then, if it would expire earlier.
+    ///
+    /// # Arguments
+    ///
+    /// * `retention` - How long the uploads are kept.
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Replicates the uploads to peer servers as they are stored, see
+    /// [`Replicator`].
+    pub fn with_replicator(mut self, replicator: Arc<Replicator>) -> Self {
+        self.replicator = Some(replicator);
+        self
+    }
+
+    /// Posts an event to webhooks whenever an upload is newly stored, see
+    /// [`Webhooks`].
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Signs the root hash of every upload committed with the identity key
+    /// of the server, keeping the signature with the upload.
+    pub fn with_identity(mut self, identity: Arc<Identity>) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Returns whether the root hashes of the uploads are signed.
+    pub fn signs_roots(&self) -> bool {
+        self.identity.is_some()
+    }
+
+    /// Logs the commits and deletions of the uploads of the store and of its
+    /// namespaces in a write-ahead log before they begin, for
+    /// [`FileStore::recover`] to repair those a crash interrupts.
+    pub fn with_wal(mut self, wal: Arc<Wal>) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    /// Logs an operation on an upload about to begin, if the store logs
+    /// them.
+    ///
+    /// # Returns
+    ///
+    /// Returns the id of the operation, to [`FileStore::end`] it with.
+    fn begin(&self, kind: OpKind, root_hash: &str) -> Result<Option<u64>> {
+        let Some(wal) = &self.wal else {
+            return Ok(None);
+        };
+        let op = Op {
+            kind,
+            namespace: self.namespace.clone(),
+            root_hash: root_hash.to_string(),
+        };
+        wal.begin(op).map(Some)
+    }
+
+    /// Logs the end of an operation begun with [`FileStore::begin`], once it
+    /// is applied. An operation failing midway is never ended, and is
+    /// repaired as one a crash interrupted.
+    fn end(&self, id: Option<u64>) {
+        if let (Some(wal), Some(id)) = (&self.wal, id) {
+            wal.end(id);
+        }
+    }
+
+    /// Refuses the uploads and deletions while the flag is set, the uploads
+    /// still being served.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_only` - The flag, which may be shared with other stores and
+    ///   toggled while they serve.
+    pub fn with_read_only(mut self, read_only: Arc<AtomicBool>) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Returns whether the uploads and deletions are refused for now.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Returns an error of kind [`ErrorKind::ReadOnly`] if the store is
+    /// read-only, before anything is written to it.
+    fn check_writable(&self) -> Result<()> {
+        match self.is_read_only() {
+            true => Err(error(
+                ErrorKind::ReadOnly,
+                "The server is read-only for maintenance, only serving \
+                 downloads",
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Queues an upload, or the names or expiry of its files, to be sent to
+    /// the peers, if the uploads are replicated.
+    fn replicate(&self, root_hash: &str) {
+        if let Some(replicator) = &self.replicator {
+            replicator.push(self.namespace.as_deref(), root_hash);
+        }
+    }
+
+    /// Tells the webhooks an upload was newly stored, if there are any.
+    fn notify(&self, root_hash: &str, files: usize, size: u64) {
+        if let Some(webhooks) = &self.webhooks {
+            let user = self.namespace.as_deref();
+            webhooks.push(Event::committed(root_hash, files, size, user));
+        }
+    }
+
+    /// Returns the name of the namespace of the store, if it is one.
+    pub fn namespace_name(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Returns the directory of the store, e.g. of its namespace.
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    /// Returns the quota of the store, in bytes, if limited.
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// Returns the number of bytes held by the uploads of the store, not
+    /// counting the files still staged or those of its namespaces. The
+    /// uploads are listed the first time only, the count being kept up to
+    /// date as uploads are stored by this server.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be read.
+    pub async fn usage(&self) -> Result<u64> {
+        let (bytes, changes) = self.usage_cache.get(&self.root_dir);
+        if let Some(bytes) = bytes {
+            return Ok(bytes);
+        }
+        let uploads = self.list_uploads().await?;
+        let bytes = uploads.iter().map(|upload| upload.size).sum();
+        self.usage_cache.insert(&self.root_dir, bytes, changes);
+        Ok(bytes)
+    }
+
+    /// Checks that the store can serve requests: that a file can be written
+    /// to and read back from the directory of the files being uploaded, and
+    /// that the uploads can be read from their backend.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error telling what failed otherwise.
+    pub async fn check_health(&self) -> Result<()> {
+        let check = CHECKS.fetch_add(1, Ordering::Relaxed);
+        let path = self.root_dir.join(STAGING_DIR).join(format!(
+            "health-{}-{}",
+            std::process::id(),
+            check
+        ));
+        let written = async {
+            tokio::fs::create_dir_all(self.root_dir.join(STAGING_DIR)).await?;
+            tokio::fs::write(&path, b"ok").await?;
+            let read = tokio::fs::read(&path).await;
+            tokio::fs::remove_file(&path).await?;
+            match read? == b"ok" {
+                true => Ok(()),
+                false => Err(io::Error::other("read back another content")),
+            }
+        };
+        written.await.map_err(|e| {
+            anyhow!("Could not write to {}: {}", self.root_dir.display(), e)
+        })?;
+        // An upload that does not exist, for the backend to only be read
+        self.backend
+            .get_tree(&"0".repeat(64))
+            .await
+            .map_err(|e| anyhow!("Could not read the uploads: {:#}", e))?;
+        Ok(())
+    }
+
+    /// Checks that the store can hold more bytes within its quota, and
+    /// reserves them until they are stored, so that concurrent uploads
+    /// cannot exceed it together.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The number of bytes to store.
+    ///
+    /// # Returns
+    ///
+    /// Returns the reservation of the bytes, counted against the quota until
+    /// dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if storing the bytes would exceed the quota, with
+    /// those reserved by others.
+    pub async fn reserve_quota(&self, size: u64) -> Result<Reservation> {
+        let Some(quota) = self.quota else {
+            return Ok(self.usage_cache.reserve(&self.root_dir, 0).0);
+        };
+        // Reserved before the usage is read, so that of two uploads
+        // checking at once, at least one sees the other
+        let (reservation, reserved) =
+            self.usage_cache.reserve(&self.root_dir, size);
+        let used = self.usage().await?.saturating_add(reserved - size);
+        if used.saturating_add(size) > quota {
+            return Err(error(
+                ErrorKind::QuotaExceeded,
+                format!(
+                    "{} bytes exceed the quota, {} of {} bytes being used",
+                    size, used, quota
+                ),
+            ));
+        }
+        Ok(reservation)
+    }
+
+    /// Returns the store of a namespace, e.g. of a user, holding its own
+    /// uploads and staged files under a directory of the root directory. The
+    /// uploads of other namespaces are neither listed nor served by it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the namespace.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is not a valid namespace, or its
+    /// directory cannot be created.
+    pub fn namespace(&self, name: &str) -> Result<Self> {
+        if !is_namespace(name) {
+            return Err(anyhow!("Invalid namespace {}", name));
+        }
+        Ok(Self {
+            root_dir: self.root_dir.join(name),
+            backend: self.backend.namespace(name)?,
+            limits: self.limits,
+            quota: self.quota,
+            cache: self.cache.clone(),
+            usage_cache: self.usage_cache.clone(),
+            retention: self.retention,
+            namespace: Some(name.to_string()),
+            replicator: self.replicator.clone(),
+            webhooks: self.webhooks.clone(),
+            identity: self.identity.clone(),
+            wal: self.wal.clone(),
+            read_only: self.read_only.clone(),
         })
     }
 
     /// Stores the given files in the file store and returns the root hash of
-    /// the Merkle tree.
+    /// the Merkle tree. The tree is stored once all of the files are, so that
+    /// the upload is only ever seen complete.
     ///
     /// # Arguments
     ///
     /// * `files` - A vector containing the file data as `Vec<u8>`.
-    pub fn store_files(&self, files: Vec<Vec<u8>>) -> Result<String> {
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the files exceed the quota of the store, unless
+    /// it already holds them as an upload, or cannot be stored.
+    pub async fn store_files(&self, files: Vec<Vec<u8>>) -> Result<Committed> {
+        self.check_writable()?;
         // Compute the Merkle tree
         let tree = MerkleTree::new(&files)?;
 
         // Compute the root hash, and convert it to a hex string
         let root_hash = tree
             .root()
-            .map(|r| hex::encode(r))
+            .map(hex::encode)
             .ok_or_else(|| anyhow!("Root Hash could not be computed"))?;
 
-        // Create a new directory for the files, named after the root hash
-        let dir = self.root_dir.join(&root_hash);
+        // Stored meanwhile by another client uploading the same files,
+        // unless the upload is corrupt, e.g. left half written by a crash of
+        // an earlier version, and storing it again repairs it
+        if self.get_tree(&root_hash).await.is_ok() {
+            self.apply_retention(&root_hash).await?;
+            let signature = self.sign(&root_hash).await?;
+            return Ok(Committed {
+                root_hash,
+                already_stored: true,
+                signature,
+            });
+        }
+        let size = files.iter().map(|file| file.len() as u64).sum();
+        let _reserved = self.reserve_quota(size).await?;
+        let logged = self.begin(OpKind::Commit, &root_hash)?;
+        let mut storing = Storing::new(sharded(&self.root_dir, &root_hash));
+        let referenced = self.is_referenced(&root_hash).await;
+        for (file, leaf) in files.iter().zip(tree.leaves()) {
+            let size = file.len() as u64;
+            let content = Some(Box::new(&file[..]) as BlobReader<'_>);
+            let hash = hex::encode(leaf);
+            self.put_content(&hash, content, size, !referenced, &mut storing)
+                .await?;
+        }
+        // The upload expires as soon as it is seen, and is signed by then
+        self.apply_retention(&root_hash).await?;
+        let signature = self.sign(&root_hash).await?;
+        self.backend.put_tree(&root_hash, &tree).await?;
+        self.end(logged);
+        drop(storing);
+        self.usage_cache.add(&self.root_dir, size);
+        self.replicate(&root_hash);
+        self.notify(&root_hash, files.len(), size);
+
+        Ok(Committed {
+            root_hash,
+            already_stored: false,
+            signature,
+        })
+    }
+
+    /// Returns the path where the file at `index` of an upload session is
+    /// received. The file is kept there across connections, so that an upload
+    /// interrupted by a network failure can be resumed from the bytes already
+    /// received, until it is staged with [`FileStore::stage_file`].
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The upload session, a hex string chosen by the client.
+    /// * `index` - The index of the file in the upload.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the session is invalid.
+    pub fn partial_file(&self, session: &str, index: usize) -> Result<PathBuf> {
+        self.check_writable()?;
+        let dir = self.session_dir(session)?;
         fs::create_dir_all(&dir)?;
+        Ok(dir.join(index.to_string()))
+    }
+
+    /// Stages a fully received file of a batch uploaded over several
+    /// connections, until the batch is committed with
+    /// [`FileStore::commit_files`].
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The upload session the file was received in.
+    /// * `index` - The index of the file in the upload.
+    ///
+    /// # Returns
+    ///
+    /// Returns the SHA-256 of the file, as a hex string, under which it is
+    /// staged.
+    pub fn stage_file(&self, session: &str, index: usize) -> Result<String> {
+        self.check_writable()?;
+        let dir = self.session_dir(session)?;
+        let partial = dir.join(index.to_string());
+
+        // Hash the file without holding it in memory
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(&partial)?, &mut hasher)?;
+        let hash = hex::encode(hasher.finalize());
+
+        // The rename is atomic, so a commit never reads a partial file
+        let staging = self.root_dir.join(STAGING_DIR);
+        fs::rename(&partial, staging.join(&hash))?;
+        // Other files of the session may still be in flight
+        let _ = fs::remove_dir(dir);
+        Ok(hash)
+    }
+
+    /// Returns which of the files with the given hashes the store already
+    /// holds the content of, for a client to only stage the others before
+    /// committing its batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `hashes` - The SHA-256 hashes of the files, as hex strings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a hash is invalid, or the batch exceeds the limits
+    /// of the store.
+    pub async fn held_contents(&self, hashes: &[String]) -> Result<Vec<bool>> {
+        self.limits
+            .check_files(hashes.len() as u64)
+            .map_err(from_protocol)?;
+        decode_hashes(hashes)?;
+        let mut held = vec![];
+        for hash in hashes {
+            held.push(self.backend.blob_size(BLOBS_DIR, hash).await?.is_some());
+        }
+        Ok(held)
+    }
+
+    /// Stores the staged files as a batch, in the given order, and returns the
+    /// root hash of the Merkle tree. The files whose content the store already
+    /// holds, as told by [`FileStore::held_contents`], need not be staged,
+    /// and a batch the store already holds as an upload is not stored again.
+    ///
+    /// # Arguments
+    ///
+    /// * `hashes` - The SHA-256 hashes returned by [`FileStore::stage_file`],
+    ///   as hex strings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a hash is invalid or its file is neither staged
+    /// nor held, or if the batch exceeds the limits or the quota of the store.
+    pub async fn commit_files(&self, hashes: &[String]) -> Result<Committed> {
+        self.check_writable()?;
+        self.limits
+            .check_files(hashes.len() as u64)
+            .map_err(from_protocol)?;
+        let leaves = decode_hashes(hashes)?;
+        let dir = self.root_dir.join(STAGING_DIR);
+        let paths =
+            hashes.iter().map(|hash| dir.join(hash)).collect::<Vec<_>>();
+
+        // Committing the same batch again, e.g. when the client retries after
+        // losing the acknowledgment, returns the stored upload, unless it is
+        // corrupt and committing it again repairs it
+        let tree = MerkleTree::from_leaves(leaves)?;
+        let root_hash = tree
+            .root()
+            .map(hex::encode)
+            .ok_or_else(|| anyhow!("Root Hash could not be computed"))?;
+        if self.get_tree(&root_hash).await.is_ok() {
+            self.apply_retention(&root_hash).await?;
+            Self::remove_staged(&paths)?;
+            let signature = self.sign(&root_hash).await?;
+            return Ok(Committed {
+                root_hash,
+                already_stored: true,
+                signature,
+            });
+        }
+
+        let not_staged = |hash| {
+            error(ErrorKind::NotFound, format!("File {} is not staged", hash))
+        };
+        // Check the size of the batch before storing any of its files, the
+        // files not staged being those whose content is already held
+        let (mut sizes, mut size) = (vec![], 0u64);
+        for (path, hash) in paths.iter().zip(hashes) {
+            let len = match fs::metadata(path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => self
+                    .backend
+                    .blob_size(BLOBS_DIR, hash)
+                    .await?
+                    .ok_or_else(|| not_staged(hash))?,
+            };
+            sizes.push(len);
+            size = size.saturating_add(len);
+        }
+        self.limits.check_upload_size(size).map_err(from_protocol)?;
+        let _reserved = self.reserve_quota(size).await?;
+
+        // The files are streamed from the staging directory, hashed when
+        // staged, without holding them in memory. The tree is stored last, so
+        // that the upload is only ever seen complete
+        let logged = self.begin(OpKind::Commit, &root_hash)?;
+        let mut storing = Storing::new(sharded(&self.root_dir, &root_hash));
+        let referenced = self.is_referenced(&root_hash).await;
+        for (index, path) in paths.iter().enumerate() {
+            let content = match tokio::fs::File::open(path).await {
+                Ok(file) => Some(Box::new(file) as BlobReader<'_>),
+                Err(_) => None,
+            };
+            let hash = hex::encode(tree.leaves()[index]);
+            let size = sizes[index];
+            self.put_content(&hash, content, size, !referenced, &mut storing)
+                .await?;
+        }
+        // The upload expires as soon as it is seen, and is signed by then
+        self.apply_retention(&root_hash).await?;
+        let signature = self.sign(&root_hash).await?;
+        self.backend.put_tree(&root_hash, &tree).await?;
+        self.end(logged);
+        drop(storing);
+        self.usage_cache.add(&self.root_dir, size);
+        Self::remove_staged(&paths)?;
+        self.replicate(&root_hash);
+        self.notify(&root_hash, hashes.len(), size);
+        Ok(Committed {
+            root_hash,
+            already_stored: false,
+            signature,
+        })
+    }
+
+    /// Returns whether an upload, corrupt or not, already holds references to
+    /// the contents of its files: its tree is stored, which it only is once
+    /// they are.
+    async fn is_referenced(&self, root_hash: &str) -> bool {
+        matches!(self.backend.get_tree(root_hash).await, Ok(Some(_)))
+    }
+
+    /// Stores the content of a file of an upload, unless another upload
+    /// already did, and takes a reference to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - The SHA-256 of the content, as a hex string.
+    /// * `content` - The content, only read if it is not stored yet, or
+    ///   `None` for a content the client was told is held.
+    /// * `size` - The size of the content.
+    /// * `reference` - Whether to take a reference to the content, rather
+    ///   than only store it again for an upload already referencing it.
+    /// * `storing` - The upload being stored, registering the reference.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the content is not stored and not given, e.g. as
+    /// the last upload holding it was deleted since the client was told it
+    /// is held.
+    async fn put_content(
+        &self,
+        hash: &str,
+        content: Option<BlobReader<'_>>,
+        size: u64,
+        reference: bool,
+        storing: &mut Storing,
+    ) -> Result<()> {
+        // The reference is taken first, so that the content is not deleted
+        // while it is stored, and a crash only ever leaves one too many
+        let stored = {
+            let _refs = REFS.lock().await;
+            let stored =
+                self.backend.blob_size(BLOBS_DIR, hash).await? == Some(size);
+            if !stored && content.is_none() {
+                return Err(error(
+                    ErrorKind::NotFound,
+                    format!("File {} is not staged", hash),
+                ));
+            }
+            if reference {
+                let refs = self.refs(hash).await?;
+                self.set_refs(hash, refs + 1).await?;
+                storing.add(sharded(&self.root_dir.join(BLOBS_DIR), hash));
+            }
+            stored
+        };
+        if let Some(content) = content.filter(|_| !stored) {
+            self.backend
+                .put_blob(BLOBS_DIR, hash, content, size)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Releases a reference to the content of a file, deleting the content
+    /// once no upload references it, [`REFS`] being held.
+    async fn release_content(&self, hash: &str) -> Result<()> {
+        let refs = self.refs(hash).await?;
+        if refs > 1 {
+            return self.set_refs(hash, refs - 1).await;
+        }
+        self.backend.delete_blob(BLOBS_DIR, hash).await?;
+        self.backend.delete_blob(BLOBS_DIR, &refs_blob(hash)).await
+    }
+
+    /// Returns the number of uploads referencing a content.
+    async fn refs(&self, hash: &str) -> Result<u64> {
+        let mut refs = vec![];
+        let name = refs_blob(hash);
+        if self
+            .backend
+            .get_blob(BLOBS_DIR, &name, None, &mut refs)
+            .await?
+            .is_none()
+        {
+            return Ok(0);
+        }
+        String::from_utf8_lossy(&refs).trim().parse().map_err(|e| {
+            anyhow!("Invalid count of the references to {}: {}", hash, e)
+        })
+    }
+
+    /// Records the number of uploads referencing a content.
+    async fn set_refs(&self, hash: &str, refs: u64) -> Result<()> {
+        let refs = refs.to_string();
+        let size = refs.len() as u64;
+        self.backend
+            .put_blob(
+                BLOBS_DIR,
+                &refs_blob(hash),
+                Box::new(refs.as_bytes()),
+                size,
+            )
+            .await
+    }
+
+    /// Returns where the file at `index` of an upload is stored, as the
+    /// directory and name of its blob, along with its size, or `None` if it
+    /// is missing. The files of the uploads stored by earlier versions are
+    /// kept within the upload rather than shared.
+    async fn locate(
+        &self,
+        root_hash: &str,
+        index: usize,
+        leaf: &[u8; 32],
+    ) -> Result<Option<(String, String, u64)>> {
+        let hash = hex::encode(leaf);
+        if let Some(size) = self.backend.blob_size(BLOBS_DIR, &hash).await? {
+            return Ok(Some((BLOBS_DIR.to_string(), hash, size)));
+        }
+        let name = index.to_string();
+        let size = self.backend.blob_size(root_hash, &name).await?;
+        Ok(size.map(|size| (root_hash.to_string(), name, size)))
+    }
+
+    /// Returns where the file at `index` of an upload is stored, as
+    /// [`FileStore::locate`] does.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the root hash is invalid, or the upload or the
+    /// file does not exist.
+    async fn file(
+        &self,
+        root_hash: &str,
+        index: usize,
+    ) -> Result<(String, String, u64)> {
+        let tree = self.get_tree(root_hash).await?;
+        let missing = || not_found(format!("File {} of {}", index, root_hash));
+        let leaf = tree.leaves().get(index).ok_or_else(missing)?;
+        self.locate(root_hash, index, leaf)
+            .await?
+            .ok_or_else(missing)
+    }
+
+    /// Keeps an upload for the given time from now, after which it is
+    /// deleted, replacing the retention it was given before.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the upload does not exist, or its retention cannot
+    /// be recorded.
+    pub async fn retain(
+        &self,
+        root_hash: &str,
+        retention: Duration,
+    ) -> Result<()> {
+        self.check_writable()?;
+        self.get_tree(root_hash).await?;
+        let expiry = unix_now().saturating_add(retention.as_secs());
+        self.set_expiry(root_hash, expiry).await?;
+        self.replicate(root_hash);
+        Ok(())
+    }
+
+    /// Keeps an upload stored for the retention of the store, if the uploads
+    /// expire, rather than the time it was kept for before if sooner.
+    async fn apply_retention(&self, root_hash: &str) -> Result<()> {
+        let Some(retention) = self.retention else {
+            return Ok(());
+        };
+        let expiry = unix_now().saturating_add(retention.as_secs());
+        match self.expiry(root_hash).await? {
+            Some(current) if current >= expiry => Ok(()),
+            _ => self.set_expiry(root_hash, expiry).await,
+        }
+    }
+
+    /// Returns when an upload expires, in seconds since the Unix epoch, or
+    /// `None` if it is kept until deleted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the expiry cannot be read.
+    pub async fn expiry(&self, root_hash: &str) -> Result<Option<u64>> {
+        let mut expiry = vec![];
+        if self
+            .backend
+            .get_blob(root_hash, EXPIRY_FILE, None, &mut expiry)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let expiry = String::from_utf8_lossy(&expiry).trim().parse();
+        let expiry = expiry.map_err(|e| {
+            corrupt(root_hash, format!("invalid expiry: {}", e))
+        })?;
+        Ok(Some(expiry))
+    }
+
+    /// Records when an upload expires, in seconds since the Unix epoch, e.g.
+    /// for an upload imported from a backup.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the expiry cannot be recorded.
+    pub async fn set_expiry(&self, root_hash: &str, expiry: u64) -> Result<()> {
+        let expiry = expiry.to_string();
+        let size = expiry.len() as u64;
+        let content = Box::new(expiry.as_bytes());
+        self.backend
+            .put_blob(root_hash, EXPIRY_FILE, content, size)
+            .await
+    }
+
+    /// Returns the signature of the root hash of an upload kept with it, if
+    /// it was signed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the signature cannot be read.
+    pub async fn signature(
+        &self,
+        root_hash: &str,
+    ) -> Result<Option<RootSignature>> {
+        let mut signature = vec![];
+        if self
+            .backend
+            .get_blob(root_hash, SIGNATURE_FILE, None, &mut signature)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let signature = serde_json::from_slice(&signature).map_err(|e| {
+            corrupt(root_hash, format!("invalid signature: {}", e))
+        })?;
+        Ok(Some(signature))
+    }
+
+    /// Keeps the signature of the root hash of an upload with it, e.g. for
+    /// an upload imported from a backup.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the signature cannot be recorded.
+    pub async fn set_signature(
+        &self,
+        root_hash: &str,
+        signature: &RootSignature,
+    ) -> Result<()> {
+        let signature = serde_json::to_vec(signature)?;
+        let size = signature.len() as u64;
+        self.backend
+            .put_blob(root_hash, SIGNATURE_FILE, Box::new(&signature[..]), size)
+            .await
+    }
 
-        // Store the files
-        for (i, file_data) in files.iter().enumerate() {
-            fs::write(dir.join(i.to_string()), file_data)?;
+    /// Signs the root hash of an upload being committed, if the store signs
+    /// them, unless it is already signed with the same key.
+    async fn sign(&self, root_hash: &str) -> Result<Option<RootSignature>> {
+        let Some(identity) = &self.identity else {
+            return Ok(None);
+        };
+        let signature = identity.sign(root_hash)?;
+        // The signatures of ed25519 being deterministic, an upload signed
+        // before is only signed again if by another key, e.g. once replaced
+        match self.signature(root_hash).await {
+            Ok(Some(kept)) if kept == signature => return Ok(Some(kept)),
+            _ => self.set_signature(root_hash, &signature).await?,
         }
+        Ok(Some(signature))
+    }
+
+    /// Returns the Merkle tree of an upload served to clients, as
+    /// [`FileStore::get_tree`] does, an upload past its retention not being
+    /// found even before the expired uploads are deleted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the upload cannot be read, as
+    /// [`FileStore::get_tree`] does, or if it has expired.
+    pub async fn get_served_tree(
+        &self,
+        root_hash: &str,
+    ) -> Result<Arc<MerkleTree>> {
+        let tree = self.get_tree(root_hash).await?;
+        if self.has_expired(root_hash).await? {
+            return Err(not_found(format!("Upload {}", root_hash)));
+        }
+        Ok(tree)
+    }
+
+    /// Returns whether an upload has expired, e.g. while the expired uploads
+    /// are not deleted yet.
+    async fn has_expired(&self, root_hash: &str) -> Result<bool> {
+        let now = unix_now();
+        Ok(self
+            .expiry(root_hash)
+            .await?
+            .is_some_and(|expiry| expiry <= now))
+    }
+
+    /// Deletes the uploads of the store which have expired, but not those of
+    /// its namespaces.
+    ///
+    /// # Returns
+    ///
+    /// Returns the root hashes of the deleted uploads.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the uploads cannot be listed or deleted.
+    pub async fn delete_expired(&self) -> Result<Vec<String>> {
+        let mut deleted = vec![];
+        for root_hash in self.backend.list().await? {
+            // An upload whose expiry cannot be read is kept
+            if self.has_expired(&root_hash).await.unwrap_or(false)
+                && self.delete_upload(&root_hash).await?
+            {
+                deleted.push(root_hash);
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Removes the staged files of a committed batch.
+    fn remove_staged(paths: &[PathBuf]) -> Result<()> {
+        for path in paths {
+            // A file may appear several times in a batch
+            if let Err(error) = fs::remove_file(path) {
+                if error.kind() != io::ErrorKind::NotFound {
+                    return Err(error.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the directory of the partial files of an upload session.
+    fn session_dir(&self, session: &str) -> Result<PathBuf> {
+        match hex::decode(session) {
+            Ok(bytes) if bytes.len() == 32 => Ok(self
+                .root_dir
+                .join(STAGING_DIR)
+                .join("sessions")
+                .join(session)),
+            _ => Err(error(
+                ErrorKind::InvalidRequest,
+                format!("Invalid session {}", session),
+            )),
+        }
+    }
+
+    /// Deletes an upload, its Merkle tree and the names of its files, and
+    /// releases the contents of its files, deleted once no other upload
+    /// holds them.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload to delete.
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the upload existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the root hash is invalid or the upload cannot be
+    /// removed.
+    pub async fn delete_upload(&self, root_hash: &str) -> Result<bool> {
+        self.check_writable()?;
+        Self::check_root_hash(root_hash)?;
+        let contents = match self.backend.get_tree(root_hash).await {
+            Ok(Some(tree)) => self.shared_contents(root_hash, &tree).await?,
+            Ok(None) => vec![],
+            // Its contents are left to collect, the upload being deleted
+            // anyway
+            Err(e) => {
+                log::warn!(
+                    "Could not release the files of {}: {}",
+                    root_hash,
+                    e
+                );
+                vec![]
+            }
+        };
+        // The upload is deleted along with its references, so that the
+        // garbage collector never counts the references of the upload and
+        // sees it deleted. They are released once the upload is no longer
+        // seen, so that its files are never seen missing
+        let logged = self.begin(OpKind::Delete, root_hash)?;
+        let refs = REFS.lock().await;
+        let existed = self.backend.delete(root_hash).await?;
+        if let Some(cache) = &self.cache {
+            cache.remove(&self.root_dir.join(root_hash));
+        }
+        if existed {
+            for hash in contents {
+                self.release_content(&hash).await?;
+            }
+            // The size of the upload is not known without reading it, so
+            // the uploads are counted again
+            self.usage_cache.remove(&self.root_dir);
+        }
+        drop(refs);
+        self.end(logged);
+        Ok(existed)
+    }
+
+    /// Returns the SHA-256 hashes of the contents of the files of an upload
+    /// it references, those of its files stored within the upload by an
+    /// earlier version being deleted with it.
+    async fn shared_contents(
+        &self,
+        root_hash: &str,
+        tree: &MerkleTree,
+    ) -> Result<Vec<String>> {
+        let mut contents = vec![];
+        for (index, leaf) in tree.leaves().iter().enumerate() {
+            let name = index.to_string();
+            if self.backend.blob_size(root_hash, &name).await?.is_none() {
+                contents.push(hex::encode(leaf));
+            }
+        }
+        Ok(contents)
+    }
+
+    /// Collects the garbage of the store, but not of its namespaces: deletes
+    /// the uploads left partial and the contents no upload references, and
+    /// corrects the counts of the references to the others, e.g. after a
+    /// crash. The uploads being stored by the process are left alone, but
+    /// not those of other processes.
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - Where what is deleted is reported, nothing being deleted
+    ///   on a dry run.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be read, or the garbage deleted.
+    pub async fn collect_garbage(&self, report: &mut GcReport) -> Result<()> {
+        let storing = || STORING.lock().unwrap().clone();
+        for root_hash in self.backend.list_partial().await? {
+            let dir = sharded(&self.root_dir, &root_hash);
+            if storing().contains(&dir) {
+                continue;
+            }
+            let mut size = 0;
+            for name in self.backend.list_blobs(&root_hash).await? {
+                let blob_size = self.backend.blob_size(&root_hash, &name);
+                size += blob_size.await?.unwrap_or(0);
+            }
+            report.remove(dir, size);
+            if !report.dry_run {
+                self.backend.delete(&root_hash).await?;
+            }
+        }
+        self.count_references(report).await
+    }
+
+    /// Counts the references to the contents of the store again from the
+    /// trees of its uploads, deleting the contents no upload references.
+    async fn count_references(&self, report: &mut GcReport) -> Result<()> {
+        let storing = || STORING.lock().unwrap().clone();
+        // The references are counted while no upload takes or releases one
+        let _refs = REFS.lock().await;
+        let mut counts = HashMap::<String, u64>::new();
+        for root_hash in self.backend.list().await? {
+            let tree = self.backend.get_tree(&root_hash).await?;
+            let Some(tree) = tree else { continue };
+            for hash in self.shared_contents(&root_hash, &tree).await? {
+                *counts.entry(hash).or_default() += 1;
+            }
+        }
+        let blobs = self.root_dir.join(BLOBS_DIR);
+        for path in storing() {
+            if path.starts_with(&blobs) {
+                let hash = path.file_name().unwrap_or_default();
+                let hash = hash.to_string_lossy().to_string();
+                *counts.entry(hash).or_default() += 1;
+            }
+        }
+        let hashes = self
+            .backend
+            .list_blobs(BLOBS_DIR)
+            .await?
+            .iter()
+            .map(|name| name.trim_end_matches(".refs").to_string())
+            .collect::<BTreeSet<_>>();
+        for hash in hashes {
+            let refs = counts.get(&hash).copied().unwrap_or(0);
+            if refs == 0 {
+                let size = self.backend.blob_size(BLOBS_DIR, &hash).await?;
+                report.remove(sharded(&blobs, &hash), size.unwrap_or(0));
+                if !report.dry_run {
+                    self.backend.delete_blob(BLOBS_DIR, &hash).await?;
+                    let refs = refs_blob(&hash);
+                    self.backend.delete_blob(BLOBS_DIR, &refs).await?;
+                }
+            } else if self.refs(&hash).await.ok() != Some(refs) {
+                report.recounted.push(sharded(&blobs, &hash));
+                if !report.dry_run {
+                    self.set_refs(&hash, refs).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Repairs the operations of the write-ahead log of the store that a
+    /// crash interrupted, on the store and its namespaces: an upload whose
+    /// commit was interrupted is deleted, unless its tree was stored, and one
+    /// whose deletion was is deleted again. The references to the contents
+    /// are not logged, and are not recovered but recomputed from the trees
+    /// of the uploads of the namespaces concerned, deleting the contents no
+    /// upload references, see [`FileStore::collect_garbage`].
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of operations repaired, none if the store does not
+    /// log them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an upload cannot be deleted or the references
+    /// counted, the operations being repaired again the next time.
+    pub async fn recover(&self) -> Result<usize> {
+        let Some(wal) = &self.wal else {
+            return Ok(0);
+        };
+        let unfinished = wal.unfinished();
+        let store = |namespace: &Option<String>| match namespace {
+            Some(name) => self.namespace(name),
+            None => Ok(self.clone()),
+        };
+        let mut namespaces = BTreeSet::new();
+        for (_, op) in &unfinished {
+            let root_hash = &op.root_hash;
+            Self::check_root_hash(root_hash)?;
+            let store = store(&op.namespace)?;
+            match op.kind {
+                OpKind::Commit if store.is_referenced(root_hash).await => {
+                    log::info!(
+                        "Completed the interrupted commit of {}",
+                        root_hash
+                    )
+                }
+                OpKind::Commit => {
+                    store.backend.delete(root_hash).await?;
+                    log::info!(
+                        "Rolled back the interrupted commit of {}",
+                        root_hash
+                    )
+                }
+                OpKind::Delete => {
+                    store.backend.delete(root_hash).await?;
+                    log::info!(
+                        "Completed the interrupted deletion of {}",
+                        root_hash
+                    )
+                }
+            }
+            namespaces.insert(op.namespace.clone());
+        }
+        for namespace in &namespaces {
+            let mut report = GcReport::default();
+            store(namespace)?.count_references(&mut report).await?;
+            if !report.is_empty() {
+                log::info!("{}", report);
+            }
+        }
+        for (id, _) in &unfinished {
+            wal.end(*id);
+        }
+        Ok(unfinished.len())
+    }
 
-        // Serialize and store the Merkle tree
-        let tree_json = serde_json::to_string(&tree)?;
-        let mut file = File::create(dir.join("tree.json"))?;
-        file.write_all(tree_json.as_bytes())?;
+    /// Scrubs the uploads of the store, but not of its namespaces: hashes
+    /// their files again and builds their trees again from the hashes,
+    /// reporting the uploads no longer as they were stored.
+    ///
+    /// When quarantining, a file with another hash is moved into its upload
+    /// as `<index>.corrupt`, so that the upload is refused as missing a file
+    /// rather than served corrupt, until a client uploads it again, which
+    /// repairs it. The uploads sharing the file are refused as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - Where the corrupt uploads are reported.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the uploads cannot be listed. An upload which
+    /// cannot be read is reported corrupt.
+    pub async fn scrub(&self, report: &mut ScrubReport) -> Result<()> {
+        for root_hash in self.backend.list().await? {
+            report.uploads += 1;
+            let dir = sharded(&self.root_dir, &root_hash);
+            match self.scrub_upload(&root_hash, report).await {
+                Ok(damage) if damage.is_empty() => {}
+                Ok(damage) => report.flag(dir, damage.join(", ")),
+                Err(e) => report.flag(dir, format!("cannot be read: {:#}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Scrubs an upload, see [`FileStore::scrub`].
+    ///
+    /// # Returns
+    ///
+    /// Returns what is corrupt in the upload, if anything.
+    async fn scrub_upload(
+        &self,
+        root_hash: &str,
+        report: &mut ScrubReport,
+    ) -> Result<Vec<String>> {
+        // Deleted since it was listed
+        let Some(tree) = self.backend.get_tree(root_hash).await? else {
+            return Ok(vec![]);
+        };
+        let tree = MerkleTree::from_leaves(tree.leaves().to_vec())?;
+        if tree.root().map(hex::encode).as_deref() != Some(root_hash) {
+            return Ok(vec!["the tree has another root".to_string()]);
+        }
+        let mut damage = vec![];
+        for (index, leaf) in tree.leaves().iter().enumerate() {
+            let file = self.locate(root_hash, index, leaf).await?;
+            let Some((dir, name, size)) = file else {
+                damage.push(format!("file {} is missing", index));
+                continue;
+            };
+            // The file is hashed as it is read, never held whole in memory
+            let mut hashing = Hashing(Sha256::new());
+            let read = self.backend.get_blob(&dir, &name, None, &mut hashing);
+            if read.await?.is_none() {
+                damage.push(format!("file {} is missing", index));
+                continue;
+            }
+            report.bytes += size;
+            if hashing.0.finalize()[..] == leaf[..] {
+                continue;
+            }
+            damage.push(format!("file {} has another hash", index));
+            if report.quarantine {
+                self.quarantine(root_hash, index, &dir, &name, size).await?;
+            }
+        }
+        Ok(damage)
+    }
+
+    /// Moves a corrupt file of an upload into the upload, as
+    /// `<index>.corrupt`, so that the upload is refused until the file is
+    /// uploaded again.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    /// * `index` - The index of the file.
+    /// * `dir` - The directory of the blob of the file, as returned by
+    ///   [`FileStore::locate`].
+    /// * `name` - The name of the blob of the file.
+    /// * `size` - The size of the blob.
+    async fn quarantine(
+        &self,
+        root_hash: &str,
+        index: usize,
+        dir: &str,
+        name: &str,
+        size: u64,
+    ) -> Result<()> {
+        // Held so that no upload takes the content for stored as it is moved
+        let _refs = REFS.lock().await;
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        let copy = async {
+            let copied = self.backend.get_blob(dir, name, None, &mut writer);
+            let copied = copied.await;
+            drop(writer);
+            copied
+        };
+        let quarantined = format!("{}{}", index, QUARANTINE_SUFFIX);
+        let reader = Box::new(reader);
+        let put = self.backend.put_blob(root_hash, &quarantined, reader, size);
+        let (copied, put) = tokio::join!(copy, put);
+        copied?;
+        put?;
+        self.backend.delete_blob(dir, name).await?;
+        // The trees of the uploads sharing the file are cached too
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    /// Returns the store and the stores of its namespaces, e.g. to collect
+    /// the garbage of all of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the namespaces cannot be listed.
+    pub async fn with_namespaces(&self) -> Result<Vec<Self>> {
+        let mut stores = vec![self.clone()];
+        for name in self.namespaces().await? {
+            stores.push(self.namespace(&name)?);
+        }
+        Ok(stores)
+    }
+
+    /// Returns the names of the namespaces of the store holding uploads or
+    /// files being uploaded, sorted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the namespaces cannot be listed.
+    pub async fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names = self.backend.namespaces().await?;
+        // The files being uploaded are received on the local disk, whatever
+        // the backend
+        match fs::read_dir(&self.root_dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if is_namespace(&name) && entry.file_type()?.is_dir() {
+                        names.push(name);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Returns whether the store holds an upload, as it was stored: a
+    /// corrupt upload is not held, for clients to upload it again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the root hash is invalid.
+    pub async fn has_upload(&self, root_hash: &str) -> Result<bool> {
+        Self::check_root_hash(root_hash)?;
+        Ok(self.get_served_tree(root_hash).await.is_ok())
+    }
+
+    /// Returns the uploads held by the store, ordered by root hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be read.
+    pub async fn list_uploads(&self) -> Result<Vec<StoredUpload>> {
+        let mut uploads = vec![];
+        for root_hash in self.backend.list().await? {
+            // Skip the uploads that are corrupt, or expired but not deleted
+            // yet
+            let Ok(tree) = self.get_tree(&root_hash).await else {
+                continue;
+            };
+            if self.has_expired(&root_hash).await.unwrap_or(true) {
+                continue;
+            }
+            let files = tree.leaves().len();
+            let mut size = 0;
+            for (index, leaf) in tree.leaves().iter().enumerate() {
+                let file = self.locate(&root_hash, index, leaf).await?;
+                size += file.map_or(0, |(_, _, size)| size);
+            }
+            uploads.push(StoredUpload {
+                root_hash,
+                files,
+                size,
+            });
+        }
+        uploads.sort_by(|a, b| a.root_hash.cmp(&b.root_hash));
+        Ok(uploads)
+    }
+
+    /// Records the names of the files of an upload, replacing the names
+    /// recorded before, e.g. by another client uploading the same files.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    /// * `names` - The names of the files, in the order of the leaves.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the upload does not exist, if there is not a name
+    /// per file, or if a name is given twice.
+    pub async fn set_names(
+        &self,
+        root_hash: &str,
+        names: &[String],
+    ) -> Result<()> {
+        self.check_writable()?;
+        let files = self.get_tree(root_hash).await?.leaves().len();
+        if names.len() != files {
+            return Err(error(
+                ErrorKind::InvalidRequest,
+                format!("{} names given for {} files", names.len(), files),
+            ));
+        }
+        let mut sorted = names.iter().collect::<Vec<_>>();
+        sorted.sort();
+        if let Some(name) = sorted.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(error(
+                ErrorKind::InvalidRequest,
+                format!("Name {} given twice", name[0]),
+            ));
+        }
+
+        let manifest = serde_json::to_vec(&Manifest {
+            version: MANIFEST_VERSION,
+            files: names.to_vec(),
+        })?;
+        let size = manifest.len() as u64;
+        self.backend
+            .put_blob(root_hash, MANIFEST_FILE, Box::new(&manifest[..]), size)
+            .await?;
+        self.replicate(root_hash);
+        Ok(())
+    }
+
+    /// Returns the names of the files of an upload, in the order of the
+    /// leaves, or `None` if they are not recorded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the names cannot be read or are invalid.
+    pub async fn names(&self, root_hash: &str) -> Result<Option<Vec<String>>> {
+        let mut manifest = vec![];
+        if self
+            .backend
+            .get_blob(root_hash, MANIFEST_FILE, None, &mut manifest)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let manifest: Manifest = serde_json::from_slice(&manifest)?;
+        Ok(Some(manifest.files))
+    }
+
+    /// Returns the index of the file of an upload with the given name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the upload does not exist, if the names of its
+    /// files are not recorded, or if none of them has the name.
+    pub async fn get_index(
+        &self,
+        root_hash: &str,
+        name: &str,
+    ) -> Result<usize> {
+        if !self.has_upload(root_hash).await? {
+            return Err(not_found(format!("Upload {}", root_hash)));
+        }
+        self.names(root_hash)
+            .await?
+            .ok_or_else(|| {
+                not_found(format!("The names of the files of {}", root_hash))
+            })?
+            .iter()
+            .position(|file| file == name)
+            .ok_or_else(|| not_found(format!("File {} of {}", name, root_hash)))
+    }
 
-        Ok(root_hash)
+    /// Checks that a root hash is valid, so that it can name an upload.
+    fn check_root_hash(root_hash: &str) -> Result<()> {
+        match hex::decode(root_hash) {
+            Ok(bytes) if bytes.len() == 32 => Ok(()),
+            _ => Err(error(
+                ErrorKind::InvalidRequest,
+                format!("Invalid root hash {}", root_hash),
+            )),
+        }
     }
 
-    /// Returns the Merkle tree with the given root hash.
+    /// Returns the Merkle tree with the given root hash, from the cache if
+    /// the upload was read recently.
     ///
     /// # Arguments
     ///
     /// * `root_hash` - The root hash of the Merkle tree to retrieve.
-    pub fn get_tree(&self, root_hash: &str) -> Result<MerkleTree> {
-        let dir = self.root_dir.join(root_hash);
-        let tree_json = fs::read_to_string(dir.join("tree.json"))?;
-        let tree: MerkleTree = serde_json::from_str(&tree_json)?;
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the root hash is invalid, if the upload does not
+    /// exist or cannot be read, or if it is corrupt: its tree has another
+    /// root, or a file is missing.
+    pub async fn get_tree(&self, root_hash: &str) -> Result<Arc<MerkleTree>> {
+        Self::check_root_hash(root_hash)?;
+        let key = self.root_dir.join(root_hash);
+        if let Some(tree) = self.cache.as_ref().and_then(|c| c.get(&key)) {
+            return Ok(tree);
+        }
+        let tree = self
+            .backend
+            .get_tree(root_hash)
+            .await?
+            .ok_or_else(|| not_found(format!("Upload {}", root_hash)))?;
+
+        // Refuse to serve an upload that is not as it was stored, e.g. after
+        // its storage was tampered with. The tree is decoded without hashing
+        // its nodes, so it is built again from its leaves, whose proofs are
+        // then served
+        let tree = MerkleTree::from_leaves(tree.leaves().to_vec())?;
+        if tree.root().map(hex::encode).as_deref() != Some(root_hash) {
+            return Err(corrupt(root_hash, "the tree has another root"));
+        }
+        for (index, leaf) in tree.leaves().iter().enumerate() {
+            if self.locate(root_hash, index, leaf).await?.is_none() {
+                let missing = format!("file {} is missing", index);
+                return Err(corrupt(root_hash, missing));
+            }
+        }
+        let tree = Arc::new(tree);
+        if let Some(cache) = &self.cache {
+            cache.insert(key, tree.clone());
+        }
         Ok(tree)
     }
 
-    /// Returns the file with the given index and root hash.
+    /// Returns the size of the file with the given index and root hash.
     ///
     /// # Arguments
     ///
     /// * `root_hash` - The root hash of the Merkle tree containing the file.
-    /// * `index` - The index of the file to retrieve.
-    pub fn get_file(&self, root_hash: &str, index: usize) -> Result<Vec<u8>> {
-        let dir = self.root_dir.join(root_hash);
-        let file_path = dir.join(index.to_string());
-        Ok(fs::read(file_path)?)
+    /// * `index` - The index of the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the root hash is invalid, or the file does not
+    /// exist.
+    pub async fn file_size(
+        &self,
+        root_hash: &str,
+        index: usize,
+    ) -> Result<u64> {
+        let (_, _, size) = self.file(root_hash, index).await?;
+        Ok(size)
+    }
+
+    /// Copies the file with the given index and root hash, or a range of it,
+    /// to `out` as it is read. Large files can be downloaded in ranges,
+    /// concurrently.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the Merkle tree containing the file.
+    /// * `index` - The index of the file.
+    /// * `range` - The range of the file, shorter at the end of the file, or
+    ///   `None` for all of it.
+    /// * `out` - Where the file is copied to.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of bytes copied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the root hash is invalid, the file does not exist
+    /// or cannot be read, or `out` cannot be written.
+    pub async fn copy_file(
+        &self,
+        root_hash: &str,
+        index: usize,
+        range: Option<Range<u64>>,
+        out: BlobWriter<'_>,
+    ) -> Result<u64> {
+        let (dir, name, _) = self.file(root_hash, index).await?;
+        self.backend
+            .get_blob(&dir, &name, range, out)
+            .await?
+            .ok_or_else(|| {
+                not_found(format!("File {} of {}", index, root_hash))
+            })
+    }
+}
+
+/// Hashes what is written to it, e.g. a file read from the backend.
+struct Hashing(Sha256);
+
+impl AsyncWrite for Hashing {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.update(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Decodes the SHA-256 hashes of the files of a batch, the leaves of its
+/// tree.
+fn decode_hashes(hashes: &[String]) -> Result<Vec<[u8; 32]>> {
+    hashes
+        .iter()
+        .map(|hash| {
+            hex::decode(hash)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| {
+                    error(
+                        ErrorKind::InvalidRequest,
+                        format!("Invalid hash {}", hash),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Returns the name of the blob counting the references to a content.
+fn refs_blob(hash: &str) -> String {
+    format!("{}.refs", hash)
+}
+
+/// Returns the error telling the client what it asked for is not found.
+fn not_found(what: String) -> anyhow::Error {
+    error(ErrorKind::NotFound, format!("{} not found", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::TREE_FILE;
+    use crate::error::kind_of;
+
+    /// Reads a file of an upload, or a range of it, from a store.
+    async fn read(
+        store: &FileStore,
+        root_hash: &str,
+        index: usize,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>> {
+        let mut file = vec![];
+        store.copy_file(root_hash, index, range, &mut file).await?;
+        Ok(file)
+    }
+
+    /// Collects the garbage of a store, but not of its namespaces.
+    async fn collect(store: &FileStore, dry_run: bool) -> GcReport {
+        let mut report = GcReport {
+            dry_run,
+            ..Default::default()
+        };
+        store.collect_garbage(&mut report).await.unwrap();
+        report
+    }
+
+    #[tokio::test]
+    async fn test_commit_staged_files() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store1");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+
+        let session = "ab".repeat(32);
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let hashes = files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let path = store.partial_file(&session, index).unwrap();
+                fs::write(path, file).unwrap();
+                store.stage_file(&session, index).unwrap()
+            })
+            .collect::<Vec<_>>();
+        // A batch larger than the limits is rejected, and stays staged
+        let limited = store.clone().with_limits(Limits {
+            max_upload_size: 9,
+            ..Limits::NONE
+        });
+        let too_large = limited.commit_files(&hashes).await.unwrap_err();
+        assert_eq!(kind_of(&too_large), ErrorKind::TooLarge);
+        let committed = store.commit_files(&hashes).await.unwrap();
+        assert!(!committed.already_stored);
+        let root_hash = committed.root_hash;
+
+        let tree = MerkleTree::new(&files).unwrap();
+        assert_eq!(root_hash, hex::encode(tree.root().unwrap()));
+        assert_eq!(read(&store, &root_hash, 1, None).await.unwrap(), b"world");
+        assert_eq!(store.file_size(&root_hash, 1).await.unwrap(), 5);
+        assert_eq!(
+            store.list_uploads().await.unwrap(),
+            vec![StoredUpload {
+                root_hash: root_hash.clone(),
+                files: 2,
+                size: 10,
+            }]
+        );
+        // Committing again returns the stored upload, storing nothing again
+        assert_eq!(
+            store.commit_files(&hashes).await.unwrap(),
+            Committed {
+                root_hash: root_hash.clone(),
+                already_stored: true,
+                signature: None,
+            }
+        );
+        // and signs it once the server signs the uploads
+        let identity = Arc::new(Identity::from_seed(&[7; 32]).unwrap());
+        let signed = store.clone().with_identity(identity.clone());
+        let signature = signed.commit_files(&hashes).await.unwrap().signature;
+        let signature = signature.unwrap();
+        assert!(signature.verifies(&root_hash));
+        assert_eq!(signature.key, hex::encode(identity.public_key()));
+        assert_eq!(store.signature(&root_hash).await.unwrap(), Some(signature));
+        // A file neither staged nor held is not
+        let missing = hex::encode(Sha256::digest(b"missing"));
+        let missing = store.commit_files(&[missing]).await.unwrap_err();
+        assert_eq!(kind_of(&missing), ErrorKind::NotFound);
+        assert!(store
+            .commit_files(&["../tree.json".to_string()])
+            .await
+            .is_err());
+        assert!(store.partial_file("../..", 0).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_names() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store3");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let root_hash = store.store_files(files).await.unwrap().root_hash;
+        let kind = |result: Result<usize>| kind_of(&result.unwrap_err());
+
+        assert_eq!(
+            kind(store.get_index(&root_hash, "a").await),
+            ErrorKind::NotFound
+        );
+        let names = ["a".to_string(), "sub/b".to_string()];
+        store.set_names(&root_hash, &names).await.unwrap();
+        assert_eq!(store.get_index(&root_hash, "sub/b").await.unwrap(), 1);
+        assert_eq!(
+            kind(store.get_index(&root_hash, "c").await),
+            ErrorKind::NotFound
+        );
+        // There must be a name per file, each given once
+        assert!(store.set_names(&root_hash, &names[..1]).await.is_err());
+        let twice = ["a".to_string(), "a".to_string()];
+        assert!(store.set_names(&root_hash, &twice).await.is_err());
+        assert!(store.set_names(&"ab".repeat(32), &names).await.is_err());
+        // The names of an upload are deleted along with it
+        store.delete_upload(&root_hash).await.unwrap();
+        assert_eq!(
+            kind(store.get_index(&root_hash, "a").await),
+            ErrorKind::NotFound
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_upload() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store2");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let root_hash = store
+            .store_files(vec![b"hello".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+
+        let range = read(&store, &root_hash, 0, Some(1..4)).await.unwrap();
+        assert_eq!(range, b"ell");
+        let range = read(&store, &root_hash, 0, Some(3..13)).await.unwrap();
+        assert_eq!(range, b"lo");
+        assert!(read(&store, "..", 0, Some(0..1)).await.is_err());
+
+        assert!(store.has_upload(&root_hash).await.unwrap());
+        assert!(store.delete_upload(&root_hash).await.unwrap());
+        assert!(!store.has_upload(&root_hash).await.unwrap());
+        assert!(store.has_upload("..").await.is_err());
+        let kind = |result: Result<()>| kind_of(&result.unwrap_err());
+        let tree = store.get_tree(&root_hash).await.map(drop);
+        assert_eq!(kind(tree), ErrorKind::NotFound);
+        let file = read(&store, &root_hash, 0, None).await.map(drop);
+        assert_eq!(kind(file), ErrorKind::NotFound);
+        let tree = store.get_tree("..").await.map(drop);
+        assert_eq!(kind(tree), ErrorKind::InvalidRequest);
+        assert!(!store.delete_upload(&root_hash).await.unwrap());
+        assert!(store.delete_upload("..").await.is_err());
+        assert!(store.list_uploads().await.unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store10");
+        let _ = fs::remove_dir_all(&dir);
+        let read_only = Arc::new(AtomicBool::new(false));
+        let store = FileStore::new(&dir)
+            .unwrap()
+            .with_read_only(read_only.clone());
+        let root_hash = store
+            .store_files(vec![b"hello".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+
+        read_only.store(true, Ordering::Relaxed);
+        let kind = |result: Result<()>| kind_of(&result.unwrap_err());
+        let stored = store.store_files(vec![b"world".to_vec()]).await;
+        assert_eq!(kind(stored.map(drop)), ErrorKind::ReadOnly);
+        let deleted = store.delete_upload(&root_hash).await.map(drop);
+        assert_eq!(kind(deleted), ErrorKind::ReadOnly);
+        let partial = store.partial_file(&"a".repeat(64), 0).map(drop);
+        assert_eq!(kind(partial), ErrorKind::ReadOnly);
+        let names = store.set_names(&root_hash, &["a".to_string()]).await;
+        assert_eq!(kind(names), ErrorKind::ReadOnly);
+        // The namespaces share the flag, and the uploads are still served
+        let namespace = store.namespace("alice").unwrap();
+        let stored = namespace.store_files(vec![b"world".to_vec()]).await;
+        assert_eq!(kind(stored.map(drop)), ErrorKind::ReadOnly);
+        assert_eq!(read(&store, &root_hash, 0, None).await.unwrap(), b"hello");
+
+        read_only.store(false, Ordering::Relaxed);
+        assert!(store.delete_upload(&root_hash).await.unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_upload() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store5");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let root_hash =
+            store.store_files(files.clone()).await.unwrap().root_hash;
+        // Nothing is left behind once the upload is in place
+        assert_eq!(fs::read_dir(dir.join(STAGING_DIR)).unwrap().count(), 0);
+
+        let upload = sharded(&dir, &root_hash);
+        let world = hex::encode(Sha256::digest(b"world"));
+        fs::remove_file(sharded(&dir.join(BLOBS_DIR), &world)).unwrap();
+        let missing = store.get_tree(&root_hash).await.unwrap_err().to_string();
+        assert!(missing.contains("file 1 is missing"), "{}", missing);
+        assert!(store.list_uploads().await.unwrap().is_empty());
+
+        // Storing the files again repairs the upload
+        assert_eq!(
+            store.store_files(files).await.unwrap(),
+            Committed {
+                root_hash: root_hash.clone(),
+                already_stored: false,
+                signature: None,
+            }
+        );
+        assert!(store.get_tree(&root_hash).await.is_ok());
+
+        let tree = store.get_tree(&root_hash).await.unwrap();
+        let other = MerkleTree::new(&[b"other".to_vec()]).unwrap();
+        fs::write(upload.join(TREE_FILE), other.to_bytes()).unwrap();
+        let root = store.get_tree(&root_hash).await.unwrap_err().to_string();
+        assert!(root.contains("another root"), "{}", root);
+        // A leaf tampered with, after the 13 bytes of the header, the stored
+        // root being left as it was
+        let mut leaf = tree.to_bytes();
+        leaf[13 + 32] ^= 1;
+        fs::write(upload.join(TREE_FILE), &leaf).unwrap();
+        let leaf = store.get_tree(&root_hash).await.unwrap_err().to_string();
+        assert!(leaf.contains("another root"), "{}", leaf);
+        let truncated = &tree.to_bytes()[..40];
+        fs::write(upload.join(TREE_FILE), truncated).unwrap();
+        assert!(store.get_tree(&root_hash).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scrub() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store9");
+        let _ = fs::remove_dir_all(&dir);
+        let cache = Arc::new(TreeCache::new(16, 1 << 20));
+        let store = FileStore::new(&dir).unwrap().with_cache(cache);
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let root_hash =
+            store.store_files(files.clone()).await.unwrap().root_hash;
+        let shared = store
+            .store_files(vec![b"world".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+        let scrub = |quarantine| {
+            let store = &store;
+            async move {
+                let mut report = ScrubReport {
+                    quarantine,
+                    ..Default::default()
+                };
+                store.scrub(&mut report).await.unwrap();
+                report
+            }
+        };
+        let report = scrub(false).await;
+        assert!(report.is_clean());
+        assert_eq!((report.uploads, report.bytes), (2, 15));
+
+        // A bit flipped on the disk, the size of the file being the same
+        let world = hex::encode(Sha256::digest(b"world"));
+        let world = sharded(&dir.join(BLOBS_DIR), &world);
+        fs::write(&world, b"wotld").unwrap();
+        let report = scrub(false).await;
+        let corrupt = report.corrupt.iter().map(|(path, _)| path.clone());
+        let corrupt = corrupt.collect::<BTreeSet<_>>();
+        let uploads = [sharded(&dir, &root_hash), sharded(&dir, &shared)];
+        assert_eq!(corrupt, BTreeSet::from(uploads.clone()));
+        assert!(report.corrupt[0].1.contains("has another hash"));
+        // Only reported, the uploads still being served
+        assert!(store.get_tree(&root_hash).await.is_ok());
+
+        let report = scrub(true).await;
+        assert_eq!(report.corrupt.len(), 2);
+        assert!(!world.exists());
+        assert!(store.get_tree(&root_hash).await.is_err());
+        assert!(store.get_tree(&shared).await.is_err());
+        let quarantined = [
+            uploads[0].join(format!("1{}", QUARANTINE_SUFFIX)),
+            uploads[1].join(format!("0{}", QUARANTINE_SUFFIX)),
+        ];
+        let quarantined =
+            quarantined.iter().filter_map(|path| fs::read(path).ok());
+        assert_eq!(quarantined.collect::<Vec<_>>(), [b"wotld".to_vec()]);
+
+        // Uploading the file again repairs both uploads
+        assert_eq!(
+            store.store_files(files).await.unwrap().root_hash,
+            root_hash
+        );
+        assert_eq!(read(&store, &shared, 0, None).await.unwrap(), b"world");
+        assert!(scrub(true).await.is_clean());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_contents() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store6");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let blobs = dir.join(BLOBS_DIR);
+        let refs = |file: &[u8]| {
+            let hash = hex::encode(Sha256::digest(file));
+            fs::read_to_string(sharded(&blobs, &refs_blob(&hash))).ok()
+        };
+
+        // A file held by several uploads is stored once
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let first = store.store_files(files).await.unwrap().root_hash;
+        let session = "cd".repeat(32);
+        let hashes = [b"world".to_vec(), b"abc".to_vec()]
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let path = store.partial_file(&session, index).unwrap();
+                fs::write(path, file).unwrap();
+                store.stage_file(&session, index).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let second = store.commit_files(&hashes).await.unwrap().root_hash;
+        assert_eq!(store.backend.list_blobs(BLOBS_DIR).await.unwrap().len(), 6);
+        assert_eq!(refs(b"world").as_deref(), Some("2"));
+        assert_eq!(read(&store, &second, 0, None).await.unwrap(), b"world");
+        // Each upload counts the bytes of its files
+        assert_eq!(store.usage().await.unwrap(), 18);
+
+        // A file whose content is held is committed without being staged
+        let hash = |file: &[u8]| hex::encode(Sha256::digest(file));
+        let hashes = [hash(b"hello"), hash(b"new")];
+        let held = store.held_contents(&hashes).await.unwrap();
+        assert_eq!(held, [true, false]);
+        let error = store.commit_files(&hashes).await.unwrap_err();
+        assert!(error.to_string().contains("is not staged"));
+        let path = store.partial_file(&session, 1).unwrap();
+        fs::write(path, b"new").unwrap();
+        store.stage_file(&session, 1).unwrap();
+        let third = store.commit_files(&hashes).await.unwrap().root_hash;
+        assert_eq!(read(&store, &third, 0, None).await.unwrap(), b"hello");
+        assert_eq!(refs(b"hello").as_deref(), Some("2"));
+        assert_eq!(store.usage().await.unwrap(), 26);
+        assert!(store.delete_upload(&third).await.unwrap());
+
+        // A file is deleted with the last upload holding it
+        assert!(store.delete_upload(&first).await.unwrap());
+        assert_eq!(refs(b"hello"), None);
+        assert_eq!(refs(b"world").as_deref(), Some("1"));
+        assert_eq!(read(&store, &second, 0, None).await.unwrap(), b"world");
+        assert!(store.delete_upload(&second).await.unwrap());
+        assert!(store
+            .backend
+            .list_blobs(BLOBS_DIR)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // The files of the uploads of earlier versions are kept within them
+        let tree = MerkleTree::new(&[b"abc".to_vec()]).unwrap();
+        let legacy = hex::encode(tree.root().unwrap());
+        let blob = Box::new(&b"abc"[..]);
+        store.backend.put_blob(&legacy, "0", blob, 3).await.unwrap();
+        store.backend.put_tree(&legacy, &tree).await.unwrap();
+        assert_eq!(store.file_size(&legacy, 0).await.unwrap(), 3);
+        assert_eq!(read(&store, &legacy, 0, None).await.unwrap(), b"abc");
+        assert_eq!(store.list_uploads().await.unwrap()[0].size, 3);
+        assert!(store.delete_upload(&legacy).await.unwrap());
+        assert!(store.list_uploads().await.unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store-wal");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(crate::wal::WAL_FILE);
+        let open = || Arc::new(Wal::open(&path).unwrap().unwrap());
+        let wal = open();
+        let store = FileStore::new(&dir)
+            .unwrap()
+            .with_wal(wal.clone())
+            .namespace("alice")
+            .unwrap();
+        let blobs = dir.join("alice").join(BLOBS_DIR);
+        let refs = |file: &[u8]| {
+            let hash = hex::encode(Sha256::digest(file));
+            fs::read_to_string(sharded(&blobs, &refs_blob(&hash))).ok()
+        };
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let first = store.store_files(files).await.unwrap().root_hash;
+        let kept = store.store_files(vec![b"world".to_vec()]).await.unwrap();
+        // The operations applied leave nothing to repair
+        let other = store.store_files(vec![b"other".to_vec()]).await.unwrap();
+        assert!(store.delete_upload(&other.root_hash).await.unwrap());
+        assert!(wal.unfinished().is_empty());
+
+        // A crash once an upload took references to its contents, before
+        // its tree is stored
+        let files = [b"world".to_vec(), b"new".to_vec()];
+        let tree = MerkleTree::new(&files).unwrap();
+        let second = hex::encode(tree.root().unwrap());
+        store.begin(OpKind::Commit, &second).unwrap();
+        let mut storing = Storing::new(sharded(&store.root_dir, &second));
+        for file in &files {
+            let hash = hex::encode(Sha256::digest(file));
+            let size = file.len() as u64;
+            let content = Some(Box::new(&file[..]) as BlobReader<'_>);
+            store
+                .put_content(&hash, content, size, true, &mut storing)
+                .await
+                .unwrap();
+        }
+        let expiry = Box::new(&b"0"[..]);
+        store
+            .backend
+            .put_blob(&second, EXPIRY_FILE, expiry, 1)
+            .await
+            .unwrap();
+        drop(storing);
+        assert_eq!(refs(b"world").as_deref(), Some("3"));
+        // and one once an upload is deleted, before it releases them
+        store.begin(OpKind::Delete, &first).unwrap();
+        store.backend.delete(&first).await.unwrap();
+        assert_eq!(store.backend.list_partial().await.unwrap(), [second]);
+        drop((store, wal));
+
+        // are both repaired once the log is read again
+        let wal = open();
+        assert_eq!(wal.unfinished().len(), 2);
+        let store = FileStore::new(&dir).unwrap().with_wal(wal.clone());
+        assert_eq!(store.recover().await.unwrap(), 2);
+        assert!(wal.unfinished().is_empty());
+        let alice = store.namespace("alice").unwrap();
+        assert!(alice.backend.list_partial().await.unwrap().is_empty());
+        let uploads = alice.list_uploads().await.unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].root_hash, kept.root_hash);
+        assert_eq!(refs(b"world").as_deref(), Some("1"));
+        assert_eq!(refs(b"hello"), None);
+        assert_eq!(refs(b"new"), None);
+        assert_eq!(
+            read(&alice, &kept.root_hash, 0, None).await.unwrap(),
+            b"world"
+        );
+        assert_eq!(store.recover().await.unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_killed_commit() {
+        const DIR_VAR: &str = "FILE_GUARDIAN_TEST_KILLED_COMMIT";
+        let empty = hex::encode(Sha256::digest(b""));
+        let open = |dir: &Path| {
+            let wal = Wal::open(&dir.join(crate::wal::WAL_FILE)).unwrap();
+            FileStore::new(dir)
+                .unwrap()
+                .with_wal(Arc::new(wal.unwrap()))
+        };
+
+        // The process committing, run by the test again, blocks reading its
+        // second file from a pipe once it took a reference to the first
+        if let Ok(dir) = std::env::var(DIR_VAR) {
+            let hashes = [hex::encode(Sha256::digest(b"hello")), empty];
+            open(Path::new(&dir)).commit_files(&hashes).await.unwrap();
+            return;
+        }
+        let dir = std::env::temp_dir().join("file-guardian-test-store-killed");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let store = open(&dir);
+        let session = "ab".repeat(32);
+        fs::write(store.partial_file(&session, 0).unwrap(), b"hello").unwrap();
+        let hello = store.stage_file(&session, 0).unwrap();
+        let pipe = dir.join(STAGING_DIR).join(&empty);
+        let made = std::process::Command::new("mkfifo").arg(&pipe).status();
+        assert!(made.unwrap().success());
+        drop(store);
+
+        let mut child =
+            std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "store::tests::test_killed_commit"])
+                .env(DIR_VAR, &dir)
+                .stdout(std::process::Stdio::null())
+                .spawn()
+                .unwrap();
+        let refs = sharded(&dir.join(BLOBS_DIR), &refs_blob(&hello));
+        for _ in 0..1000 {
+            if refs.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(fs::read_to_string(&refs).unwrap(), "1");
+        assert!(child.try_wait().unwrap().is_none());
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // Once restarted, the commit is rolled back, and the first content,
+        // referenced by no upload, deleted
+        let store = open(&dir);
+        assert_eq!(store.recover().await.unwrap(), 1);
+        assert!(store.list_uploads().await.unwrap().is_empty());
+        assert!(!refs.exists());
+        let held = store.held_contents(&[hello]).await.unwrap();
+        assert_eq!(held, [false]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_garbage() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store7");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let blobs = dir.join(BLOBS_DIR);
+        let files = vec![b"hello".to_vec(), b"world".to_vec()];
+        let kept = store.store_files(files).await.unwrap().root_hash;
+        let hello = hex::encode(Sha256::digest(b"hello"));
+        let world = hex::encode(Sha256::digest(b"world"));
+
+        // An upload without a tree, a content no upload references, and a
+        // count of references left too high by a crash
+        let partial = "ab".repeat(32);
+        let blob = Box::new(&b"abc"[..]);
+        store
+            .backend
+            .put_blob(&partial, "0", blob, 3)
+            .await
+            .unwrap();
+        let unreferenced = hex::encode(Sha256::digest(b"abc"));
+        let path = sharded(&blobs, &unreferenced);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"abc").unwrap();
+        fs::write(sharded(&blobs, &refs_blob(&unreferenced)), b"1").unwrap();
+        fs::write(sharded(&blobs, &refs_blob(&hello)), b"2").unwrap();
+
+        let report = collect(&store, true).await;
+        assert_eq!(
+            report.removed,
+            [
+                (sharded(&dir, &partial), 3),
+                (sharded(&blobs, &unreferenced), 3)
+            ]
+        );
+        assert_eq!(report.recounted, [sharded(&blobs, &hello)]);
+        assert!(sharded(&dir, &partial).exists());
+        let report = collect(&store, false).await;
+        assert_eq!(report.removed.len(), 2);
+        assert!(!sharded(&dir, &partial).exists());
+        assert!(!sharded(&blobs, &unreferenced).exists());
+        let refs =
+            fs::read_to_string(sharded(&blobs, &refs_blob(&hello))).unwrap();
+        assert_eq!(refs, "1");
+        assert!(collect(&store, false).await.is_empty());
+
+        // Nor are the uploads being stored, and the references they took
+        let mut storing = Storing::new(sharded(&dir, &partial));
+        storing.add(sharded(&blobs, &world));
+        let blob = Box::new(&b"abc"[..]);
+        store
+            .backend
+            .put_blob(&partial, "0", blob, 3)
+            .await
+            .unwrap();
+        fs::write(sharded(&blobs, &refs_blob(&world)), b"2").unwrap();
+        assert!(collect(&store, false).await.is_empty());
+        drop(storing);
+        let report = collect(&store, false).await;
+        assert_eq!(report.removed, [(sharded(&dir, &partial), 3)]);
+        assert_eq!(report.recounted, [sharded(&blobs, &world)]);
+        assert!(read(&store, &kept, 1, None).await.is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store8");
+        let _ = fs::remove_dir_all(&dir);
+        let day = Duration::from_secs(24 * 60 * 60);
+        let store = FileStore::new(&dir).unwrap().with_retention(day);
+        let expiring = store
+            .store_files(vec![b"hello".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+        let expiry = store.expiry(&expiring).await.unwrap().unwrap();
+        assert!(expiry.abs_diff(unix_now() + day.as_secs()) <= 1);
+
+        // An upload given no time at all expires at once, and is hidden
+        // until deleted
+        store.retain(&expiring, Duration::ZERO).await.unwrap();
+        assert!(store.has_expired(&expiring).await.unwrap());
+        assert!(store.list_uploads().await.unwrap().is_empty());
+        assert!(!store.has_upload(&expiring).await.unwrap());
+        let served = store.get_served_tree(&expiring).await.map(drop);
+        assert_eq!(kind_of(&served.unwrap_err()), ErrorKind::NotFound);
+        store.get_tree(&expiring).await.unwrap();
+        let kept = store
+            .store_files(vec![b"world".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+        store.retain(&kept, 2 * day).await.unwrap();
+        let missing = store.retain(&"ab".repeat(32), day).await.unwrap_err();
+        assert_eq!(kind_of(&missing), ErrorKind::NotFound);
+
+        assert_eq!(store.delete_expired().await.unwrap(), [expiring.as_str()]);
+        assert!(!store.has_upload(&expiring).await.unwrap());
+        assert!(store.delete_expired().await.unwrap().is_empty());
+        // Storing an upload again does not shorten its retention
+        store.store_files(vec![b"world".to_vec()]).await.unwrap();
+        let expiry = store.expiry(&kept).await.unwrap().unwrap();
+        assert!(expiry.abs_diff(unix_now() + 2 * day.as_secs()) <= 1);
+        assert_eq!(store.list_uploads().await.unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_namespaces() {
+        let dir = std::env::temp_dir().join("file-guardian-test-store4");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir)
+            .unwrap()
+            .with_quota(8)
+            .with_cache(Arc::new(TreeCache::new(8, 1 << 20)));
+        let (alice, bob) = (
+            store.namespace("alice").unwrap(),
+            store.namespace("bob").unwrap(),
+        );
+        let root_hash = alice
+            .store_files(vec![b"hello".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+        assert!(sharded(&dir.join("alice"), &root_hash).is_dir());
+
+        assert_eq!(alice.list_uploads().await.unwrap().len(), 1);
+        assert!(bob.list_uploads().await.unwrap().is_empty());
+        assert!(store.list_uploads().await.unwrap().is_empty());
+        let file = bob.file_size(&root_hash, 0).await.map(drop);
+        assert_eq!(kind_of(&file.unwrap_err()), ErrorKind::NotFound);
+        assert!(!bob.delete_upload(&root_hash).await.unwrap());
+        assert!(alice.has_upload(&root_hash).await.unwrap());
+        // The namespaces share the cache of trees, but not its trees
+        assert!(alice.get_tree(&root_hash).await.is_ok());
+        assert!(bob.get_tree(&root_hash).await.is_err());
+
+        // Every namespace has a quota of its own
+        assert_eq!(alice.usage().await.unwrap(), 5);
+        assert!(alice.reserve_quota(3).await.is_ok());
+        let exceeded = alice.reserve_quota(4).await.map(drop).unwrap_err();
+        assert_eq!(kind_of(&exceeded), ErrorKind::QuotaExceeded);
+        // The bytes being stored are reserved until they are
+        let reserved = alice.reserve_quota(3).await.unwrap();
+        let exceeded = alice.reserve_quota(1).await.map(drop).unwrap_err();
+        assert_eq!(kind_of(&exceeded), ErrorKind::QuotaExceeded);
+        drop(reserved);
+        assert!(alice.reserve_quota(1).await.is_ok());
+        assert_eq!(bob.usage().await.unwrap(), 0);
+        assert!(bob.reserve_quota(8).await.is_ok());
+        // The count is kept up to date, and shared by the stores of a
+        // namespace
+        bob.store_files(vec![b"abc".to_vec()]).await.unwrap();
+        let bob = store.namespace("bob").unwrap();
+        assert_eq!(bob.usage().await.unwrap(), 3);
+
+        // A deleted upload is evicted from the cache
+        assert!(alice.delete_upload(&root_hash).await.unwrap());
+        assert!(alice.get_tree(&root_hash).await.is_err());
+        assert_eq!(alice.usage().await.unwrap(), 0);
+
+        for name in ["", "..", "a/b", "staging", "blobs", &"a".repeat(64)] {
+            assert!(store.namespace(name).is_err(), "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,178 @@
+use anyhow::{anyhow, Result};
+
+/// The first file descriptor of the sockets passed by systemd.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The name of the sockets the HTTP API is served on, given with
+/// `FileDescriptorName=` in the socket unit.
+pub const HTTP_NAME: &str = "http";
+
+/// A socket passed by systemd to the server when it is socket activated.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ListenFd {
+    /// The file descriptor of the socket.
+    pub fd: i32,
+    /// The name of the socket, from `FileDescriptorName=` in the socket
+    /// unit, or the name of the unit by default.
+    pub name: String,
+}
+
+impl ListenFd {
+    /// Returns the address the socket is listened on with, see
+    /// [`crate::listener::Listener::bind`].
+    pub fn address(&self) -> String {
+        format!("fd:{}", self.fd)
+    }
+}
+
+/// Returns the sockets systemd passed to the server, when it is socket
+/// activated, see `sd_listen_fds(3)`.
+///
+/// # Errors
+///
+/// Returns an error if the environment is not as systemd sets it.
+pub fn listen_fds() -> Result<Vec<ListenFd>> {
+    let var = |name| std::env::var(name).ok();
+    parse_listen_fds(
+        var("LISTEN_PID"),
+        var("LISTEN_FDS"),
+        var("LISTEN_FDNAMES"),
+        std::process::id(),
+    )
+}
+
+/// Returns the sockets passed to the process `own_pid`, from the variables
+/// of the environment read by [`listen_fds`].
+fn parse_listen_fds(
+    pid: Option<String>,
+    fds: Option<String>,
+    names: Option<String>,
+    own_pid: u32,
+) -> Result<Vec<ListenFd>> {
+    // The sockets are for another process, e.g. the parent of the server
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(vec![]);
+    };
+    if pid.trim().parse::<u32>().ok() != Some(own_pid) {
+        return Ok(vec![]);
+    }
+    #[cfg(unix)]
+    {
+        let count = fds
+            .trim()
+            .parse::<i32>()
+            .map_err(|_| anyhow!("Invalid LISTEN_FDS {}", fds))?;
+        let mut names = names.as_deref().unwrap_or("").split(':');
+        Ok((0..count)
+            .map(|i| ListenFd {
+                fd: LISTEN_FDS_START + i,
+                name: match names.next() {
+                    Some(name) if !name.is_empty() => name.to_string(),
+                    _ => "unknown".to_string(),
+                },
+            })
+            .collect())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = names;
+        Err(anyhow!("Socket activation is not supported, for {}", fds))
+    }
+}
+
+/// Tells systemd about the state of the server, when it runs as a service
+/// of `Type=notify`, see `sd_notify(3)`, and does nothing otherwise.
+///
+/// # Arguments
+///
+/// * `state` - The state, e.g. `READY=1` once the server listens, or
+///   `STOPPING=1`.
+pub fn notify(state: &str) {
+    let Ok(socket) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket, state) {
+        log::warn!("Could not notify systemd of {}: {}", state, e);
+    }
+}
+
+/// Sends a state to the notification socket of systemd.
+#[cfg(unix)]
+fn send(socket: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        // A socket in the abstract namespace
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(
+                name.as_bytes(),
+            )?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends a state to the notification socket of systemd, which requires Unix
+/// sockets.
+#[cfg(not(unix))]
+fn send(_socket: &str, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        let parse = |pid: &str, fds: &str, names: Option<&str>| {
+            let names = names.map(String::from);
+            parse_listen_fds(Some(pid.into()), Some(fds.into()), names, 42)
+        };
+        assert_eq!(
+            parse("42", "2", Some("server.socket:http")).unwrap(),
+            [
+                ListenFd {
+                    fd: 3,
+                    name: "server.socket".to_string()
+                },
+                ListenFd {
+                    fd: 4,
+                    name: HTTP_NAME.to_string()
+                }
+            ]
+        );
+        assert_eq!(parse("42", "1", None).unwrap()[0].name, "unknown");
+        assert_eq!(parse("42", "1", None).unwrap()[0].address(), "fd:3");
+        // The sockets of another process are left alone
+        assert!(parse("7", "2", None).unwrap().is_empty());
+        assert!(parse_listen_fds(None, None, None, 42).unwrap().is_empty());
+        assert!(parse("42", "many", None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify() {
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = std::env::temp_dir().join("file-guardian-test-notify");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
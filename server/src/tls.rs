@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Result};
+use std::{path::Path, sync::Arc};
+use tokio_rustls::rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Creates a TLS acceptor that serves the given certificate.
+///
+/// # Arguments
+///
+/// * `cert` - The PEM file of the certificate chain, starting with the
+///   certificate of the server.
+/// * `key` - The PEM file of the private key of the certificate.
+/// * `client_ca` - A PEM file of certificate authorities. If given, clients
+///   must present a certificate signed by one of them (mutual TLS).
+///
+/// # Errors
+///
+/// Returns an error if a file cannot be read or parsed, or if the key does
+/// not match the certificate.
+pub fn acceptor(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<TlsAcceptor> {
+    let certs = certificates(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| anyhow!("Invalid key {}: {}", key.display(), e))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in certificates(client_ca)? {
+                roots.add(cert)?;
+            }
+            builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder(Arc::new(roots)).build()?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reads the certificates of a PEM file.
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            anyhow!("Invalid certificate {}: {}", path.display(), e)
+        })?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
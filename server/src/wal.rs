@@ -0,0 +1,268 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The file of the write-ahead log, under the directory of the store.
+pub const WAL_FILE: &str = "wal.log";
+
+/// The size the log is first compacted at, only keeping the operations not
+/// ended.
+const COMPACT_SIZE: u64 = 1 << 20;
+
+/// An operation changing which uploads a store holds, and with them how
+/// many reference each content, the counts being recomputed rather than
+/// logged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Op {
+    /// What the operation does.
+    pub kind: OpKind,
+    /// The namespace of the upload, if it belongs to one.
+    pub namespace: Option<String>,
+    /// The root hash of the upload.
+    pub root_hash: String,
+}
+
+/// What an [`Op`] does to its upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpKind {
+    /// Commits the upload, taking a reference to each of its contents and
+    /// storing its tree last.
+    Commit,
+    /// Deletes the upload, then releases the references it held.
+    Delete,
+}
+
+/// A line of the log: an operation begun, or the end of the operation of
+/// the same id once it is applied.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    op: Option<Op>,
+}
+
+/// A write-ahead log of the operations changing the uploads of a store, a
+/// JSON object per line, replayed on startup so that a crash never leaves
+/// an upload half committed or half deleted. The references to the contents
+/// are not logged: those of the namespaces of the operations replayed are
+/// counted again from the trees of their uploads.
+///
+/// An operation is logged, and the log synced, before the operation changes
+/// anything, and its end is logged once it is applied. The operations begun
+/// and never ended are those a crash interrupted, and are repaired by
+/// [`FileStore::recover`](crate::store::FileStore::recover). The log is
+/// emptied once it grows beyond its size, only keeping those operations.
+pub struct Wal {
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    /// The lines of the operations begun and not ended, by id.
+    pending: BTreeMap<u64, Vec<u8>>,
+    /// The id of the next operation.
+    next: u64,
+    /// The size the log is compacted at, twice what it kept when last
+    /// compacted, so that the operations never ended are not copied over
+    /// and over.
+    compact_at: u64,
+}
+
+impl Wal {
+    /// Opens the log of a store, reading the operations a crash interrupted,
+    /// see [`Wal::unfinished`].
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if another process holds the log, e.g. a server
+    /// running on the store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened or read.
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let open = |e: io::Error| {
+            anyhow!("Could not open the log {}: {}", path.display(), e)
+        };
+        let mut options = OpenOptions::new();
+        options.read(true).append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(path).map_err(open)?;
+        if file.try_lock().is_err() {
+            return Ok(None);
+        }
+
+        // A line cut short by a crash is the last one, and is cut off so
+        // that the next entries are appended after the valid ones
+        let content = std::fs::read(path).map_err(open)?;
+        let mut valid = 0;
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for line in content.split_inclusive(|&byte| byte == b'\n') {
+            let entry = match serde_json::from_slice::<Entry>(line) {
+                Ok(entry) if line.ends_with(b"\n") => entry,
+                _ => break,
+            };
+            match entry.op {
+                Some(_) => pending.insert(entry.id, line.to_vec()),
+                None => pending.remove(&entry.id),
+            };
+            next = next.max(entry.id + 1);
+            valid += line.len();
+        }
+        file.set_len(valid as u64).map_err(open)?;
+        Ok(Some(Self {
+            state: Mutex::new(State {
+                file,
+                size: valid as u64,
+                pending,
+                next,
+                compact_at: COMPACT_SIZE,
+            }),
+        }))
+    }
+
+    /// Returns the operations begun and not ended, with their ids, oldest
+    /// first.
+    pub fn unfinished(&self) -> Vec<(u64, Op)> {
+        let state = self.state.lock().unwrap();
+        state
+            .pending
+            .iter()
+            .filter_map(|(&id, line)| {
+                let entry = serde_json::from_slice::<Entry>(line).ok()?;
+                Some((id, entry.op?))
+            })
+            .collect()
+    }
+
+    /// Logs an operation about to begin, once written to the disk.
+    ///
+    /// # Returns
+    ///
+    /// Returns the id of the operation, to [`Wal::end`] it with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the log cannot be written, in which case the
+    /// operation must not begin.
+    pub fn begin(&self, op: Op) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next;
+        let line = line(&Entry { id, op: Some(op) })?;
+        state
+            .append(&line)
+            .and_then(|()| state.file.sync_data())
+            .map_err(|e| anyhow!("Could not write the log: {}", e))?;
+        state.next += 1;
+        state.pending.insert(id, line);
+        Ok(id)
+    }
+
+    /// Logs the end of an operation applied, compacting the log if it grew
+    /// beyond its size.
+    ///
+    /// The end is not synced, an operation whose end is lost being repaired
+    /// again, which changes nothing.
+    pub fn end(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let ended = line(&Entry { id, op: None })
+            .map_err(|e| io::Error::other(e.to_string()))
+            .and_then(|line| state.append(&line));
+        if let Err(e) = ended {
+            log::error!("Could not write the log: {}", e);
+            return;
+        }
+        state.pending.remove(&id);
+        if state.size >= state.compact_at {
+            if let Err(e) = state.compact() {
+                log::error!("Could not compact the log: {}", e);
+            }
+        }
+    }
+}
+
+impl State {
+    /// Appends a line to the log, in a single write.
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Empties the log but for the operations not ended.
+    fn compact(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.size = 0;
+        let pending = self.pending.values().flatten().copied();
+        let pending = pending.collect::<Vec<_>>();
+        self.append(&pending)?;
+        self.compact_at = COMPACT_SIZE.max(2 * self.size);
+        self.file.sync_data()
+    }
+}
+
+/// Encodes an entry as a line of the log.
+fn line(entry: &Entry) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wal() {
+        let dir = std::env::temp_dir().join("file-guardian-test-wal");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(WAL_FILE);
+        let op = |kind, root_hash: &str| Op {
+            kind,
+            namespace: Some("alice".to_string()),
+            root_hash: root_hash.to_string(),
+        };
+
+        let wal = Wal::open(&path).unwrap().unwrap();
+        assert!(wal.unfinished().is_empty());
+        // Held by a single process at a time
+        assert!(Wal::open(&path).unwrap().is_none());
+        let committed = wal.begin(op(OpKind::Commit, "aa")).unwrap();
+        let deleted = wal.begin(op(OpKind::Delete, "bb")).unwrap();
+        wal.end(committed);
+        assert_eq!(wal.unfinished(), [(deleted, op(OpKind::Delete, "bb"))]);
+        drop(wal);
+
+        // The operations not ended are read again, but for a line cut short
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"id":2,"op":{"kind":"com"#).unwrap();
+        let wal = Wal::open(&path).unwrap().unwrap();
+        assert_eq!(wal.unfinished(), [(deleted, op(OpKind::Delete, "bb"))]);
+        let next = wal.begin(op(OpKind::Commit, "cc")).unwrap();
+        assert_eq!(next, 2);
+        wal.end(next);
+        wal.end(deleted);
+        assert!(wal.unfinished().is_empty());
+
+        // and the log only keeps them once compacted
+        let pending = wal.begin(op(OpKind::Commit, "dd")).unwrap();
+        for _ in 0..COMPACT_SIZE / 64 {
+            let id = wal.begin(op(OpKind::Commit, "ee")).unwrap();
+            wal.end(id);
+        }
+        assert!(std::fs::metadata(&path).unwrap().len() < COMPACT_SIZE);
+        drop(wal);
+        let wal = Wal::open(&path).unwrap().unwrap();
+        assert_eq!(wal.unfinished(), [(pending, op(OpKind::Commit, "dd"))]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
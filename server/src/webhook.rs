@@ -0,0 +1,285 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+
+use crate::cloud::{self, Request, Transport};
+use crate::logger;
+
+/// The number of times the delivery of an event is retried by default.
+pub const DEFAULT_RETRIES: u32 = 5;
+
+/// The header of the requests naming their event.
+const EVENT_HEADER: &str = "X-File-Guardian-Event";
+
+/// The header of the requests carrying the signature of their body, when
+/// the events are signed.
+const SIGNATURE_HEADER: &str = "X-File-Guardian-Signature";
+
+/// The statuses of the responses acknowledging an event.
+const ACKNOWLEDGED: &[u16] = &[200, 201, 202, 204];
+
+/// An upload newly stored, posted to the webhooks as JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    /// The kind of the event, `upload.committed`.
+    pub event: &'static str,
+    /// The root hash of the upload.
+    pub root_hash: String,
+    /// The number of files of the upload.
+    pub files: usize,
+    /// The total size of the files.
+    pub size: u64,
+    /// The user the upload belongs to, if the clients authenticate as
+    /// users.
+    pub user: Option<String>,
+    /// When the upload was stored, e.g. `2024-05-01T12:30:00Z`.
+    pub time: String,
+}
+
+impl Event {
+    /// Returns the event of an upload committed now.
+    ///
+    /// # Arguments
+    ///
+    /// * `root_hash` - The root hash of the upload.
+    /// * `files` - The number of files of the upload.
+    /// * `size` - The total size of the files.
+    /// * `user` - The namespace of the store holding the upload, if any.
+    pub fn committed(
+        root_hash: &str,
+        files: usize,
+        size: u64,
+        user: Option<&str>,
+    ) -> Self {
+        Self {
+            event: "upload.committed",
+            root_hash: root_hash.to_string(),
+            files,
+            size,
+            user: user.map(str::to_string),
+            time: logger::timestamp(SystemTime::now()),
+        }
+    }
+}
+
+/// The URLs posted an event whenever an upload finishes, for downstream
+/// pipelines to pick up the uploads as they land.
+///
+/// Each event is posted to each URL on its own, as soon as the upload is
+/// committed, and posted again while the URL cannot be reached or fails
+/// with a 5xx or 429 status; the events are not kept across restarts.
+pub struct Webhooks {
+    /// The transports of the URLs, with the URL each is posted to.
+    hooks: Vec<(String, Arc<Transport>)>,
+    /// The secret the bodies are signed with, if they are.
+    secret: Option<Vec<u8>>,
+    /// The queue of the events to post.
+    queue: mpsc::UnboundedSender<Event>,
+    /// The end of the queue, until the webhooks run.
+    events: Mutex<Option<mpsc::UnboundedReceiver<Event>>>,
+}
+
+impl Webhooks {
+    /// Creates the webhooks posted to the given URLs.
+    ///
+    /// # Arguments
+    ///
+    /// * `urls` - The `http://` or `https://` URLs.
+    /// * `ca` - A PEM file of the certificate authorities the URLs over
+    ///   HTTPS are verified with, instead of the Mozilla root certificates.
+    /// * `retries` - The number of times a failed delivery is retried.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a URL is invalid, or the CA file cannot be read.
+    pub fn new(
+        urls: &[String],
+        ca: Option<&Path>,
+        retries: u32,
+    ) -> Result<Self> {
+        let hooks = urls
+            .iter()
+            .map(|url| {
+                let transport = Transport::new("Webhook", url, ca, retries)?;
+                Ok((url.clone(), Arc::new(transport)))
+            })
+            .collect::<Result<_>>()?;
+        let (queue, events) = mpsc::unbounded_channel();
+        Ok(Self {
+            hooks,
+            secret: None,
+            queue,
+            events: Mutex::new(Some(events)),
+        })
+    }
+
+    /// Signs the body of every request with a secret, in its
+    /// `X-File-Guardian-Signature` header: `sha256=` followed by the hex
+    /// encoded HMAC-SHA256 of the body, for the receivers to check that the
+    /// events come from the server.
+    pub fn with_secret(mut self, secret: &[u8]) -> Self {
+        self.secret = Some(secret.to_vec());
+        self
+    }
+
+    /// Queues an event to be posted to the URLs.
+    pub fn push(&self, event: Event) {
+        // Closed once the server stops
+        let _ = self.queue.send(event);
+    }
+
+    /// Posts the events queued to the URLs for as long as the server runs,
+    /// a slow or failing URL not holding back the others.
+    pub async fn run(self: Arc<Self>) {
+        let Some(mut events) = self.events.lock().unwrap().take() else {
+            return;
+        };
+        while let Some(event) = events.recv().await {
+            let body = match serde_json::to_vec(&event) {
+                Ok(body) => Arc::new(body),
+                Err(e) => {
+                    log::error!("Could not encode {:?}: {}", event, e);
+                    continue;
+                }
+            };
+            let signature = self.secret.as_ref().map(|secret| {
+                format!("sha256={}", hex::encode(cloud::hmac(secret, &body)))
+            });
+            for (url, transport) in &self.hooks {
+                let (url, transport) = (url.clone(), transport.clone());
+                let (body, signature) = (body.clone(), signature.clone());
+                let (kind, root_hash) = (event.event, event.root_hash.clone());
+                tokio::spawn(async move {
+                    let posted = post(&transport, kind, &body, signature).await;
+                    match posted {
+                        Ok(()) => log::debug!(
+                            "Posted the commit of {} to {}",
+                            root_hash,
+                            url
+                        ),
+                        Err(e) => log::error!(
+                            "Could not post the commit of {} to {}: {:#}",
+                            root_hash,
+                            url,
+                            e
+                        ),
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Posts the body of an event to a URL, retrying while it fails with a
+/// transient error.
+async fn post(
+    transport: &Transport,
+    event: &str,
+    body: &[u8],
+    signature: Option<String>,
+) -> Result<()> {
+    let endpoint = &transport.endpoint;
+    let target = match endpoint.path.as_str() {
+        "" => "/",
+        path => path,
+    };
+    let mut request = Request::new("POST", target)
+        .header("Host", endpoint.authority.as_str())
+        .header("Content-Type", "application/json")
+        .header(
+            "User-Agent",
+            concat!("file-guardian/", env!("CARGO_PKG_VERSION")),
+        )
+        .header(EVENT_HEADER, event)
+        .body(body);
+    if let Some(signature) = signature {
+        request = request.header(SIGNATURE_HEADER, signature);
+    }
+    let head = || {
+        let mut head = format!("POST {} HTTP/1.1\r\n", target);
+        for (name, value) in &request.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        ));
+        head
+    };
+    transport.send(&request, head, ACKNOWLEDGED).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::FileStore;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_webhooks() {
+        let dir = std::env::temp_dir().join("file-guardian-test-webhook");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // A receiver failing the first delivery
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url =
+            format!("http://{}/hooks/landed", listener.local_addr().unwrap());
+        let received = tokio::spawn(async move {
+            let mut requests = vec![];
+            for status in ["503 Service Unavailable", "204 No Content"] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let mut len = 0;
+                // The head and the body are read once the body is whole
+                while !String::from_utf8_lossy(&request[..len]).ends_with('}') {
+                    len += socket.read(&mut request[len..]).await.unwrap();
+                }
+                requests
+                    .push(String::from_utf8_lossy(&request[..len]).to_string());
+                let response =
+                    format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let webhooks = Webhooks::new(&[url], None, 2)
+            .unwrap()
+            .with_secret(b"secret");
+        let webhooks = Arc::new(webhooks);
+        tokio::spawn(webhooks.clone().run());
+        let store = FileStore::new(&dir)
+            .unwrap()
+            .with_webhooks(webhooks)
+            .namespace("alice")
+            .unwrap();
+        let root_hash = store
+            .store_files(vec![b"hello".to_vec(), b"world!".to_vec()])
+            .await
+            .unwrap()
+            .root_hash;
+
+        let requests = received.await.unwrap();
+        assert_eq!(requests[0], requests[1]);
+        let (head, body) = requests[1].split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /hooks/landed HTTP/1.1\r\n"));
+        assert!(head.contains("X-File-Guardian-Event: upload.committed\r\n"));
+        let signature = hex::encode(cloud::hmac(b"secret", body.as_bytes()));
+        assert!(head.contains(&format!(
+            "X-File-Guardian-Signature: sha256={}\r\n",
+            signature
+        )));
+        let event: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(event["event"], "upload.committed");
+        assert_eq!(event["root_hash"], root_hash);
+        assert_eq!(event["files"], 2);
+        assert_eq!(event["size"], 11);
+        assert_eq!(event["user"], "alice");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}